@@ -0,0 +1,91 @@
+//! Compare fragmentation and speed of allocation strategies
+//! - run with `cargo bench --bench allocation`
+//! - same workload for every strategy: records of 1 to 8 blocks written and deleted
+//!   at pseudo random, about 1 delete per 2 writes
+use se1::storage::{AllocationStrategy, BlockStore, RecordId, Storage};
+use std::time::Instant;
+
+const BLOCK_LEN: u64 = 64;
+const OPERATIONS: usize = 4000;
+
+/// Linear congruential generator, so every strategy gets the same workload
+struct Lcg(u64);
+
+impl Lcg {
+    fn next(&mut self, bound: u64) -> u64 {
+        self.0 = self
+            .0
+            .wrapping_mul(6364136223846793005)
+            .wrapping_add(1442695040888963407);
+        (self.0 >> 33) % bound
+    }
+}
+
+struct Report {
+    file_blocks: u64,
+    free_blocks: u64,
+    live_records: usize,
+    /// Live records whose blocks are not contiguous
+    scattered_records: usize,
+    millis: u128,
+}
+
+fn run(strategy: AllocationStrategy, file_path: String) -> Report {
+    let mut storage = Storage::new(file_path, BLOCK_LEN).unwrap();
+    storage.set_allocation_strategy(strategy);
+    let mut rng = Lcg(42);
+    let mut records: Vec<RecordId> = Vec::new();
+    let started = Instant::now();
+    for _ in 0..OPERATIONS {
+        if !records.is_empty() && rng.next(3) == 0 {
+            let record_id = records.swap_remove(rng.next(records.len() as u64) as usize);
+            storage.delete_record(record_id, false).unwrap();
+        } else {
+            let block_count = 1 + rng.next(8);
+            let data = vec![1u8; (block_count * BLOCK_LEN) as usize];
+            records.push(storage.write_record(&data).unwrap());
+        }
+    }
+    let millis = started.elapsed().as_millis();
+    let mut scattered_records = 0;
+    for record_id in records.iter() {
+        let blocks = storage.record_blocks(*record_id).unwrap();
+        if blocks.windows(2).any(|pair| pair[1] != pair[0] + 1) {
+            scattered_records += 1;
+        }
+    }
+    let stats = storage.stats();
+    Report {
+        file_blocks: stats.block_count,
+        free_blocks: stats.free_blocks,
+        live_records: records.len(),
+        scattered_records,
+        millis,
+    }
+}
+
+fn main() {
+    let tmp_dir = tempfile::tempdir().unwrap();
+    println!(
+        "{:<12} {:>12} {:>12} {:>12} {:>10} {:>8}",
+        "strategy", "file blocks", "free blocks", "records", "scattered", "ms"
+    );
+    let strategies = [
+        AllocationStrategy::FirstFit,
+        AllocationStrategy::BestFit,
+        AllocationStrategy::AppendOnly,
+    ];
+    for strategy in strategies.iter() {
+        let file_path = tmp_dir.path().join(format!("{:?}.hex", strategy));
+        let report = run(*strategy, file_path.to_str().unwrap().to_string());
+        println!(
+            "{:<12} {:>12} {:>12} {:>12} {:>10} {:>8}",
+            format!("{:?}", strategy),
+            report.file_blocks,
+            report.free_blocks,
+            report.live_records,
+            report.scattered_records,
+            report.millis
+        );
+    }
+}
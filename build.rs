@@ -0,0 +1,31 @@
+//! Generates code of the gRPC service in proto/se1.proto, with feature grpc, and the C
+//! header include/se1.h of src/ffi.rs, with feature ffi
+fn main() {
+    #[cfg(feature = "grpc")]
+    {
+        // - protoc is vendored, building does not need it installed
+        let protoc = protoc_bin_vendored::protoc_bin_path().expect("vendored protoc");
+        std::env::set_var("PROTOC", protoc);
+        // - client connect helpers use the 2021 prelude, this crate is on edition 2018,
+        //   clients are made from a tonic Channel instead
+        tonic_build::configure()
+            .build_transport(false)
+            .compile_protos(&["proto/se1.proto"], &["proto"])
+            .expect("compile proto/se1.proto");
+    }
+    #[cfg(feature = "ffi")]
+    {
+        println!("cargo:rerun-if-changed=src/ffi.rs");
+        println!("cargo:rerun-if-changed=cbindgen.toml");
+        let crate_dir = std::env::var("CARGO_MANIFEST_DIR").expect("CARGO_MANIFEST_DIR");
+        let config = cbindgen::Config::from_file(format!("{}/cbindgen.toml", crate_dir))
+            .expect("read cbindgen.toml");
+        // - only src/ffi.rs is parsed, other public items of the crate are not part of the C API
+        cbindgen::Builder::new()
+            .with_src(format!("{}/src/ffi.rs", crate_dir))
+            .with_config(config)
+            .generate()
+            .expect("generate C header of src/ffi.rs")
+            .write_to_file(format!("{}/include/se1.h", crate_dir));
+    }
+}
@@ -0,0 +1,96 @@
+#ifndef SE1_H
+#define SE1_H
+
+/* Generated by cbindgen from src/ffi.rs with feature ffi, do not edit */
+
+#include <stdbool.h>
+#include <stddef.h>
+#include <stdint.h>
+
+// Call succeeded
+#define SE1_OK 0
+
+// A pointer argument was null, or the path was not UTF-8
+#define SE1_ERR_INVALID_POINTER 1
+
+// Storage file could not be read or written
+#define SE1_ERR_IO 2
+
+// Storage file content is invalid, or not a storage file of a supported version
+#define SE1_ERR_CORRUPTION 3
+
+// Argument out of range, e.g. empty record data or invalid block length
+#define SE1_ERR_INVALID_ARGUMENT 4
+
+// Operation is not allowed, the block is pinned, the file is append-only or read-only,
+// or the write would grow the file past its quota
+#define SE1_ERR_NOT_PERMITTED 5
+
+// Any other failure, see se1_last_error_message
+#define SE1_ERR_OTHER 6
+
+// se1 panicked, the storage should be closed
+#define SE1_ERR_PANIC 7
+
+// Open storage file, opaque to C
+typedef struct Se1Storage Se1Storage;
+
+// Open storage file at path, creating it with block_len if it does not exist
+// - block_len is ignored for an existing file, it keeps its block length
+// - out_storage: set to the opened storage, close it with se1_close
+//
+// # Safety
+// path must be a NUL terminated string, out_storage must be valid for a write
+int32_t se1_open(const char *path, uint64_t block_len, struct Se1Storage **out_storage);
+
+// Write data_len bytes of data as a new record, see Storage::write_record
+// - out_record_id: set to the record id, to read or delete the record
+//
+// # Safety
+// storage must come from se1_open, data must be valid for data_len bytes unless
+// data_len is 0, out_record_id must be valid for a write
+int32_t se1_write(struct Se1Storage *storage,
+                  const uint8_t *data,
+                  size_t data_len,
+                  uint64_t *out_record_id);
+
+// Read all data of a record, see Storage::read_record
+// - out_data, out_data_len: set to a buffer allocated by se1 and its length, free it
+//   with se1_free_data, an empty record sets null and 0
+//
+// # Safety
+// storage must come from se1_open, out_data and out_data_len must be valid for a write
+int32_t se1_read(struct Se1Storage *storage,
+                 uint64_t record_id,
+                 uint8_t **out_data,
+                 size_t *out_data_len);
+
+// Free a buffer returned by se1_read, null is ignored
+//
+// # Safety
+// data and data_len must be a buffer and its length set by se1_read, freed only once
+void se1_free_data(uint8_t *data, size_t data_len);
+
+// Delete all blocks of a record, see Storage::delete_record
+// - hard_delete: zero block data on disk, not only free the blocks
+// - out_deleted_blocks: set to the number of deleted blocks, may be null
+//
+// # Safety
+// storage must come from se1_open, out_deleted_blocks must be null or valid for a write
+int32_t se1_delete(struct Se1Storage *storage,
+                   uint64_t record_id,
+                   bool hard_delete,
+                   size_t *out_deleted_blocks);
+
+// Close storage file cleanly, see Storage::close
+// - storage is freed even if closing fails, it must not be used afterwards
+//
+// # Safety
+// storage must come from se1_open, and be closed only once
+int32_t se1_close(struct Se1Storage *storage);
+
+// Message of the last error on the calling thread, null if no call failed yet
+// - valid until the next failing call on the same thread, do not free it
+const char *se1_last_error_message(void);
+
+#endif  /* SE1_H */
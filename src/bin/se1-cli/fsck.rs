@@ -0,0 +1,90 @@
+//! fsck command, checking a storage file for damage and optionally repairing it, and
+//! repair command, rebuilding its free list
+use super::CliError;
+use se1::storage::{BlockStore, Storage};
+use std::io::Write;
+
+/// Print damage found in storage file, one line per issue
+/// - fails with CliError::Damaged if damage was found and not repaired, so scripts
+///   can tell from the exit code
+pub fn fsck(file_path: &str, repair: bool, out: &mut dyn Write) -> Result<(), CliError> {
+    let report = Storage::fsck(file_path, repair)?;
+    writeln!(out, "file:          {}", file_path)?;
+    writeln!(out, "block length:  {} bytes", report.block_len)?;
+    writeln!(out, "blocks:        {}", report.block_count)?;
+    writeln!(out, "problems:      {}", report.issues.len())?;
+    for issue in report.issues.iter() {
+        writeln!(out, "  {}", issue)?;
+    }
+    if report.repaired {
+        writeln!(out, "repaired")?;
+    } else if !report.issues.is_empty() {
+        return Err(CliError::Damaged {
+            issue_count: report.issues.len(),
+        });
+    }
+    Ok(())
+}
+
+/// Rebuild free blocks of storage from its block headers, see Storage::rebuild_free_list
+/// - always rescans, even if the file was closed cleanly and its bitmap is trusted
+pub fn repair_free_list(storage: &mut Storage, out: &mut dyn Write) -> Result<(), CliError> {
+    let free_count = storage.rebuild_free_list()?;
+    let stats = storage.stats();
+    writeln!(out, "file:          {}", storage.file_path())?;
+    writeln!(out, "blocks:        {}", stats.block_count)?;
+    writeln!(out, "free blocks:   {}", free_count)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod unit_tests_fsck {
+    use super::*;
+
+    #[test]
+    fn test_fsck() {
+        let tmp_dir = tempfile::tempdir().unwrap();
+        let file_path = tmp_dir.path().join("fsck.hex");
+        let file_path = file_path.to_str().unwrap().to_string();
+        let mut storage = Storage::new(file_path.clone(), 4).unwrap();
+        storage.write_block(0, &[1, 2, 3]).unwrap();
+        storage.close().unwrap();
+        // - cut block 0 short
+        let file_len = std::fs::metadata(&file_path).unwrap().len();
+        std::fs::OpenOptions::new()
+            .write(true)
+            .open(&file_path)
+            .unwrap()
+            .set_len(file_len - 2)
+            .unwrap();
+        let mut out = Vec::new();
+        assert!(matches!(
+            fsck(&file_path, false, &mut out),
+            Err(CliError::Damaged { issue_count: 1 })
+        ));
+        let report = String::from_utf8(out).unwrap();
+        assert!(report.contains("problems:      1\n"));
+        let mut out = Vec::new();
+        fsck(&file_path, true, &mut out).unwrap();
+        assert!(String::from_utf8(out).unwrap().ends_with("repaired\n"));
+        let mut out = Vec::new();
+        fsck(&file_path, false, &mut out).unwrap();
+        assert!(String::from_utf8(out)
+            .unwrap()
+            .contains("problems:      0\n"));
+    }
+    #[test]
+    fn test_repair_free_list() {
+        let tmp_dir = tempfile::tempdir().unwrap();
+        let file_path = tmp_dir.path().join("repair.hex");
+        let file_path = file_path.to_str().unwrap().to_string();
+        let mut storage = Storage::new(file_path, 4).unwrap();
+        storage.write_block(0, &[1]).unwrap();
+        storage.write_block(3, &[2]).unwrap();
+        let mut out = Vec::new();
+        repair_free_list(&mut storage, &mut out).unwrap();
+        let report = String::from_utf8(out).unwrap();
+        assert!(report.contains("blocks:        4\n"));
+        assert!(report.ends_with("free blocks:   2\n"));
+    }
+}
@@ -0,0 +1,209 @@
+//! inspect, list and dump commands, reading a storage file without changing its blocks
+use super::CliError;
+use se1::storage::{BlockIndex, BlockStore, Storage};
+use std::io::Write;
+
+/// Bytes per line of hex dump
+const DUMP_LINE_LEN: usize = 16;
+
+/// Print storage header, block counts, free blocks and fragmentation
+/// - fragmentation counts free blocks below the last used block, which new writes
+///   can fill but which compaction would remove
+pub fn inspect(storage: &mut Storage, out: &mut dyn Write) -> Result<(), CliError> {
+    let stats = storage.stats();
+    let mut used_blocks = Vec::new();
+    for block in storage.iter_block_headers() {
+        used_blocks.push(block?.block_index);
+    }
+    let free_runs = free_runs(&used_blocks, stats.block_count);
+    let holes: Vec<&(BlockIndex, u64)> = free_runs
+        .iter()
+        .filter(|(first_block, length)| first_block + length < stats.block_count)
+        .collect();
+    let hole_blocks: u64 = holes.iter().map(|(_, length)| length).sum();
+    let file_len = std::fs::metadata(storage.file_path())?.len();
+    writeln!(out, "file:          {}", storage.file_path())?;
+    writeln!(out, "file size:     {} bytes", file_len)?;
+    writeln!(out, "block length:  {} bytes", stats.block_len)?;
+    writeln!(out, "compression:   {:?}", storage.compression())?;
+    writeln!(out, "encrypted:     {}", yes_no(storage.is_encrypted()))?;
+    writeln!(out, "append-only:   {}", yes_no(storage.is_append_only()))?;
+    writeln!(
+        out,
+        "blocks:        {} ({} used, {} free)",
+        stats.block_count, stats.used_blocks, stats.free_blocks
+    )?;
+    let free_list: Vec<String> = free_runs.iter().map(format_run).collect();
+    writeln!(out, "free blocks:   {}", or_none(&free_list.join(", ")))?;
+    writeln!(
+        out,
+        "fragmentation: {} free blocks in {} holes below last used block ({:.1}%)",
+        hole_blocks,
+        holes.len(),
+        percent(hole_blocks, stats.block_count)
+    )?;
+    Ok(())
+}
+
+/// Print block header of every used block, one row per block
+pub fn list(storage: &mut Storage, out: &mut dyn Write) -> Result<(), CliError> {
+    writeln!(
+        out,
+        "{:>10} {:>10} {:>10} {:>10} {:>12}",
+        "block", "data_size", "checksum", "next", "expires_at"
+    )?;
+    for block in storage.iter_block_headers() {
+        let block = block?;
+        let optional = |value: Option<u64>| value.map_or("-".to_string(), |v| v.to_string());
+        writeln!(
+            out,
+            "{:>10} {:>10} {:>10} {:>10} {:>12}",
+            block.block_index,
+            block.data_size,
+            format!("{:08x}", block.checksum),
+            optional(block.next_block),
+            optional(block.expires_at)
+        )?;
+    }
+    Ok(())
+}
+
+/// Print data of block as hex dump, after checksum and transforms, like read_block
+pub fn dump(
+    storage: &mut Storage,
+    block_index: BlockIndex,
+    out: &mut dyn Write,
+) -> Result<(), CliError> {
+    let (_, block_data) = storage.read_block(block_index)?;
+    writeln!(out, "block {}: {} bytes", block_index, block_data.len())?;
+    for (line, bytes) in block_data.chunks(DUMP_LINE_LEN).enumerate() {
+        writeln!(out, "{}", hex_line(line * DUMP_LINE_LEN, bytes))?;
+    }
+    Ok(())
+}
+
+/// One line of hex dump: offset, hex bytes padded to DUMP_LINE_LEN, printable ASCII
+fn hex_line(offset: usize, bytes: &[u8]) -> String {
+    let hex: Vec<String> = bytes.iter().map(|byte| format!("{:02x}", byte)).collect();
+    let ascii: String = bytes
+        .iter()
+        .map(|byte| match byte {
+            0x20..=0x7e => *byte as char,
+            _ => '.',
+        })
+        .collect();
+    format!(
+        "{:08x}  {:<width$}  |{}|",
+        offset,
+        hex.join(" "),
+        ascii,
+        width = DUMP_LINE_LEN * 3 - 1
+    )
+}
+
+/// Runs of free blocks as (first block, length), from used blocks in ascending order
+fn free_runs(used_blocks: &[BlockIndex], block_count: u64) -> Vec<(BlockIndex, u64)> {
+    let mut free_runs = Vec::new();
+    let mut next_block = 0;
+    for block_index in used_blocks
+        .iter()
+        .cloned()
+        .chain(std::iter::once(block_count))
+    {
+        if block_index > next_block {
+            free_runs.push((next_block, block_index - next_block));
+        }
+        next_block = block_index + 1;
+    }
+    free_runs
+}
+
+fn format_run(run: &(BlockIndex, u64)) -> String {
+    match run {
+        (first_block, 1) => first_block.to_string(),
+        (first_block, length) => format!("{}-{}", first_block, first_block + length - 1),
+    }
+}
+
+fn yes_no(value: bool) -> &'static str {
+    if value {
+        "yes"
+    } else {
+        "no"
+    }
+}
+
+fn or_none(list: &str) -> &str {
+    if list.is_empty() {
+        "none"
+    } else {
+        list
+    }
+}
+
+fn percent(part: u64, total: u64) -> f64 {
+    if total == 0 {
+        0.0
+    } else {
+        part as f64 * 100.0 / total as f64
+    }
+}
+
+#[cfg(test)]
+mod unit_tests_inspect {
+    use super::*;
+
+    /// storage with used blocks 0, 1 and 3, free blocks 2, 4 and 5
+    fn new_storage(tmp_dir: &tempfile::TempDir) -> Storage {
+        let file_path = tmp_dir.path().join("inspect.hex");
+        let mut storage = Storage::new(file_path.to_str().unwrap().to_string(), 4).unwrap();
+        storage.write_block(0, b"ab").unwrap();
+        storage.write_block(3, &[1, 2, 3]).unwrap();
+        storage.write_block(5, &[9]).unwrap();
+        storage.delete_block(5, false).unwrap();
+        storage.write_record(&[7; 4]).unwrap();
+        storage
+    }
+    fn output(command: impl FnOnce(&mut dyn Write) -> Result<(), CliError>) -> String {
+        let mut out = Vec::new();
+        command(&mut out).unwrap();
+        String::from_utf8(out).unwrap()
+    }
+
+    #[test]
+    fn test_inspect() {
+        let tmp_dir = tempfile::tempdir().unwrap();
+        let mut storage = new_storage(&tmp_dir);
+        let report = output(|out| inspect(&mut storage, out));
+        assert!(report.contains("block length:  4 bytes\n"));
+        assert!(report.contains("blocks:        6 (3 used, 3 free)\n"));
+        assert!(report.contains("free blocks:   2, 4-5\n"));
+        assert!(report.contains("fragmentation: 1 free blocks in 1 holes below"));
+        assert!(report.contains("append-only:   no\n"));
+    }
+    #[test]
+    fn test_list_and_dump() {
+        let tmp_dir = tempfile::tempdir().unwrap();
+        let mut storage = new_storage(&tmp_dir);
+        let table = output(|out| list(&mut storage, out));
+        let rows: Vec<&str> = table.lines().collect();
+        assert_eq!(rows.len(), 4);
+        let fields: Vec<&str> = rows[2].split_whitespace().collect();
+        assert_eq!(
+            (fields[0], fields[1], fields[3], fields[4]),
+            ("1", "4", "-", "-")
+        );
+        let hex_dump = output(|out| dump(&mut storage, 0, out));
+        assert_eq!(
+            hex_dump.lines().nth(1).unwrap(),
+            format!("00000000  61 62{}  |ab|", " ".repeat(42))
+        );
+    }
+    #[test]
+    fn test_free_runs() {
+        assert_eq!(free_runs(&[0, 3, 4], 8), vec![(1, 2), (5, 3)]);
+        assert_eq!(free_runs(&[], 2), vec![(0, 2)]);
+        assert!(free_runs(&[0, 1], 2).is_empty());
+        assert_eq!(format_run(&(5, 3)), "5-7");
+    }
+}
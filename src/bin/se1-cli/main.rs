@@ -0,0 +1,303 @@
+//! se1-cli, command line tool to look into se1 storage files
+//! - run without arguments for usage
+use se1::storage::{Storage, StorageError};
+use std::fmt;
+use std::io::{self, Write};
+use std::process::ExitCode;
+
+mod fsck;
+mod inspect;
+mod migrate;
+mod upgrade;
+
+const USAGE: &str = "\
+usage: se1-cli <command> <file> [options]
+
+commands:
+  inspect <file>            storage header, block counts and fragmentation
+  list <file>               table of block headers of used blocks
+  dump <file> --block <N>   hex dump of data of block N
+  fsck <file> [--repair]    check file for damage, --repair truncates or zeroes it
+  repair <file> --free-list rebuild free blocks and allocation bitmap from block headers
+  migrate <file> <dst> --block-len <N>
+                            copy records to new file dst with block length N,
+                            writing old and new record ids to dst.map
+  upgrade <file> [--from <N>]
+                            rewrite file of an older format version in the current
+                            one, --from names the version of a file without magic
+";
+
+/// Failure of a command, printed to stderr
+#[derive(Debug)]
+enum CliError {
+    /// Arguments do not match any command, usage is printed with it
+    Usage(String),
+    Storage(StorageError),
+    Io(io::Error),
+    /// File can not be handled by the command
+    Unsupported(String),
+    /// fsck found damage it did not repair
+    Damaged {
+        issue_count: usize,
+    },
+}
+
+impl fmt::Display for CliError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CliError::Usage(message) => write!(f, "{}\n\n{}", message, USAGE),
+            CliError::Storage(error) => write!(f, "{}", error),
+            CliError::Io(error) => write!(f, "{}", error),
+            CliError::Unsupported(message) => write!(f, "{}", message),
+            CliError::Damaged { issue_count } => write!(
+                f,
+                "{} problems found, run fsck with --repair to fix them",
+                issue_count
+            ),
+        }
+    }
+}
+
+impl From<StorageError> for CliError {
+    fn from(error: StorageError) -> Self {
+        CliError::Storage(error)
+    }
+}
+
+impl From<io::Error> for CliError {
+    fn from(error: io::Error) -> Self {
+        CliError::Io(error)
+    }
+}
+
+/// Parsed command line
+#[derive(Debug, PartialEq)]
+enum Command {
+    Inspect {
+        file_path: String,
+    },
+    List {
+        file_path: String,
+    },
+    Dump {
+        file_path: String,
+        block_index: u64,
+    },
+    Fsck {
+        file_path: String,
+        repair: bool,
+    },
+    RepairFreeList {
+        file_path: String,
+    },
+    Migrate {
+        file_path: String,
+        dst_path: String,
+        block_len: u64,
+    },
+    Upgrade {
+        file_path: String,
+        from_version: Option<u16>,
+    },
+}
+
+impl Command {
+    /// Parse arguments after the program name
+    fn parse(args: &[String]) -> Result<Command, CliError> {
+        let (command, file_path, options) = match args {
+            [command, file_path, options @ ..] => (command.as_str(), file_path.clone(), options),
+            [] => return Err(CliError::Usage("missing command".to_string())),
+            [command] => return Err(CliError::Usage(format!("{}: missing file", command))),
+        };
+        match (command, options) {
+            ("inspect", []) => Ok(Command::Inspect { file_path }),
+            ("list", []) => Ok(Command::List { file_path }),
+            ("dump", [flag, block_index]) if flag == "--block" => {
+                let block_index = block_index.parse().map_err(|_| {
+                    CliError::Usage(format!("dump: invalid block index {}", block_index))
+                })?;
+                Ok(Command::Dump {
+                    file_path,
+                    block_index,
+                })
+            }
+            ("dump", _) => Err(CliError::Usage("dump: expected --block <N>".to_string())),
+            ("fsck", []) => Ok(Command::Fsck {
+                file_path,
+                repair: false,
+            }),
+            ("fsck", [flag]) if flag == "--repair" => Ok(Command::Fsck {
+                file_path,
+                repair: true,
+            }),
+            ("repair", [flag]) if flag == "--free-list" => {
+                Ok(Command::RepairFreeList { file_path })
+            }
+            ("repair", _) => Err(CliError::Usage("repair: expected --free-list".to_string())),
+            ("migrate", [dst_path, flag, block_len]) if flag == "--block-len" => {
+                let block_len = block_len.parse().map_err(|_| {
+                    CliError::Usage(format!("migrate: invalid block length {}", block_len))
+                })?;
+                Ok(Command::Migrate {
+                    file_path,
+                    dst_path: dst_path.clone(),
+                    block_len,
+                })
+            }
+            ("migrate", _) => Err(CliError::Usage(
+                "migrate: expected <dst> --block-len <N>".to_string(),
+            )),
+            ("upgrade", []) => Ok(Command::Upgrade {
+                file_path,
+                from_version: None,
+            }),
+            ("upgrade", [flag, from_version]) if flag == "--from" => {
+                let from_version = from_version.parse().map_err(|_| {
+                    CliError::Usage(format!("upgrade: invalid format version {}", from_version))
+                })?;
+                Ok(Command::Upgrade {
+                    file_path,
+                    from_version: Some(from_version),
+                })
+            }
+            ("upgrade", _) => Err(CliError::Usage("upgrade: expected --from <N>".to_string())),
+            ("inspect", _) | ("list", _) | ("fsck", _) => Err(CliError::Usage(format!(
+                "{}: unexpected argument {}",
+                command, options[0]
+            ))),
+            _ => Err(CliError::Usage(format!("unknown command {}", command))),
+        }
+    }
+    /// Open storage file and run command, writing its report to out
+    /// - file is closed cleanly afterwards, even if the command failed
+    /// - fsck and upgrade do not open the file as Storage, see Storage::fsck and
+    ///   Storage::upgrade
+    fn run(&self, out: &mut dyn Write) -> Result<(), CliError> {
+        let file_path = match self {
+            Command::Inspect { file_path }
+            | Command::List { file_path }
+            | Command::Dump { file_path, .. }
+            | Command::Fsck { file_path, .. }
+            | Command::RepairFreeList { file_path }
+            | Command::Migrate { file_path, .. }
+            | Command::Upgrade { file_path, .. } => file_path,
+        };
+        // - Storage::open creates missing files
+        if !std::path::Path::new(file_path).is_file() {
+            let message = format!("{}: no such file", file_path);
+            return Err(CliError::Io(io::Error::new(
+                io::ErrorKind::NotFound,
+                message,
+            )));
+        }
+        if let Command::Fsck { repair, .. } = self {
+            return fsck::fsck(file_path, *repair, out);
+        }
+        if let Command::Upgrade { from_version, .. } = self {
+            return upgrade::upgrade(file_path, *from_version, out);
+        }
+        let mut storage = Storage::open(file_path.clone())?;
+        let result = match self {
+            Command::Inspect { .. } => inspect::inspect(&mut storage, out),
+            Command::List { .. } => inspect::list(&mut storage, out),
+            Command::Dump { block_index, .. } => inspect::dump(&mut storage, *block_index, out),
+            Command::RepairFreeList { .. } => fsck::repair_free_list(&mut storage, out),
+            Command::Migrate {
+                dst_path,
+                block_len,
+                ..
+            } => migrate::migrate(&mut storage, dst_path, *block_len, out),
+            Command::Fsck { .. } | Command::Upgrade { .. } => Ok(()),
+        };
+        storage.close()?;
+        result
+    }
+}
+
+fn main() -> ExitCode {
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    let stdout = io::stdout();
+    let result = Command::parse(&args).and_then(|command| command.run(&mut stdout.lock()));
+    match result {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(error) => {
+            eprintln!("se1-cli: {}", error);
+            ExitCode::FAILURE
+        }
+    }
+}
+
+#[cfg(test)]
+mod unit_tests_cli {
+    use super::*;
+
+    fn args(args: &[&str]) -> Vec<String> {
+        args.iter().map(|arg| arg.to_string()).collect()
+    }
+
+    #[test]
+    fn test_parse() {
+        assert_eq!(
+            Command::parse(&args(&["inspect", "a.hex"])).unwrap(),
+            Command::Inspect {
+                file_path: "a.hex".to_string()
+            }
+        );
+        assert_eq!(
+            Command::parse(&args(&["dump", "a.hex", "--block", "7"])).unwrap(),
+            Command::Dump {
+                file_path: "a.hex".to_string(),
+                block_index: 7
+            }
+        );
+        assert_eq!(
+            Command::parse(&args(&["fsck", "a.hex", "--repair"])).unwrap(),
+            Command::Fsck {
+                file_path: "a.hex".to_string(),
+                repair: true
+            }
+        );
+        assert_eq!(
+            Command::parse(&args(&["repair", "a.hex", "--free-list"])).unwrap(),
+            Command::RepairFreeList {
+                file_path: "a.hex".to_string()
+            }
+        );
+        assert_eq!(
+            Command::parse(&args(&["migrate", "a.hex", "b.hex", "--block-len", "64"])).unwrap(),
+            Command::Migrate {
+                file_path: "a.hex".to_string(),
+                dst_path: "b.hex".to_string(),
+                block_len: 64
+            }
+        );
+        assert_eq!(
+            Command::parse(&args(&["upgrade", "a.hex", "--from", "2"])).unwrap(),
+            Command::Upgrade {
+                file_path: "a.hex".to_string(),
+                from_version: Some(2)
+            }
+        );
+        for bad_args in [
+            &[][..],
+            &["list"][..],
+            &["list", "a.hex", "b.hex"][..],
+            &["dump", "a.hex"][..],
+            &["dump", "a.hex", "--block", "x"][..],
+            &["fsck", "a.hex", "--fix"][..],
+            &["repair", "a.hex"][..],
+            &["migrate", "a.hex", "b.hex"][..],
+            &["migrate", "a.hex", "b.hex", "--block-len", "-1"][..],
+            &["upgrade", "a.hex", "--from"][..],
+            &["upgrade", "a.hex", "--from", "x"][..],
+            &["format", "a.hex"][..],
+        ]
+        .iter()
+        {
+            assert!(matches!(
+                Command::parse(&args(bad_args)),
+                Err(CliError::Usage(_))
+            ));
+        }
+    }
+}
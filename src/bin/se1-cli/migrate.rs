@@ -0,0 +1,127 @@
+//! migrate command, rewriting a storage file with a different block length
+use super::CliError;
+use se1::storage::{BlockIndex, BlockStore, RecordId, Storage};
+use std::collections::HashSet;
+use std::io::{self, Write};
+
+/// Copy every record of storage to a new storage file at dst_path with block_len
+/// - records are read whole and written again, so they are chunked for the new block
+///   length, a single block is a record of one block
+/// - expiry of each record and compression and append-only settings are kept
+/// - records get new ids, old and new id of each record are written to `<dst_path>.map`,
+///   one `old new` line per record in order of old id
+/// - dst_path and the map file must not exist yet
+pub fn migrate(
+    storage: &mut Storage,
+    dst_path: &str,
+    block_len: u64,
+    out: &mut dyn Write,
+) -> Result<(), CliError> {
+    if storage.is_encrypted() {
+        return Err(CliError::Unsupported(
+            "migrate: encrypted files can not be read without their key".to_string(),
+        ));
+    }
+    let map_path = format!("{}.map", dst_path);
+    for path in [dst_path, map_path.as_str()].iter() {
+        if std::path::Path::new(path).exists() {
+            let message = format!("{}: file exists", path);
+            return Err(CliError::Io(io::Error::new(
+                io::ErrorKind::AlreadyExists,
+                message,
+            )));
+        }
+    }
+    let records = record_heads(storage)?;
+    let mut target = Storage::new(dst_path.to_string(), block_len)?;
+    target.set_compression(storage.compression())?;
+    let mut remapping = String::new();
+    for (record_id, expires_at) in records.iter() {
+        let record_data = storage.read_record(*record_id)?;
+        let new_record_id = target.write_record_expiring(&record_data, *expires_at)?;
+        remapping.push_str(&format!("{} {}\n", record_id, new_record_id));
+    }
+    if storage.is_append_only() {
+        target.enable_append_only()?;
+    }
+    let block_count = target.stats().block_count;
+    target.close()?;
+    std::fs::write(&map_path, remapping)?;
+    writeln!(
+        out,
+        "migrated {} records from block length {} to {} ({} blocks)",
+        records.len(),
+        storage.block_len(),
+        block_len,
+        block_count
+    )?;
+    writeln!(out, "index remapping written to {}", map_path)?;
+    Ok(())
+}
+
+/// First block and expiry of every record, in block index order
+/// - first blocks are used blocks no other block links to
+fn record_heads(storage: &mut Storage) -> Result<Vec<(RecordId, Option<u64>)>, CliError> {
+    let mut blocks = Vec::new();
+    let mut linked_blocks: HashSet<BlockIndex> = HashSet::new();
+    for block in storage.iter_block_headers() {
+        let block = block?;
+        if let Some(next_block) = block.next_block {
+            linked_blocks.insert(next_block);
+        }
+        blocks.push((block.block_index, block.expires_at));
+    }
+    blocks.retain(|(block_index, _)| !linked_blocks.contains(block_index));
+    Ok(blocks)
+}
+
+#[cfg(test)]
+mod unit_tests_migrate {
+    use super::*;
+
+    #[test]
+    fn test_migrate() {
+        let tmp_dir = tempfile::tempdir().unwrap();
+        let src_path = tmp_dir.path().join("src.hex");
+        let dst_path = tmp_dir.path().join("dst.hex");
+        let dst_path = dst_path.to_str().unwrap().to_string();
+        let mut storage = Storage::new(src_path.to_str().unwrap().to_string(), 4).unwrap();
+        storage.write_block(1, &[1, 2]).unwrap();
+        let record_id = storage.write_record(&[3; 10]).unwrap();
+        storage.write_block_expiring(9, &[4], Some(99)).unwrap();
+        let mut out = Vec::new();
+        migrate(&mut storage, &dst_path, 8, &mut out).unwrap();
+        let remapping = std::fs::read_to_string(format!("{}.map", dst_path)).unwrap();
+        let remapping: std::collections::HashMap<u64, u64> = remapping
+            .lines()
+            .map(|line| {
+                let ids: Vec<u64> = line.split(' ').map(|id| id.parse().unwrap()).collect();
+                (ids[0], ids[1])
+            })
+            .collect();
+        assert_eq!(remapping.len(), 3);
+        let mut target = Storage::open(dst_path.clone()).unwrap();
+        assert_eq!(target.block_len(), 8);
+        assert_eq!(target.read_record(remapping[&1]).unwrap(), vec![1, 2]);
+        assert_eq!(
+            target.record_blocks(remapping[&record_id]).unwrap().len(),
+            2
+        );
+        assert_eq!(
+            target.read_record(remapping[&record_id]).unwrap(),
+            vec![3; 10]
+        );
+        let expiries: Vec<Option<u64>> = target
+            .iter_block_headers()
+            .map(|block| block.unwrap().expires_at)
+            .collect();
+        assert_eq!(expiries.iter().filter(|e| **e == Some(99)).count(), 1);
+        assert_eq!(target.read_record(remapping[&9]).unwrap(), vec![4]);
+        target.close().unwrap();
+        // - existing destination is not overwritten
+        assert!(matches!(
+            migrate(&mut storage, &dst_path, 8, &mut Vec::new()),
+            Err(CliError::Io(_))
+        ));
+    }
+}
@@ -0,0 +1,52 @@
+//! upgrade command, rewriting a storage file of an older format version in the current one
+use super::CliError;
+use se1::storage::{Storage, FORMAT_VERSION};
+use std::io::Write;
+
+/// Upgrade storage file at file_path to FORMAT_VERSION in place, see Storage::upgrade
+/// - from_version: format version of a file without magic, detected if None
+pub fn upgrade(
+    file_path: &str,
+    from_version: Option<u16>,
+    out: &mut dyn Write,
+) -> Result<(), CliError> {
+    let version = Storage::upgrade(file_path, from_version)?;
+    writeln!(out, "file:          {}", file_path)?;
+    if version == FORMAT_VERSION {
+        writeln!(
+            out,
+            "format version {} is current, file left alone",
+            version
+        )?;
+    } else {
+        writeln!(
+            out,
+            "upgraded from format version {} to {}",
+            version, FORMAT_VERSION
+        )?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod unit_tests_upgrade {
+    use super::*;
+
+    #[test]
+    fn test_upgrade() {
+        let tmp_dir = tempfile::tempdir().unwrap();
+        let file_path = tmp_dir.path().join("version_1.hex");
+        std::fs::copy("tests/samples/storage_upgrade/version_1.hex", &file_path).unwrap();
+        let file_path = file_path.to_str().unwrap().to_string();
+        let mut out = Vec::new();
+        upgrade(&file_path, None, &mut out).unwrap();
+        let out = String::from_utf8(out).unwrap();
+        assert!(out.contains(&format!("from format version 1 to {}", FORMAT_VERSION)));
+        let mut storage = Storage::open(file_path.clone()).unwrap();
+        assert_eq!(storage.read_block(2).unwrap().1, vec![17, 18, 19, 20]);
+        storage.close().unwrap();
+        let mut out = Vec::new();
+        upgrade(&file_path, None, &mut out).unwrap();
+        assert!(String::from_utf8(out).unwrap().contains("is current"));
+    }
+}
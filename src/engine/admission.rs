@@ -0,0 +1,250 @@
+//! Admission control, a limit on requests and bytes queued in an engine, see
+//! Engine::set_admission_limit
+use super::*;
+use std::sync::{Condvar, Mutex};
+use std::time::Duration;
+
+/// Most requests and bytes an engine queues, past it requests fail with
+/// StorageError::Overloaded instead of growing the queue
+/// - a request is queued from the time it is appended or sent until its result is sent,
+///   or until it is dropped without one
+/// - bytes are the data of write requests, other requests count 0 bytes
+/// - a write larger than max_bytes alone is only admitted into an empty queue
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct AdmissionLimit {
+    max_requests: Option<usize>,
+    max_bytes: Option<u64>,
+    /// Longest time EngineHandle waits for room in the queue before rejecting a request
+    wait: Duration,
+}
+
+impl AdmissionLimit {
+    /// No limit, set one with max_requests or max_bytes
+    pub fn new() -> Self {
+        AdmissionLimit::default()
+    }
+    /// Queue at most max_requests requests
+    pub fn max_requests(mut self, max_requests: usize) -> Self {
+        self.max_requests = Some(max_requests);
+        self
+    }
+    /// Queue at most max_bytes bytes of write data
+    pub fn max_bytes(mut self, max_bytes: u64) -> Self {
+        self.max_bytes = Some(max_bytes);
+        self
+    }
+    /// Let EngineHandle block the sending thread for up to wait until the queue has room,
+    /// instead of rejecting right away
+    /// - default is zero, Engine::append_request never waits, nothing would make room
+    /// - blocks the thread, also for AsyncEngine, keep it zero to reject under async
+    pub fn wait(mut self, wait: Duration) -> Self {
+        self.wait = wait;
+        self
+    }
+}
+
+/// Requests and bytes queued in an engine
+#[derive(Default)]
+struct Queued {
+    requests: usize,
+    bytes: u64,
+}
+
+/// Admission limit of an engine and what it queued, shared by Engine and EngineHandle
+pub(super) struct Admission {
+    limit: AdmissionLimit,
+    queued: Mutex<Queued>,
+    /// Notified whenever a request leaves the queue
+    room: Condvar,
+}
+
+/// Place of a request in the queue, given back when dropped
+struct Permit {
+    admission: Arc<Admission>,
+    bytes: u64,
+}
+
+impl Drop for Permit {
+    fn drop(&mut self) {
+        let mut queued = self.admission.queued();
+        queued.requests = queued.requests.saturating_sub(1);
+        queued.bytes = queued.bytes.saturating_sub(self.bytes);
+        self.admission.room.notify_all();
+    }
+}
+
+impl Admission {
+    pub(super) fn new(limit: AdmissionLimit) -> Self {
+        Admission {
+            limit,
+            queued: Mutex::default(),
+            room: Condvar::new(),
+        }
+    }
+    fn queued(&self) -> std::sync::MutexGuard<'_, Queued> {
+        match self.queued.lock() {
+            Ok(queued) => queued,
+            Err(poisoned) => poisoned.into_inner(),
+        }
+    }
+    fn has_room(&self, queued: &Queued, bytes: u64) -> bool {
+        if queued.requests == 0 {
+            return true;
+        }
+        let requests_fit = self
+            .limit
+            .max_requests
+            .is_none_or(|max_requests| queued.requests < max_requests);
+        let bytes_fit = self
+            .limit
+            .max_bytes
+            .is_none_or(|max_bytes| queued.bytes.saturating_add(bytes) <= max_bytes);
+        requests_fit && bytes_fit
+    }
+    /// Queue request, waiting for room up to AdmissionLimit::wait if wait is set
+    /// - returns: request holding its place in the queue, or request back if it was not
+    ///   admitted
+    fn admit(self: &Arc<Self>, request: IORequest, wait: bool) -> Result<IORequest, IORequest> {
+        let bytes = request.data_len() as u64;
+        let deadline = Instant::now()
+            + if wait {
+                self.limit.wait
+            } else {
+                Duration::ZERO
+            };
+        let mut queued = self.queued();
+        while !self.has_room(&queued, bytes) {
+            let timeout = match deadline.checked_duration_since(Instant::now()) {
+                Some(timeout) if !timeout.is_zero() => timeout,
+                _ => return Err(request),
+            };
+            queued = match self.room.wait_timeout(queued, timeout) {
+                Ok((queued, _)) => queued,
+                Err(poisoned) => poisoned.into_inner().0,
+            };
+        }
+        queued.requests += 1;
+        queued.bytes = queued.bytes.saturating_add(bytes);
+        drop(queued);
+        Ok(request.map_sender(&mut Some(Permit {
+            admission: self.clone(),
+            bytes,
+        })))
+    }
+}
+
+/// Keep permit until result is sent, or dropped with result
+impl MapSender for Option<Permit> {
+    fn map<T: RequestResult>(&mut self, _: OpKind, result: ResultSender<T>) -> ResultSender<T> {
+        let permit = self.take();
+        result.inspect(move |_| drop(permit))
+    }
+}
+
+impl IORequest {
+    /// Queue request within admission, if any, else fail it with Overloaded
+    /// - wait: wait for room up to AdmissionLimit::wait
+    /// - returns: request to queue, None if it was rejected
+    pub(super) fn admitted(
+        self,
+        admission: Option<&Arc<Admission>>,
+        metrics: &EngineMetrics,
+        wait: bool,
+    ) -> Option<IORequest> {
+        let admission = match admission {
+            Some(admission) => admission,
+            None => return Some(self),
+        };
+        match admission.admit(self, wait) {
+            Ok(request) => Some(request),
+            Err(request) => {
+                metrics.observe_overloaded();
+                request.fail(StorageError::Overloaded);
+                None
+            }
+        }
+    }
+}
+
+impl Engine {
+    /// Limit requests and bytes queued, see AdmissionLimit
+    /// - append_request fails requests past the limit with StorageError::Overloaded right
+    ///   away, EngineHandle first waits up to AdmissionLimit::wait for room
+    /// - rejected requests are counted in EngineMetrics::overloaded
+    /// - set it before queueing requests and before spawn_engine, requests queued before
+    ///   do not count
+    /// - None queues every request, the default
+    pub fn set_admission_limit(&mut self, limit: Option<AdmissionLimit>) {
+        self.admission = limit.map(|limit| Arc::new(Admission::new(limit)));
+    }
+}
+
+#[cfg(test)]
+mod unit_tests_admission {
+    use super::*;
+
+    fn new_storage(tmp_dir: &tempfile::TempDir) -> Storage {
+        let file_path = tmp_dir.path().join("admission.hex");
+        Storage::new(file_path.to_str().unwrap().to_string(), 4).unwrap()
+    }
+    fn write(engine: &mut Engine, data: Vec<u8>) -> ResultReceiver<WriteResponse> {
+        let (result, receiver) = ResultSender::channel();
+        engine.append_request(IORequest::Write {
+            data,
+            expires_at: None,
+            result,
+        });
+        receiver
+    }
+
+    #[test]
+    fn test_admission_limit() {
+        let tmp_dir = tempfile::tempdir().unwrap();
+        let mut engine = Engine::new(new_storage(&tmp_dir));
+        engine.set_admission_limit(Some(AdmissionLimit::new().max_requests(2).max_bytes(8)));
+        let metrics = engine.metrics();
+        // - a write larger than max_bytes is admitted into an empty queue
+        let large = write(&mut engine, vec![1; 9]);
+        let rejected = write(&mut engine, vec![2]);
+        assert!(matches!(
+            rejected.try_recv().unwrap(),
+            Err(StorageError::Overloaded)
+        ));
+        engine.io_cycle().unwrap();
+        assert!(large.try_recv().unwrap().is_ok());
+        // - request count, then bytes
+        let first = write(&mut engine, vec![3; 4]);
+        let second = write(&mut engine, vec![4; 4]);
+        let third = write(&mut engine, vec![5]);
+        assert!(matches!(
+            third.try_recv(),
+            Ok(Err(StorageError::Overloaded))
+        ));
+        engine.io_cycle().unwrap();
+        assert!(first.try_recv().unwrap().is_ok() && second.try_recv().unwrap().is_ok());
+        write(&mut engine, vec![6; 5]);
+        let too_many_bytes = write(&mut engine, vec![7; 4]);
+        assert!(matches!(
+            too_many_bytes.try_recv(),
+            Ok(Err(StorageError::Overloaded))
+        ));
+        assert_eq!(metrics.overloaded(), 3);
+    }
+    #[test]
+    fn test_handle_waits_for_room() {
+        let tmp_dir = tempfile::tempdir().unwrap();
+        let mut engine = Engine::new(new_storage(&tmp_dir));
+        let limit = AdmissionLimit::new()
+            .max_requests(1)
+            .wait(Duration::from_secs(10));
+        engine.set_admission_limit(Some(limit));
+        let handle = engine.spawn_engine();
+        // - every write waits for the one before to be served
+        let receivers: Vec<_> = (0..4).map(|n| handle.write(vec![n + 1])).collect();
+        for receiver in receivers {
+            assert!(receiver.recv().unwrap().is_ok());
+        }
+        assert_eq!(handle.metrics().overloaded(), 0);
+        handle.join().unwrap();
+    }
+}
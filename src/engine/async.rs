@@ -0,0 +1,139 @@
+use super::*;
+use tokio::sync::oneshot;
+
+/// Async client of an Engine running on a background thread
+/// - every method queues a request and awaits its result on a tokio oneshot channel,
+///   without blocking the calling thread
+/// - works with any async runtime, the Engine thread is not part of the runtime
+/// - dropping it waits for background thread to serve pending requests, like EngineHandle
+pub struct AsyncEngine {
+    handle: EngineHandle,
+}
+
+impl AsyncEngine {
+    /// Move storage to a background Engine thread, see Engine::spawn
+    pub fn spawn(storage: Storage) -> Self {
+        AsyncEngine::from(Engine::spawn(storage))
+    }
+    /// Read all data of a record
+    pub async fn read(&self, record_id: RecordId) -> Result<ReadResponse, StorageError> {
+        self.submit(RequestBuilder::read(record_id)).await
+    }
+    /// Read data of each block of a record separately
+    pub async fn read_blocks(&self, record_id: RecordId) -> Result<Vec<Vec<u8>>, StorageError> {
+        self.submit(RequestBuilder::read_blocks(record_id)).await
+    }
+    /// Write data as a new record
+    pub async fn write(&self, data: Vec<u8>) -> Result<WriteResponse, StorageError> {
+        self.write_expiring(data, None).await
+    }
+    /// Write data as a new record, expiring at expires_at, see EngineHandle::write_expiring
+    pub async fn write_expiring(
+        &self,
+        data: Vec<u8>,
+        expires_at: Option<u64>,
+    ) -> Result<WriteResponse, StorageError> {
+        self.submit(RequestBuilder::write(data, expires_at)).await
+    }
+    /// Delete all blocks of a record
+    /// - returns: number of deleted blocks
+    pub async fn delete(
+        &self,
+        record_id: RecordId,
+        hard_delete: bool,
+    ) -> Result<usize, StorageError> {
+        self.submit(RequestBuilder::delete(record_id, hard_delete))
+            .await
+    }
+    /// Read data of up to max_blocks used blocks at or after from_block, see
+    /// IORequest::ScanBlocks
+    pub async fn scan_blocks(
+        &self,
+        from_block: BlockIndex,
+        max_blocks: usize,
+    ) -> Result<Vec<(BlockIndex, Vec<u8>)>, StorageError> {
+        self.submit(RequestBuilder::scan_blocks(from_block, max_blocks))
+            .await
+    }
+    /// Serve request built by request, e.g. a traced one, see EngineHandle::submit
+    pub async fn submit<T: Send + 'static>(
+        &self,
+        request: RequestBuilder<T>,
+    ) -> Result<T, StorageError> {
+        let (result, receiver) = oneshot_result();
+        self.handle.send(request.build_with(result));
+        await_result(receiver).await
+    }
+    /// Counters, latencies and queue length of the background engine, see EngineMetrics
+    pub fn metrics(&self) -> Arc<EngineMetrics> {
+        self.handle.metrics()
+    }
+    /// Stop background thread after it served pending requests, see EngineHandle::join
+    pub fn join(self) -> Option<Storage> {
+        self.handle.join()
+    }
+    /// Stop background thread by policy, see EngineHandle::shutdown
+    pub fn shutdown(self, policy: DrainPolicy) -> Option<Storage> {
+        self.handle.shutdown(policy)
+    }
+}
+
+impl From<EngineHandle> for AsyncEngine {
+    fn from(handle: EngineHandle) -> Self {
+        AsyncEngine { handle }
+    }
+}
+
+type OneshotReceiver<T> = oneshot::Receiver<Result<T, StorageError>>;
+
+/// Result sender backed by a tokio oneshot channel
+/// - a result whose future was dropped is discarded, like ResultSender::channel
+fn oneshot_result<T: Send + 'static>() -> (ResultSender<T>, OneshotReceiver<T>) {
+    let (sender, receiver) = oneshot::channel();
+    let result_sender = ResultSender::new(move |result| {
+        if sender.send(result).is_err() {
+            dropped_result();
+        }
+    });
+    (result_sender, receiver)
+}
+
+/// Await result, a dropped sender means the request was never served
+async fn await_result<T>(receiver: OneshotReceiver<T>) -> Result<T, StorageError> {
+    receiver.await.unwrap_or(Err(StorageError::EngineStopped))
+}
+
+#[cfg(test)]
+mod unit_tests_async_engine {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_async_engine() {
+        let tmp_dir = tempfile::tempdir().unwrap();
+        let file_path = tmp_dir.path().join("async_engine.hex");
+        let storage = Storage::new(file_path.to_str().unwrap().to_string(), 4).unwrap();
+        let engine = AsyncEngine::spawn(storage);
+        let record_id = engine.write(vec![7u8; 9]).await.unwrap().record_id;
+        assert_eq!(engine.read(record_id).await.unwrap().data, vec![7u8; 9]);
+        assert_eq!(engine.read_blocks(record_id).await.unwrap().len(), 3);
+        assert!(matches!(
+            engine.write(vec![]).await,
+            Err(StorageError::EmptyRecord)
+        ));
+        let blocks = engine.scan_blocks(1, 2).await.unwrap();
+        assert_eq!(blocks.len(), 2);
+        assert_eq!(blocks[0], (1, vec![7u8; 4]));
+        assert_eq!(engine.delete(record_id, true).await.unwrap(), 3);
+        assert_eq!(engine.read(record_id).await.unwrap().data, Vec::<u8>::new());
+    }
+    #[tokio::test]
+    async fn test_engine_stopped() {
+        let (result, receiver) = oneshot_result::<usize>();
+        // request dropped without being served
+        drop(result);
+        assert!(matches!(
+            await_result(receiver).await,
+            Err(StorageError::EngineStopped)
+        ));
+    }
+}
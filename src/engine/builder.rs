@@ -0,0 +1,147 @@
+//! Builder of IORequest, creating the request together with the receiver of its result
+use super::*;
+
+/// Builds an IORequest of any kind and the receiver of its result, see build
+/// - start with the constructor of the operation, e.g. RequestBuilder::write, then
+///   address it with on or trace it with traced
+/// - T is the result type of the operation, the same as in the IORequest variant
+pub struct RequestBuilder<T> {
+    request: Box<dyn FnOnce(ResultSender<T>) -> IORequest + Send>,
+    storage: Option<String>,
+    trace_id: Option<String>,
+}
+
+impl RequestBuilder<ReadResponse> {
+    /// Read all data of a record, see IORequest::Read
+    pub fn read(record_id: RecordId) -> Self {
+        RequestBuilder::new(move |result| IORequest::Read { record_id, result })
+    }
+}
+
+impl RequestBuilder<Vec<Vec<u8>>> {
+    /// Read data of each block of a record separately, see IORequest::ReadBlocks
+    pub fn read_blocks(record_id: RecordId) -> Self {
+        RequestBuilder::new(move |result| IORequest::ReadBlocks { record_id, result })
+    }
+}
+
+impl RequestBuilder<WriteResponse> {
+    /// Write data as a new record, expiring at expires_at, see IORequest::Write
+    pub fn write(data: Vec<u8>, expires_at: Option<u64>) -> Self {
+        RequestBuilder::new(move |result| IORequest::Write {
+            data,
+            expires_at,
+            result,
+        })
+    }
+}
+
+impl RequestBuilder<usize> {
+    /// Delete all blocks of a record, see IORequest::Delete
+    pub fn delete(record_id: RecordId, hard_delete: bool) -> Self {
+        RequestBuilder::new(move |result| IORequest::Delete {
+            record_id,
+            hard_delete,
+            result,
+        })
+    }
+}
+
+impl RequestBuilder<Vec<(BlockIndex, Vec<u8>)>> {
+    /// Read up to max_blocks used blocks at or after from_block, see IORequest::ScanBlocks
+    pub fn scan_blocks(from_block: BlockIndex, max_blocks: usize) -> Self {
+        RequestBuilder::new(move |result| IORequest::ScanBlocks {
+            from_block,
+            max_blocks,
+            result,
+        })
+    }
+}
+
+impl RequestBuilder<HashMap<BlockIndex, BlockIndex>> {
+    /// Move up to max_moves blocks, see IORequest::CompactStep
+    pub fn compact_step(max_moves: usize) -> Self {
+        RequestBuilder::new(move |result| IORequest::CompactStep { max_moves, result })
+    }
+}
+
+impl RequestBuilder<Committed> {
+    /// Apply ops together, see IORequest::Commit
+    pub fn commit(ops: Vec<TxnOp>) -> Self {
+        RequestBuilder::new(move |result| IORequest::Commit { ops, result })
+    }
+}
+
+impl<T: Send + 'static> RequestBuilder<T> {
+    fn new(request: impl FnOnce(ResultSender<T>) -> IORequest + Send + 'static) -> Self {
+        RequestBuilder {
+            request: Box::new(request),
+            storage: None,
+            trace_id: None,
+        }
+    }
+    /// Address request to the storage attached under name, see IORequest::on
+    pub fn on(mut self, name: impl Into<String>) -> Self {
+        self.storage = Some(name.into());
+        self
+    }
+    /// Carry trace_id, see IORequest::traced
+    pub fn traced(mut self, trace_id: impl Into<String>) -> Self {
+        self.trace_id = Some(trace_id.into());
+        self
+    }
+    /// Request sending its result to result
+    pub fn build_with(self, result: ResultSender<T>) -> IORequest {
+        let mut request = (self.request)(result);
+        if let Some(trace_id) = self.trace_id {
+            request = request.traced(trace_id);
+        }
+        match self.storage {
+            Some(name) => request.on(name),
+            None => request,
+        }
+    }
+    /// Request and receiver of its result, on a std mpsc channel, see ResultSender::channel
+    pub fn build(self) -> (IORequest, ResultReceiver<T>) {
+        let (result, receiver) = ResultSender::channel();
+        (self.build_with(result), receiver)
+    }
+}
+
+#[cfg(test)]
+mod unit_tests_builder {
+    use super::*;
+
+    #[test]
+    fn test_request_builder() {
+        let tmp_dir = tempfile::tempdir().unwrap();
+        let file_path = tmp_dir.path().join("builder.hex");
+        let storage = Storage::new(file_path.to_str().unwrap().to_string(), 4).unwrap();
+        let mut engine = Engine::new(storage);
+        let events_path = tmp_dir.path().join("events.hex");
+        let events = Storage::new(events_path.to_str().unwrap().to_string(), 4).unwrap();
+        engine.attach("events", events);
+        let (write, write_receiver) = RequestBuilder::write(vec![1; 6], None)
+            .on("events")
+            .traced("write")
+            .build();
+        assert_eq!(write.trace_id(), Some("write"));
+        assert_eq!(write.storage_name(), Some("events"));
+        engine.append_request(write);
+        let (read, read_receiver) = RequestBuilder::read_blocks(0).on("events").build();
+        engine.append_request(read);
+        let (scan, scan_receiver) = RequestBuilder::scan_blocks(0, 8).build();
+        engine.append_request(scan);
+        assert_eq!(engine.io_cycle().unwrap(), 3);
+        assert_eq!(write_receiver.recv().unwrap().unwrap().record_id, 0);
+        assert_eq!(
+            read_receiver.recv().unwrap().unwrap(),
+            vec![vec![1; 4], vec![1; 2]]
+        );
+        assert!(scan_receiver.recv().unwrap().unwrap().is_empty());
+        let (delete, delete_receiver) = RequestBuilder::delete(0, true).on("events").build();
+        engine.append_request(delete);
+        engine.io_cycle().unwrap();
+        assert_eq!(delete_receiver.recv().unwrap().unwrap(), 2);
+    }
+}
@@ -0,0 +1,307 @@
+//! Change data capture, events of block changes sent to subscribers after each cycle,
+//! see Engine::subscribe
+use super::*;
+use crate::storage::BlockObserver;
+use std::sync::{Mutex, Weak};
+
+/// Change to the storage an engine was created with, sent to subscribers
+/// - trace_id is the id of the traced request that made the change, see
+///   IORequest::traced, None for untraced requests and changes made outside requests
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ChangeEvent {
+    /// Block was written with len bytes of data
+    BlockWritten {
+        index: BlockIndex,
+        len: usize,
+        trace_id: Option<String>,
+    },
+    /// Block was deleted, see BlockObserver::after_delete
+    BlockDeleted {
+        index: BlockIndex,
+        hard: bool,
+        trace_id: Option<String>,
+    },
+    /// A compaction step moved blocks, remap maps old to new index of every moved block
+    /// - sent after the BlockWritten and BlockDeleted events of the moves
+    Compacted {
+        remap: HashMap<BlockIndex, BlockIndex>,
+        trace_id: Option<String>,
+    },
+}
+
+impl ChangeEvent {
+    /// Trace id of the request that made the change, see ChangeEvent
+    pub fn trace_id(&self) -> Option<&str> {
+        match self {
+            ChangeEvent::BlockWritten { trace_id, .. }
+            | ChangeEvent::BlockDeleted { trace_id, .. }
+            | ChangeEvent::Compacted { trace_id, .. } => trace_id.as_deref(),
+        }
+    }
+}
+
+/// Subscribers and events of the cycle not sent yet, shared by Engine and EngineHandle
+#[derive(Default)]
+struct FeedState {
+    subscribers: Vec<mpsc::Sender<ChangeEvent>>,
+    events: Vec<ChangeEvent>,
+    /// Trace id of the request being served, given to the events it records
+    trace_id: Option<String>,
+    /// ChangeCollector was added to the storage
+    observing: bool,
+}
+
+/// Change events of an Engine, see Engine::subscribe
+#[derive(Clone, Default)]
+pub(super) struct ChangeFeed(Arc<Mutex<FeedState>>);
+
+impl ChangeFeed {
+    fn state(&self) -> std::sync::MutexGuard<'_, FeedState> {
+        match self.0.lock() {
+            Ok(state) => state,
+            Err(poisoned) => poisoned.into_inner(),
+        }
+    }
+    /// New subscriber, receiving the events of cycles starting after this call
+    pub(super) fn subscribe(&self) -> mpsc::Receiver<ChangeEvent> {
+        let (sender, receiver) = mpsc::channel();
+        self.state().subscribers.push(sender);
+        receiver
+    }
+    /// Collect events of storage once there are subscribers, called at the start of a
+    /// cycle
+    fn observe(&self, storage: &mut Storage) {
+        let mut state = self.state();
+        if state.observing || state.subscribers.is_empty() {
+            return;
+        }
+        state.observing = true;
+        storage.add_observer(Box::new(ChangeCollector {
+            feed: Arc::downgrade(&self.0),
+        }));
+    }
+    /// Note trace id of the request being served, None once it was served
+    pub(super) fn set_trace_id(&self, trace_id: Option<&str>) {
+        self.state().trace_id = trace_id.map(str::to_string);
+    }
+    /// Record event of the request being served, made by event with its trace id
+    fn record(&self, event: impl FnOnce(Option<String>) -> ChangeEvent) {
+        let mut state = self.state();
+        if !state.subscribers.is_empty() {
+            let event = event(state.trace_id.clone());
+            state.events.push(event);
+        }
+    }
+    /// Send events collected so far to every subscriber, dropping subscribers whose
+    /// receiver is gone
+    fn publish(&self) {
+        let mut state = self.state();
+        let events = std::mem::take(&mut state.events);
+        if events.is_empty() {
+            return;
+        }
+        state.subscribers.retain(|subscriber| {
+            events
+                .iter()
+                .all(|event| subscriber.send(event.clone()).is_ok())
+        });
+    }
+}
+
+/// Observer recording block writes and deletes of the default storage in a ChangeFeed
+/// - does nothing once the engine is gone
+struct ChangeCollector {
+    feed: Weak<Mutex<FeedState>>,
+}
+
+impl ChangeCollector {
+    fn record(&self, event: impl FnOnce(Option<String>) -> ChangeEvent) {
+        if let Some(feed) = self.feed.upgrade() {
+            ChangeFeed(feed).record(event);
+        }
+    }
+}
+
+impl BlockObserver for ChangeCollector {
+    fn after_write(&mut self, block_index: BlockIndex, data: &[u8]) {
+        let len = data.len();
+        self.record(|trace_id| ChangeEvent::BlockWritten {
+            index: block_index,
+            len,
+            trace_id,
+        });
+    }
+    fn after_delete(&mut self, block_index: BlockIndex, hard_delete: bool) {
+        self.record(|trace_id| ChangeEvent::BlockDeleted {
+            index: block_index,
+            hard: hard_delete,
+            trace_id,
+        });
+    }
+}
+
+/// Records the remap of compaction steps in a ChangeFeed
+impl MapSender for ChangeFeed {
+    fn map<T: RequestResult>(&mut self, kind: OpKind, result: ResultSender<T>) -> ResultSender<T> {
+        if kind != OpKind::CompactStep {
+            return result;
+        }
+        let feed = self.clone();
+        result.inspect(move |served| {
+            if let Some(remap) = served.as_ref().ok().and_then(|value| value.remap()) {
+                if !remap.is_empty() {
+                    feed.record(|trace_id| ChangeEvent::Compacted {
+                        remap: remap.clone(),
+                        trace_id,
+                    });
+                }
+            }
+        })
+    }
+}
+
+impl IORequest {
+    /// Record the remap of a compaction step of the default storage in feed
+    pub(super) fn captured(self, feed: &ChangeFeed) -> IORequest {
+        self.map_sender(&mut feed.clone())
+    }
+}
+
+impl Engine {
+    /// Receive a ChangeEvent for every block written or deleted, and every compaction
+    /// step, of the storage the engine was created with
+    /// - events of a cycle are sent after the storage was flushed at its end, a cycle
+    ///   that failed to flush sends its events with the next cycle that flushes
+    /// - events are in the order of the changes, beginning with the next io_cycle
+    /// - changes of attached storages are not sent
+    /// - a subscriber whose receiver is dropped is removed
+    pub fn subscribe(&self) -> mpsc::Receiver<ChangeEvent> {
+        self.change_feed.subscribe()
+    }
+    /// Collect events of the cycle, if anyone subscribed
+    pub(super) fn capture_changes(&mut self) {
+        self.change_feed.observe(&mut self.storage);
+    }
+    /// Send events of the cycle to subscribers
+    /// - synced: default storage was flushed, or needs no flush
+    pub(super) fn publish_changes(&self, synced: bool) {
+        if synced {
+            self.change_feed.publish();
+        }
+    }
+}
+
+impl EngineHandle {
+    /// Receive change events of the background engine, see Engine::subscribe
+    pub fn subscribe(&self) -> mpsc::Receiver<ChangeEvent> {
+        self.change_feed.subscribe()
+    }
+}
+
+#[cfg(test)]
+mod unit_tests_cdc {
+    use super::*;
+
+    fn new_storage(tmp_dir: &tempfile::TempDir) -> Storage {
+        let file_path = tmp_dir.path().join("cdc.hex");
+        Storage::new(file_path.to_str().unwrap().to_string(), 4).unwrap()
+    }
+
+    #[test]
+    fn test_change_events() {
+        let tmp_dir = tempfile::tempdir().unwrap();
+        let mut engine = Engine::new(new_storage(&tmp_dir));
+        let events = engine.subscribe();
+        let (write_result, write_receiver) = ResultSender::channel();
+        engine.append_request(IORequest::Write {
+            data: vec![1; 6],
+            expires_at: None,
+            result: write_result,
+        });
+        engine.io_cycle().unwrap();
+        let record_id = write_receiver.recv().unwrap().unwrap().record_id;
+        // - blocks of a record are written last block first
+        let written: Vec<ChangeEvent> = events.try_iter().collect();
+        assert_eq!(
+            written,
+            vec![
+                ChangeEvent::BlockWritten {
+                    index: 1,
+                    len: 2,
+                    trace_id: None
+                },
+                ChangeEvent::BlockWritten {
+                    index: 0,
+                    len: 4,
+                    trace_id: None
+                },
+            ]
+        );
+        // - cycles without changes send nothing
+        engine.io_cycle().unwrap();
+        assert_eq!(events.try_iter().count(), 0);
+        // - delete and compaction, remap after the moves
+        engine.storage.write_block(3, &[3]).unwrap();
+        let (delete_result, _delete_receiver) = ResultSender::channel();
+        engine.append_request(IORequest::Delete {
+            record_id,
+            hard_delete: true,
+            result: delete_result,
+        });
+        let (compact_result, _compact_receiver) = ResultSender::channel();
+        engine.append_request(IORequest::CompactStep {
+            max_moves: 1,
+            result: compact_result,
+        });
+        engine.io_cycle().unwrap();
+        let changed: Vec<ChangeEvent> = events.try_iter().collect();
+        assert_eq!(
+            changed[0],
+            ChangeEvent::BlockWritten {
+                index: 3,
+                len: 1,
+                trace_id: None
+            }
+        );
+        assert_eq!(
+            changed[1],
+            ChangeEvent::BlockDeleted {
+                index: 0,
+                hard: true,
+                trace_id: None
+            }
+        );
+        assert_eq!(
+            changed.last(),
+            Some(&ChangeEvent::Compacted {
+                remap: vec![(3, 0)].into_iter().collect(),
+                trace_id: None
+            })
+        );
+    }
+    #[test]
+    fn test_subscribe_handle() {
+        let tmp_dir = tempfile::tempdir().unwrap();
+        let handle = Engine::spawn(new_storage(&tmp_dir));
+        let events = handle.subscribe();
+        let dropped = handle.subscribe();
+        drop(dropped);
+        handle.write(vec![1, 2]).recv().unwrap().unwrap();
+        assert_eq!(
+            events.recv().unwrap(),
+            ChangeEvent::BlockWritten {
+                index: 0,
+                len: 2,
+                trace_id: None
+            }
+        );
+        // - changes of a traced request carry its trace id
+        let (write, receiver) = RequestBuilder::write(vec![3], None)
+            .traced("write-3")
+            .build();
+        handle.send_with_priority(write, Priority::Normal);
+        receiver.recv().unwrap().unwrap();
+        assert_eq!(events.recv().unwrap().trace_id(), Some("write-3"));
+        handle.join().unwrap();
+    }
+}
@@ -0,0 +1,214 @@
+use super::*;
+use std::io;
+use std::time::Duration;
+
+/// Result of a write, delete or compaction held back until its storage is synced
+/// - called with the message of the failed sync, None once synced
+type Ack = Box<dyn FnOnce(Option<&str>) + Send>;
+
+/// Acks of a cycle, with the name of the storage they wait for, None for the default
+type AckSender = mpsc::Sender<(Option<String>, Ack)>;
+
+/// Group commit settings and acks of an Engine, see Engine::set_group_commit
+pub(super) struct GroupCommit {
+    /// Time the background thread waits for more requests before a cycle
+    max_latency: Duration,
+    /// Storages with syncs held back in the current cycle, and their durability mode
+    suspended: Vec<(Option<String>, DurabilityMode)>,
+    sender: AckSender,
+    receiver: mpsc::Receiver<(Option<String>, Ack)>,
+}
+
+impl GroupCommit {
+    fn new(max_latency: Duration) -> Self {
+        let (sender, receiver) = mpsc::channel();
+        GroupCommit {
+            max_latency,
+            suspended: Vec::new(),
+            sender,
+            receiver,
+        }
+    }
+    /// Hold back the result of request, if it is for a storage with syncs held back
+    pub(super) fn defer(&self, request: IORequest) -> IORequest {
+        let storage = request.storage_name().map(str::to_string);
+        if self.suspended.iter().any(|(name, _)| *name == storage) {
+            let acks = &self.sender;
+            request.map_sender(&mut Deferral { storage, acks })
+        } else {
+            request
+        }
+    }
+}
+
+/// Sends results of requests that modify storage as an Ack on acks, instead of right
+/// away, results of reads are sent right away
+/// - a successful result turns into an error if the sync fails
+struct Deferral<'a> {
+    storage: Option<String>,
+    acks: &'a AckSender,
+}
+
+impl MapSender for Deferral<'_> {
+    fn map<T: RequestResult>(&mut self, kind: OpKind, result: ResultSender<T>) -> ResultSender<T> {
+        if kind == OpKind::Read {
+            return result;
+        }
+        let storage = self.storage.clone();
+        let acks = self.acks.clone();
+        ResultSender::new(move |served| {
+            let ack: Ack = Box::new(move |sync_error| match (served, sync_error) {
+                (Ok(_), Some(message)) => result.send(Err(StorageError::Io {
+                    operation: "sync group commit",
+                    block_index: None,
+                    source: io::Error::other(message.to_string()),
+                })),
+                (served, _) => result.send(served),
+            });
+            let _ = acks.send((storage, ack));
+        })
+    }
+}
+
+impl Engine {
+    /// Sync writes of a cycle once, before sending any of their results (group commit)
+    /// - applies to storages whose durability mode is not Never: writes, deletes and
+    ///   compaction of an io_cycle are not synced one by one, but with one flush at the
+    ///   end of the cycle, and their results are sent after it
+    /// - reads are answered right away, a read may see a write that is not synced yet
+    /// - max_latency: time the background thread of spawn_engine waits for more
+    ///   requests after the first one arrives, to grow the batch, e.g. 2ms; zero
+    ///   batches only requests that already arrived
+    /// - if the sync fails, requests of the cycle get its error, although they were applied
+    /// - None disables group commit, the default
+    pub fn set_group_commit(&mut self, max_latency: Option<Duration>) {
+        self.group_commit = max_latency.map(GroupCommit::new);
+    }
+    /// Time to wait for more requests before a cycle, see set_group_commit
+    pub(super) fn batch_latency(&self) -> Duration {
+        let syncing = std::iter::once(&self.storage)
+            .chain(self.storages.values())
+            .any(|storage| storage.durability() != DurabilityMode::Never);
+        match &self.group_commit {
+            Some(group_commit) if syncing => group_commit.max_latency,
+            _ => Duration::ZERO,
+        }
+    }
+    /// Hold back syncs of every write for the cycle, on storages that sync
+    pub(super) fn suspend_syncs(&mut self) {
+        let group_commit = match self.group_commit.as_mut() {
+            Some(group_commit) => group_commit,
+            None => return,
+        };
+        for (name, storage) in std::iter::once((None, &mut self.storage)).chain(
+            self.storages
+                .iter_mut()
+                .map(|(name, storage)| (Some(name.clone()), storage)),
+        ) {
+            let mode = storage.durability();
+            if mode != DurabilityMode::Never {
+                storage.set_durability(DurabilityMode::Never);
+                group_commit.suspended.push((name, mode));
+            }
+        }
+    }
+    /// Give back durability modes taken by suspend_syncs
+    pub(super) fn resume_syncs(&mut self) {
+        let group_commit = match self.group_commit.as_mut() {
+            Some(group_commit) => group_commit,
+            None => return,
+        };
+        for (name, mode) in group_commit.suspended.drain(..) {
+            let storage = match name {
+                None => Some(&mut self.storage),
+                Some(name) => self.storages.get_mut(&name),
+            };
+            if let Some(storage) = storage {
+                storage.set_durability(mode);
+            }
+        }
+    }
+    /// Send results held back in the cycle, once their storages were flushed
+    /// - sync_errors: message of each storage that failed to flush, by name
+    pub(super) fn send_acks(&self, sync_errors: &[(Option<String>, String)]) {
+        let group_commit = match self.group_commit.as_ref() {
+            Some(group_commit) => group_commit,
+            None => return,
+        };
+        for (storage, ack) in group_commit.receiver.try_iter() {
+            let sync_error = sync_errors
+                .iter()
+                .find(|(name, _)| *name == storage)
+                .map(|(_, message)| message.as_str());
+            ack(sync_error);
+        }
+    }
+}
+
+#[cfg(test)]
+mod unit_tests_group_commit {
+    use super::*;
+    use std::sync::Mutex;
+
+    fn new_storage(tmp_dir: &tempfile::TempDir) -> Storage {
+        let file_path = tmp_dir.path().join("group_commit.hex");
+        Storage::new(file_path.to_str().unwrap().to_string(), 4).unwrap()
+    }
+
+    /// Result sender logging name, in the order results are sent
+    fn logged<T: Send + 'static>(log: &Arc<Mutex<Vec<String>>>, name: &str) -> ResultSender<T> {
+        let (log, name) = (log.clone(), name.to_string());
+        ResultSender::new(move |result: Result<T, StorageError>| {
+            assert!(result.is_ok());
+            log.lock().unwrap().push(name);
+        })
+    }
+
+    #[test]
+    fn test_group_commit() {
+        let tmp_dir = tempfile::tempdir().unwrap();
+        let mut storage = new_storage(&tmp_dir);
+        storage.set_durability(DurabilityMode::EveryWrite);
+        let mut engine = Engine::new(storage);
+        engine.set_group_commit(Some(Duration::ZERO));
+        let log = Arc::new(Mutex::new(Vec::new()));
+        for name in ["write 0", "write 1"].iter() {
+            engine.append_request(IORequest::Write {
+                data: vec![1; 5],
+                expires_at: None,
+                result: logged(&log, name),
+            });
+        }
+        engine.append_request(IORequest::Read {
+            record_id: 0,
+            result: logged(&log, "read"),
+        });
+        assert_eq!(engine.io_cycle().unwrap(), 3);
+        // - read is answered right away, writes after the sync at the end of the cycle
+        assert_eq!(*log.lock().unwrap(), vec!["read", "write 0", "write 1"]);
+        let mut storage = engine.into_storage();
+        assert_eq!(storage.durability(), DurabilityMode::EveryWrite);
+        assert_eq!(storage.read_record(2).unwrap(), vec![1; 5]);
+    }
+
+    #[test]
+    fn test_group_commit_spawned() {
+        let tmp_dir = tempfile::tempdir().unwrap();
+        let mut storage = new_storage(&tmp_dir);
+        storage.set_durability(DurabilityMode::EveryWrite);
+        let mut engine = Engine::new(storage);
+        engine.set_group_commit(Some(Duration::from_millis(2)));
+        assert_eq!(engine.batch_latency(), Duration::from_millis(2));
+        let handle = engine.spawn_engine();
+        let receivers: Vec<_> = (1..=4u8).map(|byte| handle.write(vec![byte; 3])).collect();
+        for receiver in receivers {
+            assert!(receiver.recv().unwrap().is_ok());
+        }
+        let storage = handle.join().unwrap();
+        // - no latency without a storage that syncs
+        let mut engine = Engine::new(storage);
+        engine.set_group_commit(Some(Duration::from_millis(2)));
+        engine.storage.set_durability(DurabilityMode::Never);
+        assert_eq!(engine.batch_latency(), Duration::ZERO);
+    }
+}
@@ -0,0 +1,673 @@
+//! Counters, latencies and queue length of an Engine, see Engine::metrics
+use super::*;
+use crate::storage::StoreStats;
+use std::collections::BTreeMap;
+use std::convert::TryFrom;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+/// Upper bounds of latency histogram buckets, in microseconds
+/// - a last bucket without upper bound counts every observation
+pub const LATENCY_BUCKETS_MICROS: [u64; 12] = [
+    10, 50, 100, 500, 1_000, 5_000, 10_000, 50_000, 100_000, 500_000, 1_000_000, 5_000_000,
+];
+
+/// Kind of served request, see EngineMetrics::served
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OpKind {
+    /// Read, ReadBlocks and ScanBlocks requests
+    Read,
+    /// Write requests
+    Write,
+    /// Delete requests
+    Delete,
+    /// CompactStep requests
+    CompactStep,
+}
+
+impl OpKind {
+    const ALL: [OpKind; 4] = [
+        OpKind::Read,
+        OpKind::Write,
+        OpKind::Delete,
+        OpKind::CompactStep,
+    ];
+    fn position(self) -> usize {
+        match self {
+            OpKind::Read => 0,
+            OpKind::Write => 1,
+            OpKind::Delete => 2,
+            OpKind::CompactStep => 3,
+        }
+    }
+    #[cfg(feature = "prometheus")]
+    fn label(self) -> &'static str {
+        match self {
+            OpKind::Read => "read",
+            OpKind::Write => "write",
+            OpKind::Delete => "delete",
+            OpKind::CompactStep => "compact_step",
+        }
+    }
+}
+
+/// Histogram of durations, with fixed buckets of LATENCY_BUCKETS_MICROS
+#[derive(Default)]
+pub struct LatencyHistogram {
+    /// Observations per bucket, not cumulative
+    buckets: [AtomicU64; LATENCY_BUCKETS_MICROS.len()],
+    count: AtomicU64,
+    sum_micros: AtomicU64,
+}
+
+impl LatencyHistogram {
+    fn observe(&self, elapsed: Duration) {
+        let micros = u64::try_from(elapsed.as_micros()).unwrap_or(u64::MAX);
+        if let Some(position) = LATENCY_BUCKETS_MICROS
+            .iter()
+            .position(|upper_bound| micros <= *upper_bound)
+        {
+            self.buckets[position].fetch_add(1, Ordering::Relaxed);
+        }
+        self.count.fetch_add(1, Ordering::Relaxed);
+        self.sum_micros.fetch_add(micros, Ordering::Relaxed);
+    }
+    /// Number of observations
+    pub fn count(&self) -> u64 {
+        self.count.load(Ordering::Relaxed)
+    }
+    /// Sum of all observations, in microsecond precision
+    pub fn sum(&self) -> Duration {
+        Duration::from_micros(self.sum_micros.load(Ordering::Relaxed))
+    }
+    /// (upper bound in microseconds, observations up to it) of each bucket
+    /// - cumulative, like Prometheus buckets, observations above the last bound are
+    ///   only in count
+    pub fn buckets(&self) -> Vec<(u64, u64)> {
+        let mut cumulative = 0;
+        LATENCY_BUCKETS_MICROS
+            .iter()
+            .zip(self.buckets.iter())
+            .map(|(upper_bound, bucket)| {
+                cumulative += bucket.load(Ordering::Relaxed);
+                (*upper_bound, cumulative)
+            })
+            .collect()
+    }
+}
+
+/// Requests served for one storage of an Engine, and its block usage, see
+/// EngineMetrics::storage
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct StorageMetrics {
+    /// Requests served, of every kind
+    pub served: u64,
+    /// Served requests whose result was an error
+    pub errors: u64,
+    /// Record data bytes of successful writes
+    pub bytes_in: u64,
+    /// Record data bytes returned by successful reads
+    pub bytes_out: u64,
+    /// Blocks holding data, as of the end of the last io_cycle
+    pub used_blocks: u64,
+    /// Free blocks below the last block, as of the end of the last io_cycle
+    pub free_blocks: u64,
+    /// Bytes of the storage file, as of the end of the last io_cycle
+    pub bytes: u64,
+}
+
+impl StorageMetrics {
+    fn note_usage(&mut self, stats: &StoreStats) {
+        self.used_blocks = stats.used_blocks;
+        self.free_blocks = stats.free_blocks;
+        self.bytes = stats.bytes;
+    }
+}
+
+/// Metrics of an Engine, updated while it serves requests
+/// - shared with Arc, read them from any thread while the engine runs, see
+///   Engine::metrics and EngineHandle::metrics
+/// - counters start at 0 when the engine is created, they are not stored in the
+///   storage file
+/// - a request counts as served once its result is sent, failed requests count as
+///   served and as errors; cancelled and timed out requests are not counted
+/// - latency of a request is measured from the start of serving it until its result
+///   is sent, reads served on the read pool include the wait for the other reads of
+///   their batch
+#[derive(Default)]
+pub struct EngineMetrics {
+    served: [AtomicU64; OpKind::ALL.len()],
+    latencies: [LatencyHistogram; OpKind::ALL.len()],
+    errors: AtomicU64,
+    bytes_in: AtomicU64,
+    bytes_out: AtomicU64,
+    queue_len: AtomicU64,
+    cycle_durations: LatencyHistogram,
+    scrubbed_blocks: AtomicU64,
+    bad_blocks: AtomicU64,
+    scrub_passes: AtomicU64,
+    overloaded: AtomicU64,
+    cycle_errors: AtomicU64,
+    /// Metrics per attached storage name, "" for the default storage
+    storages: Mutex<BTreeMap<String, StorageMetrics>>,
+}
+
+impl EngineMetrics {
+    /// Requests of kind served so far
+    pub fn served(&self, kind: OpKind) -> u64 {
+        self.served[kind.position()].load(Ordering::Relaxed)
+    }
+    /// Latencies of requests of kind
+    pub fn latency(&self, kind: OpKind) -> &LatencyHistogram {
+        &self.latencies[kind.position()]
+    }
+    /// Served requests whose result was an error
+    pub fn errors(&self) -> u64 {
+        self.errors.load(Ordering::Relaxed)
+    }
+    /// Record data bytes of successful writes
+    pub fn bytes_in(&self) -> u64 {
+        self.bytes_in.load(Ordering::Relaxed)
+    }
+    /// Record data bytes returned by successful reads
+    pub fn bytes_out(&self) -> u64 {
+        self.bytes_out.load(Ordering::Relaxed)
+    }
+    /// Requests waiting in the engine queue
+    /// - sampled when a request is appended and at start and end of every io_cycle,
+    ///   requests sent to a background engine count once its thread queued them
+    pub fn queue_len(&self) -> u64 {
+        self.queue_len.load(Ordering::Relaxed)
+    }
+    /// Durations of io_cycle calls, count is the number of cycles run
+    pub fn cycle_duration(&self) -> &LatencyHistogram {
+        &self.cycle_durations
+    }
+    /// Blocks verified by the scrubber, of all storages, see Engine::set_scrubber
+    pub fn scrubbed_blocks(&self) -> u64 {
+        self.scrubbed_blocks.load(Ordering::Relaxed)
+    }
+    /// Corrupted blocks found by the scrubber, a block is counted again on every pass
+    pub fn bad_blocks(&self) -> u64 {
+        self.bad_blocks.load(Ordering::Relaxed)
+    }
+    /// Passes of the scrubber through every block of a storage, counted per storage
+    pub fn scrub_passes(&self) -> u64 {
+        self.scrub_passes.load(Ordering::Relaxed)
+    }
+    /// Requests rejected with StorageError::Overloaded, see Engine::set_admission_limit
+    pub fn overloaded(&self) -> u64 {
+        self.overloaded.load(Ordering::Relaxed)
+    }
+    /// Cycles of a background engine that failed, see Engine::spawn_engine
+    pub fn cycle_errors(&self) -> u64 {
+        self.cycle_errors.load(Ordering::Relaxed)
+    }
+    /// Requests served for the storage attached under name, see Engine::attach
+    /// - "" is the storage the engine was created with
+    /// - a request for a name that is not attached counts only in the engine totals, so
+    ///   unknown names add no entries; detach drops the entry of the storage
+    pub fn storage(&self, name: &str) -> StorageMetrics {
+        self.storage_metrics()
+            .get(name)
+            .copied()
+            .unwrap_or_default()
+    }
+    /// Metrics of every attached storage served a request, or through an io_cycle, so
+    /// far, by name, see storage
+    pub fn storages(&self) -> Vec<(String, StorageMetrics)> {
+        self.storage_metrics()
+            .iter()
+            .map(|(name, metrics)| (name.clone(), *metrics))
+            .collect()
+    }
+    fn storage_metrics(&self) -> std::sync::MutexGuard<'_, BTreeMap<String, StorageMetrics>> {
+        match self.storages.lock() {
+            Ok(storages) => storages,
+            Err(poisoned) => poisoned.into_inner(),
+        }
+    }
+    /// All metrics in Prometheus text exposition format, names prefixed with se1_engine_
+    #[cfg(feature = "prometheus")]
+    pub fn to_prometheus(&self) -> String {
+        let mut text = String::new();
+        text.push_str("# HELP se1_engine_requests_total Requests served by the engine.\n");
+        text.push_str("# TYPE se1_engine_requests_total counter\n");
+        for kind in OpKind::ALL.iter() {
+            text.push_str(&format!(
+                "se1_engine_requests_total{{op=\"{}\"}} {}\n",
+                kind.label(),
+                self.served(*kind)
+            ));
+        }
+        let counters = [
+            (
+                "se1_engine_request_errors_total",
+                "Served requests that failed.",
+                self.errors(),
+            ),
+            (
+                "se1_engine_bytes_in_total",
+                "Record bytes written.",
+                self.bytes_in(),
+            ),
+            (
+                "se1_engine_bytes_out_total",
+                "Record bytes read.",
+                self.bytes_out(),
+            ),
+            (
+                "se1_engine_scrubbed_blocks_total",
+                "Blocks verified by the scrubber.",
+                self.scrubbed_blocks(),
+            ),
+            (
+                "se1_engine_bad_blocks_total",
+                "Corrupted blocks found by the scrubber.",
+                self.bad_blocks(),
+            ),
+            (
+                "se1_engine_scrub_passes_total",
+                "Scrubber passes through a storage.",
+                self.scrub_passes(),
+            ),
+            (
+                "se1_engine_overloaded_total",
+                "Requests rejected at the admission limit.",
+                self.overloaded(),
+            ),
+            (
+                "se1_engine_cycle_errors_total",
+                "Cycles of the background engine that failed.",
+                self.cycle_errors(),
+            ),
+        ];
+        for (name, help, value) in counters.iter() {
+            text.push_str(&format!(
+                "# HELP {} {}\n# TYPE {} counter\n{} {}\n",
+                name, help, name, name, value
+            ));
+        }
+        let storages = self.storages();
+        type StorageCounter = (&'static str, &'static str, fn(&StorageMetrics) -> u64);
+        let storage_counters: [StorageCounter; 7] = [
+            (
+                "se1_engine_storage_requests_total",
+                "Requests served for a storage.",
+                |metrics| metrics.served,
+            ),
+            (
+                "se1_engine_storage_request_errors_total",
+                "Served requests for a storage that failed.",
+                |metrics| metrics.errors,
+            ),
+            (
+                "se1_engine_storage_bytes_in_total",
+                "Record bytes written to a storage.",
+                |metrics| metrics.bytes_in,
+            ),
+            (
+                "se1_engine_storage_bytes_out_total",
+                "Record bytes read from a storage.",
+                |metrics| metrics.bytes_out,
+            ),
+            (
+                "se1_engine_storage_used_blocks",
+                "Blocks of a storage holding data.",
+                |metrics| metrics.used_blocks,
+            ),
+            (
+                "se1_engine_storage_free_blocks",
+                "Free blocks of a storage.",
+                |metrics| metrics.free_blocks,
+            ),
+            (
+                "se1_engine_storage_bytes",
+                "Bytes of a storage file.",
+                |metrics| metrics.bytes,
+            ),
+        ];
+        for (name, help, value) in storage_counters.iter() {
+            let kind = if name.ends_with("_total") {
+                "counter"
+            } else {
+                "gauge"
+            };
+            text.push_str(&format!(
+                "# HELP {} {}\n# TYPE {} {}\n",
+                name, help, name, kind
+            ));
+            for (storage, metrics) in storages.iter() {
+                text.push_str(&format!(
+                    "{}{{storage=\"{}\"}} {}\n",
+                    name,
+                    escape_label(storage),
+                    value(metrics)
+                ));
+            }
+        }
+        text.push_str(&format!(
+            "# HELP se1_engine_queue_length Requests waiting in the engine queue.\n\
+             # TYPE se1_engine_queue_length gauge\nse1_engine_queue_length {}\n",
+            self.queue_len()
+        ));
+        text.push_str("# HELP se1_engine_request_duration_seconds Latency of served requests.\n");
+        text.push_str("# TYPE se1_engine_request_duration_seconds histogram\n");
+        for kind in OpKind::ALL.iter() {
+            let labels = format!("op=\"{}\",", kind.label());
+            push_histogram(
+                &mut text,
+                "se1_engine_request_duration_seconds",
+                &labels,
+                self.latency(*kind),
+            );
+        }
+        text.push_str("# HELP se1_engine_cycle_duration_seconds Duration of io cycles.\n");
+        text.push_str("# TYPE se1_engine_cycle_duration_seconds histogram\n");
+        push_histogram(
+            &mut text,
+            "se1_engine_cycle_duration_seconds",
+            "",
+            self.cycle_duration(),
+        );
+        text
+    }
+    pub(super) fn set_queue_len(&self, queue_len: usize) {
+        self.queue_len.store(queue_len as u64, Ordering::Relaxed);
+    }
+    /// Note block usage of the storage attached under name
+    pub(super) fn set_usage(&self, name: &str, stats: &StoreStats) {
+        let mut storages = self.storage_metrics();
+        storages
+            .entry(name.to_string())
+            .or_default()
+            .note_usage(stats);
+    }
+    /// Drop metrics of the storage detached from name
+    pub(super) fn remove_storage(&self, name: &str) {
+        self.storage_metrics().remove(name);
+    }
+    pub(super) fn observe_cycle(&self, elapsed: Duration) {
+        self.cycle_durations.observe(elapsed);
+    }
+    pub(super) fn observe_scrub(&self, scrubbed_blocks: u64, bad_blocks: u64, pass_done: bool) {
+        self.scrubbed_blocks
+            .fetch_add(scrubbed_blocks, Ordering::Relaxed);
+        self.bad_blocks.fetch_add(bad_blocks, Ordering::Relaxed);
+        if pass_done {
+            self.scrub_passes.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+    pub(super) fn observe_overloaded(&self) {
+        self.overloaded.fetch_add(1, Ordering::Relaxed);
+    }
+    pub(super) fn observe_cycle_error(&self) {
+        self.cycle_errors.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+/// Label value with backslash, double quote and line feed escaped
+#[cfg(feature = "prometheus")]
+pub(crate) fn escape_label(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+/// Append samples of histogram, labels: leading labels of every sample, with trailing ,
+#[cfg(feature = "prometheus")]
+fn push_histogram(text: &mut String, name: &str, labels: &str, histogram: &LatencyHistogram) {
+    for (upper_bound, count) in histogram.buckets() {
+        text.push_str(&format!(
+            "{}_bucket{{{}le=\"{}\"}} {}\n",
+            name,
+            labels,
+            upper_bound as f64 / 1e6,
+            count
+        ));
+    }
+    let labels = labels.trim_end_matches(',');
+    text.push_str(&format!(
+        "{}_bucket{{{}{}le=\"+Inf\"}} {}\n",
+        name,
+        labels,
+        if labels.is_empty() { "" } else { "," },
+        histogram.count()
+    ));
+    let labels = if labels.is_empty() {
+        String::new()
+    } else {
+        format!("{{{}}}", labels)
+    };
+    text.push_str(&format!(
+        "{}_sum{} {}\n{}_count{} {}\n",
+        name,
+        labels,
+        histogram.sum().as_secs_f64(),
+        name,
+        labels,
+        histogram.count()
+    ));
+}
+
+/// Counts results of requests in metrics, once they are sent
+/// - bytes_in: record bytes the request writes, counted on success
+/// - storage: name of the storage addressed, "" for the default storage, None if no
+///   storage is attached under its name
+struct Recorder<'a> {
+    metrics: &'a Arc<EngineMetrics>,
+    bytes_in: usize,
+    storage: Option<String>,
+}
+
+impl MapSender for Recorder<'_> {
+    fn map<T: RequestResult>(&mut self, kind: OpKind, result: ResultSender<T>) -> ResultSender<T> {
+        let metrics = self.metrics.clone();
+        let bytes_in = self.bytes_in;
+        let storage = self.storage.take();
+        let started = Instant::now();
+        result.inspect(move |result| {
+            let position = kind.position();
+            metrics.served[position].fetch_add(1, Ordering::Relaxed);
+            metrics.latencies[position].observe(started.elapsed());
+            let (bytes_in, bytes_out) = match result {
+                Ok(value) => (bytes_in as u64, value.bytes_read() as u64),
+                Err(_) => (0, 0),
+            };
+            metrics.bytes_in.fetch_add(bytes_in, Ordering::Relaxed);
+            metrics.bytes_out.fetch_add(bytes_out, Ordering::Relaxed);
+            if result.is_err() {
+                metrics.errors.fetch_add(1, Ordering::Relaxed);
+            }
+            if let Some(storage) = storage {
+                let mut storages = metrics.storage_metrics();
+                let storage_metrics = storages.entry(storage).or_default();
+                storage_metrics.served += 1;
+                storage_metrics.errors += u64::from(result.is_err());
+                storage_metrics.bytes_in += bytes_in;
+                storage_metrics.bytes_out += bytes_out;
+            }
+        })
+    }
+}
+
+impl Engine {
+    /// Requests served and block usage of the default storage, as "", and of every
+    /// attached storage, by name
+    /// - block usage is read now, request counts are those of EngineMetrics::storage
+    pub fn stats(&self) -> Vec<(String, StorageMetrics)> {
+        let attached = self
+            .storages
+            .iter()
+            .map(|(name, storage)| (name.as_str(), storage));
+        let mut stats: Vec<(String, StorageMetrics)> = std::iter::once(("", &self.storage))
+            .chain(attached)
+            .map(|(name, storage)| {
+                let mut storage_metrics = self.metrics.storage(name);
+                storage_metrics.note_usage(&storage.stats());
+                (name.to_string(), storage_metrics)
+            })
+            .collect();
+        stats.sort_by(|(name, _), (other, _)| name.cmp(other));
+        stats
+    }
+}
+
+impl IORequest {
+    /// Wrap result sender, so sending the result counts the request in metrics
+    /// - attached: a storage is attached under the name of request, counted per storage
+    pub(super) fn observed(self, metrics: &Arc<EngineMetrics>, attached: bool) -> IORequest {
+        let bytes_in = self.data_len();
+        let storage =
+            Some(self.storage_name().unwrap_or_default().to_string()).filter(|_| attached);
+        self.map_sender(&mut Recorder {
+            metrics,
+            bytes_in,
+            storage,
+        })
+    }
+}
+
+#[cfg(test)]
+mod unit_tests_metrics {
+    use super::*;
+
+    fn new_storage(tmp_dir: &tempfile::TempDir) -> Storage {
+        let file_path = tmp_dir.path().join("metrics.hex");
+        Storage::new(file_path.to_str().unwrap().to_string(), 4).unwrap()
+    }
+
+    #[test]
+    fn test_engine_metrics() {
+        let tmp_dir = tempfile::tempdir().unwrap();
+        let mut engine = Engine::new(new_storage(&tmp_dir));
+        let metrics = engine.metrics();
+        let (write_result, write_receiver) = ResultSender::channel();
+        engine.append_request(IORequest::Write {
+            data: vec![1; 6],
+            expires_at: None,
+            result: write_result,
+        });
+        let (read_result, _) = ResultSender::channel();
+        engine.append_request(IORequest::ReadBlocks {
+            record_id: 0,
+            result: read_result,
+        });
+        let (empty_result, _) = ResultSender::channel();
+        engine.append_request(IORequest::Write {
+            data: vec![],
+            expires_at: None,
+            result: empty_result,
+        });
+        // cancelled requests are not counted
+        let (delete_result, _) = ResultSender::channel();
+        engine
+            .append_request(IORequest::Delete {
+                record_id: 0,
+                hard_delete: false,
+                result: delete_result,
+            })
+            .cancel();
+        assert_eq!(metrics.queue_len(), 4);
+        engine.io_cycle().unwrap();
+        assert_eq!(write_receiver.recv().unwrap().unwrap().record_id, 0);
+        assert_eq!(metrics.served(OpKind::Write), 2);
+        assert_eq!(metrics.served(OpKind::Read), 1);
+        assert_eq!(metrics.served(OpKind::Delete), 0);
+        assert_eq!(metrics.errors(), 1);
+        assert_eq!((metrics.bytes_in(), metrics.bytes_out()), (6, 6));
+        assert_eq!(metrics.latency(OpKind::Write).count(), 2);
+        assert_eq!(metrics.queue_len(), 0);
+        assert_eq!(metrics.cycle_duration().count(), 1);
+        // - per storage, "" for the default storage
+        let default_metrics = StorageMetrics {
+            served: 3,
+            errors: 1,
+            bytes_in: 6,
+            bytes_out: 6,
+            used_blocks: 2,
+            free_blocks: 0,
+            bytes: engine.storage.stats().bytes,
+        };
+        assert_eq!(metrics.storage(""), default_metrics);
+        let events_path = tmp_dir.path().join("events.hex");
+        let events = Storage::new(events_path.to_str().unwrap().to_string(), 4).unwrap();
+        engine.attach("events", events);
+        let (events_result, _) = ResultSender::channel();
+        let write = IORequest::Write {
+            data: vec![2; 3],
+            expires_at: None,
+            result: events_result,
+        };
+        engine.append_request(write.on("events"));
+        // - requests for names that are not attached add no entry
+        let (other_result, _) = ResultSender::channel();
+        engine.append_request(
+            IORequest::Read {
+                record_id: 0,
+                result: other_result,
+            }
+            .on("other"),
+        );
+        engine.io_cycle().unwrap();
+        assert_eq!(metrics.storage("events").bytes_in, 3);
+        assert_eq!(metrics.storage("events").used_blocks, 1);
+        assert_eq!(metrics.storage("").served, 3);
+        assert_eq!(metrics.errors(), 2);
+        assert_eq!(metrics.storages().len(), 2);
+        assert_eq!(metrics.storage("other"), StorageMetrics::default());
+        let stats = engine.stats();
+        assert_eq!(stats.len(), 2);
+        assert_eq!((stats[1].0.as_str(), stats[1].1.served), ("events", 1));
+        engine.detach("events");
+        assert_eq!(metrics.storages().len(), 1);
+        // background engine shares its metrics with the handle
+        let handle = engine.spawn_engine();
+        handle.read(0).recv().unwrap().unwrap();
+        assert_eq!(handle.metrics().served(OpKind::Read), 3);
+        assert_eq!(metrics.bytes_out(), 12);
+    }
+    #[test]
+    fn test_latency_histogram() {
+        let histogram = LatencyHistogram::default();
+        histogram.observe(Duration::from_micros(7));
+        histogram.observe(Duration::from_micros(60));
+        histogram.observe(Duration::from_secs(10));
+        assert_eq!(histogram.count(), 3);
+        assert_eq!(histogram.sum(), Duration::from_micros(10_000_067));
+        let buckets = histogram.buckets();
+        assert_eq!(buckets[0], (10, 1));
+        assert_eq!(buckets[1], (50, 1));
+        assert_eq!(buckets[2], (100, 2));
+        assert_eq!(buckets.last(), Some(&(5_000_000, 2)));
+    }
+    #[cfg(feature = "prometheus")]
+    #[test]
+    fn test_prometheus_text() {
+        let metrics = EngineMetrics::default();
+        metrics.set_queue_len(3);
+        metrics.observe_cycle(Duration::from_micros(20));
+        let text = metrics.to_prometheus();
+        assert!(text.contains("se1_engine_requests_total{op=\"read\"} 0\n"));
+        assert!(text.contains("# TYPE se1_engine_queue_length gauge\nse1_engine_queue_length 3\n"));
+        assert!(text
+            .contains("se1_engine_request_duration_seconds_bucket{op=\"write\",le=\"+Inf\"} 0\n"));
+        assert!(text.contains("se1_engine_cycle_duration_seconds_bucket{le=\"0.00005\"} 1\n"));
+        assert!(text.contains("se1_engine_cycle_duration_seconds_bucket{le=\"+Inf\"} 1\n"));
+        assert!(text.contains("se1_engine_cycle_duration_seconds_count 1\n"));
+        assert!(text.contains("se1_engine_cycle_errors_total 0\n"));
+        metrics.storage_metrics().insert(
+            "a\"b".to_string(),
+            StorageMetrics {
+                served: 2,
+                ..StorageMetrics::default()
+            },
+        );
+        let text = metrics.to_prometheus();
+        assert!(text.contains("se1_engine_storage_requests_total{storage=\"a\\\"b\"} 2\n"));
+        assert!(text.contains("se1_engine_storage_bytes_out_total{storage=\"a\\\"b\"} 0\n"));
+        assert!(text.contains("# TYPE se1_engine_storage_used_blocks gauge\n"));
+    }
+}
@@ -0,0 +1,1501 @@
+use crate::storage::{BlockIndex, BlockStore, DurabilityMode, RecordId, Storage, StorageError};
+use std::collections::{HashMap, VecDeque};
+use std::sync::{mpsc, Arc};
+use std::thread;
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
+
+mod admission;
+#[cfg(feature = "async")]
+pub mod r#async;
+pub use admission::AdmissionLimit;
+mod builder;
+pub use builder::RequestBuilder;
+mod cdc;
+pub use cdc::ChangeEvent;
+mod group_commit;
+pub mod metrics;
+pub use metrics::EngineMetrics;
+use metrics::OpKind;
+mod pending;
+pub use pending::{PendingRequest, PendingState, RequestKind};
+mod read_pool;
+pub mod replication;
+mod request_queue;
+mod response;
+pub use response::{ReadResponse, WriteResponse};
+mod schedule;
+pub use schedule::{Priority, RequestHandle, RequestOrder, DEFAULT_AGING_CYCLES};
+mod scrub;
+pub use scrub::{BadBlockHook, Scrubber};
+mod shutdown;
+pub use shutdown::DrainPolicy;
+use shutdown::ShutdownState;
+mod txn;
+pub use txn::{AckMode, Committed, Transaction, TxnOp, TxnRecovery};
+
+/// Channel end on which the result of a request is received
+pub type ResultReceiver<T> = mpsc::Receiver<Result<T, StorageError>>;
+
+/// Destination of the result of a request
+/// - wraps the sending end of any channel, e.g. std mpsc or an async oneshot
+pub struct ResultSender<T>(Box<dyn FnOnce(Result<T, StorageError>) + Send>);
+
+impl<T: Send + 'static> ResultSender<T> {
+    /// Wrap function called with the result, once request is served
+    pub fn new(send: impl FnOnce(Result<T, StorageError>) + Send + 'static) -> Self {
+        ResultSender(Box::new(send))
+    }
+    /// Result sender backed by a std mpsc channel
+    /// - a result whose receiver was dropped is discarded, with a DEBUG event under
+    ///   feature tracing
+    pub fn channel() -> (Self, ResultReceiver<T>) {
+        let (sender, receiver) = mpsc::channel();
+        let result_sender = ResultSender::new(move |result| {
+            if sender.send(result).is_err() {
+                dropped_result();
+            }
+        });
+        (result_sender, receiver)
+    }
+}
+
+impl<T: Send + 'static> ResultSender<T> {
+    /// Call inspect with the result, right before it is sent
+    fn inspect(self, inspect: impl FnOnce(&Result<T, StorageError>) + Send + 'static) -> Self {
+        ResultSender::new(move |result| {
+            inspect(&result);
+            self.send(result)
+        })
+    }
+}
+
+impl<T> ResultSender<T> {
+    fn send(self, result: Result<T, StorageError>) {
+        (self.0)(result)
+    }
+}
+
+/// Note a result that was discarded, as nobody waits for it any more
+/// - e.g. a client that gave up waiting; the request was served all the same
+fn dropped_result() {
+    #[cfg(feature = "tracing")]
+    tracing::debug!("result receiver dropped, result discarded");
+}
+
+// ... ... ... ... ... ... ... ... ... IO Request ... ... ... ... ... ... ... ... ... ..
+
+/// Operation queued in Engine, with channel to send its result on
+pub enum IORequest {
+    /// Read all data of a record
+    Read {
+        record_id: RecordId,
+        result: ResultSender<ReadResponse>,
+    },
+    /// Read data of each block of a record separately, in chain order
+    ReadBlocks {
+        record_id: RecordId,
+        result: ResultSender<Vec<Vec<u8>>>,
+    },
+    /// Write data as a new record
+    Write {
+        data: Vec<u8>,
+        /// Expiry of the record in seconds since UNIX epoch, see Storage::sweep_expired
+        expires_at: Option<u64>,
+        result: ResultSender<WriteResponse>,
+    },
+    /// Delete all blocks of a record, result is number of deleted blocks
+    Delete {
+        record_id: RecordId,
+        hard_delete: bool,
+        result: ResultSender<usize>,
+    },
+    /// Read data of up to max_blocks used blocks at or after from_block, in block index
+    /// order, see Storage::iter_blocks_from
+    /// - result has fewer than max_blocks blocks only at end of file
+    /// - page through all blocks by starting the next request after the last block, other
+    ///   requests served in between may change blocks not read yet
+    ScanBlocks {
+        from_block: BlockIndex,
+        max_blocks: usize,
+        result: ResultSender<Vec<(BlockIndex, Vec<u8>)>>,
+    },
+    /// Move up to max_moves blocks with Storage::compact_step, result maps old to new index
+    CompactStep {
+        max_moves: usize,
+        result: ResultSender<HashMap<BlockIndex, BlockIndex>>,
+    },
+    /// Apply operations of a transaction together, see Transaction::commit
+    Commit {
+        ops: Vec<TxnOp>,
+        result: ResultSender<Committed>,
+    },
+    /// Serve request on the storage attached to the engine under name, see Engine::attach
+    /// - requests without it are served on the storage the engine was created with
+    OnStorage {
+        name: String,
+        request: Box<IORequest>,
+    },
+    /// Serve request with a caller supplied trace id, see IORequest::traced
+    Traced {
+        trace_id: String,
+        request: Box<IORequest>,
+    },
+}
+
+impl IORequest {
+    /// Address request to the storage attached under name, see IORequest::OnStorage
+    /// - replaces the name of a request that was already addressed
+    pub fn on(self, name: impl Into<String>) -> IORequest {
+        let request = match self {
+            IORequest::OnStorage { request, .. } => request,
+            request => Box::new(request),
+        };
+        IORequest::OnStorage {
+            name: name.into(),
+            request,
+        }
+    }
+    /// Carry trace_id, an opaque id of the caller, e.g. to correlate a request with the
+    /// client call it serves
+    /// - under feature tracing, the request is served in a DEBUG span `request` with field
+    ///   trace_id, so spans and events of serving it are attributed to it; its result is
+    ///   sent in the same span, also the event of a result discarded for a dropped receiver
+    /// - an error result is sent as StorageError::Traced, and change events of the request
+    ///   carry the id, see ChangeEvent::trace_id
+    /// - traced reads are served on the engine thread, not the read pool, see
+    ///   Engine::set_read_concurrency
+    /// - replaces the trace id of a request that was already traced
+    pub fn traced(self, trace_id: impl Into<String>) -> IORequest {
+        match self {
+            IORequest::OnStorage { name, request } => IORequest::OnStorage {
+                name,
+                request: Box::new(request.traced(trace_id)),
+            },
+            IORequest::Traced { request, .. } => request.traced(trace_id),
+            request => IORequest::Traced {
+                trace_id: trace_id.into(),
+                request: Box::new(request),
+            },
+        }
+    }
+    /// Trace id of request, see IORequest::traced
+    pub fn trace_id(&self) -> Option<&str> {
+        match self {
+            IORequest::Traced { trace_id, .. } => Some(trace_id),
+            IORequest::OnStorage { request, .. } => request.trace_id(),
+            _ => None,
+        }
+    }
+    /// Make request send its result with its trace id, if traced, see IORequest::traced
+    /// - called as the request is queued, before any other sender is mapped, so the
+    ///   result gets its trace id last, right before it is sent
+    fn trace_results(self) -> IORequest {
+        match self.trace_id().map(str::to_string) {
+            Some(trace_id) => self.map_sender(&mut TraceContext(trace_id)),
+            None => self,
+        }
+    }
+    /// Name of the storage request is addressed to, None for the default storage
+    fn storage_name(&self) -> Option<&str> {
+        match self {
+            IORequest::OnStorage { name, .. } => Some(name),
+            IORequest::Traced { request, .. } => request.storage_name(),
+            _ => None,
+        }
+    }
+    /// Send error as result, without performing operation
+    fn fail(self, error: StorageError) {
+        match self {
+            IORequest::Read { result, .. } => result.send(Err(error)),
+            IORequest::ReadBlocks { result, .. } => result.send(Err(error)),
+            IORequest::Write { result, .. } => result.send(Err(error)),
+            IORequest::Delete { result, .. } => result.send(Err(error)),
+            IORequest::ScanBlocks { result, .. } => result.send(Err(error)),
+            IORequest::CompactStep { result, .. } => result.send(Err(error)),
+            IORequest::Commit { result, .. } => result.send(Err(error)),
+            IORequest::OnStorage { request, .. } | IORequest::Traced { request, .. } => {
+                request.fail(error)
+            }
+        }
+    }
+    /// Perform operation on storage and send its result
+    /// - ack_mode: when results of writes, deletes and commits are sent
+    fn serve(self, storage: &mut Storage, ack_mode: AckMode) {
+        let journaled = ack_mode == AckMode::Journaled;
+        match self {
+            IORequest::Read { record_id, result } => {
+                let chunks = storage.read_record_chunks(record_id);
+                result.send(chunks.map(ReadResponse::from_chunks));
+            }
+            IORequest::ReadBlocks { record_id, result } => {
+                result.send(storage.read_record_chunks(record_id));
+            }
+            IORequest::Write {
+                data,
+                expires_at: None,
+                result,
+            } if journaled => {
+                let started = Instant::now();
+                let bytes_written = data.len();
+                txn::commit_journaled(storage, vec![TxnOp::Write(data)], result, |commit| {
+                    let block_indexes = commit.written_blocks[0].clone();
+                    WriteResponse {
+                        record_id: block_indexes[0],
+                        block_indexes,
+                        bytes_written,
+                        duration: started.elapsed(),
+                    }
+                });
+            }
+            IORequest::Write {
+                data,
+                expires_at,
+                result,
+            } => {
+                result.send(WriteResponse::write(storage, &data, expires_at));
+            }
+            IORequest::Delete {
+                record_id,
+                hard_delete,
+                result,
+            } if journaled => {
+                let op = TxnOp::Delete {
+                    record_id,
+                    hard_delete,
+                };
+                txn::commit_journaled(storage, vec![op], result, |commit| {
+                    commit.committed().deleted_blocks
+                });
+            }
+            IORequest::Delete {
+                record_id,
+                hard_delete,
+                result,
+            } => {
+                result.send(storage.delete_record(record_id, hard_delete));
+            }
+            IORequest::ScanBlocks {
+                from_block,
+                max_blocks,
+                result,
+            } => {
+                let blocks = storage.iter_blocks_from(from_block).take(max_blocks);
+                result.send(blocks.collect());
+            }
+            IORequest::CompactStep { max_moves, result } => {
+                result.send(storage.compact_step(max_moves));
+            }
+            IORequest::Commit { ops, result } if journaled => {
+                txn::commit_journaled(storage, ops, result, txn::JournaledCommit::committed);
+            }
+            IORequest::Commit { ops, result } => result.send(txn::commit(storage, ops)),
+            // - Engine looks up the named storage before serving, see Engine::serve
+            IORequest::OnStorage { request, .. } => request.serve(storage, ack_mode),
+            IORequest::Traced { trace_id, request } => {
+                #[cfg(feature = "tracing")]
+                let _span = tracing::debug_span!("request", trace_id = trace_id.as_str()).entered();
+                #[cfg(not(feature = "tracing"))]
+                let _ = trace_id;
+                request.serve(storage, ack_mode)
+            }
+        }
+    }
+}
+
+/// Result type of an IORequest, see IORequest::map_sender
+pub(super) trait RequestResult: Send + 'static {
+    /// Record bytes read, 0 for results of requests that do not read
+    fn bytes_read(&self) -> usize {
+        0
+    }
+    /// Old to new index of every block moved, for results of CompactStep
+    fn remap(&self) -> Option<&HashMap<BlockIndex, BlockIndex>> {
+        None
+    }
+}
+
+impl RequestResult for ReadResponse {
+    fn bytes_read(&self) -> usize {
+        self.bytes
+    }
+}
+
+impl RequestResult for Vec<Vec<u8>> {
+    fn bytes_read(&self) -> usize {
+        self.iter().map(Vec::len).sum()
+    }
+}
+
+impl RequestResult for WriteResponse {}
+
+impl RequestResult for usize {}
+
+impl RequestResult for Vec<(BlockIndex, Vec<u8>)> {
+    fn bytes_read(&self) -> usize {
+        self.iter().map(|(_, block_data)| block_data.len()).sum()
+    }
+}
+
+impl RequestResult for Committed {}
+
+impl RequestResult for HashMap<BlockIndex, BlockIndex> {
+    fn remap(&self) -> Option<&HashMap<BlockIndex, BlockIndex>> {
+        Some(self)
+    }
+}
+
+/// Trace id of a request, attached to its error and to the sending of its result, see
+/// IORequest::trace_results
+struct TraceContext(String);
+
+impl MapSender for TraceContext {
+    fn map<T: RequestResult>(&mut self, _: OpKind, result: ResultSender<T>) -> ResultSender<T> {
+        let trace_id = self.0.clone();
+        ResultSender::new(move |served| {
+            #[cfg(feature = "tracing")]
+            let _span = tracing::debug_span!("request", trace_id = trace_id.as_str()).entered();
+            result.send(served.map_err(|error| error.traced(&trace_id)))
+        })
+    }
+}
+
+/// Change to the result sender of a request, whatever its result type, see
+/// IORequest::map_sender
+pub(super) trait MapSender {
+    /// Result sender to use instead of result, of a request of kind
+    fn map<T: RequestResult>(&mut self, kind: OpKind, result: ResultSender<T>) -> ResultSender<T>;
+}
+
+impl IORequest {
+    /// Replace the result sender of request with the one map returns for it
+    /// - map is called once, for the request wrapped by OnStorage if addressed
+    pub(super) fn map_sender(self, map: &mut impl MapSender) -> IORequest {
+        match self {
+            IORequest::Read { record_id, result } => IORequest::Read {
+                record_id,
+                result: map.map(OpKind::Read, result),
+            },
+            IORequest::ReadBlocks { record_id, result } => IORequest::ReadBlocks {
+                record_id,
+                result: map.map(OpKind::Read, result),
+            },
+            IORequest::Write {
+                data,
+                expires_at,
+                result,
+            } => IORequest::Write {
+                data,
+                expires_at,
+                result: map.map(OpKind::Write, result),
+            },
+            IORequest::Delete {
+                record_id,
+                hard_delete,
+                result,
+            } => IORequest::Delete {
+                record_id,
+                hard_delete,
+                result: map.map(OpKind::Delete, result),
+            },
+            IORequest::ScanBlocks {
+                from_block,
+                max_blocks,
+                result,
+            } => IORequest::ScanBlocks {
+                from_block,
+                max_blocks,
+                result: map.map(OpKind::Read, result),
+            },
+            IORequest::CompactStep { max_moves, result } => IORequest::CompactStep {
+                max_moves,
+                result: map.map(OpKind::CompactStep, result),
+            },
+            IORequest::Commit { ops, result } => IORequest::Commit {
+                ops,
+                result: map.map(OpKind::Write, result),
+            },
+            IORequest::OnStorage { name, request } => IORequest::OnStorage {
+                name,
+                request: Box::new(request.map_sender(map)),
+            },
+            IORequest::Traced { trace_id, request } => IORequest::Traced {
+                trace_id,
+                request: Box::new(request.map_sender(map)),
+            },
+        }
+    }
+    /// Bytes of data the request writes, 0 for requests other than Write and Commit
+    fn data_len(&self) -> usize {
+        match self {
+            IORequest::Write { data, .. } => data.len(),
+            IORequest::Commit { ops, .. } => ops
+                .iter()
+                .map(|op| match op {
+                    TxnOp::Write(data) => data.len(),
+                    TxnOp::Delete { .. } => 0,
+                })
+                .sum(),
+            IORequest::OnStorage { request, .. } | IORequest::Traced { request, .. } => {
+                request.data_len()
+            }
+            _ => 0,
+        }
+    }
+}
+
+// ... ... ... ... ... ... ... ... ... ... Engine ... ... ... ... ... ... ... ... ... ...
+
+/// Queue of IO requests served in cycles against one Storage
+/// - drive it by hand with append_request and io_cycle,
+///   or move it to a background thread with Engine::spawn
+pub struct Engine {
+    storage: Storage,
+    /// Storages addressed by name with IORequest::OnStorage
+    storages: HashMap<String, Storage>,
+    requests: schedule::Scheduler,
+    /// Most requests served per io_cycle, None for all queued requests
+    cycle_limit: Option<usize>,
+    /// Threads serving consecutive read requests, 1 serves them on the engine thread
+    read_concurrency: usize,
+    /// Soft delete expired blocks at the start of every io_cycle
+    expiry_sweep: bool,
+    /// Updated while requests are served, shared with readers of Engine::metrics
+    metrics: Arc<EngineMetrics>,
+    /// Sync writes of a cycle once before sending their results, see set_group_commit
+    group_commit: Option<group_commit::GroupCommit>,
+    /// Verify block checksums after the requests of a cycle, see set_scrubber
+    scrubber: Option<Scrubber>,
+    /// Ship blocks changed by a cycle to a follower, see set_replication
+    replication: Option<replication::Primary>,
+    /// Subscribers to changes of the default storage, see subscribe
+    change_feed: cdc::ChangeFeed,
+    /// Limit of queued requests and bytes, see set_admission_limit
+    admission: Option<Arc<admission::Admission>>,
+    /// Requests queued and being served, see pending
+    pending: Arc<pending::PendingTracker>,
+    /// Writes, deletes and commits kept until served, see set_request_queue
+    request_queue: Option<request_queue::RequestQueue>,
+    /// When results of writes, deletes and commits are sent, see set_ack_mode
+    ack_mode: AckMode,
+    /// Flush at the end of the last io_cycle failed for some storage
+    flush_failed: bool,
+    /// Background engine stopped serving after a failed flush, see spawn_engine
+    stopped: bool,
+}
+
+impl Engine {
+    pub fn new(storage: Storage) -> Self {
+        Engine {
+            storage,
+            storages: HashMap::new(),
+            requests: schedule::Scheduler::new(),
+            cycle_limit: None,
+            read_concurrency: 1,
+            expiry_sweep: false,
+            metrics: Arc::default(),
+            group_commit: None,
+            scrubber: None,
+            replication: None,
+            change_feed: cdc::ChangeFeed::default(),
+            admission: None,
+            pending: Arc::default(),
+            request_queue: None,
+            ack_mode: AckMode::default(),
+            flush_failed: false,
+            stopped: false,
+        }
+    }
+    /// Attach storage under name, for requests addressed with IORequest::on(name)
+    /// - all storages are served by the same io_cycle, in one queue
+    /// - reads of attached storages are not served on the read pool, see
+    ///   set_read_concurrency, and transactions apply to the default storage only
+    /// - returns: storage attached under name before, if any
+    pub fn attach(&mut self, name: impl Into<String>, storage: Storage) -> Option<Storage> {
+        self.storages.insert(name.into(), storage)
+    }
+    /// Take back storage attached under name
+    /// - requests still queued for it fail with UnknownStorage
+    pub fn detach(&mut self, name: &str) -> Option<Storage> {
+        self.metrics.remove_storage(name);
+        self.storages.remove(name)
+    }
+    /// Queue request with Priority::Normal, to be served in next io_cycle
+    /// - past the admission limit the request fails with Overloaded, see
+    ///   set_admission_limit
+    /// - returns: handle to cancel request or set its deadline
+    pub fn append_request(&mut self, request: IORequest) -> RequestHandle {
+        self.append_request_with_priority(request, Priority::Normal)
+    }
+    /// Queue request with given priority, see Priority
+    /// - returns: handle to cancel request or set its deadline
+    pub fn append_request_with_priority(
+        &mut self,
+        request: IORequest,
+        priority: Priority,
+    ) -> RequestHandle {
+        let admission = self.admission.as_ref();
+        let request = request.trace_results();
+        let request = match request.admitted(admission, &self.metrics, false) {
+            Some(request) => request,
+            None => return RequestHandle::default(),
+        };
+        let request = match self.keep_request(request) {
+            Some(request) => request,
+            None => return RequestHandle::default(),
+        };
+        let (request, handle) = self.pending.track(request, priority);
+        self.requests.push(request, priority, handle.clone());
+        self.metrics.set_queue_len(self.requests.len());
+        handle
+    }
+    /// Number of requests waiting for next io_cycle
+    pub fn queue_len(&self) -> usize {
+        self.requests.len()
+    }
+    /// Counters, latencies and queue length of this engine, see EngineMetrics
+    pub fn metrics(&self) -> Arc<EngineMetrics> {
+        self.metrics.clone()
+    }
+    /// Serve at most limit requests per io_cycle, None serves all queued requests
+    /// - default is None
+    /// - with a limit, lower priorities wait for cycles with room left
+    pub fn set_cycle_limit(&mut self, limit: Option<usize>) {
+        self.cycle_limit = limit;
+    }
+    /// Serve requests by priority or strictly in submission order, see RequestOrder
+    /// - default is RequestOrder::Priority
+    /// - set it before queueing requests, requests promoted by aging before may be served
+    ///   out of submission order
+    pub fn set_request_order(&mut self, order: RequestOrder) {
+        self.requests.set_order(order);
+    }
+    /// Promote requests to the next higher priority after waiting aging_cycles cycles
+    /// - default is DEFAULT_AGING_CYCLES, so background requests eventually run
+    ///   even while higher priorities fill every cycle
+    pub fn set_aging_cycles(&mut self, aging_cycles: u64) {
+        self.requests.set_aging_cycles(aging_cycles);
+    }
+    /// Soft delete expired blocks at the start of every io_cycle, see Storage::sweep_expired
+    /// - default is false
+    /// - expiry is checked against system time, in seconds since UNIX epoch
+    /// - a background engine only runs cycles when requests arrive, an idle engine does
+    ///   not sweep
+    pub fn set_expiry_sweep(&mut self, enabled: bool) {
+        self.expiry_sweep = enabled;
+    }
+    /// Serve queued requests, up to cycle limit
+    /// - with expiry sweep enabled, expired blocks are deleted first, so requests of the
+    ///   cycle do not see them
+    /// - higher priorities first, requests of same priority in the order they were appended,
+    ///   or all in the order they were appended, see set_request_order
+    /// - a request sees the effects of every request served before it, including requests
+    ///   served earlier in the same cycle
+    /// - cancelled and expired requests get Cancelled or TimedOut as result, they do not
+    ///   count as served
+    /// - every request gets its own result, a failed request does not stop the cycle, and
+    ///   results nobody waits for any more are discarded
+    /// - with a scrubber set, its step runs after the requests, see set_scrubber
+    /// - unless durability mode is Never, each storage is flushed at the end of the cycle,
+    ///   with group commit results of writes are only sent after it, see set_group_commit
+    /// - with replication set, blocks changed by the cycle are shipped after the flush, see
+    ///   set_replication, change events are sent to subscribers likewise, see subscribe
+    /// - returns: number of served requests, errors only if expiry sweep, scrub step, flush
+    ///   or replication failed, results of served requests were already sent
+    #[cfg_attr(
+        feature = "tracing",
+        tracing::instrument(
+            level = "debug",
+            skip(self),
+            fields(queued = self.requests.len(), served = tracing::field::Empty),
+            err
+        )
+    )]
+    pub fn io_cycle(&mut self) -> Result<usize, StorageError> {
+        let started = Instant::now();
+        self.metrics.set_queue_len(self.requests.len());
+        // - requests are kept on disk before they are served
+        self.sync_request_queue()?;
+        // - a failed sweep does not hold back requests, its error is returned after them
+        let mut sweep_result = Ok(());
+        if self.expiry_sweep {
+            let now = unix_time();
+            for storage in self.all_storages() {
+                if let Err(error) = storage.sweep_expired(now) {
+                    sweep_result = sweep_result.and(Err(error));
+                }
+            }
+        }
+        self.capture_changes();
+        self.requests.next_cycle();
+        self.suspend_syncs();
+        let limit = self.cycle_limit.unwrap_or(usize::MAX);
+        let mut request_count = 0;
+        while request_count < limit {
+            let (request, handle) = match self.requests.pop() {
+                Some(queued) => queued,
+                None => break,
+            };
+            if let Some(error) = handle.skip_error() {
+                request.fail(error);
+                continue;
+            }
+            self.pending.serving(handle.id());
+            if self.is_pooled_read(&request) {
+                let reads = self.pop_reads(request, limit - request_count);
+                request_count += reads.len();
+                self.serve_reads(reads);
+            } else {
+                self.serve(request);
+                request_count += 1;
+            }
+        }
+        self.resume_syncs();
+        let scrub_result = self.scrub_step();
+        let mut flush_result = Ok(());
+        let mut sync_errors = Vec::new();
+        for (name, storage) in self.named_storages() {
+            if storage.durability() != DurabilityMode::Never {
+                if let Err(error) = storage.flush() {
+                    sync_errors.push((name, error.to_string()));
+                    flush_result = flush_result.and(Err(error));
+                }
+            }
+        }
+        self.flush_failed = !sync_errors.is_empty();
+        self.send_acks(&sync_errors);
+        let queue_result = self.ack_requests();
+        self.publish_changes(sync_errors.iter().all(|(name, _)| name.is_some()));
+        let replication_result = self.replicate();
+        let metrics = self.metrics.clone();
+        for (name, storage) in self.named_storages() {
+            metrics.set_usage(name.as_deref().unwrap_or_default(), &storage.stats());
+        }
+        self.metrics.set_queue_len(self.requests.len());
+        self.metrics.observe_cycle(started.elapsed());
+        #[cfg(feature = "tracing")]
+        tracing::Span::current().record("served", request_count);
+        flush_result?;
+        queue_result?;
+        sweep_result?;
+        scrub_result?;
+        replication_result?;
+        Ok(request_count)
+    }
+    /// Serve request on the storage it is addressed to
+    /// - UnknownStorage if no storage is attached under its name
+    fn serve(&mut self, request: IORequest) {
+        let attached = request
+            .storage_name()
+            .is_none_or(|name| self.storages.contains_key(name));
+        let mut request = request.observed(&self.metrics, attached);
+        if let Some(group_commit) = self.group_commit.as_ref() {
+            request = group_commit.defer(request);
+        }
+        match request.storage_name().map(str::to_string) {
+            Some(name) => match self.storages.get_mut(&name) {
+                Some(storage) => request.serve(storage, self.ack_mode),
+                None => request.fail(StorageError::UnknownStorage { name }),
+            },
+            None => {
+                self.change_feed.set_trace_id(request.trace_id());
+                let request = request.captured(&self.change_feed);
+                request.serve(&mut self.storage, self.ack_mode);
+                self.change_feed.set_trace_id(None);
+            }
+        }
+    }
+    /// Default storage followed by attached storages
+    fn all_storages(&mut self) -> impl Iterator<Item = &mut Storage> {
+        std::iter::once(&mut self.storage).chain(self.storages.values_mut())
+    }
+    /// all_storages with the name each is attached under, None for the default storage
+    fn named_storages(&mut self) -> impl Iterator<Item = (Option<String>, &mut Storage)> {
+        std::iter::once((None, &mut self.storage)).chain(
+            self.storages
+                .iter_mut()
+                .map(|(name, storage)| (Some(name.clone()), storage)),
+        )
+    }
+    /// Give back storage, dropping requests that were never served
+    /// - attached storages are dropped, which closes them
+    pub fn into_storage(self) -> Storage {
+        self.storage
+    }
+    /// Give back storage and attached storages by name, dropping requests that were
+    /// never served
+    pub fn into_storages(self) -> (Storage, HashMap<String, Storage>) {
+        (self.storage, self.storages)
+    }
+    /// Move engine with storage to a background thread
+    /// - thread waits for requests, and serves every request received
+    ///   so far in one io_cycle
+    /// - thread stops once EngineHandle is dropped, after serving pending requests,
+    ///   or as EngineHandle::shutdown asks
+    /// - served writes are synced with Storage::flush before the thread stops
+    /// - needs thread support, on wasm32-wasip1 drive the engine with io_cycle instead
+    pub fn spawn(storage: Storage) -> EngineHandle {
+        Engine::new(storage).spawn_engine()
+    }
+    /// Move this engine to a background thread, keeping its settings, see spawn
+    /// - with a cycle limit, requests left after a cycle are served in next cycles
+    /// - a failed cycle is logged as an ERROR event under feature tracing, and counted in
+    ///   EngineMetrics::cycle_errors
+    /// - after a failed flush, writes already served may not be on disk, so the engine
+    ///   stops serving: requests queued and sent later fail with EngineStopped, and join
+    ///   gives back the storage to be checked and flushed again
+    pub fn spawn_engine(mut self) -> EngineHandle {
+        let (request_sender, request_receiver) = mpsc::channel();
+        let metrics = self.metrics();
+        let change_feed = self.change_feed.clone();
+        let admission = self.admission.clone();
+        let pending = self.pending.clone();
+        let shutdown = Arc::new(ShutdownState::default());
+        let handle_shutdown = shutdown.clone();
+        let thread = thread::spawn(move || {
+            let mut connected = true;
+            loop {
+                // - block until a request arrives, every sender is dropped, or a scrub
+                //   step is due
+                if self.queue_len() == 0 {
+                    if !connected {
+                        break;
+                    }
+                    let received = match self.scrub_wait() {
+                        Some(wait) => request_receiver.recv_timeout(wait),
+                        None => request_receiver
+                            .recv()
+                            .map_err(|_| mpsc::RecvTimeoutError::Disconnected),
+                    };
+                    match received {
+                        Ok((request, priority, handle)) => {
+                            self.accept(request, priority, handle, &shutdown)
+                        }
+                        Err(mpsc::RecvTimeoutError::Timeout) => {}
+                        Err(mpsc::RecvTimeoutError::Disconnected) => break,
+                    }
+                }
+                // -- batch requests that arrived meanwhile, or within the group
+                //    commit latency
+                let batch_deadline = Instant::now() + self.batch_latency();
+                loop {
+                    let received = match batch_deadline.checked_duration_since(Instant::now()) {
+                        Some(wait) if !wait.is_zero() => request_receiver
+                            .recv_timeout(wait)
+                            .map_err(|error| match error {
+                                mpsc::RecvTimeoutError::Timeout => mpsc::TryRecvError::Empty,
+                                mpsc::RecvTimeoutError::Disconnected => {
+                                    mpsc::TryRecvError::Disconnected
+                                }
+                            }),
+                        _ => request_receiver.try_recv(),
+                    };
+                    match received {
+                        Ok((request, priority, handle)) => {
+                            self.accept(request, priority, handle, &shutdown)
+                        }
+                        Err(mpsc::TryRecvError::Empty) => break,
+                        Err(mpsc::TryRecvError::Disconnected) => {
+                            connected = false;
+                            break;
+                        }
+                    }
+                }
+                if shutdown.policy() == Some(DrainPolicy::Abort) {
+                    self.reject_queued();
+                }
+                if self.stopped {
+                    self.fail_queued();
+                } else if let Err(error) = self.io_cycle() {
+                    self.cycle_failed(&error);
+                }
+            }
+            // - sync served writes whatever the durability mode, a failed flush leaves
+            //   them unsynced for the owner of the returned storage to flush again
+            let failed = self
+                .all_storages()
+                .filter_map(|storage| storage.flush().err())
+                .count();
+            self.flush_failed |= failed > 0;
+            if self.ack_requests().is_ok() {
+                let _ = self.sync_request_queue();
+            }
+            self.into_storage()
+        });
+        EngineHandle {
+            request_sender: Some(request_sender),
+            thread: Some(thread),
+            metrics,
+            shutdown: handle_shutdown,
+            change_feed,
+            admission,
+            pending,
+        }
+    }
+}
+
+impl Engine {
+    /// Report error of an io_cycle run by the background thread, which has no caller to
+    /// return it to, and stop serving if a flush failed
+    fn cycle_failed(&mut self, error: &StorageError) {
+        self.metrics.observe_cycle_error();
+        #[cfg(feature = "tracing")]
+        tracing::error!(%error, stopped = self.flush_failed, "io_cycle failed");
+        #[cfg(not(feature = "tracing"))]
+        let _ = error;
+        if self.flush_failed {
+            self.stopped = true;
+            self.fail_queued();
+        }
+    }
+    /// Fail every queued request with EngineStopped
+    fn fail_queued(&mut self) {
+        while let Some((request, _)) = self.requests.pop() {
+            request.fail(StorageError::EngineStopped);
+        }
+        self.metrics.set_queue_len(0);
+    }
+}
+
+/// Seconds since UNIX epoch, 0 if system time is before it
+pub(crate) fn unix_time() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|elapsed| elapsed.as_secs())
+        .unwrap_or(0)
+}
+
+// ... ... ... ... ... ... ... ... ... Engine Handle ... ... ... ... ... ... ... ... ...
+
+/// Client of an Engine running on a background thread
+/// - every method queues a request and returns receiver for its result
+/// - if background thread is gone, the receiver reports a disconnected channel
+/// - dropping the handle waits for background thread to serve pending requests
+pub struct EngineHandle {
+    request_sender: Option<mpsc::Sender<(IORequest, Priority, RequestHandle)>>,
+    thread: Option<thread::JoinHandle<Storage>>,
+    metrics: Arc<EngineMetrics>,
+    shutdown: Arc<ShutdownState>,
+    change_feed: cdc::ChangeFeed,
+    admission: Option<Arc<admission::Admission>>,
+    pending: Arc<pending::PendingTracker>,
+}
+
+impl EngineHandle {
+    /// Counters, latencies and queue length of the background engine, see EngineMetrics
+    pub fn metrics(&self) -> Arc<EngineMetrics> {
+        self.metrics.clone()
+    }
+    /// Queue request to read all data of a record
+    pub fn read(&self, record_id: RecordId) -> ResultReceiver<ReadResponse> {
+        self.submit(RequestBuilder::read(record_id))
+    }
+    /// Queue request to read data of each block of a record separately
+    pub fn read_blocks(&self, record_id: RecordId) -> ResultReceiver<Vec<Vec<u8>>> {
+        self.submit(RequestBuilder::read_blocks(record_id))
+    }
+    /// Queue request to write data as a new record
+    pub fn write(&self, data: Vec<u8>) -> ResultReceiver<WriteResponse> {
+        self.write_expiring(data, None)
+    }
+    /// Queue request to write data as a new record, expiring at expires_at
+    /// - expires_at: seconds since UNIX epoch, see Engine::set_expiry_sweep
+    pub fn write_expiring(
+        &self,
+        data: Vec<u8>,
+        expires_at: Option<u64>,
+    ) -> ResultReceiver<WriteResponse> {
+        self.submit(RequestBuilder::write(data, expires_at))
+    }
+    /// Queue request to delete all blocks of a record
+    pub fn delete(&self, record_id: RecordId, hard_delete: bool) -> ResultReceiver<usize> {
+        self.submit(RequestBuilder::delete(record_id, hard_delete))
+    }
+    /// Queue request to read up to max_blocks used blocks at or after from_block
+    pub fn scan_blocks(
+        &self,
+        from_block: BlockIndex,
+        max_blocks: usize,
+    ) -> ResultReceiver<Vec<(BlockIndex, Vec<u8>)>> {
+        self.submit(RequestBuilder::scan_blocks(from_block, max_blocks))
+    }
+    /// Queue request to move up to max_moves blocks, with Priority::Background
+    pub fn compact_step(
+        &self,
+        max_moves: usize,
+    ) -> ResultReceiver<HashMap<BlockIndex, BlockIndex>> {
+        let (request, receiver) = RequestBuilder::compact_step(max_moves).build();
+        self.send_with_priority(request, Priority::Background);
+        receiver
+    }
+    /// Queue request built by request, with Priority::Normal, e.g. a traced one
+    /// - returns: receiver for its result
+    pub fn submit<T: Send + 'static>(&self, request: RequestBuilder<T>) -> ResultReceiver<T> {
+        let (request, receiver) = request.build();
+        self.send(request);
+        receiver
+    }
+    /// Queue any request to background thread with given priority
+    /// - on failure the request is dropped, together with its result sender
+    /// - past the admission limit the calling thread waits for room up to
+    ///   AdmissionLimit::wait, then the request fails with Overloaded
+    /// - returns: handle to cancel request or set its deadline
+    pub fn send_with_priority(&self, request: IORequest, priority: Priority) -> RequestHandle {
+        let request = request.trace_results();
+        let request = match request.admitted(self.admission.as_ref(), &self.metrics, true) {
+            Some(request) => request,
+            None => return RequestHandle::default(),
+        };
+        let (request, handle) = self.pending.track(request, priority);
+        if let Some(request_sender) = self.request_sender.as_ref() {
+            let _ = request_sender.send((request, priority, handle.clone()));
+        }
+        handle
+    }
+    /// Queue request to background thread, with Priority::Normal
+    fn send(&self, request: IORequest) {
+        self.send_with_priority(request, Priority::Normal);
+    }
+    /// Stop background thread after it served pending requests
+    /// - same as shutdown with DrainPolicy::DrainAll
+    /// - returns: storage, or None if background thread panicked
+    pub fn join(mut self) -> Option<Storage> {
+        self.stop()
+    }
+    /// Stop background thread, serving or rejecting requests not yet served by policy
+    /// - every request sent gets a result, StorageError::ShuttingDown if it was not served
+    /// - writes served are synced to disk before the thread stops
+    /// - returns: storage, or None if background thread panicked
+    pub fn shutdown(mut self, policy: DrainPolicy) -> Option<Storage> {
+        self.shutdown.begin(policy);
+        self.stop()
+    }
+    fn stop(&mut self) -> Option<Storage> {
+        // - dropping the only sender ends the loop of background thread
+        self.request_sender.take();
+        self.thread.take().and_then(|thread| thread.join().ok())
+    }
+}
+
+impl Drop for EngineHandle {
+    fn drop(&mut self) {
+        self.stop();
+    }
+}
+
+#[cfg(test)]
+mod unit_tests_engine {
+    use super::*;
+
+    fn new_storage(tmp_dir: &tempfile::TempDir) -> Storage {
+        let file_path = tmp_dir.path().join("engine.hex");
+        Storage::new(file_path.to_str().unwrap().to_string(), 4).unwrap()
+    }
+
+    #[test]
+    fn test_io_cycle() {
+        let tmp_dir = tempfile::tempdir().unwrap();
+        let mut engine = Engine::new(new_storage(&tmp_dir));
+        let (write_result, write_receiver) = ResultSender::channel();
+        let (read_result, read_receiver) = ResultSender::channel();
+        let (empty_result, empty_receiver) = ResultSender::channel();
+        engine.append_request(IORequest::Write {
+            data: vec![1, 2, 3, 4, 5],
+            expires_at: None,
+            result: write_result,
+        });
+        // read sees write appended before it in the same cycle
+        engine.append_request(IORequest::Read {
+            record_id: 0,
+            result: read_result,
+        });
+        // failed request does not stop the cycle
+        engine.append_request(IORequest::Write {
+            data: vec![],
+            expires_at: None,
+            result: empty_result,
+        });
+        let (delete_result, delete_receiver) = ResultSender::channel();
+        engine.append_request(IORequest::Delete {
+            record_id: 0,
+            hard_delete: true,
+            result: delete_result,
+        });
+        assert_eq!(engine.queue_len(), 4);
+        assert_eq!(engine.io_cycle().unwrap(), 4);
+        assert_eq!(engine.queue_len(), 0);
+        assert_eq!(write_receiver.recv().unwrap().unwrap().record_id, 0);
+        assert_eq!(
+            read_receiver.recv().unwrap().unwrap().data,
+            vec![1, 2, 3, 4, 5]
+        );
+        assert!(matches!(
+            empty_receiver.recv().unwrap(),
+            Err(StorageError::EmptyRecord)
+        ));
+        assert_eq!(delete_receiver.recv().unwrap().unwrap(), 2);
+    }
+    #[test]
+    fn test_expiry_sweep() {
+        let tmp_dir = tempfile::tempdir().unwrap();
+        let mut engine = Engine::new(new_storage(&tmp_dir));
+        let expired = engine
+            .storage
+            .write_record_expiring(&[1; 6], Some(1))
+            .unwrap();
+        let mut receivers = Vec::new();
+        for expires_at in [None, Some(u64::MAX)].iter() {
+            let (result, receiver) = ResultSender::channel();
+            engine.append_request(IORequest::Write {
+                data: vec![2],
+                expires_at: *expires_at,
+                result,
+            });
+            receivers.push(receiver);
+        }
+        // sweep is off by default
+        engine.io_cycle().unwrap();
+        assert_eq!(engine.storage.read_record(expired).unwrap(), vec![1; 6]);
+        engine.set_expiry_sweep(true);
+        let (read_result, read_receiver) = ResultSender::channel();
+        engine.append_request(IORequest::Read {
+            record_id: expired,
+            result: read_result,
+        });
+        assert_eq!(engine.io_cycle().unwrap(), 1);
+        assert!(read_receiver.recv().unwrap().unwrap().data.is_empty());
+        let mut storage = engine.into_storage();
+        for receiver in receivers.iter() {
+            let record_id = receiver.recv().unwrap().unwrap().record_id;
+            assert_eq!(storage.read_record(record_id).unwrap(), vec![2]);
+        }
+    }
+    #[test]
+    fn test_attached_storages() {
+        let tmp_dir = tempfile::tempdir().unwrap();
+        let mut engine = Engine::new(new_storage(&tmp_dir));
+        let events_path = tmp_dir.path().join("events.hex");
+        let events = Storage::new(events_path.to_str().unwrap().to_string(), 4).unwrap();
+        assert!(engine.attach("events", events).is_none());
+        let mut write_receivers = Vec::new();
+        for (data, name) in [(vec![1], None), (vec![2; 6], Some("events"))].iter() {
+            let (result, receiver) = ResultSender::channel();
+            let request = IORequest::Write {
+                data: data.clone(),
+                expires_at: None,
+                result,
+            };
+            engine.append_request(match name {
+                Some(name) => request.on("other").on(*name),
+                None => request,
+            });
+            write_receivers.push(receiver);
+        }
+        let (read_result, read_receiver) = ResultSender::channel();
+        engine.append_request(
+            IORequest::Read {
+                record_id: 0,
+                result: read_result,
+            }
+            .on("events"),
+        );
+        let (unknown_result, unknown_receiver) = ResultSender::channel();
+        engine.append_request(
+            IORequest::Read {
+                record_id: 0,
+                result: unknown_result,
+            }
+            .on("missing"),
+        );
+        assert_eq!(engine.io_cycle().unwrap(), 4);
+        // both writes got record 0, each in its own file
+        for receiver in write_receivers.iter() {
+            assert_eq!(receiver.recv().unwrap().unwrap().record_id, 0);
+        }
+        assert_eq!(read_receiver.recv().unwrap().unwrap().data, vec![2; 6]);
+        assert!(matches!(
+            unknown_receiver.recv().unwrap(),
+            Err(StorageError::UnknownStorage { ref name }) if name == "missing"
+        ));
+        let mut events = engine.detach("events").unwrap();
+        assert_eq!(events.read_record(0).unwrap(), vec![2; 6]);
+        let (mut storage, storages) = engine.into_storages();
+        assert!(storages.is_empty());
+        assert_eq!(storage.read_record(0).unwrap(), vec![1]);
+    }
+    #[test]
+    fn test_traced_requests() {
+        let tmp_dir = tempfile::tempdir().unwrap();
+        let mut engine = Engine::new(new_storage(&tmp_dir));
+        let events_path = tmp_dir.path().join("events.hex");
+        let events = Storage::new(events_path.to_str().unwrap().to_string(), 4).unwrap();
+        engine.attach("events", events);
+        let write = |byte: u8| {
+            let (result, receiver) = ResultSender::channel();
+            let request = IORequest::Write {
+                data: vec![byte],
+                expires_at: None,
+                result,
+            };
+            (request, receiver)
+        };
+        // - traced before or after it is addressed, and traced again
+        let (first, first_receiver) = write(1);
+        let first = first.traced("a").on("events");
+        let (second, second_receiver) = write(2);
+        let second = second.on("events").traced("b").traced("c");
+        assert_eq!(first.trace_id(), Some("a"));
+        assert_eq!(second.trace_id(), Some("c"));
+        engine.append_request(first);
+        engine.append_request(second);
+        assert_eq!(engine.io_cycle().unwrap(), 2);
+        assert_eq!(first_receiver.recv().unwrap().unwrap().record_id, 0);
+        assert_eq!(second_receiver.recv().unwrap().unwrap().record_id, 1);
+        let mut events = engine.detach("events").unwrap();
+        assert_eq!(events.read_record(1).unwrap(), vec![2]);
+        assert!(engine.storage.read_record(0).unwrap().is_empty());
+        // - errors carry the trace id, whether the request failed serving or before
+        let (missing, missing_receiver) = write(3);
+        engine.append_request(missing.on("events").traced("d"));
+        let (cancelled, cancelled_receiver) = write(4);
+        engine.append_request(cancelled.traced("e")).cancel();
+        engine.io_cycle().unwrap();
+        let error = missing_receiver.recv().unwrap().unwrap_err();
+        assert_eq!(error.trace_id(), Some("d"));
+        assert!(matches!(
+            error.untraced(),
+            StorageError::UnknownStorage { name } if name == "events"
+        ));
+        assert_eq!(
+            error.to_string(),
+            "No storage named events is attached to the engine (trace id d)"
+        );
+        let error = cancelled_receiver.recv().unwrap().unwrap_err();
+        assert_eq!(error.trace_id(), Some("e"));
+        assert!(matches!(error.untraced(), StorageError::Cancelled));
+    }
+    #[test]
+    fn test_dropped_receiver() {
+        let tmp_dir = tempfile::tempdir().unwrap();
+        let mut engine = Engine::new(new_storage(&tmp_dir));
+        let (result, receiver) = ResultSender::channel();
+        drop(receiver);
+        engine.append_request(IORequest::Write {
+            data: vec![1],
+            expires_at: None,
+            result,
+        });
+        // write is applied even though nobody waits for its result
+        assert_eq!(engine.io_cycle().unwrap(), 1);
+        let mut storage = engine.into_storage();
+        assert_eq!(storage.read_record(0).unwrap(), vec![1]);
+    }
+    #[test]
+    fn test_priority_cycles() {
+        let tmp_dir = tempfile::tempdir().unwrap();
+        let mut engine = Engine::new(new_storage(&tmp_dir));
+        engine.set_cycle_limit(Some(1));
+        engine.set_aging_cycles(1);
+        let (compact_result, compact_receiver) = ResultSender::channel();
+        engine.append_request_with_priority(
+            IORequest::CompactStep {
+                max_moves: 1,
+                result: compact_result,
+            },
+            Priority::Background,
+        );
+        // a new high priority request every cycle
+        let mut write_receivers = Vec::new();
+        for byte in 0..3 {
+            let (result, receiver) = ResultSender::channel();
+            engine.append_request_with_priority(
+                IORequest::Write {
+                    data: vec![byte],
+                    expires_at: None,
+                    result,
+                },
+                Priority::High,
+            );
+            write_receivers.push(receiver);
+            assert_eq!(engine.io_cycle().unwrap(), 1);
+        }
+        // background request was promoted to Normal, then High, and served before last write
+        assert!(compact_receiver.try_recv().unwrap().unwrap().is_empty());
+        assert!(write_receivers[2].try_recv().is_err());
+        assert_eq!(engine.io_cycle().unwrap(), 1);
+        assert_eq!(write_receivers[2].try_recv().unwrap().unwrap().record_id, 2);
+    }
+    #[test]
+    fn test_request_order() {
+        let tmp_dir = tempfile::tempdir().unwrap();
+        let mut engine = Engine::new(new_storage(&tmp_dir));
+        // - normal write, then high priority read of the record it writes
+        let write_then_read = |engine: &mut Engine| {
+            let (write_result, _) = ResultSender::channel();
+            engine.append_request(IORequest::Write {
+                data: vec![5],
+                expires_at: None,
+                result: write_result,
+            });
+            let (read_result, read_receiver) = ResultSender::channel();
+            engine.append_request_with_priority(
+                IORequest::Read {
+                    record_id: 0,
+                    result: read_result,
+                },
+                Priority::High,
+            );
+            engine.io_cycle().unwrap();
+            read_receiver.recv().unwrap().unwrap().data
+        };
+        // - by priority the read is served first and misses the write
+        assert_eq!(write_then_read(&mut engine), Vec::<u8>::new());
+        engine.storage.delete_record(0, false).unwrap();
+        // - in submission order the read sees the write
+        engine.set_request_order(RequestOrder::Submission);
+        assert_eq!(write_then_read(&mut engine), vec![5]);
+    }
+    #[test]
+    fn test_cancel_and_timeout() {
+        let tmp_dir = tempfile::tempdir().unwrap();
+        let mut engine = Engine::new(new_storage(&tmp_dir));
+        let mut receivers = Vec::new();
+        let mut handles = Vec::new();
+        for byte in 0..3 {
+            let (result, receiver) = ResultSender::channel();
+            handles.push(engine.append_request(IORequest::Write {
+                data: vec![byte],
+                expires_at: None,
+                result,
+            }));
+            receivers.push(receiver);
+        }
+        handles[0].cancel();
+        handles[1].set_deadline(std::time::Instant::now());
+        handles[2].set_timeout(std::time::Duration::from_secs(60));
+        // skipped requests are not counted as served
+        assert_eq!(engine.io_cycle().unwrap(), 1);
+        assert!(matches!(
+            receivers[0].recv().unwrap(),
+            Err(StorageError::Cancelled)
+        ));
+        assert!(matches!(
+            receivers[1].recv().unwrap(),
+            Err(StorageError::TimedOut)
+        ));
+        assert_eq!(receivers[2].recv().unwrap().unwrap().record_id, 0);
+        // cancelling a served request has no effect
+        handles[2].cancel();
+        assert_eq!(engine.storage.read_record(0).unwrap(), vec![2]);
+    }
+    #[test]
+    fn test_spawn() {
+        let tmp_dir = tempfile::tempdir().unwrap();
+        let engine = Engine::spawn(new_storage(&tmp_dir));
+        let written = engine.write(vec![7u8; 9]).recv().unwrap().unwrap();
+        let record_id = written.record_id;
+        assert_eq!(written.block_indexes, vec![record_id, 1, 2]);
+        assert_eq!(written.bytes_written, 9);
+        let read = engine.read(record_id).recv().unwrap().unwrap();
+        assert_eq!(read.data, vec![7u8; 9]);
+        assert_eq!((read.blocks_read, read.bytes), (3, 9));
+        assert_eq!(
+            engine.read_blocks(record_id).recv().unwrap().unwrap(),
+            vec![vec![7u8; 4], vec![7u8; 4], vec![7u8; 1]]
+        );
+        // results of queued requests arrive even if the handle is joined first
+        let delete_receiver = engine.delete(record_id, false);
+        let mut storage = engine.join().unwrap();
+        assert_eq!(delete_receiver.recv().unwrap().unwrap(), 3);
+        assert_eq!(storage.read_record(record_id).unwrap(), Vec::<u8>::new());
+    }
+    #[test]
+    fn test_shutdown() {
+        let tmp_dir = tempfile::tempdir().unwrap();
+        // - DrainAll serves every request sent before shutdown
+        let handle = Engine::spawn(new_storage(&tmp_dir));
+        let receivers: Vec<_> = (0..4u8).map(|byte| handle.write(vec![byte; 5])).collect();
+        let storage = handle.shutdown(DrainPolicy::DrainAll).unwrap();
+        for receiver in receivers {
+            assert!(receiver.recv().unwrap().is_ok());
+        }
+        // - RejectNew serves requests already in the engine queue
+        let queued_read = |engine: &mut Engine| {
+            let (result, receiver) = ResultSender::channel();
+            engine.append_request(IORequest::Read {
+                record_id: 0,
+                result,
+            });
+            receiver
+        };
+        let mut engine = Engine::new(storage);
+        engine.set_cycle_limit(Some(1));
+        let queued: Vec<_> = (0..3).map(|_| queued_read(&mut engine)).collect();
+        let handle = engine.spawn_engine();
+        let sent = handle.write(vec![9]);
+        let storage = handle.shutdown(DrainPolicy::RejectNew).unwrap();
+        for receiver in queued {
+            assert_eq!(receiver.recv().unwrap().unwrap().data, vec![0; 5]);
+        }
+        // -- request sent right before shutdown may or may not have been taken in
+        assert!(matches!(
+            sent.recv().unwrap(),
+            Ok(_) | Err(StorageError::ShuttingDown)
+        ));
+        // - Abort rejects requests left after the cycle being served
+        let mut engine = Engine::new(storage);
+        engine.set_cycle_limit(Some(1));
+        let queued: Vec<_> = (0..3).map(|_| queued_read(&mut engine)).collect();
+        engine.spawn_engine().shutdown(DrainPolicy::Abort).unwrap();
+        let results: Vec<_> = queued.iter().map(|r| r.recv().unwrap()).collect();
+        let served = results.iter().take_while(|result| result.is_ok()).count();
+        assert!(results[served..]
+            .iter()
+            .all(|result| matches!(result, Err(StorageError::ShuttingDown))));
+    }
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn test_spawned_flush_error() {
+        let tmp_dir = tempfile::tempdir().unwrap();
+        let mut storage = new_storage(&tmp_dir);
+        storage.set_durability(DurabilityMode::EveryNWrites(100));
+        storage.fail_syncs();
+        let handle = Engine::spawn(storage);
+        // - write is served, the flush at the end of its cycle fails
+        assert!(handle.write(vec![1]).recv().unwrap().is_ok());
+        // - engine stops serving after it
+        assert!(matches!(
+            handle.read(0).recv().unwrap(),
+            Err(StorageError::EngineStopped)
+        ));
+        assert_eq!(handle.metrics().cycle_errors(), 1);
+        assert!(handle.join().is_some());
+    }
+    #[cfg(feature = "tracing")]
+    #[test]
+    fn test_tracing_spans() {
+        use std::sync::Mutex;
+        use tracing::field::{Field, Visit};
+        use tracing::span::{Attributes, Id, Record};
+
+        /// Metadata and integer fields of every span created
+        type Spans = Arc<
+            Mutex<
+                Vec<(
+                    &'static tracing::Metadata<'static>,
+                    Vec<(&'static str, u64)>,
+                )>,
+            >,
+        >;
+        /// Fields of the entered span at every event
+        type Events = Arc<Mutex<Vec<Vec<(&'static str, u64)>>>>;
+        /// Spans created, stack of entered spans, and events
+        struct SpanLog(Spans, Mutex<Vec<Id>>, Events);
+        struct Fields<'a>(&'a mut Vec<(&'static str, u64)>);
+        impl Visit for Fields<'_> {
+            fn record_u64(&mut self, field: &Field, value: u64) {
+                self.0.push((field.name(), value));
+            }
+            fn record_str(&mut self, field: &Field, value: &str) {
+                if let Ok(value) = value.parse() {
+                    self.0.push((field.name(), value));
+                }
+            }
+            // - arguments of type aliases like BlockIndex are recorded with Debug
+            fn record_debug(&mut self, field: &Field, value: &dyn std::fmt::Debug) {
+                if let Ok(value) = format!("{:?}", value).parse() {
+                    self.0.push((field.name(), value));
+                }
+            }
+        }
+        impl tracing::Subscriber for SpanLog {
+            fn enabled(&self, _: &tracing::Metadata<'_>) -> bool {
+                true
+            }
+            fn new_span(&self, span: &Attributes<'_>) -> Id {
+                let mut spans = self.0.lock().unwrap();
+                let mut fields = Vec::new();
+                span.record(&mut Fields(&mut fields));
+                spans.push((span.metadata(), fields));
+                Id::from_u64(spans.len() as u64)
+            }
+            fn record(&self, span: &Id, values: &Record<'_>) {
+                let mut spans = self.0.lock().unwrap();
+                let position = span.into_u64() as usize - 1;
+                values.record(&mut Fields(&mut spans[position].1));
+            }
+            fn record_follows_from(&self, _: &Id, _: &Id) {}
+            fn event(&self, _: &tracing::Event<'_>) {
+                if let Some(span) = self.1.lock().unwrap().last() {
+                    let fields = self.0.lock().unwrap()[span.into_u64() as usize - 1]
+                        .1
+                        .clone();
+                    self.2.lock().unwrap().push(fields);
+                }
+            }
+            fn enter(&self, span: &Id) {
+                self.1.lock().unwrap().push(span.clone());
+            }
+            fn exit(&self, _: &Id) {
+                self.1.lock().unwrap().pop();
+            }
+            // - Span::current records fields on the entered span
+            fn current_span(&self) -> tracing_core::span::Current {
+                match self.1.lock().unwrap().last() {
+                    Some(span) => {
+                        let metadata = self.0.lock().unwrap()[span.into_u64() as usize - 1].0;
+                        tracing_core::span::Current::new(span.clone(), metadata)
+                    }
+                    None => tracing_core::span::Current::none(),
+                }
+            }
+        }
+
+        let tmp_dir = tempfile::tempdir().unwrap();
+        let mut engine = Engine::new(new_storage(&tmp_dir));
+        let spans = Spans::default();
+        let events = Events::default();
+        let span_log = SpanLog(spans.clone(), Mutex::default(), events.clone());
+        tracing::subscriber::with_default(span_log, || {
+            engine.storage.write_block(1, &[1, 2, 3]).unwrap();
+            engine.storage.read_block(1).unwrap();
+            let (result, _) = ResultSender::channel();
+            engine.append_request(
+                IORequest::Delete {
+                    record_id: 1,
+                    hard_delete: false,
+                    result,
+                }
+                .traced("7"),
+            );
+            engine.io_cycle().unwrap();
+            engine.storage.write_block(0, &[4]).unwrap();
+            engine.storage.write_block(2, &[5]).unwrap();
+            engine.storage.compact().unwrap();
+        });
+        let spans = spans.lock().unwrap();
+        let fields_of = |name: &str| {
+            spans
+                .iter()
+                .find(|(metadata, _)| metadata.name() == name)
+                .map(|(_, fields)| fields.clone())
+                .unwrap()
+        };
+        assert_eq!(
+            fields_of("write_block"),
+            vec![("block_index", 1), ("bytes", 3)]
+        );
+        assert_eq!(
+            fields_of("read_block"),
+            vec![("block_index", 1), ("bytes", 3)]
+        );
+        assert_eq!(fields_of("io_cycle"), vec![("queued", 1), ("served", 1)]);
+        assert_eq!(fields_of("delete_block"), vec![("block_index", 1)]);
+        assert_eq!(fields_of("request"), vec![("trace_id", 7)]);
+        // - result of the traced delete was discarded inside its span
+        assert!(events.lock().unwrap().contains(&vec![("trace_id", 7)]));
+        assert_eq!(
+            fields_of("compact"),
+            vec![("max_moves", u64::MAX), ("moved", 1)]
+        );
+    }
+}
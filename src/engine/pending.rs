@@ -0,0 +1,268 @@
+//! Snapshot of the requests an engine holds, queued or being served, see Engine::pending
+use super::*;
+use std::collections::BTreeMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::time::Duration;
+
+/// Operation of a request, see IORequest
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RequestKind {
+    Read,
+    ReadBlocks,
+    Write,
+    Delete,
+    ScanBlocks,
+    CompactStep,
+    Commit,
+}
+
+/// Where a pending request is
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PendingState {
+    /// Sent or appended, waiting for its turn in an io_cycle
+    Queued,
+    /// Taken from the queue by an io_cycle, on the engine thread or the read pool, and
+    /// not answered yet, e.g. waiting for the flush of group commit
+    Serving,
+}
+
+/// Request an engine holds, see Engine::pending
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PendingRequest {
+    /// Id given when the request was queued, ascending in queueing order, see
+    /// RequestHandle::id
+    pub id: u64,
+    pub kind: RequestKind,
+    pub state: PendingState,
+    /// Priority the request was queued with, aging may serve it at a higher one
+    pub priority: Priority,
+    /// Time since the request was queued
+    pub age: Duration,
+    /// Bytes of data the request writes, 0 for requests that do not write
+    pub size: u64,
+    /// Storage the request is addressed to, None for the default storage
+    pub storage: Option<String>,
+    /// Trace id of the request, see IORequest::traced
+    pub trace_id: Option<String>,
+}
+
+/// Pending request as tracked, see PendingRequest
+struct Entry {
+    kind: RequestKind,
+    state: PendingState,
+    priority: Priority,
+    queued_at: Instant,
+    size: u64,
+    storage: Option<String>,
+    trace_id: Option<String>,
+}
+
+/// Requests of an engine from the time they are queued until their result is sent, or
+/// until they are dropped without one, shared by Engine and EngineHandle
+#[derive(Default)]
+pub(super) struct PendingTracker {
+    last_id: AtomicU64,
+    entries: Mutex<BTreeMap<u64, Entry>>,
+}
+
+impl PendingTracker {
+    fn entries(&self) -> std::sync::MutexGuard<'_, BTreeMap<u64, Entry>> {
+        match self.entries.lock() {
+            Ok(entries) => entries,
+            Err(poisoned) => poisoned.into_inner(),
+        }
+    }
+    /// Track request queued with priority, until its result is sent
+    /// - returns: request to queue and its handle, holding its id
+    pub(super) fn track(
+        self: &Arc<Self>,
+        request: IORequest,
+        priority: Priority,
+    ) -> (IORequest, RequestHandle) {
+        let id = self.last_id.fetch_add(1, Ordering::Relaxed) + 1;
+        let entry = Entry {
+            kind: request.kind(),
+            state: PendingState::Queued,
+            priority,
+            queued_at: Instant::now(),
+            size: request.data_len() as u64,
+            storage: request.storage_name().map(str::to_string),
+            trace_id: request.trace_id().map(str::to_string),
+        };
+        self.entries().insert(id, entry);
+        let request = request.map_sender(&mut Some(Tracked {
+            tracker: self.clone(),
+            id,
+        }));
+        (request, RequestHandle::with_id(id))
+    }
+    /// Note that the request of id was taken from the queue to be served
+    pub(super) fn serving(&self, id: u64) {
+        if let Some(entry) = self.entries().get_mut(&id) {
+            entry.state = PendingState::Serving;
+        }
+    }
+    /// Every tracked request, in id order
+    pub(super) fn snapshot(&self) -> Vec<PendingRequest> {
+        self.entries()
+            .iter()
+            .map(|(id, entry)| PendingRequest {
+                id: *id,
+                kind: entry.kind,
+                state: entry.state,
+                priority: entry.priority,
+                age: entry.queued_at.elapsed(),
+                size: entry.size,
+                storage: entry.storage.clone(),
+                trace_id: entry.trace_id.clone(),
+            })
+            .collect()
+    }
+}
+
+/// Entry of a tracked request, removed when dropped with its result sender
+struct Tracked {
+    tracker: Arc<PendingTracker>,
+    id: u64,
+}
+
+impl Drop for Tracked {
+    fn drop(&mut self) {
+        self.tracker.entries().remove(&self.id);
+    }
+}
+
+/// Keep entry until result is sent, or dropped with result
+impl MapSender for Option<Tracked> {
+    fn map<T: RequestResult>(&mut self, _: OpKind, result: ResultSender<T>) -> ResultSender<T> {
+        let tracked = self.take();
+        result.inspect(move |_| drop(tracked))
+    }
+}
+
+impl IORequest {
+    /// Operation of request, of the request wrapped if addressed or traced
+    pub fn kind(&self) -> RequestKind {
+        match self {
+            IORequest::Read { .. } => RequestKind::Read,
+            IORequest::ReadBlocks { .. } => RequestKind::ReadBlocks,
+            IORequest::Write { .. } => RequestKind::Write,
+            IORequest::Delete { .. } => RequestKind::Delete,
+            IORequest::ScanBlocks { .. } => RequestKind::ScanBlocks,
+            IORequest::CompactStep { .. } => RequestKind::CompactStep,
+            IORequest::Commit { .. } => RequestKind::Commit,
+            IORequest::OnStorage { request, .. } | IORequest::Traced { request, .. } => {
+                request.kind()
+            }
+        }
+    }
+}
+
+impl Engine {
+    /// Requests queued and being served, with their id, kind, age, size and priority,
+    /// e.g. to see what a stuck workload waits for
+    /// - a request is pending from the time it is appended until its result is sent,
+    ///   or until it is dropped without one
+    pub fn pending(&self) -> Vec<PendingRequest> {
+        self.pending.snapshot()
+    }
+}
+
+impl EngineHandle {
+    /// Requests sent to the background engine and not answered yet, see Engine::pending
+    /// - includes requests sent and not yet received by the engine thread, as Queued
+    pub fn pending(&self) -> Vec<PendingRequest> {
+        self.pending.snapshot()
+    }
+}
+
+#[cfg(test)]
+mod unit_tests_pending {
+    use super::*;
+    use crate::storage::BlockObserver;
+    use std::sync::mpsc::{Receiver, Sender};
+
+    fn new_storage(tmp_dir: &tempfile::TempDir) -> Storage {
+        let file_path = tmp_dir.path().join("pending.hex");
+        Storage::new(file_path.to_str().unwrap().to_string(), 4).unwrap()
+    }
+    /// Signals every write, then waits until it is released
+    struct BlockingObserver {
+        writing: Sender<()>,
+        release: Receiver<()>,
+    }
+    impl BlockObserver for BlockingObserver {
+        fn before_write(&mut self, _block_index: BlockIndex, _data: &[u8]) {
+            let _ = self.writing.send(());
+            let _ = self.release.recv();
+        }
+    }
+
+    #[test]
+    fn test_pending_requests() {
+        let tmp_dir = tempfile::tempdir().unwrap();
+        let mut engine = Engine::new(new_storage(&tmp_dir));
+        let (write, _write_receiver) = RequestBuilder::write(vec![1; 6], None)
+            .traced("write")
+            .build();
+        let write_handle = engine.append_request(write);
+        let (read, _read_receiver) = RequestBuilder::read(0).on("events").build();
+        let read_handle = engine.append_request_with_priority(read, Priority::High);
+        let pending = engine.pending();
+        assert_eq!(pending.len(), 2);
+        assert_eq!(pending[0].id, write_handle.id());
+        assert_eq!(
+            (pending[0].kind, pending[0].state, pending[0].size),
+            (RequestKind::Write, PendingState::Queued, 6)
+        );
+        assert_eq!(pending[0].trace_id.as_deref(), Some("write"));
+        assert_eq!(pending[1].id, read_handle.id());
+        assert!(read_handle.id() > write_handle.id());
+        assert_eq!(
+            (pending[1].kind, pending[1].priority, pending[1].size),
+            (RequestKind::Read, Priority::High, 0)
+        );
+        assert_eq!(pending[1].storage.as_deref(), Some("events"));
+        // - answered requests, also failed ones, are no longer pending
+        engine.io_cycle().unwrap();
+        assert!(engine.pending().is_empty());
+        let (read, read_receiver) = RequestBuilder::read(0).build();
+        drop(read_receiver);
+        engine.append_request(read).cancel();
+        engine.io_cycle().unwrap();
+        assert!(engine.pending().is_empty());
+    }
+    #[test]
+    fn test_pending_serving() {
+        let tmp_dir = tempfile::tempdir().unwrap();
+        let mut storage = new_storage(&tmp_dir);
+        let (writing, writing_receiver) = mpsc::channel();
+        let (release, release_receiver) = mpsc::channel();
+        storage.add_observer(Box::new(BlockingObserver {
+            writing,
+            release: release_receiver,
+        }));
+        let handle = Engine::spawn(storage);
+        let write_receiver = handle.write(vec![1; 3]);
+        // - the write blocks in its observer while it is served
+        writing_receiver.recv().unwrap();
+        let read_receiver = handle.read(0);
+        let pending = handle.pending();
+        assert_eq!(pending.len(), 2);
+        assert_eq!(
+            (pending[0].kind, pending[0].state),
+            (RequestKind::Write, PendingState::Serving)
+        );
+        assert_eq!(
+            (pending[1].kind, pending[1].state),
+            (RequestKind::Read, PendingState::Queued)
+        );
+        release.send(()).unwrap();
+        assert!(write_receiver.recv().unwrap().is_ok());
+        assert!(read_receiver.recv().unwrap().is_ok());
+        assert!(handle.pending().is_empty());
+        drop(release);
+        handle.join().unwrap();
+    }
+}
@@ -0,0 +1,171 @@
+use super::*;
+use crate::storage::{SharedReader, StoredChunk};
+
+/// Stored chunks of a record read by a pool thread, None if the thread panicked
+type PoolResult = Option<Result<Vec<StoredChunk>, StorageError>>;
+
+impl IORequest {
+    /// Record read by Read and ReadBlocks requests, None for other requests
+    fn read_record_id(&self) -> Option<RecordId> {
+        match self {
+            IORequest::Read { record_id, .. } | IORequest::ReadBlocks { record_id, .. } => {
+                Some(*record_id)
+            }
+            _ => None,
+        }
+    }
+    /// Send decoded chunks of a record as result of Read or ReadBlocks request
+    fn send_chunks(self, chunks: Vec<Vec<u8>>) {
+        match self {
+            IORequest::Read { result, .. } => result.send(Ok(ReadResponse::from_chunks(chunks))),
+            IORequest::ReadBlocks { result, .. } => result.send(Ok(chunks)),
+            request => request.fail(StorageError::Corruption {
+                block_index: None,
+                reason: "read result sent to a request that is not a read",
+            }),
+        }
+    }
+}
+
+impl Engine {
+    /// Serve independent read requests with up to read_concurrency threads per io_cycle
+    /// - default is 1, every request is served on the engine thread
+    /// - consecutive read requests in the queue are split over the threads, a write or
+    ///   delete in between waits for them, so reads never see a later write
+    /// - threads share the storage file through positional reads, bypassing block cache
+    ///   and read backend; a read that fails is served again on the engine thread
+    pub fn set_read_concurrency(&mut self, read_concurrency: usize) {
+        self.read_concurrency = read_concurrency.max(1);
+    }
+    /// Take first read and following read requests from queue, at most max_reads
+    /// - cancelled and expired reads get their error right away, and are not counted
+    pub(super) fn pop_reads(&mut self, first_read: IORequest, max_reads: usize) -> Vec<IORequest> {
+        let mut reads = vec![first_read];
+        while reads.len() < max_reads {
+            let is_read = |request: &IORequest| request.read_record_id().is_some();
+            match self.requests.pop_if(is_read) {
+                Some((request, handle)) => match handle.skip_error() {
+                    Some(error) => request.fail(error),
+                    None => {
+                        self.pending.serving(handle.id());
+                        reads.push(request)
+                    }
+                },
+                None => break,
+            }
+        }
+        reads
+    }
+    pub(super) fn is_pooled_read(&self, request: &IORequest) -> bool {
+        self.read_concurrency > 1 && request.read_record_id().is_some()
+    }
+    /// Read records of read requests on pool threads, then decode and send results
+    pub(super) fn serve_reads(&mut self, reads: Vec<IORequest>) {
+        let reads: Vec<IORequest> = reads
+            .into_iter()
+            .map(|read| read.observed(&self.metrics, true))
+            .collect();
+        let record_ids: Vec<RecordId> =
+            reads.iter().filter_map(IORequest::read_record_id).collect();
+        let chunk_len = record_ids.len().div_ceil(self.read_concurrency).max(1);
+        let reader = self.storage.shared_reader();
+        let results: Vec<PoolResult> = thread::scope(|scope| {
+            let threads: Vec<_> = record_ids
+                .chunks(chunk_len)
+                .map(|record_ids| {
+                    (
+                        record_ids.len(),
+                        scope.spawn(move || read_all(reader, record_ids)),
+                    )
+                })
+                .collect();
+            threads
+                .into_iter()
+                .flat_map(|(read_count, thread)| match thread.join() {
+                    Ok(results) => results,
+                    Err(_) => (0..read_count).map(|_| None).collect(),
+                })
+                .collect()
+        });
+        for (request, result) in reads.into_iter().zip(results) {
+            match result.map(|stored| stored.and_then(|chunks| self.storage.decode_chunks(chunks)))
+            {
+                Some(Ok(chunks)) => request.send_chunks(chunks),
+                // - serve again on engine thread, to notify observers and use the backend
+                // - reads are served alike in every ack mode
+                _ => request.serve(&mut self.storage, AckMode::Applied),
+            }
+        }
+    }
+}
+
+fn read_all(reader: SharedReader, record_ids: &[RecordId]) -> Vec<PoolResult> {
+    record_ids
+        .iter()
+        .map(|record_id| Some(reader.read_record_chunks(*record_id)))
+        .collect()
+}
+
+#[cfg(test)]
+mod unit_tests_read_pool {
+    use super::*;
+
+    #[test]
+    fn test_concurrent_reads() {
+        let tmp_dir = tempfile::tempdir().unwrap();
+        let file_path = tmp_dir.path().join("read_pool.hex");
+        let storage = Storage::new(file_path.to_str().unwrap().to_string(), 4).unwrap();
+        let mut engine = Engine::new(storage);
+        engine.set_read_concurrency(3);
+        let record_ids: Vec<RecordId> = (1..9u8)
+            .map(|len| {
+                engine
+                    .storage
+                    .write_record(&vec![len; len as usize])
+                    .unwrap()
+            })
+            .collect();
+        let mut read_receivers = Vec::new();
+        for record_id in record_ids.iter() {
+            let (result, receiver) = ResultSender::channel();
+            engine.append_request(IORequest::Read {
+                record_id: *record_id,
+                result,
+            });
+            read_receivers.push(receiver);
+        }
+        // write between reads splits them in two pooled runs
+        let (write_result, write_receiver) = ResultSender::channel();
+        engine.append_request(IORequest::Write {
+            data: vec![9; 9],
+            expires_at: None,
+            result: write_result,
+        });
+        let (blocks_result, blocks_receiver) = ResultSender::channel();
+        engine.append_request(IORequest::ReadBlocks {
+            record_id: record_ids[7],
+            result: blocks_result,
+        });
+        let (cancelled_result, cancelled_receiver) = ResultSender::channel();
+        engine
+            .append_request(IORequest::Read {
+                record_id: record_ids[0],
+                result: cancelled_result,
+            })
+            .cancel();
+        assert_eq!(engine.io_cycle().unwrap(), 10);
+        for (len, receiver) in (1..9u8).zip(read_receivers.iter()) {
+            assert_eq!(
+                receiver.recv().unwrap().unwrap().data,
+                vec![len; len as usize]
+            );
+        }
+        let record_id = write_receiver.recv().unwrap().unwrap().record_id;
+        assert_eq!(engine.storage.read_record(record_id).unwrap(), vec![9; 9]);
+        assert_eq!(blocks_receiver.recv().unwrap().unwrap().len(), 2);
+        assert!(matches!(
+            cancelled_receiver.recv().unwrap(),
+            Err(StorageError::Cancelled)
+        ));
+    }
+}
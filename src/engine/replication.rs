@@ -0,0 +1,522 @@
+//! Replication of the storage of a primary Engine to a follower Storage, e.g. for a warm
+//! standby
+//! - a Primary set with Engine::set_replication ships the blocks changed by every
+//!   io_cycle over a Transport, as ReplicationMessages
+//! - a Follower applies the messages to its own storage, in the order they were sent
+//! - a follower that fell behind catches up from its last synced generation, see
+//!   Primary::catch_up, a new or diverged follower is resynced in full, see Primary::new
+use super::*;
+use crate::storage::{BlockChange, BlockObserver, BlockStore, Generation};
+use std::collections::BTreeSet;
+use std::convert::TryFrom;
+use std::io::{self, Read, Write};
+use std::sync::{Mutex, Weak};
+
+/// Message of a primary to its followers
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ReplicationMessage {
+    /// Content of a block changed on the primary, see Storage::apply_change
+    Change(BlockChange),
+    /// Every change up to generation was sent, and the primary has block_count blocks
+    /// - sent after the changes of every shipment
+    Synced {
+        generation: Generation,
+        block_count: u64,
+    },
+}
+
+const MESSAGE_CHANGE: u8 = 1;
+const MESSAGE_SYNCED: u8 = 2;
+
+/// Largest encoded message WriteTransport sends and read_from accepts, 64 MiB
+/// - a change holds one block, so block_len of a replicated storage must stay below it
+pub const MAX_MESSAGE_LEN: u32 = 64 * 1024 * 1024;
+
+/// Error of a message that can not be decoded
+const MALFORMED_MESSAGE: StorageError = StorageError::Corruption {
+    block_index: None,
+    reason: "replication message is malformed",
+};
+
+impl ReplicationMessage {
+    /// Message as bytes, for transports of bytes, see WriteTransport
+    /// - little endian, a type byte and its fields, block data is the rest of a change
+    pub fn encode(&self) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        match self {
+            ReplicationMessage::Change(change) => {
+                bytes.push(MESSAGE_CHANGE);
+                bytes.extend_from_slice(&change.block_index.to_le_bytes());
+                bytes.extend_from_slice(&change.generation.to_le_bytes());
+                // - next block stored as index + 1, expiry as 0 for None, like block headers
+                let next_block = change.next_block.map_or(0, |next| next.saturating_add(1));
+                bytes.extend_from_slice(&next_block.to_le_bytes());
+                bytes.extend_from_slice(&change.expires_at.unwrap_or(0).to_le_bytes());
+                bytes.extend_from_slice(&change.tag.to_le_bytes());
+                bytes.extend_from_slice(&change.data);
+            }
+            ReplicationMessage::Synced {
+                generation,
+                block_count,
+            } => {
+                bytes.push(MESSAGE_SYNCED);
+                bytes.extend_from_slice(&generation.to_le_bytes());
+                bytes.extend_from_slice(&block_count.to_le_bytes());
+            }
+        }
+        bytes
+    }
+    /// Message from bytes of encode
+    /// - Corruption if bytes are not a message
+    pub fn decode(bytes: &[u8]) -> Result<ReplicationMessage, StorageError> {
+        let u64_at = |offset: usize| {
+            let field = bytes.get(offset..offset + 8).ok_or(MALFORMED_MESSAGE)?;
+            let mut field_bytes = [0u8; 8];
+            field_bytes.copy_from_slice(field);
+            Ok::<u64, StorageError>(u64::from_le_bytes(field_bytes))
+        };
+        match bytes.first() {
+            Some(&MESSAGE_CHANGE) => {
+                let tag = bytes.get(33..35).ok_or(MALFORMED_MESSAGE)?;
+                let next_block = u64_at(17)?;
+                let expires_at = u64_at(25)?;
+                Ok(ReplicationMessage::Change(BlockChange {
+                    block_index: u64_at(1)?,
+                    generation: u64_at(9)?,
+                    data: bytes[35..].to_vec(),
+                    next_block: next_block.checked_sub(1),
+                    expires_at: Some(expires_at).filter(|expires_at| *expires_at != 0),
+                    tag: u16::from_le_bytes([tag[0], tag[1]]),
+                }))
+            }
+            Some(&MESSAGE_SYNCED) if bytes.len() == 17 => Ok(ReplicationMessage::Synced {
+                generation: u64_at(1)?,
+                block_count: u64_at(9)?,
+            }),
+            _ => Err(MALFORMED_MESSAGE),
+        }
+    }
+    /// Read next message written by WriteTransport
+    /// - TooLarge for a length above MAX_MESSAGE_LEN, before anything is allocated for it,
+    ///   so a corrupt or hostile peer can not make the follower allocate 4 GiB
+    /// - returns: None at end of reader, between messages
+    pub fn read_from(reader: &mut impl Read) -> Result<Option<ReplicationMessage>, StorageError> {
+        let mut len_bytes = [0u8; 4];
+        match reader.read_exact(&mut len_bytes) {
+            Ok(()) => {}
+            Err(error) if error.kind() == io::ErrorKind::UnexpectedEof => return Ok(None),
+            Err(error) => return Err(replication_io("read replication message")(error)),
+        }
+        let len = u32::from_le_bytes(len_bytes);
+        if len > MAX_MESSAGE_LEN {
+            return Err(StorageError::TooLarge {
+                len: u64::from(len),
+                max: u64::from(MAX_MESSAGE_LEN),
+            });
+        }
+        let len = usize::try_from(len).map_err(|_| MALFORMED_MESSAGE)?;
+        let mut bytes = vec![0u8; len];
+        reader
+            .read_exact(&mut bytes)
+            .map_err(replication_io("read replication message"))?;
+        ReplicationMessage::decode(&bytes).map(Some)
+    }
+}
+
+fn replication_io(operation: &'static str) -> impl FnOnce(io::Error) -> StorageError {
+    move |source| StorageError::Io {
+        operation,
+        block_index: None,
+        source,
+    }
+}
+
+// ... ... ... ... ... ... ... ... ... Transport ... ... ... ... ... ... ... ... ... ..
+
+/// Channel carrying messages of a primary to a follower
+/// - messages must arrive in the order they were sent, a failed send makes the primary
+///   catch up its follower from the last Synced message on the next shipment
+pub trait Transport: Send {
+    fn send(&mut self, message: &ReplicationMessage) -> Result<(), StorageError>;
+}
+
+/// Transport to a follower in the same process, e.g. on another thread
+impl Transport for mpsc::Sender<ReplicationMessage> {
+    fn send(&mut self, message: &ReplicationMessage) -> Result<(), StorageError> {
+        mpsc::Sender::send(self, message.clone()).map_err(|_| {
+            replication_io("send replication message")(io::Error::new(
+                io::ErrorKind::BrokenPipe,
+                "follower disconnected",
+            ))
+        })
+    }
+}
+
+/// Transport writing encoded messages to a byte stream, e.g. a TcpStream
+/// - every message is its length as u32 and its bytes, see ReplicationMessage::encode,
+///   read them with ReplicationMessage::read_from
+/// - messages longer than MAX_MESSAGE_LEN fail with TooLarge, nothing is written
+pub struct WriteTransport<W: Write + Send>(pub W);
+
+impl<W: Write + Send> Transport for WriteTransport<W> {
+    fn send(&mut self, message: &ReplicationMessage) -> Result<(), StorageError> {
+        let bytes = message.encode();
+        let len = u32::try_from(bytes.len())
+            .ok()
+            .filter(|len| *len <= MAX_MESSAGE_LEN)
+            .ok_or(StorageError::TooLarge {
+                len: bytes.len() as u64,
+                max: u64::from(MAX_MESSAGE_LEN),
+            })?;
+        self.0
+            .write_all(&len.to_le_bytes())
+            .and_then(|_| self.0.write_all(&bytes))
+            .and_then(|_| self.0.flush())
+            .map_err(replication_io("send replication message"))
+    }
+}
+
+// ... ... ... ... ... ... ... ... ... Primary ... ... ... ... ... ... ... ... ... ..
+
+/// Blocks to ship with the next shipment of a Primary
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Shipment {
+    /// Blocks changed since the last shipment, as collected by ChangeCollector
+    Changed,
+    /// Blocks changed after generation, see Storage::changed_blocks_since
+    Since(Generation),
+    /// Every block of the storage, used or free
+    Full,
+}
+
+/// Primary side of replication, set on an Engine with Engine::set_replication
+/// - replicates the storage the engine was created with, not attached storages
+/// - ships after the flush at the end of every io_cycle that changed blocks, so a
+///   follower never gets a change before it is synced on the primary (unless durability
+///   mode is Never)
+/// - a shipment is the current content of each changed block, followed by
+///   ReplicationMessage::Synced; a block changed twice in a cycle is shipped once
+pub struct Primary {
+    transport: Box<dyn Transport>,
+    /// Filled by the ChangeCollector observer of the storage
+    changed_blocks: Arc<Mutex<BTreeSet<BlockIndex>>>,
+    shipment: Shipment,
+    /// Generation of the last Synced message sent
+    synced_generation: Generation,
+}
+
+impl Primary {
+    /// Primary resyncing its follower in full first
+    /// - every block of the storage is shipped, also free blocks, and blocks of the
+    ///   follower past the end of the primary are freed, so any follower storage with
+    ///   the same block length ends up with the same blocks
+    pub fn new(transport: Box<dyn Transport>) -> Self {
+        Primary {
+            transport,
+            changed_blocks: Arc::default(),
+            shipment: Shipment::Full,
+            synced_generation: 0,
+        }
+    }
+    /// Primary catching up a follower synced up to generation first, see
+    /// Follower::generation
+    /// - only blocks changed after generation are shipped, see
+    ///   Storage::changed_blocks_since
+    pub fn catch_up(transport: Box<dyn Transport>, generation: Generation) -> Self {
+        Primary {
+            shipment: Shipment::Since(generation),
+            synced_generation: generation,
+            ..Primary::new(transport)
+        }
+    }
+    /// Ship blocks changed in storage since the last shipment, if any
+    /// - a failed shipment is retried as a catch up from the last synced generation
+    fn ship(&mut self, storage: &mut Storage) -> Result<(), StorageError> {
+        let shipment = self.shipment;
+        let result = self.try_ship(storage, shipment);
+        if result.is_err() {
+            self.shipment = match shipment {
+                Shipment::Full => Shipment::Full,
+                _ => Shipment::Since(self.synced_generation),
+            };
+        }
+        result
+    }
+    fn try_ship(&mut self, storage: &mut Storage, shipment: Shipment) -> Result<(), StorageError> {
+        let changed_blocks = match self.changed_blocks.lock() {
+            Ok(mut changed_blocks) => std::mem::take(&mut *changed_blocks),
+            Err(poisoned) => std::mem::take(&mut *poisoned.into_inner()),
+        };
+        let block_count = storage.stats().block_count;
+        let blocks: Box<dyn Iterator<Item = BlockIndex>> = match shipment {
+            Shipment::Changed if changed_blocks.is_empty() => return Ok(()),
+            Shipment::Changed => Box::new(changed_blocks.into_iter()),
+            Shipment::Since(generation) => {
+                Box::new(storage.changed_blocks_since(generation)?.into_iter())
+            }
+            Shipment::Full => Box::new(0..block_count),
+        };
+        for block_index in blocks {
+            let change = storage.block_change(block_index)?;
+            self.transport.send(&ReplicationMessage::Change(change))?;
+        }
+        let generation = storage.generation();
+        self.transport.send(&ReplicationMessage::Synced {
+            generation,
+            block_count,
+        })?;
+        self.synced_generation = generation;
+        self.shipment = Shipment::Changed;
+        Ok(())
+    }
+}
+
+/// Observer collecting blocks written or deleted, for the next shipment of a Primary
+/// - does nothing once the primary is gone
+struct ChangeCollector {
+    changed_blocks: Weak<Mutex<BTreeSet<BlockIndex>>>,
+}
+
+impl ChangeCollector {
+    fn changed(&mut self, block_index: BlockIndex) {
+        if let Some(changed_blocks) = self.changed_blocks.upgrade() {
+            if let Ok(mut changed_blocks) = changed_blocks.lock() {
+                changed_blocks.insert(block_index);
+            }
+        }
+    }
+}
+
+impl BlockObserver for ChangeCollector {
+    fn after_write(&mut self, block_index: BlockIndex, _data: &[u8]) {
+        self.changed(block_index);
+    }
+    fn after_delete(&mut self, block_index: BlockIndex, _hard_delete: bool) {
+        self.changed(block_index);
+    }
+}
+
+impl Engine {
+    /// Replicate the storage of this engine with primary, see Primary
+    /// - ships the first resync or catch up of primary right away, then blocks changed
+    ///   by every io_cycle, after its flush
+    /// - a failed shipment is an error of its io_cycle, the next cycle catches up
+    /// - None stops replication, the default
+    /// - returns: error of the first shipment, primary is set all the same
+    pub fn set_replication(&mut self, primary: Option<Primary>) -> Result<(), StorageError> {
+        self.replication = primary;
+        match self.replication.as_mut() {
+            Some(primary) => {
+                self.storage.add_observer(Box::new(ChangeCollector {
+                    changed_blocks: Arc::downgrade(&primary.changed_blocks),
+                }));
+                primary.ship(&mut self.storage)
+            }
+            None => Ok(()),
+        }
+    }
+    /// Ship blocks changed by the cycle, if replication is set
+    pub(super) fn replicate(&mut self) -> Result<(), StorageError> {
+        match self.replication.as_mut() {
+            Some(primary) => primary.ship(&mut self.storage),
+            None => Ok(()),
+        }
+    }
+}
+
+// ... ... ... ... ... ... ... ... ... Follower ... ... ... ... ... ... ... ... ... ..
+
+/// Follower side of replication, applying messages of a primary to its own storage
+/// - reads of the follower storage see each shipment once it is applied in full
+/// - the synced generation is kept in memory only, a follower opened again is resynced
+///   with Primary::new, or caught up if its last generation was kept by the caller
+pub struct Follower {
+    storage: Storage,
+    generation: Option<Generation>,
+}
+
+impl Follower {
+    pub fn new(storage: Storage) -> Self {
+        Follower {
+            storage,
+            generation: None,
+        }
+    }
+    /// Apply message of the primary
+    /// - changes are written to storage, see Storage::apply_change
+    /// - on Synced, blocks past the end of the primary are freed and storage is flushed
+    pub fn apply(&mut self, message: &ReplicationMessage) -> Result<(), StorageError> {
+        match message {
+            ReplicationMessage::Change(change) => self.storage.apply_change(change),
+            ReplicationMessage::Synced {
+                generation,
+                block_count,
+            } => {
+                self.storage.free_blocks_from(*block_count)?;
+                self.storage.flush()?;
+                self.generation = Some(*generation);
+                Ok(())
+            }
+        }
+    }
+    /// Generation of the primary this follower is synced up to, None before the first
+    /// Synced message, pass it to Primary::catch_up
+    pub fn generation(&self) -> Option<Generation> {
+        self.generation
+    }
+    /// Follower storage, e.g. to read from it
+    /// - blocks written to it directly are overwritten by later changes of the primary
+    pub fn storage(&mut self) -> &mut Storage {
+        &mut self.storage
+    }
+    pub fn into_storage(self) -> Storage {
+        self.storage
+    }
+}
+
+#[cfg(test)]
+mod unit_tests_replication {
+    use super::*;
+
+    fn new_storage(tmp_dir: &tempfile::TempDir, name: &str) -> Storage {
+        let file_path = tmp_dir.path().join(name);
+        Storage::new(file_path.to_str().unwrap().to_string(), 4).unwrap()
+    }
+    fn write(engine: &mut Engine, data: Vec<u8>) -> ResultReceiver<WriteResponse> {
+        let (result, receiver) = ResultSender::channel();
+        engine.append_request(IORequest::Write {
+            data,
+            expires_at: None,
+            result,
+        });
+        receiver
+    }
+    fn apply_all(follower: &mut Follower, receiver: &mpsc::Receiver<ReplicationMessage>) {
+        for message in receiver.try_iter() {
+            follower.apply(&message).unwrap();
+        }
+    }
+
+    #[test]
+    fn test_replication() {
+        let tmp_dir = tempfile::tempdir().unwrap();
+        let mut engine = Engine::new(new_storage(&tmp_dir, "primary.hex"));
+        let old_record = engine.storage.write_record(&[1; 6]).unwrap();
+        let mut follower = Follower::new(new_storage(&tmp_dir, "follower.hex"));
+        // - stale block of the follower, past the end of the primary
+        follower.storage().write_block(7, &[9]).unwrap();
+        let (sender, receiver) = mpsc::channel();
+        engine
+            .set_replication(Some(Primary::new(Box::new(sender))))
+            .unwrap();
+        apply_all(&mut follower, &receiver);
+        assert_eq!(follower.generation(), Some(engine.storage.generation()));
+        assert_eq!(
+            follower.storage().read_record(old_record).unwrap(),
+            vec![1; 6]
+        );
+        assert!(follower.storage().read_block(7).unwrap().1.is_empty());
+        // - changes of a cycle, then a Synced message
+        let new_record = write(&mut engine, vec![2; 5]);
+        engine.io_cycle().unwrap();
+        let new_record = new_record.recv().unwrap().unwrap().record_id;
+        let messages: Vec<ReplicationMessage> = receiver.try_iter().collect();
+        assert_eq!(messages.len(), 3);
+        for message in messages.iter() {
+            follower.apply(message).unwrap();
+        }
+        assert_eq!(
+            follower.storage().read_record(new_record).unwrap(),
+            vec![2; 5]
+        );
+        // - cycles without changes ship nothing
+        engine.io_cycle().unwrap();
+        assert_eq!(receiver.try_iter().count(), 0);
+        // - follower gone, the next primary catches up from its last generation
+        drop(receiver);
+        engine.storage.delete_record(old_record, false).unwrap();
+        assert!(engine.io_cycle().is_err());
+        let (sender, receiver) = mpsc::channel();
+        let generation = follower.generation().unwrap();
+        engine
+            .set_replication(Some(Primary::catch_up(Box::new(sender), generation)))
+            .unwrap();
+        assert_eq!(receiver.try_iter().count(), 3);
+        let mut follower_storage = follower.into_storage();
+        assert_eq!(
+            follower_storage.read_record(new_record).unwrap(),
+            vec![2; 5]
+        );
+        // - the new primary ships from then on, the one it replaced does not
+        write(&mut engine, vec![3]);
+        engine.io_cycle().unwrap();
+        assert_eq!(receiver.try_iter().count(), 2);
+    }
+    #[test]
+    fn test_encoded_messages() {
+        let messages = vec![
+            ReplicationMessage::Change(BlockChange {
+                block_index: 3,
+                generation: 7,
+                data: vec![1, 2],
+                next_block: Some(0),
+                expires_at: Some(99),
+                tag: 5,
+            }),
+            ReplicationMessage::Change(BlockChange {
+                block_index: 4,
+                generation: 8,
+                data: vec![],
+                next_block: None,
+                expires_at: None,
+                tag: 0,
+            }),
+            ReplicationMessage::Synced {
+                generation: 8,
+                block_count: 5,
+            },
+        ];
+        let mut transport = WriteTransport(Vec::new());
+        for message in messages.iter() {
+            transport.send(message).unwrap();
+        }
+        let mut reader = &transport.0[..];
+        let mut received = Vec::new();
+        while let Some(message) = ReplicationMessage::read_from(&mut reader).unwrap() {
+            received.push(message);
+        }
+        assert_eq!(received, messages);
+        assert!(ReplicationMessage::decode(&[MESSAGE_SYNCED, 1]).is_err());
+        assert!(ReplicationMessage::decode(&[]).is_err());
+    }
+    #[test]
+    fn test_oversized_message() {
+        // - a length past the limit fails before its bytes are read or allocated
+        let mut frame = (MAX_MESSAGE_LEN + 1).to_le_bytes().to_vec();
+        frame.push(MESSAGE_SYNCED);
+        assert!(matches!(
+            ReplicationMessage::read_from(&mut &frame[..]),
+            Err(StorageError::TooLarge { len, max })
+                if len == u64::from(MAX_MESSAGE_LEN) + 1 && max == u64::from(MAX_MESSAGE_LEN)
+        ));
+        let frame = u32::MAX.to_le_bytes();
+        assert!(matches!(
+            ReplicationMessage::read_from(&mut &frame[..]),
+            Err(StorageError::TooLarge { .. })
+        ));
+        let change = ReplicationMessage::Change(BlockChange {
+            block_index: 0,
+            generation: 1,
+            data: vec![0; MAX_MESSAGE_LEN as usize],
+            next_block: None,
+            expires_at: None,
+            tag: 0,
+        });
+        let mut transport = WriteTransport(Vec::new());
+        assert!(matches!(
+            transport.send(&change),
+            Err(StorageError::TooLarge { .. })
+        ));
+        assert!(transport.0.is_empty());
+    }
+}
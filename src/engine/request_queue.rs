@@ -0,0 +1,411 @@
+//! Requests that modify storage kept in a DurableQueue until served, to be replayed
+//! after a crash, see Engine::set_request_queue
+use super::*;
+use crate::queue::{AckToken, DurableQueue};
+use std::convert::TryFrom;
+use std::sync::Mutex;
+
+/// Queue of requests of an Engine, and requests served but not acknowledged yet
+pub(super) struct RequestQueue {
+    queue: DurableQueue,
+    /// Sequence numbers of queued requests whose result was sent
+    served: Arc<Mutex<Vec<u64>>>,
+    /// Queue changed since it was last synced
+    unsynced: bool,
+}
+
+impl RequestQueue {
+    fn served(&self) -> std::sync::MutexGuard<'_, Vec<u64>> {
+        match self.served.lock() {
+            Ok(served) => served,
+            Err(poisoned) => poisoned.into_inner(),
+        }
+    }
+}
+
+/// Notes the sequence number of a queued request once its result is sent
+struct Served {
+    served: Arc<Mutex<Vec<u64>>>,
+    sequence: u64,
+}
+
+impl Served {
+    fn note(self) {
+        match self.served.lock() {
+            Ok(mut served) => served.push(self.sequence),
+            Err(poisoned) => poisoned.into_inner().push(self.sequence),
+        }
+    }
+}
+
+impl MapSender for Option<Served> {
+    fn map<T: RequestResult>(&mut self, _: OpKind, result: ResultSender<T>) -> ResultSender<T> {
+        let served = self.take();
+        result.inspect(move |_| {
+            if let Some(served) = served {
+                served.note();
+            }
+        })
+    }
+}
+
+impl Engine {
+    /// Keep writes, deletes and commits in queue from the time they are queued until
+    /// they are served and synced, and replay the requests queue holds
+    /// - a request is added to queue as it is appended, or received by the background
+    ///   thread of spawn_engine, queue is synced at the start of the next io_cycle
+    /// - a request is acknowledged once its result is sent and the storage it was
+    ///   served on is flushed, after the flush of its io_cycle; storages with
+    ///   durability mode Never are flushed as well then
+    /// - requests still in queue are queued again, on startup, at Normal priority, with
+    ///   their storage name and trace id; their results are discarded, so attach every
+    ///   storage before, and call it after recover_txn
+    /// - delivery is at least once: after a crash between serving a request and
+    ///   acknowledging it, the request is served again, e.g. a write writes a second
+    ///   record
+    /// - requests sent to an EngineHandle are only kept once the background thread
+    ///   receives them; reads, block scans and compaction steps are not kept
+    /// - a request that cannot be added fails with the error of the queue
+    /// - None stops keeping requests, the default; requests served but not
+    ///   acknowledged yet are replayed by the next queue set
+    /// - returns: number of requests replayed, Corruption if a request in queue is
+    ///   malformed, then nothing is replayed and queue is not set
+    pub fn set_request_queue(
+        &mut self,
+        queue: Option<DurableQueue>,
+    ) -> Result<usize, StorageError> {
+        self.request_queue = None;
+        let mut queue = match queue {
+            Some(queue) => queue,
+            None => return Ok(0),
+        };
+        let served = Arc::new(Mutex::new(Vec::new()));
+        let mut replayed = Vec::new();
+        while let Some((token, bytes)) = queue.dequeue()? {
+            let served = Served {
+                served: served.clone(),
+                sequence: token.sequence(),
+            };
+            let request = decode_request(&bytes, served).ok_or(StorageError::Corruption {
+                block_index: None,
+                reason: "queued request is malformed",
+            })?;
+            replayed.push(request);
+        }
+        let count = replayed.len();
+        for request in replayed {
+            let (request, handle) = self.pending.track(request, Priority::Normal);
+            self.requests.push(request, Priority::Normal, handle);
+        }
+        self.metrics.set_queue_len(self.requests.len());
+        self.request_queue = Some(RequestQueue {
+            queue,
+            served,
+            unsynced: false,
+        });
+        Ok(count)
+    }
+    /// Stop keeping requests, see set_request_queue
+    /// - returns: request queue, requests served since the last flush are not
+    ///   acknowledged yet and replayed by the next engine
+    pub fn take_request_queue(&mut self) -> Option<DurableQueue> {
+        self.request_queue.take().map(|queue| queue.queue)
+    }
+    /// Add request to the request queue, if it modifies storage
+    /// - returns: request to queue, None if it failed to be added
+    pub(super) fn keep_request(&mut self, request: IORequest) -> Option<IORequest> {
+        let queue = match self.request_queue.as_mut() {
+            Some(queue) => queue,
+            None => return Some(request),
+        };
+        let bytes = match encode_request(&request) {
+            Some(bytes) => bytes,
+            None => return Some(request),
+        };
+        match queue.queue.enqueue(&bytes) {
+            Ok(sequence) => {
+                queue.unsynced = true;
+                Some(request.map_sender(&mut Some(Served {
+                    served: queue.served.clone(),
+                    sequence,
+                })))
+            }
+            Err(error) => {
+                request.fail(error);
+                None
+            }
+        }
+    }
+    /// Sync requests added to, or acknowledged in, the request queue
+    pub(super) fn sync_request_queue(&mut self) -> Result<(), StorageError> {
+        match self.request_queue.as_mut() {
+            Some(queue) if queue.unsynced => {
+                queue.queue.flush()?;
+                queue.unsynced = false;
+                Ok(())
+            }
+            _ => Ok(()),
+        }
+    }
+    /// Acknowledge requests whose result was sent, after the flush of a cycle
+    /// - storages with durability mode Never are flushed first; after a failed flush
+    ///   they stay in queue, to be replayed
+    pub(super) fn ack_requests(&mut self) -> Result<(), StorageError> {
+        let served = match self.request_queue.as_ref() {
+            Some(queue) if !self.flush_failed => std::mem::take(&mut *queue.served()),
+            _ => return Ok(()),
+        };
+        if served.is_empty() {
+            return Ok(());
+        }
+        let flush_result = self
+            .all_storages()
+            .filter(|storage| storage.durability() == DurabilityMode::Never)
+            .try_for_each(Storage::flush);
+        if let Err(error) = flush_result {
+            self.flush_failed = true;
+            return Err(error);
+        }
+        if let Some(queue) = self.request_queue.as_mut() {
+            queue.unsynced = true;
+            for sequence in served {
+                queue.queue.ack(AckToken::of(sequence))?;
+            }
+        }
+        Ok(())
+    }
+}
+
+// ... ... ... ... ... ... ... ... ... . Encoding . ... ... ... ... ... ... ... ... ... ...
+
+const QUEUED_WRITE: u8 = 1;
+const QUEUED_DELETE: u8 = 2;
+const QUEUED_COMMIT: u8 = 3;
+
+/// Bytes of request in queue, None for requests that do not modify storage
+/// - kind (u8), storage name and trace id (u32 length and bytes each, empty for none),
+///   then the operation:
+///   - write: expiry flag (u8), expiry (u64), data
+///   - delete: record id (u64), hard delete flag (u8)
+///   - commit: per op, 0 and length (u64) and data for a write, 1 and record id (u64)
+///     and hard delete flag (u8) for a delete
+fn encode_request(request: &IORequest) -> Option<Vec<u8>> {
+    let (kind, operation) = encode_operation(request)?;
+    let mut bytes = vec![kind];
+    for text in &[request.storage_name(), request.trace_id()] {
+        let text = (*text).unwrap_or_default().as_bytes();
+        bytes.extend_from_slice(&(text.len() as u32).to_le_bytes());
+        bytes.extend_from_slice(text);
+    }
+    bytes.extend_from_slice(&operation);
+    Some(bytes)
+}
+
+fn encode_operation(request: &IORequest) -> Option<(u8, Vec<u8>)> {
+    let mut bytes = Vec::new();
+    let kind = match request {
+        IORequest::Write {
+            data, expires_at, ..
+        } => {
+            bytes.push(u8::from(expires_at.is_some()));
+            bytes.extend_from_slice(&expires_at.unwrap_or_default().to_le_bytes());
+            bytes.extend_from_slice(data);
+            QUEUED_WRITE
+        }
+        IORequest::Delete {
+            record_id,
+            hard_delete,
+            ..
+        } => {
+            bytes.extend_from_slice(&record_id.to_le_bytes());
+            bytes.push(u8::from(*hard_delete));
+            QUEUED_DELETE
+        }
+        IORequest::Commit { ops, .. } => {
+            for op in ops {
+                match op {
+                    TxnOp::Write(data) => {
+                        bytes.push(0);
+                        bytes.extend_from_slice(&(data.len() as u64).to_le_bytes());
+                        bytes.extend_from_slice(data);
+                    }
+                    TxnOp::Delete {
+                        record_id,
+                        hard_delete,
+                    } => {
+                        bytes.push(1);
+                        bytes.extend_from_slice(&record_id.to_le_bytes());
+                        bytes.push(u8::from(*hard_delete));
+                    }
+                }
+            }
+            QUEUED_COMMIT
+        }
+        IORequest::OnStorage { request, .. } | IORequest::Traced { request, .. } => {
+            return encode_operation(request)
+        }
+        _ => return None,
+    };
+    Some((kind, bytes))
+}
+
+/// Request of bytes in queue, whose result notes served, None if bytes are malformed
+fn decode_request(bytes: &[u8], served: Served) -> Option<IORequest> {
+    fn take<'a>(bytes: &mut &'a [u8], len: usize) -> Option<&'a [u8]> {
+        let (value, rest) = (bytes.get(..len)?, bytes.get(len..)?);
+        *bytes = rest;
+        Some(value)
+    }
+    fn read_u64(bytes: &mut &[u8]) -> Option<u64> {
+        let mut value_bytes = [0u8; 8];
+        value_bytes.copy_from_slice(take(bytes, 8)?);
+        Some(u64::from_le_bytes(value_bytes))
+    }
+    fn read_u8(bytes: &mut &[u8]) -> Option<u8> {
+        Some(take(bytes, 1)?[0])
+    }
+    fn read_text(bytes: &mut &[u8]) -> Option<Option<String>> {
+        let mut len_bytes = [0u8; 4];
+        len_bytes.copy_from_slice(take(bytes, 4)?);
+        let len = usize::try_from(u32::from_le_bytes(len_bytes)).ok()?;
+        let text = String::from_utf8(take(bytes, len)?.to_vec()).ok()?;
+        Some(Some(text).filter(|text| !text.is_empty()))
+    }
+    fn sender<T: Send + 'static>(served: Served) -> ResultSender<T> {
+        ResultSender::new(move |_| served.note())
+    }
+    let mut bytes = bytes;
+    let kind = read_u8(&mut bytes)?;
+    let storage = read_text(&mut bytes)?;
+    let trace_id = read_text(&mut bytes)?;
+    let mut request = match kind {
+        QUEUED_WRITE => {
+            let has_expiry = read_u8(&mut bytes)? != 0;
+            let expires_at = read_u64(&mut bytes)?;
+            IORequest::Write {
+                data: bytes.to_vec(),
+                expires_at: Some(expires_at).filter(|_| has_expiry),
+                result: sender(served),
+            }
+        }
+        QUEUED_DELETE => IORequest::Delete {
+            record_id: read_u64(&mut bytes)?,
+            hard_delete: read_u8(&mut bytes)? != 0,
+            result: sender(served),
+        },
+        QUEUED_COMMIT => {
+            let mut ops = Vec::new();
+            while !bytes.is_empty() {
+                ops.push(match read_u8(&mut bytes)? {
+                    0 => {
+                        let len = usize::try_from(read_u64(&mut bytes)?).ok()?;
+                        TxnOp::Write(take(&mut bytes, len)?.to_vec())
+                    }
+                    1 => TxnOp::Delete {
+                        record_id: read_u64(&mut bytes)?,
+                        hard_delete: read_u8(&mut bytes)? != 0,
+                    },
+                    _ => return None,
+                });
+            }
+            IORequest::Commit {
+                ops,
+                result: sender(served),
+            }
+        }
+        _ => return None,
+    };
+    if let Some(trace_id) = trace_id {
+        request = request.traced(trace_id);
+    }
+    if let Some(name) = storage {
+        request = request.on(name);
+    }
+    Some(request)
+}
+
+#[cfg(test)]
+mod unit_tests_request_queue {
+    use super::*;
+
+    fn new_storage(tmp_dir: &tempfile::TempDir, name: &str) -> Storage {
+        let file_path = tmp_dir.path().join(name);
+        Storage::new(file_path.to_str().unwrap().to_string(), 16).unwrap()
+    }
+    fn open_storage(tmp_dir: &tempfile::TempDir, name: &str) -> Storage {
+        let file_path = tmp_dir.path().join(name);
+        Storage::open(file_path.to_str().unwrap().to_string()).unwrap()
+    }
+    fn unused_served() -> Served {
+        Served {
+            served: Arc::default(),
+            sequence: 0,
+        }
+    }
+
+    #[test]
+    fn test_replay_requests() {
+        let tmp_dir = tempfile::tempdir().unwrap();
+        let mut engine = Engine::new(new_storage(&tmp_dir, "data.hex"));
+        let queue = DurableQueue::create(new_storage(&tmp_dir, "requests.hex")).unwrap();
+        let anchor = queue.id();
+        assert_eq!(engine.set_request_queue(Some(queue)).unwrap(), 0);
+        let (write, _write_receiver) = RequestBuilder::write(vec![1; 20], None)
+            .traced("write")
+            .build();
+        engine.append_request(write);
+        let (delete, _delete_receiver) = RequestBuilder::delete(7, true).on("events").build();
+        engine.append_request(delete);
+        let (read, _read_receiver) = RequestBuilder::read(0).build();
+        engine.append_request(read);
+        // - crash before the requests are served: the queue holds the write and delete
+        let queue = engine.take_request_queue().unwrap();
+        assert_eq!(queue.len(), 2);
+        drop(queue);
+        drop(engine);
+        let mut engine = Engine::new(open_storage(&tmp_dir, "data.hex"));
+        let queue = DurableQueue::open(open_storage(&tmp_dir, "requests.hex"), anchor).unwrap();
+        assert_eq!(engine.set_request_queue(Some(queue)).unwrap(), 2);
+        let pending = engine.pending();
+        assert_eq!(
+            (pending[0].kind, pending[0].trace_id.as_deref()),
+            (RequestKind::Write, Some("write"))
+        );
+        assert_eq!(
+            (pending[1].kind, pending[1].storage.as_deref()),
+            (RequestKind::Delete, Some("events"))
+        );
+        // - the replayed delete fails, no storage is attached as events, and is
+        //   acknowledged as well
+        engine.io_cycle().unwrap();
+        assert_eq!(engine.storage.read_record(0).unwrap(), vec![1; 20]);
+        assert!(engine.take_request_queue().unwrap().is_empty());
+    }
+    #[test]
+    fn test_ack_after_serving() {
+        let tmp_dir = tempfile::tempdir().unwrap();
+        let mut engine = Engine::new(new_storage(&tmp_dir, "data.hex"));
+        let queue = DurableQueue::create(new_storage(&tmp_dir, "requests.hex")).unwrap();
+        engine.set_request_queue(Some(queue)).unwrap();
+        let (commit, commit_receiver) =
+            RequestBuilder::commit(vec![TxnOp::Write(vec![2; 3])]).build();
+        engine.append_request(commit);
+        assert_eq!(engine.request_queue.as_ref().unwrap().queue.len(), 1);
+        engine.io_cycle().unwrap();
+        assert_eq!(commit_receiver.recv().unwrap().unwrap().written, vec![0]);
+        assert!(engine.request_queue.as_ref().unwrap().queue.is_empty());
+        // - every kind of request that modifies storage comes back from its bytes
+        let ops = vec![
+            TxnOp::Write(vec![3; 2]),
+            TxnOp::Delete {
+                record_id: 4,
+                hard_delete: true,
+            },
+        ];
+        let (commit, _) = RequestBuilder::commit(ops).on("events").build();
+        let bytes = encode_request(&commit).unwrap();
+        let decoded = decode_request(&bytes, unused_served()).unwrap();
+        assert_eq!(encode_request(&decoded).unwrap(), bytes);
+        assert!(decode_request(&bytes[..bytes.len() - 1], unused_served()).is_none());
+    }
+}
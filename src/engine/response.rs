@@ -0,0 +1,57 @@
+use super::*;
+use std::time::Duration;
+
+/// Result of a Read request, with what was read for accounting
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ReadResponse {
+    /// All data of the record, empty if its first block is empty
+    pub data: Vec<u8>,
+    /// Blocks of the record read
+    pub blocks_read: usize,
+    /// Length of data, after block transforms were undone
+    pub bytes: usize,
+}
+
+impl ReadResponse {
+    /// Response joining decoded data of each block of a record, in chain order
+    pub(super) fn from_chunks(chunks: Vec<Vec<u8>>) -> Self {
+        let blocks_read = chunks.len();
+        let data = chunks.concat();
+        ReadResponse {
+            bytes: data.len(),
+            data,
+            blocks_read,
+        }
+    }
+}
+
+/// Result of a Write request, with what was written for accounting
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct WriteResponse {
+    /// Record id, to read or delete the record, first of block_indexes
+    pub record_id: RecordId,
+    /// Blocks written, in data order
+    pub block_indexes: Vec<BlockIndex>,
+    /// Length of record data, before block transforms
+    pub bytes_written: usize,
+    /// Time the engine took to write the record, without time queued
+    pub duration: Duration,
+}
+
+impl WriteResponse {
+    /// Write data as a record on storage, timing the write
+    pub(super) fn write(
+        storage: &mut Storage,
+        data: &[u8],
+        expires_at: Option<u64>,
+    ) -> Result<Self, StorageError> {
+        let started = Instant::now();
+        let block_indexes = storage.write_blocks_expiring(data, expires_at)?;
+        Ok(WriteResponse {
+            record_id: block_indexes[0],
+            block_indexes,
+            bytes_written: data.len(),
+            duration: started.elapsed(),
+        })
+    }
+}
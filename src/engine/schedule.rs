@@ -0,0 +1,311 @@
+use super::*;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+/// Scheduling class of a request, higher classes are served first
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Priority {
+    /// Foreground requests that must not wait behind other work
+    High,
+    /// Default for requests queued without a priority
+    #[default]
+    Normal,
+    /// Maintenance work like compaction, served when nothing else is waiting
+    Background,
+}
+
+impl Priority {
+    /// Class a request is promoted to once it waited long enough
+    fn promoted(self) -> Priority {
+        match self {
+            Priority::High | Priority::Normal => Priority::High,
+            Priority::Background => Priority::Normal,
+        }
+    }
+    fn queue_index(self) -> usize {
+        self as usize
+    }
+}
+
+/// Order in which io_cycle serves queued requests, see Engine::set_request_order
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum RequestOrder {
+    /// Higher priorities first, requests of same priority in submission order
+    /// - a request sees every request of same or higher priority queued before it, a
+    ///   high priority read is served before a normal write queued earlier and does not
+    ///   see it
+    #[default]
+    Priority,
+    /// Strictly in submission order, priorities and aging are ignored
+    /// - every request sees all requests queued before it, e.g. a read sees a write
+    ///   queued before it in the same cycle (read your writes)
+    Submission,
+}
+
+/// Number of cycles a request waits before it is promoted to the next priority
+pub const DEFAULT_AGING_CYCLES: u64 = 8;
+
+/// Control over a queued request, returned when it is queued
+/// - a cancelled request, or one past its deadline, is skipped by io_cycle and gets
+///   StorageError::Cancelled or StorageError::TimedOut as result
+/// - a request is only checked when its turn comes, cancelling a request being served
+///   or already served has no effect
+/// - clones control the same request
+#[derive(Clone, Default)]
+pub struct RequestHandle {
+    state: Arc<RequestState>,
+}
+
+#[derive(Default)]
+struct RequestState {
+    /// Id given by the engine when the request was queued, 0 if not queued by one
+    id: u64,
+    cancelled: AtomicBool,
+    deadline: Mutex<Option<Instant>>,
+}
+
+impl RequestHandle {
+    pub(super) fn with_id(id: u64) -> Self {
+        RequestHandle {
+            state: Arc::new(RequestState {
+                id,
+                ..RequestState::default()
+            }),
+        }
+    }
+    /// Id of the request, as in Engine::pending
+    /// - 0 if the request was rejected by the admission limit, or never reached an engine
+    pub fn id(&self) -> u64 {
+        self.state.id
+    }
+    /// Skip request if it was not served yet
+    pub fn cancel(&self) {
+        self.state.cancelled.store(true, Ordering::SeqCst);
+    }
+    pub fn is_cancelled(&self) -> bool {
+        self.state.cancelled.load(Ordering::SeqCst)
+    }
+    /// Skip request if it is not served by deadline
+    pub fn set_deadline(&self, deadline: Instant) {
+        *self.lock_deadline() = Some(deadline);
+    }
+    /// Skip request if it is not served within timeout from now
+    pub fn set_timeout(&self, timeout: Duration) {
+        self.set_deadline(Instant::now() + timeout);
+    }
+    pub fn deadline(&self) -> Option<Instant> {
+        *self.lock_deadline()
+    }
+    /// Error to send instead of serving the request, None if it must be served
+    pub(super) fn skip_error(&self) -> Option<StorageError> {
+        if self.is_cancelled() {
+            return Some(StorageError::Cancelled);
+        }
+        match self.deadline() {
+            Some(deadline) if Instant::now() >= deadline => Some(StorageError::TimedOut),
+            _ => None,
+        }
+    }
+    fn lock_deadline(&self) -> std::sync::MutexGuard<'_, Option<Instant>> {
+        // deadline is a plain value, a holder that panicked can not leave it half written
+        self.state
+            .deadline
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+}
+
+struct QueuedRequest {
+    request: IORequest,
+    handle: RequestHandle,
+    /// Cycle request was queued in, or last promoted in
+    queued_cycle: u64,
+    /// Position of request among all requests pushed, for RequestOrder::Submission
+    sequence: u64,
+}
+
+/// Queue per priority, served highest priority first
+/// - a request waiting aging_cycles cycles moves to the next higher queue, so with a
+///   cycle limit background requests still run while higher priorities keep coming
+pub(super) struct Scheduler {
+    queues: [VecDeque<QueuedRequest>; 3],
+    cycle: u64,
+    aging_cycles: u64,
+    order: RequestOrder,
+    next_sequence: u64,
+}
+
+impl Scheduler {
+    pub(super) fn new() -> Self {
+        Scheduler {
+            queues: [VecDeque::new(), VecDeque::new(), VecDeque::new()],
+            cycle: 0,
+            aging_cycles: DEFAULT_AGING_CYCLES,
+            order: RequestOrder::default(),
+            next_sequence: 0,
+        }
+    }
+    pub(super) fn set_aging_cycles(&mut self, aging_cycles: u64) {
+        self.aging_cycles = aging_cycles;
+    }
+    pub(super) fn set_order(&mut self, order: RequestOrder) {
+        self.order = order;
+    }
+    pub(super) fn push(&mut self, request: IORequest, priority: Priority, handle: RequestHandle) {
+        self.queues[priority.queue_index()].push_back(QueuedRequest {
+            request,
+            handle,
+            queued_cycle: self.cycle,
+            sequence: self.next_sequence,
+        });
+        self.next_sequence += 1;
+    }
+    pub(super) fn len(&self) -> usize {
+        self.queues.iter().map(VecDeque::len).sum()
+    }
+    /// Start next cycle, promoting requests that waited aging_cycles cycles
+    pub(super) fn next_cycle(&mut self) {
+        self.cycle += 1;
+        // - no request waits behind later ones in submission order
+        if self.order == RequestOrder::Submission {
+            return;
+        }
+        // - Normal before Background, so a request moves up at most one class per cycle
+        for priority in [Priority::Normal, Priority::Background].iter() {
+            let promoted = priority.promoted();
+            while let Some(queued) = self.queues[priority.queue_index()].front() {
+                if self.cycle - queued.queued_cycle < self.aging_cycles {
+                    break;
+                }
+                if let Some(mut queued) = self.queues[priority.queue_index()].pop_front() {
+                    queued.queued_cycle = self.cycle;
+                    self.queues[promoted.queue_index()].push_back(queued);
+                }
+            }
+        }
+    }
+    /// Next request pop would return, if it matches predicate
+    pub(super) fn pop_if(
+        &mut self,
+        predicate: impl Fn(&IORequest) -> bool,
+    ) -> Option<(IORequest, RequestHandle)> {
+        let queue = self.next_queue()?;
+        if !queue
+            .front()
+            .is_some_and(|queued| predicate(&queued.request))
+        {
+            return None;
+        }
+        queue
+            .pop_front()
+            .map(|queued| (queued.request, queued.handle))
+    }
+    /// Next request by order, oldest request of highest non empty priority or oldest
+    /// request of all
+    pub(super) fn pop(&mut self) -> Option<(IORequest, RequestHandle)> {
+        self.next_queue()?
+            .pop_front()
+            .map(|queued| (queued.request, queued.handle))
+    }
+    /// Queue holding the next request by order, None if all are empty
+    fn next_queue(&mut self) -> Option<&mut VecDeque<QueuedRequest>> {
+        let mut queues = self.queues.iter_mut().filter(|queue| !queue.is_empty());
+        match self.order {
+            RequestOrder::Priority => queues.next(),
+            RequestOrder::Submission => {
+                queues.min_by_key(|queue| queue.front().map(|queued| queued.sequence))
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod unit_tests_schedule {
+    use super::*;
+
+    fn write_request(byte: u8) -> IORequest {
+        let (result, _) = ResultSender::channel();
+        IORequest::Write {
+            data: vec![byte],
+            expires_at: None,
+            result,
+        }
+    }
+    fn popped_byte(scheduler: &mut Scheduler) -> Option<u8> {
+        match scheduler.pop() {
+            Some((IORequest::Write { data, .. }, _)) => Some(data[0]),
+            _ => None,
+        }
+    }
+
+    #[test]
+    fn test_priority_order() {
+        let mut scheduler = Scheduler::new();
+        scheduler.push(
+            write_request(3),
+            Priority::Background,
+            RequestHandle::default(),
+        );
+        scheduler.push(write_request(2), Priority::Normal, RequestHandle::default());
+        scheduler.push(write_request(1), Priority::High, RequestHandle::default());
+        scheduler.push(write_request(4), Priority::Normal, RequestHandle::default());
+        assert_eq!(scheduler.len(), 4);
+        let order: Vec<u8> = std::iter::from_fn(|| popped_byte(&mut scheduler)).collect();
+        assert_eq!(order, vec![1, 2, 4, 3]);
+    }
+    #[test]
+    fn test_submission_order() {
+        let mut scheduler = Scheduler::new();
+        scheduler.set_order(RequestOrder::Submission);
+        scheduler.set_aging_cycles(1);
+        scheduler.push(
+            write_request(1),
+            Priority::Background,
+            RequestHandle::default(),
+        );
+        scheduler.push(write_request(2), Priority::High, RequestHandle::default());
+        scheduler.next_cycle();
+        scheduler.push(write_request(3), Priority::Normal, RequestHandle::default());
+        scheduler.push(write_request(4), Priority::High, RequestHandle::default());
+        let order: Vec<u8> = std::iter::from_fn(|| popped_byte(&mut scheduler)).collect();
+        assert_eq!(order, vec![1, 2, 3, 4]);
+    }
+    #[test]
+    fn test_request_handle() {
+        let handle = RequestHandle::default();
+        assert!(handle.skip_error().is_none());
+        handle.set_timeout(Duration::from_secs(60));
+        assert!(handle.skip_error().is_none());
+        handle.set_deadline(Instant::now());
+        assert!(matches!(handle.skip_error(), Some(StorageError::TimedOut)));
+        // clones control the same request
+        handle.clone().cancel();
+        assert!(handle.is_cancelled());
+        assert!(matches!(handle.skip_error(), Some(StorageError::Cancelled)));
+    }
+    #[test]
+    fn test_aging() {
+        let mut scheduler = Scheduler::new();
+        scheduler.set_aging_cycles(2);
+        scheduler.push(
+            write_request(0),
+            Priority::Background,
+            RequestHandle::default(),
+        );
+        scheduler.next_cycle();
+        scheduler.push(write_request(1), Priority::Normal, RequestHandle::default());
+        // after 2 cycles background request is promoted to Normal, behind request 1
+        scheduler.next_cycle();
+        scheduler.push(write_request(2), Priority::Normal, RequestHandle::default());
+        assert_eq!(popped_byte(&mut scheduler), Some(1));
+        assert_eq!(popped_byte(&mut scheduler), Some(0));
+        // after 2 more cycles a normal request is promoted to High
+        scheduler.push(write_request(3), Priority::High, RequestHandle::default());
+        scheduler.next_cycle();
+        scheduler.next_cycle();
+        scheduler.push(write_request(4), Priority::High, RequestHandle::default());
+        let order: Vec<u8> = std::iter::from_fn(|| popped_byte(&mut scheduler)).collect();
+        assert_eq!(order, vec![3, 2, 4]);
+    }
+}
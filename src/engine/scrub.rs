@@ -0,0 +1,205 @@
+//! Background verification of block checksums, see Engine::set_scrubber
+use super::*;
+use std::time::Duration;
+
+/// Callback receiving every corrupted block found by a Scrubber
+/// - with the name of its storage, None for the storage the engine was created with
+pub type BadBlockHook = Box<dyn FnMut(Option<&str>, BlockIndex) + Send>;
+
+/// Scrubber of an Engine, verifying block checksums of its storages a few blocks at a
+/// time, to find latent corruption before a read does
+/// - every scrub step verifies up to blocks_per_step blocks of each storage, see
+///   Storage::verify_blocks, continuing where the step before stopped
+/// - once the last block of a storage is verified the pass ends, and the next step
+///   starts again at block 0
+/// - blocks are read from file, not from the block cache
+pub struct Scrubber {
+    /// Most blocks of each storage verified per scrub step
+    blocks_per_step: usize,
+    /// Least time between two scrub steps, zero runs a step in every io_cycle
+    interval: Duration,
+    on_bad_block: Option<BadBlockHook>,
+    /// Block each storage continues from, by name, None for the default storage
+    next_blocks: HashMap<Option<String>, BlockIndex>,
+    last_step: Option<Instant>,
+}
+
+impl Scrubber {
+    /// Scrubber verifying up to blocks_per_step blocks of each storage per io_cycle
+    pub fn new(blocks_per_step: usize) -> Self {
+        Scrubber {
+            blocks_per_step,
+            interval: Duration::ZERO,
+            on_bad_block: None,
+            next_blocks: HashMap::new(),
+            last_step: None,
+        }
+    }
+    /// Run scrub steps at most once per interval, instead of in every io_cycle
+    /// - a background engine with an interval also wakes up for scrub steps while no
+    ///   requests arrive
+    pub fn interval(mut self, interval: Duration) -> Self {
+        self.interval = interval;
+        self
+    }
+    /// Call hook with every corrupted block found
+    /// - a corrupted block is reported again on every pass, until it is rewritten
+    /// - observers of the storage see checksum mismatches too, see
+    ///   StorageObserver::on_checksum_mismatch
+    pub fn on_bad_block(mut self, hook: BadBlockHook) -> Self {
+        self.on_bad_block = Some(hook);
+        self
+    }
+    /// Time left until the next scrub step is due, None if it is due with every io_cycle
+    fn wait(&self) -> Option<Duration> {
+        if self.interval.is_zero() {
+            return None;
+        }
+        let elapsed = self
+            .last_step
+            .map_or(self.interval, |last_step| last_step.elapsed());
+        Some(self.interval.saturating_sub(elapsed))
+    }
+}
+
+impl Engine {
+    /// Verify block checksums in the background with scrubber, see Scrubber
+    /// - an io_cycle runs the scrub step after serving its requests, so the step only
+    ///   delays requests of later cycles, by at most blocks_per_step block reads per
+    ///   storage
+    /// - without an interval, a background engine only scrubs when requests arrive, an
+    ///   idle engine does not scrub
+    /// - blocks found and passes completed are counted in EngineMetrics
+    /// - None disables scrubbing, the default
+    pub fn set_scrubber(&mut self, scrubber: Option<Scrubber>) {
+        self.scrubber = scrubber;
+    }
+    /// Time a background engine waits for requests before running a cycle to scrub
+    /// - None if it only scrubs when requests arrive
+    pub(super) fn scrub_wait(&self) -> Option<Duration> {
+        self.scrubber.as_ref().and_then(Scrubber::wait)
+    }
+    /// Run a scrub step, if a scrubber is set and its step is due
+    /// - a storage that fails to read does not hold back the others, its error is
+    ///   returned after them
+    pub(super) fn scrub_step(&mut self) -> Result<(), StorageError> {
+        let mut scrubber = match self.scrubber.take() {
+            Some(scrubber) if scrubber.wait().is_none_or(|wait| wait.is_zero()) => scrubber,
+            scrubber => {
+                self.scrubber = scrubber;
+                return Ok(());
+            }
+        };
+        scrubber.last_step = Some(Instant::now());
+        let metrics = self.metrics.clone();
+        let mut scrub_result = Ok(());
+        for (name, storage) in self.named_storages() {
+            let from_block = scrubber.next_blocks.get(&name).copied().unwrap_or(0);
+            let step = match storage.verify_blocks(from_block, scrubber.blocks_per_step) {
+                Ok(step) => step,
+                Err(error) => {
+                    scrub_result = scrub_result.and(Err(error));
+                    continue;
+                }
+            };
+            metrics.observe_scrub(
+                step.verified_blocks,
+                step.corrupted_blocks.len() as u64,
+                step.next_block.is_none(),
+            );
+            for block_index in step.corrupted_blocks {
+                #[cfg(feature = "tracing")]
+                tracing::warn!(storage = ?name, block_index, "scrubber found corrupted block");
+                if let Some(hook) = scrubber.on_bad_block.as_mut() {
+                    hook(name.as_deref(), block_index);
+                }
+            }
+            scrubber
+                .next_blocks
+                .insert(name, step.next_block.unwrap_or(0));
+        }
+        self.scrubber = Some(scrubber);
+        scrub_result
+    }
+}
+
+#[cfg(test)]
+mod unit_tests_scrub {
+    use super::*;
+    use std::sync::Mutex;
+
+    /// storage with 4 used blocks, data of block 1 corrupted on disk
+    fn corrupt_storage(tmp_dir: &tempfile::TempDir, name: &str) -> Storage {
+        let file_path = tmp_dir.path().join(name);
+        let file_path = file_path.to_str().unwrap().to_string();
+        let mut storage = Storage::new(file_path.clone(), 4).unwrap();
+        for block_index in 0..4 {
+            storage
+                .write_block(block_index, &[block_index as u8 + 7; 4])
+                .unwrap();
+        }
+        let mut file_bytes = std::fs::read(&file_path).unwrap();
+        let data_offset = file_bytes
+            .windows(4)
+            .position(|window| window == [8; 4])
+            .unwrap();
+        file_bytes[data_offset] ^= 1;
+        std::fs::write(&file_path, file_bytes).unwrap();
+        storage
+    }
+
+    #[test]
+    fn test_scrub_steps() {
+        let tmp_dir = tempfile::tempdir().unwrap();
+        let mut engine = Engine::new(corrupt_storage(&tmp_dir, "scrub.hex"));
+        engine.attach("other", corrupt_storage(&tmp_dir, "other.hex"));
+        let metrics = engine.metrics();
+        let bad_blocks = Arc::new(Mutex::new(Vec::new()));
+        let found = bad_blocks.clone();
+        engine.set_scrubber(Some(Scrubber::new(3).on_bad_block(Box::new(
+            move |name, block_index| {
+                let name = name.map(str::to_string);
+                found.lock().unwrap().push((name, block_index));
+            },
+        ))));
+        // - blocks 0..3 of each storage, then the rest of the pass
+        engine.io_cycle().unwrap();
+        assert_eq!((metrics.scrubbed_blocks(), metrics.scrub_passes()), (6, 0));
+        engine.io_cycle().unwrap();
+        assert_eq!((metrics.scrubbed_blocks(), metrics.scrub_passes()), (8, 2));
+        assert_eq!(metrics.bad_blocks(), 2);
+        let mut found = bad_blocks.lock().unwrap().clone();
+        found.sort();
+        assert_eq!(found, vec![(None, 1), (Some("other".to_string()), 1)]);
+        // - next pass starts at block 0, a rewritten block is no longer reported
+        engine.storage.write_block(1, &[8; 4]).unwrap();
+        engine.io_cycle().unwrap();
+        engine.io_cycle().unwrap();
+        assert_eq!((metrics.scrub_passes(), metrics.bad_blocks()), (4, 3));
+        // - scrubbing is off once the scrubber is removed
+        engine.set_scrubber(None);
+        engine.io_cycle().unwrap();
+        assert_eq!(metrics.scrubbed_blocks(), 16);
+    }
+    #[test]
+    fn test_scrub_interval() {
+        let tmp_dir = tempfile::tempdir().unwrap();
+        let mut engine = Engine::new(corrupt_storage(&tmp_dir, "scrub.hex"));
+        let metrics = engine.metrics();
+        engine.set_scrubber(Some(Scrubber::new(1).interval(Duration::from_secs(3600))));
+        // - a step is due right away, the next one only after the interval
+        engine.io_cycle().unwrap();
+        engine.io_cycle().unwrap();
+        assert_eq!(metrics.scrubbed_blocks(), 1);
+        // - an idle background engine wakes up for scrub steps
+        engine.set_scrubber(Some(Scrubber::new(2).interval(Duration::from_millis(1))));
+        let handle = engine.spawn_engine();
+        let started = Instant::now();
+        while metrics.scrub_passes() == 0 {
+            assert!(started.elapsed() < Duration::from_secs(10));
+            thread::sleep(Duration::from_millis(1));
+        }
+        assert!(metrics.bad_blocks() >= 1);
+        handle.join().unwrap();
+    }
+}
@@ -0,0 +1,74 @@
+use super::*;
+use std::sync::atomic::{AtomicU8, Ordering};
+
+/// What happens to requests not yet served when an engine is shut down, see
+/// EngineHandle::shutdown
+/// - the io_cycle being served when shutdown begins always finishes
+/// - requests that are not served get StorageError::ShuttingDown as result
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DrainPolicy {
+    /// Serve every request sent before shutdown, like EngineHandle::join
+    DrainAll,
+    /// Serve requests the engine already took into its queue, reject requests still
+    /// on their way to it
+    RejectNew,
+    /// Finish the io_cycle being served, reject every other request
+    Abort,
+}
+
+/// Drain policy shared by an EngineHandle and its background thread
+/// - None until shutdown begins
+#[derive(Default)]
+pub(super) struct ShutdownState {
+    policy: AtomicU8,
+}
+
+impl ShutdownState {
+    pub(super) fn begin(&self, policy: DrainPolicy) {
+        let policy = match policy {
+            DrainPolicy::DrainAll => 1,
+            DrainPolicy::RejectNew => 2,
+            DrainPolicy::Abort => 3,
+        };
+        self.policy.store(policy, Ordering::SeqCst);
+    }
+    pub(super) fn policy(&self) -> Option<DrainPolicy> {
+        match self.policy.load(Ordering::SeqCst) {
+            1 => Some(DrainPolicy::DrainAll),
+            2 => Some(DrainPolicy::RejectNew),
+            3 => Some(DrainPolicy::Abort),
+            _ => None,
+        }
+    }
+    /// Check if requests received from now on are rejected
+    fn rejects_new(&self) -> bool {
+        matches!(
+            self.policy(),
+            Some(DrainPolicy::RejectNew) | Some(DrainPolicy::Abort)
+        )
+    }
+}
+
+impl Engine {
+    /// Queue request received by the background thread, unless shutdown rejects it
+    pub(super) fn accept(
+        &mut self,
+        request: IORequest,
+        priority: Priority,
+        handle: RequestHandle,
+        shutdown: &ShutdownState,
+    ) {
+        if shutdown.rejects_new() {
+            request.fail(StorageError::ShuttingDown);
+        } else if let Some(request) = self.keep_request(request) {
+            self.requests.push(request, priority, handle);
+        }
+    }
+    /// Fail every queued request with ShuttingDown
+    pub(super) fn reject_queued(&mut self) {
+        while let Some((request, _)) = self.requests.pop() {
+            request.fail(StorageError::ShuttingDown);
+        }
+        self.metrics.set_queue_len(0);
+    }
+}
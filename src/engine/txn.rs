@@ -0,0 +1,674 @@
+use super::*;
+use std::collections::BTreeMap;
+use std::fs::{self, File, OpenOptions};
+use std::io::{Read, Write};
+
+/// Operation staged in a Transaction, see IORequest::Commit
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum TxnOp {
+    /// Write data as a new record
+    Write(Vec<u8>),
+    /// Delete all blocks of a record
+    Delete {
+        record_id: RecordId,
+        hard_delete: bool,
+    },
+}
+
+/// Result of a committed transaction
+#[derive(Debug, Clone, PartialEq)]
+pub struct Committed {
+    /// Record id of every staged write, in staging order
+    pub written: Vec<RecordId>,
+    /// Number of blocks deleted by staged deletes, a block of a record staged twice
+    /// counts once
+    pub deleted_blocks: usize,
+}
+
+/// What Engine::recover_txn found of a commit interrupted by a crash
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TxnRecovery {
+    /// No commit was interrupted
+    Nothing,
+    /// A commit was interrupted before it committed, its records were deleted again
+    RolledBack,
+    /// A commit was interrupted after it committed, its deletes were applied
+    Completed,
+}
+
+/// When the result of a write, delete or commit request is sent, see Engine::set_ack_mode
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum AckMode {
+    /// Once the request is applied in place, the default
+    /// - the result says nothing about the disk: writes are synced as the durability
+    ///   mode of the storage says, or at the end of the io_cycle, see set_group_commit
+    #[default]
+    Applied,
+    /// Once the request is recorded in the journal of its storage, and journal and
+    /// written blocks are synced, whatever the durability mode
+    /// - requests are served like Transaction::commit, their result is sent once the
+    ///   journal is marked committed, deletes are applied in place after it
+    /// - a result of Ok survives a crash: Engine::recover_txn applies deletes that were
+    ///   not applied yet; call it on startup
+    /// - a delete failing after the result was sent keeps the journal, the next commit
+    ///   on the storage, or recover_txn, applies it
+    /// - writes with an expiry are served as Applied, the journal holds no expiries
+    Journaled,
+}
+
+/// Writes and deletes staged in memory, applied together on commit
+/// - writes go to fresh shadow blocks first, deletes are applied only once every
+///   write succeeded, so a failed commit leaves existing records untouched
+/// - commit keeps a journal next to the storage file, see commit, so a commit
+///   interrupted by a crash is rolled back or completed by Engine::recover_txn
+/// - dropping the transaction without commit discards it, like rollback
+pub struct Transaction<'a> {
+    target: Target<'a>,
+    ops: Vec<TxnOp>,
+}
+
+/// Engine a transaction is committed on
+enum Target<'a> {
+    Engine(&'a mut Engine),
+    Handle(&'a EngineHandle),
+}
+
+impl Engine {
+    /// Start a transaction on the storage of this engine
+    /// - queued requests are not affected, commit applies the transaction right away
+    pub fn begin_txn(&mut self) -> Transaction<'_> {
+        Transaction {
+            target: Target::Engine(self),
+            ops: Vec::new(),
+        }
+    }
+    /// Roll back or complete a commit of the storage of this engine interrupted by a
+    /// crash, from its journal, see Transaction::commit
+    /// - call it after opening the storage, before other writes: blocks of an
+    ///   interrupted commit are not reserved, other writes could take them
+    /// - commit calls it first as well
+    pub fn recover_txn(&mut self) -> Result<TxnRecovery, StorageError> {
+        recover(&mut self.storage)
+    }
+}
+
+impl Engine {
+    /// Send results of writes, deletes and commits once applied, or once journaled
+    /// - default is AckMode::Applied
+    pub fn set_ack_mode(&mut self, mode: AckMode) {
+        self.ack_mode = mode;
+    }
+    /// When results of writes, deletes and commits are sent, see set_ack_mode
+    pub fn ack_mode(&self) -> AckMode {
+        self.ack_mode
+    }
+}
+
+impl EngineHandle {
+    /// Start a transaction on the storage of the background engine
+    /// - commit sends the staged operations as one IORequest::Commit and waits for it
+    pub fn begin_txn(&self) -> Transaction<'_> {
+        Transaction {
+            target: Target::Handle(self),
+            ops: Vec::new(),
+        }
+    }
+}
+
+impl<'a> Transaction<'a> {
+    /// Stage data to be written as a new record
+    /// - returns: position of its record id in Committed::written
+    pub fn write(&mut self, data: Vec<u8>) -> usize {
+        let position = self
+            .ops
+            .iter()
+            .filter(|op| matches!(op, TxnOp::Write(_)))
+            .count();
+        self.ops.push(TxnOp::Write(data));
+        position
+    }
+    /// Stage deletion of all blocks of a record
+    pub fn delete(&mut self, record_id: RecordId, hard_delete: bool) {
+        self.ops.push(TxnOp::Delete {
+            record_id,
+            hard_delete,
+        });
+    }
+    /// Discard staged operations
+    pub fn rollback(self) {}
+    /// Apply all staged operations
+    /// - every staged write, every record to delete, its pins and append-only mode are
+    ///   checked first, before the file is touched
+    /// - shadow blocks for the writes are listed in the journal, a file next to the
+    ///   storage file with extension .txn, before they are written; once they are
+    ///   written and flushed the journal is marked committed, then deletes are applied
+    ///   and the journal removed
+    /// - on failure before the deletes are applied, or when the first delete fails,
+    ///   records written by this commit are deleted again; once a delete was applied the
+    ///   journal is kept, recover_txn applies the other deletes
+    /// - unless durability mode is Never, storage and journal are synced before each
+    ///   step
+    pub fn commit(self) -> Result<Committed, StorageError> {
+        match self.target {
+            Target::Engine(engine) => commit(&mut engine.storage, self.ops),
+            Target::Handle(handle) => {
+                let (request, receiver) = RequestBuilder::commit(self.ops).build();
+                handle.send(request);
+                receiver.recv().unwrap_or(Err(StorageError::EngineStopped))
+            }
+        }
+    }
+}
+
+// ... ... ... ... ... ... ... ... ... . Commit ... ... ... ... ... ... ... ... ... ... ...
+
+/// Apply ops together on storage, see Transaction::commit
+pub(super) fn commit(storage: &mut Storage, ops: Vec<TxnOp>) -> Result<Committed, StorageError> {
+    let journaled = journal_commit(storage, ops, false)?;
+    let committed = journaled.committed();
+    journaled.apply(storage, true)?;
+    Ok(committed)
+}
+
+/// Apply ops together on storage, sending result once the journal is committed and
+/// synced, see AckMode::Journaled
+/// - response: result of the committed ops
+pub(super) fn commit_journaled<T: Send + 'static>(
+    storage: &mut Storage,
+    ops: Vec<TxnOp>,
+    result: ResultSender<T>,
+    response: impl FnOnce(&JournaledCommit) -> T,
+) {
+    match journal_commit(storage, ops, true) {
+        Ok(journaled) => {
+            result.send(Ok(response(&journaled)));
+            let _ = journaled.apply(storage, false);
+        }
+        Err(error) => result.send(Err(error)),
+    }
+}
+
+/// Commit whose journal is marked committed, with its deletes not applied yet
+pub(super) struct JournaledCommit {
+    journal: Journal,
+    shadow_blocks: Vec<BlockIndex>,
+    deletes: BTreeMap<BlockIndex, bool>,
+    /// Blocks of every staged write, in staging order
+    pub(super) written_blocks: Vec<Vec<BlockIndex>>,
+}
+
+impl JournaledCommit {
+    pub(super) fn committed(&self) -> Committed {
+        Committed {
+            written: self.written_blocks.iter().map(|blocks| blocks[0]).collect(),
+            deleted_blocks: self.deletes.len(),
+        }
+    }
+    /// Apply deletes in place, then remove the journal
+    /// - undoable: when the first delete fails, uncommit and delete written records again
+    fn apply(self, storage: &mut Storage, undoable: bool) -> Result<(), StorageError> {
+        let journal = self.journal;
+        for (position, (block_index, hard_delete)) in self.deletes.iter().enumerate() {
+            if let Err(error) = storage.delete_block(*block_index, *hard_delete) {
+                if undoable && position == 0 && journal.uncommit().is_ok() {
+                    undo(storage, &journal, &self.shadow_blocks);
+                }
+                return Err(error);
+            }
+        }
+        journal.sync_storage(storage)?;
+        journal.remove()
+    }
+}
+
+/// Check ops, write their records to shadow blocks, then mark the journal committed
+/// - always_sync: sync journal and storage whatever the durability mode
+fn journal_commit(
+    storage: &mut Storage,
+    ops: Vec<TxnOp>,
+    always_sync: bool,
+) -> Result<JournaledCommit, StorageError> {
+    recover(storage)?;
+    // - check writes and collect blocks to delete, before touching the file
+    let mut writes = Vec::new();
+    let mut deletes = BTreeMap::new();
+    for op in ops {
+        match op {
+            TxnOp::Write(data) if data.is_empty() => return Err(StorageError::EmptyRecord),
+            TxnOp::Write(data) => writes.push(data),
+            TxnOp::Delete {
+                record_id,
+                hard_delete,
+            } => {
+                for block_index in storage.record_blocks(record_id)? {
+                    let hard = deletes.entry(block_index).or_insert(false);
+                    *hard |= hard_delete;
+                }
+            }
+        }
+    }
+    if let Some(block_index) = deletes.keys().next() {
+        if storage.is_append_only() {
+            return Err(StorageError::AppendOnly {
+                block_index: *block_index,
+            });
+        }
+    }
+    if let Some(block_index) = deletes
+        .keys()
+        .find(|block_index| storage.is_pinned(**block_index))
+    {
+        return Err(StorageError::BlockPinned {
+            block_index: *block_index,
+        });
+    }
+    // - shadow blocks, not referenced by anyone until commit returns
+    let chunk_len = storage.chunk_len();
+    let block_counts: Vec<usize> = writes
+        .iter()
+        .map(|data| data.len().div_ceil(chunk_len))
+        .collect();
+    let shadow_blocks = storage.allocate(block_counts.iter().sum())?;
+    let mut journal = Journal::new(storage);
+    journal.sync |= always_sync;
+    journal.prepare(&shadow_blocks, &deletes)?;
+    let mut written_blocks = Vec::with_capacity(writes.len());
+    let mut planned = &shadow_blocks[..];
+    for (data, block_count) in writes.iter().zip(block_counts) {
+        let (record_blocks, rest) = planned.split_at(block_count);
+        planned = rest;
+        match storage.write_into(record_blocks, data) {
+            Ok(block_indexes) => written_blocks.push(block_indexes),
+            Err(error) => {
+                undo(storage, &journal, &shadow_blocks);
+                return Err(error);
+            }
+        }
+    }
+    let committed = journal.sync_storage(storage).and_then(|_| journal.commit());
+    if let Err(error) = committed {
+        undo(storage, &journal, &shadow_blocks);
+        return Err(error);
+    }
+    Ok(JournaledCommit {
+        journal,
+        shadow_blocks,
+        deletes,
+        written_blocks,
+    })
+}
+
+/// Delete shadow blocks of a failed commit, then its journal
+/// - failure to undo leaves the journal, recover_txn deletes them again; report the
+///   original error
+fn undo(storage: &mut Storage, journal: &Journal, shadow_blocks: &[BlockIndex]) {
+    for block_index in shadow_blocks {
+        if storage.delete_block(*block_index, false).is_err() {
+            return;
+        }
+    }
+    if journal.sync_storage(storage).is_ok() {
+        let _ = journal.remove();
+    }
+}
+
+/// Roll back or complete the commit of the journal of storage, if any
+fn recover(storage: &mut Storage) -> Result<TxnRecovery, StorageError> {
+    let journal = Journal::new(storage);
+    let (shadow_blocks, deletes, committed) = match journal.read()? {
+        Some(entry) => entry,
+        None => return Ok(TxnRecovery::Nothing),
+    };
+    // - a free block is not deleted again
+    let recovery = if committed {
+        for (block_index, hard_delete) in deletes {
+            storage.delete_block(block_index, hard_delete)?;
+        }
+        TxnRecovery::Completed
+    } else {
+        for block_index in shadow_blocks {
+            storage.delete_block(block_index, false)?;
+        }
+        TxnRecovery::RolledBack
+    };
+    journal.sync_storage(storage)?;
+    journal.remove()?;
+    Ok(recovery)
+}
+
+// ... ... ... ... ... ... ... ... ... . Journal ... ... ... ... ... ... ... ... ... ... ..
+
+/// First bytes of a journal
+const JOURNAL_MAGIC: &[u8; 4] = b"SE1T";
+/// Last byte of a committed journal
+const JOURNAL_COMMITTED: u8 = 1;
+
+/// Journal of a commit, a file next to the storage file
+/// - magic, shadow block count u64 and shadow blocks u64, delete count u64 and every
+///   block to delete as block index u64 and hard delete u8, all little endian
+/// - JOURNAL_COMMITTED is appended once the shadow blocks are written
+/// - a journal cut short was written before any shadow block, it is ignored
+struct Journal {
+    path: String,
+    /// Sync journal and storage, false with DurabilityMode::Never
+    sync: bool,
+}
+
+impl Journal {
+    fn new(storage: &Storage) -> Self {
+        Journal {
+            path: format!("{}.txn", storage.file_path()),
+            sync: storage.durability() != DurabilityMode::Never,
+        }
+    }
+    fn prepare(
+        &self,
+        shadow_blocks: &[BlockIndex],
+        deletes: &BTreeMap<BlockIndex, bool>,
+    ) -> Result<(), StorageError> {
+        let mut bytes = JOURNAL_MAGIC.to_vec();
+        bytes.extend_from_slice(&(shadow_blocks.len() as u64).to_le_bytes());
+        for block_index in shadow_blocks {
+            bytes.extend_from_slice(&block_index.to_le_bytes());
+        }
+        bytes.extend_from_slice(&(deletes.len() as u64).to_le_bytes());
+        for (block_index, hard_delete) in deletes {
+            bytes.extend_from_slice(&block_index.to_le_bytes());
+            bytes.push(u8::from(*hard_delete));
+        }
+        let mut file = File::create(&self.path).map_err(journal_error)?;
+        file.write_all(&bytes).map_err(journal_error)?;
+        self.sync_file(&file)
+    }
+    /// Mark journal committed
+    fn commit(&self) -> Result<(), StorageError> {
+        let mut file = OpenOptions::new()
+            .append(true)
+            .open(&self.path)
+            .map_err(journal_error)?;
+        file.write_all(&[JOURNAL_COMMITTED])
+            .map_err(journal_error)?;
+        self.sync_file(&file)
+    }
+    /// Drop the commit mark, before any delete was applied
+    fn uncommit(&self) -> Result<(), StorageError> {
+        let file = OpenOptions::new()
+            .write(true)
+            .open(&self.path)
+            .map_err(journal_error)?;
+        let len = file.metadata().map_err(journal_error)?.len();
+        file.set_len(len.saturating_sub(1)).map_err(journal_error)?;
+        self.sync_file(&file)
+    }
+    fn remove(&self) -> Result<(), StorageError> {
+        fs::remove_file(&self.path).map_err(journal_error)
+    }
+    fn sync_file(&self, file: &File) -> Result<(), StorageError> {
+        if self.sync {
+            file.sync_all().map_err(journal_error)?;
+        }
+        Ok(())
+    }
+    fn sync_storage(&self, storage: &mut Storage) -> Result<(), StorageError> {
+        if self.sync {
+            storage.flush()?;
+        }
+        Ok(())
+    }
+    /// Shadow blocks, blocks to delete and if the commit committed
+    /// - returns: None without journal, a journal cut short is removed
+    #[allow(clippy::type_complexity)]
+    fn read(
+        &self,
+    ) -> Result<Option<(Vec<BlockIndex>, Vec<(BlockIndex, bool)>, bool)>, StorageError> {
+        let mut bytes = Vec::new();
+        match File::open(&self.path) {
+            Ok(mut file) => file.read_to_end(&mut bytes).map_err(journal_error)?,
+            Err(error) if error.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+            Err(error) => return Err(journal_error(error)),
+        };
+        match parse_journal(&bytes) {
+            Some(entry) => Ok(Some(entry)),
+            None => {
+                self.remove()?;
+                Ok(None)
+            }
+        }
+    }
+}
+
+fn journal_error(error: std::io::Error) -> StorageError {
+    StorageError::Io {
+        operation: "write transaction journal",
+        block_index: None,
+        source: error,
+    }
+}
+
+/// Parse journal, None if it is cut short
+#[allow(clippy::type_complexity)]
+fn parse_journal(bytes: &[u8]) -> Option<(Vec<BlockIndex>, Vec<(BlockIndex, bool)>, bool)> {
+    fn read_u64(bytes: &mut &[u8]) -> Option<u64> {
+        let (value, rest) = (bytes.get(..8)?, bytes.get(8..)?);
+        *bytes = rest;
+        let mut value_bytes = [0u8; 8];
+        value_bytes.copy_from_slice(value);
+        Some(u64::from_le_bytes(value_bytes))
+    }
+    let mut bytes = bytes.strip_prefix(&JOURNAL_MAGIC[..])?;
+    let shadow_count = read_u64(&mut bytes)?;
+    let mut shadow_blocks = Vec::new();
+    for _ in 0..shadow_count {
+        shadow_blocks.push(read_u64(&mut bytes)?);
+    }
+    let delete_count = read_u64(&mut bytes)?;
+    let mut deletes = Vec::new();
+    for _ in 0..delete_count {
+        let block_index = read_u64(&mut bytes)?;
+        let (hard_delete, rest) = bytes.split_first()?;
+        bytes = rest;
+        deletes.push((block_index, *hard_delete != 0));
+    }
+    Some((shadow_blocks, deletes, bytes == [JOURNAL_COMMITTED]))
+}
+
+#[cfg(test)]
+mod unit_tests_txn {
+    use super::*;
+
+    fn new_engine(tmp_dir: &tempfile::TempDir) -> Engine {
+        let file_path = tmp_dir.path().join("txn.hex");
+        Engine::new(Storage::new(file_path.to_str().unwrap().to_string(), 4).unwrap())
+    }
+
+    #[test]
+    fn test_commit() {
+        let tmp_dir = tempfile::tempdir().unwrap();
+        let mut engine = new_engine(&tmp_dir);
+        let old_record = engine.storage.write_record(&[9; 6]).unwrap();
+        let mut txn = engine.begin_txn();
+        assert_eq!(txn.write(vec![1; 5]), 0);
+        txn.delete(old_record, false);
+        assert_eq!(txn.write(vec![2]), 1);
+        let committed = txn.commit().unwrap();
+        // writes do not reuse blocks of records deleted in the same transaction
+        assert_eq!(committed.written, vec![2, 4]);
+        assert_eq!(committed.deleted_blocks, 2);
+        assert_eq!(engine.storage.read_record(2).unwrap(), vec![1; 5]);
+        assert_eq!(engine.storage.read_record(4).unwrap(), vec![2]);
+        assert_eq!(
+            engine.storage.read_record(old_record).unwrap(),
+            Vec::<u8>::new()
+        );
+    }
+    #[test]
+    fn test_journaled_ack() {
+        let tmp_dir = tempfile::tempdir().unwrap();
+        let mut engine = new_engine(&tmp_dir);
+        engine.set_ack_mode(AckMode::Journaled);
+        let record = engine.storage.write_record(&[9; 6]).unwrap();
+        let journal_path = format!("{}.txn", engine.storage.file_path());
+        let (sender, receiver) = mpsc::channel();
+        let journal = journal_path.clone();
+        let delete =
+            RequestBuilder::delete(record, false).build_with(ResultSender::new(move |result| {
+                let journal_bytes = fs::read(&journal).unwrap();
+                sender
+                    .send((result, journal_bytes.last().cloned()))
+                    .unwrap();
+            }));
+        engine.append_request(delete);
+        engine.io_cycle().unwrap();
+        // - result is sent once the journal is committed, before the delete is applied
+        let (result, last_byte) = receiver.recv().unwrap();
+        assert_eq!(result.unwrap(), 2);
+        assert_eq!(last_byte, Some(JOURNAL_COMMITTED));
+        assert!(!std::path::Path::new(&journal_path).exists());
+        assert_eq!(
+            engine.storage.read_record(record).unwrap(),
+            Vec::<u8>::new()
+        );
+        let (write, write_receiver) = RequestBuilder::write(vec![1; 5], None).build();
+        engine.append_request(write);
+        engine.io_cycle().unwrap();
+        let written = write_receiver.recv().unwrap().unwrap();
+        assert_eq!(written.bytes_written, 5);
+        assert_eq!(
+            engine.storage.read_record(written.record_id).unwrap(),
+            vec![1; 5]
+        );
+        // - a crash after the result was sent leaves the delete to recover_txn
+        let ops = vec![TxnOp::Delete {
+            record_id: written.record_id,
+            hard_delete: false,
+        }];
+        drop(journal_commit(&mut engine.storage, ops, true).unwrap());
+        assert_eq!(engine.recover_txn().unwrap(), TxnRecovery::Completed);
+        assert_eq!(
+            engine.storage.read_record(written.record_id).unwrap(),
+            Vec::<u8>::new()
+        );
+    }
+    #[test]
+    fn test_rollback() {
+        let tmp_dir = tempfile::tempdir().unwrap();
+        let mut engine = new_engine(&tmp_dir);
+        let old_record = engine.storage.write_record(&[9]).unwrap();
+        let mut txn = engine.begin_txn();
+        txn.write(vec![1]);
+        txn.delete(old_record, true);
+        txn.rollback();
+        assert_eq!(engine.storage.read_record(old_record).unwrap(), vec![9]);
+        assert_eq!(engine.storage.read_record(1).unwrap(), Vec::<u8>::new());
+    }
+    #[test]
+    fn test_failed_commit_is_undone() {
+        let tmp_dir = tempfile::tempdir().unwrap();
+        let mut engine = new_engine(&tmp_dir);
+        // second write does not fit in a block after transform
+        struct Grow;
+        impl crate::storage::BlockTransform for Grow {
+            fn name(&self) -> &str {
+                "grow"
+            }
+            fn encode(&self, data: &[u8]) -> Result<Vec<u8>, StorageError> {
+                Ok([data, &[0]].concat())
+            }
+            fn decode(&self, data: &[u8]) -> Result<Vec<u8>, StorageError> {
+                Ok(data[..data.len() - 1].to_vec())
+            }
+        }
+        engine.storage.push_transform(Box::new(Grow)).unwrap();
+        let old_record = engine.storage.write_record(&[9]).unwrap();
+        let mut txn = engine.begin_txn();
+        txn.write(vec![1; 3]);
+        txn.write(vec![2; 4]);
+        txn.delete(old_record, false);
+        assert!(matches!(
+            txn.commit(),
+            Err(StorageError::BlockTooLarge { .. })
+        ));
+        // first write was undone, delete was never applied
+        assert_eq!(engine.storage.read_record(1).unwrap(), Vec::<u8>::new());
+        assert_eq!(engine.storage.read_record(old_record).unwrap(), vec![9]);
+        // empty write is rejected before anything is written
+        let mut txn = engine.begin_txn();
+        txn.delete(old_record, false);
+        txn.write(Vec::new());
+        assert!(matches!(txn.commit(), Err(StorageError::EmptyRecord)));
+        assert_eq!(engine.storage.read_record(old_record).unwrap(), vec![9]);
+        // - a pinned record is rejected before anything is written
+        engine.storage.pin_block(old_record);
+        let mut txn = engine.begin_txn();
+        txn.write(vec![3]);
+        txn.delete(old_record, false);
+        assert!(matches!(
+            txn.commit(),
+            Err(StorageError::BlockPinned { block_index: 0 })
+        ));
+        assert!(engine.storage.read_block(1).unwrap().1.is_empty());
+        assert!(!std::path::Path::new(&Journal::new(&engine.storage).path).exists());
+    }
+    #[test]
+    fn test_duplicate_deletes_count_once() {
+        let tmp_dir = tempfile::tempdir().unwrap();
+        let mut engine = new_engine(&tmp_dir);
+        let old_record = engine.storage.write_record(&[9; 6]).unwrap();
+        let mut txn = engine.begin_txn();
+        txn.delete(old_record, false);
+        txn.delete(old_record, true);
+        assert_eq!(txn.commit().unwrap().deleted_blocks, 2);
+        assert!(engine.storage.read_record(old_record).unwrap().is_empty());
+    }
+    #[test]
+    fn test_recover_txn() {
+        let tmp_dir = tempfile::tempdir().unwrap();
+        let mut engine = new_engine(&tmp_dir);
+        let old_record = engine.storage.write_record(&[9]).unwrap();
+        let journal = Journal::new(&engine.storage);
+        let deletes: BTreeMap<BlockIndex, bool> = [(old_record, false)].iter().cloned().collect();
+        // - crash after a shadow write, before the journal was committed
+        journal.prepare(&[1, 2], &deletes).unwrap();
+        engine.storage.write_block(1, &[1]).unwrap();
+        assert_eq!(engine.recover_txn().unwrap(), TxnRecovery::RolledBack);
+        assert!(engine.storage.read_block(1).unwrap().1.is_empty());
+        assert_eq!(engine.storage.read_record(old_record).unwrap(), vec![9]);
+        assert_eq!(engine.recover_txn().unwrap(), TxnRecovery::Nothing);
+        // - crash after the journal was committed, before the deletes
+        journal.prepare(&[1], &deletes).unwrap();
+        engine.storage.write_block(1, &[1]).unwrap();
+        journal.commit().unwrap();
+        assert_eq!(engine.recover_txn().unwrap(), TxnRecovery::Completed);
+        assert_eq!(engine.storage.read_block(1).unwrap().1, vec![1]);
+        assert!(engine.storage.read_record(old_record).unwrap().is_empty());
+        // - a journal cut short was written before any shadow block
+        std::fs::write(&journal.path, &JOURNAL_MAGIC[..]).unwrap();
+        assert_eq!(engine.recover_txn().unwrap(), TxnRecovery::Nothing);
+        assert!(!std::path::Path::new(&journal.path).exists());
+    }
+    #[test]
+    fn test_handle_commit() {
+        let tmp_dir = tempfile::tempdir().unwrap();
+        let mut engine = new_engine(&tmp_dir);
+        let old_record = engine.storage.write_record(&[9]).unwrap();
+        let handle = engine.spawn_engine();
+        let mut txn = handle.begin_txn();
+        txn.write(vec![1; 5]);
+        txn.delete(old_record, false);
+        let committed = txn.commit().unwrap();
+        assert_eq!(committed.deleted_blocks, 1);
+        let record_id = committed.written[0];
+        assert_eq!(
+            handle.read(record_id).recv().unwrap().unwrap().data,
+            vec![1; 5]
+        );
+        assert!(handle
+            .read(old_record)
+            .recv()
+            .unwrap()
+            .unwrap()
+            .data
+            .is_empty());
+        handle.join().unwrap();
+    }
+}
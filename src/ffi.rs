@@ -0,0 +1,313 @@
+//! C bindings of Storage, for embedding in other languages
+//! - header is include/se1.h, generated by build.rs with cbindgen
+//! - build the shared library with `cargo rustc --release --lib --features ffi
+//!   --crate-type cdylib`
+//! - every function returns SE1_OK or an SE1_ERR_* code, results are written to out
+//!   parameters only on SE1_OK
+//! - message of the last error on the calling thread is se1_last_error_message
+//! - a Se1Storage must not be used from two threads at the same time
+use crate::storage::{Storage, StorageError};
+use std::cell::RefCell;
+use std::ffi::{CStr, CString};
+use std::os::raw::c_char;
+use std::panic::{self, AssertUnwindSafe};
+use std::path::Path;
+
+/// Call succeeded
+pub const SE1_OK: i32 = 0;
+/// A pointer argument was null, or the path was not UTF-8
+pub const SE1_ERR_INVALID_POINTER: i32 = 1;
+/// Storage file could not be read or written
+pub const SE1_ERR_IO: i32 = 2;
+/// Storage file content is invalid, or not a storage file of a supported version
+pub const SE1_ERR_CORRUPTION: i32 = 3;
+/// Argument out of range, e.g. empty record data or invalid block length
+pub const SE1_ERR_INVALID_ARGUMENT: i32 = 4;
+/// Operation is not allowed, the block is pinned, the file is append-only or read-only,
+/// or the write would grow the file past its quota
+pub const SE1_ERR_NOT_PERMITTED: i32 = 5;
+/// Any other failure, see se1_last_error_message
+pub const SE1_ERR_OTHER: i32 = 6;
+/// se1 panicked, the storage should be closed
+pub const SE1_ERR_PANIC: i32 = 7;
+
+/// Open storage file, opaque to C
+pub struct Se1Storage {
+    storage: Storage,
+}
+
+thread_local! {
+    static LAST_ERROR: RefCell<Option<CString>> = const { RefCell::new(None) };
+}
+
+/// Run f, turning its error or panic into an error code and recording its message
+fn call(f: impl FnOnce() -> Result<(), (i32, String)>) -> i32 {
+    let (code, message) = match panic::catch_unwind(AssertUnwindSafe(f)) {
+        Ok(Ok(())) => return SE1_OK,
+        Ok(Err(error)) => error,
+        Err(_) => (SE1_ERR_PANIC, "se1 panicked".to_string()),
+    };
+    // - messages of StorageError hold no NUL bytes, fall back to an empty message
+    let message = CString::new(message).unwrap_or_default();
+    LAST_ERROR.with(|last_error| *last_error.borrow_mut() = Some(message));
+    code
+}
+
+/// Error code and message of a StorageError
+fn storage_error(error: StorageError) -> (i32, String) {
+    let code = match error.untraced() {
+        StorageError::Io { .. } => SE1_ERR_IO,
+        StorageError::Corruption { .. }
+        | StorageError::ChecksumMismatch { .. }
+        | StorageError::NotStorageFile
+        | StorageError::UnsupportedVersion { .. }
+        | StorageError::DecryptionFailed { .. } => SE1_ERR_CORRUPTION,
+        StorageError::BlockOutOfRange { .. }
+        | StorageError::BlockTooLarge { .. }
+        | StorageError::InvalidBlockLength { .. }
+        | StorageError::EmptyRecord
+        | StorageError::RecordTooLarge { .. }
+        | StorageError::TooLarge { .. }
+        | StorageError::NotEnoughBlocks { .. }
+        | StorageError::DuplicateBlock { .. } => SE1_ERR_INVALID_ARGUMENT,
+        StorageError::BlockPinned { .. }
+        | StorageError::AppendOnly { .. }
+        | StorageError::QuotaExceeded { .. }
+        | StorageError::ReadOnly
+        | StorageError::Frozen { .. } => SE1_ERR_NOT_PERMITTED,
+        _ => SE1_ERR_OTHER,
+    };
+    (code, error.to_string())
+}
+
+fn invalid_pointer(name: &str) -> (i32, String) {
+    (SE1_ERR_INVALID_POINTER, format!("{} is null", name))
+}
+
+/// Open storage file at path, creating it with block_len if it does not exist
+/// - block_len is ignored for an existing file, it keeps its block length
+/// - out_storage: set to the opened storage, close it with se1_close
+///
+/// # Safety
+/// path must be a NUL terminated string, out_storage must be valid for a write
+#[no_mangle]
+pub unsafe extern "C" fn se1_open(
+    path: *const c_char,
+    block_len: u64,
+    out_storage: *mut *mut Se1Storage,
+) -> i32 {
+    call(|| {
+        if path.is_null() {
+            return Err(invalid_pointer("path"));
+        }
+        if out_storage.is_null() {
+            return Err(invalid_pointer("out_storage"));
+        }
+        // SAFETY: path is a NUL terminated string, checked for null above
+        let path = unsafe { CStr::from_ptr(path) }
+            .to_str()
+            .map_err(|_| (SE1_ERR_INVALID_POINTER, "path is not UTF-8".to_string()))?
+            .to_string();
+        let storage = if Path::new(&path).exists() {
+            Storage::open(path)
+        } else {
+            Storage::new(path, block_len)
+        }
+        .map_err(storage_error)?;
+        let storage = Box::into_raw(Box::new(Se1Storage { storage }));
+        // SAFETY: out_storage is valid for a write, checked for null above
+        unsafe { *out_storage = storage };
+        Ok(())
+    })
+}
+
+/// Write data_len bytes of data as a new record, see Storage::write_record
+/// - out_record_id: set to the record id, to read or delete the record
+///
+/// # Safety
+/// storage must come from se1_open, data must be valid for data_len bytes unless
+/// data_len is 0, out_record_id must be valid for a write
+#[no_mangle]
+pub unsafe extern "C" fn se1_write(
+    storage: *mut Se1Storage,
+    data: *const u8,
+    data_len: usize,
+    out_record_id: *mut u64,
+) -> i32 {
+    call(|| {
+        // SAFETY: storage comes from se1_open and is not used by another thread
+        let storage = unsafe { storage.as_mut() }.ok_or_else(|| invalid_pointer("storage"))?;
+        if out_record_id.is_null() {
+            return Err(invalid_pointer("out_record_id"));
+        }
+        let data = match (data.is_null(), data_len) {
+            (_, 0) => &[][..],
+            (true, _) => return Err(invalid_pointer("data")),
+            // SAFETY: data is valid for data_len bytes, checked for null above
+            (false, _) => unsafe { std::slice::from_raw_parts(data, data_len) },
+        };
+        let record_id = storage.storage.write_record(data).map_err(storage_error)?;
+        // SAFETY: out_record_id is valid for a write, checked for null above
+        unsafe { *out_record_id = record_id };
+        Ok(())
+    })
+}
+
+/// Read all data of a record, see Storage::read_record
+/// - out_data, out_data_len: set to a buffer allocated by se1 and its length, free it
+///   with se1_free_data, an empty record sets null and 0
+///
+/// # Safety
+/// storage must come from se1_open, out_data and out_data_len must be valid for a write
+#[no_mangle]
+pub unsafe extern "C" fn se1_read(
+    storage: *mut Se1Storage,
+    record_id: u64,
+    out_data: *mut *mut u8,
+    out_data_len: *mut usize,
+) -> i32 {
+    call(|| {
+        // SAFETY: storage comes from se1_open and is not used by another thread
+        let storage = unsafe { storage.as_mut() }.ok_or_else(|| invalid_pointer("storage"))?;
+        if out_data.is_null() || out_data_len.is_null() {
+            return Err(invalid_pointer("out_data"));
+        }
+        let data = storage
+            .storage
+            .read_record(record_id)
+            .map_err(storage_error)?;
+        let data_len = data.len();
+        let data = if data.is_empty() {
+            std::ptr::null_mut()
+        } else {
+            Box::into_raw(data.into_boxed_slice()) as *mut u8
+        };
+        // SAFETY: out_data and out_data_len are valid for a write, checked for null above
+        unsafe {
+            *out_data = data;
+            *out_data_len = data_len;
+        }
+        Ok(())
+    })
+}
+
+/// Free a buffer returned by se1_read, null is ignored
+///
+/// # Safety
+/// data and data_len must be a buffer and its length set by se1_read, freed only once
+#[no_mangle]
+pub unsafe extern "C" fn se1_free_data(data: *mut u8, data_len: usize) {
+    if !data.is_null() {
+        // SAFETY: data is a boxed slice of data_len bytes made by se1_read
+        drop(unsafe { Box::from_raw(std::ptr::slice_from_raw_parts_mut(data, data_len)) });
+    }
+}
+
+/// Delete all blocks of a record, see Storage::delete_record
+/// - hard_delete: zero block data on disk, not only free the blocks
+/// - out_deleted_blocks: set to the number of deleted blocks, may be null
+///
+/// # Safety
+/// storage must come from se1_open, out_deleted_blocks must be null or valid for a write
+#[no_mangle]
+pub unsafe extern "C" fn se1_delete(
+    storage: *mut Se1Storage,
+    record_id: u64,
+    hard_delete: bool,
+    out_deleted_blocks: *mut usize,
+) -> i32 {
+    call(|| {
+        // SAFETY: storage comes from se1_open and is not used by another thread
+        let storage = unsafe { storage.as_mut() }.ok_or_else(|| invalid_pointer("storage"))?;
+        let deleted_blocks = storage
+            .storage
+            .delete_record(record_id, hard_delete)
+            .map_err(storage_error)?;
+        // SAFETY: out_deleted_blocks is null or valid for a write
+        if let Some(out_deleted_blocks) = unsafe { out_deleted_blocks.as_mut() } {
+            *out_deleted_blocks = deleted_blocks;
+        }
+        Ok(())
+    })
+}
+
+/// Close storage file cleanly, see Storage::close
+/// - storage is freed even if closing fails, it must not be used afterwards
+///
+/// # Safety
+/// storage must come from se1_open, and be closed only once
+#[no_mangle]
+pub unsafe extern "C" fn se1_close(storage: *mut Se1Storage) -> i32 {
+    call(|| {
+        if storage.is_null() {
+            return Err(invalid_pointer("storage"));
+        }
+        // SAFETY: storage comes from se1_open and is closed only once
+        let storage = unsafe { Box::from_raw(storage) };
+        storage.storage.close().map_err(storage_error)
+    })
+}
+
+/// Message of the last error on the calling thread, null if no call failed yet
+/// - valid until the next failing call on the same thread, do not free it
+#[no_mangle]
+pub extern "C" fn se1_last_error_message() -> *const c_char {
+    LAST_ERROR.with(|last_error| {
+        last_error
+            .borrow()
+            .as_ref()
+            .map_or(std::ptr::null(), |message| message.as_ptr())
+    })
+}
+
+#[cfg(test)]
+mod unit_tests_ffi {
+    use super::*;
+
+    #[test]
+    fn test_ffi_calls() {
+        let tmp_dir = tempfile::tempdir().unwrap();
+        let file_path = tmp_dir.path().join("ffi.hex");
+        let path = CString::new(file_path.to_str().unwrap()).unwrap();
+        let mut storage = std::ptr::null_mut();
+        unsafe {
+            assert_eq!(se1_open(path.as_ptr(), 4, &mut storage), SE1_OK);
+            let mut record_id = u64::MAX;
+            let data = [1u8, 2, 3, 4, 5];
+            assert_eq!(
+                se1_write(storage, data.as_ptr(), data.len(), &mut record_id),
+                SE1_OK
+            );
+            // - empty record is rejected with a message
+            assert_eq!(
+                se1_write(storage, data.as_ptr(), 0, &mut record_id),
+                SE1_ERR_INVALID_ARGUMENT
+            );
+            let message = CStr::from_ptr(se1_last_error_message());
+            assert_eq!(message.to_str().unwrap(), "Record data is empty");
+            assert_eq!(se1_close(storage), SE1_OK);
+            // - reopened file keeps its block length
+            assert_eq!(se1_open(path.as_ptr(), 99, &mut storage), SE1_OK);
+            let (mut out_data, mut out_data_len) = (std::ptr::null_mut(), 0);
+            assert_eq!(
+                se1_read(storage, record_id, &mut out_data, &mut out_data_len),
+                SE1_OK
+            );
+            assert_eq!(std::slice::from_raw_parts(out_data, out_data_len), data);
+            se1_free_data(out_data, out_data_len);
+            let mut deleted_blocks = 0;
+            assert_eq!(
+                se1_delete(storage, record_id, true, &mut deleted_blocks),
+                SE1_OK
+            );
+            assert_eq!(deleted_blocks, 2);
+            assert_eq!(
+                se1_read(storage, record_id, &mut out_data, &mut out_data_len),
+                SE1_OK
+            );
+            assert!(out_data.is_null() && out_data_len == 0);
+            assert_eq!(se1_close(storage), SE1_OK);
+            assert_eq!(se1_close(std::ptr::null_mut()), SE1_ERR_INVALID_POINTER);
+        }
+    }
+}
@@ -0,0 +1,278 @@
+//! gRPC service of proto/se1.proto, serving block operations on a background Engine
+//! - `proto` holds the messages, `proto::block_service_server` the tonic server and
+//!   `proto::block_service_client` a tonic client, generated at build time
+//! - clients are made from a tonic Channel, with BlockServiceClient::new
+//! - the service is versioned by its package, `se1.v1`
+//! - an rpc with metadata `x-trace-id` is served as a traced request, see
+//!   IORequest::traced; its errors carry the trace id in the status message
+use crate::engine::metrics::OpKind;
+use crate::engine::r#async::AsyncEngine;
+use crate::engine::{EngineHandle, RequestBuilder};
+use crate::storage::StorageError;
+use std::convert::TryFrom;
+use std::pin::Pin;
+use std::sync::Arc;
+use tokio_stream::wrappers::ReceiverStream;
+use tonic::{Request, Response, Status};
+
+/// Messages and tonic service code generated from proto/se1.proto
+#[allow(clippy::all, clippy::unwrap_used, clippy::expect_used, clippy::panic)]
+pub mod proto {
+    tonic::include_proto!("se1.v1");
+}
+
+use proto::block_service_server::{BlockService as BlockServiceRpc, BlockServiceServer};
+
+/// Blocks read per engine request of ScanBlocks, if the request leaves page_size 0
+pub const DEFAULT_SCAN_PAGE_SIZE: usize = 64;
+
+/// Metadata key of the trace id of an rpc
+pub const TRACE_ID_METADATA: &str = "x-trace-id";
+
+/// Blocks of ScanBlocks buffered ahead of a slow client
+const SCAN_BUFFER_LEN: usize = 64;
+
+/// BlockService of proto/se1.proto on the requests queue of an EngineHandle
+/// - turn it into a tonic service with into_server, and add it to a
+///   tonic::transport::Server
+pub struct BlockService {
+    engine: Arc<AsyncEngine>,
+}
+
+impl BlockService {
+    pub fn new(handle: EngineHandle) -> Self {
+        BlockService {
+            engine: Arc::new(AsyncEngine::from(handle)),
+        }
+    }
+    /// Tonic service serving rpcs with this BlockService
+    pub fn into_server(self) -> BlockServiceServer<BlockService> {
+        BlockServiceServer::new(self)
+    }
+}
+
+type ScanBlocksStream =
+    Pin<Box<dyn tokio_stream::Stream<Item = Result<proto::Block, Status>> + Send>>;
+
+#[tonic::async_trait]
+impl BlockServiceRpc for BlockService {
+    async fn read_blocks(
+        &self,
+        request: Request<proto::ReadBlocksRequest>,
+    ) -> Result<Response<proto::ReadBlocksResponse>, Status> {
+        let trace_id = trace_id(&request);
+        let read = RequestBuilder::read_blocks(request.into_inner().record_id);
+        let blocks = self.engine.submit(traced(read, &trace_id)).await;
+        Ok(Response::new(proto::ReadBlocksResponse {
+            blocks: blocks.map_err(status)?,
+        }))
+    }
+    async fn write_record(
+        &self,
+        request: Request<proto::WriteRecordRequest>,
+    ) -> Result<Response<proto::WriteRecordResponse>, Status> {
+        let trace_id = trace_id(&request);
+        let request = request.into_inner();
+        let write = RequestBuilder::write(request.data, request.expires_at);
+        let record_id = self.engine.submit(traced(write, &trace_id)).await;
+        Ok(Response::new(proto::WriteRecordResponse {
+            record_id: record_id.map_err(status)?.record_id,
+        }))
+    }
+    async fn delete_blocks(
+        &self,
+        request: Request<proto::DeleteBlocksRequest>,
+    ) -> Result<Response<proto::DeleteBlocksResponse>, Status> {
+        let trace_id = trace_id(&request);
+        let request = request.into_inner();
+        let delete = RequestBuilder::delete(request.record_id, request.hard_delete);
+        let deleted_blocks = self.engine.submit(traced(delete, &trace_id)).await;
+        Ok(Response::new(proto::DeleteBlocksResponse {
+            deleted_blocks: deleted_blocks.map_err(status)? as u64,
+        }))
+    }
+    async fn stats(
+        &self,
+        _request: Request<proto::StatsRequest>,
+    ) -> Result<Response<proto::StatsResponse>, Status> {
+        let metrics = self.engine.metrics();
+        Ok(Response::new(proto::StatsResponse {
+            reads: metrics.served(OpKind::Read),
+            writes: metrics.served(OpKind::Write),
+            deletes: metrics.served(OpKind::Delete),
+            errors: metrics.errors(),
+            bytes_in: metrics.bytes_in(),
+            bytes_out: metrics.bytes_out(),
+            queue_len: metrics.queue_len(),
+        }))
+    }
+
+    type ScanBlocksStream = ScanBlocksStream;
+
+    /// Stream blocks read page by page, one IORequest::ScanBlocks per page
+    /// - requests of other clients are served between pages, blocks written or deleted
+    ///   meanwhile may or may not be streamed
+    /// - a block that fails to read ends the stream with its error
+    async fn scan_blocks(
+        &self,
+        request: Request<proto::ScanBlocksRequest>,
+    ) -> Result<Response<Self::ScanBlocksStream>, Status> {
+        let trace_id = trace_id(&request);
+        let request = request.into_inner();
+        let page_size = match usize::try_from(request.page_size) {
+            Ok(0) | Err(_) => DEFAULT_SCAN_PAGE_SIZE,
+            Ok(page_size) => page_size,
+        };
+        let (sender, receiver) = tokio::sync::mpsc::channel(SCAN_BUFFER_LEN);
+        let engine = self.engine.clone();
+        tokio::spawn(async move {
+            let mut from_block = request.from_block;
+            loop {
+                let scan = RequestBuilder::scan_blocks(from_block, page_size);
+                let blocks = match engine.submit(traced(scan, &trace_id)).await {
+                    Ok(blocks) => blocks,
+                    Err(error) => {
+                        let _ = sender.send(Err(status(error))).await;
+                        return;
+                    }
+                };
+                let last_page = blocks.len() < page_size;
+                for (block_index, data) in blocks {
+                    from_block = block_index.saturating_add(1);
+                    let block = proto::Block { block_index, data };
+                    // - client went away
+                    if sender.send(Ok(block)).await.is_err() {
+                        return;
+                    }
+                }
+                if last_page {
+                    return;
+                }
+            }
+        });
+        Ok(Response::new(Box::pin(ReceiverStream::new(receiver))))
+    }
+}
+
+/// Trace id of rpc, from its TRACE_ID_METADATA
+fn trace_id<M>(rpc: &Request<M>) -> Option<String> {
+    let trace_id = rpc.metadata().get(TRACE_ID_METADATA)?;
+    trace_id.to_str().ok().map(str::to_string)
+}
+
+/// Request traced with trace_id, if any
+fn traced<T: Send + 'static>(
+    request: RequestBuilder<T>,
+    trace_id: &Option<String>,
+) -> RequestBuilder<T> {
+    match trace_id {
+        Some(trace_id) => request.traced(trace_id.as_str()),
+        None => request,
+    }
+}
+
+/// gRPC status of a failed engine request
+fn status(error: StorageError) -> Status {
+    let message = error.to_string();
+    match error.untraced() {
+        StorageError::BlockOutOfRange { .. } => Status::out_of_range(message),
+        StorageError::EmptyRecord
+        | StorageError::BlockTooLarge { .. }
+        | StorageError::RecordTooLarge { .. }
+        | StorageError::TooLarge { .. }
+        | StorageError::NotEnoughBlocks { .. }
+        | StorageError::DuplicateBlock { .. } => Status::invalid_argument(message),
+        StorageError::BlockPinned { .. }
+        | StorageError::AppendOnly { .. }
+        | StorageError::ReadOnly
+        | StorageError::Frozen { .. } => Status::failed_precondition(message),
+        StorageError::Conflict { .. } => Status::aborted(message),
+        StorageError::QuotaExceeded { .. } | StorageError::Overloaded => {
+            Status::resource_exhausted(message)
+        }
+        StorageError::UnknownStorage { .. } => Status::not_found(message),
+        StorageError::EngineStopped | StorageError::ShuttingDown => Status::unavailable(message),
+        StorageError::Cancelled => Status::cancelled(message),
+        StorageError::TimedOut => Status::deadline_exceeded(message),
+        error if error.is_corruption() => Status::data_loss(message),
+        _ => Status::internal(message),
+    }
+}
+
+#[cfg(test)]
+mod unit_tests_grpc {
+    use super::*;
+    use crate::engine::Engine;
+    use crate::storage::Storage;
+    use tokio_stream::StreamExt;
+
+    #[tokio::test]
+    async fn test_block_service() {
+        let tmp_dir = tempfile::tempdir().unwrap();
+        let file_path = tmp_dir.path().join("grpc.hex");
+        let storage = Storage::new(file_path.to_str().unwrap().to_string(), 4).unwrap();
+        let service = BlockService::new(Engine::spawn(storage));
+        let write = |data: Vec<u8>| {
+            Request::new(proto::WriteRecordRequest {
+                data,
+                expires_at: None,
+            })
+        };
+        let record_id = service
+            .write_record(write(vec![1; 6]))
+            .await
+            .unwrap()
+            .into_inner()
+            .record_id;
+        service.write_record(write(vec![2])).await.unwrap();
+        let status = service.write_record(write(vec![])).await.unwrap_err();
+        assert_eq!(status.code(), tonic::Code::InvalidArgument);
+        // - error of a traced rpc names its trace id
+        let mut traced_write = write(vec![]);
+        let trace_id = tonic::metadata::MetadataValue::from_static("rpc-1");
+        traced_write
+            .metadata_mut()
+            .insert(TRACE_ID_METADATA, trace_id);
+        let status = service.write_record(traced_write).await.unwrap_err();
+        assert_eq!(status.code(), tonic::Code::InvalidArgument);
+        assert_eq!(status.message(), "Record data is empty (trace id rpc-1)");
+        let blocks = service
+            .read_blocks(Request::new(proto::ReadBlocksRequest { record_id }))
+            .await
+            .unwrap()
+            .into_inner()
+            .blocks;
+        assert_eq!(blocks, vec![vec![1; 4], vec![1; 2]]);
+        // - pages of two blocks, stream ends after partial last page
+        let stream = service
+            .scan_blocks(Request::new(proto::ScanBlocksRequest {
+                from_block: 0,
+                page_size: 2,
+            }))
+            .await
+            .unwrap()
+            .into_inner();
+        let scanned: Vec<proto::Block> = stream.map(Result::unwrap).collect().await;
+        let block_indexes: Vec<u64> = scanned.iter().map(|block| block.block_index).collect();
+        assert_eq!(block_indexes, vec![0, 1, 2]);
+        assert_eq!(scanned[2].data, vec![2]);
+        let deleted_blocks = service
+            .delete_blocks(Request::new(proto::DeleteBlocksRequest {
+                record_id,
+                hard_delete: false,
+            }))
+            .await
+            .unwrap()
+            .into_inner()
+            .deleted_blocks;
+        assert_eq!(deleted_blocks, 2);
+        let stats = service
+            .stats(Request::new(proto::StatsRequest {}))
+            .await
+            .unwrap()
+            .into_inner();
+        assert_eq!((stats.writes, stats.deletes, stats.errors), (4, 1, 2));
+        // - read and two scan pages
+        assert_eq!(stats.reads, 3);
+    }
+}
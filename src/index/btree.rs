@@ -0,0 +1,810 @@
+use crate::storage::{checked_u32, BlockIndex, RecordId, Storage, StorageError};
+use std::convert::TryFrom;
+use std::marker::PhantomData;
+use std::ops::{Bound, RangeBounds};
+
+/// Key of a BTreeIndex, stored in index nodes as bytes
+/// - keys are ordered by Ord, not by their encoding
+pub trait IndexKey: Ord + Clone {
+    fn encode(&self) -> Vec<u8>;
+    /// Parse key from bytes written by encode, None if bytes are not a valid key
+    fn decode(bytes: &[u8]) -> Option<Self>;
+}
+
+impl IndexKey for u32 {
+    fn encode(&self) -> Vec<u8> {
+        self.to_le_bytes().to_vec()
+    }
+    fn decode(bytes: &[u8]) -> Option<Self> {
+        let mut le_bytes = [0u8; 4];
+        if bytes.len() != le_bytes.len() {
+            return None;
+        }
+        le_bytes.copy_from_slice(bytes);
+        Some(u32::from_le_bytes(le_bytes))
+    }
+}
+
+impl IndexKey for u64 {
+    fn encode(&self) -> Vec<u8> {
+        self.to_le_bytes().to_vec()
+    }
+    fn decode(bytes: &[u8]) -> Option<Self> {
+        let mut le_bytes = [0u8; 8];
+        if bytes.len() != le_bytes.len() {
+            return None;
+        }
+        le_bytes.copy_from_slice(bytes);
+        Some(u64::from_le_bytes(le_bytes))
+    }
+}
+
+impl IndexKey for Vec<u8> {
+    fn encode(&self) -> Vec<u8> {
+        self.clone()
+    }
+    fn decode(bytes: &[u8]) -> Option<Self> {
+        Some(bytes.to_vec())
+    }
+}
+
+impl IndexKey for String {
+    fn encode(&self) -> Vec<u8> {
+        self.as_bytes().to_vec()
+    }
+    fn decode(bytes: &[u8]) -> Option<Self> {
+        String::from_utf8(bytes.to_vec()).ok()
+    }
+}
+
+/// Key followed by a u64, e.g. to store several values under one key
+/// - ordered by key first, encoded as key length (u32), key, then the u64
+impl<K: IndexKey> IndexKey for (K, u64) {
+    fn encode(&self) -> Vec<u8> {
+        let key_bytes = self.0.encode();
+        // - a key too long for its length is longer than the whole encoding, which fails
+        //   with TooLarge when its node is written
+        let key_len = u32::try_from(key_bytes.len()).unwrap_or(u32::MAX);
+        let mut bytes = key_len.to_le_bytes().to_vec();
+        bytes.extend_from_slice(&key_bytes);
+        bytes.extend_from_slice(&self.1.to_le_bytes());
+        bytes
+    }
+    fn decode(bytes: &[u8]) -> Option<Self> {
+        let key_len = usize::try_from(u32::decode(bytes.get(..4)?)?).ok()?;
+        let key_end = key_len.checked_add(4)?;
+        let key = K::decode(bytes.get(4..key_end)?)?;
+        Some((key, u64::decode(bytes.get(key_end..)?)?))
+    }
+}
+
+// ... ... ... ... ... ... ... ... ... Index Nodes ... ... ... ... ... ... ... ... ... ..
+
+const LEAF_NODE: u8 = 0;
+const INTERNAL_NODE: u8 = 1;
+
+/// Node of B+tree, stored as one record
+/// - leaf: keys[i] maps to values[i]
+/// - internal: children[i] holds keys below keys[i], and at or above keys[i - 1]
+enum Node<K> {
+    Leaf {
+        keys: Vec<K>,
+        values: Vec<BlockIndex>,
+    },
+    Internal {
+        keys: Vec<K>,
+        children: Vec<RecordId>,
+    },
+}
+
+impl<K: IndexKey> Node<K> {
+    fn empty_leaf() -> Self {
+        Node::Leaf {
+            keys: Vec::new(),
+            values: Vec::new(),
+        }
+    }
+    fn key_count(&self) -> usize {
+        match self {
+            Node::Leaf { keys, .. } | Node::Internal { keys, .. } => keys.len(),
+        }
+    }
+    /// Node bytes
+    /// - kind (1 byte), key count (u32)
+    /// - leaf: key count times (key length u32, key, value u64)
+    /// - internal: first child (u64), then key count times (key length u32, key, child u64)
+    /// - integers are little endian
+    /// - TooLarge if an encoded key is longer than u32::MAX bytes
+    fn to_bytes(&self) -> Result<Vec<u8>, StorageError> {
+        let mut bytes = Vec::new();
+        let (kind, keys, pointers) = match self {
+            Node::Leaf { keys, values } => (LEAF_NODE, keys, &values[..]),
+            Node::Internal { keys, children } => (INTERNAL_NODE, keys, &children[..]),
+        };
+        bytes.push(kind);
+        bytes.extend_from_slice(&checked_u32(keys.len())?.to_le_bytes());
+        let mut pointers = pointers.iter();
+        if kind == INTERNAL_NODE {
+            if let Some(first_child) = pointers.next() {
+                bytes.extend_from_slice(&first_child.to_le_bytes());
+            }
+        }
+        for (key, pointer) in keys.iter().zip(pointers) {
+            let key_bytes = key.encode();
+            bytes.extend_from_slice(&checked_u32(key_bytes.len())?.to_le_bytes());
+            bytes.extend_from_slice(&key_bytes);
+            bytes.extend_from_slice(&pointer.to_le_bytes());
+        }
+        Ok(bytes)
+    }
+    /// Parse node bytes, None if malformed
+    fn from_bytes(bytes: &[u8]) -> Option<Self> {
+        let mut reader = NodeReader { bytes, offset: 0 };
+        let kind = reader.take(1)?[0];
+        let key_count = usize::try_from(reader.u32()?).ok()?;
+        let mut keys = Vec::new();
+        let mut pointers = Vec::new();
+        if kind == INTERNAL_NODE {
+            pointers.push(reader.u64()?);
+        }
+        for _ in 0..key_count {
+            let key_len = usize::try_from(reader.u32()?).ok()?;
+            keys.push(K::decode(reader.take(key_len)?)?);
+            pointers.push(reader.u64()?);
+        }
+        if reader.offset != bytes.len() {
+            return None;
+        }
+        match kind {
+            LEAF_NODE => Some(Node::Leaf {
+                keys,
+                values: pointers,
+            }),
+            INTERNAL_NODE => Some(Node::Internal {
+                keys,
+                children: pointers,
+            }),
+            _ => None,
+        }
+    }
+}
+
+struct NodeReader<'a> {
+    bytes: &'a [u8],
+    offset: usize,
+}
+
+impl<'a> NodeReader<'a> {
+    fn take(&mut self, len: usize) -> Option<&'a [u8]> {
+        let end = self.offset.checked_add(len)?;
+        let taken = self.bytes.get(self.offset..end)?;
+        self.offset = end;
+        Some(taken)
+    }
+    fn u32(&mut self) -> Option<u32> {
+        u32::decode(self.take(4)?)
+    }
+    fn u64(&mut self) -> Option<u64> {
+        u64::decode(self.take(8)?)
+    }
+}
+
+/// Result of changing a subtree, nodes on the path are rewritten as new records
+enum Change<K> {
+    /// Subtree has a new root node
+    Replaced(RecordId),
+    /// Subtree root was split in two, keys at or above key are in right
+    Split {
+        left: RecordId,
+        key: K,
+        right: RecordId,
+    },
+    /// Subtree holds no keys anymore
+    Emptied,
+}
+
+// ... ... ... ... ... ... ... ... ... BTree Index ... ... ... ... ... ... ... ... ... ..
+
+/// Persistent B+tree mapping keys to block indexes, with nodes stored as records
+/// - anchor block holds record id of root node, it identifies the index across opens
+/// - changes are copy-on-write: new nodes are written, then anchor is rewritten in place,
+///   then old nodes are deleted, so a failed change leaves the previous tree intact
+/// - deleting keys does not merge half empty nodes, only empty nodes are dropped
+/// - every method takes the storage, so several indexes can share one storage file
+pub struct BTreeIndex<K: IndexKey> {
+    anchor: RecordId,
+    max_keys: usize,
+    key: PhantomData<K>,
+}
+
+/// Keys per node if not set with with_max_keys
+pub const DEFAULT_MAX_KEYS: usize = 64;
+
+impl<K: IndexKey> BTreeIndex<K> {
+    /// Create empty index in storage
+    /// - block_len of storage must be at least 8 bytes, to hold the anchor
+    pub fn create(storage: &mut Storage) -> Result<Self, StorageError> {
+        let anchor_bytes = 0u64.to_le_bytes();
+        if storage.block_len() < anchor_bytes.len() as u64 {
+            return Err(StorageError::BlockTooLarge {
+                block_index: 0,
+                data_len: anchor_bytes.len(),
+                block_len: storage.block_len(),
+            });
+        }
+        let root = storage.write_record(&Node::<K>::empty_leaf().to_bytes()?)?;
+        let anchor = storage.write_record(&root.to_le_bytes())?;
+        Ok(BTreeIndex::open(anchor))
+    }
+    /// Open index created earlier, by its anchor block
+    pub fn open(anchor: RecordId) -> Self {
+        BTreeIndex {
+            anchor,
+            max_keys: DEFAULT_MAX_KEYS,
+            key: PhantomData,
+        }
+    }
+    /// Set maximum keys per node, at least 3
+    /// - only affects nodes written from now on
+    pub fn with_max_keys(mut self, max_keys: usize) -> Self {
+        self.max_keys = max_keys.max(3);
+        self
+    }
+    /// Anchor block of index, to open it again with BTreeIndex::open
+    pub fn id(&self) -> RecordId {
+        self.anchor
+    }
+    /// Block index stored for key
+    pub fn get(&self, storage: &mut Storage, key: &K) -> Result<Option<BlockIndex>, StorageError> {
+        let mut node_id = self.root(storage)?;
+        loop {
+            match self.read_node(storage, node_id)? {
+                Node::Leaf { keys, values } => {
+                    return Ok(keys
+                        .binary_search(key)
+                        .ok()
+                        .map(|position| values[position]));
+                }
+                Node::Internal { keys, children } => node_id = children[child_position(&keys, key)],
+            }
+        }
+    }
+    /// Store block index for key
+    /// - returns: block index previously stored for key
+    pub fn insert(
+        &mut self,
+        storage: &mut Storage,
+        key: K,
+        value: BlockIndex,
+    ) -> Result<Option<BlockIndex>, StorageError> {
+        let root = self.root(storage)?;
+        let mut old_nodes = Vec::new();
+        let (new_root, previous) = self.insert_at(storage, root, key, value, &mut old_nodes)?;
+        self.set_root(storage, new_root, old_nodes)?;
+        Ok(previous)
+    }
+    /// Remove key from index
+    /// - returns: block index that was stored for key
+    pub fn remove(
+        &mut self,
+        storage: &mut Storage,
+        key: &K,
+    ) -> Result<Option<BlockIndex>, StorageError> {
+        let root = self.root(storage)?;
+        let mut old_nodes = Vec::new();
+        let (new_root, removed) = self.remove_at(storage, root, key, &mut old_nodes)?;
+        if removed.is_some() {
+            self.set_root(storage, new_root, old_nodes)?;
+        }
+        Ok(removed)
+    }
+    /// Store or remove block indexes of several keys together, in one change of the index
+    /// - changes: key with block index to store, or None to remove key, applied in order
+    /// - anchor is rewritten once, after every change is written, so a failure leaves
+    ///   none of them applied
+    /// - returns: block index previously stored for key of each change, in change order
+    pub fn apply(
+        &mut self,
+        storage: &mut Storage,
+        changes: Vec<(K, Option<BlockIndex>)>,
+    ) -> Result<Vec<Option<BlockIndex>>, StorageError> {
+        let mut root = self.root(storage)?;
+        let mut old_nodes = Vec::new();
+        let mut previous = Vec::with_capacity(changes.len());
+        for (key, value) in changes {
+            // - nodes written by an earlier change are replaced nodes of a later one
+            let (new_root, replaced) = match value {
+                Some(value) => self.insert_at(storage, root, key, value, &mut old_nodes)?,
+                None => self.remove_at(storage, root, &key, &mut old_nodes)?,
+            };
+            root = new_root;
+            previous.push(replaced);
+        }
+        if !old_nodes.is_empty() {
+            self.set_root(storage, root, old_nodes)?;
+        }
+        Ok(previous)
+    }
+    /// Keys within range with their block indexes, in ascending key order
+    pub fn range<R: RangeBounds<K>>(
+        &self,
+        storage: &mut Storage,
+        range: R,
+    ) -> Result<Vec<(K, BlockIndex)>, StorageError> {
+        let mut entries = Vec::new();
+        let root = self.root(storage)?;
+        self.collect_range(storage, root, &range, &mut entries)?;
+        Ok(entries)
+    }
+    /// Delete anchor and every node of index, e.g. when what it indexes is dropped
+    /// - blocks stored for its keys are left alone
+    /// - anchor is deleted first, a failure after it leaves unreachable nodes behind
+    pub fn destroy(self, storage: &mut Storage) -> Result<(), StorageError> {
+        let mut node_ids = vec![self.root(storage)?];
+        let mut position = 0;
+        while let Some(node_id) = node_ids.get(position).cloned() {
+            if let Node::Internal { children, .. } = self.read_node(storage, node_id)? {
+                node_ids.extend(children);
+            }
+            position += 1;
+        }
+        storage.delete_record(self.anchor, false)?;
+        for node_id in node_ids {
+            storage.delete_record(node_id, false)?;
+        }
+        Ok(())
+    }
+
+    // ... ... ... ... ... ... ... ... Tree Operations ... ... ... ... ... ... ... ...
+
+    /// Insert key into tree of root, without pointing anchor at the new root
+    /// - returns: new root and block index previously stored for key
+    fn insert_at(
+        &self,
+        storage: &mut Storage,
+        root: RecordId,
+        key: K,
+        value: BlockIndex,
+        old_nodes: &mut Vec<RecordId>,
+    ) -> Result<(RecordId, Option<BlockIndex>), StorageError> {
+        let mut previous = None;
+        let change = self.insert_into(storage, root, key, value, &mut previous, old_nodes)?;
+        let new_root = match change {
+            Change::Replaced(node_id) => node_id,
+            Change::Split { left, key, right } => {
+                let node = Node::Internal {
+                    keys: vec![key],
+                    children: vec![left, right],
+                };
+                storage.write_record(&node.to_bytes()?)?
+            }
+            Change::Emptied => storage.write_record(&Node::<K>::empty_leaf().to_bytes()?)?,
+        };
+        Ok((new_root, previous))
+    }
+    /// Remove key from tree of root, without pointing anchor at the new root
+    /// - returns: new root, root itself if key is not in tree, and block index that was
+    ///   stored for key
+    fn remove_at(
+        &self,
+        storage: &mut Storage,
+        root: RecordId,
+        key: &K,
+        old_nodes: &mut Vec<RecordId>,
+    ) -> Result<(RecordId, Option<BlockIndex>), StorageError> {
+        match self.remove_from(storage, root, key, old_nodes)? {
+            None => Ok((root, None)),
+            Some((removed, change)) => {
+                let new_root = match change {
+                    Change::Replaced(node_id) => node_id,
+                    Change::Split { .. } | Change::Emptied => {
+                        storage.write_record(&Node::<K>::empty_leaf().to_bytes()?)?
+                    }
+                };
+                Ok((new_root, Some(removed)))
+            }
+        }
+    }
+    fn insert_into(
+        &self,
+        storage: &mut Storage,
+        node_id: RecordId,
+        key: K,
+        value: BlockIndex,
+        previous: &mut Option<BlockIndex>,
+        old_nodes: &mut Vec<RecordId>,
+    ) -> Result<Change<K>, StorageError> {
+        let node = match self.read_node(storage, node_id)? {
+            Node::Leaf {
+                mut keys,
+                mut values,
+            } => {
+                match keys.binary_search(&key) {
+                    Ok(position) => {
+                        *previous = Some(std::mem::replace(&mut values[position], value))
+                    }
+                    Err(position) => {
+                        keys.insert(position, key);
+                        values.insert(position, value);
+                    }
+                }
+                Node::Leaf { keys, values }
+            }
+            Node::Internal {
+                mut keys,
+                mut children,
+            } => {
+                let position = child_position(&keys, &key);
+                match self.insert_into(
+                    storage,
+                    children[position],
+                    key,
+                    value,
+                    previous,
+                    old_nodes,
+                )? {
+                    Change::Replaced(child) => children[position] = child,
+                    Change::Split { left, key, right } => {
+                        children[position] = left;
+                        keys.insert(position, key);
+                        children.insert(position + 1, right);
+                    }
+                    // insert never empties a subtree
+                    Change::Emptied => {}
+                }
+                Node::Internal { keys, children }
+            }
+        };
+        old_nodes.push(node_id);
+        self.write_node(storage, node)
+    }
+    /// Remove key from subtree
+    /// - returns: None if key is not in subtree, nothing is rewritten then
+    fn remove_from(
+        &self,
+        storage: &mut Storage,
+        node_id: RecordId,
+        key: &K,
+        old_nodes: &mut Vec<RecordId>,
+    ) -> Result<Option<(BlockIndex, Change<K>)>, StorageError> {
+        let (removed, node) = match self.read_node(storage, node_id)? {
+            Node::Leaf {
+                mut keys,
+                mut values,
+            } => match keys.binary_search(key) {
+                Err(_) => return Ok(None),
+                Ok(position) => {
+                    keys.remove(position);
+                    (values.remove(position), Node::Leaf { keys, values })
+                }
+            },
+            Node::Internal {
+                mut keys,
+                mut children,
+            } => {
+                let position = child_position(&keys, key);
+                let (removed, change) =
+                    match self.remove_from(storage, children[position], key, old_nodes)? {
+                        None => return Ok(None),
+                        Some(removed) => removed,
+                    };
+                match change {
+                    Change::Replaced(child) => children[position] = child,
+                    // drop empty child with the key separating it from a neighbour
+                    Change::Emptied => {
+                        children.remove(position);
+                        keys.remove(position.saturating_sub(1));
+                    }
+                    // remove never splits a subtree
+                    Change::Split { .. } => {}
+                }
+                // internal node left with a single child is replaced by it
+                if keys.is_empty() {
+                    old_nodes.push(node_id);
+                    return Ok(Some((removed, Change::Replaced(children[0]))));
+                }
+                (removed, Node::Internal { keys, children })
+            }
+        };
+        old_nodes.push(node_id);
+        if node.key_count() == 0 {
+            return Ok(Some((removed, Change::Emptied)));
+        }
+        Ok(Some((removed, self.write_node(storage, node)?)))
+    }
+    fn collect_range<R: RangeBounds<K>>(
+        &self,
+        storage: &mut Storage,
+        node_id: RecordId,
+        range: &R,
+        entries: &mut Vec<(K, BlockIndex)>,
+    ) -> Result<(), StorageError> {
+        match self.read_node(storage, node_id)? {
+            Node::Leaf { keys, values } => {
+                for (key, value) in keys.into_iter().zip(values) {
+                    if range.contains(&key) {
+                        entries.push((key, value));
+                    }
+                }
+            }
+            Node::Internal { keys, children } => {
+                for (position, child) in children.into_iter().enumerate() {
+                    // child holds keys in keys[position - 1]..keys[position]
+                    let below_start = position < keys.len()
+                        && match range.start_bound() {
+                            Bound::Included(start) | Bound::Excluded(start) => {
+                                keys[position] <= *start
+                            }
+                            Bound::Unbounded => false,
+                        };
+                    let above_end = position > 0
+                        && match range.end_bound() {
+                            Bound::Included(end) => keys[position - 1] > *end,
+                            Bound::Excluded(end) => keys[position - 1] >= *end,
+                            Bound::Unbounded => false,
+                        };
+                    if !below_start && !above_end {
+                        self.collect_range(storage, child, range, entries)?;
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+
+    // ... ... ... ... ... ... ... ... Node Storage ... ... ... ... ... ... ... ... ...
+
+    fn root(&self, storage: &mut Storage) -> Result<RecordId, StorageError> {
+        let anchor_bytes = storage.read_record(self.anchor)?;
+        u64::decode(&anchor_bytes)
+            .map(|root| root as RecordId)
+            .ok_or(StorageError::Corruption {
+                block_index: Some(self.anchor),
+                reason: "index anchor is malformed",
+            })
+    }
+    /// Point anchor at new root, then delete replaced nodes
+    fn set_root(
+        &self,
+        storage: &mut Storage,
+        root: RecordId,
+        old_nodes: Vec<RecordId>,
+    ) -> Result<(), StorageError> {
+        storage.write_block(self.anchor, &root.to_le_bytes())?;
+        for node_id in old_nodes {
+            storage.delete_record(node_id, false)?;
+        }
+        Ok(())
+    }
+    fn read_node(&self, storage: &mut Storage, node_id: RecordId) -> Result<Node<K>, StorageError> {
+        let node_bytes = storage.read_record(node_id)?;
+        Node::from_bytes(&node_bytes).ok_or(StorageError::Corruption {
+            block_index: Some(node_id),
+            reason: "index node is malformed",
+        })
+    }
+    /// Write node as new record, split in two if it holds more than max_keys keys
+    fn write_node(&self, storage: &mut Storage, node: Node<K>) -> Result<Change<K>, StorageError> {
+        if node.key_count() <= self.max_keys {
+            return Ok(Change::Replaced(storage.write_record(&node.to_bytes()?)?));
+        }
+        let middle = node.key_count() / 2;
+        let (left, key, right) = match node {
+            Node::Leaf {
+                mut keys,
+                mut values,
+            } => {
+                let right_keys = keys.split_off(middle);
+                let right_values = values.split_off(middle);
+                let key = right_keys[0].clone();
+                let right = Node::Leaf {
+                    keys: right_keys,
+                    values: right_values,
+                };
+                (Node::Leaf { keys, values }, key, right)
+            }
+            Node::Internal {
+                mut keys,
+                mut children,
+            } => {
+                let right_keys = keys.split_off(middle + 1);
+                let right_children = children.split_off(middle + 1);
+                // middle key moves up to parent
+                let key = keys.remove(middle);
+                let right = Node::Internal {
+                    keys: right_keys,
+                    children: right_children,
+                };
+                (Node::Internal { keys, children }, key, right)
+            }
+        };
+        Ok(Change::Split {
+            left: storage.write_record(&left.to_bytes()?)?,
+            key,
+            right: storage.write_record(&right.to_bytes()?)?,
+        })
+    }
+}
+
+/// Position of child of internal node that holds key
+fn child_position<K: Ord>(keys: &[K], key: &K) -> usize {
+    keys.partition_point(|separator| separator <= key)
+}
+
+#[cfg(test)]
+mod unit_tests_btree {
+    use super::*;
+
+    fn new_storage(tmp_dir: &tempfile::TempDir) -> (Storage, String) {
+        let file_path = tmp_dir.path().join("btree.hex");
+        let file_path = file_path.to_str().unwrap().to_string();
+        (Storage::new(file_path.clone(), 16).unwrap(), file_path)
+    }
+    /// keys 0..count in a scattered order
+    fn scattered_keys(count: u64) -> Vec<u64> {
+        (0..count).map(|key| (key * 37) % count).collect()
+    }
+
+    #[test]
+    fn test_node_bytes() {
+        let node = Node::Internal {
+            keys: vec![String::from("b")],
+            children: vec![1, 2],
+        };
+        match Node::<String>::from_bytes(&node.to_bytes().unwrap()).unwrap() {
+            Node::Internal { keys, children } => {
+                assert_eq!(keys, vec![String::from("b")]);
+                assert_eq!(children, vec![1, 2]);
+            }
+            Node::Leaf { .. } => panic!("expected internal node"),
+        }
+        assert!(Node::<u64>::from_bytes(&[LEAF_NODE, 1, 0, 0, 0]).is_none());
+    }
+    #[test]
+    fn test_insert_and_get() {
+        let tmp_dir = tempfile::tempdir().unwrap();
+        let (mut storage, file_path) = new_storage(&tmp_dir);
+        let mut index = BTreeIndex::<u64>::create(&mut storage)
+            .unwrap()
+            .with_max_keys(4);
+        for key in scattered_keys(100) {
+            assert_eq!(index.insert(&mut storage, key, key * 2).unwrap(), None);
+        }
+        assert_eq!(index.insert(&mut storage, 7, 1).unwrap(), Some(14));
+        assert_eq!(index.get(&mut storage, &7).unwrap(), Some(1));
+        assert_eq!(index.get(&mut storage, &100).unwrap(), None);
+        // index survives reopening the storage file
+        let index_id = index.id();
+        storage.close().unwrap();
+        let mut storage = Storage::open(file_path).unwrap();
+        let index = BTreeIndex::<u64>::open(index_id);
+        for key in 0..100 {
+            let expected = if key == 7 { 1 } else { key * 2 };
+            assert_eq!(index.get(&mut storage, &key).unwrap(), Some(expected));
+        }
+    }
+    #[test]
+    fn test_range() {
+        let tmp_dir = tempfile::tempdir().unwrap();
+        let (mut storage, _) = new_storage(&tmp_dir);
+        let mut index = BTreeIndex::<u64>::create(&mut storage)
+            .unwrap()
+            .with_max_keys(3);
+        for key in scattered_keys(50) {
+            index.insert(&mut storage, key, key).unwrap();
+        }
+        let keys = |entries: Vec<(u64, RecordId)>| -> Vec<u64> {
+            entries.into_iter().map(|(key, _)| key).collect()
+        };
+        let entries = index.range(&mut storage, 10..15).unwrap();
+        assert_eq!(keys(entries), (10..15).collect::<Vec<u64>>());
+        let entries = index.range(&mut storage, 45..=60).unwrap();
+        assert_eq!(keys(entries), (45..50).collect::<Vec<u64>>());
+        let entries = index.range(&mut storage, ..).unwrap();
+        assert_eq!(keys(entries), (0..50).collect::<Vec<u64>>());
+        let entries = index
+            .range(&mut storage, (Bound::Excluded(20), Bound::Included(22)))
+            .unwrap();
+        assert_eq!(keys(entries), vec![21, 22]);
+    }
+    #[test]
+    fn test_remove() {
+        let tmp_dir = tempfile::tempdir().unwrap();
+        let (mut storage, _) = new_storage(&tmp_dir);
+        let mut index = BTreeIndex::<String>::create(&mut storage)
+            .unwrap()
+            .with_max_keys(3);
+        for key in scattered_keys(40) {
+            index
+                .insert(&mut storage, format!("{:02}", key), key)
+                .unwrap();
+        }
+        for key in (0..40).filter(|key| key % 3 != 0) {
+            let removed = index.remove(&mut storage, &format!("{:02}", key)).unwrap();
+            assert_eq!(removed, Some(key));
+        }
+        assert_eq!(
+            index.remove(&mut storage, &String::from("01")).unwrap(),
+            None
+        );
+        let entries = index.range(&mut storage, ..).unwrap();
+        let values: Vec<RecordId> = entries.into_iter().map(|(_, value)| value).collect();
+        assert_eq!(
+            values,
+            (0..40)
+                .filter(|key| key % 3 == 0)
+                .collect::<Vec<RecordId>>()
+        );
+        // emptied index is usable again
+        for key in (0..40).filter(|key| key % 3 == 0) {
+            index.remove(&mut storage, &format!("{:02}", key)).unwrap();
+        }
+        assert!(index.range(&mut storage, ..).unwrap().is_empty());
+        index.insert(&mut storage, String::from("a"), 1).unwrap();
+        assert_eq!(
+            index.get(&mut storage, &String::from("a")).unwrap(),
+            Some(1)
+        );
+    }
+    #[test]
+    fn test_apply() {
+        let tmp_dir = tempfile::tempdir().unwrap();
+        let (mut storage, _) = new_storage(&tmp_dir);
+        let mut index = BTreeIndex::<u64>::create(&mut storage)
+            .unwrap()
+            .with_max_keys(3);
+        let mut changes: Vec<(u64, Option<BlockIndex>)> = scattered_keys(30)
+            .into_iter()
+            .map(|key| (key, Some(key)))
+            .collect();
+        changes.push((4, Some(40)));
+        changes.push((5, None));
+        changes.push((99, None));
+        let previous = index.apply(&mut storage, changes).unwrap();
+        assert_eq!(previous[30..], [Some(4), Some(5), None]);
+        assert!(previous[..30].iter().all(Option::is_none));
+        assert_eq!(index.get(&mut storage, &4).unwrap(), Some(40));
+        assert_eq!(index.get(&mut storage, &5).unwrap(), None);
+        assert_eq!(index.range(&mut storage, ..).unwrap().len(), 29);
+        // - nodes replaced within the change are deleted, like after single inserts
+        let used_blocks = storage.iter_blocks().count();
+        let sequential_dir = tempfile::tempdir().unwrap();
+        let (mut sequential_storage, _) = new_storage(&sequential_dir);
+        let mut sequential = BTreeIndex::<u64>::create(&mut sequential_storage)
+            .unwrap()
+            .with_max_keys(3);
+        for key in scattered_keys(30) {
+            sequential
+                .insert(&mut sequential_storage, key, key)
+                .unwrap();
+        }
+        sequential.insert(&mut sequential_storage, 4, 40).unwrap();
+        sequential.remove(&mut sequential_storage, &5).unwrap();
+        assert_eq!(sequential_storage.iter_blocks().count(), used_blocks);
+        // - changes that change nothing leave the anchor alone
+        assert_eq!(index.apply(&mut storage, vec![(99, None)]).unwrap(), [None]);
+        assert_eq!(storage.iter_blocks().count(), used_blocks);
+    }
+    #[test]
+    fn test_destroy() {
+        let tmp_dir = tempfile::tempdir().unwrap();
+        let (mut storage, _) = new_storage(&tmp_dir);
+        storage.write_record(&[1; 20]).unwrap();
+        let mut index = BTreeIndex::<u64>::create(&mut storage)
+            .unwrap()
+            .with_max_keys(3);
+        for key in scattered_keys(30) {
+            index.insert(&mut storage, key, 0).unwrap();
+        }
+        index.destroy(&mut storage).unwrap();
+        // - only the record the keys pointed at is left
+        assert_eq!(storage.iter_blocks().count(), 2);
+    }
+    #[test]
+    fn test_small_blocks_rejected() {
+        let tmp_dir = tempfile::tempdir().unwrap();
+        let file_path = tmp_dir.path().join("btree.hex");
+        let mut storage = Storage::new(file_path.to_str().unwrap().to_string(), 4).unwrap();
+        assert!(BTreeIndex::<u64>::create(&mut storage).is_err());
+    }
+}
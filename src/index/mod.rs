@@ -0,0 +1,6 @@
+//! Indexes mapping logical keys to block indexes, stored in storage blocks
+
+pub mod btree;
+pub use btree::{BTreeIndex, IndexKey};
+pub mod secondary;
+pub use secondary::{RecordIndex, SecondaryIndex};
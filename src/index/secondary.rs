@@ -0,0 +1,244 @@
+use super::{BTreeIndex, IndexKey};
+use crate::record::{Record, RecordId, RecordStore};
+use crate::storage::{Storage, StorageError};
+use std::ops::{Bound, RangeBounds};
+
+/// Index kept up to date by the RecordStore methods taking indexes, e.g.
+/// RecordStore::insert_indexed
+pub trait RecordIndex {
+    /// Move record id from the entries of old record to those of new record
+    /// - old: None for an inserted record, new: None for a removed record
+    /// - a failed update must leave the index as it was
+    fn update(
+        &mut self,
+        storage: &mut Storage,
+        id: RecordId,
+        old: Option<&Record>,
+        new: Option<&Record>,
+    ) -> Result<(), StorageError>;
+}
+
+/// Function extracting the key a record is indexed under, None to leave record out
+pub type KeyExtractor<K> = Box<dyn Fn(&Record) -> Option<K> + Send + Sync>;
+
+/// Persistent index from a key extracted from records to the ids of records of a
+/// RecordStore
+/// - stored as a BTreeIndex of (key, record id), several records can have one key
+/// - only updated through RecordStore methods taking indexes, records written through
+///   other methods are not in the index
+pub struct SecondaryIndex<K: IndexKey> {
+    index: BTreeIndex<(K, RecordId)>,
+    extract: KeyExtractor<K>,
+}
+
+impl<K: IndexKey> SecondaryIndex<K> {
+    /// Create index in storage, holding every record already in store
+    pub fn create(
+        storage: &mut Storage,
+        store: &RecordStore,
+        extract: KeyExtractor<K>,
+    ) -> Result<Self, StorageError> {
+        let mut secondary_index = SecondaryIndex {
+            index: BTreeIndex::create(storage)?,
+            extract,
+        };
+        for id in store.ids(storage)? {
+            if let Some(record) = store.get(storage, id)? {
+                secondary_index.update(storage, id, None, Some(&record))?;
+            }
+        }
+        Ok(secondary_index)
+    }
+    /// Open index created earlier, by its anchor block
+    /// - extract must extract the keys the index was created with
+    pub fn open(anchor: crate::storage::RecordId, extract: KeyExtractor<K>) -> Self {
+        SecondaryIndex {
+            index: BTreeIndex::open(anchor),
+            extract,
+        }
+    }
+    /// Anchor block of index, to open it again with SecondaryIndex::open
+    pub fn id(&self) -> crate::storage::RecordId {
+        self.index.id()
+    }
+    /// Ids of records indexed under key, in ascending order
+    pub fn get(&self, storage: &mut Storage, key: &K) -> Result<Vec<RecordId>, StorageError> {
+        let entries = self.index.range(
+            storage,
+            (key.clone(), RecordId::MIN)..=(key.clone(), RecordId::MAX),
+        )?;
+        Ok(entries.into_iter().map(|((_, id), _)| id).collect())
+    }
+    /// Keys within range with ids of their records, in ascending key order, then id order
+    pub fn range<R: RangeBounds<K>>(
+        &self,
+        storage: &mut Storage,
+        range: R,
+    ) -> Result<Vec<(K, RecordId)>, StorageError> {
+        // - (key, id) range covering every id of keys within range
+        let start = match range.start_bound() {
+            Bound::Included(start) => Bound::Included((start.clone(), RecordId::MIN)),
+            Bound::Excluded(start) => Bound::Excluded((start.clone(), RecordId::MAX)),
+            Bound::Unbounded => Bound::Unbounded,
+        };
+        let end = match range.end_bound() {
+            Bound::Included(end) => Bound::Included((end.clone(), RecordId::MAX)),
+            Bound::Excluded(end) => Bound::Excluded((end.clone(), RecordId::MIN)),
+            Bound::Unbounded => Bound::Unbounded,
+        };
+        let entries = self.index.range(storage, (start, end))?;
+        Ok(entries.into_iter().map(|(key, _)| key).collect())
+    }
+}
+
+impl<K: IndexKey> RecordIndex for SecondaryIndex<K> {
+    fn update(
+        &mut self,
+        storage: &mut Storage,
+        id: RecordId,
+        old: Option<&Record>,
+        new: Option<&Record>,
+    ) -> Result<(), StorageError> {
+        let old_key = old.and_then(|record| (self.extract)(record));
+        let new_key = new.and_then(|record| (self.extract)(record));
+        if old_key == new_key {
+            return Ok(());
+        }
+        if let Some(old_key) = old_key.clone() {
+            self.index.remove(storage, &(old_key, id))?;
+        }
+        if let Some(new_key) = new_key {
+            if let Err(error) = self.index.insert(storage, (new_key, id), id) {
+                if let Some(old_key) = old_key {
+                    // failure to undo leaves the record out of the index, report the
+                    // original error
+                    let _ = self.index.insert(storage, (old_key, id), id);
+                }
+                return Err(error);
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod unit_tests_secondary {
+    use super::*;
+
+    fn new_storage(tmp_dir: &tempfile::TempDir) -> Storage {
+        let file_path = tmp_dir.path().join("secondary.hex");
+        Storage::new(file_path.to_str().unwrap().to_string(), 16).unwrap()
+    }
+    /// records indexed by their metadata city
+    fn by_city() -> KeyExtractor<String> {
+        Box::new(|record: &Record| record.metadata.get("city").cloned())
+    }
+    fn person(name: &str, city: &str) -> Record {
+        Record::new(name.as_bytes().to_vec()).with_metadata("city", city)
+    }
+
+    #[test]
+    fn test_key_with_id_bytes() {
+        let key = (String::from("oslo"), 7u64);
+        assert_eq!(<(String, u64)>::decode(&key.encode()), Some(key));
+        assert!(<(String, u64)>::decode(&[9, 0, 0, 0, b'a']).is_none());
+    }
+    #[test]
+    fn test_secondary_index() {
+        let tmp_dir = tempfile::tempdir().unwrap();
+        let mut storage = new_storage(&tmp_dir);
+        let mut store = RecordStore::create(&mut storage).unwrap();
+        let ann = store.insert(&mut storage, &person("ann", "oslo")).unwrap();
+        // - existing records are indexed on create
+        let mut cities = SecondaryIndex::create(&mut storage, &store, by_city()).unwrap();
+        let bob = store
+            .insert_indexed(&mut storage, &person("bob", "lima"), &mut [&mut cities])
+            .unwrap();
+        let cat = store
+            .insert_indexed(&mut storage, &person("cat", "oslo"), &mut [&mut cities])
+            .unwrap();
+        // - record without key is left out
+        store
+            .insert_indexed(&mut storage, &Record::new(vec![1]), &mut [&mut cities])
+            .unwrap();
+        let oslo = String::from("oslo");
+        assert_eq!(cities.get(&mut storage, &oslo).unwrap(), vec![ann, cat]);
+        // - update moves record to its new key
+        store
+            .update_indexed(
+                &mut storage,
+                ann,
+                &person("ann", "rome"),
+                &mut [&mut cities],
+            )
+            .unwrap();
+        assert_eq!(cities.get(&mut storage, &oslo).unwrap(), vec![cat]);
+        assert_eq!(
+            cities
+                .range(&mut storage, String::from("lima")..String::from("rome"))
+                .unwrap(),
+            vec![(String::from("lima"), bob), (oslo.clone(), cat)]
+        );
+        store
+            .remove_indexed(&mut storage, cat, &mut [&mut cities])
+            .unwrap();
+        assert!(cities.get(&mut storage, &oslo).unwrap().is_empty());
+        // - index opens again by its anchor
+        let cities = SecondaryIndex::open(cities.id(), by_city());
+        assert_eq!(
+            cities.range(&mut storage, ..).unwrap(),
+            vec![(String::from("lima"), bob), (String::from("rome"), ann)]
+        );
+    }
+    #[test]
+    fn test_failed_index_update_is_undone() {
+        let tmp_dir = tempfile::tempdir().unwrap();
+        let mut storage = new_storage(&tmp_dir);
+        let mut store = RecordStore::create(&mut storage).unwrap();
+        let mut cities = SecondaryIndex::create(&mut storage, &store, by_city()).unwrap();
+        let ann = store
+            .insert_indexed(&mut storage, &person("ann", "oslo"), &mut [&mut cities])
+            .unwrap();
+        // - second index fails, first index and record are left as they were
+        let mut failing = FailingIndex;
+        assert!(store
+            .update_indexed(
+                &mut storage,
+                ann,
+                &person("ann", "rome"),
+                &mut [&mut cities, &mut failing]
+            )
+            .is_err());
+        assert_eq!(
+            store.get(&mut storage, ann).unwrap(),
+            Some(person("ann", "oslo"))
+        );
+        assert_eq!(
+            cities.get(&mut storage, &String::from("oslo")).unwrap(),
+            vec![ann]
+        );
+        assert!(cities
+            .get(&mut storage, &String::from("rome"))
+            .unwrap()
+            .is_empty());
+    }
+
+    /// fails every update of a record that is not removed
+    struct FailingIndex;
+    impl RecordIndex for FailingIndex {
+        fn update(
+            &mut self,
+            _storage: &mut Storage,
+            _id: RecordId,
+            _old: Option<&Record>,
+            new: Option<&Record>,
+        ) -> Result<(), StorageError> {
+            match new {
+                Some(_) => Err(StorageError::Transform {
+                    message: String::from("index is failing"),
+                }),
+                None => Ok(()),
+            }
+        }
+    }
+}
@@ -0,0 +1,715 @@
+//! Column families, key value stores sharing one storage file, each with its own key
+//! space and options
+use super::*;
+use crate::storage::Compression;
+use std::convert::TryFrom;
+
+/// Options of a column family, fixed when it is created, see ColumnFamilies::create_cf
+/// - families share the storage and its block cache, see Storage::enable_cache, a family
+///   created with no_cache stays out of the cache
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct ColumnFamilyOptions {
+    /// Codec of values, None for the compression of the storage, see
+    /// Storage::set_compression
+    pub(super) compression: Option<Compression>,
+    /// Seconds a value is kept after it was written, None to keep it until replaced
+    pub(super) ttl: Option<u64>,
+    /// Blocks of the family bypass the block cache, see no_cache
+    pub(super) no_cache: bool,
+}
+
+impl ColumnFamilyOptions {
+    /// Values compressed like the rest of the storage, kept until replaced
+    pub fn new() -> Self {
+        ColumnFamilyOptions::default()
+    }
+    /// Compress values with compression, whatever the setting of the storage
+    pub fn compression(mut self, compression: Compression) -> Self {
+        self.compression = Some(compression);
+        self
+    }
+    /// Expire values ttl seconds after they were written
+    /// - expired values read as missing, ColumnFamilies::sweep_expired removes them
+    pub fn ttl(mut self, ttl: u64) -> Self {
+        self.ttl = Some(ttl);
+        self
+    }
+    /// Leave the block cache to the rest of the storage, e.g. for a family of bulk data
+    /// whose writes would evict hot blocks of read_block
+    /// - the cache is write-through, blocks written by other families are cached, blocks
+    ///   written by this family are not
+    /// - the cache is shared whole or not at all, a family cannot be given a part of it,
+    ///   as the cache holds blocks by index, whatever family they are of
+    pub fn no_cache(mut self) -> Self {
+        self.no_cache = true;
+        self
+    }
+}
+
+/// Key and block usage of a column family, see ColumnFamilies::stats
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ColumnFamilyStats {
+    pub name: String,
+    /// Keys in the family, also expired keys not swept yet
+    pub keys: u64,
+    /// Blocks holding values of the keys, blocks of the index are not counted
+    pub value_blocks: u64,
+    /// Writes fail with Frozen, see ColumnFamilies::freeze_cf
+    pub frozen: bool,
+}
+
+// ... ... ... ... ... ... ... ... ... Family Descriptor ... ... ... ... ... ... ... ... ...
+
+const DESCRIPTOR_SIZE: usize = 18;
+const HAS_COMPRESSION: u8 = 1;
+const HAS_TTL: u8 = 2;
+const NO_CACHE: u8 = 4;
+
+/// Descriptor record of a family, in the catalog under its name
+/// - index anchor (u64), option flags (1 byte), compression byte (1 byte), ttl (u64)
+/// - option flags tell which options are set, bit 0 compression, bit 1 ttl, bit 2
+///   no_cache
+/// - integers are little endian
+fn descriptor_bytes(anchor: RecordId, options: &ColumnFamilyOptions) -> Vec<u8> {
+    let mut bytes = Vec::with_capacity(DESCRIPTOR_SIZE);
+    bytes.extend_from_slice(&anchor.to_le_bytes());
+    let mut flags = 0;
+    if options.compression.is_some() {
+        flags |= HAS_COMPRESSION;
+    }
+    if options.ttl.is_some() {
+        flags |= HAS_TTL;
+    }
+    if options.no_cache {
+        flags |= NO_CACHE;
+    }
+    bytes.push(flags);
+    bytes.push(options.compression.unwrap_or_default().to_byte());
+    bytes.extend_from_slice(&options.ttl.unwrap_or(0).to_le_bytes());
+    bytes
+}
+
+/// Index anchor and options of descriptor bytes, None if malformed
+fn parse_descriptor(bytes: &[u8]) -> Option<(RecordId, ColumnFamilyOptions)> {
+    if bytes.len() != DESCRIPTOR_SIZE {
+        return None;
+    }
+    let anchor = u64::from_le_bytes(<[u8; 8]>::try_from(&bytes[..8]).ok()?);
+    let flags = bytes[8];
+    let compression = match flags & HAS_COMPRESSION {
+        0 => None,
+        _ => Some(Compression::from_byte(bytes[9])?),
+    };
+    let ttl = match flags & HAS_TTL {
+        0 => None,
+        _ => Some(u64::from_le_bytes(<[u8; 8]>::try_from(&bytes[10..]).ok()?)),
+    };
+    let options = ColumnFamilyOptions {
+        compression,
+        ttl,
+        no_cache: flags & NO_CACHE != 0,
+    };
+    Some((anchor, options))
+}
+
+/// Run operation on storage, with its block cache bypassed if no_cache, see
+/// ColumnFamilyOptions::no_cache
+fn cached_unless<T>(
+    storage: &mut Storage,
+    no_cache: bool,
+    operation: impl FnOnce(&mut Storage) -> Result<T, StorageError>,
+) -> Result<T, StorageError> {
+    if !no_cache {
+        return operation(storage);
+    }
+    let bypass = storage.set_cache_bypass(true);
+    let result = operation(storage);
+    storage.set_cache_bypass(bypass);
+    result
+}
+
+// ... ... ... ... ... ... ... ... ... Column Families ... ... ... ... ... ... ... ... ...
+
+/// Family of ColumnFamilies, its key space and the record describing it
+struct Family {
+    descriptor: RecordId,
+    key_space: KeySpace,
+    /// Writes fail with Frozen, see ColumnFamilies::freeze_cf
+    frozen: bool,
+}
+
+/// Named key value stores in one storage file, each with its own key space and
+/// options, see ColumnFamilyOptions
+/// - every family is a BTreeKvStore of its own, addressed by name with cf, e.g.
+///   `families.cf("index").put(key, value)`
+/// - a catalog index maps names to families, it identifies the store across opens
+/// - a batch applies within one family, see KvStore::write
+/// - merge operators are not stored, set them with set_merge_operator after every open
+pub struct ColumnFamilies {
+    storage: Storage,
+    catalog: BTreeIndex<String>,
+    families: HashMap<String, Family>,
+}
+
+impl ColumnFamilies {
+    /// Create store without families in storage
+    pub fn create(mut storage: Storage) -> Result<Self, StorageError> {
+        let catalog = BTreeIndex::create(&mut storage)?;
+        Ok(ColumnFamilies {
+            storage,
+            catalog,
+            families: HashMap::new(),
+        })
+    }
+    /// Open store created earlier, by its anchor block, reading the catalog of families
+    pub fn open(mut storage: Storage, anchor: RecordId) -> Result<Self, StorageError> {
+        let catalog = BTreeIndex::open(anchor);
+        let mut families = HashMap::new();
+        for (name, descriptor) in catalog.range(&mut storage, ..)? {
+            let descriptor_bytes = storage.read_record(descriptor)?;
+            let (index_anchor, options) =
+                parse_descriptor(&descriptor_bytes).ok_or(StorageError::Corruption {
+                    block_index: Some(descriptor),
+                    reason: "column family descriptor is malformed",
+                })?;
+            let key_space = KeySpace::new(BTreeIndex::open(index_anchor), options);
+            families.insert(
+                name,
+                Family {
+                    descriptor,
+                    key_space,
+                    frozen: false,
+                },
+            );
+        }
+        Ok(ColumnFamilies {
+            storage,
+            catalog,
+            families,
+        })
+    }
+    /// Anchor block of store, to open it again with ColumnFamilies::open
+    pub fn id(&self) -> RecordId {
+        self.catalog.id()
+    }
+    /// Create empty family name with options
+    /// - a family that exists keeps the options it was created with
+    /// - CompressionUnavailable if the codec of options is not compiled in
+    /// - returns: true if the family was created, false if it existed
+    pub fn create_cf(
+        &mut self,
+        name: &str,
+        options: ColumnFamilyOptions,
+    ) -> Result<bool, StorageError> {
+        if self.families.contains_key(name) {
+            return Ok(false);
+        }
+        if let Some(compression) = options.compression {
+            if !compression.is_available() {
+                return Err(StorageError::CompressionUnavailable { compression });
+            }
+        }
+        let index = cached_unless(&mut self.storage, options.no_cache, BTreeIndex::create)?;
+        let descriptor = match self
+            .storage
+            .write_record(&descriptor_bytes(index.id(), &options))
+        {
+            Ok(descriptor) => descriptor,
+            Err(error) => {
+                // failure to undo leaves an unreachable index, report the original error
+                let _ = index.destroy(&mut self.storage);
+                return Err(error);
+            }
+        };
+        if let Err(error) = self
+            .catalog
+            .insert(&mut self.storage, name.to_string(), descriptor)
+        {
+            let _ = self.storage.delete_record(descriptor, false);
+            let _ = index.destroy(&mut self.storage);
+            return Err(error);
+        }
+        self.families.insert(
+            name.to_string(),
+            Family {
+                descriptor,
+                key_space: KeySpace::new(index, options),
+                frozen: false,
+            },
+        );
+        Ok(true)
+    }
+    /// Remove family name with all its keys and values
+    /// - the family is gone once it is out of the catalog, a failure deleting its
+    ///   blocks after that leaves them unreachable
+    /// - returns: true if the family existed
+    /// - Frozen if writes to the family are frozen
+    pub fn drop_cf(&mut self, name: &str) -> Result<bool, StorageError> {
+        let family = match self.families.remove(name) {
+            Some(family) if family.frozen => {
+                self.families.insert(name.to_string(), family);
+                return Err(StorageError::Frozen {
+                    name: name.to_string(),
+                });
+            }
+            Some(family) => family,
+            None => return Ok(false),
+        };
+        if let Err(error) = self.catalog.remove(&mut self.storage, &name.to_string()) {
+            self.families.insert(name.to_string(), family);
+            return Err(error);
+        }
+        self.storage.delete_record(family.descriptor, false)?;
+        family.key_space.destroy(&mut self.storage)?;
+        Ok(true)
+    }
+    /// Names of all families, in ascending order
+    pub fn cf_names(&self) -> Vec<&str> {
+        let mut names: Vec<&str> = self.families.keys().map(String::as_str).collect();
+        names.sort_unstable();
+        names
+    }
+    /// Options family name was created with, None if there is no such family
+    pub fn cf_options(&self, name: &str) -> Option<ColumnFamilyOptions> {
+        self.families
+            .get(name)
+            .map(|family| family.key_space.options)
+    }
+    /// Make puts, deletes, batches and merges of family name fail with Frozen, until
+    /// thaw_cf, while other families carry on, e.g. during an export of the family
+    /// - reads of the family are served as before
+    /// - the freeze is not stored, the family is writable again after an open
+    /// - UnknownColumnFamily if there is no such family
+    pub fn freeze_cf(&mut self, name: &str) -> Result<(), StorageError> {
+        self.set_frozen(name, true)
+    }
+    /// Let writes to family name through again, see freeze_cf
+    /// - UnknownColumnFamily if there is no such family
+    pub fn thaw_cf(&mut self, name: &str) -> Result<(), StorageError> {
+        self.set_frozen(name, false)
+    }
+    /// Check if writes to family name are frozen, false if there is no such family
+    pub fn is_frozen(&self, name: &str) -> bool {
+        self.families.get(name).is_some_and(|family| family.frozen)
+    }
+    fn set_frozen(&mut self, name: &str, frozen: bool) -> Result<(), StorageError> {
+        match self.families.get_mut(name) {
+            Some(family) => {
+                family.frozen = frozen;
+                Ok(())
+            }
+            None => Err(StorageError::UnknownColumnFamily {
+                name: name.to_string(),
+            }),
+        }
+    }
+    /// Family name, to use as a KvStore
+    /// - every operation of a family that does not exist fails with UnknownColumnFamily
+    /// - writes to a frozen family fail with Frozen, see freeze_cf
+    pub fn cf(&mut self, name: &str) -> ColumnFamily<'_> {
+        let family = self.families.get_mut(name);
+        ColumnFamily {
+            name: name.to_string(),
+            storage: &mut self.storage,
+            frozen: family.as_ref().is_some_and(|family| family.frozen),
+            key_space: family.map(|family| &mut family.key_space),
+        }
+    }
+    /// Set function folding merge operands into values of family name, see
+    /// KvStore::merge
+    /// - UnknownColumnFamily if there is no such family
+    pub fn set_merge_operator(
+        &mut self,
+        name: &str,
+        merge_operator: MergeOperator,
+    ) -> Result<(), StorageError> {
+        match self.families.get_mut(name) {
+            Some(family) => {
+                family.key_space.merge_operator = Some(merge_operator);
+                Ok(())
+            }
+            None => Err(StorageError::UnknownColumnFamily {
+                name: name.to_string(),
+            }),
+        }
+    }
+    /// Remove keys of families with a TTL whose value expired at or before now, and
+    /// delete their values
+    /// - now: seconds since UNIX epoch
+    /// - use it instead of Storage::sweep_expired, which would delete values keys still
+    ///   point at
+    /// - frozen families are skipped, their expired keys are removed by a sweep after
+    ///   thaw_cf
+    /// - returns: number of keys removed
+    pub fn sweep_expired(&mut self, now: u64) -> Result<usize, StorageError> {
+        let mut removed = 0;
+        for family in self.families.values_mut().filter(|family| !family.frozen) {
+            let key_space = &mut family.key_space;
+            removed += cached_unless(&mut self.storage, key_space.options.no_cache, |storage| {
+                key_space.sweep_expired(storage, now)
+            })?;
+        }
+        Ok(removed)
+    }
+    /// Keys and value blocks of every family, in name order, e.g. to attribute usage of
+    /// the shared storage to tenants
+    /// - walks the index of every family and reads the block headers of every value, for
+    ///   block usage of the whole storage see BlockStore::stats
+    pub fn stats(&mut self) -> Result<Vec<ColumnFamilyStats>, StorageError> {
+        let mut stats = Vec::with_capacity(self.families.len());
+        for (name, family) in self.families.iter() {
+            let key_space = &family.key_space;
+            let (keys, value_blocks) =
+                cached_unless(&mut self.storage, key_space.options.no_cache, |storage| {
+                    key_space.usage(storage)
+                })?;
+            stats.push(ColumnFamilyStats {
+                name: name.clone(),
+                keys,
+                value_blocks,
+                frozen: family.frozen,
+            });
+        }
+        stats.sort_by(|stat, other| stat.name.cmp(&other.name));
+        Ok(stats)
+    }
+    /// Stats of every family in Prometheus text exposition format, names prefixed with
+    /// se1_cf_, see stats
+    #[cfg(feature = "prometheus")]
+    pub fn to_prometheus(&mut self) -> Result<String, StorageError> {
+        let stats = self.stats()?;
+        type FamilyGauge = (&'static str, &'static str, fn(&ColumnFamilyStats) -> u64);
+        let gauges: [FamilyGauge; 2] = [
+            ("se1_cf_keys", "Keys in a column family.", |stats| {
+                stats.keys
+            }),
+            (
+                "se1_cf_value_blocks",
+                "Blocks holding values of a column family.",
+                |stats| stats.value_blocks,
+            ),
+        ];
+        let mut text = String::new();
+        for (name, help, value) in gauges.iter() {
+            text.push_str(&format!(
+                "# HELP {} {}\n# TYPE {} gauge\n",
+                name, help, name
+            ));
+            for family in stats.iter() {
+                text.push_str(&format!(
+                    "{}{{family=\"{}\"}} {}\n",
+                    name,
+                    crate::engine::metrics::escape_label(&family.name),
+                    value(family)
+                ));
+            }
+        }
+        Ok(text)
+    }
+    /// Storage holding the store
+    pub fn into_storage(self) -> Storage {
+        self.storage
+    }
+}
+
+/// Column family of ColumnFamilies, borrowed to put, get and delete its keys, see
+/// ColumnFamilies::cf
+pub struct ColumnFamily<'a> {
+    name: String,
+    storage: &'a mut Storage,
+    key_space: Option<&'a mut KeySpace>,
+    /// Writes fail with Frozen, see ColumnFamilies::freeze_cf
+    frozen: bool,
+}
+
+impl ColumnFamily<'_> {
+    /// Storage and key space of family to write to, Frozen if writes are frozen
+    fn writable_key_space(&mut self) -> Result<(&mut Storage, &mut KeySpace), StorageError> {
+        if self.frozen {
+            return Err(StorageError::Frozen {
+                name: self.name.clone(),
+            });
+        }
+        self.key_space()
+    }
+    /// Storage and key space of family, UnknownColumnFamily if it does not exist
+    fn key_space(&mut self) -> Result<(&mut Storage, &mut KeySpace), StorageError> {
+        match self.key_space.as_deref_mut() {
+            Some(key_space) => Ok((&mut *self.storage, key_space)),
+            None => Err(StorageError::UnknownColumnFamily {
+                name: self.name.clone(),
+            }),
+        }
+    }
+}
+
+impl KvStore for ColumnFamily<'_> {
+    fn put(&mut self, key: &[u8], value: &[u8]) -> Result<(), StorageError> {
+        let (storage, key_space) = self.writable_key_space()?;
+        cached_unless(storage, key_space.options.no_cache, |storage| {
+            key_space.put(storage, key, value)
+        })
+    }
+    fn get(&mut self, key: &[u8]) -> Result<Option<Vec<u8>>, StorageError> {
+        let (storage, key_space) = self.key_space()?;
+        cached_unless(storage, key_space.options.no_cache, |storage| {
+            key_space.get(storage, key)
+        })
+    }
+    fn delete(&mut self, key: &[u8]) -> Result<(), StorageError> {
+        let (storage, key_space) = self.writable_key_space()?;
+        cached_unless(storage, key_space.options.no_cache, |storage| {
+            key_space.delete(storage, key)
+        })
+    }
+    fn write(&mut self, batch: WriteBatch) -> Result<(), StorageError> {
+        let (storage, key_space) = self.writable_key_space()?;
+        cached_unless(storage, key_space.options.no_cache, |storage| {
+            key_space.write(storage, batch)
+        })
+    }
+    fn merge(&mut self, key: &[u8], operand: &[u8]) -> Result<(), StorageError> {
+        let (storage, key_space) = self.writable_key_space()?;
+        cached_unless(storage, key_space.options.no_cache, |storage| {
+            key_space.merge(storage, key, operand)
+        })
+    }
+}
+
+#[cfg(test)]
+mod unit_tests_family {
+    use super::*;
+
+    fn new_storage(tmp_dir: &tempfile::TempDir) -> (Storage, String) {
+        let file_path = tmp_dir.path().join("family.hex");
+        let file_path = file_path.to_str().unwrap().to_string();
+        (Storage::new(file_path.clone(), 32).unwrap(), file_path)
+    }
+
+    #[test]
+    fn test_descriptor_bytes() {
+        let options = ColumnFamilyOptions::new().ttl(0);
+        let bytes = descriptor_bytes(7, &options);
+        assert_eq!(parse_descriptor(&bytes), Some((7, options)));
+        let options = ColumnFamilyOptions::new().no_cache();
+        let bytes = descriptor_bytes(7, &options);
+        assert_eq!(bytes[8], NO_CACHE);
+        assert_eq!(parse_descriptor(&bytes), Some((7, options)));
+        let bytes = descriptor_bytes(3, &ColumnFamilyOptions::new());
+        assert_eq!(
+            parse_descriptor(&bytes),
+            Some((3, ColumnFamilyOptions::new()))
+        );
+        assert_eq!(parse_descriptor(&bytes[1..]), None);
+        let mut unknown_codec = descriptor_bytes(3, &ColumnFamilyOptions::new());
+        unknown_codec[8..10].copy_from_slice(&[HAS_COMPRESSION, 9]);
+        assert_eq!(parse_descriptor(&unknown_codec), None);
+    }
+    #[test]
+    fn test_column_families() {
+        let tmp_dir = tempfile::tempdir().unwrap();
+        let (storage, file_path) = new_storage(&tmp_dir);
+        let mut families = ColumnFamilies::create(storage).unwrap();
+        assert!(families
+            .create_cf("index", ColumnFamilyOptions::new())
+            .unwrap());
+        let sessions = ColumnFamilyOptions::new().ttl(3600);
+        assert!(families.create_cf("sessions", sessions).unwrap());
+        assert!(!families.create_cf("index", sessions).unwrap());
+        // - key spaces are independent
+        families.cf("index").put(b"a", b"index value").unwrap();
+        families.cf("sessions").put(b"a", b"session").unwrap();
+        assert_eq!(
+            families.cf("index").get(b"a").unwrap(),
+            Some(b"index value".to_vec())
+        );
+        families.cf("sessions").delete(b"a").unwrap();
+        assert_eq!(families.cf("sessions").get(b"a").unwrap(), None);
+        assert!(families.cf("index").get(b"a").unwrap().is_some());
+        assert!(matches!(
+            families.cf("missing").put(b"a", b"1"),
+            Err(StorageError::UnknownColumnFamily { name }) if name == "missing"
+        ));
+        assert!(matches!(
+            families.set_merge_operator("missing", Box::new(|_, _, _| Vec::new())),
+            Err(StorageError::UnknownColumnFamily { .. })
+        ));
+        // - families and their options survive reopening
+        families.cf("sessions").put(b"b", b"session").unwrap();
+        let anchor = families.id();
+        families.into_storage().close().unwrap();
+        let storage = Storage::open(file_path).unwrap();
+        let mut families = ColumnFamilies::open(storage, anchor).unwrap();
+        assert_eq!(families.cf_names(), vec!["index", "sessions"]);
+        assert_eq!(families.cf_options("sessions"), Some(sessions));
+        assert_eq!(
+            families.cf("sessions").get(b"b").unwrap(),
+            Some(b"session".to_vec())
+        );
+        // - dropping a family deletes its blocks
+        let used_blocks = |families: &mut ColumnFamilies| families.storage.iter_blocks().count();
+        let before = used_blocks(&mut families);
+        families
+            .create_cf("scratch", ColumnFamilyOptions::new())
+            .unwrap();
+        families.cf("scratch").put(b"a", &[1; 100]).unwrap();
+        assert!(families.drop_cf("scratch").unwrap());
+        assert!(!families.drop_cf("scratch").unwrap());
+        assert_eq!(used_blocks(&mut families), before);
+        assert!(families.cf("scratch").get(b"a").is_err());
+    }
+    #[test]
+    fn test_column_family_ttl() {
+        let tmp_dir = tempfile::tempdir().unwrap();
+        let (storage, _) = new_storage(&tmp_dir);
+        let mut families = ColumnFamilies::create(storage).unwrap();
+        families
+            .create_cf("cache", ColumnFamilyOptions::new().ttl(0))
+            .unwrap();
+        families
+            .create_cf("kept", ColumnFamilyOptions::new())
+            .unwrap();
+        families.cf("cache").put(b"a", b"1").unwrap();
+        families.cf("cache").put(b"b", b"2").unwrap();
+        families.cf("kept").put(b"a", b"1").unwrap();
+        // - values expire right away with a TTL of 0, they read as missing until swept
+        assert_eq!(families.cf("cache").get(b"a").unwrap(), None);
+        let before = families.storage.iter_blocks().count();
+        assert_eq!(families.sweep_expired(unix_time()).unwrap(), 2);
+        assert_eq!(families.storage.iter_blocks().count(), before - 2);
+        assert_eq!(families.sweep_expired(u64::MAX).unwrap(), 0);
+        assert_eq!(families.cf("kept").get(b"a").unwrap(), Some(b"1".to_vec()));
+    }
+    #[test]
+    fn test_frozen_column_family() {
+        let tmp_dir = tempfile::tempdir().unwrap();
+        let (storage, _) = new_storage(&tmp_dir);
+        let mut families = ColumnFamilies::create(storage).unwrap();
+        families
+            .create_cf("tenant", ColumnFamilyOptions::new().ttl(0))
+            .unwrap();
+        families
+            .create_cf("other", ColumnFamilyOptions::new())
+            .unwrap();
+        families.cf("tenant").put(b"a", b"1").unwrap();
+        families.freeze_cf("tenant").unwrap();
+        assert!(families.is_frozen("tenant") && !families.is_frozen("other"));
+        let frozen = |result: Result<(), StorageError>| matches!(result, Err(StorageError::Frozen { name }) if name == "tenant");
+        assert!(frozen(families.cf("tenant").put(b"b", b"2")));
+        assert!(frozen(families.cf("tenant").delete(b"a")));
+        assert!(frozen(families.cf("tenant").merge(b"a", b"2")));
+        let mut batch = WriteBatch::new();
+        batch.put(b"b", b"2");
+        assert!(frozen(families.cf("tenant").write(batch)));
+        assert!(matches!(
+            families.drop_cf("tenant"),
+            Err(StorageError::Frozen { .. })
+        ));
+        // - reads and other families carry on, sweeps skip the frozen family
+        assert_eq!(families.cf("tenant").get(b"a").unwrap(), None);
+        families.cf("other").put(b"a", b"1").unwrap();
+        assert_eq!(families.sweep_expired(u64::MAX).unwrap(), 0);
+        assert!(matches!(
+            families.freeze_cf("missing"),
+            Err(StorageError::UnknownColumnFamily { .. })
+        ));
+        families.thaw_cf("tenant").unwrap();
+        families.cf("tenant").put(b"b", b"2").unwrap();
+        assert_eq!(families.sweep_expired(u64::MAX).unwrap(), 2);
+    }
+    #[test]
+    fn test_column_family_stats() {
+        let tmp_dir = tempfile::tempdir().unwrap();
+        let (storage, _) = new_storage(&tmp_dir);
+        let mut families = ColumnFamilies::create(storage).unwrap();
+        families
+            .create_cf("tenant", ColumnFamilyOptions::new())
+            .unwrap();
+        families
+            .create_cf("empty", ColumnFamilyOptions::new())
+            .unwrap();
+        families.cf("tenant").put(b"a", b"1").unwrap();
+        families.cf("tenant").put(b"b", &[2; 100]).unwrap();
+        families.freeze_cf("tenant").unwrap();
+        let stats = families.stats().unwrap();
+        let names: Vec<&str> = stats.iter().map(|stats| stats.name.as_str()).collect();
+        assert_eq!(names, vec!["empty", "tenant"]);
+        assert_eq!((stats[0].keys, stats[0].value_blocks), (0, 0));
+        assert_eq!(stats[1].keys, 2);
+        assert!(stats[1].value_blocks > 2);
+        assert!(stats[1].frozen);
+        #[cfg(feature = "prometheus")]
+        {
+            let text = families.to_prometheus().unwrap();
+            assert!(text.contains("# TYPE se1_cf_keys gauge\n"));
+            assert!(text.contains("se1_cf_keys{family=\"tenant\"} 2\n"));
+        }
+    }
+    #[test]
+    fn test_column_family_no_cache() {
+        let tmp_dir = tempfile::tempdir().unwrap();
+        let (mut storage, file_path) = new_storage(&tmp_dir);
+        storage.enable_cache(1 << 20, crate::storage::CachePolicy::Lru);
+        let mut families = ColumnFamilies::create(storage).unwrap();
+        families
+            .create_cf("hot", ColumnFamilyOptions::new())
+            .unwrap();
+        families
+            .create_cf("bulk", ColumnFamilyOptions::new().no_cache())
+            .unwrap();
+        let cached_bytes =
+            |families: &ColumnFamilies| families.storage.cache_stats().unwrap().cached_bytes;
+        let created = cached_bytes(&families);
+        // - blocks written by the bulk family stay out of the cache
+        families.cf("bulk").put(b"a", &[1; 100]).unwrap();
+        assert!(cached_bytes(&families) <= created);
+        assert_eq!(families.cf("bulk").get(b"a").unwrap(), Some(vec![1; 100]));
+        let bulk = cached_bytes(&families);
+        families.cf("hot").put(b"a", &[2; 100]).unwrap();
+        assert!(cached_bytes(&families) >= bulk + 100);
+        assert_eq!(families.cf("hot").get(b"a").unwrap(), Some(vec![2; 100]));
+        // - the option is kept across opens
+        let id = families.id();
+        drop(families);
+        let storage = Storage::open(file_path).unwrap();
+        let families = ColumnFamilies::open(storage, id).unwrap();
+        assert!(families.cf_options("bulk").unwrap().no_cache);
+        assert!(!families.cf_options("hot").unwrap().no_cache);
+    }
+    #[cfg(feature = "lz4")]
+    #[test]
+    fn test_column_family_compression() {
+        let tmp_dir = tempfile::tempdir().unwrap();
+        let file_path = tmp_dir.path().join("family.hex");
+        let storage = Storage::new(file_path.to_str().unwrap().to_string(), 256).unwrap();
+        let mut families = ColumnFamilies::create(storage).unwrap();
+        let options = ColumnFamilyOptions::new().compression(Compression::Lz4);
+        families.create_cf("compressed", options).unwrap();
+        families
+            .create_cf("plain", ColumnFamilyOptions::new())
+            .unwrap();
+        families.cf("compressed").put(b"a", &[7; 200]).unwrap();
+        families.cf("plain").put(b"a", &[7; 200]).unwrap();
+        // - stored data size of the value block
+        let value_size = |families: &mut ColumnFamilies, name: &str| {
+            let value_record = families.families[name]
+                .key_space
+                .index
+                .get(&mut families.storage, &b"a".to_vec())
+                .unwrap()
+                .unwrap();
+            let mut block_headers = families.storage.iter_block_headers();
+            let block_info = block_headers
+                .find(|block_info| block_info.as_ref().unwrap().block_index == value_record)
+                .unwrap();
+            block_info.unwrap().data_size
+        };
+        assert!(value_size(&mut families, "compressed") < 100);
+        assert_eq!(value_size(&mut families, "plain"), 200);
+        assert_eq!(families.storage.compression(), Compression::None);
+        assert_eq!(
+            families.cf("compressed").get(b"a").unwrap(),
+            Some(vec![7; 200])
+        );
+    }
+}
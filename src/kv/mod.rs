@@ -0,0 +1,479 @@
+//! Key value stores on a storage file, with a backend per access pattern
+
+mod family;
+
+pub use family::{ColumnFamilies, ColumnFamily, ColumnFamilyOptions, ColumnFamilyStats};
+
+use crate::engine::unix_time;
+use crate::index::BTreeIndex;
+use crate::storage::{RecordId, Storage, StorageError};
+use std::collections::HashMap;
+
+/// Byte keys mapped to byte values, stored in a storage file by a backend
+/// - BTreeKvStore keeps keys in a BTreeIndex and rewrites index nodes on every put,
+///   lsm::LsmStore collects puts in memory and writes them as sorted tables, for write
+///   heavy workloads
+/// - ColumnFamilies holds several BTreeKvStore key spaces in one storage file, each
+///   ColumnFamily is a KvStore of its own
+/// - values must not be empty, like record data, empty values fail with EmptyRecord
+pub trait KvStore {
+    /// Store value for key, replacing the value stored before
+    fn put(&mut self, key: &[u8], value: &[u8]) -> Result<(), StorageError>;
+    /// Value stored for key
+    fn get(&mut self, key: &[u8]) -> Result<Option<Vec<u8>>, StorageError>;
+    /// Remove key, a key that is not stored is ignored
+    fn delete(&mut self, key: &[u8]) -> Result<(), StorageError>;
+    /// Apply every operation of batch together, see WriteBatch
+    /// - returns: Ok once all of them are applied, an error if none of them is
+    fn write(&mut self, batch: WriteBatch) -> Result<(), StorageError>;
+    /// Fold operand into the value of key with the merge operator of the store, without
+    /// the caller reading the value first, see MergeOperator
+    /// - MergeOperatorMissing if the store was not given a merge operator
+    fn merge(&mut self, key: &[u8], operand: &[u8]) -> Result<(), StorageError>;
+}
+
+/// Function folding a merge operand into the value of a key, e.g. adding to a counter
+/// or appending to a list, see KvStore::merge
+/// - called with the key, its value, None if it is not stored, and the operand
+/// - returns: new value of key, an empty value removes the key
+/// - stores call it when they see fit, also later and for several operands in a row,
+///   it must only depend on its arguments
+pub type MergeOperator = Box<dyn Fn(&[u8], Option<&[u8]>, &[u8]) -> Vec<u8> + Send + Sync>;
+
+/// Operation of a WriteBatch
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum BatchOp {
+    /// Store value for key, see KvStore::put
+    Put { key: Vec<u8>, value: Vec<u8> },
+    /// Remove key, see KvStore::delete
+    Delete { key: Vec<u8> },
+    /// Fold operand into value of key, see KvStore::merge
+    Merge { key: Vec<u8>, operand: Vec<u8> },
+}
+
+/// Puts and deletes of any keys, collected in memory and applied together by
+/// KvStore::write
+/// - operations apply in the order they were added, the last put or delete of a key
+///   wins, a merge folds into the value of the operations before it
+/// - every value is checked before anything is written, an empty value fails the whole
+///   batch with EmptyRecord
+/// - readers of the store see either none or all of the operations
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct WriteBatch {
+    ops: Vec<BatchOp>,
+}
+
+impl WriteBatch {
+    pub fn new() -> Self {
+        WriteBatch::default()
+    }
+    /// Add put of value for key
+    pub fn put(&mut self, key: &[u8], value: &[u8]) -> &mut Self {
+        self.ops.push(BatchOp::Put {
+            key: key.to_vec(),
+            value: value.to_vec(),
+        });
+        self
+    }
+    /// Add removal of key
+    pub fn delete(&mut self, key: &[u8]) -> &mut Self {
+        self.ops.push(BatchOp::Delete { key: key.to_vec() });
+        self
+    }
+    /// Add merge of operand into value of key
+    pub fn merge(&mut self, key: &[u8], operand: &[u8]) -> &mut Self {
+        self.ops.push(BatchOp::Merge {
+            key: key.to_vec(),
+            operand: operand.to_vec(),
+        });
+        self
+    }
+    /// Operations added, in order
+    pub fn ops(&self) -> &[BatchOp] {
+        &self.ops
+    }
+    pub fn len(&self) -> usize {
+        self.ops.len()
+    }
+    pub fn is_empty(&self) -> bool {
+        self.ops.is_empty()
+    }
+    /// EmptyRecord if a put has an empty value
+    pub(crate) fn check_values(&self) -> Result<(), StorageError> {
+        let empty_value = self
+            .ops
+            .iter()
+            .any(|op| matches!(op, BatchOp::Put { value, .. } if value.is_empty()));
+        if empty_value {
+            return Err(StorageError::EmptyRecord);
+        }
+        Ok(())
+    }
+}
+
+impl IntoIterator for WriteBatch {
+    type Item = BatchOp;
+    type IntoIter = std::vec::IntoIter<BatchOp>;
+    fn into_iter(self) -> Self::IntoIter {
+        self.ops.into_iter()
+    }
+}
+
+/// KvStore keeping keys in a BTreeIndex, each value in a record of its own
+/// - a put writes the value to a new record, points the key at it, then deletes the
+///   record of the previous value
+/// - a batch writes the records of all its values first, then changes the index for
+///   every key at once, see BTreeIndex::apply; a failure deletes the new records again
+/// - a merge reads the value of its key in the store and puts the folded value
+pub struct BTreeKvStore {
+    storage: Storage,
+    key_space: KeySpace,
+}
+
+impl BTreeKvStore {
+    /// Create empty store in storage
+    pub fn create(mut storage: Storage) -> Result<Self, StorageError> {
+        let index = BTreeIndex::create(&mut storage)?;
+        Ok(BTreeKvStore {
+            storage,
+            key_space: KeySpace::new(index, ColumnFamilyOptions::default()),
+        })
+    }
+    /// Open store created earlier, by its anchor block
+    pub fn open(storage: Storage, anchor: RecordId) -> Self {
+        BTreeKvStore {
+            storage,
+            key_space: KeySpace::new(BTreeIndex::open(anchor), ColumnFamilyOptions::default()),
+        }
+    }
+    /// Set function folding merge operands into values, see KvStore::merge
+    pub fn with_merge_operator(mut self, merge_operator: MergeOperator) -> Self {
+        self.key_space.merge_operator = Some(merge_operator);
+        self
+    }
+    /// Anchor block of store, to open it again with BTreeKvStore::open
+    pub fn id(&self) -> RecordId {
+        self.key_space.index.id()
+    }
+    /// Storage holding the store
+    pub fn into_storage(self) -> Storage {
+        self.storage
+    }
+}
+
+impl KvStore for BTreeKvStore {
+    fn put(&mut self, key: &[u8], value: &[u8]) -> Result<(), StorageError> {
+        self.key_space.put(&mut self.storage, key, value)
+    }
+    fn get(&mut self, key: &[u8]) -> Result<Option<Vec<u8>>, StorageError> {
+        self.key_space.get(&mut self.storage, key)
+    }
+    fn delete(&mut self, key: &[u8]) -> Result<(), StorageError> {
+        self.key_space.delete(&mut self.storage, key)
+    }
+    fn write(&mut self, batch: WriteBatch) -> Result<(), StorageError> {
+        self.key_space.write(&mut self.storage, batch)
+    }
+    fn merge(&mut self, key: &[u8], operand: &[u8]) -> Result<(), StorageError> {
+        self.key_space.merge(&mut self.storage, key, operand)
+    }
+}
+
+// ... ... ... ... ... ... ... ... ... ... Key Space ... ... ... ... ... ... ... ... ... ...
+
+/// Keys in a BTreeIndex pointing at a record per value, in a storage passed to every
+/// method, the store of BTreeKvStore and of each family of ColumnFamilies
+/// - values are written with the compression and TTL of options, values past their TTL
+///   read as missing
+struct KeySpace {
+    index: BTreeIndex<Vec<u8>>,
+    options: ColumnFamilyOptions,
+    merge_operator: Option<MergeOperator>,
+}
+
+impl KeySpace {
+    fn new(index: BTreeIndex<Vec<u8>>, options: ColumnFamilyOptions) -> Self {
+        KeySpace {
+            index,
+            options,
+            merge_operator: None,
+        }
+    }
+    fn put(&mut self, storage: &mut Storage, key: &[u8], value: &[u8]) -> Result<(), StorageError> {
+        let value_record = self.write_value(storage, value)?;
+        match self.index.insert(storage, key.to_vec(), value_record) {
+            Ok(Some(previous)) => storage.delete_record(previous, false).map(|_| ()),
+            Ok(None) => Ok(()),
+            Err(error) => {
+                // failure to undo leaves an unreachable record, report the original error
+                let _ = storage.delete_record(value_record, false);
+                Err(error)
+            }
+        }
+    }
+    fn get(&self, storage: &mut Storage, key: &[u8]) -> Result<Option<Vec<u8>>, StorageError> {
+        let value_record = match self.index.get(storage, &key.to_vec())? {
+            Some(value_record) => value_record,
+            None => return Ok(None),
+        };
+        if self.options.ttl.is_some()
+            && is_expired(storage.block_expiry(value_record)?, unix_time())
+        {
+            return Ok(None);
+        }
+        Ok(Some(storage.read_record(value_record)?))
+    }
+    fn delete(&mut self, storage: &mut Storage, key: &[u8]) -> Result<(), StorageError> {
+        if let Some(value_record) = self.index.remove(storage, &key.to_vec())? {
+            storage.delete_record(value_record, false)?;
+        }
+        Ok(())
+    }
+    fn write(&mut self, storage: &mut Storage, batch: WriteBatch) -> Result<(), StorageError> {
+        batch.check_values()?;
+        let ops = self.fold_merges(storage, batch)?;
+        // - values first, not referenced by the index until it is changed
+        let mut value_records = Vec::new();
+        let mut changes = Vec::with_capacity(ops.len());
+        for op in ops {
+            match op {
+                BatchOp::Put { key, value } => match self.write_value(storage, &value) {
+                    Ok(value_record) => {
+                        value_records.push(value_record);
+                        changes.push((key, Some(value_record)));
+                    }
+                    Err(error) => {
+                        delete_records(storage, &value_records);
+                        return Err(error);
+                    }
+                },
+                BatchOp::Delete { key } | BatchOp::Merge { key, .. } => changes.push((key, None)),
+            }
+        }
+        let previous = match self.index.apply(storage, changes) {
+            Ok(previous) => previous,
+            Err(error) => {
+                delete_records(storage, &value_records);
+                return Err(error);
+            }
+        };
+        // - a key put twice in the batch replaced the record of its first put
+        for value_record in previous.into_iter().flatten() {
+            storage.delete_record(value_record, false)?;
+        }
+        Ok(())
+    }
+    fn merge(
+        &mut self,
+        storage: &mut Storage,
+        key: &[u8],
+        operand: &[u8],
+    ) -> Result<(), StorageError> {
+        let mut batch = WriteBatch::new();
+        batch.merge(key, operand);
+        self.write(storage, batch)
+    }
+    /// Remove keys whose value expired at or before now, and delete their values
+    /// - now: seconds since UNIX epoch
+    /// - returns: number of keys removed
+    fn sweep_expired(&mut self, storage: &mut Storage, now: u64) -> Result<usize, StorageError> {
+        if self.options.ttl.is_none() {
+            return Ok(0);
+        }
+        let mut changes = Vec::new();
+        let mut value_records = Vec::new();
+        for (key, value_record) in self.index.range(storage, ..)? {
+            if is_expired(storage.block_expiry(value_record)?, now) {
+                changes.push((key, None));
+                value_records.push(value_record);
+            }
+        }
+        if changes.is_empty() {
+            return Ok(0);
+        }
+        // - keys first, so no key points at a deleted value
+        self.index.apply(storage, changes)?;
+        for value_record in value_records.iter() {
+            storage.delete_record(*value_record, false)?;
+        }
+        Ok(value_records.len())
+    }
+    /// Number of keys and of blocks of their values, reading block headers only
+    fn usage(&self, storage: &mut Storage) -> Result<(u64, u64), StorageError> {
+        let mut keys = 0;
+        let mut value_blocks = 0;
+        for (_, value_record) in self.index.range(storage, ..)? {
+            keys += 1;
+            value_blocks += storage.record_blocks(value_record)?.len() as u64;
+        }
+        Ok((keys, value_blocks))
+    }
+    /// Delete every value and the index
+    fn destroy(self, storage: &mut Storage) -> Result<(), StorageError> {
+        for (_, value_record) in self.index.range(storage, ..)? {
+            storage.delete_record(value_record, false)?;
+        }
+        self.index.destroy(storage)
+    }
+    /// Write value as a new record, with the compression and expiry of options
+    fn write_value(&self, storage: &mut Storage, value: &[u8]) -> Result<RecordId, StorageError> {
+        let expires_at = self.options.ttl.map(|ttl| unix_time().saturating_add(ttl));
+        match self.options.compression {
+            Some(compression) => storage.write_record_compressed(value, compression, expires_at),
+            None => storage.write_record_expiring(value, expires_at),
+        }
+    }
+    /// Replace merges of batch by puts of the folded values, or deletes for empty ones
+    /// - the value a merge folds into is the one left by the operations before it, else
+    ///   the one in the store
+    fn fold_merges(
+        &self,
+        storage: &mut Storage,
+        batch: WriteBatch,
+    ) -> Result<Vec<BatchOp>, StorageError> {
+        let has_merges = batch
+            .ops()
+            .iter()
+            .any(|op| matches!(op, BatchOp::Merge { .. }));
+        if has_merges && self.merge_operator.is_none() {
+            return Err(StorageError::MergeOperatorMissing);
+        }
+        let mut values: HashMap<Vec<u8>, Option<Vec<u8>>> = HashMap::new();
+        let mut ops = Vec::with_capacity(batch.len());
+        for op in batch {
+            let (key, value) = match op {
+                BatchOp::Put { key, value } => (key, Some(value)),
+                BatchOp::Delete { key } => (key, None),
+                BatchOp::Merge { key, operand } => {
+                    let value = match values.get(&key) {
+                        Some(value) => value.clone(),
+                        None => self.get(storage, &key)?,
+                    };
+                    let value = self
+                        .merge_operator
+                        .as_ref()
+                        .map(|merge_operator| merge_operator(&key, value.as_deref(), &operand));
+                    (key, value.filter(|value| !value.is_empty()))
+                }
+            };
+            ops.push(match value.clone() {
+                Some(value) => BatchOp::Put {
+                    key: key.clone(),
+                    value,
+                },
+                None => BatchOp::Delete { key: key.clone() },
+            });
+            values.insert(key, value);
+        }
+        Ok(ops)
+    }
+}
+
+/// Check if a block expiring at expires_at has expired at now
+fn is_expired(expires_at: Option<u64>, now: u64) -> bool {
+    expires_at.is_some_and(|expires_at| expires_at <= now)
+}
+
+/// Delete value records of a failed change
+/// - failure to undo leaves unreachable records, the caller reports the original error
+fn delete_records(storage: &mut Storage, value_records: &[RecordId]) {
+    for value_record in value_records {
+        let _ = storage.delete_record(*value_record, false);
+    }
+}
+
+#[cfg(test)]
+mod unit_tests_kv {
+    use super::*;
+
+    #[test]
+    fn test_btree_kv_store() {
+        let tmp_dir = tempfile::tempdir().unwrap();
+        let file_path = tmp_dir.path().join("kv.hex");
+        let file_path = file_path.to_str().unwrap().to_string();
+        let mut store = BTreeKvStore::create(Storage::new(file_path.clone(), 16).unwrap()).unwrap();
+        store.put(b"a", b"first").unwrap();
+        store.put(b"b", &[2; 40]).unwrap();
+        store.put(b"a", b"second").unwrap();
+        assert_eq!(store.get(b"a").unwrap(), Some(b"second".to_vec()));
+        assert!(matches!(
+            store.put(b"c", b""),
+            Err(StorageError::EmptyRecord)
+        ));
+        store.delete(b"b").unwrap();
+        store.delete(b"missing").unwrap();
+        assert_eq!(store.get(b"b").unwrap(), None);
+        let anchor = store.id();
+        drop(store);
+        let mut store = BTreeKvStore::open(Storage::open(file_path).unwrap(), anchor);
+        assert_eq!(store.get(b"a").unwrap(), Some(b"second".to_vec()));
+    }
+    #[test]
+    fn test_btree_write_batch() {
+        let tmp_dir = tempfile::tempdir().unwrap();
+        let file_path = tmp_dir.path().join("kv.hex");
+        let storage = Storage::new(file_path.to_str().unwrap().to_string(), 16).unwrap();
+        let mut store = BTreeKvStore::create(storage).unwrap();
+        store.put(b"a", b"old").unwrap();
+        store.put(b"b", b"old").unwrap();
+        let used_blocks = |store: &mut BTreeKvStore| store.storage.iter_blocks().count();
+        let before = used_blocks(&mut store);
+        let mut batch = WriteBatch::new();
+        batch
+            .put(b"a", b"first")
+            .delete(b"b")
+            .put(b"c", &[3; 40])
+            .put(b"a", b"second");
+        assert_eq!(batch.len(), 4);
+        store.write(batch).unwrap();
+        assert_eq!(store.get(b"a").unwrap(), Some(b"second".to_vec()));
+        assert_eq!(store.get(b"b").unwrap(), None);
+        assert_eq!(store.get(b"c").unwrap(), Some(vec![3; 40]));
+        // - replaced values are deleted, c takes 3 blocks, b's block is freed
+        assert_eq!(used_blocks(&mut store), before + 2);
+        // - an empty value fails the batch before anything is written
+        let mut batch = WriteBatch::new();
+        batch.delete(b"a").put(b"d", b"");
+        assert!(matches!(store.write(batch), Err(StorageError::EmptyRecord)));
+        assert_eq!(store.get(b"a").unwrap(), Some(b"second".to_vec()));
+        store.write(WriteBatch::new()).unwrap();
+    }
+    /// Merge operator appending operand to value, removing key for operand "clear"
+    fn append() -> MergeOperator {
+        Box::new(
+            |_key: &[u8], value: Option<&[u8]>, operand: &[u8]| match operand {
+                b"clear" => Vec::new(),
+                _ => [value.unwrap_or_default(), operand].concat(),
+            },
+        )
+    }
+    #[test]
+    fn test_btree_merge() {
+        let tmp_dir = tempfile::tempdir().unwrap();
+        let file_path = tmp_dir.path().join("kv.hex");
+        let storage = Storage::new(file_path.to_str().unwrap().to_string(), 16).unwrap();
+        let mut store = BTreeKvStore::create(storage).unwrap();
+        assert!(matches!(
+            store.merge(b"a", b"1"),
+            Err(StorageError::MergeOperatorMissing)
+        ));
+        let mut store = store.with_merge_operator(append());
+        store.merge(b"a", b"1").unwrap();
+        store.merge(b"a", b"2").unwrap();
+        assert_eq!(store.get(b"a").unwrap(), Some(b"12".to_vec()));
+        // - merges of a batch fold into the value left by the operations before them
+        let mut batch = WriteBatch::new();
+        batch
+            .merge(b"a", b"3")
+            .put(b"b", b"x")
+            .merge(b"b", b"y")
+            .delete(b"a")
+            .merge(b"a", b"4");
+        store.write(batch).unwrap();
+        assert_eq!(store.get(b"a").unwrap(), Some(b"4".to_vec()));
+        assert_eq!(store.get(b"b").unwrap(), Some(b"xy".to_vec()));
+        // - an empty value removes the key
+        store.merge(b"b", b"clear").unwrap();
+        assert_eq!(store.get(b"b").unwrap(), None);
+    }
+}
@@ -1 +1,26 @@
+//! Block based storage engine
+//!
+//! # No panic policy
+//! Library code does not panic on IO failures, corrupt storage files or out of
+//! range arguments, every failure is returned as a `StorageError` to the caller.
+//! `unwrap`, `expect` and `panic!` are denied outside of tests to keep it that way.
+//! Callbacks registered by the caller (observers, transforms, hooks) are not
+//! covered, a panic inside them unwinds through the calling Storage method.
+#![cfg_attr(
+    not(test),
+    deny(clippy::unwrap_used, clippy::expect_used, clippy::panic)
+)]
+pub mod engine;
+#[cfg(feature = "ffi")]
+pub mod ffi;
+#[cfg(feature = "grpc")]
+pub mod grpc;
+pub mod index;
+pub mod kv;
+pub mod log;
+pub mod lsm;
+pub mod queue;
+pub mod record;
+#[cfg(feature = "net")]
+pub mod server;
 pub mod storage;
@@ -0,0 +1,250 @@
+//! Append-only log of entries on a storage file, e.g. for event streams
+
+use crate::storage::{saturating_u64, BlockIndex, Storage, StorageError};
+
+/// Position of an entry in an AppendLog, the block its data starts at
+/// - grows with every append, by the number of blocks of the entry before
+pub type Lsn = u64;
+
+/// Block holding head and tail of the log
+const SUPERBLOCK: BlockIndex = 0;
+/// Bytes of the superblock, head (u64) and tail (u64), and of the Lsn in front of the
+/// data of every entry
+const SUPERBLOCK_LEN: usize = 16;
+
+/// Log of entries appended to contiguous blocks of a storage, read in append order
+/// - block 0 is the superblock, holding head, position of the first entry kept, and
+///   tail, position the next entry is appended at
+/// - an entry is written to the blocks from tail on, linked like a record, with its
+///   Lsn in front of its data, then the superblock is rewritten
+/// - the log uses every block of the storage, create it in a new storage file
+/// - truncate_before frees blocks at the head, they are not written by the log again
+/// - NOTE: an entry is appended once the superblock is written, an entry written
+///   before a crash but not listed in the superblock is overwritten by the next append
+pub struct AppendLog {
+    storage: Storage,
+    head: Lsn,
+    tail: Lsn,
+}
+
+impl AppendLog {
+    /// Create empty log in storage
+    /// - block_len of storage must be at least 16 bytes, to hold the superblock
+    pub fn create(storage: Storage) -> Result<Self, StorageError> {
+        if storage.block_len() < SUPERBLOCK_LEN as u64 {
+            return Err(StorageError::BlockTooLarge {
+                block_index: SUPERBLOCK,
+                data_len: SUPERBLOCK_LEN,
+                block_len: storage.block_len(),
+            });
+        }
+        let mut log = AppendLog {
+            storage,
+            head: SUPERBLOCK + 1,
+            tail: SUPERBLOCK + 1,
+        };
+        log.write_superblock()?;
+        Ok(log)
+    }
+    /// Open log created earlier in storage
+    pub fn open(mut storage: Storage) -> Result<Self, StorageError> {
+        let (_, superblock) = storage.read_block(SUPERBLOCK)?;
+        let malformed = StorageError::Corruption {
+            block_index: Some(SUPERBLOCK),
+            reason: "log superblock is malformed",
+        };
+        if superblock.len() != SUPERBLOCK_LEN {
+            return Err(malformed);
+        }
+        let (head, tail) = (read_u64(&superblock[..8]), read_u64(&superblock[8..]));
+        if head == SUPERBLOCK || head > tail {
+            return Err(malformed);
+        }
+        Ok(AppendLog {
+            storage,
+            head,
+            tail,
+        })
+    }
+    /// Position of first entry kept, equal to tail if log is empty
+    pub fn head(&self) -> Lsn {
+        self.head
+    }
+    /// Position the next entry is appended at
+    pub fn tail(&self) -> Lsn {
+        self.tail
+    }
+    /// Storage holding the log
+    pub fn into_storage(self) -> Storage {
+        self.storage
+    }
+    /// Append bytes as a new entry
+    /// - empty bytes fail with EmptyRecord, like record data
+    /// - returns: position of entry, to read entries from it on
+    pub fn append(&mut self, bytes: &[u8]) -> Result<Lsn, StorageError> {
+        if bytes.is_empty() {
+            return Err(StorageError::EmptyRecord);
+        }
+        let lsn = self.tail;
+        let mut data = lsn.to_le_bytes().to_vec();
+        data.extend_from_slice(bytes);
+        let block_count = saturating_u64(data.len().div_ceil(self.storage.chunk_len()));
+        let end = lsn
+            .checked_add(block_count)
+            .ok_or(StorageError::BlockOutOfRange { block_index: lsn })?;
+        let blocks: Vec<BlockIndex> = (lsn..end).collect();
+        self.storage.write_into(&blocks, &data)?;
+        self.tail = end;
+        if let Err(error) = self.write_superblock() {
+            self.tail = lsn;
+            return Err(error);
+        }
+        Ok(lsn)
+    }
+    /// Entries from position lsn on, in append order
+    /// - lsn must be the position of an entry kept, or tail, else every entry fails
+    ///   with InvalidLsn
+    pub fn read_from(&mut self, lsn: Lsn) -> LogEntries<'_> {
+        LogEntries {
+            log: self,
+            next: lsn,
+        }
+    }
+    /// Delete entries before position lsn
+    /// - lsn must be the position of an entry kept, or tail to delete every entry, else
+    ///   InvalidLsn and nothing is deleted
+    /// - superblock is written first, a failed delete leaves unreachable blocks
+    pub fn truncate_before(&mut self, lsn: Lsn) -> Result<(), StorageError> {
+        if lsn != self.tail {
+            self.read_entry(lsn)?;
+        }
+        let old_head = self.head;
+        self.head = lsn;
+        if let Err(error) = self.write_superblock() {
+            self.head = old_head;
+            return Err(error);
+        }
+        for block_index in old_head..lsn {
+            self.storage.delete_block(block_index, false)?;
+        }
+        Ok(())
+    }
+    /// Read entry at position lsn
+    /// - returns: data of entry, and position of next entry
+    fn read_entry(&mut self, lsn: Lsn) -> Result<(Vec<u8>, Lsn), StorageError> {
+        if lsn < self.head || lsn >= self.tail {
+            return Err(StorageError::InvalidLsn { lsn });
+        }
+        let mut data = self.storage.read_record(lsn)?;
+        // - a block in the middle of an entry does not start with its own position
+        if data.len() < 8 || read_u64(&data[..8]) != lsn {
+            return Err(StorageError::InvalidLsn { lsn });
+        }
+        let block_count = saturating_u64(self.storage.record_blocks(lsn)?.len());
+        data.drain(..8);
+        Ok((data, lsn + block_count))
+    }
+    fn write_superblock(&mut self) -> Result<(), StorageError> {
+        let mut superblock = self.head.to_le_bytes().to_vec();
+        superblock.extend_from_slice(&self.tail.to_le_bytes());
+        self.storage.write_block(SUPERBLOCK, &superblock)?;
+        Ok(())
+    }
+}
+
+/// Iterator over entries of an AppendLog, see AppendLog::read_from
+/// - yields position and data of each entry, ends after the first error
+pub struct LogEntries<'a> {
+    log: &'a mut AppendLog,
+    next: Lsn,
+}
+
+impl<'a> Iterator for LogEntries<'a> {
+    type Item = Result<(Lsn, Vec<u8>), StorageError>;
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.next == self.log.tail {
+            return None;
+        }
+        let lsn = self.next;
+        match self.log.read_entry(lsn) {
+            Ok((data, next)) => {
+                self.next = next;
+                Some(Ok((lsn, data)))
+            }
+            Err(error) => {
+                self.next = self.log.tail;
+                Some(Err(error))
+            }
+        }
+    }
+}
+
+fn read_u64(bytes: &[u8]) -> u64 {
+    let mut le_bytes = [0u8; 8];
+    le_bytes.copy_from_slice(bytes);
+    u64::from_le_bytes(le_bytes)
+}
+
+#[cfg(test)]
+mod unit_tests_log {
+    use super::*;
+
+    fn file_path(tmp_dir: &tempfile::TempDir) -> String {
+        let file_path = tmp_dir.path().join("log.hex");
+        file_path.to_str().unwrap().to_string()
+    }
+    fn entries(log: &mut AppendLog, lsn: Lsn) -> Vec<(Lsn, Vec<u8>)> {
+        log.read_from(lsn).map(Result::unwrap).collect()
+    }
+
+    #[test]
+    fn test_append_log() {
+        let tmp_dir = tempfile::tempdir().unwrap();
+        let storage = Storage::new(file_path(&tmp_dir), 16).unwrap();
+        let mut log = AppendLog::create(storage).unwrap();
+        assert_eq!(log.append(b"first").unwrap(), 1);
+        // - 8 bytes of Lsn and 20 bytes of data take two blocks
+        assert_eq!(log.append(&[2; 20]).unwrap(), 2);
+        assert_eq!(log.append(b"third").unwrap(), 4);
+        assert!(matches!(log.append(b""), Err(StorageError::EmptyRecord)));
+        assert_eq!(
+            entries(&mut log, 2),
+            vec![(2, vec![2; 20]), (4, b"third".to_vec())]
+        );
+        assert!(entries(&mut log, 5).is_empty());
+        // - position in the middle of an entry
+        assert!(matches!(
+            log.read_from(3).next(),
+            Some(Err(StorageError::InvalidLsn { lsn: 3 }))
+        ));
+        // - truncated entries are deleted, log opens again with head and tail
+        assert!(log.truncate_before(3).is_err());
+        log.truncate_before(4).unwrap();
+        assert!(matches!(
+            log.read_from(1).next(),
+            Some(Err(StorageError::InvalidLsn { lsn: 1 }))
+        ));
+        drop(log);
+        let mut log = AppendLog::open(Storage::open(file_path(&tmp_dir)).unwrap()).unwrap();
+        assert_eq!((log.head(), log.tail()), (4, 5));
+        assert_eq!(log.append(b"fourth").unwrap(), 5);
+        assert_eq!(
+            entries(&mut log, 4),
+            vec![(4, b"third".to_vec()), (5, b"fourth".to_vec())]
+        );
+        log.truncate_before(log.tail()).unwrap();
+        assert_eq!(log.head(), log.tail());
+        let mut storage = log.into_storage();
+        assert!(storage.read_block(2).unwrap().1.is_empty());
+    }
+    #[test]
+    fn test_open_malformed_superblock() {
+        let tmp_dir = tempfile::tempdir().unwrap();
+        let mut storage = Storage::new(file_path(&tmp_dir), 16).unwrap();
+        storage.write_block(0, &[1, 2, 3]).unwrap();
+        assert!(matches!(
+            AppendLog::open(storage),
+            Err(StorageError::Corruption { .. })
+        ));
+    }
+}
@@ -0,0 +1,88 @@
+/// Bits per key of table filters, if not set with LsmStore::with_bloom_bits_per_key
+/// - about 1% of lookups for keys a table does not hold read a chunk anyway
+pub const DEFAULT_BITS_PER_KEY: usize = 10;
+
+/// Bloom filter of the keys of a table, to skip tables that do not hold a key without
+/// reading a chunk
+/// - bytes: hash count (u32, little endian), then the bits
+/// - bit positions are derived from FNV-1a of the key, so filters read the same on
+///   every platform and version
+pub(super) struct BloomFilter {
+    hash_count: u32,
+    bits: Vec<u8>,
+}
+
+impl BloomFilter {
+    /// Filter of keys, given as key_hash of each key
+    /// - bits_per_key: bits set aside per key, more bits mean fewer false positives
+    pub(super) fn new(key_hashes: &[u64], bits_per_key: usize) -> Self {
+        let bit_count = key_hashes.len().saturating_mul(bits_per_key).max(64);
+        // - ln 2 hashes per bit of a key give the fewest false positives
+        let hash_count = ((bits_per_key as f64) * std::f64::consts::LN_2).round() as u32;
+        let mut filter = BloomFilter {
+            hash_count: hash_count.clamp(1, 30),
+            bits: vec![0u8; bit_count.div_ceil(8)],
+        };
+        for key_hash in key_hashes {
+            for bit in filter.bit_positions(*key_hash) {
+                filter.bits[bit / 8] |= 1 << (bit % 8);
+            }
+        }
+        filter
+    }
+    /// Check if key may be in the filter, false if it is certainly not
+    pub(super) fn may_contain(&self, key: &[u8]) -> bool {
+        self.bit_positions(key_hash(key))
+            .all(|bit| self.bits[bit / 8] & (1 << (bit % 8)) != 0)
+    }
+    /// Bit of each hash, by double hashing from the two halves of key hash
+    fn bit_positions(&self, key_hash: u64) -> impl Iterator<Item = usize> {
+        let bit_count = (self.bits.len() * 8) as u64;
+        let low = key_hash & 0xffff_ffff;
+        let high = (key_hash >> 32) | 1;
+        (0..u64::from(self.hash_count))
+            .map(move |hash| (low.wrapping_add(hash.wrapping_mul(high)) % bit_count) as usize)
+    }
+    pub(super) fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = self.hash_count.to_le_bytes().to_vec();
+        bytes.extend_from_slice(&self.bits);
+        bytes
+    }
+    /// Parse filter bytes, None if malformed
+    pub(super) fn from_bytes(bytes: &[u8]) -> Option<Self> {
+        let mut le_bytes = [0u8; 4];
+        le_bytes.copy_from_slice(bytes.get(..4)?);
+        let hash_count = u32::from_le_bytes(le_bytes);
+        let bits = bytes[4..].to_vec();
+        if bits.is_empty() || hash_count == 0 {
+            return None;
+        }
+        Some(BloomFilter { hash_count, bits })
+    }
+}
+
+/// FNV-1a hash of key
+pub(super) fn key_hash(key: &[u8]) -> u64 {
+    key.iter().fold(0xcbf2_9ce4_8422_2325, |hash, byte| {
+        (hash ^ u64::from(*byte)).wrapping_mul(0x0100_0000_01b3)
+    })
+}
+
+#[cfg(test)]
+mod unit_tests_bloom {
+    use super::*;
+
+    #[test]
+    fn test_bloom_filter() {
+        let keys: Vec<Vec<u8>> = (0u32..1000).map(|key| key.to_le_bytes().to_vec()).collect();
+        let key_hashes: Vec<u64> = keys.iter().map(|key| key_hash(key)).collect();
+        let filter = BloomFilter::new(&key_hashes, DEFAULT_BITS_PER_KEY);
+        let filter = BloomFilter::from_bytes(&filter.to_bytes()).unwrap();
+        assert!(keys.iter().all(|key| filter.may_contain(key)));
+        let false_positives = (1000u32..11000)
+            .filter(|key| filter.may_contain(&key.to_le_bytes()))
+            .count();
+        assert!(false_positives < 300, "{} false positives", false_positives);
+        assert!(BloomFilter::from_bytes(&[1, 0, 0, 0]).is_none());
+    }
+}
@@ -0,0 +1,941 @@
+//! Log-structured merge tree on a storage file, a KvStore backend for write heavy
+//! workloads
+
+mod bloom;
+pub use bloom::DEFAULT_BITS_PER_KEY;
+mod sstable;
+use sstable::{Table, TableBuilder, TableCursor};
+
+use crate::kv::{BatchOp, KvStore, MergeOperator, WriteBatch};
+use crate::storage::{
+    checked_u32, CompactionFilter, FilterDecision, RecordId, Storage, StorageError,
+};
+use std::collections::BTreeMap;
+use std::convert::TryFrom;
+use std::sync::{Arc, Condvar, Mutex, MutexGuard};
+use std::thread::{self, JoinHandle};
+
+/// Value of a key in the memtable and in tables, None for a deleted key
+type Entry = Option<Vec<u8>>;
+
+/// Memtable entry and pending merges of a key before a change, to undo it
+type Undo = (Vec<u8>, Option<Entry>, Option<Vec<Vec<u8>>>);
+
+/// Bytes of keys and values put before the memtable is flushed, if not set with
+/// with_memtable_bytes
+pub const DEFAULT_MEMTABLE_BYTES: usize = 1024 * 1024;
+/// Tables that start a compaction, if not set with with_compaction_trigger
+pub const DEFAULT_COMPACTION_TRIGGER: usize = 4;
+
+/// State shared with the compaction thread
+struct State {
+    storage: Storage,
+    /// Block holding record id of the manifest, it identifies the store across opens
+    anchor: RecordId,
+    /// Record listing index records of tables, newest first
+    manifest: RecordId,
+    memtable: BTreeMap<Vec<u8>, Entry>,
+    /// Merge operands of keys without memtable entry, oldest first, folded into the
+    /// value in the tables on get and on flush
+    merges: BTreeMap<Vec<u8>, Vec<Vec<u8>>>,
+    merge_operator: Option<MergeOperator>,
+    /// Decides on every live entry of a compaction, see with_compaction_filter
+    compaction_filter: Option<Box<dyn CompactionFilter>>,
+    /// Bytes of keys, values and merge operands put in memtable
+    memtable_bytes: usize,
+    /// Tables, newest first
+    tables: Vec<Arc<Table>>,
+    /// Bits per key of bloom filters of tables written, 0 for tables without filter
+    bits_per_key: usize,
+    compacting: bool,
+    stopping: bool,
+    /// Error of a background compaction, returned by the next put or flush
+    compaction_error: Option<StorageError>,
+}
+
+impl State {
+    fn compaction_due(&self, compaction_trigger: usize) -> bool {
+        !self.compacting
+            && self.compaction_error.is_none()
+            && self.tables.len() >= compaction_trigger
+    }
+    /// Write memtable as newest table, then list it in the manifest
+    /// - pending merges are folded into memtable entries first, see resolve_merges
+    fn flush_memtable(&mut self) -> Result<(), StorageError> {
+        self.resolve_merges()?;
+        let chunk_len = self.chunk_len();
+        let bits_per_key = self.bits_per_key;
+        let entries = self
+            .memtable
+            .iter()
+            .map(|(key, entry)| (&key[..], entry.as_deref()));
+        let table = match sstable::write_table(&mut self.storage, chunk_len, bits_per_key, entries)?
+        {
+            None => return Ok(()),
+            Some(table) => Arc::new(table),
+        };
+        let mut tables = vec![table.clone()];
+        tables.extend(self.tables.iter().cloned());
+        if let Err(error) = self.write_manifest(&tables) {
+            // failure to undo leaves an unreachable table, report the original error
+            let _ = table.delete(&mut self.storage);
+            return Err(error);
+        }
+        self.tables = tables;
+        self.memtable.clear();
+        self.memtable_bytes = 0;
+        Ok(())
+    }
+    /// Write manifest listing tables, then point anchor at it and delete the old one
+    fn write_manifest(&mut self, tables: &[Arc<Table>]) -> Result<(), StorageError> {
+        let mut manifest_bytes = checked_u32(tables.len())?.to_le_bytes().to_vec();
+        for table in tables {
+            manifest_bytes.extend_from_slice(&table.id.to_le_bytes());
+        }
+        let manifest = self.storage.write_record(&manifest_bytes)?;
+        if let Err(error) = self
+            .storage
+            .write_block(self.anchor, &manifest.to_le_bytes())
+        {
+            let _ = self.storage.delete_record(manifest, false);
+            return Err(error);
+        }
+        let old_manifest = std::mem::replace(&mut self.manifest, manifest);
+        self.storage.delete_record(old_manifest, false)?;
+        Ok(())
+    }
+    /// Entry of key in the newest table holding it
+    /// - returns: None if no table holds key, Some(None) if the newest holds a tombstone
+    fn table_entry(&mut self, key: &[u8]) -> Result<Option<Entry>, StorageError> {
+        for table in self.tables.iter() {
+            if let Some(entry) = table.get(&mut self.storage, key)? {
+                return Ok(Some(entry));
+            }
+        }
+        Ok(None)
+    }
+    /// Entry of key after folding operands into entry, in order
+    fn fold(&self, key: &[u8], mut entry: Entry, operands: &[Vec<u8>]) -> Entry {
+        if let Some(merge_operator) = self.merge_operator.as_ref() {
+            for operand in operands {
+                let value = merge_operator(key, entry.as_deref(), operand);
+                entry = Some(value).filter(|value| !value.is_empty());
+            }
+        }
+        entry
+    }
+    /// Fold pending merges into memtable entries, reading the value of each key from
+    /// the tables
+    /// - a failed read leaves the merges of keys not folded yet pending
+    fn resolve_merges(&mut self) -> Result<(), StorageError> {
+        while let Some(key) = self.merges.keys().next().cloned() {
+            let entry = self.table_entry(&key)?.flatten();
+            let operands = self.merges.remove(&key).unwrap_or_default();
+            let entry = self.fold(&key, entry, &operands);
+            self.memtable.insert(key, entry);
+        }
+        Ok(())
+    }
+    /// Apply op to memtable
+    /// - a merge of a key with memtable entry is folded right away, else it is pending
+    /// - returns: key of op with its memtable entry and merges before
+    fn apply(&mut self, op: BatchOp) -> Undo {
+        let (key, entry) = match op {
+            BatchOp::Put { key, value } => {
+                self.memtable_bytes = self
+                    .memtable_bytes
+                    .saturating_add(key.len())
+                    .saturating_add(value.len());
+                (key, Some(value))
+            }
+            BatchOp::Delete { key } => {
+                self.memtable_bytes = self.memtable_bytes.saturating_add(key.len());
+                (key, None)
+            }
+            BatchOp::Merge { key, operand } => {
+                self.memtable_bytes = self
+                    .memtable_bytes
+                    .saturating_add(key.len())
+                    .saturating_add(operand.len());
+                match self.memtable.get(&key) {
+                    Some(entry) => {
+                        let entry = self.fold(&key, entry.clone(), std::slice::from_ref(&operand));
+                        (key, entry)
+                    }
+                    None => {
+                        let merges = self.merges.get(&key).cloned();
+                        self.merges.entry(key.clone()).or_default().push(operand);
+                        return (key, None, merges);
+                    }
+                }
+            }
+        };
+        let merges = self.merges.remove(&key);
+        let previous = self.memtable.insert(key.clone(), entry);
+        (key, previous, merges)
+    }
+    /// Table chunks are cut at block_len bytes, most chunks fill a single block
+    fn chunk_len(&self) -> usize {
+        usize::try_from(self.storage.block_len()).unwrap_or(usize::MAX)
+    }
+}
+
+struct Shared {
+    state: Mutex<State>,
+    /// Wakes the compaction thread, after a flush or to stop it
+    wake: Condvar,
+}
+
+impl Shared {
+    fn lock(&self) -> MutexGuard<'_, State> {
+        // state is only changed after the storage writes it mirrors succeeded, a
+        // holder that panicked can not leave it half written
+        self.state
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+}
+
+// ... ... ... ... ... ... ... ... ... ... Lsm Store ... ... ... ... ... ... ... ... ... ...
+
+/// KvStore collecting puts in a memtable, flushed to the storage as sorted tables
+/// - a put only writes to the memtable until it holds memtable_bytes of keys and
+///   values, then the memtable is written as a new table in one go
+/// - get looks a key up in the memtable, then in tables from newest to oldest
+/// - once compaction_trigger tables are written, a compaction merges all tables into
+///   one, keeping the newest entry of each key and dropping deleted keys
+/// - compactions run on a background thread, started with the first compaction, or in
+///   the put that starts them, see with_background_compaction
+/// - anchor block holds record id of the manifest listing the tables, it identifies
+///   the store across opens
+/// - a batch goes to the memtable in one go, under the lock gets take; a flush it
+///   starts writes it to one table, if the flush fails it is taken out of the memtable
+/// - a merge of a key in the memtable is folded into its entry right away, else it is
+///   kept pending, get folds it into the value in the tables and a flush writes the
+///   folded value, so a merge reads nothing from the storage
+/// - NOTE: the memtable is not logged, puts since the last flush are lost if the
+///   process dies; close or drop the store, or call flush, to keep them
+pub struct LsmStore {
+    shared: Arc<Shared>,
+    memtable_limit: usize,
+    compaction_trigger: usize,
+    background_compaction: bool,
+    compactor: Option<JoinHandle<()>>,
+}
+
+impl LsmStore {
+    /// Create empty store in storage
+    /// - block_len of storage must be at least 8 bytes, to hold the anchor
+    pub fn create(mut storage: Storage) -> Result<Self, StorageError> {
+        let anchor_bytes = 0u64.to_le_bytes();
+        if storage.block_len() < anchor_bytes.len() as u64 {
+            return Err(StorageError::BlockTooLarge {
+                block_index: 0,
+                data_len: anchor_bytes.len(),
+                block_len: storage.block_len(),
+            });
+        }
+        let manifest = storage.write_record(&0u32.to_le_bytes())?;
+        let anchor = storage.write_record(&manifest.to_le_bytes())?;
+        Ok(LsmStore::with_state(storage, anchor, manifest, Vec::new()))
+    }
+    /// Open store created earlier, by its anchor block
+    pub fn open(mut storage: Storage, anchor: RecordId) -> Result<Self, StorageError> {
+        let malformed = |block_index| StorageError::Corruption {
+            block_index: Some(block_index),
+            reason: "lsm manifest is malformed",
+        };
+        let manifest = read_u64s(&storage.read_record(anchor)?)
+            .filter(|ids| ids.len() == 1)
+            .ok_or_else(|| malformed(anchor))?[0];
+        let manifest_bytes = storage.read_record(manifest)?;
+        let table_ids = manifest_bytes
+            .get(4..)
+            .and_then(read_u64s)
+            .filter(|ids| {
+                u32::try_from(ids.len()).is_ok_and(|len| manifest_bytes[..4] == len.to_le_bytes())
+            })
+            .ok_or_else(|| malformed(manifest))?;
+        let mut tables = Vec::with_capacity(table_ids.len());
+        for table_id in table_ids {
+            tables.push(Arc::new(Table::open(&mut storage, table_id)?));
+        }
+        Ok(LsmStore::with_state(storage, anchor, manifest, tables))
+    }
+    fn with_state(
+        storage: Storage,
+        anchor: RecordId,
+        manifest: RecordId,
+        tables: Vec<Arc<Table>>,
+    ) -> Self {
+        let state = State {
+            storage,
+            anchor,
+            manifest,
+            memtable: BTreeMap::new(),
+            merges: BTreeMap::new(),
+            merge_operator: None,
+            compaction_filter: None,
+            memtable_bytes: 0,
+            tables,
+            bits_per_key: DEFAULT_BITS_PER_KEY,
+            compacting: false,
+            stopping: false,
+            compaction_error: None,
+        };
+        LsmStore {
+            shared: Arc::new(Shared {
+                state: Mutex::new(state),
+                wake: Condvar::new(),
+            }),
+            memtable_limit: DEFAULT_MEMTABLE_BYTES,
+            compaction_trigger: DEFAULT_COMPACTION_TRIGGER,
+            background_compaction: true,
+            compactor: None,
+        }
+    }
+    /// Set bytes of keys and values the memtable collects before it is flushed
+    pub fn with_memtable_bytes(mut self, memtable_bytes: usize) -> Self {
+        self.memtable_limit = memtable_bytes;
+        self
+    }
+    /// Set tables that start a compaction, at least 2
+    pub fn with_compaction_trigger(mut self, compaction_trigger: usize) -> Self {
+        self.compaction_trigger = compaction_trigger.max(2);
+        self
+    }
+    /// Run compactions on a background thread, default, or in the put that flushes the
+    /// memtable
+    pub fn with_background_compaction(mut self, background_compaction: bool) -> Self {
+        self.background_compaction = background_compaction;
+        self
+    }
+    /// Set bits per key of the bloom filter of each table, 0 to write tables without
+    /// filter
+    /// - get skips a table whose filter rules a key out, without reading a chunk
+    /// - only affects tables written from now on
+    pub fn with_bloom_bits_per_key(self, bits_per_key: usize) -> Self {
+        self.shared.lock().bits_per_key = bits_per_key;
+        self
+    }
+    /// Set function folding merge operands into values, see KvStore::merge
+    pub fn with_merge_operator(self, merge_operator: MergeOperator) -> Self {
+        self.shared.lock().merge_operator = Some(merge_operator);
+        self
+    }
+    /// Pass the value of every live key a compaction merges to filter, which keeps,
+    /// drops or replaces it, see CompactionFilter::filter_entry
+    /// - e.g. to purge keys past a retention policy while tables are merged, instead of
+    ///   scanning for them
+    /// - compactions merge every table, a dropped key does not come back from an older
+    ///   table; memtable flushes are not filtered
+    /// - not stored in the storage, set it after every open
+    pub fn with_compaction_filter(self, filter: Box<dyn CompactionFilter>) -> Self {
+        self.shared.lock().compaction_filter = Some(filter);
+        self
+    }
+    /// Anchor block of store, to open it again with LsmStore::open
+    pub fn id(&self) -> RecordId {
+        self.shared.lock().anchor
+    }
+    /// Number of tables in storage
+    pub fn table_count(&self) -> usize {
+        self.shared.lock().tables.len()
+    }
+    /// Write memtable to a new table, starting a compaction if one is due
+    /// - also returns the error of a failed background compaction
+    pub fn flush(&mut self) -> Result<(), StorageError> {
+        let mut state = self.shared.lock();
+        if let Some(error) = state.compaction_error.take() {
+            return Err(error);
+        }
+        state.flush_memtable()?;
+        let compaction_due = state.compaction_due(self.compaction_trigger);
+        drop(state);
+        if compaction_due {
+            self.start_compaction()?;
+        }
+        Ok(())
+    }
+    /// Merge all tables into one now, waiting for a background compaction to end first
+    pub fn compact(&mut self) -> Result<(), StorageError> {
+        let mut state = self.shared.lock();
+        while state.compacting {
+            state = self
+                .shared
+                .wake
+                .wait(state)
+                .unwrap_or_else(|poisoned| poisoned.into_inner());
+        }
+        drop(state);
+        compact_tables(&self.shared, 2)
+    }
+    /// Flush memtable and stop compaction thread, returning errors a drop can not report
+    pub fn close(mut self) -> Result<(), StorageError> {
+        self.stop_compactor();
+        self.flush()?;
+        // - compaction started by the flush runs in the background
+        self.stop_compactor();
+        Ok(())
+    }
+    fn start_compaction(&mut self) -> Result<(), StorageError> {
+        if !self.background_compaction {
+            return compact_tables(&self.shared, self.compaction_trigger);
+        }
+        if self.compactor.is_none() {
+            let shared = self.shared.clone();
+            let compaction_trigger = self.compaction_trigger;
+            self.compactor = Some(thread::spawn(move || {
+                compactor(&shared, compaction_trigger)
+            }));
+        }
+        self.shared.wake.notify_all();
+        Ok(())
+    }
+    /// Stop compaction thread after its current compaction
+    fn stop_compactor(&mut self) {
+        if let Some(compactor) = self.compactor.take() {
+            self.shared.lock().stopping = true;
+            self.shared.wake.notify_all();
+            // a compactor that panicked has nothing left to stop
+            let _ = compactor.join();
+            self.shared.lock().stopping = false;
+        }
+    }
+}
+
+impl KvStore for LsmStore {
+    fn put(&mut self, key: &[u8], value: &[u8]) -> Result<(), StorageError> {
+        if value.is_empty() {
+            return Err(StorageError::EmptyRecord);
+        }
+        self.insert(key, Some(value))
+    }
+    fn get(&mut self, key: &[u8]) -> Result<Option<Vec<u8>>, StorageError> {
+        let mut state = self.shared.lock();
+        if let Some(entry) = state.memtable.get(key) {
+            return Ok(entry.clone());
+        }
+        let entry = state.table_entry(key)?.flatten();
+        match state.merges.get(key) {
+            Some(operands) => Ok(state.fold(key, entry, operands)),
+            None => Ok(entry),
+        }
+    }
+    fn delete(&mut self, key: &[u8]) -> Result<(), StorageError> {
+        self.insert(key, None)
+    }
+    fn write(&mut self, batch: WriteBatch) -> Result<(), StorageError> {
+        batch.check_values()?;
+        for op in batch.ops() {
+            let (key, value_len) = match op {
+                BatchOp::Put { key, value } => (key, value.len()),
+                BatchOp::Delete { key } => (key, 0),
+                BatchOp::Merge { key, .. } => {
+                    self.check_merge_operator()?;
+                    (key, 0)
+                }
+            };
+            checked_u32(key.len())?;
+            checked_u32(value_len)?;
+        }
+        self.apply_ops(batch.into_iter().collect())
+    }
+    fn merge(&mut self, key: &[u8], operand: &[u8]) -> Result<(), StorageError> {
+        self.check_merge_operator()?;
+        checked_u32(key.len())?;
+        self.apply_ops(vec![BatchOp::Merge {
+            key: key.to_vec(),
+            operand: operand.to_vec(),
+        }])
+    }
+}
+
+impl LsmStore {
+    /// Put entry of key in memtable, flushing it once it is full
+    /// - TooLarge if key or value is longer than u32::MAX bytes, tables store their
+    ///   lengths as u32
+    fn insert(&mut self, key: &[u8], value: Option<&[u8]>) -> Result<(), StorageError> {
+        checked_u32(key.len())?;
+        checked_u32(value.map_or(0, <[u8]>::len))?;
+        let op = match value {
+            Some(value) => BatchOp::Put {
+                key: key.to_vec(),
+                value: value.to_vec(),
+            },
+            None => BatchOp::Delete { key: key.to_vec() },
+        };
+        self.apply_ops(vec![op])
+    }
+    /// MergeOperatorMissing if no merge operator is set
+    fn check_merge_operator(&self) -> Result<(), StorageError> {
+        match self.shared.lock().merge_operator {
+            Some(_) => Ok(()),
+            None => Err(StorageError::MergeOperatorMissing),
+        }
+    }
+    /// Apply ops to memtable together, flushing it once it is full
+    /// - ops are taken out of the memtable again if the flush fails, entries and merges
+    ///   of their keys before are put back
+    fn apply_ops(&mut self, ops: Vec<BatchOp>) -> Result<(), StorageError> {
+        let mut state = self.shared.lock();
+        if let Some(error) = state.compaction_error.take() {
+            return Err(error);
+        }
+        let memtable_bytes = state.memtable_bytes;
+        let undo: Vec<Undo> = ops.into_iter().map(|op| state.apply(op)).collect();
+        if state.memtable_bytes < self.memtable_limit {
+            return Ok(());
+        }
+        if let Err(error) = state.flush_memtable() {
+            // - latest op of a key first, the state before its first op is put back last
+            for (key, previous, merges) in undo.into_iter().rev() {
+                match previous {
+                    Some(previous) => state.memtable.insert(key.clone(), previous),
+                    None => state.memtable.remove(&key),
+                };
+                match merges {
+                    Some(merges) => state.merges.insert(key, merges),
+                    None => state.merges.remove(&key),
+                };
+            }
+            state.memtable_bytes = memtable_bytes;
+            return Err(error);
+        }
+        let compaction_due = state.compaction_due(self.compaction_trigger);
+        drop(state);
+        if compaction_due {
+            self.start_compaction()?;
+        }
+        Ok(())
+    }
+}
+
+impl Drop for LsmStore {
+    fn drop(&mut self) {
+        self.stop_compactor();
+        // nothing to report an error to, use close to get it
+        let _ = self.shared.lock().flush_memtable();
+    }
+}
+
+// ... ... ... ... ... ... ... ... ... ... Compaction ... ... ... ... ... ... ... ... ... ...
+
+/// Loop of compaction thread, compacting whenever compaction_trigger tables are written
+fn compactor(shared: &Shared, compaction_trigger: usize) {
+    loop {
+        let mut state = shared.lock();
+        while !state.stopping && !state.compaction_due(compaction_trigger) {
+            state = shared
+                .wake
+                .wait(state)
+                .unwrap_or_else(|poisoned| poisoned.into_inner());
+        }
+        if state.stopping {
+            return;
+        }
+        drop(state);
+        if let Err(error) = compact_tables(shared, compaction_trigger) {
+            shared.lock().compaction_error = Some(error);
+        }
+    }
+}
+
+/// Merge all tables into one, if there are at least min_tables
+/// - the lock is taken for every entry merged, puts and gets go on meanwhile; tables
+///   flushed meanwhile are newer than all merged tables and are kept
+fn compact_tables(shared: &Shared, min_tables: usize) -> Result<(), StorageError> {
+    let inputs = {
+        let mut state = shared.lock();
+        if state.compacting || state.tables.len() < min_tables {
+            return Ok(());
+        }
+        state.compacting = true;
+        state.tables.clone()
+    };
+    let merged = merge_tables(shared, &inputs);
+    let mut state = shared.lock();
+    state.compacting = false;
+    shared.wake.notify_all();
+    let merged = merged?;
+    let kept_tables = state.tables.len() - inputs.len();
+    let mut tables: Vec<Arc<Table>> = state.tables[..kept_tables].to_vec();
+    tables.extend(merged.clone());
+    if let Err(error) = state.write_manifest(&tables) {
+        if let Some(merged) = merged {
+            // failure to undo leaves an unreachable table, report the original error
+            let _ = merged.delete(&mut state.storage);
+        }
+        return Err(error);
+    }
+    state.tables = tables;
+    for table in inputs.iter() {
+        table.delete(&mut state.storage)?;
+    }
+    Ok(())
+}
+
+/// Merge inputs, newest first, into a new table
+/// - returns: None if every key of inputs is deleted
+fn merge_tables(
+    shared: &Shared,
+    inputs: &[Arc<Table>],
+) -> Result<Option<Arc<Table>>, StorageError> {
+    let (chunk_len, bits_per_key) = {
+        let state = shared.lock();
+        (state.chunk_len(), state.bits_per_key)
+    };
+    let mut builder = TableBuilder::new(chunk_len, bits_per_key);
+    let mut cursors: Vec<TableCursor> =
+        inputs.iter().map(|table| TableCursor::new(table)).collect();
+    loop {
+        let mut state = shared.lock();
+        let state = &mut *state;
+        let filter = state.compaction_filter.as_deref_mut();
+        match merge_entry(&mut state.storage, filter, &mut cursors, &mut builder) {
+            Ok(true) => {}
+            Ok(false) => return Ok(builder.finish(&mut state.storage)?.map(Arc::new)),
+            Err(error) => {
+                builder.discard(&mut state.storage);
+                return Err(error);
+            }
+        }
+    }
+}
+
+/// Add smallest key of cursors to builder, with its entry in the newest table
+/// - deleted keys are left out, inputs of a compaction hold every older table
+/// - filter decides on the entry of a live key, see with_compaction_filter
+/// - returns: false if cursors hold no more entries
+fn merge_entry(
+    storage: &mut Storage,
+    filter: Option<&mut (dyn CompactionFilter + 'static)>,
+    cursors: &mut [TableCursor],
+    builder: &mut TableBuilder,
+) -> Result<bool, StorageError> {
+    let mut smallest: Option<Vec<u8>> = None;
+    for cursor in cursors.iter_mut() {
+        if let Some(key) = cursor.peek_key(storage)? {
+            if smallest.as_ref().is_none_or(|smallest| key < &smallest[..]) {
+                smallest = Some(key.to_vec());
+            }
+        }
+    }
+    let key = match smallest {
+        None => return Ok(false),
+        Some(key) => key,
+    };
+    // - cursors are newest first, first entry found is the newest
+    let mut newest = None;
+    for cursor in cursors.iter_mut() {
+        if cursor.peek_key(storage)? == Some(&key[..]) {
+            let entry = cursor.pop().and_then(|(_, entry)| entry);
+            newest.get_or_insert(entry);
+        }
+    }
+    let value = match newest {
+        Some(Some(value)) => value,
+        _ => return Ok(true),
+    };
+    let decision = match filter {
+        Some(filter) => filter.filter_entry(&key, &value),
+        None => FilterDecision::Keep,
+    };
+    match decision {
+        FilterDecision::Keep => builder.add(storage, &key, Some(&value))?,
+        FilterDecision::Drop => {}
+        FilterDecision::Replace(value) if value.is_empty() => {
+            return Err(StorageError::EmptyRecord)
+        }
+        FilterDecision::Replace(value) => builder.add(storage, &key, Some(&value))?,
+    }
+    Ok(true)
+}
+
+/// Parse little endian u64s, None if bytes are not a multiple of 8
+fn read_u64s(bytes: &[u8]) -> Option<Vec<u64>> {
+    if !bytes.len().is_multiple_of(8) {
+        return None;
+    }
+    let mut values = Vec::with_capacity(bytes.len() / 8);
+    for value_bytes in bytes.chunks(8) {
+        let mut le_bytes = [0u8; 8];
+        le_bytes.copy_from_slice(value_bytes);
+        values.push(u64::from_le_bytes(le_bytes));
+    }
+    Some(values)
+}
+
+#[cfg(test)]
+mod unit_tests_lsm {
+    use super::*;
+    use crate::storage::StorageOptions;
+
+    fn file_path(tmp_dir: &tempfile::TempDir) -> String {
+        let file_path = tmp_dir.path().join("lsm.hex");
+        file_path.to_str().unwrap().to_string()
+    }
+    fn key(index: u32) -> Vec<u8> {
+        format!("key-{:04}", index).into_bytes()
+    }
+
+    #[test]
+    fn test_lsm_store() {
+        let tmp_dir = tempfile::tempdir().unwrap();
+        let storage = Storage::new(file_path(&tmp_dir), 64).unwrap();
+        let mut store = LsmStore::create(storage)
+            .unwrap()
+            .with_memtable_bytes(200)
+            .with_compaction_trigger(3)
+            .with_background_compaction(false);
+        for index in 0..100u32 {
+            store.put(&key(index), &index.to_le_bytes()).unwrap();
+        }
+        // - overwrites and deletes land in newer tables than the entries they replace
+        for index in (0..100).step_by(3) {
+            store.put(&key(index), b"new").unwrap();
+        }
+        for index in (0..100).step_by(5) {
+            store.delete(&key(index)).unwrap();
+        }
+        assert!(store.table_count() < 3);
+        for index in 0..100u32 {
+            let expected = match index {
+                _ if index % 5 == 0 => None,
+                _ if index % 3 == 0 => Some(b"new".to_vec()),
+                _ => Some(index.to_le_bytes().to_vec()),
+            };
+            assert_eq!(store.get(&key(index)).unwrap(), expected);
+        }
+        assert_eq!(store.get(b"missing").unwrap(), None);
+        // - store opens again, memtable is flushed on close
+        let anchor = store.id();
+        store.close().unwrap();
+        let storage = Storage::open(file_path(&tmp_dir)).unwrap();
+        let mut store = LsmStore::open(storage, anchor).unwrap();
+        assert_eq!(store.get(&key(99)).unwrap(), Some(b"new".to_vec()));
+        assert_eq!(store.get(&key(95)).unwrap(), None);
+        // - compaction merges every table, keeping the newest entries
+        store.compact().unwrap();
+        assert_eq!(store.table_count(), 1);
+        assert_eq!(
+            store.get(&key(1)).unwrap(),
+            Some(1u32.to_le_bytes().to_vec())
+        );
+        assert_eq!(store.get(&key(10)).unwrap(), None);
+        // - key or value past u32::MAX bytes is rejected before it is copied, the zeroed
+        //   pages of long are never touched
+        let long = vec![0u8; u32::MAX as usize + 1];
+        assert!(matches!(
+            store.put(&long, b"v"),
+            Err(StorageError::TooLarge { .. })
+        ));
+        assert!(matches!(
+            store.put(b"k", &long),
+            Err(StorageError::TooLarge { .. })
+        ));
+        assert_eq!(store.get(b"k").unwrap(), None);
+    }
+    #[test]
+    fn test_background_compaction() {
+        let tmp_dir = tempfile::tempdir().unwrap();
+        let storage = Storage::new(file_path(&tmp_dir), 64).unwrap();
+        let mut store = LsmStore::create(storage)
+            .unwrap()
+            .with_memtable_bytes(100)
+            .with_compaction_trigger(2);
+        for round in 0..5u32 {
+            for index in 0..50 {
+                store.put(&key(index), &round.to_le_bytes()).unwrap();
+            }
+        }
+        for index in 0..50 {
+            assert_eq!(
+                store.get(&key(index)).unwrap(),
+                Some(4u32.to_le_bytes().to_vec())
+            );
+        }
+        let anchor = store.id();
+        drop(store);
+        // - drop waits for the compaction thread, then flushes the memtable
+        let storage = Storage::open(file_path(&tmp_dir)).unwrap();
+        let mut store = LsmStore::open(storage, anchor).unwrap();
+        for index in 0..50 {
+            assert_eq!(
+                store.get(&key(index)).unwrap(),
+                Some(4u32.to_le_bytes().to_vec())
+            );
+        }
+    }
+    #[test]
+    fn test_write_batch() {
+        let tmp_dir = tempfile::tempdir().unwrap();
+        let storage = Storage::new(file_path(&tmp_dir), 64).unwrap();
+        let mut store = LsmStore::create(storage)
+            .unwrap()
+            .with_memtable_bytes(100)
+            .with_background_compaction(false);
+        store.put(&key(0), b"old").unwrap();
+        let mut batch = WriteBatch::new();
+        batch
+            .put(&key(0), b"new")
+            .put(&key(1), b"one")
+            .delete(&key(2));
+        store.write(batch).unwrap();
+        assert_eq!(store.get(&key(0)).unwrap(), Some(b"new".to_vec()));
+        assert_eq!(store.get(&key(1)).unwrap(), Some(b"one".to_vec()));
+        let mut batch = WriteBatch::new();
+        batch.delete(&key(0)).put(&key(3), b"");
+        assert!(matches!(store.write(batch), Err(StorageError::EmptyRecord)));
+        assert_eq!(store.get(&key(0)).unwrap(), Some(b"new".to_vec()));
+        let anchor = store.id();
+        store.close().unwrap();
+        // - a batch whose flush fails is taken out of the memtable again
+        let storage = Storage::open(file_path(&tmp_dir)).unwrap();
+        let file_bytes = storage.file_bytes();
+        drop(storage);
+        let storage = StorageOptions::new(file_path(&tmp_dir))
+            .max_bytes(file_bytes)
+            .open()
+            .unwrap();
+        let mut store = LsmStore::open(storage, anchor)
+            .unwrap()
+            .with_memtable_bytes(100);
+        store.put(&key(4), b"four").unwrap();
+        let mut batch = WriteBatch::new();
+        batch.put(&key(0), &[0; 100]).put(&key(4), b"batch");
+        assert!(matches!(
+            store.write(batch),
+            Err(StorageError::QuotaExceeded { .. })
+        ));
+        assert_eq!(store.get(&key(0)).unwrap(), Some(b"new".to_vec()));
+        assert_eq!(store.get(&key(4)).unwrap(), Some(b"four".to_vec()));
+    }
+    /// Merge operator adding u32 operands to a u32 value
+    fn add() -> MergeOperator {
+        Box::new(|_key: &[u8], value: Option<&[u8]>, operand: &[u8]| {
+            let number = |bytes: &[u8]| u32::from_le_bytes(<[u8; 4]>::try_from(bytes).unwrap());
+            (value.map_or(0, number) + number(operand))
+                .to_le_bytes()
+                .to_vec()
+        })
+    }
+    #[test]
+    fn test_merge() {
+        let tmp_dir = tempfile::tempdir().unwrap();
+        let storage = Storage::new(file_path(&tmp_dir), 64).unwrap();
+        let mut store = LsmStore::create(storage)
+            .unwrap()
+            .with_memtable_bytes(200)
+            .with_background_compaction(false);
+        assert!(matches!(
+            store.merge(&key(0), &1u32.to_le_bytes()),
+            Err(StorageError::MergeOperatorMissing)
+        ));
+        let mut store = store.with_merge_operator(add());
+        store.put(&key(0), &10u32.to_le_bytes()).unwrap();
+        store.flush().unwrap();
+        // - key 0 is only in a table, its merges are pending until get or flush
+        for _ in 0..3 {
+            store.merge(&key(0), &1u32.to_le_bytes()).unwrap();
+            store.merge(&key(1), &2u32.to_le_bytes()).unwrap();
+        }
+        assert_eq!(store.shared.lock().merges.len(), 2);
+        assert_eq!(
+            store.get(&key(0)).unwrap(),
+            Some(13u32.to_le_bytes().to_vec())
+        );
+        assert_eq!(
+            store.get(&key(1)).unwrap(),
+            Some(6u32.to_le_bytes().to_vec())
+        );
+        // - a merge of a key in the memtable is folded right away
+        store.put(&key(2), &5u32.to_le_bytes()).unwrap();
+        let mut batch = WriteBatch::new();
+        batch
+            .merge(&key(2), &1u32.to_le_bytes())
+            .delete(&key(1))
+            .merge(&key(1), &7u32.to_le_bytes());
+        store.write(batch).unwrap();
+        assert_eq!(
+            store.get(&key(2)).unwrap(),
+            Some(6u32.to_le_bytes().to_vec())
+        );
+        assert_eq!(
+            store.get(&key(1)).unwrap(),
+            Some(7u32.to_le_bytes().to_vec())
+        );
+        // - flush writes folded values, the store opens again without merge operator
+        let anchor = store.id();
+        store.close().unwrap();
+        let storage = Storage::open(file_path(&tmp_dir)).unwrap();
+        let mut store = LsmStore::open(storage, anchor).unwrap();
+        assert_eq!(
+            store.get(&key(0)).unwrap(),
+            Some(13u32.to_le_bytes().to_vec())
+        );
+        assert_eq!(
+            store.get(&key(1)).unwrap(),
+            Some(7u32.to_le_bytes().to_vec())
+        );
+    }
+    /// Drops values whose first 8 bytes, an expiry, are before now, and shortens
+    /// values of keys starting with "trim"
+    struct ExpiryFilter {
+        now: u64,
+    }
+    impl CompactionFilter for ExpiryFilter {
+        fn filter_entry(&mut self, key: &[u8], value: &[u8]) -> FilterDecision {
+            let expires_at = u64::from_le_bytes(<[u8; 8]>::try_from(&value[..8]).unwrap());
+            if expires_at < self.now {
+                FilterDecision::Drop
+            } else if key.starts_with(b"trim") {
+                FilterDecision::Replace(value[..8].to_vec())
+            } else {
+                FilterDecision::Keep
+            }
+        }
+    }
+
+    #[test]
+    fn test_compaction_filter() {
+        let tmp_dir = tempfile::tempdir().unwrap();
+        let storage = Storage::new(file_path(&tmp_dir), 64).unwrap();
+        let mut store = LsmStore::create(storage)
+            .unwrap()
+            .with_memtable_bytes(100)
+            .with_compaction_trigger(100)
+            .with_background_compaction(false)
+            .with_compaction_filter(Box::new(ExpiryFilter { now: 50 }));
+        let value = |expires_at: u64| [&expires_at.to_le_bytes()[..], b"payload"].concat();
+        for index in 0..20u32 {
+            store
+                .put(&key(index), &value(u64::from(index) * 5))
+                .unwrap();
+        }
+        store.put(b"trim", &value(99)).unwrap();
+        // - flushes keep expired keys, the compaction drops them
+        store.flush().unwrap();
+        assert!(store.get(&key(0)).unwrap().is_some());
+        assert!(store.table_count() > 1);
+        store.compact().unwrap();
+        for index in 0..20u32 {
+            let expected = Some(value(u64::from(index) * 5)).filter(|_| index >= 10);
+            assert_eq!(store.get(&key(index)).unwrap(), expected);
+        }
+        assert_eq!(
+            store.get(b"trim").unwrap(),
+            Some(99u64.to_le_bytes().to_vec())
+        );
+    }
+    #[test]
+    fn test_open_malformed_manifest() {
+        let tmp_dir = tempfile::tempdir().unwrap();
+        let mut storage = Storage::new(file_path(&tmp_dir), 64).unwrap();
+        let anchor = storage.write_record(&[1, 2, 3]).unwrap();
+        assert!(matches!(
+            LsmStore::open(storage, anchor),
+            Err(StorageError::Corruption { .. })
+        ));
+    }
+}
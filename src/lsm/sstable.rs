@@ -0,0 +1,426 @@
+use super::bloom::{self, BloomFilter};
+use super::Entry;
+use crate::storage::{checked_u32, RecordId, Storage, StorageError};
+use std::collections::VecDeque;
+use std::convert::TryFrom;
+
+const TOMBSTONE: u8 = 0;
+const VALUE: u8 = 1;
+
+/// Sorted table of entries, written once and deleted whole by a compaction
+/// - entries are split in chunks of up to chunk_len bytes, each stored as a record
+/// - index record lists the first key and record of each chunk, then the last key of
+///   the table, it is held in memory while the table is open
+/// - chunk bytes: entries in key order, each key length (u32), key, kind (1 byte),
+///   and for a value value length (u32), value
+/// - index bytes: chunk count (u32), chunk count times (key length u32, first key,
+///   record u64), then key length (u32), last key, then the bloom filter record (u64)
+///   if the table has one
+/// - integers are little endian
+pub(super) struct Table {
+    /// Index record of table, listed in the manifest
+    pub(super) id: RecordId,
+    chunks: Vec<(Vec<u8>, RecordId)>,
+    last_key: Vec<u8>,
+    /// Record holding bloom filter of keys, and the filter read from it
+    filter: Option<(RecordId, BloomFilter)>,
+}
+
+impl Table {
+    /// Open table by its index record, reading its bloom filter
+    pub(super) fn open(storage: &mut Storage, id: RecordId) -> Result<Self, StorageError> {
+        let index_bytes = storage.read_record(id)?;
+        let (mut table, filter_record) =
+            parse_index(id, &index_bytes).ok_or(StorageError::Corruption {
+                block_index: Some(id),
+                reason: "table index is malformed",
+            })?;
+        if let Some(filter_record) = filter_record {
+            let filter_bytes = storage.read_record(filter_record)?;
+            let filter =
+                BloomFilter::from_bytes(&filter_bytes).ok_or(StorageError::Corruption {
+                    block_index: Some(filter_record),
+                    reason: "table bloom filter is malformed",
+                })?;
+            table.filter = Some((filter_record, filter));
+        }
+        Ok(table)
+    }
+    /// Entry of key in table
+    /// - keys the bloom filter rules out are not looked up in chunks
+    /// - returns: None if table does not hold key, Some(None) if it holds a tombstone
+    pub(super) fn get(
+        &self,
+        storage: &mut Storage,
+        key: &[u8],
+    ) -> Result<Option<Entry>, StorageError> {
+        let position = self
+            .chunks
+            .partition_point(|(first_key, _)| &first_key[..] <= key);
+        if position == 0 || key > &self.last_key[..] {
+            return Ok(None);
+        }
+        if let Some((_, filter)) = self.filter.as_ref() {
+            if !filter.may_contain(key) {
+                return Ok(None);
+            }
+        }
+        let entries = self.read_chunk(storage, position - 1)?;
+        Ok(entries
+            .binary_search_by(|(entry_key, _)| entry_key[..].cmp(key))
+            .ok()
+            .map(|found| entries[found].1.clone()))
+    }
+    fn read_chunk(
+        &self,
+        storage: &mut Storage,
+        position: usize,
+    ) -> Result<Vec<(Vec<u8>, Entry)>, StorageError> {
+        let chunk = self.chunks[position].1;
+        let chunk_bytes = storage.read_record(chunk)?;
+        parse_chunk(&chunk_bytes).ok_or(StorageError::Corruption {
+            block_index: Some(chunk),
+            reason: "table chunk is malformed",
+        })
+    }
+    /// Delete chunks, bloom filter and index record of table
+    pub(super) fn delete(&self, storage: &mut Storage) -> Result<(), StorageError> {
+        for (_, chunk) in self.chunks.iter() {
+            storage.delete_record(*chunk, false)?;
+        }
+        if let Some((filter_record, _)) = self.filter.as_ref() {
+            storage.delete_record(*filter_record, false)?;
+        }
+        storage.delete_record(self.id, false)?;
+        Ok(())
+    }
+}
+
+/// Entries of a table in key order, read a chunk at a time
+pub(super) struct TableCursor<'a> {
+    table: &'a Table,
+    next_chunk: usize,
+    entries: VecDeque<(Vec<u8>, Entry)>,
+}
+
+impl<'a> TableCursor<'a> {
+    pub(super) fn new(table: &'a Table) -> Self {
+        TableCursor {
+            table,
+            next_chunk: 0,
+            entries: VecDeque::new(),
+        }
+    }
+    /// Key of next entry, reading the next chunk if needed
+    pub(super) fn peek_key(
+        &mut self,
+        storage: &mut Storage,
+    ) -> Result<Option<&[u8]>, StorageError> {
+        while self.entries.is_empty() && self.next_chunk < self.table.chunks.len() {
+            self.entries = self.table.read_chunk(storage, self.next_chunk)?.into();
+            self.next_chunk += 1;
+        }
+        Ok(self.entries.front().map(|(key, _)| &key[..]))
+    }
+    /// Take next entry, call peek_key first to read it
+    pub(super) fn pop(&mut self) -> Option<(Vec<u8>, Entry)> {
+        self.entries.pop_front()
+    }
+}
+
+/// Table written entry by entry, in key order
+pub(super) struct TableBuilder {
+    chunk_len: usize,
+    chunk: Vec<u8>,
+    chunk_first_key: Vec<u8>,
+    chunks: Vec<(Vec<u8>, RecordId)>,
+    last_key: Vec<u8>,
+    /// Bits per key of bloom filter, 0 for a table without filter
+    bits_per_key: usize,
+    key_hashes: Vec<u64>,
+}
+
+impl TableBuilder {
+    pub(super) fn new(chunk_len: usize, bits_per_key: usize) -> Self {
+        TableBuilder {
+            chunk_len,
+            chunk: Vec::new(),
+            chunk_first_key: Vec::new(),
+            chunks: Vec::new(),
+            last_key: Vec::new(),
+            bits_per_key,
+            key_hashes: Vec::new(),
+        }
+    }
+    /// Add entry of key, keys must be added in ascending order
+    /// - writes the chunk so far if entry does not fit in it
+    pub(super) fn add(
+        &mut self,
+        storage: &mut Storage,
+        key: &[u8],
+        value: Option<&[u8]>,
+    ) -> Result<(), StorageError> {
+        let mut entry_bytes = Vec::with_capacity(key.len().saturating_add(9));
+        entry_bytes.extend_from_slice(&checked_u32(key.len())?.to_le_bytes());
+        entry_bytes.extend_from_slice(key);
+        match value {
+            None => entry_bytes.push(TOMBSTONE),
+            Some(value) => {
+                entry_bytes.push(VALUE);
+                entry_bytes.extend_from_slice(&checked_u32(value.len())?.to_le_bytes());
+                entry_bytes.extend_from_slice(value);
+            }
+        }
+        if !self.chunk.is_empty() && self.chunk.len() + entry_bytes.len() > self.chunk_len {
+            self.write_chunk(storage)?;
+        }
+        if self.chunk.is_empty() {
+            self.chunk_first_key = key.to_vec();
+        }
+        self.chunk.extend_from_slice(&entry_bytes);
+        self.last_key = key.to_vec();
+        if self.bits_per_key > 0 {
+            self.key_hashes.push(bloom::key_hash(key));
+        }
+        Ok(())
+    }
+    fn write_chunk(&mut self, storage: &mut Storage) -> Result<(), StorageError> {
+        let chunk = storage.write_record(&self.chunk)?;
+        self.chunks
+            .push((std::mem::take(&mut self.chunk_first_key), chunk));
+        self.chunk.clear();
+        Ok(())
+    }
+    /// Write last chunk, bloom filter and index record
+    /// - on failure, every record written for the table is deleted
+    /// - returns: None if no entry was added, nothing is written then
+    pub(super) fn finish(mut self, storage: &mut Storage) -> Result<Option<Table>, StorageError> {
+        if !self.chunk.is_empty() {
+            if let Err(error) = self.write_chunk(storage) {
+                self.discard(storage);
+                return Err(error);
+            }
+        }
+        if self.chunks.is_empty() {
+            return Ok(None);
+        }
+        let filter = match self.bits_per_key {
+            0 => None,
+            bits_per_key => {
+                let filter = BloomFilter::new(&self.key_hashes, bits_per_key);
+                match storage.write_record(&filter.to_bytes()) {
+                    Ok(filter_record) => Some((filter_record, filter)),
+                    Err(error) => {
+                        self.discard(storage);
+                        return Err(error);
+                    }
+                }
+            }
+        };
+        let filter_record = filter.as_ref().map(|(filter_record, _)| *filter_record);
+        let written = self
+            .index_bytes(filter_record)
+            .and_then(|index_bytes| storage.write_record(&index_bytes));
+        let id = match written {
+            Ok(id) => id,
+            Err(error) => {
+                if let Some((filter_record, _)) = filter {
+                    let _ = storage.delete_record(filter_record, false);
+                }
+                self.discard(storage);
+                return Err(error);
+            }
+        };
+        Ok(Some(Table {
+            id,
+            chunks: self.chunks,
+            last_key: self.last_key,
+            filter,
+        }))
+    }
+    /// Index record bytes
+    /// - chunk count (u32), then chunk count times (first key length u32, first key,
+    ///   chunk u64), then last key length (u32), last key, and filter record (u64) if any
+    fn index_bytes(&self, filter_record: Option<RecordId>) -> Result<Vec<u8>, StorageError> {
+        let mut index_bytes = Vec::new();
+        index_bytes.extend_from_slice(&checked_u32(self.chunks.len())?.to_le_bytes());
+        for (first_key, chunk) in self.chunks.iter() {
+            index_bytes.extend_from_slice(&checked_u32(first_key.len())?.to_le_bytes());
+            index_bytes.extend_from_slice(first_key);
+            index_bytes.extend_from_slice(&chunk.to_le_bytes());
+        }
+        index_bytes.extend_from_slice(&checked_u32(self.last_key.len())?.to_le_bytes());
+        index_bytes.extend_from_slice(&self.last_key);
+        if let Some(filter_record) = filter_record {
+            index_bytes.extend_from_slice(&filter_record.to_le_bytes());
+        }
+        Ok(index_bytes)
+    }
+    /// Delete chunks written so far, after a failed write
+    pub(super) fn discard(self, storage: &mut Storage) {
+        for (_, chunk) in self.chunks {
+            // failure to delete leaves an unreachable record, the caller reports the
+            // original error
+            let _ = storage.delete_record(chunk, false);
+        }
+    }
+}
+
+/// Write entries in key order as a table
+/// - returns: None if there are no entries
+pub(super) fn write_table<'a>(
+    storage: &mut Storage,
+    chunk_len: usize,
+    bits_per_key: usize,
+    entries: impl Iterator<Item = (&'a [u8], Option<&'a [u8]>)>,
+) -> Result<Option<Table>, StorageError> {
+    let mut builder = TableBuilder::new(chunk_len, bits_per_key);
+    for (key, value) in entries {
+        if let Err(error) = builder.add(storage, key, value) {
+            builder.discard(storage);
+            return Err(error);
+        }
+    }
+    builder.finish(storage)
+}
+
+struct ByteReader<'a> {
+    bytes: &'a [u8],
+    offset: usize,
+}
+
+impl<'a> ByteReader<'a> {
+    fn take(&mut self, len: usize) -> Option<&'a [u8]> {
+        let end = self.offset.checked_add(len)?;
+        let taken = self.bytes.get(self.offset..end)?;
+        self.offset = end;
+        Some(taken)
+    }
+    fn u32(&mut self) -> Option<u32> {
+        let mut le_bytes = [0u8; 4];
+        le_bytes.copy_from_slice(self.take(4)?);
+        Some(u32::from_le_bytes(le_bytes))
+    }
+    fn u64(&mut self) -> Option<u64> {
+        let mut le_bytes = [0u8; 8];
+        le_bytes.copy_from_slice(self.take(8)?);
+        Some(u64::from_le_bytes(le_bytes))
+    }
+    fn key(&mut self) -> Option<Vec<u8>> {
+        let key_len = usize::try_from(self.u32()?).ok()?;
+        Some(self.take(key_len)?.to_vec())
+    }
+    fn is_done(&self) -> bool {
+        self.offset == self.bytes.len()
+    }
+}
+
+fn parse_chunk(bytes: &[u8]) -> Option<Vec<(Vec<u8>, Entry)>> {
+    let mut reader = ByteReader { bytes, offset: 0 };
+    let mut entries = Vec::new();
+    while !reader.is_done() {
+        let key = reader.key()?;
+        let entry = match reader.take(1)?[0] {
+            TOMBSTONE => None,
+            VALUE => Some(reader.key()?),
+            _ => return None,
+        };
+        entries.push((key, entry));
+    }
+    Some(entries)
+}
+
+/// Parse index bytes, with record of bloom filter that is not read yet
+fn parse_index(id: RecordId, bytes: &[u8]) -> Option<(Table, Option<RecordId>)> {
+    let mut reader = ByteReader { bytes, offset: 0 };
+    let chunk_count = reader.u32()?;
+    let mut chunks = Vec::new();
+    for _ in 0..chunk_count {
+        chunks.push((reader.key()?, reader.u64()?));
+    }
+    let last_key = reader.key()?;
+    let filter_record = match reader.is_done() {
+        true => None,
+        false => Some(reader.u64()?),
+    };
+    if !reader.is_done() {
+        return None;
+    }
+    let table = Table {
+        id,
+        chunks,
+        last_key,
+        filter: None,
+    };
+    Some((table, filter_record))
+}
+
+#[cfg(test)]
+mod unit_tests_sstable {
+    use super::*;
+
+    #[test]
+    fn test_table() {
+        let tmp_dir = tempfile::tempdir().unwrap();
+        let file_path = tmp_dir.path().join("sstable.hex");
+        let mut storage = Storage::new(file_path.to_str().unwrap().to_string(), 32).unwrap();
+        let keys: Vec<Vec<u8>> = (0u8..20).map(|key| vec![b'k', key]).collect();
+        let entries = keys.iter().map(|key| {
+            let value = if key[1] % 5 == 0 {
+                None
+            } else {
+                Some(&key[..])
+            };
+            (&key[..], value)
+        });
+        let table = write_table(&mut storage, 32, 0, entries).unwrap().unwrap();
+        assert!(table.chunks.len() > 1);
+        let table = Table::open(&mut storage, table.id).unwrap();
+        assert_eq!(
+            table.get(&mut storage, b"k\x03").unwrap(),
+            Some(Some(b"k\x03".to_vec()))
+        );
+        assert_eq!(table.get(&mut storage, b"k\x05").unwrap(), Some(None));
+        assert_eq!(table.get(&mut storage, b"a").unwrap(), None);
+        assert_eq!(table.get(&mut storage, b"z").unwrap(), None);
+        // - cursor reads every entry in key order
+        let mut cursor = TableCursor::new(&table);
+        let mut read_keys = Vec::new();
+        while cursor.peek_key(&mut storage).unwrap().is_some() {
+            read_keys.push(cursor.pop().unwrap().0);
+        }
+        assert_eq!(read_keys, keys);
+        assert!(write_table(&mut storage, 32, 0, std::iter::empty())
+            .unwrap()
+            .is_none());
+        assert!(parse_index(0, &[1, 0, 0, 0]).is_none());
+    }
+    #[test]
+    fn test_table_bloom_filter() {
+        let tmp_dir = tempfile::tempdir().unwrap();
+        let file_path = tmp_dir.path().join("bloom.hex");
+        let mut storage = Storage::new(file_path.to_str().unwrap().to_string(), 64).unwrap();
+        let key = |index: u32| format!("key-{:04}", index).into_bytes();
+        let keys: Vec<Vec<u8>> = (0..200).step_by(2).map(key).collect();
+        let entries = keys.iter().map(|key| (&key[..], Some(&key[..])));
+        let table = write_table(&mut storage, 64, bloom::DEFAULT_BITS_PER_KEY, entries)
+            .unwrap()
+            .unwrap();
+        let table = Table::open(&mut storage, table.id).unwrap();
+        assert!(table.filter.is_some());
+        // - break every chunk, lookups that read a chunk fail
+        for (_, chunk) in table.chunks.iter() {
+            storage.write_block(*chunk, &[0xff]).unwrap();
+        }
+        assert!(table.get(&mut storage, &key(10)).is_err());
+        let skipped = (1..200)
+            .step_by(2)
+            .filter(|index| matches!(table.get(&mut storage, &key(*index)), Ok(None)))
+            .count();
+        assert!(skipped >= 90, "{} of 100 lookups skipped", skipped);
+        // - filter record is deleted with the table
+        let filter_record = table.filter.as_ref().unwrap().0;
+        table.delete(&mut storage).unwrap();
+        assert!(storage.read_record(filter_record).unwrap().is_empty());
+    }
+}
@@ -0,0 +1,237 @@
+//! Durable message queue on a storage file, with at-least-once delivery
+
+use crate::index::BTreeIndex;
+use crate::storage::{RecordId, Storage, StorageError};
+use std::collections::{BTreeMap, BTreeSet};
+
+/// Token of a dequeued message, pass it to DurableQueue::ack once the message is handled
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct AckToken {
+    sequence: u64,
+}
+
+impl AckToken {
+    /// Position of message in the queue, messages are dequeued in ascending order
+    pub fn sequence(&self) -> u64 {
+        self.sequence
+    }
+    /// Token of the message enqueued with sequence, for a producer that handles its
+    /// own messages without dequeueing them
+    pub(crate) fn of(sequence: u64) -> Self {
+        AckToken { sequence }
+    }
+}
+
+/// Queue of messages that stay in the storage until they are acknowledged
+/// - every message is a record, a BTreeIndex maps sequence numbers of pending messages
+///   to their records, ack removes the message from the index, then deletes it
+/// - anchor block holds anchor of the index and the next sequence number, so sequence
+///   numbers are not given out twice, also across restarts
+/// - dequeue hands out the oldest pending message that is not in flight; messages in
+///   flight are only tracked in memory, so after a restart every message that was not
+///   acknowledged is delivered again
+/// - release puts a message in flight back, e.g. after its handler failed
+pub struct DurableQueue {
+    storage: Storage,
+    anchor: RecordId,
+    index: BTreeIndex<u64>,
+    /// Records of pending messages by sequence number, as in the index
+    pending: BTreeMap<u64, RecordId>,
+    in_flight: BTreeSet<u64>,
+    next_sequence: u64,
+}
+
+impl DurableQueue {
+    /// Create empty queue in storage
+    /// - block_len of storage must be at least 16 bytes, to hold the anchor
+    pub fn create(mut storage: Storage) -> Result<Self, StorageError> {
+        if storage.block_len() < QUEUE_ANCHOR_LEN as u64 {
+            return Err(StorageError::BlockTooLarge {
+                block_index: 0,
+                data_len: QUEUE_ANCHOR_LEN,
+                block_len: storage.block_len(),
+            });
+        }
+        let index = BTreeIndex::create(&mut storage)?;
+        let anchor = storage.write_record(&anchor_bytes(&index, 0))?;
+        Ok(DurableQueue {
+            storage,
+            anchor,
+            index,
+            pending: BTreeMap::new(),
+            in_flight: BTreeSet::new(),
+            next_sequence: 0,
+        })
+    }
+    /// Open queue created earlier, by its anchor block
+    /// - every pending message is delivered again, none is in flight
+    pub fn open(mut storage: Storage, anchor: RecordId) -> Result<Self, StorageError> {
+        let anchor_bytes = storage.read_record(anchor)?;
+        if anchor_bytes.len() != QUEUE_ANCHOR_LEN {
+            return Err(StorageError::Corruption {
+                block_index: Some(anchor),
+                reason: "queue anchor is malformed",
+            });
+        }
+        let index = BTreeIndex::open(read_u64(&anchor_bytes[..8]));
+        let next_sequence = read_u64(&anchor_bytes[8..]);
+        let pending: BTreeMap<u64, RecordId> = index.range(&mut storage, ..)?.into_iter().collect();
+        Ok(DurableQueue {
+            storage,
+            anchor,
+            index,
+            pending,
+            in_flight: BTreeSet::new(),
+            next_sequence,
+        })
+    }
+    /// Anchor block of queue, to open it again with DurableQueue::open
+    pub fn id(&self) -> RecordId {
+        self.anchor
+    }
+    /// Number of messages not acknowledged yet, in flight or not
+    pub fn len(&self) -> usize {
+        self.pending.len()
+    }
+    /// Check if every message was acknowledged
+    pub fn is_empty(&self) -> bool {
+        self.pending.is_empty()
+    }
+    /// Number of messages dequeued and not acknowledged or released yet
+    pub fn in_flight(&self) -> usize {
+        self.in_flight.len()
+    }
+    /// Storage holding the queue
+    pub fn into_storage(self) -> Storage {
+        self.storage
+    }
+    /// Sync queue to disk, see Storage::flush
+    pub fn flush(&mut self) -> Result<(), StorageError> {
+        self.storage.flush()
+    }
+    /// Add message at the end of the queue
+    /// - empty messages fail with EmptyRecord, like record data
+    /// - next sequence number is written to the anchor first, a failed enqueue leaves a
+    ///   gap in sequence numbers
+    /// - returns: sequence number of message
+    pub fn enqueue(&mut self, bytes: &[u8]) -> Result<u64, StorageError> {
+        if bytes.is_empty() {
+            return Err(StorageError::EmptyRecord);
+        }
+        let sequence = self.next_sequence;
+        let next_sequence = sequence.saturating_add(1);
+        self.storage
+            .write_block(self.anchor, &anchor_bytes(&self.index, next_sequence))?;
+        self.next_sequence = next_sequence;
+        let message = self.storage.write_record(bytes)?;
+        if let Err(error) = self.index.insert(&mut self.storage, sequence, message) {
+            // failure to undo leaves an unreachable record, report the original error
+            let _ = self.storage.delete_record(message, false);
+            return Err(error);
+        }
+        self.pending.insert(sequence, message);
+        Ok(sequence)
+    }
+    /// Take oldest pending message that is not in flight, and put it in flight
+    /// - returns: None if every pending message is in flight
+    pub fn dequeue(&mut self) -> Result<Option<(AckToken, Vec<u8>)>, StorageError> {
+        let (sequence, message) = match self
+            .pending
+            .iter()
+            .find(|(sequence, _)| !self.in_flight.contains(sequence))
+        {
+            None => return Ok(None),
+            Some((sequence, message)) => (*sequence, *message),
+        };
+        let bytes = self.storage.read_record(message)?;
+        self.in_flight.insert(sequence);
+        Ok(Some((AckToken { sequence }, bytes)))
+    }
+    /// Acknowledge message, removing it from the queue for good
+    /// - returns: false if message was acknowledged before
+    pub fn ack(&mut self, token: AckToken) -> Result<bool, StorageError> {
+        let message = match self.pending.get(&token.sequence) {
+            None => return Ok(false),
+            Some(message) => *message,
+        };
+        self.index.remove(&mut self.storage, &token.sequence)?;
+        self.pending.remove(&token.sequence);
+        self.in_flight.remove(&token.sequence);
+        self.storage.delete_record(message, false)?;
+        Ok(true)
+    }
+    /// Put message in flight back, to be dequeued again before newer messages
+    /// - returns: false if message was not in flight
+    pub fn release(&mut self, token: AckToken) -> bool {
+        self.in_flight.remove(&token.sequence)
+    }
+}
+
+/// Bytes of a queue anchor, index anchor (u64) and next sequence number (u64)
+const QUEUE_ANCHOR_LEN: usize = 16;
+
+fn anchor_bytes(index: &BTreeIndex<u64>, next_sequence: u64) -> Vec<u8> {
+    let mut bytes = index.id().to_le_bytes().to_vec();
+    bytes.extend_from_slice(&next_sequence.to_le_bytes());
+    bytes
+}
+
+fn read_u64(bytes: &[u8]) -> u64 {
+    let mut le_bytes = [0u8; 8];
+    le_bytes.copy_from_slice(bytes);
+    u64::from_le_bytes(le_bytes)
+}
+
+#[cfg(test)]
+mod unit_tests_queue {
+    use super::*;
+
+    fn file_path(tmp_dir: &tempfile::TempDir) -> String {
+        let file_path = tmp_dir.path().join("queue.hex");
+        file_path.to_str().unwrap().to_string()
+    }
+
+    #[test]
+    fn test_durable_queue() {
+        let tmp_dir = tempfile::tempdir().unwrap();
+        let storage = Storage::new(file_path(&tmp_dir), 16).unwrap();
+        let mut queue = DurableQueue::create(storage).unwrap();
+        for message in [&b"one"[..], &b"two"[..], &[3; 40][..]] {
+            queue.enqueue(message).unwrap();
+        }
+        let (one, bytes) = queue.dequeue().unwrap().unwrap();
+        assert_eq!(bytes, b"one");
+        let (two, _) = queue.dequeue().unwrap().unwrap();
+        assert!(queue.ack(one).unwrap());
+        assert!(!queue.ack(one).unwrap());
+        // - released message is delivered again before newer messages
+        assert!(queue.release(two));
+        let (again, bytes) = queue.dequeue().unwrap().unwrap();
+        assert_eq!((again, bytes), (two, b"two".to_vec()));
+        let (three, bytes) = queue.dequeue().unwrap().unwrap();
+        assert_eq!(bytes, vec![3; 40]);
+        assert!(queue.dequeue().unwrap().is_none());
+        assert!(queue.ack(three).unwrap());
+        assert_eq!((queue.len(), queue.in_flight()), (1, 1));
+        // - message in flight and not acknowledged is delivered again after a restart
+        let anchor = queue.id();
+        drop(queue);
+        let storage = Storage::open(file_path(&tmp_dir)).unwrap();
+        let mut queue = DurableQueue::open(storage, anchor).unwrap();
+        assert_eq!(queue.in_flight(), 0);
+        let (two, bytes) = queue.dequeue().unwrap().unwrap();
+        assert_eq!((two.sequence(), bytes), (1, b"two".to_vec()));
+        assert_eq!(queue.enqueue(b"four").unwrap(), 3);
+        queue.ack(two).unwrap();
+        assert_eq!(queue.dequeue().unwrap().unwrap().1, b"four");
+        assert!(matches!(
+            DurableQueue::open(queue.into_storage(), 1),
+            Err(StorageError::Corruption { .. })
+        ));
+        let storage = Storage::new(file_path(&tmp_dir), 8).unwrap();
+        assert!(matches!(
+            DurableQueue::create(storage),
+            Err(StorageError::BlockTooLarge { data_len: 16, .. })
+        ));
+    }
+}
@@ -0,0 +1,480 @@
+//! Records with metadata, stored under ids that stay the same when records are rewritten
+
+use crate::index::{BTreeIndex, IndexKey, RecordIndex};
+use crate::storage::{checked_u32, Storage, StorageError};
+use std::collections::BTreeMap;
+use std::convert::TryFrom;
+
+/// Id of a record in a RecordStore, given out by the store in ascending order
+/// - unlike storage::RecordId, the first block of a record chain, it stays the same when
+///   the record is updated
+pub type RecordId = u64;
+
+/// Metadata key the serde helpers set to the encoding of record data
+pub const ENCODING_KEY: &str = "encoding";
+
+// ... ... ... ... ... ... ... ... ... ... Records ... ... ... ... ... ... ... ... ... ... .
+
+/// Bytes of a record with metadata, e.g. a content type, stored together in one record
+/// chain
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct Record {
+    pub data: Vec<u8>,
+    pub metadata: BTreeMap<String, String>,
+}
+
+impl Record {
+    /// Record of data, without metadata
+    pub fn new(data: Vec<u8>) -> Self {
+        Record {
+            data,
+            metadata: BTreeMap::new(),
+        }
+    }
+    /// Set metadata key to value
+    pub fn with_metadata(mut self, key: &str, value: &str) -> Self {
+        self.metadata.insert(key.to_string(), value.to_string());
+        self
+    }
+    /// Record bytes
+    /// - metadata count (u32), then metadata count times (key length u32, key, value
+    ///   length u32, value), then data
+    /// - integers are little endian
+    /// - TooLarge if a metadata key or value is longer than u32::MAX bytes
+    fn to_bytes(&self) -> Result<Vec<u8>, StorageError> {
+        let mut bytes = Vec::new();
+        bytes.extend_from_slice(&checked_u32(self.metadata.len())?.to_le_bytes());
+        for (key, value) in self.metadata.iter() {
+            for text in [key, value] {
+                bytes.extend_from_slice(&checked_u32(text.len())?.to_le_bytes());
+                bytes.extend_from_slice(text.as_bytes());
+            }
+        }
+        bytes.extend_from_slice(&self.data);
+        Ok(bytes)
+    }
+    /// Parse record bytes, None if malformed
+    fn from_bytes(bytes: &[u8]) -> Option<Self> {
+        let mut offset: usize = 0;
+        let mut take = |len: usize| {
+            let end = offset.checked_add(len)?;
+            let taken = bytes.get(offset..end)?;
+            offset = end;
+            Some(taken)
+        };
+        let metadata_count = u32::decode(take(4)?)?;
+        let mut metadata = BTreeMap::new();
+        for _ in 0..metadata_count {
+            let key_len = usize::try_from(u32::decode(take(4)?)?).ok()?;
+            let key = String::decode(take(key_len)?)?;
+            let value_len = usize::try_from(u32::decode(take(4)?)?).ok()?;
+            let value = String::decode(take(value_len)?)?;
+            metadata.insert(key, value);
+        }
+        Some(Record {
+            data: bytes[offset..].to_vec(),
+            metadata,
+        })
+    }
+    /// Check encoding set by a serde helper, records without one are decoded as encoding
+    #[cfg(any(feature = "bincode", feature = "cbor"))]
+    fn check_encoding(&self, encoding: &str) -> Result<(), StorageError> {
+        match self.metadata.get(ENCODING_KEY) {
+            Some(record_encoding) if record_encoding != encoding => {
+                Err(StorageError::Serialization {
+                    message: format!("record is encoded as {}, not {}", record_encoding, encoding),
+                })
+            }
+            _ => Ok(()),
+        }
+    }
+    /// Record of value encoded with bincode, with metadata encoding set to bincode
+    #[cfg(feature = "bincode")]
+    pub fn encode_bincode<T: serde::Serialize>(value: &T) -> Result<Self, StorageError> {
+        let data = bincode::serialize(value).map_err(|error| StorageError::Serialization {
+            message: error.to_string(),
+        })?;
+        Ok(Record::new(data).with_metadata(ENCODING_KEY, "bincode"))
+    }
+    /// Decode value from record data encoded with bincode
+    /// - Serialization if metadata sets another encoding
+    #[cfg(feature = "bincode")]
+    pub fn decode_bincode<T: serde::de::DeserializeOwned>(&self) -> Result<T, StorageError> {
+        self.check_encoding("bincode")?;
+        bincode::deserialize(&self.data).map_err(|error| StorageError::Serialization {
+            message: error.to_string(),
+        })
+    }
+    /// Record of value encoded as CBOR, with metadata encoding set to cbor
+    #[cfg(feature = "cbor")]
+    pub fn encode_cbor<T: serde::Serialize>(value: &T) -> Result<Self, StorageError> {
+        let mut data = Vec::new();
+        ciborium::ser::into_writer(value, &mut data).map_err(|error| {
+            StorageError::Serialization {
+                message: error.to_string(),
+            }
+        })?;
+        Ok(Record::new(data).with_metadata(ENCODING_KEY, "cbor"))
+    }
+    /// Decode value from record data encoded as CBOR
+    /// - Serialization if metadata sets another encoding
+    #[cfg(feature = "cbor")]
+    pub fn decode_cbor<T: serde::de::DeserializeOwned>(&self) -> Result<T, StorageError> {
+        self.check_encoding("cbor")?;
+        ciborium::de::from_reader(&self.data[..]).map_err(|error| StorageError::Serialization {
+            message: error.to_string(),
+        })
+    }
+}
+
+// ... ... ... ... ... ... ... ... ... ... Record Store ... ... ... ... ... ... ... ... ... .
+
+/// Records stored under ids given out by the store, mapped to their record chains by a
+/// BTreeIndex
+/// - anchor block holds anchor of the index and the next id, it identifies the store
+///   across opens
+/// - updates are copy-on-write: the record is written to new blocks, then the index
+///   points the id at them, then the old blocks are deleted
+/// - every method takes the storage, like BTreeIndex, so a store can share a storage
+///   file with indexes and other stores
+pub struct RecordStore {
+    anchor: crate::storage::RecordId,
+    index: BTreeIndex<RecordId>,
+    next_id: RecordId,
+}
+
+/// Bytes of a record store anchor, index anchor (u64) and next id (u64)
+const STORE_ANCHOR_LEN: usize = 16;
+
+impl RecordStore {
+    /// Create empty record store in storage
+    /// - block_len of storage must be at least 16 bytes, to hold the anchor
+    pub fn create(storage: &mut Storage) -> Result<Self, StorageError> {
+        if storage.block_len() < STORE_ANCHOR_LEN as u64 {
+            return Err(StorageError::BlockTooLarge {
+                block_index: 0,
+                data_len: STORE_ANCHOR_LEN,
+                block_len: storage.block_len(),
+            });
+        }
+        let index = BTreeIndex::create(storage)?;
+        let anchor = storage.write_record(&anchor_bytes(&index, 0))?;
+        Ok(RecordStore {
+            anchor,
+            index,
+            next_id: 0,
+        })
+    }
+    /// Open record store created earlier, by its anchor block
+    pub fn open(
+        storage: &mut Storage,
+        anchor: crate::storage::RecordId,
+    ) -> Result<Self, StorageError> {
+        let anchor_bytes = storage.read_record(anchor)?;
+        let malformed = StorageError::Corruption {
+            block_index: Some(anchor),
+            reason: "record store anchor is malformed",
+        };
+        if anchor_bytes.len() != STORE_ANCHOR_LEN {
+            return Err(malformed);
+        }
+        match (
+            u64::decode(&anchor_bytes[..8]),
+            u64::decode(&anchor_bytes[8..]),
+        ) {
+            (Some(index_anchor), Some(next_id)) => Ok(RecordStore {
+                anchor,
+                index: BTreeIndex::open(index_anchor),
+                next_id,
+            }),
+            _ => Err(malformed),
+        }
+    }
+    /// Anchor block of store, to open it again with RecordStore::open
+    pub fn id(&self) -> crate::storage::RecordId {
+        self.anchor
+    }
+    /// Store record under a new id
+    /// - next id is written to the anchor first, a failed insert leaves a gap in ids
+    /// - returns: id of record
+    pub fn insert(
+        &mut self,
+        storage: &mut Storage,
+        record: &Record,
+    ) -> Result<RecordId, StorageError> {
+        self.insert_indexed(storage, record, &mut [])
+    }
+    /// Store record under a new id, like insert, and add it to indexes
+    /// - record is written first, then indexes are updated, then the id is pointed at the
+    ///   record, a failed step undoes the steps before it
+    pub fn insert_indexed(
+        &mut self,
+        storage: &mut Storage,
+        record: &Record,
+        indexes: &mut [&mut dyn RecordIndex],
+    ) -> Result<RecordId, StorageError> {
+        let id = self.next_id;
+        let next_id = id.saturating_add(1);
+        storage.write_block(self.anchor, &anchor_bytes(&self.index, next_id))?;
+        self.next_id = next_id;
+        self.point_at_new_chain(storage, id, None, record, indexes)?;
+        Ok(id)
+    }
+    /// Record stored under id
+    pub fn get(&self, storage: &mut Storage, id: RecordId) -> Result<Option<Record>, StorageError> {
+        match self.index.get(storage, &id)? {
+            None => Ok(None),
+            Some(chain) => Ok(Some(read_chain(storage, chain)?)),
+        }
+    }
+    /// Replace record stored under id, keeping its id
+    /// - returns: record previously stored, None if no record is stored under id, nothing
+    ///   is written then
+    pub fn update(
+        &mut self,
+        storage: &mut Storage,
+        id: RecordId,
+        record: &Record,
+    ) -> Result<Option<Record>, StorageError> {
+        self.update_indexed(storage, id, record, &mut [])
+    }
+    /// Replace record stored under id, like update, and move it to its new keys in
+    /// indexes
+    /// - a failed update leaves the previous record and its index entries in place
+    pub fn update_indexed(
+        &mut self,
+        storage: &mut Storage,
+        id: RecordId,
+        record: &Record,
+        indexes: &mut [&mut dyn RecordIndex],
+    ) -> Result<Option<Record>, StorageError> {
+        let old_chain = match self.index.get(storage, &id)? {
+            None => return Ok(None),
+            Some(old_chain) => old_chain,
+        };
+        let previous = read_chain(storage, old_chain)?;
+        self.point_at_new_chain(storage, id, Some(&previous), record, indexes)?;
+        storage.delete_record(old_chain, false)?;
+        Ok(Some(previous))
+    }
+    /// Remove record stored under id, and delete its blocks
+    /// - returns: record removed
+    pub fn remove(
+        &mut self,
+        storage: &mut Storage,
+        id: RecordId,
+    ) -> Result<Option<Record>, StorageError> {
+        self.remove_indexed(storage, id, &mut [])
+    }
+    /// Remove record stored under id, like remove, and drop it from indexes
+    pub fn remove_indexed(
+        &mut self,
+        storage: &mut Storage,
+        id: RecordId,
+        indexes: &mut [&mut dyn RecordIndex],
+    ) -> Result<Option<Record>, StorageError> {
+        let chain = match self.index.get(storage, &id)? {
+            None => return Ok(None),
+            Some(chain) => chain,
+        };
+        let removed = read_chain(storage, chain)?;
+        update_indexes(storage, indexes, id, Some(&removed), None)?;
+        if let Err(error) = self.index.remove(storage, &id) {
+            undo_indexes(storage, indexes, id, Some(&removed), None);
+            return Err(error);
+        }
+        storage.delete_record(chain, false)?;
+        Ok(Some(removed))
+    }
+    /// Write record to a new chain, update indexes from previous record, then point id
+    /// at the new chain
+    /// - on failure, indexes are updated back and the new chain is deleted
+    fn point_at_new_chain(
+        &mut self,
+        storage: &mut Storage,
+        id: RecordId,
+        previous: Option<&Record>,
+        record: &Record,
+        indexes: &mut [&mut dyn RecordIndex],
+    ) -> Result<(), StorageError> {
+        let chain = storage.write_record(&record.to_bytes()?)?;
+        if let Err(error) = update_indexes(storage, indexes, id, previous, Some(record)) {
+            // failure to undo leaves an unreachable record, report the original error
+            let _ = storage.delete_record(chain, false);
+            return Err(error);
+        }
+        if let Err(error) = self.index.insert(storage, id, chain) {
+            undo_indexes(storage, indexes, id, previous, Some(record));
+            let _ = storage.delete_record(chain, false);
+            return Err(error);
+        }
+        Ok(())
+    }
+    /// Ids of all records in store, in ascending order
+    pub fn ids(&self, storage: &mut Storage) -> Result<Vec<RecordId>, StorageError> {
+        let entries = self.index.range(storage, ..)?;
+        Ok(entries.into_iter().map(|(id, _)| id).collect())
+    }
+}
+
+/// Update every index from old to new record of id
+/// - on failure, indexes updated before are updated back
+fn update_indexes(
+    storage: &mut Storage,
+    indexes: &mut [&mut dyn RecordIndex],
+    id: RecordId,
+    old: Option<&Record>,
+    new: Option<&Record>,
+) -> Result<(), StorageError> {
+    for position in 0..indexes.len() {
+        if let Err(error) = indexes[position].update(storage, id, old, new) {
+            undo_indexes(storage, &mut indexes[..position], id, old, new);
+            return Err(error);
+        }
+    }
+    Ok(())
+}
+
+/// Update indexes back from new to old record of id, after a later step failed
+fn undo_indexes(
+    storage: &mut Storage,
+    indexes: &mut [&mut dyn RecordIndex],
+    id: RecordId,
+    old: Option<&Record>,
+    new: Option<&Record>,
+) {
+    for index in indexes.iter_mut().rev() {
+        // failure to undo leaves a stale index entry, report the original error
+        let _ = index.update(storage, id, new, old);
+    }
+}
+
+fn anchor_bytes(index: &BTreeIndex<RecordId>, next_id: RecordId) -> Vec<u8> {
+    let mut bytes = index.id().to_le_bytes().to_vec();
+    bytes.extend_from_slice(&next_id.to_le_bytes());
+    bytes
+}
+
+fn read_chain(
+    storage: &mut Storage,
+    chain: crate::storage::RecordId,
+) -> Result<Record, StorageError> {
+    let bytes = storage.read_record(chain)?;
+    Record::from_bytes(&bytes).ok_or(StorageError::Corruption {
+        block_index: Some(chain),
+        reason: "record metadata is malformed",
+    })
+}
+
+#[cfg(test)]
+mod unit_tests_record {
+    use super::*;
+
+    fn new_storage(tmp_dir: &tempfile::TempDir) -> (Storage, String) {
+        let file_path = tmp_dir.path().join("record.hex");
+        let file_path = file_path.to_str().unwrap().to_string();
+        (Storage::new(file_path.clone(), 16).unwrap(), file_path)
+    }
+
+    #[test]
+    fn test_record_bytes() {
+        let record = Record::new(vec![1, 2, 3]).with_metadata("type", "point");
+        assert_eq!(
+            Record::from_bytes(&record.to_bytes().unwrap()).unwrap(),
+            record
+        );
+        assert_eq!(
+            Record::from_bytes(&Record::default().to_bytes().unwrap()).unwrap(),
+            Record::default()
+        );
+        // - metadata entry is cut short
+        assert!(Record::from_bytes(&[1, 0, 0, 0, 4, 0, 0, 0, b'a']).is_none());
+    }
+    #[test]
+    fn test_record_store() {
+        let tmp_dir = tempfile::tempdir().unwrap();
+        let (mut storage, file_path) = new_storage(&tmp_dir);
+        let mut store = RecordStore::create(&mut storage).unwrap();
+        let first = Record::new(vec![1; 40]).with_metadata("type", "long");
+        let second = Record::new(vec![2]);
+        assert_eq!(store.insert(&mut storage, &first).unwrap(), 0);
+        assert_eq!(store.insert(&mut storage, &second).unwrap(), 1);
+        assert_eq!(store.get(&mut storage, 0).unwrap(), Some(first.clone()));
+        assert_eq!(store.get(&mut storage, 2).unwrap(), None);
+        // - update keeps the id
+        let third = Record::new(vec![3; 4]);
+        assert_eq!(store.update(&mut storage, 0, &third).unwrap(), Some(first));
+        assert_eq!(store.get(&mut storage, 0).unwrap(), Some(third));
+        assert_eq!(store.update(&mut storage, 5, &second).unwrap(), None);
+        // - removed id is not given out again
+        assert_eq!(store.remove(&mut storage, 1).unwrap(), Some(second.clone()));
+        assert_eq!(store.remove(&mut storage, 1).unwrap(), None);
+        assert_eq!(store.insert(&mut storage, &second).unwrap(), 2);
+        assert_eq!(store.ids(&mut storage).unwrap(), vec![0, 2]);
+        // - store opens again by its anchor
+        let anchor = store.id();
+        drop(storage);
+        let mut storage = Storage::open(file_path).unwrap();
+        let mut store = RecordStore::open(&mut storage, anchor).unwrap();
+        assert_eq!(store.get(&mut storage, 2).unwrap(), Some(second));
+        assert_eq!(
+            store.insert(&mut storage, &Record::new(vec![4])).unwrap(),
+            3
+        );
+    }
+    #[test]
+    fn test_record_store_small_blocks() {
+        let tmp_dir = tempfile::tempdir().unwrap();
+        let file_path = tmp_dir.path().join("small.hex");
+        let mut storage = Storage::new(file_path.to_str().unwrap().to_string(), 8).unwrap();
+        assert!(matches!(
+            RecordStore::create(&mut storage),
+            Err(StorageError::BlockTooLarge { data_len: 16, .. })
+        ));
+    }
+    #[cfg(any(feature = "bincode", feature = "cbor"))]
+    #[derive(Debug, PartialEq, serde::Serialize, serde::Deserialize)]
+    struct Point {
+        name: String,
+        x: i32,
+        y: i32,
+    }
+    #[cfg(feature = "bincode")]
+    #[test]
+    fn test_bincode_records() {
+        let tmp_dir = tempfile::tempdir().unwrap();
+        let (mut storage, _) = new_storage(&tmp_dir);
+        let mut store = RecordStore::create(&mut storage).unwrap();
+        let point = Point {
+            name: String::from("origin"),
+            x: 0,
+            y: -1,
+        };
+        let record = Record::encode_bincode(&point).unwrap();
+        assert_eq!(record.metadata.get(ENCODING_KEY).unwrap(), "bincode");
+        let id = store.insert(&mut storage, &record).unwrap();
+        let record = store.get(&mut storage, id).unwrap().unwrap();
+        assert_eq!(record.decode_bincode::<Point>().unwrap(), point);
+        assert!(matches!(
+            Record::new(vec![1]).decode_bincode::<Point>(),
+            Err(StorageError::Serialization { .. })
+        ));
+    }
+    #[cfg(feature = "cbor")]
+    #[test]
+    fn test_cbor_records() {
+        let point = Point {
+            name: String::from("corner"),
+            x: 3,
+            y: 4,
+        };
+        let record = Record::encode_cbor(&point).unwrap();
+        assert_eq!(record.decode_cbor::<Point>().unwrap(), point);
+        // - record encoded as cbor is not decoded as bincode
+        #[cfg(feature = "bincode")]
+        assert!(matches!(
+            record.decode_bincode::<Point>(),
+            Err(StorageError::Serialization { .. })
+        ));
+    }
+}
@@ -0,0 +1,206 @@
+use super::*;
+use std::time::Duration;
+
+/// Engine metrics as answered to a STATS request, see EngineMetrics
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ServerStats {
+    /// Read requests served
+    pub reads: u64,
+    /// Write requests served
+    pub writes: u64,
+    /// Delete requests served
+    pub deletes: u64,
+    /// Requests that failed
+    pub errors: u64,
+    /// Record data written
+    pub bytes_in: u64,
+    /// Record data read
+    pub bytes_out: u64,
+    /// Requests waiting in the engine queue
+    pub queue_len: u64,
+}
+
+/// Connection to a Server, sending one request at a time and waiting for its response
+/// - errors of the server are returned as StorageError::Remote with their message
+/// - network failures and malformed responses are returned as StorageError::Io, the
+///   connection should be dropped after them
+pub struct Client {
+    stream: TcpStream,
+    /// Trace id sent with every request, see set_trace_id
+    trace_id: Option<String>,
+}
+
+impl Client {
+    pub fn connect(addr: impl ToSocketAddrs) -> Result<Client, StorageError> {
+        let stream = TcpStream::connect(addr).map_err(net_error("connect to server"))?;
+        stream
+            .set_nodelay(true)
+            .map_err(net_error("connect to server"))?;
+        Ok(Client {
+            stream,
+            trace_id: None,
+        })
+    }
+    /// Send trace_id with every following request, None to stop tracing them
+    /// - requests are served as traced requests, see IORequest::traced, and their error
+    ///   messages end with the trace id
+    pub fn set_trace_id(&mut self, trace_id: Option<String>) {
+        self.trace_id = trace_id;
+    }
+    /// Read all data of a record, empty if the record does not exist
+    pub fn read(&mut self, record_id: RecordId) -> Result<Vec<u8>, StorageError> {
+        let mut request = vec![OP_READ];
+        request.extend_from_slice(&record_id.to_le_bytes());
+        self.request(&request)
+    }
+    /// Write data as a new record
+    /// - returns: record id
+    pub fn write(&mut self, data: &[u8]) -> Result<RecordId, StorageError> {
+        self.write_expiring(data, None)
+    }
+    /// Write data as a new record, expiring at expires_at, see EngineHandle::write_expiring
+    pub fn write_expiring(
+        &mut self,
+        data: &[u8],
+        expires_at: Option<u64>,
+    ) -> Result<RecordId, StorageError> {
+        let mut request = vec![OP_WRITE, expires_at.is_some() as u8];
+        request.extend_from_slice(&expires_at.unwrap_or(0).to_le_bytes());
+        request.extend_from_slice(data);
+        let response = self.request(&request)?;
+        response_u64s(&response, 1).map(|ids| ids[0])
+    }
+    /// Delete all blocks of a record
+    /// - returns: number of deleted blocks
+    pub fn delete(
+        &mut self,
+        record_id: RecordId,
+        hard_delete: bool,
+    ) -> Result<usize, StorageError> {
+        let mut request = vec![OP_DELETE];
+        request.extend_from_slice(&record_id.to_le_bytes());
+        request.push(hard_delete as u8);
+        let response = self.request(&request)?;
+        let deleted_blocks = response_u64s(&response, 1)?[0];
+        usize::try_from(deleted_blocks).map_err(|_| malformed_response())
+    }
+    /// Metrics of the engine behind the server
+    pub fn stats(&mut self) -> Result<ServerStats, StorageError> {
+        let response = self.request(&[OP_STATS])?;
+        let stats = response_u64s(&response, 7)?;
+        Ok(ServerStats {
+            reads: stats[0],
+            writes: stats[1],
+            deletes: stats[2],
+            errors: stats[3],
+            bytes_in: stats[4],
+            bytes_out: stats[5],
+            queue_len: stats[6],
+        })
+    }
+    /// Requests queued and being served by the engine behind the server, see
+    /// EngineHandle::pending
+    pub fn pending(&mut self) -> Result<Vec<PendingRequest>, StorageError> {
+        let response = self.request(&[OP_PENDING])?;
+        let mut body = ResponseReader(&response);
+        let count = body.u64()?;
+        let mut pending = Vec::new();
+        for _ in 0..count {
+            pending.push(PendingRequest {
+                id: body.u64()?,
+                kind: body.coded(&REQUEST_KINDS)?,
+                state: body.coded(&PENDING_STATES)?,
+                priority: body.coded(&PRIORITIES)?,
+                age: Duration::from_micros(body.u64()?),
+                size: body.u64()?,
+                storage: body.text()?,
+                trace_id: body.text()?,
+            });
+        }
+        if !body.0.is_empty() {
+            return Err(malformed_response());
+        }
+        Ok(pending)
+    }
+    /// Send request and wait for its response
+    /// - returns: response body, without status byte
+    fn request(&mut self, request: &[u8]) -> Result<Vec<u8>, StorageError> {
+        let mut traced;
+        let request = match self.trace_id.as_ref() {
+            Some(trace_id) => {
+                traced = vec![OP_TRACED];
+                traced.extend_from_slice(&(trace_id.len() as u32).to_le_bytes());
+                traced.extend_from_slice(trace_id.as_bytes());
+                traced.extend_from_slice(request);
+                &traced
+            }
+            None => request,
+        };
+        write_frame(&mut self.stream, request).map_err(net_error("send request"))?;
+        let mut response = read_frame(&mut self.stream)
+            .map_err(net_error("receive response"))?
+            .ok_or_else(|| net_error("receive response")(io::ErrorKind::UnexpectedEof.into()))?;
+        match response.first() {
+            Some(&STATUS_OK) => {
+                response.remove(0);
+                Ok(response)
+            }
+            Some(&STATUS_ERROR) => Err(StorageError::Remote {
+                message: String::from_utf8_lossy(&response[1..]).into_owned(),
+            }),
+            _ => Err(malformed_response()),
+        }
+    }
+}
+
+/// count u64s of response body
+fn response_u64s(response: &[u8], count: usize) -> Result<Vec<u64>, StorageError> {
+    if response.len() != count * 8 {
+        return Err(malformed_response());
+    }
+    Ok(response
+        .chunks_exact(8)
+        .filter_map(|bytes| <[u8; 8]>::try_from(bytes).ok())
+        .map(u64::from_le_bytes)
+        .collect())
+}
+
+/// Fields of a response body, read from the front
+struct ResponseReader<'a>(&'a [u8]);
+
+impl ResponseReader<'_> {
+    fn take(&mut self, len: usize) -> Result<&[u8], StorageError> {
+        if self.0.len() < len {
+            return Err(malformed_response());
+        }
+        let (field, rest) = self.0.split_at(len);
+        self.0 = rest;
+        Ok(field)
+    }
+    fn u64(&mut self) -> Result<u64, StorageError> {
+        let bytes = self.take(8)?;
+        Ok(response_u64s(bytes, 1)?[0])
+    }
+    /// Value of a code byte, see codes of PENDING
+    fn coded<T: Copy>(&mut self, codes: &[T]) -> Result<T, StorageError> {
+        let code = self.take(1)?[0];
+        codes
+            .get(code as usize)
+            .copied()
+            .ok_or_else(malformed_response)
+    }
+    /// Length prefixed UTF-8, None if empty
+    fn text(&mut self) -> Result<Option<String>, StorageError> {
+        let len_bytes = <[u8; 4]>::try_from(self.take(4)?).map_err(|_| malformed_response())?;
+        let text = self.take(u32::from_le_bytes(len_bytes) as usize)?;
+        let text = String::from_utf8(text.to_vec()).map_err(|_| malformed_response())?;
+        Ok(Some(text).filter(|text| !text.is_empty()))
+    }
+}
+
+fn malformed_response() -> StorageError {
+    net_error("receive response")(io::Error::new(
+        io::ErrorKind::InvalidData,
+        "malformed response",
+    ))
+}
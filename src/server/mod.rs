@@ -0,0 +1,606 @@
+//! Engine requests over TCP, in a length-prefixed binary protocol
+//!
+//! # Protocol
+//! Every message is a frame: payload length as u32, then the payload.
+//! Integers are little endian, like in storage files.
+//!
+//! Request payload is an opcode byte followed by its arguments:
+//! - `READ` (1): record id u64
+//! - `WRITE` (2): expiry flag u8, expiry u64 in seconds since UNIX epoch (ignored if flag is 0),
+//!   then record data
+//! - `DELETE` (3): record id u64, hard delete flag u8
+//! - `STATS` (4): no arguments
+//! - `PENDING` (5): no arguments
+//! - `TRACED` (6): trace id length u32, trace id as UTF-8, then the payload of a request
+//!   of another opcode, served as a traced request, see IORequest::traced
+//!
+//! Response payload is a status byte, 0 for ok and 1 for error, followed by:
+//! - `READ`: record data
+//! - `WRITE`: record id u64
+//! - `DELETE`: number of deleted blocks u64
+//! - `STATS`: reads, writes, deletes, errors, bytes in, bytes out and queue length, u64 each
+//! - `PENDING`: number of requests u64, then per request queued or being served: id u64,
+//!   kind u8 (read 0, read blocks 1, write 2, delete 3, scan blocks 4, compact step 5,
+//!   commit 6), state u8 (queued 0, serving 1), priority u8 (high 0, normal 1,
+//!   background 2), age u64 in microseconds, size u64, then storage name and trace id,
+//!   each as length u32 followed by UTF-8, empty if none
+//! - `TRACED`: the response of the request it carries
+//! - error: message as UTF-8, of a traced request ending in "(trace id <id>)"
+//!
+//! Requests of one TCP connection are answered in order, one at a time.
+//!
+//! # Pipelining
+//! Connections of a UnixServer may send requests without waiting for responses. Their
+//! request and response payloads start with a request id u64 chosen by the client,
+//! followed by the payload above. Responses are sent as requests are served, which may
+//! be out of order, each tagged with the id of its request.
+use crate::engine::metrics::OpKind;
+use crate::engine::{
+    EngineHandle, IORequest, PendingRequest, PendingState, Priority, ReadResponse, RequestKind,
+    ResultSender, WriteResponse,
+};
+use crate::storage::{RecordId, Storage, StorageError};
+use std::convert::TryFrom;
+use std::io::{self, Read, Write};
+use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr, TcpListener, TcpStream, ToSocketAddrs};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{mpsc, Arc};
+use std::thread;
+
+mod client;
+pub use client::{Client, ServerStats};
+#[cfg(unix)]
+mod unix;
+#[cfg(unix)]
+pub use unix::UnixServer;
+
+/// Largest payload accepted in a frame, larger frames close the connection
+pub const MAX_FRAME_LEN: u32 = 64 * 1024 * 1024;
+
+const OP_READ: u8 = 1;
+const OP_WRITE: u8 = 2;
+const OP_DELETE: u8 = 3;
+const OP_STATS: u8 = 4;
+const OP_PENDING: u8 = 5;
+const OP_TRACED: u8 = 6;
+
+const STATUS_OK: u8 = 0;
+const STATUS_ERROR: u8 = 1;
+
+/// TCP listener serving requests of every connection on one background Engine
+/// - see the module documentation for the protocol
+pub struct Server {
+    listener: TcpListener,
+    engine: Arc<EngineHandle>,
+    stopped: Arc<AtomicBool>,
+}
+
+/// Stops a Server running serve on another thread, see Server::stop_handle
+#[derive(Clone)]
+pub struct StopHandle {
+    wake: Wake,
+    stopped: Arc<AtomicBool>,
+}
+
+/// Address a StopHandle connects to, to wake serve
+#[derive(Clone)]
+enum Wake {
+    Tcp(SocketAddr),
+    #[cfg(unix)]
+    Unix(std::path::PathBuf),
+}
+
+impl StopHandle {
+    /// Make serve stop accepting connections
+    /// - serve returns once connections already accepted are closed by their clients
+    pub fn stop(&self) {
+        self.stopped.store(true, Ordering::SeqCst);
+        // - wake serve, blocked in accept
+        match &self.wake {
+            Wake::Tcp(addr) => drop(TcpStream::connect(addr)),
+            #[cfg(unix)]
+            Wake::Unix(path) => drop(std::os::unix::net::UnixStream::connect(path)),
+        }
+    }
+}
+
+impl Server {
+    /// Listen on addr for requests to engine, e.g. "127.0.0.1:7000"
+    /// - port 0 picks a free port, see local_addr
+    pub fn bind(addr: impl ToSocketAddrs, engine: EngineHandle) -> Result<Server, StorageError> {
+        let listener = TcpListener::bind(addr).map_err(net_error("bind server socket"))?;
+        Ok(Server {
+            listener,
+            engine: Arc::new(engine),
+            stopped: Arc::new(AtomicBool::new(false)),
+        })
+    }
+    /// Address the server listens on
+    pub fn local_addr(&self) -> Result<SocketAddr, StorageError> {
+        self.listener
+            .local_addr()
+            .map_err(net_error("get server address"))
+    }
+    /// Handle to stop serve from another thread
+    pub fn stop_handle(&self) -> Result<StopHandle, StorageError> {
+        let mut addr = self.local_addr()?;
+        // - a wildcard address can not be connected to on every platform
+        if addr.ip().is_unspecified() {
+            addr.set_ip(match addr {
+                SocketAddr::V4(_) => Ipv4Addr::LOCALHOST.into(),
+                SocketAddr::V6(_) => Ipv6Addr::LOCALHOST.into(),
+            });
+        }
+        Ok(StopHandle {
+            wake: Wake::Tcp(addr),
+            stopped: self.stopped.clone(),
+        })
+    }
+    /// Accept connections and serve their requests, each connection on its own thread
+    /// - requests of all connections share the queue of the engine
+    /// - a connection is closed on IO errors and malformed frames, a malformed request in
+    ///   a well formed frame is answered with an error and the connection stays open
+    /// - runs until stopped through a StopHandle
+    /// - returns: storage of the engine, once every connection is closed, or None if the
+    ///   engine thread panicked
+    pub fn serve(self) -> Result<Option<Storage>, StorageError> {
+        let Server {
+            listener,
+            engine,
+            stopped,
+        } = self;
+        Ok(serve_connections(
+            listener.incoming(),
+            &stopped,
+            engine,
+            serve_connection,
+        ))
+    }
+}
+
+/// Serve every accepted connection with serve, each on its own thread, until stopped
+/// - returns: storage of engine, once every connection is closed, see Server::serve
+fn serve_connections<S: Send + 'static>(
+    incoming: impl Iterator<Item = io::Result<S>>,
+    stopped: &AtomicBool,
+    engine: Arc<EngineHandle>,
+    serve: fn(S, &EngineHandle) -> io::Result<()>,
+) -> Option<Storage> {
+    let mut connections: Vec<thread::JoinHandle<io::Result<()>>> = Vec::new();
+    for stream in incoming {
+        if stopped.load(Ordering::SeqCst) {
+            break;
+        }
+        // - failed accepts, e.g. a connection reset before it was accepted, are skipped
+        let stream = match stream {
+            Ok(stream) => stream,
+            Err(_) => continue,
+        };
+        let engine = engine.clone();
+        let connection = thread::spawn(move || serve(stream, &engine));
+        connections.retain(|connection| !connection.is_finished());
+        connections.push(connection);
+    }
+    for connection in connections {
+        let _ = connection.join();
+    }
+    Arc::try_unwrap(engine)
+        .ok()
+        .and_then(|engine| engine.join())
+}
+
+/// Answer requests of one connection until the client closes it, one at a time
+fn serve_connection(mut stream: TcpStream, engine: &EngineHandle) -> io::Result<()> {
+    stream.set_nodelay(true)?;
+    while let Some(request) = read_frame(&mut stream)? {
+        let (sender, receiver) = mpsc::channel();
+        let responder = Responder::new(move |response| {
+            let _ = sender.send(response);
+        });
+        queue_request(&request, engine, responder);
+        let response = receiver
+            .recv()
+            .unwrap_or_else(|_| Err(StorageError::EngineStopped.to_string()));
+        write_frame(&mut stream, &encode_response(&[], response))?;
+    }
+    Ok(())
+}
+
+/// Response body without status byte, or error message
+type Response = Result<Vec<u8>, String>;
+
+/// Response payload: prefix, status byte, then body or error message
+fn encode_response(prefix: &[u8], response: Response) -> Vec<u8> {
+    let (status, body) = match response {
+        Ok(body) => (STATUS_OK, body),
+        Err(message) => (STATUS_ERROR, message.into_bytes()),
+    };
+    let mut payload = Vec::with_capacity(prefix.len() + 1 + body.len());
+    payload.extend_from_slice(prefix);
+    payload.push(status);
+    payload.extend_from_slice(&body);
+    payload
+}
+
+/// Sends the response of a request once, see queue_request
+/// - a responder dropped without responding, e.g. together with its request by a stopped
+///   engine, responds with EngineStopped
+struct Responder(Option<Box<dyn FnOnce(Response) + Send>>);
+
+impl Responder {
+    fn new(respond: impl FnOnce(Response) + Send + 'static) -> Self {
+        Responder(Some(Box::new(respond)))
+    }
+    fn respond(mut self, response: Response) {
+        if let Some(respond) = self.0.take() {
+            respond(response)
+        }
+    }
+    /// Result sender of an IORequest, responding with the encoded result
+    fn result_sender<T: Send + 'static>(self, encode: fn(T) -> Vec<u8>) -> ResultSender<T> {
+        ResultSender::new(move |result| {
+            self.respond(result.map(encode).map_err(|error| error.to_string()))
+        })
+    }
+}
+
+impl Drop for Responder {
+    fn drop(&mut self) {
+        if let Some(respond) = self.0.take() {
+            respond(Err(StorageError::EngineStopped.to_string()))
+        }
+    }
+}
+
+/// Operation of a request payload, see module documentation
+enum Operation {
+    Read {
+        record_id: RecordId,
+    },
+    Write {
+        data: Vec<u8>,
+        expires_at: Option<u64>,
+    },
+    Delete {
+        record_id: RecordId,
+        hard_delete: bool,
+    },
+    Stats,
+    Pending,
+}
+
+impl Operation {
+    /// Parse request payload, error message if it is malformed
+    fn parse(request: &[u8]) -> Result<Operation, String> {
+        let (opcode, arguments) = request.split_first().ok_or(MALFORMED_REQUEST)?;
+        match *opcode {
+            OP_READ => Ok(Operation::Read {
+                record_id: get_u64(arguments, 0)?,
+            }),
+            OP_WRITE => {
+                let has_expiry = *arguments.first().ok_or(MALFORMED_REQUEST)? != 0;
+                let expires_at = Some(get_u64(arguments, 1)?).filter(|_| has_expiry);
+                Ok(Operation::Write {
+                    data: arguments[9..].to_vec(),
+                    expires_at,
+                })
+            }
+            OP_DELETE => Ok(Operation::Delete {
+                record_id: get_u64(arguments, 0)?,
+                hard_delete: *arguments.get(8).ok_or(MALFORMED_REQUEST)? != 0,
+            }),
+            OP_STATS => Ok(Operation::Stats),
+            OP_PENDING => Ok(Operation::Pending),
+            _ => Err(MALFORMED_REQUEST.to_string()),
+        }
+    }
+}
+
+/// Queue request on engine, responder gets its response once the request is served
+/// - returns right away, without waiting for the engine
+/// - STATS, PENDING and malformed requests are answered right away
+fn queue_request(request: &[u8], engine: &EngineHandle, responder: Responder) {
+    let (trace_id, request) = match split_trace_id(request) {
+        Ok(traced) => traced,
+        Err(message) => return responder.respond(Err(message)),
+    };
+    let io_request = match Operation::parse(request) {
+        Err(message) => return responder.respond(Err(message)),
+        Ok(Operation::Stats) => return responder.respond(Ok(stats(engine))),
+        Ok(Operation::Pending) => return responder.respond(Ok(pending(engine))),
+        Ok(Operation::Read { record_id }) => IORequest::Read {
+            record_id,
+            result: responder.result_sender(|response: ReadResponse| response.data),
+        },
+        Ok(Operation::Write { data, expires_at }) => IORequest::Write {
+            data,
+            expires_at,
+            result: responder
+                .result_sender(|response: WriteResponse| response.record_id.to_le_bytes().to_vec()),
+        },
+        Ok(Operation::Delete {
+            record_id,
+            hard_delete,
+        }) => IORequest::Delete {
+            record_id,
+            hard_delete,
+            result: responder.result_sender(|deleted_blocks: usize| {
+                (deleted_blocks as u64).to_le_bytes().to_vec()
+            }),
+        },
+    };
+    let io_request = match trace_id {
+        Some(trace_id) => io_request.traced(trace_id),
+        None => io_request,
+    };
+    engine.send_with_priority(io_request, Priority::Normal);
+}
+
+/// Trace id of a TRACED request payload and the payload it carries, or payload as is
+fn split_trace_id(request: &[u8]) -> Result<(Option<String>, &[u8]), String> {
+    if request.first() != Some(&OP_TRACED) {
+        return Ok((None, request));
+    }
+    let len_bytes = request.get(1..5).ok_or(MALFORMED_REQUEST)?;
+    let len = u32::from_le_bytes(<[u8; 4]>::try_from(len_bytes).map_err(|_| MALFORMED_REQUEST)?);
+    let trace_id = request
+        .get(5..5 + len as usize)
+        .and_then(|trace_id| std::str::from_utf8(trace_id).ok())
+        .ok_or(MALFORMED_REQUEST)?;
+    Ok((Some(trace_id.to_string()), &request[5 + len as usize..]))
+}
+
+/// Response body of STATS
+fn stats(engine: &EngineHandle) -> Vec<u8> {
+    let metrics = engine.metrics();
+    let stats = [
+        metrics.served(OpKind::Read),
+        metrics.served(OpKind::Write),
+        metrics.served(OpKind::Delete),
+        metrics.errors(),
+        metrics.bytes_in(),
+        metrics.bytes_out(),
+        metrics.queue_len(),
+    ];
+    stats.iter().flat_map(|n| n.to_le_bytes()).collect()
+}
+
+/// Response body of PENDING
+fn pending(engine: &EngineHandle) -> Vec<u8> {
+    let pending = engine.pending();
+    let mut body = (pending.len() as u64).to_le_bytes().to_vec();
+    for request in pending {
+        body.extend_from_slice(&request.id.to_le_bytes());
+        body.push(code_of(&REQUEST_KINDS, request.kind));
+        body.push(code_of(&PENDING_STATES, request.state));
+        body.push(code_of(&PRIORITIES, request.priority));
+        let age = u64::try_from(request.age.as_micros()).unwrap_or(u64::MAX);
+        body.extend_from_slice(&age.to_le_bytes());
+        body.extend_from_slice(&request.size.to_le_bytes());
+        for text in [&request.storage, &request.trace_id].iter() {
+            let text = text.as_deref().unwrap_or("").as_bytes();
+            body.extend_from_slice(&(text.len() as u32).to_le_bytes());
+            body.extend_from_slice(text);
+        }
+    }
+    body
+}
+
+/// Codes of PENDING response fields, the index of each value
+const REQUEST_KINDS: [RequestKind; 7] = [
+    RequestKind::Read,
+    RequestKind::ReadBlocks,
+    RequestKind::Write,
+    RequestKind::Delete,
+    RequestKind::ScanBlocks,
+    RequestKind::CompactStep,
+    RequestKind::Commit,
+];
+const PENDING_STATES: [PendingState; 2] = [PendingState::Queued, PendingState::Serving];
+const PRIORITIES: [Priority; 3] = [Priority::High, Priority::Normal, Priority::Background];
+
+/// Code of value, its index in codes
+fn code_of<T: PartialEq>(codes: &[T], value: T) -> u8 {
+    codes.iter().position(|code| *code == value).unwrap_or(0) as u8
+}
+
+/// Error message of requests with unknown opcode or missing arguments
+const MALFORMED_REQUEST: &str = "malformed request";
+
+/// u64 at offset of request arguments
+fn get_u64(bytes: &[u8], offset: usize) -> Result<u64, String> {
+    bytes
+        .get(offset..offset + 8)
+        .and_then(|bytes| <[u8; 8]>::try_from(bytes).ok())
+        .map(u64::from_le_bytes)
+        .ok_or_else(|| MALFORMED_REQUEST.to_string())
+}
+
+/// Read one frame, None if the stream ended before it
+/// - InvalidData if payload length exceeds MAX_FRAME_LEN
+pub(crate) fn read_frame(stream: &mut impl Read) -> io::Result<Option<Vec<u8>>> {
+    let mut len_bytes = [0u8; 4];
+    match stream.read_exact(&mut len_bytes) {
+        Ok(()) => {}
+        Err(error) if error.kind() == io::ErrorKind::UnexpectedEof => return Ok(None),
+        Err(error) => return Err(error),
+    }
+    let len = u32::from_le_bytes(len_bytes);
+    if len > MAX_FRAME_LEN {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "frame exceeds MAX_FRAME_LEN",
+        ));
+    }
+    let mut payload = vec![0u8; len as usize];
+    stream.read_exact(&mut payload)?;
+    Ok(Some(payload))
+}
+
+/// Write payload as one frame
+/// - InvalidInput if payload exceeds MAX_FRAME_LEN
+pub(crate) fn write_frame(stream: &mut impl Write, payload: &[u8]) -> io::Result<()> {
+    let len = u32::try_from(payload.len())
+        .ok()
+        .filter(|len| *len <= MAX_FRAME_LEN)
+        .ok_or_else(|| {
+            io::Error::new(io::ErrorKind::InvalidInput, "frame exceeds MAX_FRAME_LEN")
+        })?;
+    let mut frame = Vec::with_capacity(4 + payload.len());
+    frame.extend_from_slice(&len.to_le_bytes());
+    frame.extend_from_slice(payload);
+    stream.write_all(&frame)?;
+    stream.flush()
+}
+
+/// Map io::Error of network operation into StorageError::Io
+pub(crate) fn net_error(operation: &'static str) -> impl FnOnce(io::Error) -> StorageError {
+    move |source| StorageError::Io {
+        operation,
+        block_index: None,
+        source,
+    }
+}
+
+#[cfg(test)]
+mod unit_tests_server {
+    use super::*;
+    use crate::engine::{Engine, RequestBuilder};
+    use crate::storage::{BlockIndex, BlockObserver};
+
+    /// Signals every write, then waits until it is released
+    struct BlockingObserver {
+        writing: mpsc::Sender<()>,
+        release: mpsc::Receiver<()>,
+    }
+    impl BlockObserver for BlockingObserver {
+        fn before_write(&mut self, _block_index: BlockIndex, _data: &[u8]) {
+            let _ = self.writing.send(());
+            let _ = self.release.recv();
+        }
+    }
+
+    fn new_server(tmp_dir: &tempfile::TempDir) -> Server {
+        let file_path = tmp_dir.path().join("server.hex");
+        let storage = Storage::new(file_path.to_str().unwrap().to_string(), 4).unwrap();
+        Server::bind("127.0.0.1:0", Engine::spawn(storage)).unwrap()
+    }
+
+    #[test]
+    fn test_client_requests() {
+        let tmp_dir = tempfile::tempdir().unwrap();
+        let server = new_server(&tmp_dir);
+        let addr = server.local_addr().unwrap();
+        let stop_handle = server.stop_handle().unwrap();
+        let serve = thread::spawn(move || server.serve());
+        let mut client = Client::connect(addr).unwrap();
+        let record_id = client.write(&[1, 2, 3, 4, 5]).unwrap();
+        assert_eq!(client.read(record_id).unwrap(), vec![1, 2, 3, 4, 5]);
+        // - second connection shares the engine
+        let mut other = Client::connect(addr).unwrap();
+        let other_id = other.write_expiring(&[6], Some(u64::MAX)).unwrap();
+        assert_ne!(other_id, record_id);
+        assert_eq!(client.read(other_id).unwrap(), vec![6]);
+        assert_eq!(client.delete(record_id, true).unwrap(), 2);
+        assert!(client.read(record_id).unwrap().is_empty());
+        // - errors are answered, connection stays open
+        assert!(matches!(
+            client.write(&[]),
+            Err(StorageError::Remote { ref message }) if message == "Record data is empty"
+        ));
+        let stats = client.stats().unwrap();
+        assert_eq!((stats.reads, stats.writes, stats.deletes), (3, 3, 1));
+        assert_eq!(stats.errors, 1);
+        // - traced requests, their errors name the trace id
+        client.set_trace_id(Some("client-1".to_string()));
+        assert_eq!(client.read(other_id).unwrap(), vec![6]);
+        assert!(matches!(
+            client.write(&[]),
+            Err(StorageError::Remote { ref message })
+                if message == "Record data is empty (trace id client-1)"
+        ));
+        client.set_trace_id(None);
+        drop(other);
+        drop(client);
+        stop_handle.stop();
+        let mut storage = serve.join().unwrap().unwrap().unwrap();
+        assert_eq!(storage.read_record(other_id).unwrap(), vec![6]);
+    }
+    #[test]
+    fn test_client_pending() {
+        let tmp_dir = tempfile::tempdir().unwrap();
+        let file_path = tmp_dir.path().join("server.hex");
+        let mut storage = Storage::new(file_path.to_str().unwrap().to_string(), 4).unwrap();
+        let (writing, writing_receiver) = mpsc::channel();
+        let (release, release_receiver) = mpsc::channel();
+        storage.add_observer(Box::new(BlockingObserver {
+            writing,
+            release: release_receiver,
+        }));
+        let server = Server::bind("127.0.0.1:0", Engine::spawn(storage)).unwrap();
+        let addr = server.local_addr().unwrap();
+        let stop_handle = server.stop_handle().unwrap();
+        let engine = server.engine.clone();
+        let serve = thread::spawn(move || server.serve());
+        let mut client = Client::connect(addr).unwrap();
+        assert!(client.pending().unwrap().is_empty());
+        // - a write of another connection blocks in its observer while it is served
+        let writer = thread::spawn(move || Client::connect(addr).unwrap().write(&[1; 3]));
+        writing_receiver.recv().unwrap();
+        let (request, _receiver) = RequestBuilder::write(vec![2; 5], None)
+            .on("events")
+            .traced("trace-1")
+            .build();
+        let handle = engine.send_with_priority(request, Priority::Background);
+        let pending = client.pending().unwrap();
+        assert_eq!(pending.len(), 2);
+        assert_eq!(
+            (pending[0].kind, pending[0].state, pending[0].size),
+            (RequestKind::Write, PendingState::Serving, 3)
+        );
+        assert_eq!(
+            (pending[0].storage.as_ref(), pending[0].trace_id.as_ref()),
+            (None, None)
+        );
+        assert_eq!(pending[1].id, handle.id());
+        assert_eq!(
+            (pending[1].state, pending[1].priority, pending[1].size),
+            (PendingState::Queued, Priority::Background, 5)
+        );
+        assert_eq!(pending[1].storage.as_deref(), Some("events"));
+        assert_eq!(pending[1].trace_id.as_deref(), Some("trace-1"));
+        release.send(()).unwrap();
+        drop(release);
+        assert!(writer.join().unwrap().is_ok());
+        drop(engine);
+        drop(client);
+        stop_handle.stop();
+        assert!(serve.join().unwrap().unwrap().is_some());
+    }
+    #[test]
+    fn test_malformed_frames() {
+        let tmp_dir = tempfile::tempdir().unwrap();
+        let server = new_server(&tmp_dir);
+        let addr = server.local_addr().unwrap();
+        let stop_handle = server.stop_handle().unwrap();
+        let serve = thread::spawn(move || server.serve());
+        let mut stream = TcpStream::connect(addr).unwrap();
+        let requests = [
+            &[][..],
+            &[OP_READ, 1, 2][..],
+            &[9][..],
+            &[OP_TRACED, 9, 0, 0, 0, b'a'][..],
+            &[OP_TRACED, 0, 0, 0, 0, OP_TRACED, 0, 0, 0, 0][..],
+        ];
+        for request in requests.iter() {
+            write_frame(&mut stream, request).unwrap();
+            let response = read_frame(&mut stream).unwrap().unwrap();
+            assert_eq!(response, b"\x01malformed request".to_vec());
+        }
+        // - oversized frame closes the connection
+        stream.write_all(&u32::MAX.to_le_bytes()).unwrap();
+        assert!(read_frame(&mut stream).unwrap().is_none());
+        stop_handle.stop();
+        assert!(serve.join().unwrap().unwrap().is_some());
+    }
+}
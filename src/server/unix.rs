@@ -0,0 +1,145 @@
+use super::*;
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::{Path, PathBuf};
+
+/// Unix domain socket listener with pipelined requests, on one background Engine
+/// - every request is queued as soon as it is read, so requests in flight on all
+///   connections are served together in the next io_cycle of the engine
+/// - see the module documentation for the protocol
+pub struct UnixServer {
+    listener: UnixListener,
+    path: PathBuf,
+    engine: Arc<EngineHandle>,
+    stopped: Arc<AtomicBool>,
+}
+
+impl UnixServer {
+    /// Listen on a socket file at path for requests to engine
+    /// - fails if a file exists at path, e.g. the socket of a server that did not stop
+    ///   cleanly, remove it first
+    pub fn bind(path: impl AsRef<Path>, engine: EngineHandle) -> Result<UnixServer, StorageError> {
+        let path = path.as_ref().to_path_buf();
+        let listener = UnixListener::bind(&path).map_err(net_error("bind server socket"))?;
+        Ok(UnixServer {
+            listener,
+            path,
+            engine: Arc::new(engine),
+            stopped: Arc::new(AtomicBool::new(false)),
+        })
+    }
+    /// Handle to stop serve from another thread
+    pub fn stop_handle(&self) -> StopHandle {
+        StopHandle {
+            wake: Wake::Unix(self.path.clone()),
+            stopped: self.stopped.clone(),
+        }
+    }
+    /// Accept connections and serve their requests, like Server::serve
+    /// - a connection is closed on IO errors and malformed frames, including frames
+    ///   without request id, after responses to requests already queued are sent
+    /// - socket file is removed once serve stops accepting connections
+    /// - returns: storage of the engine, once every connection is closed, or None if the
+    ///   engine thread panicked
+    pub fn serve(self) -> Result<Option<Storage>, StorageError> {
+        let UnixServer {
+            listener,
+            path,
+            engine,
+            stopped,
+        } = self;
+        let storage = serve_connections(listener.incoming(), &stopped, engine, serve_pipelined);
+        std::fs::remove_file(&path).map_err(net_error("remove server socket"))?;
+        Ok(storage)
+    }
+}
+
+/// Queue requests of one connection as they arrive, until the client closes it
+/// - responses are written by a second thread, in the order requests are served
+fn serve_pipelined(stream: UnixStream, engine: &EngineHandle) -> io::Result<()> {
+    let mut writer = stream.try_clone()?;
+    let (sender, receiver) = mpsc::channel::<Vec<u8>>();
+    let responses = thread::spawn(move || -> io::Result<()> {
+        // - ends once the reader and every queued request dropped their sender
+        for response in receiver {
+            write_frame(&mut writer, &response)?;
+        }
+        Ok(())
+    });
+    let mut reader = stream;
+    let read_result = (|| {
+        while let Some(frame) = read_frame(&mut reader)? {
+            if frame.len() < 8 {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    "frame without request id",
+                ));
+            }
+            let (request_id, request) = frame.split_at(8);
+            let request_id = request_id.to_vec();
+            let sender = sender.clone();
+            let responder = Responder::new(move |response| {
+                let _ = sender.send(encode_response(&request_id, response));
+            });
+            queue_request(request, engine, responder);
+        }
+        Ok(())
+    })();
+    drop(sender);
+    let write_result = responses
+        .join()
+        .unwrap_or_else(|_| Err(io::Error::other("writer panicked")));
+    read_result.and(write_result)
+}
+
+#[cfg(test)]
+mod unit_tests_unix {
+    use super::*;
+    use crate::engine::Engine;
+    use std::collections::HashMap;
+
+    fn request(request_id: u64, payload: &[u8]) -> Vec<u8> {
+        let mut frame = request_id.to_le_bytes().to_vec();
+        frame.extend_from_slice(payload);
+        frame
+    }
+
+    #[test]
+    fn test_pipelined_requests() {
+        let tmp_dir = tempfile::tempdir().unwrap();
+        let file_path = tmp_dir.path().join("unix.hex");
+        let storage = Storage::new(file_path.to_str().unwrap().to_string(), 4).unwrap();
+        let socket_path = tmp_dir.path().join("se1.sock");
+        let server = UnixServer::bind(&socket_path, Engine::spawn(storage)).unwrap();
+        let stop_handle = server.stop_handle();
+        let serve = thread::spawn(move || server.serve());
+        let mut stream = UnixStream::connect(&socket_path).unwrap();
+        // - three requests in flight before any response is read
+        let mut write = vec![OP_WRITE, 0];
+        write.extend_from_slice(&[0; 8]);
+        write.extend_from_slice(&[1, 2, 3, 4, 5]);
+        let mut read = vec![OP_READ];
+        read.extend_from_slice(&0u64.to_le_bytes());
+        for (request_id, payload) in [(7, &write), (8, &read), (9, &vec![9])].iter() {
+            write_frame(&mut stream, &request(*request_id, payload)).unwrap();
+        }
+        let mut responses = HashMap::new();
+        for _ in 0..3 {
+            let response = read_frame(&mut stream).unwrap().unwrap();
+            let request_id = get_u64(&response, 0).unwrap();
+            responses.insert(request_id, response[8..].to_vec());
+        }
+        assert_eq!(
+            responses[&7],
+            [&[STATUS_OK][..], &0u64.to_le_bytes()].concat()
+        );
+        // - write was queued before read, read sees it
+        assert_eq!(responses[&8], vec![STATUS_OK, 1, 2, 3, 4, 5]);
+        assert_eq!(responses[&9], b"\x01malformed request".to_vec());
+        // - frame without request id closes the connection
+        write_frame(&mut stream, &[OP_STATS]).unwrap();
+        assert!(read_frame(&mut stream).unwrap().is_none());
+        stop_handle.stop();
+        assert!(serve.join().unwrap().unwrap().is_some());
+        assert!(!socket_path.exists());
+    }
+}
@@ -0,0 +1,251 @@
+use super::*;
+
+/// How allocate picks blocks for new data, see Storage::set_allocation_strategy
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum AllocationStrategy {
+    /// Lowest free blocks first, then blocks past end of file
+    /// - reuses every hole, but a multi block record can be scattered over holes
+    #[default]
+    FirstFit,
+    /// Smallest run of contiguous free blocks that holds all blocks, ties go to lower index
+    /// - a free run at the end of the file is extended past the end when no run fits,
+    ///   otherwise blocks are appended
+    /// - records stay contiguous, small writes fill small holes and keep large runs free
+    BestFit,
+    /// Blocks past end of file only, free blocks are never reused
+    /// - file only grows, until compacted
+    AppendOnly,
+}
+
+impl Storage {
+    // ... ... ... ... ... ... ... ... ... Allocation ... ... ... ... ... ... ... ... ... .
+
+    /// Set how new blocks of write_record, write_blocks_chunked and write_batch are picked,
+    /// see AllocationStrategy
+    /// - default is AllocationStrategy::FirstFit
+    /// - strategy is not stored in storage file, it must be set every time the file is opened
+    /// - write_block writes the block it is given whatever the strategy
+    pub fn set_allocation_strategy(&mut self, strategy: AllocationStrategy) {
+        self.allocation = strategy;
+    }
+    /// Current allocation strategy
+    pub fn allocation_strategy(&self) -> AllocationStrategy {
+        self.allocation
+    }
+    /// Pick block indexes for block_count new blocks, as told by the allocation strategy
+    /// - append-only storage files always append, see enable_append_only
+    /// - blocks are not reserved, they are taken once written, so allocate again after
+    ///   any other write
+    /// - returns: block indexes in ascending order
+    pub fn allocate(&self, block_count: usize) -> Result<Vec<BlockIndex>, StorageError> {
+        if self.is_append_only() {
+            return self.append_blocks(block_count);
+        }
+        match self.allocation {
+            AllocationStrategy::FirstFit => {
+                let mut block_indexes: Vec<BlockIndex> =
+                    self.free_blocks.iter().take(block_count).cloned().collect();
+                let appended = self.append_blocks(block_count - block_indexes.len())?;
+                block_indexes.extend(appended);
+                Ok(block_indexes)
+            }
+            AllocationStrategy::BestFit => self.allocate_best_fit(block_count),
+            AllocationStrategy::AppendOnly => self.append_blocks(block_count),
+        }
+    }
+    /// Smallest free run holding block_count blocks, see AllocationStrategy::BestFit
+    fn allocate_best_fit(&self, block_count: usize) -> Result<Vec<BlockIndex>, StorageError> {
+        if block_count == 0 {
+            return Ok(Vec::new());
+        }
+        let free_runs = self.free_runs();
+        let best_run = free_runs
+            .iter()
+            .filter(|(_, length)| *length >= block_count)
+            .min_by_key(|(first_block, length)| (*length, *first_block));
+        let first_block = match (best_run, free_runs.last()) {
+            (Some((first_block, _)), _) => *first_block,
+            // - free run at end of file grows past the end
+            (None, Some((first_block, length)))
+                if *first_block + *length as u64 == self.end_block_count =>
+            {
+                *first_block
+            }
+            (None, _) => self.end_block_count,
+        };
+        // u64::MAX is not linkable, see BlockHeader::next_block
+        if first_block.checked_add(block_count as u64).is_none() {
+            return Err(StorageError::BlockOutOfRange {
+                block_index: u64::MAX,
+            });
+        }
+        Ok((first_block..).take(block_count).collect())
+    }
+    /// block_count blocks past end of file, preallocated blocks not written yet first
+    pub(super) fn append_blocks(
+        &self,
+        block_count: usize,
+    ) -> Result<Vec<BlockIndex>, StorageError> {
+        let first_block = self.preallocated_from.min(self.end_block_count);
+        // u64::MAX is not linkable, see BlockHeader::next_block
+        if first_block.checked_add(block_count as u64).is_none() {
+            return Err(StorageError::BlockOutOfRange {
+                block_index: u64::MAX,
+            });
+        }
+        Ok((first_block..).take(block_count).collect())
+    }
+
+    // ... ... ... ... ... ... ... ... ... Preallocation ... ... ... ... ... ... ... ... ...
+
+    /// Reserve disk space for blocks up to block_count up front, so writes into them do
+    /// not grow the file, fragment it, or run out of space midway
+    /// - posix_fallocate on Linux with feature fallocate, else the blocks are written as
+    ///   zeros; Windows has no way to reserve space without privileges, zeros are
+    ///   written there too
+    /// - preallocated blocks are free blocks, allocate hands them out like the end of
+    ///   the file, also with AllocationStrategy::AppendOnly and append-only files
+    /// - a file opened again does not know which free blocks were preallocated, they are
+    ///   free blocks like any other then; trim_tail and auto trim release them
+    /// - returns: number of blocks added, 0 if the file already holds block_count blocks
+    pub fn preallocate(&mut self, block_count: u64) -> Result<u64, StorageError> {
+        if block_count <= self.end_block_count {
+            return Ok(0);
+        }
+        if self.read_only {
+            return Err(StorageError::ReadOnly);
+        }
+        self.check_quota(block_count - 1)?;
+        let end = self.block_offset(block_count)?;
+        // - from end of file, the last block may end before block_len bytes of data
+        let start = self
+            .file_writer
+            .metadata()
+            .map_err(StorageError::io("read storage file metadata", None))?
+            .len();
+        if end > start {
+            positional::allocate_at(&self.file_writer, start, end - start)
+                .map_err(StorageError::io("preallocate storage file", None))?;
+        }
+        let added_count = block_count - self.end_block_count;
+        self.free_blocks.extend(self.end_block_count..block_count);
+        self.preallocated_from = self.preallocated_from.min(self.end_block_count);
+        self.end_block_count = block_count;
+        // - bits of blocks past the old end may be left over from a truncated file
+        self.write_bitmap()?;
+        self.written(1)?;
+        Ok(added_count)
+    }
+    /// Number of preallocated blocks not written yet, see preallocate
+    pub fn preallocated_blocks(&self) -> u64 {
+        self.end_block_count.saturating_sub(self.preallocated_from)
+    }
+    /// Move start of preallocated blocks past a block just written
+    pub(super) fn written_past_preallocated(&mut self, block_index: BlockIndex) {
+        if block_index >= self.preallocated_from {
+            self.preallocated_from = block_index.saturating_add(1);
+        }
+    }
+    /// Runs of contiguous free blocks as (first block, length), in ascending order
+    pub(super) fn free_runs(&self) -> Vec<(BlockIndex, usize)> {
+        let mut free_runs: Vec<(BlockIndex, usize)> = Vec::new();
+        for block_index in self.free_blocks.iter().cloned() {
+            match free_runs.last_mut() {
+                Some((first_block, length)) if *first_block + *length as u64 == block_index => {
+                    *length += 1;
+                }
+                _ => free_runs.push((block_index, 1)),
+            }
+        }
+        free_runs
+    }
+}
+
+#[cfg(test)]
+mod unit_tests_allocation {
+    use super::*;
+
+    /// storage with used blocks 0, 3, 4 and 7, free runs 1..3 and 5..7
+    fn new_storage(tmp_dir: &tempfile::TempDir) -> Storage {
+        let file_path = tmp_dir.path().join("allocation.hex");
+        let mut storage = Storage::new(file_path.to_str().unwrap().to_string(), 4).unwrap();
+        for block_index in 0..8 {
+            storage.write_block(block_index, &[1]).unwrap();
+        }
+        for block_index in [1, 2, 5, 6].iter() {
+            storage.delete_block(*block_index, false).unwrap();
+        }
+        storage
+    }
+
+    #[test]
+    fn test_first_fit() {
+        let tmp_dir = tempfile::tempdir().unwrap();
+        let storage = new_storage(&tmp_dir);
+        assert_eq!(storage.allocation_strategy(), AllocationStrategy::FirstFit);
+        assert_eq!(storage.allocate(3).unwrap(), vec![1, 2, 5]);
+        assert_eq!(storage.allocate(6).unwrap(), vec![1, 2, 5, 6, 8, 9]);
+    }
+    #[test]
+    fn test_best_fit() {
+        let tmp_dir = tempfile::tempdir().unwrap();
+        let mut storage = new_storage(&tmp_dir);
+        storage.delete_block(4, false).unwrap();
+        storage.set_allocation_strategy(AllocationStrategy::BestFit);
+        // free runs 1..3 and 4..7, smallest run that fits wins
+        assert_eq!(storage.allocate(1).unwrap(), vec![1]);
+        assert_eq!(storage.allocate(3).unwrap(), vec![4, 5, 6]);
+        assert_eq!(storage.allocate(4).unwrap(), vec![8, 9, 10, 11]);
+        assert_eq!(storage.allocate(0).unwrap(), Vec::<BlockIndex>::new());
+        // free run at end of file is extended
+        storage.delete_block(7, false).unwrap();
+        assert_eq!(storage.allocate(5).unwrap(), vec![4, 5, 6, 7, 8]);
+        let record_id = storage.write_record(&[2; 10]).unwrap();
+        assert_eq!(storage.record_blocks(record_id).unwrap(), vec![4, 5, 6]);
+    }
+    #[test]
+    fn test_append_only() {
+        let tmp_dir = tempfile::tempdir().unwrap();
+        let mut storage = new_storage(&tmp_dir);
+        storage.set_allocation_strategy(AllocationStrategy::AppendOnly);
+        assert_eq!(storage.allocate(2).unwrap(), vec![8, 9]);
+        let record_id = storage.write_record(&[2; 5]).unwrap();
+        assert_eq!(record_id, 8);
+        let payloads: Vec<&[u8]> = vec![&[3; 4], &[4; 8]];
+        assert_eq!(
+            storage.write_batch(&payloads).unwrap(),
+            vec![vec![10], vec![11, 12]]
+        );
+        assert_eq!(storage.free_runs(), vec![(1, 2), (5, 2)]);
+    }
+    #[test]
+    fn test_preallocate() {
+        let tmp_dir = tempfile::tempdir().unwrap();
+        let mut storage = new_storage(&tmp_dir);
+        assert_eq!(storage.preallocate(4).unwrap(), 0);
+        assert_eq!(storage.preallocate(12).unwrap(), 4);
+        // - file holds every preallocated block up front
+        let file_len = std::fs::metadata(storage.file_path()).unwrap().len();
+        assert_eq!(file_len, storage.block_offset(12).unwrap());
+        assert_eq!(storage.stats().block_count, 12);
+        assert_eq!(storage.stats().preallocated_blocks, 4);
+        // - appended blocks take preallocated blocks first, the file does not grow
+        storage.set_allocation_strategy(AllocationStrategy::AppendOnly);
+        assert_eq!(storage.allocate(2).unwrap(), vec![8, 9]);
+        storage.write_record(&[2; 5]).unwrap();
+        assert_eq!(storage.preallocated_blocks(), 2);
+        // - writing past a preallocated block gives it up for appends
+        storage.write_block(10, &[3]).unwrap();
+        assert_eq!(storage.allocate(2).unwrap(), vec![11, 12]);
+        assert_eq!(storage.stats().preallocated_blocks, 1);
+        let file_len_after = std::fs::metadata(storage.file_path()).unwrap().len();
+        assert_eq!(file_len_after, file_len);
+        // - opened again, preallocated blocks are free blocks
+        let file_path = storage.file_path().to_string();
+        drop(storage);
+        let storage = Storage::open(file_path).unwrap();
+        assert_eq!(storage.stats().block_count, 12);
+        assert_eq!(storage.stats().preallocated_blocks, 0);
+        assert_eq!(storage.allocate(1).unwrap(), vec![1]);
+    }
+}
@@ -0,0 +1,83 @@
+use super::*;
+
+impl Storage {
+    // ... ... ... ... ... ... ... ... ... Append-only ... ... ... ... ... ... ... ... ... .
+
+    /// Make storage file append-only, a block holding data can not change
+    /// - write_block only takes free blocks and delete_block fails, both with AppendOnly,
+    ///   records and batches are always appended
+    /// - compaction, sweep_expired and restore_from fail for the same reason, trim_tail drops
+    ///   free blocks at end of file
+    /// - mode is stored in storage file header, once enabled it can not be disabled
+    /// - enforced by this crate only, block checksums tell changes made to the file
+    ///   from outside
+    pub fn enable_append_only(&mut self) -> Result<(), StorageError> {
+        if self.is_append_only() {
+            return Ok(());
+        }
+        self.header.flags |= STORAGE_FLAG_APPEND_ONLY;
+        self.set_storage_header()?;
+        self.written(1)
+    }
+    /// Check if storage file is append-only, see enable_append_only
+    pub fn is_append_only(&self) -> bool {
+        self.header.flags & STORAGE_FLAG_APPEND_ONLY != 0
+    }
+    /// AppendOnly if storage file is append-only and block holds data
+    pub(super) fn check_appendable(&mut self, block_index: BlockIndex) -> Result<(), StorageError> {
+        if self.is_append_only() && !self.is_empty_block(block_index) {
+            return Err(StorageError::AppendOnly { block_index });
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod unit_tests_append_only {
+    use super::*;
+
+    fn new_storage(tmp_dir: &tempfile::TempDir) -> (Storage, String) {
+        let file_path = tmp_dir.path().join("append_only.hex");
+        let file_path = file_path.to_str().unwrap().to_string();
+        (Storage::new(file_path.clone(), 4).unwrap(), file_path)
+    }
+
+    #[test]
+    fn test_append_only() {
+        let tmp_dir = tempfile::tempdir().unwrap();
+        let (mut storage, file_path) = new_storage(&tmp_dir);
+        storage.write_block(0, &[1]).unwrap();
+        storage.write_block(1, &[2]).unwrap();
+        storage.delete_block(0, false).unwrap();
+        storage.enable_append_only().unwrap();
+        storage.close().unwrap();
+        // mode is kept in storage header
+        let mut storage = Storage::open(file_path).unwrap();
+        assert!(storage.is_append_only());
+        assert!(matches!(
+            storage.write_block(1, &[3]),
+            Err(StorageError::AppendOnly { block_index: 1 })
+        ));
+        assert!(matches!(
+            storage.delete_block(1, true),
+            Err(StorageError::AppendOnly { block_index: 1 })
+        ));
+        // free blocks can be written once, records go past end of file
+        storage.write_block(0, &[4]).unwrap();
+        assert!(storage.write_block(0, &[6]).is_err());
+        storage.write_block(2, &[4]).unwrap();
+        let record_id = storage.write_record(&[5; 6]).unwrap();
+        assert_eq!(storage.record_blocks(record_id).unwrap(), vec![3, 4]);
+        assert!(matches!(
+            storage.delete_record(record_id, false),
+            Err(StorageError::AppendOnly { block_index: 3 })
+        ));
+        storage.write_block(7, &[7]).unwrap();
+        assert!(matches!(
+            storage.compact(),
+            Err(StorageError::AppendOnly { block_index: 7 })
+        ));
+        assert_eq!(storage.read_block(1).unwrap().1, vec![2]);
+        assert_eq!(storage.read_record(record_id).unwrap(), vec![5; 6]);
+    }
+}
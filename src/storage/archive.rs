@@ -0,0 +1,189 @@
+use super::*;
+use std::collections::HashMap;
+use std::io::{BufReader, BufWriter, Read, Write};
+
+/// First bytes of an archive
+const ARCHIVE_MAGIC: &[u8; 4] = b"SE1A";
+/// Version of the archive format, independent of FORMAT_VERSION of storage files
+/// - archive header: magic, version u16, block_len u64 and record count u64
+/// - every record: record id u64, expires_at u64 (0 for None), tag u16, data length u64
+///   and data, all little endian
+const ARCHIVE_VERSION: u16 = 1;
+
+const MALFORMED_ARCHIVE: StorageError = StorageError::Corruption {
+    block_index: None,
+    reason: "archive is malformed",
+};
+
+fn read_bytes<const N: usize>(reader: &mut impl Read) -> Result<[u8; N], StorageError> {
+    let mut bytes = [0u8; N];
+    reader
+        .read_exact(&mut bytes)
+        .map_err(|error| match error.kind() {
+            std::io::ErrorKind::UnexpectedEof => MALFORMED_ARCHIVE,
+            _ => StorageError::Io {
+                operation: "read archive",
+                block_index: None,
+                source: error,
+            },
+        })?;
+    Ok(bytes)
+}
+
+fn read_u64(reader: &mut impl Read) -> Result<u64, StorageError> {
+    read_bytes::<8>(reader).map(u64::from_le_bytes)
+}
+
+impl Storage {
+    // ... ... ... ... ... ... ... ... ... ... Archive ... ... ... ... ... ... ... ... ... ..
+
+    /// Write every record of this storage to writer as a portable archive, see import
+    /// - a record is a chain of blocks, a block written with write_block is a record of
+    ///   one block, records are written in record id order
+    /// - records keep their data, expiry and tag, not their block indexes, generations or
+    ///   pins, so they can be imported into a storage of another block length
+    /// - data is written decoded, without compression or encryption, so an archive of an
+    ///   encrypted storage holds plain data
+    /// - returns: number of records written
+    pub fn export(&mut self, writer: impl Write) -> Result<u64, StorageError> {
+        let previous_blocks = self.previous_blocks()?;
+        let record_ids: Vec<RecordId> = (0..self.end_block_count)
+            .filter(|block_index| {
+                !self.is_empty_block(*block_index) && !previous_blocks.contains_key(block_index)
+            })
+            .collect();
+        let mut writer = BufWriter::new(writer);
+        let mut header = ARCHIVE_MAGIC.to_vec();
+        header.extend_from_slice(&ARCHIVE_VERSION.to_le_bytes());
+        header.extend_from_slice(&self.header.block_len.to_le_bytes());
+        header.extend_from_slice(&(record_ids.len() as u64).to_le_bytes());
+        writer
+            .write_all(&header)
+            .map_err(StorageError::io("write archive", None))?;
+        for record_id in record_ids.iter().copied() {
+            let block_header = self.read_block_header(record_id)?;
+            let data = self.read_record(record_id)?;
+            let mut record = record_id.to_le_bytes().to_vec();
+            record.extend_from_slice(&block_header.expires_at.unwrap_or(0).to_le_bytes());
+            record.extend_from_slice(&block_header.tag.to_le_bytes());
+            record.extend_from_slice(&(data.len() as u64).to_le_bytes());
+            writer
+                .write_all(&record)
+                .and_then(|_| writer.write_all(&data))
+                .map_err(StorageError::io("write archive", Some(record_id)))?;
+        }
+        writer
+            .flush()
+            .map_err(StorageError::io("write archive", None))?;
+        Ok(record_ids.len() as u64)
+    }
+    /// Write every record of archive read from reader, see export, to the storage opened
+    /// with options
+    /// - a new file gets the block length of the archive, unless options set one
+    /// - records are written like write_record, with their expiry and tag, into blocks
+    ///   picked by allocate, records of an existing file are kept
+    /// - Corruption if reader is not an archive, or of an archive version not supported
+    /// - returns: storage, and the new record id of each record id of the archive
+    pub fn import(
+        reader: impl Read,
+        options: StorageOptions,
+    ) -> Result<(Storage, HashMap<RecordId, RecordId>), StorageError> {
+        let mut reader = BufReader::new(reader);
+        if &read_bytes::<4>(&mut reader)? != ARCHIVE_MAGIC {
+            return Err(MALFORMED_ARCHIVE);
+        }
+        if u16::from_le_bytes(read_bytes(&mut reader)?) != ARCHIVE_VERSION {
+            return Err(StorageError::Corruption {
+                block_index: None,
+                reason: "archive version is not supported",
+            });
+        }
+        let block_len = read_u64(&mut reader)?;
+        let record_count = read_u64(&mut reader)?;
+        let mut storage = options.default_block_len(block_len).open()?;
+        let mut record_ids = HashMap::new();
+        for _ in 0..record_count {
+            let record_id = read_u64(&mut reader)?;
+            let expires_at = Some(read_u64(&mut reader)?).filter(|at| *at != 0);
+            let tag = u16::from_le_bytes(read_bytes(&mut reader)?);
+            let data_len = read_u64(&mut reader)?;
+            // - read as much as the archive holds, not data_len up front
+            let mut data = Vec::new();
+            (&mut reader)
+                .take(data_len)
+                .read_to_end(&mut data)
+                .map_err(StorageError::io("read archive", Some(record_id)))?;
+            if data.len() as u64 != data_len {
+                return Err(MALFORMED_ARCHIVE);
+            }
+            let block_indexes = storage.write_chunks(&data, expires_at, tag)?;
+            record_ids.insert(record_id, block_indexes[0]);
+        }
+        storage.flush()?;
+        Ok((storage, record_ids))
+    }
+}
+
+#[cfg(test)]
+mod unit_tests_archive {
+    use super::*;
+
+    fn file_path(tmp_dir: &tempfile::TempDir, name: &str) -> String {
+        tmp_dir.path().join(name).to_str().unwrap().to_string()
+    }
+
+    #[test]
+    fn test_export_import() {
+        let tmp_dir = tempfile::tempdir().unwrap();
+        let mut storage = Storage::new(file_path(&tmp_dir, "storage.hex"), 4).unwrap();
+        let data: Vec<u8> = (0..10).collect();
+        let record_id = storage.write_record(&data).unwrap();
+        storage.write_block_tagged(5, &[5], 3).unwrap();
+        storage.write_block_expiring(7, &[7, 7], Some(99)).unwrap();
+        let mut archive = Vec::new();
+        assert_eq!(storage.export(&mut archive).unwrap(), 3);
+        // - into a storage with longer blocks
+        let options = StorageOptions::new(file_path(&tmp_dir, "imported.hex"))
+            .block_len(16)
+            .create(true);
+        let (mut imported, record_ids) = Storage::import(&archive[..], options).unwrap();
+        assert_eq!(imported.block_len(), 16);
+        assert_eq!(imported.used_block_count(), 3);
+        assert_eq!(imported.read_record(record_ids[&record_id]).unwrap(), data);
+        assert_eq!(imported.block_tag(record_ids[&5]).unwrap(), 3);
+        let expiring = imported.read_block_header(record_ids[&7]).unwrap();
+        assert_eq!(expiring.expires_at, Some(99));
+        // - block length of the archive by default, shorter blocks split records
+        let options = StorageOptions::new(file_path(&tmp_dir, "default.hex")).create(true);
+        let (mut imported, record_ids) = Storage::import(&archive[..], options).unwrap();
+        assert_eq!(imported.block_len(), 4);
+        assert_eq!(imported.read_record(record_ids[&record_id]).unwrap(), data);
+        // - an existing file keeps its block length
+        Storage::new(file_path(&tmp_dir, "short.hex"), 2).unwrap();
+        let options = StorageOptions::new(file_path(&tmp_dir, "short.hex")).create(true);
+        let (mut imported, record_ids) = Storage::import(&archive[..], options).unwrap();
+        assert_eq!(imported.used_block_count(), 7);
+        assert_eq!(imported.read_record(record_ids[&record_id]).unwrap(), data);
+    }
+    #[test]
+    fn test_import_rejects_bad_archive() {
+        let tmp_dir = tempfile::tempdir().unwrap();
+        let mut storage = Storage::new(file_path(&tmp_dir, "storage.hex"), 4).unwrap();
+        storage.write_record(&[1; 6]).unwrap();
+        let mut archive = Vec::new();
+        storage.export(&mut archive).unwrap();
+        let options = StorageOptions::new(file_path(&tmp_dir, "imported.hex")).create(true);
+        let truncated = &archive[..archive.len() - 1];
+        assert!(matches!(
+            Storage::import(truncated, options.clone()),
+            Err(StorageError::Corruption { .. })
+        ));
+        let mut future = archive.clone();
+        future[4] = 2;
+        assert!(matches!(
+            Storage::import(&future[..], options.clone()),
+            Err(StorageError::Corruption { .. })
+        ));
+        assert!(Storage::import(&b"SE1X"[..], options).is_err());
+    }
+}
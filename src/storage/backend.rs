@@ -0,0 +1,353 @@
+use super::*;
+use std::io;
+
+/// Source of block reads
+/// - read_block and read_record read block headers and data through it,
+///   writes and header scans go to the storage file directly
+pub trait Backend: Send {
+    /// Read bytes of storage file at offset into buf
+    /// - returns: number of bytes read, less than buf length only at end of file
+    fn read_at(&mut self, offset: u64, buf: &mut [u8]) -> io::Result<usize>;
+}
+
+/// Backend reading with a seek and read syscall per call
+/// - default backend of every Storage
+pub struct FileBackend {
+    file: File,
+}
+
+impl FileBackend {
+    pub fn new(file: File) -> Self {
+        FileBackend { file }
+    }
+}
+
+impl Backend for FileBackend {
+    fn read_at(&mut self, offset: u64, buf: &mut [u8]) -> io::Result<usize> {
+        positional::read_at(&self.file, offset, buf)
+    }
+}
+
+/// Backend reading from a memory map of storage file, without a syscall per read
+/// - file is mapped again when a read goes past the mapped length, e.g. after the file grew
+/// - writes of the owning Storage are visible through the map, as both share the page cache
+/// - NOTE: truncating the file from outside while mapped makes reads crash with SIGBUS
+#[cfg(feature = "mmap")]
+pub struct MmapBackend {
+    file: File,
+    map: memmap2::Mmap,
+}
+
+#[cfg(feature = "mmap")]
+impl MmapBackend {
+    pub fn new(file: File) -> io::Result<Self> {
+        let map = MmapBackend::map(&file)?;
+        Ok(MmapBackend { file, map })
+    }
+    fn map(file: &File) -> io::Result<memmap2::Mmap> {
+        // SAFETY: storage file is only modified through the owning Storage, which never
+        // shrinks it while mapped; concurrent writes change bytes, not the mapping
+        unsafe { memmap2::Mmap::map(file) }
+    }
+}
+
+#[cfg(feature = "mmap")]
+impl Backend for MmapBackend {
+    fn read_at(&mut self, offset: u64, buf: &mut [u8]) -> io::Result<usize> {
+        if offset + buf.len() as u64 > self.map.len() as u64 {
+            self.map = MmapBackend::map(&self.file)?;
+        }
+        let start = (offset as usize).min(self.map.len());
+        let end = (start + buf.len()).min(self.map.len());
+        buf[..end - start].copy_from_slice(&self.map[start..end]);
+        Ok(end - start)
+    }
+}
+
+/// Alignment of direct reads when the file system does not tell its block size
+#[cfg(feature = "direct-io")]
+const DEFAULT_DIRECT_IO_ALIGNMENT: usize = 4096;
+
+/// Backend reading with direct IO, bypassing the OS page cache
+/// - storage file is opened again with O_DIRECT (FILE_FLAG_NO_BUFFERING on Windows,
+///   F_NOCACHE on macOS)
+/// - reads are widened to whole sectors of alignment bytes, into a buffer aligned to it
+/// - large scans do not evict other pages from the page cache, enable the block cache
+///   to keep hot blocks in memory
+/// - writes of the owning Storage still go through the page cache, the kernel writes
+///   them back before a direct read of the same range
+#[cfg(feature = "direct-io")]
+pub struct DirectBackend {
+    file: File,
+    alignment: usize,
+    /// Sector reads land in here, alignment bytes longer than needed so an aligned
+    /// slice always fits
+    buffer: Vec<u8>,
+}
+
+#[cfg(feature = "direct-io")]
+impl DirectBackend {
+    /// Open file_path for direct reads
+    /// - alignment is block size of the file system, a multiple of the device sector size
+    pub fn open(file_path: &str) -> io::Result<Self> {
+        let file = open_direct(file_path)?;
+        let alignment = direct_io_alignment(&file)?;
+        Ok(DirectBackend {
+            file,
+            alignment,
+            buffer: Vec::new(),
+        })
+    }
+    /// Bytes every read offset and length is rounded to
+    pub fn alignment(&self) -> usize {
+        self.alignment
+    }
+}
+
+#[cfg(feature = "direct-io")]
+impl Backend for DirectBackend {
+    fn read_at(&mut self, offset: u64, buf: &mut [u8]) -> io::Result<usize> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+        let out_of_range = || io::Error::new(io::ErrorKind::InvalidInput, "read past u64 offsets");
+        // - widen read to whole sectors
+        let alignment = self.alignment as u64;
+        let start = offset / alignment * alignment;
+        let end = offset
+            .checked_add(buf.len() as u64)
+            .and_then(|end| end.checked_add(alignment - 1))
+            .ok_or_else(out_of_range)?
+            / alignment
+            * alignment;
+        let len = usize::try_from(end - start).map_err(|_| out_of_range())?;
+        if self.buffer.len() < len + self.alignment {
+            self.buffer.resize(len + self.alignment, 0);
+        }
+        let pad = self.buffer.as_ptr().align_offset(self.alignment);
+        let sectors = &mut self.buffer[pad..pad + len];
+        // - read sectors, a read short of a whole sector only happens at end of file
+        let mut read_size = 0;
+        while read_size < len {
+            let read_offset = start + read_size as u64;
+            match positional::read_once_at(&self.file, read_offset, &mut sectors[read_size..]) {
+                Ok(0) => break,
+                Ok(size) => {
+                    read_size += size;
+                    if size % self.alignment != 0 {
+                        break;
+                    }
+                }
+                Err(error) if error.kind() == io::ErrorKind::Interrupted => {}
+                Err(error) => return Err(error),
+            }
+        }
+        // - copy requested bytes out of the sectors
+        let skip = (offset - start) as usize;
+        let copied = read_size.saturating_sub(skip).min(buf.len());
+        buf[..copied].copy_from_slice(&sectors[skip..skip + copied]);
+        Ok(copied)
+    }
+}
+
+#[cfg(all(feature = "direct-io", any(target_os = "linux", target_os = "android")))]
+fn open_direct(file_path: &str) -> io::Result<File> {
+    use std::os::unix::fs::OpenOptionsExt;
+    OpenOptions::new()
+        .read(true)
+        .custom_flags(libc::O_DIRECT)
+        .open(file_path)
+}
+
+#[cfg(all(feature = "direct-io", any(target_os = "macos", target_os = "ios")))]
+fn open_direct(file_path: &str) -> io::Result<File> {
+    use std::os::unix::io::AsRawFd;
+    let file = File::open(file_path)?;
+    // SAFETY: fcntl on an open file descriptor owned by file, no memory is passed
+    if unsafe { libc::fcntl(file.as_raw_fd(), libc::F_NOCACHE, 1) } == -1 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(file)
+}
+
+#[cfg(all(feature = "direct-io", windows))]
+fn open_direct(file_path: &str) -> io::Result<File> {
+    use std::os::windows::fs::OpenOptionsExt;
+    const FILE_FLAG_NO_BUFFERING: u32 = 0x2000_0000;
+    OpenOptions::new()
+        .read(true)
+        .custom_flags(FILE_FLAG_NO_BUFFERING)
+        .open(file_path)
+}
+
+#[cfg(all(
+    feature = "direct-io",
+    not(any(
+        target_os = "linux",
+        target_os = "android",
+        target_os = "macos",
+        target_os = "ios",
+        windows
+    ))
+))]
+fn open_direct(_file_path: &str) -> io::Result<File> {
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        "direct IO is not supported on this platform",
+    ))
+}
+
+/// Block size of the file system of file, where it is a power of two of at least 512
+#[cfg(all(feature = "direct-io", unix))]
+fn direct_io_alignment(file: &File) -> io::Result<usize> {
+    use std::os::unix::fs::MetadataExt;
+    let block_size = usize::try_from(file.metadata()?.blksize()).unwrap_or(0);
+    if block_size >= 512 && block_size.is_power_of_two() {
+        Ok(block_size)
+    } else {
+        Ok(DEFAULT_DIRECT_IO_ALIGNMENT)
+    }
+}
+
+#[cfg(all(feature = "direct-io", not(unix)))]
+fn direct_io_alignment(_file: &File) -> io::Result<usize> {
+    Ok(DEFAULT_DIRECT_IO_ALIGNMENT)
+}
+
+impl Storage {
+    /// Replace backend used for block reads
+    pub fn set_backend(&mut self, backend: Box<dyn Backend>) {
+        self.backend = backend;
+    }
+    /// Read blocks through a memory map of storage file, see MmapBackend
+    #[cfg(feature = "mmap")]
+    pub fn enable_mmap(&mut self) -> Result<(), StorageError> {
+        let file = self
+            .file_reader
+            .try_clone()
+            .map_err(StorageError::io("open storage file", None))?;
+        let backend = MmapBackend::new(file).map_err(StorageError::io("map storage file", None))?;
+        self.set_backend(Box::new(backend));
+        Ok(())
+    }
+    /// Read blocks with direct IO, bypassing the OS page cache, see DirectBackend
+    #[cfg(feature = "direct-io")]
+    pub fn enable_direct_io(&mut self) -> Result<(), StorageError> {
+        let backend = DirectBackend::open(&self.file_path)
+            .map_err(StorageError::io("open storage file for direct io", None))?;
+        self.set_backend(Box::new(backend));
+        Ok(())
+    }
+    /// Default backend reading through a new handle of file at file_path
+    pub(super) fn file_backend(
+        file: &File,
+        file_path: &str,
+    ) -> Result<Box<dyn Backend>, StorageError> {
+        let file = Storage::new_file_handle(file, file_path)?;
+        Ok(Box::new(FileBackend::new(file)))
+    }
+    #[cfg(not(target_os = "wasi"))]
+    fn new_file_handle(file: &File, _file_path: &str) -> Result<File, StorageError> {
+        file.try_clone()
+            .map_err(StorageError::io("open storage file", None))
+    }
+    /// WASI has no duplicate of a file handle, open file_path again
+    #[cfg(target_os = "wasi")]
+    fn new_file_handle(_file: &File, file_path: &str) -> Result<File, StorageError> {
+        Storage::open_file_reader(file_path)
+    }
+}
+
+#[cfg(test)]
+mod unit_tests_backend {
+    use super::*;
+
+    fn new_storage(tmp_dir: &tempfile::TempDir) -> Storage {
+        let file_path = tmp_dir.path().join("backend.hex");
+        Storage::new(file_path.to_str().unwrap().to_string(), 4).unwrap()
+    }
+
+    /// backend serving every read from a fixed buffer
+    struct BufferBackend(Vec<u8>);
+    impl Backend for BufferBackend {
+        fn read_at(&mut self, offset: u64, buf: &mut [u8]) -> io::Result<usize> {
+            let start = (offset as usize).min(self.0.len());
+            let end = (start + buf.len()).min(self.0.len());
+            buf[..end - start].copy_from_slice(&self.0[start..end]);
+            Ok(end - start)
+        }
+    }
+
+    #[test]
+    fn test_file_backend() {
+        let tmp_dir = tempfile::tempdir().unwrap();
+        let file_path = tmp_dir.path().join("file_backend.hex");
+        std::fs::write(&file_path, [1, 2, 3, 4]).unwrap();
+        let mut backend = FileBackend::new(File::open(&file_path).unwrap());
+        let mut buf = [0u8; 3];
+        assert_eq!(backend.read_at(2, &mut buf).unwrap(), 2);
+        assert_eq!(buf, [3, 4, 0]);
+        assert_eq!(backend.read_at(8, &mut buf).unwrap(), 0);
+    }
+    #[test]
+    fn test_set_backend() {
+        let tmp_dir = tempfile::tempdir().unwrap();
+        let mut storage = new_storage(&tmp_dir);
+        storage.write_block(0, &[1, 2, 3]).unwrap();
+        // reads go to the backend, file is not read
+        let file_bytes = std::fs::read(tmp_dir.path().join("backend.hex")).unwrap();
+        let block_offset = storage.block_offset(0).unwrap() as usize;
+        let mut backend_bytes = file_bytes.clone();
+        backend_bytes[block_offset + BLOCK_HEADER_SIZE] = 7;
+        storage.set_backend(Box::new(BufferBackend(backend_bytes)));
+        assert!(matches!(
+            storage.read_block(0),
+            Err(StorageError::ChecksumMismatch { block_index: 0 })
+        ));
+        storage.set_backend(Box::new(BufferBackend(file_bytes)));
+        assert_eq!(storage.read_block(0).unwrap().1, vec![1, 2, 3]);
+    }
+    #[cfg(feature = "mmap")]
+    #[test]
+    fn test_mmap_backend() {
+        let tmp_dir = tempfile::tempdir().unwrap();
+        let mut storage = new_storage(&tmp_dir);
+        storage.write_block(0, &[1, 2, 3]).unwrap();
+        storage.enable_mmap().unwrap();
+        assert_eq!(storage.read_block(0).unwrap().1, vec![1, 2, 3]);
+        // writes after mapping are visible, including blocks that grew the file
+        storage.write_block(0, &[4]).unwrap();
+        storage.write_block(5, &[5, 6, 7, 8]).unwrap();
+        assert_eq!(storage.read_block(0).unwrap().1, vec![4]);
+        assert_eq!(storage.read_block(5).unwrap().1, vec![5, 6, 7, 8]);
+        let data: Vec<u8> = (0..10).collect();
+        let record_id = storage.write_record(&data).unwrap();
+        assert_eq!(storage.read_record(record_id).unwrap(), data);
+    }
+    #[cfg(feature = "direct-io")]
+    #[test]
+    fn test_direct_backend() {
+        let tmp_dir = tempfile::tempdir().unwrap();
+        let mut storage = new_storage(&tmp_dir);
+        storage.write_block(0, &[1, 2, 3]).unwrap();
+        storage.enable_direct_io().unwrap();
+        assert_eq!(storage.read_block(0).unwrap().1, vec![1, 2, 3]);
+        // buffered writes are visible to direct reads, also past the first sector
+        storage.write_block(0, &[4]).unwrap();
+        let data: Vec<u8> = (0..255).collect();
+        let record_id = storage.write_record(&data).unwrap();
+        assert_eq!(storage.read_block(0).unwrap().1, vec![4]);
+        assert_eq!(storage.read_record(record_id).unwrap(), data);
+        // unaligned reads and reads past end of file
+        let file_path = tmp_dir.path().join("backend.hex");
+        let file_bytes = std::fs::read(&file_path).unwrap();
+        let mut backend = DirectBackend::open(file_path.to_str().unwrap()).unwrap();
+        assert!(backend.alignment() >= 512);
+        let mut buf = [0u8; 7];
+        assert_eq!(backend.read_at(3, &mut buf).unwrap(), 7);
+        assert_eq!(buf[..], file_bytes[3..10]);
+        let end = file_bytes.len() as u64;
+        assert_eq!(backend.read_at(end - 2, &mut buf).unwrap(), 2);
+        assert_eq!(backend.read_at(end + 4096, &mut buf).unwrap(), 0);
+    }
+}
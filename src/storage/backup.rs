@@ -0,0 +1,382 @@
+use super::incremental::{IncrementalEntry, IncrementalHeader};
+use super::*;
+use std::collections::BTreeMap;
+use std::io::prelude::*;
+use std::io::{BufReader, BufWriter};
+
+/// Copy of a storage file being written to another path, see Storage::begin_backup
+/// - copies blocks as they were when backup began, through a Snapshot
+/// - storage can be written between copy steps, those writes are not in the backup
+/// - backup file is marked dirty until last block is copied, so an unfinished
+///   backup opens with a full scan of block headers
+pub struct Backup {
+    snapshot: Snapshot,
+    file_writer: BufWriter<File>,
+    header: StorageHeader,
+    /// Allocation bitmap of backup file, built as blocks are copied
+    bitmap: Vec<u8>,
+    copied_block_count: u64,
+    finished: bool,
+}
+
+impl Backup {
+    /// Copy up to max_blocks more blocks to backup file
+    /// - backup file is completed and synced to disk with the last block
+    /// - returns: true once backup is complete
+    pub fn copy_step(
+        &mut self,
+        storage: &mut Storage,
+        max_blocks: usize,
+    ) -> Result<bool, StorageError> {
+        if self.finished {
+            return Ok(true);
+        }
+        let block_count = self.snapshot.block_count();
+        let max_blocks = u64::try_from(max_blocks).unwrap_or(u64::MAX);
+        let last_block = block_count.min(self.copied_block_count.saturating_add(max_blocks));
+        for block_index in self.copied_block_count..last_block {
+            let content = self
+                .snapshot
+                .block(storage, block_index)?
+                .unwrap_or_default();
+            let block_data = content.block_data;
+            let mut block_bytes = BlockHeader::for_data(&block_data)
+                .with_next_block(content.next_block)
+                .with_flags(content.block_flags)
+                .with_tag(content.tag)
+                .with_expiry(content.expires_at)
+                .with_generation(content.generation)
+                .to_bytes()
+                .to_vec();
+            block_bytes.extend_from_slice(&block_data);
+            self.file_writer
+                .write_all(&block_bytes)
+                .map_err(StorageError::io("write backup block", Some(block_index)))?;
+            // - pad block to block_len without holding the padding in memory
+            let padding_len = self.header.block_len - block_data.len() as u64;
+            std::io::copy(
+                &mut std::io::repeat(0).take(padding_len),
+                &mut self.file_writer,
+            )
+            .map_err(StorageError::io("write backup block", Some(block_index)))?;
+            if !block_data.is_empty() && block_index < self.header.bitmap_capacity as u64 {
+                self.bitmap[(block_index / 8) as usize] |= 1 << (block_index % 8);
+            }
+            self.copied_block_count = block_index + 1;
+        }
+        if self.copied_block_count < block_count {
+            return Ok(false);
+        }
+        self.finish()?;
+        Ok(true)
+    }
+    /// Check that the backup opened with options, followed by incrementals in order,
+    /// restores, without restoring it, see restore_from and apply_incremental
+    /// - the backup is opened read-only, options must give it the block transforms and
+    ///   key of the storage it was taken of
+    /// - Corruption if the backup is unfinished, a block checksum does not match or a
+    ///   record does not read back, like read_record
+    /// - each incremental backup must build on the backup before it, else Corruption,
+    ///   and have its block length, else InvalidBlockLength
+    /// - incremental backups are replayed in memory: block data is decoded, and once all
+    ///   are applied every linked block must hold data, else Corruption
+    /// - returns: generation of the last backup, the since of the next incremental backup
+    pub fn verify<R: Read>(
+        options: StorageOptions,
+        incrementals: impl IntoIterator<Item = R>,
+    ) -> Result<Generation, StorageError> {
+        let mut backup = options.read_only(true).open()?;
+        if backup.header.flags & STORAGE_FLAG_DIRTY != 0 {
+            return Err(StorageError::Corruption {
+                block_index: None,
+                reason: "backup is not finished",
+            });
+        }
+        if let Some(block_index) = backup.verify_all()?.first() {
+            return Err(StorageError::Corruption {
+                block_index: Some(*block_index),
+                reason: "backup block does not match its checksum",
+            });
+        }
+        backup.export(std::io::sink())?;
+        // - next block of every used block, as a restore would leave it
+        let mut next_blocks = BTreeMap::new();
+        for block_index in 0..backup.end_block_count {
+            if backup.is_used_block(block_index) {
+                let next_block = backup.read_block_header(block_index)?.next_block;
+                next_blocks.insert(block_index, next_block);
+            }
+        }
+        let block_len = backup.header.block_len;
+        let mut generation = backup.generation();
+        for incremental in incrementals {
+            let mut reader = BufReader::new(incremental);
+            let header = IncrementalHeader::read(&mut reader, block_len)?;
+            if header.since != generation {
+                return Err(StorageError::Corruption {
+                    block_index: None,
+                    reason: "incremental backup does not build on the backup before",
+                });
+            }
+            for _ in 0..header.entry_count {
+                match IncrementalEntry::read(&mut reader, block_len)? {
+                    IncrementalEntry::Deleted(block_index) => {
+                        next_blocks.remove(&block_index);
+                    }
+                    IncrementalEntry::Written {
+                        block_index,
+                        block_flags,
+                        next_block,
+                        block_data,
+                        ..
+                    } => {
+                        backup.decode_block_data(block_index, block_data, block_flags)?;
+                        next_blocks.insert(block_index, next_block);
+                    }
+                }
+            }
+            next_blocks.split_off(&header.block_count);
+            generation = header.generation;
+        }
+        for (block_index, next_block) in next_blocks.iter() {
+            if next_block.is_some_and(|next_block| !next_blocks.contains_key(&next_block)) {
+                return Err(StorageError::Corruption {
+                    block_index: Some(*block_index),
+                    reason: "backup block links to a free block",
+                });
+            }
+        }
+        Ok(generation)
+    }
+    /// Write bitmap, clear dirty flag and sync backup file
+    fn finish(&mut self) -> Result<(), StorageError> {
+        self.file_writer
+            .seek(std::io::SeekFrom::Start(BITMAP_OFFSET))
+            .map_err(StorageError::io("seek to backup bitmap", None))?;
+        self.file_writer
+            .write_all(&self.bitmap)
+            .map_err(StorageError::io("write backup bitmap", None))?;
+        self.header.flags &= !STORAGE_FLAG_DIRTY;
+        self.header.sequence += 1;
+        self.file_writer
+            .seek(std::io::SeekFrom::Start(storage_header_offset(
+                self.header.sequence,
+            )))
+            .map_err(StorageError::io("seek to backup header", None))?;
+        self.file_writer
+            .write_all(&self.header.to_bytes())
+            .map_err(StorageError::io("write backup header", None))?;
+        self.file_writer
+            .flush()
+            .map_err(StorageError::io("flush backup file", None))?;
+        self.file_writer
+            .get_ref()
+            .sync_all()
+            .map_err(StorageError::io("sync backup file", None))?;
+        self.finished = true;
+        Ok(())
+    }
+}
+
+impl Storage {
+    // ... ... ... ... ... ... ... ... ... ... Backup ... ... ... ... ... ... ... ... ... ...
+
+    /// Copy storage file to file_path, as it is now
+    /// - same as begin_backup followed by a single copy step of all blocks
+    pub fn backup_to(&mut self, file_path: String) -> Result<(), StorageError> {
+        let mut backup = self.begin_backup(file_path)?;
+        backup.copy_step(self, usize::MAX)?;
+        Ok(())
+    }
+    /// Start an online backup to file_path, see Backup
+    /// - creates/overwrites file_path and writes its storage header
+    /// - blocks are copied by Backup::copy_step, writes can go on in between
+    /// - block data is copied as stored, so the backup needs the same block
+    ///   transforms to be read, and the same key if encrypted
+    /// - backup keeps compression, encryption, the pipeline and append-only mode of the
+    ///   storage, and generations of the blocks copied
+    pub fn begin_backup(&mut self, file_path: String) -> Result<Backup, StorageError> {
+        let snapshot = self.snapshot();
+        let file = OpenOptions::new()
+            .write(true)
+            .truncate(true)
+            .create(true)
+            .open(file_path)
+            .map_err(StorageError::io("create backup file", None))?;
+        let mut file_writer = BufWriter::new(file);
+        let mut header = StorageHeader::new(self.header.block_len, self.header.bitmap_capacity);
+        let kept_flags = STORAGE_FLAG_ENCRYPTED | STORAGE_FLAG_APPEND_ONLY;
+        header.flags |= STORAGE_FLAG_DIRTY | (self.header.flags & kept_flags);
+        header.compression = self.header.compression;
+        header.pipeline = self.header.pipeline;
+        header.generation = self.header.generation;
+        let bitmap = vec![0u8; bitmap_len(header.bitmap_capacity) as usize];
+        // - every copy of the header, in file order
+        for sequence in 0..STORAGE_HEADER_COPIES {
+            header.sequence = sequence;
+            file_writer
+                .write_all(&header.to_bytes())
+                .map_err(StorageError::io("write backup header", None))?;
+        }
+        file_writer
+            .write_all(&bitmap)
+            .map_err(StorageError::io("write backup bitmap", None))?;
+        Ok(Backup {
+            snapshot,
+            file_writer,
+            header,
+            bitmap,
+            copied_block_count: 0,
+            finished: false,
+        })
+    }
+    /// Replace all blocks of this storage with blocks of backup at file_path
+    /// - backup must have the same block_len, else InvalidBlockLength of backup is returned
+    /// - blocks are written and deleted one by one like any other write, so observers,
+    ///   cache and snapshots stay in line
+    /// - block data is decoded with block transforms of this storage
+    pub fn restore_from(&mut self, file_path: String) -> Result<(), StorageError> {
+        let mut backup = Storage::open(file_path)?;
+        if backup.header.block_len != self.header.block_len {
+            return Err(StorageError::InvalidBlockLength {
+                block_len: backup.header.block_len,
+            });
+        }
+        let block_count = backup.end_block_count.max(self.end_block_count);
+        for block_index in 0..block_count {
+            match backup.current_block(block_index)? {
+                Some(content) => {
+                    let data = self.decode_block_data(
+                        block_index,
+                        content.block_data,
+                        content.block_flags,
+                    )?;
+                    let (next_block, expires_at) = (content.next_block, content.expires_at);
+                    self.write_linked_block(
+                        block_index,
+                        &data,
+                        next_block,
+                        expires_at,
+                        content.tag,
+                    )?;
+                }
+                None if self.is_used_block(block_index) => {
+                    self.delete_block(block_index, false)?;
+                }
+                None => {}
+            }
+        }
+        if self.end_block_count > backup.end_block_count {
+            self.trim_tail()?;
+        }
+        backup.close()
+    }
+}
+
+#[cfg(test)]
+mod unit_tests_backup {
+    use super::*;
+
+    fn file_path(tmp_dir: &tempfile::TempDir, name: &str) -> String {
+        tmp_dir.path().join(name).to_str().unwrap().to_string()
+    }
+
+    #[test]
+    fn test_backup_and_restore() {
+        let tmp_dir = tempfile::tempdir().unwrap();
+        let mut storage = Storage::new(file_path(&tmp_dir, "storage.hex"), 4).unwrap();
+        let data: Vec<u8> = (0..10).collect();
+        let record_id = storage.write_record(&data).unwrap();
+        storage.write_block(4, &[1]).unwrap();
+        let backup_path = file_path(&tmp_dir, "backup.hex");
+        storage.backup_to(backup_path.clone()).unwrap();
+        // backup opens as a storage file of its own
+        let mut backup = Storage::open(backup_path.clone()).unwrap();
+        assert_eq!(backup.end_block_count, 5);
+        assert_eq!(backup.free_blocks, [3].iter().cloned().collect());
+        assert_eq!(backup.read_record(record_id).unwrap(), data);
+        backup.close().unwrap();
+        // restore undoes later writes and deletes
+        storage.delete_record(record_id, true).unwrap();
+        storage.write_block(3, &[2]).unwrap();
+        storage.write_block(7, &[3]).unwrap();
+        storage.restore_from(backup_path).unwrap();
+        assert_eq!(storage.read_record(record_id).unwrap(), data);
+        assert_eq!(storage.read_block(3).unwrap().1, Vec::<u8>::new());
+        assert_eq!(storage.read_block(4).unwrap().1, vec![1]);
+        assert_eq!(storage.end_block_count, 5);
+    }
+    #[test]
+    fn test_online_backup() {
+        let tmp_dir = tempfile::tempdir().unwrap();
+        let mut storage = Storage::new(file_path(&tmp_dir, "storage.hex"), 4).unwrap();
+        for block_index in 0..4 {
+            storage.write_block(block_index, &[1]).unwrap();
+        }
+        let backup_path = file_path(&tmp_dir, "backup.hex");
+        let mut backup = storage.begin_backup(backup_path.clone()).unwrap();
+        assert!(!backup.copy_step(&mut storage, 2).unwrap());
+        // writes between steps are not in the backup
+        storage.write_block(3, &[2]).unwrap();
+        storage.write_block(5, &[2]).unwrap();
+        assert!(backup.copy_step(&mut storage, 2).unwrap());
+        assert!(backup.copy_step(&mut storage, 2).unwrap());
+        let mut backup = Storage::open(backup_path).unwrap();
+        assert_eq!(backup.end_block_count, 4);
+        assert_eq!(backup.read_block(3).unwrap().1, vec![1]);
+    }
+    #[test]
+    fn test_verify_backup() {
+        let tmp_dir = tempfile::tempdir().unwrap();
+        let mut storage = Storage::new(file_path(&tmp_dir, "storage.hex"), 4).unwrap();
+        storage.write_record(&[1; 10]).unwrap();
+        storage.write_block(4, &[2]).unwrap();
+        let since = storage.generation();
+        let backup_path = file_path(&tmp_dir, "backup.hex");
+        storage.backup_to(backup_path.clone()).unwrap();
+        let backup_options = StorageOptions::new(backup_path.clone());
+        let no_incrementals: Vec<&[u8]> = Vec::new();
+        assert_eq!(
+            Backup::verify(backup_options.clone(), no_incrementals.clone()).unwrap(),
+            since
+        );
+        // - a chain of incremental backups
+        storage.write_block(5, &[3]).unwrap();
+        let mut first = Vec::new();
+        let generation = storage.backup_incremental(since, &mut first).unwrap();
+        storage.delete_block(4, false).unwrap();
+        let mut second = Vec::new();
+        let last = storage.backup_incremental(generation, &mut second).unwrap();
+        let chain = vec![&first[..], &second[..]];
+        assert_eq!(Backup::verify(backup_options.clone(), chain).unwrap(), last);
+        let out_of_order = vec![&second[..], &first[..]];
+        assert!(matches!(
+            Backup::verify(backup_options.clone(), out_of_order),
+            Err(StorageError::Corruption { .. })
+        ));
+        // - a corrupted block of the backup
+        let block_offset = storage.block_offset(1).unwrap() + BLOCK_HEADER_SIZE as u64;
+        let mut file = OpenOptions::new().write(true).open(&backup_path).unwrap();
+        file.seek(std::io::SeekFrom::Start(block_offset)).unwrap();
+        file.write_all(&[9]).unwrap();
+        drop(file);
+        assert!(matches!(
+            Backup::verify(backup_options, no_incrementals),
+            Err(StorageError::Corruption {
+                block_index: Some(1),
+                ..
+            })
+        ));
+    }
+    #[test]
+    fn test_restore_rejects_other_block_len() {
+        let tmp_dir = tempfile::tempdir().unwrap();
+        let backup_path = file_path(&tmp_dir, "backup.hex");
+        Storage::new(backup_path.clone(), 8).unwrap();
+        let mut storage = Storage::new(file_path(&tmp_dir, "storage.hex"), 4).unwrap();
+        assert!(matches!(
+            storage.restore_from(backup_path),
+            Err(StorageError::InvalidBlockLength { block_len: 8 })
+        ));
+    }
+}
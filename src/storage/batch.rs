@@ -0,0 +1,269 @@
+use super::*;
+
+/// Block of a batch, ready to be written
+pub(super) struct BatchBlock {
+    pub(super) block_index: BlockIndex,
+    /// Data before block transforms, as given to observers
+    pub(super) data: Vec<u8>,
+    /// Data after compression and block transforms, as stored in file
+    pub(super) block_data: Vec<u8>,
+    /// Flags of block header, tell how block data was compressed
+    pub(super) block_flags: u32,
+    pub(super) next_block: Option<BlockIndex>,
+}
+
+impl Storage {
+    // ... ... ... ... ... ... ... ... ... Batch Writes ... ... ... ... ... ... ... ... ...
+
+    /// Write every payload as a record, allocating blocks for all of them up front
+    /// - blocks are taken from the longest runs of free blocks first, then appended,
+    ///   and handed out in ascending order, so records occupy contiguous blocks where possible
+    /// - each run of contiguous blocks is written with a single write, runs are cut where
+    ///   their padded bytes would not fit in memory
+    /// - all payloads are checked before the file is touched, a payload that is empty
+    ///   or does not fit its blocks after block transforms fails the whole batch
+    /// - NOTE: unlike write_record, a failed write can leave a record linking to a
+    ///   block that was not written yet
+    /// - returns: block indexes of each payload, first block index is its record id
+    pub fn write_batch(
+        &mut self,
+        payloads: &[&[u8]],
+    ) -> Result<Vec<Vec<BlockIndex>>, StorageError> {
+        if payloads.iter().any(|payload| payload.is_empty()) {
+            return Err(StorageError::EmptyRecord);
+        }
+        let chunk_len = self.chunk_len();
+        let block_count = payloads
+            .iter()
+            .map(|payload| payload.len().div_ceil(chunk_len))
+            .sum();
+        let mut block_indexes = self.allocate_runs(block_count)?.into_iter();
+        // - plan blocks of every payload
+        let mut payload_blocks = Vec::with_capacity(payloads.len());
+        let mut batch_blocks = Vec::with_capacity(block_count);
+        for payload in payloads.iter() {
+            let chunk_count = payload.len().div_ceil(chunk_len);
+            let indexes: Vec<BlockIndex> = block_indexes.by_ref().take(chunk_count).collect();
+            for (chunk_index, chunk) in payload.chunks(chunk_len).enumerate() {
+                let block_index = indexes[chunk_index];
+                let (block_data, block_flags) = self.encode_block_data(chunk)?;
+                if block_data.len() as u64 > self.header.block_len {
+                    return Err(StorageError::BlockTooLarge {
+                        block_index,
+                        data_len: block_data.len(),
+                        block_len: self.header.block_len,
+                    });
+                }
+                batch_blocks.push(BatchBlock {
+                    block_index,
+                    data: chunk.to_vec(),
+                    block_data,
+                    block_flags,
+                    next_block: indexes.get(chunk_index + 1).cloned(),
+                });
+            }
+            payload_blocks.push(indexes);
+        }
+        batch_blocks.sort_by_key(|batch_block| batch_block.block_index);
+        self.write_block_runs(&batch_blocks)?;
+        Ok(payload_blocks)
+    }
+    /// Write blocks in ascending order of block index, a write per run of contiguous blocks
+    /// - runs are cut where their padded bytes would not fit in memory
+    pub(super) fn write_block_runs(
+        &mut self,
+        batch_blocks: &[BatchBlock],
+    ) -> Result<(), StorageError> {
+        // - highest block grows the file the most, check it before any block is written
+        if let Some(last_block) = batch_blocks.last() {
+            self.check_quota(last_block.block_index)?;
+        }
+        let block_size =
+            block_size(self.header.block_len).and_then(|size| usize::try_from(size).ok());
+        let mut run_start = 0;
+        for run_end in 1..=batch_blocks.len() {
+            let run_bytes_fit = block_size
+                .and_then(|size| size.checked_mul(run_end - run_start + 1))
+                .is_some();
+            let run_continues = run_end < batch_blocks.len()
+                && batch_blocks[run_end].block_index == batch_blocks[run_end - 1].block_index + 1
+                && run_bytes_fit;
+            if !run_continues {
+                self.write_block_run(&batch_blocks[run_start..run_end])?;
+                run_start = run_end;
+            }
+        }
+        Ok(())
+    }
+    /// Pick block indexes for block_count new blocks, preferring contiguous blocks
+    /// - takes longest runs of free blocks first, ties go to lower index,
+    ///   then blocks past end of file
+    /// - appends only with AllocationStrategy::AppendOnly or an append-only storage file
+    /// - returns: block indexes in ascending order
+    fn allocate_runs(&self, block_count: usize) -> Result<Vec<BlockIndex>, StorageError> {
+        if self.allocation == AllocationStrategy::AppendOnly || self.is_append_only() {
+            return self.append_blocks(block_count);
+        }
+        let mut free_runs = self.free_runs();
+        free_runs.sort_by(|a, b| b.1.cmp(&a.1).then(a.0.cmp(&b.0)));
+        // - take free runs, then append
+        let mut block_indexes = Vec::with_capacity(block_count);
+        for (first_block, length) in free_runs {
+            let remaining = block_count - block_indexes.len();
+            block_indexes.extend((first_block..).take(length.min(remaining)));
+        }
+        let appended = self.append_blocks(block_count - block_indexes.len())?;
+        block_indexes.extend(appended);
+        block_indexes.sort_unstable();
+        Ok(block_indexes)
+    }
+    /// Write blocks with contiguous indexes in one write
+    /// - blocks but the last are zero padded to block_len, so each header lands at its offset
+    /// - block headers hold no expiry
+    fn write_block_run(&mut self, batch_blocks: &[BatchBlock]) -> Result<(), StorageError> {
+        let first_block = match batch_blocks.first() {
+            Some(batch_block) => batch_block.block_index,
+            None => return Ok(()),
+        };
+        let block_offset = self.block_offset(first_block)?;
+        // - write_block_runs only builds runs of more than one block if their bytes fit in usize
+        let block_size = block_size(self.header.block_len)
+            .and_then(|size| usize::try_from(size).ok())
+            .unwrap_or(usize::MAX);
+        for batch_block in batch_blocks.iter() {
+            for observer in self.observers.iter_mut() {
+                observer.before_write(batch_block.block_index, &batch_block.data);
+            }
+        }
+        for batch_block in batch_blocks.iter() {
+            self.preserve_for_snapshots(batch_block.block_index)?;
+        }
+        // - build run bytes
+        let mut run_bytes = Vec::new();
+        for (position, batch_block) in batch_blocks.iter().enumerate() {
+            let block_header = BlockHeader::for_data(&batch_block.block_data)
+                .with_next_block(batch_block.next_block)
+                .with_flags(batch_block.block_flags)
+                .with_generation(self.next_generation());
+            run_bytes.extend_from_slice(&block_header.to_bytes());
+            run_bytes.extend_from_slice(&batch_block.block_data);
+            if position + 1 < batch_blocks.len() {
+                run_bytes.resize(block_size * (position + 1), 0);
+            }
+        }
+        // - write the run from first block
+        self.write_file_at(
+            block_offset,
+            &run_bytes,
+            "write block run",
+            Some(first_block),
+        )?;
+        // - update free_blocks map and end_block_count
+        for batch_block in batch_blocks.iter() {
+            self.free_blocks.remove(&batch_block.block_index);
+            if batch_block.block_index >= self.end_block_count {
+                // blocks skipped over are zero filled, so they are free
+                self.free_blocks
+                    .extend(self.end_block_count..batch_block.block_index);
+                self.end_block_count = batch_block.block_index + 1;
+            }
+            self.written_past_preallocated(batch_block.block_index);
+        }
+        // - update bitmap, once per bitmap byte
+        let mut last_bitmap_byte = None;
+        for batch_block in batch_blocks.iter() {
+            let bitmap_byte = batch_block.block_index / 8;
+            if last_bitmap_byte != Some(bitmap_byte) {
+                self.write_bitmap_bit(batch_block.block_index)?;
+                last_bitmap_byte = Some(bitmap_byte);
+            }
+        }
+        for batch_block in batch_blocks.iter() {
+            self.cache_block(batch_block.block_index, &batch_block.data);
+            self.track_expiry(batch_block.block_index, None);
+            self.track_content(batch_block.block_index, Some(&batch_block.data));
+            for observer in self.observers.iter_mut() {
+                observer.after_write(batch_block.block_index, &batch_block.data);
+            }
+            self.check_block_consistency(batch_block.block_index)?;
+        }
+        self.written(u32::try_from(batch_blocks.len()).unwrap_or(u32::MAX))?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod unit_tests_batch {
+    use super::*;
+
+    fn storage_path(tmp_dir: &tempfile::TempDir) -> String {
+        tmp_dir
+            .path()
+            .join("batch.hex")
+            .to_str()
+            .unwrap()
+            .to_string()
+    }
+    fn new_storage(tmp_dir: &tempfile::TempDir) -> Storage {
+        Storage::new(storage_path(tmp_dir), 4).unwrap()
+    }
+
+    #[test]
+    fn test_write_batch() {
+        let tmp_dir = tempfile::tempdir().unwrap();
+        let mut storage = new_storage(&tmp_dir);
+        let long_payload: Vec<u8> = (0..10).collect();
+        let payloads: [&[u8]; 3] = [&[1], &long_payload, &[2, 3]];
+        let payload_blocks = storage.write_batch(&payloads).unwrap();
+        assert_eq!(payload_blocks, vec![vec![0], vec![1, 2, 3], vec![4]]);
+        assert_eq!(storage.end_block_count, 5);
+        assert_eq!(storage.read_record(0).unwrap(), vec![1]);
+        assert_eq!(storage.read_record(1).unwrap(), long_payload);
+        assert_eq!(storage.read_record(4).unwrap(), vec![2, 3]);
+        // written file reads back after reopen
+        storage.close().unwrap();
+        let mut storage = Storage::open(storage_path(&tmp_dir)).unwrap();
+        assert_eq!(storage.read_record(1).unwrap(), long_payload);
+        assert_eq!(storage.verify_all().unwrap(), Vec::<BlockIndex>::new());
+    }
+    #[test]
+    fn test_write_batch_prefers_contiguous_runs() {
+        let tmp_dir = tempfile::tempdir().unwrap();
+        let mut storage = new_storage(&tmp_dir);
+        for block_index in 0..8 {
+            storage.write_block(block_index, &[9]).unwrap();
+        }
+        // free runs: 1, 3..6
+        for block_index in [1, 3, 4, 5].iter() {
+            storage.delete_block(*block_index, false).unwrap();
+        }
+        let payloads: [&[u8]; 2] = [&[1, 1, 1, 1, 1], &[2; 5]];
+        let payload_blocks = storage.write_batch(&payloads).unwrap();
+        // longest run 3..6 is used first, then run 1
+        // blocks are handed out in ascending order
+        assert_eq!(payload_blocks, vec![vec![1, 3], vec![4, 5]]);
+        // no free blocks left, blocks are appended
+        let payload_blocks = storage.write_batch(&[&[3; 5]]).unwrap();
+        assert_eq!(payload_blocks, vec![vec![8, 9]]);
+        assert!(storage.free_blocks.is_empty());
+        assert_eq!(storage.read_record(1).unwrap(), vec![1; 5]);
+        assert_eq!(storage.read_record(4).unwrap(), vec![2; 5]);
+        assert_eq!(storage.read_record(8).unwrap(), vec![3; 5]);
+        assert_eq!(storage.read_block(7).unwrap().1, vec![9]);
+    }
+    #[test]
+    fn test_write_batch_rejects_before_writing() {
+        let tmp_dir = tempfile::tempdir().unwrap();
+        let mut storage = new_storage(&tmp_dir);
+        let payloads: [&[u8]; 2] = [&[1], &[]];
+        assert!(matches!(
+            storage.write_batch(&payloads),
+            Err(StorageError::EmptyRecord)
+        ));
+        assert_eq!(storage.end_block_count, 0);
+        assert_eq!(
+            storage.write_batch(&[]).unwrap(),
+            Vec::<Vec<BlockIndex>>::new()
+        );
+    }
+}
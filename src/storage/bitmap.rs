@@ -0,0 +1,268 @@
+use super::*;
+
+/// Number of blocks tracked by allocation bitmap of files created with Storage::new
+/// - 32768 blocks take a 4 KiB bitmap
+pub const DEFAULT_BITMAP_CAPACITY: u32 = 32768;
+
+impl Storage {
+    // ... ... ... ... ... ... ... Allocation Bitmap ... ... ... ... ... ... ...
+
+    /// Check if block holds data, without reading it from file (in memory)
+    pub(super) fn is_used_block(&self, block_index: BlockIndex) -> bool {
+        block_index < self.end_block_count && !self.free_blocks.contains(&block_index)
+    }
+    /// Build bitmap byte covering blocks byte_index * 8 .. byte_index * 8 + 8 from in memory state
+    fn bitmap_byte(&self, byte_index: u32) -> u8 {
+        let mut byte = 0u8;
+        for bit in 0..8 {
+            let block_index = byte_index as u64 * 8 + bit;
+            if block_index < self.header.bitmap_capacity as u64 && self.is_used_block(block_index) {
+                byte |= 1 << bit;
+            }
+        }
+        byte
+    }
+    /// Write bitmap byte covering block to storage file
+    /// - no-op for blocks beyond bitmap_capacity
+    pub(super) fn write_bitmap_bit(&mut self, block_index: BlockIndex) -> Result<(), StorageError> {
+        if block_index >= self.header.bitmap_capacity as u64 {
+            return Ok(());
+        }
+        // - below bitmap_capacity, so byte index fits in u32
+        let byte_index = (block_index / 8) as u32;
+        let byte = self.bitmap_byte(byte_index);
+        self.write_bitmap_bytes(byte_index as u64, &[byte])
+    }
+    /// Write full allocation bitmap to storage file from in memory state
+    pub(super) fn write_bitmap(&mut self) -> Result<(), StorageError> {
+        let bitmap_len = bitmap_len(self.header.bitmap_capacity);
+        let bitmap: Vec<u8> = (0..bitmap_len as u32)
+            .map(|byte_index| self.bitmap_byte(byte_index))
+            .collect();
+        self.write_bitmap_bytes(0, &bitmap)
+    }
+    fn write_bitmap_bytes(&mut self, byte_offset: u64, bytes: &[u8]) -> Result<(), StorageError> {
+        let offset = BITMAP_OFFSET + byte_offset;
+        self.write_file_at(offset, bytes, "write allocation bitmap", None)
+    }
+    /// Load free blocks Set from allocation bitmap in storage file
+    /// -- total blocks - update self.end_block_count, from file length
+    /// -- free blocks - update self.free_blocks
+    /// - blocks beyond bitmap_capacity are loaded by scanning their block headers
+    pub(super) fn read_bitmap(&mut self) -> Result<(), StorageError> {
+        let mut bitmap = vec![0u8; bitmap_len(self.header.bitmap_capacity) as usize];
+        let read_size = positional::read_at(&self.file_reader, BITMAP_OFFSET, &mut bitmap)
+            .map_err(StorageError::io("read allocation bitmap", None))?;
+        if read_size != bitmap.len() {
+            return Err(StorageError::io("read allocation bitmap", None)(
+                std::io::ErrorKind::UnexpectedEof.into(),
+            ));
+        }
+        // - blocks tracked by bitmap
+        let block_count = self.count_blocks_on_disk()?;
+        let tracked_block_count = block_count.min(self.header.bitmap_capacity as u64);
+        self.free_blocks = (0..tracked_block_count)
+            .filter(|block_index| bitmap[*block_index as usize / 8] & (1 << (block_index % 8)) == 0)
+            .collect();
+        self.end_block_count = tracked_block_count;
+        // - blocks beyond bitmap
+        if block_count > tracked_block_count {
+            self.scan_block_headers(tracked_block_count)?;
+        }
+        Ok(())
+    }
+    /// Set or clear dirty flag in storage header
+    /// - generation is written with the flag, see Storage::generation
+    pub(super) fn write_dirty_flag(&mut self, dirty: bool) -> Result<(), StorageError> {
+        let flags = self.header.flags;
+        if dirty {
+            self.header.flags |= STORAGE_FLAG_DIRTY;
+        } else {
+            self.header.flags &= !STORAGE_FLAG_DIRTY;
+        }
+        if let Err(error) = self.set_storage_header() {
+            self.header.flags = flags;
+            return Err(error);
+        }
+        self.dirty_flag_set = dirty;
+        Ok(())
+    }
+
+    /// Set dirty flag once the file is open, and sync it, so a crash after any later
+    /// write finds the flag set
+    /// - dedup table, if any, is truncated away first, see remove_dedup_table
+    pub(super) fn mark_dirty(&mut self) -> Result<(), StorageError> {
+        self.remove_dedup_table()?;
+        self.write_dirty_flag(true)?;
+        self.flush()
+    }
+    /// Clear dirty flag, so next open can trust the allocation bitmap
+    /// - blocks, bitmap and dedup table, if loaded, are synced before the flag is
+    ///   cleared, the flag after
+    /// - the flag stays set if a write or sync failed since open, as the bitmap may not
+    ///   match block headers, next open rebuilds it
+    pub(super) fn mark_clean(&mut self) -> Result<(), StorageError> {
+        if self.write_failed.load(Ordering::Relaxed) {
+            return Ok(());
+        }
+        self.write_dedup_table()?;
+        self.flush()?;
+        self.write_dirty_flag(false)?;
+        self.flush()
+    }
+
+    // ... ... ... ... ... ... ... ... ... ... ... ... ... ... ... ... ...
+
+    /// Close storage file
+    /// - syncs writes and clears dirty flag so next open can trust the allocation bitmap,
+    ///   see mark_clean
+    /// - dropping Storage does the same but ignores errors
+    /// - a read-only storage file was not marked dirty, nothing is written
+    pub fn close(mut self) -> Result<(), StorageError> {
+        if self.read_only {
+            return Ok(());
+        }
+        self.mark_clean()
+    }
+}
+
+impl Drop for Storage {
+    fn drop(&mut self) {
+        // only clear the flag this object set, a half opened file keeps its state
+        if self.dirty_flag_set {
+            // nothing to report an error to, next open falls back to a full scan
+            let _ = self.mark_clean();
+        }
+    }
+}
+
+#[cfg(test)]
+mod unit_tests_bitmap {
+    use super::*;
+
+    fn file_path(tmp_dir: &tempfile::TempDir) -> String {
+        let file_path = tmp_dir.path().join("bitmap.hex");
+        file_path.to_str().unwrap().to_string()
+    }
+    /// overwrite block header on disk with header of block_data, keeping in memory state untouched
+    fn corrupt_block_header(file_path: &str, block_offset: u64, block_data: &[u8]) {
+        let mut file_bytes = std::fs::read(file_path).unwrap();
+        let offset = block_offset as usize;
+        let block_header = BlockHeader::for_data(block_data).to_bytes();
+        file_bytes[offset..offset + BLOCK_HEADER_SIZE].copy_from_slice(&block_header);
+        std::fs::write(file_path, file_bytes).unwrap();
+    }
+
+    #[test]
+    fn test_bitmap_bits_follow_writes_and_deletes() {
+        let tmp_dir = tempfile::tempdir().unwrap();
+        let file_path = file_path(&tmp_dir);
+        let mut storage = Storage::new_with_bitmap_capacity(file_path.clone(), 4, 16).unwrap();
+        for block_index in 0..10 {
+            storage.write_block(block_index, &[1]).unwrap();
+        }
+        storage.delete_block(1, false).unwrap();
+        storage.delete_block(9, true).unwrap();
+        let file_bytes = std::fs::read(&file_path).unwrap();
+        let bitmap = &file_bytes[BITMAP_OFFSET as usize..BITMAP_OFFSET as usize + 2];
+        assert_eq!(bitmap, [0b1111_1101, 0b0000_0001]);
+    }
+    #[test]
+    fn test_dirty_flag_lifecycle() {
+        let tmp_dir = tempfile::tempdir().unwrap();
+        let file_path = file_path(&tmp_dir);
+        let flags = |file_path: &str| {
+            let file = File::open(file_path).unwrap();
+            StorageHeader::read_from(&file).unwrap().flags
+        };
+        let storage = Storage::new(file_path.clone(), 4).unwrap();
+        assert_eq!(flags(&file_path), 1);
+        storage.close().unwrap();
+        assert_eq!(flags(&file_path), 0);
+        let storage = Storage::open(file_path.clone()).unwrap();
+        assert_eq!(flags(&file_path), 1);
+        drop(storage);
+        assert_eq!(flags(&file_path), 0);
+        // - a failed write keeps the flag set, also when dropped
+        let mut storage = Storage::open(file_path.clone()).unwrap();
+        let file_writer =
+            std::mem::replace(&mut storage.file_writer, File::open(&file_path).unwrap());
+        assert!(storage.write_block(0, &[1]).is_err());
+        storage.file_writer = file_writer;
+        drop(storage);
+        assert_eq!(flags(&file_path), 1);
+        let storage = Storage::open(file_path.clone()).unwrap();
+        storage.close().unwrap();
+        assert_eq!(flags(&file_path), 0);
+    }
+    #[test]
+    fn test_clean_open_trusts_bitmap() {
+        let tmp_dir = tempfile::tempdir().unwrap();
+        let file_path = file_path(&tmp_dir);
+        let mut storage = Storage::new_with_bitmap_capacity(file_path.clone(), 4, 16).unwrap();
+        storage.write_block(0, &[1]).unwrap();
+        storage.write_block(1, &[2]).unwrap();
+        storage.delete_block(0, false).unwrap();
+        let block_0_offset = storage.block_offset(0).unwrap();
+        storage.close().unwrap();
+        // block header says block 0 holds data, bitmap says it is free
+        corrupt_block_header(&file_path, block_0_offset, &[1]);
+        let mut storage = Storage::open(file_path.clone()).unwrap();
+        assert_eq!(storage.end_block_count, 2);
+        assert!(storage.free_blocks.contains(&0));
+        // rebuild from block headers trusts the headers
+        assert_eq!(storage.rebuild_free_list().unwrap(), 0);
+    }
+    #[test]
+    fn test_dirty_open_scans_block_headers() {
+        let tmp_dir = tempfile::tempdir().unwrap();
+        let file_path = file_path(&tmp_dir);
+        let mut storage = Storage::new_with_bitmap_capacity(file_path.clone(), 4, 16).unwrap();
+        storage.write_block(0, &[1]).unwrap();
+        storage.write_block(1, &[2]).unwrap();
+        storage.delete_block(0, false).unwrap();
+        let block_0_offset = storage.block_offset(0).unwrap();
+        // simulate crash, dirty flag stays set
+        storage.crash();
+        corrupt_block_header(&file_path, block_0_offset, &[1]);
+        let mut storage = Storage::open(file_path.clone()).unwrap();
+        assert!(storage.free_blocks.is_empty());
+        // bitmap was rewritten from block headers
+        let file_bytes = std::fs::read(&file_path).unwrap();
+        assert_eq!(file_bytes[BITMAP_OFFSET as usize], 0b0000_0011);
+        let (_, data) = storage.read_block(0).unwrap();
+        assert_eq!(data, vec![1]);
+    }
+    #[test]
+    fn test_blocks_beyond_bitmap_capacity() {
+        let tmp_dir = tempfile::tempdir().unwrap();
+        let file_path = file_path(&tmp_dir);
+        let mut storage = Storage::new_with_bitmap_capacity(file_path.clone(), 4, 2).unwrap();
+        for block_index in 0..5 {
+            storage
+                .write_block(block_index, &[block_index as u8 + 1])
+                .unwrap();
+        }
+        storage.delete_block(1, false).unwrap();
+        storage.delete_block(3, false).unwrap();
+        storage.close().unwrap();
+        let mut storage = Storage::open(file_path.clone()).unwrap();
+        assert_eq!(storage.end_block_count, 5);
+        assert_eq!(storage.free_blocks, [1, 3].iter().cloned().collect());
+        let (_, data) = storage.read_block(4).unwrap();
+        assert_eq!(data, vec![5]);
+    }
+    #[test]
+    fn test_skipped_blocks_are_free() {
+        let tmp_dir = tempfile::tempdir().unwrap();
+        let file_path = file_path(&tmp_dir);
+        let mut storage = Storage::new_with_bitmap_capacity(file_path.clone(), 4, 16).unwrap();
+        storage.write_block(3, &[1]).unwrap();
+        assert_eq!(storage.free_blocks, [0, 1, 2].iter().cloned().collect());
+        storage.close().unwrap();
+        let mut storage = Storage::open(file_path.clone()).unwrap();
+        assert_eq!(storage.free_blocks, [0, 1, 2].iter().cloned().collect());
+        // full scan agrees with bitmap
+        assert_eq!(storage.rebuild_free_list().unwrap(), 3);
+    }
+}
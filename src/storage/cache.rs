@@ -0,0 +1,287 @@
+use super::*;
+use std::collections::{BTreeMap, HashMap, VecDeque};
+
+/// Eviction policy of block cache
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum CachePolicy {
+    /// Evict least recently read or written block
+    Lru,
+    /// Evict first block on the clock that was not used since the hand last passed it
+    /// - cheaper than Lru on hits, a hit only sets a flag
+    Clock,
+}
+
+/// Counters of block cache, for tuning its capacity
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct CacheStats {
+    /// Reads served from cache
+    pub hits: u64,
+    /// Reads that went to storage file
+    pub misses: u64,
+    /// Blocks dropped to make room for other blocks
+    pub evictions: u64,
+    /// Bytes of block data currently cached
+    pub cached_bytes: usize,
+}
+
+struct CacheEntry {
+    data: Vec<u8>,
+    /// Tick of insertion, tells entry apart from earlier entries of same block on the clock
+    inserted: u64,
+    /// Tick of last use, key in lru order
+    last_used: u64,
+    /// Used since clock hand last passed
+    referenced: bool,
+}
+
+/// Buffer pool of decoded block data, in front of read_block
+/// - write-through: writes go to storage file and replace cached data,
+///   so cached blocks are never dirty and never need a flush
+pub(super) struct BlockCache {
+    policy: CachePolicy,
+    capacity_bytes: usize,
+    entries: HashMap<BlockIndex, CacheEntry>,
+    /// Lru: cached blocks by tick of last use
+    lru: BTreeMap<u64, BlockIndex>,
+    /// Clock: (block, tick of insertion) in insertion order, front is under the hand
+    /// - may hold entries removed or replaced since, they are skipped
+    clock: VecDeque<(BlockIndex, u64)>,
+    tick: u64,
+    stats: CacheStats,
+    /// Reads and writes leave cache alone, see Storage::set_cache_bypass
+    bypass: bool,
+}
+
+impl BlockCache {
+    fn new(capacity_bytes: usize, policy: CachePolicy) -> Self {
+        BlockCache {
+            policy,
+            capacity_bytes,
+            entries: HashMap::new(),
+            lru: BTreeMap::new(),
+            clock: VecDeque::new(),
+            tick: 0,
+            stats: CacheStats::default(),
+            bypass: false,
+        }
+    }
+    /// Cached data of block, counting a hit or miss
+    fn get(&mut self, block_index: BlockIndex) -> Option<Vec<u8>> {
+        self.tick += 1;
+        let entry = match self.entries.get_mut(&block_index) {
+            Some(entry) => entry,
+            None => {
+                self.stats.misses += 1;
+                return None;
+            }
+        };
+        self.stats.hits += 1;
+        match self.policy {
+            CachePolicy::Lru => {
+                self.lru.remove(&entry.last_used);
+                self.lru.insert(self.tick, block_index);
+            }
+            CachePolicy::Clock => entry.referenced = true,
+        }
+        entry.last_used = self.tick;
+        Some(entry.data.clone())
+    }
+    /// Cache data of block, evicting other blocks until it fits
+    /// - data larger than capacity is not cached
+    fn insert(&mut self, block_index: BlockIndex, data: Vec<u8>) {
+        self.remove(block_index);
+        if data.len() > self.capacity_bytes {
+            return;
+        }
+        while self.stats.cached_bytes + data.len() > self.capacity_bytes {
+            if !self.evict() {
+                break;
+            }
+        }
+        self.tick += 1;
+        self.stats.cached_bytes += data.len();
+        match self.policy {
+            CachePolicy::Lru => {
+                self.lru.insert(self.tick, block_index);
+            }
+            CachePolicy::Clock => self.clock.push_back((block_index, self.tick)),
+        }
+        let entry = CacheEntry {
+            data,
+            inserted: self.tick,
+            last_used: self.tick,
+            referenced: false,
+        };
+        self.entries.insert(block_index, entry);
+    }
+    /// Drop block from cache, if cached
+    fn remove(&mut self, block_index: BlockIndex) {
+        if let Some(entry) = self.entries.remove(&block_index) {
+            self.stats.cached_bytes -= entry.data.len();
+            self.lru.remove(&entry.last_used);
+        }
+    }
+    /// Drop one block chosen by policy
+    /// - returns: false if cache is empty
+    fn evict(&mut self) -> bool {
+        let victim = match self.policy {
+            CachePolicy::Lru => self.lru.values().next().cloned(),
+            CachePolicy::Clock => self.clock_victim(),
+        };
+        match victim {
+            Some(block_index) => {
+                self.remove(block_index);
+                self.stats.evictions += 1;
+                true
+            }
+            None => false,
+        }
+    }
+    /// Advance clock hand to first block not referenced since last pass
+    /// - referenced blocks get a second chance
+    fn clock_victim(&mut self) -> Option<BlockIndex> {
+        while let Some((block_index, inserted)) = self.clock.pop_front() {
+            match self.entries.get_mut(&block_index) {
+                // replaced since, a newer entry of block is on the clock
+                Some(entry) if entry.inserted != inserted => {}
+                Some(entry) if entry.referenced => {
+                    entry.referenced = false;
+                    self.clock.push_back((block_index, inserted));
+                }
+                Some(_) => return Some(block_index),
+                // removed since it was inserted
+                None => {}
+            }
+        }
+        None
+    }
+}
+
+impl Storage {
+    // ... ... ... ... ... ... ... ... ... Block Cache ... ... ... ... ... ... ... ... ...
+
+    /// Enable cache of block data read with read_block
+    /// - capacity_bytes bounds cached block data, after block transforms are reversed
+    /// - replaces current cache, dropping its blocks and counters
+    /// - cache is in memory only, it starts empty each time the file is opened
+    pub fn enable_cache(&mut self, capacity_bytes: usize, policy: CachePolicy) {
+        self.cache = Some(BlockCache::new(capacity_bytes, policy));
+    }
+    /// Disable block cache, dropping its blocks and counters
+    pub fn disable_cache(&mut self) {
+        self.cache = None;
+    }
+    /// Counters of block cache, None when disabled
+    pub fn cache_stats(&self) -> Option<CacheStats> {
+        self.cache.as_ref().map(|cache| cache.stats)
+    }
+    /// Serve no reads from block cache and cache no blocks read or written while bypass
+    /// is set, e.g. for blocks of a column family that would evict hot blocks of others
+    /// - a block written while bypassing is dropped from cache, so cached data stays
+    ///   current
+    /// - reads while bypassing count as neither hits nor misses
+    /// - returns: previous setting, false when cache is disabled
+    pub(crate) fn set_cache_bypass(&mut self, bypass: bool) -> bool {
+        match self.cache.as_mut() {
+            Some(cache) => std::mem::replace(&mut cache.bypass, bypass),
+            None => false,
+        }
+    }
+    /// Cached data of block, None on miss, when bypassed or when cache is disabled
+    pub(super) fn cached_block(&mut self, block_index: BlockIndex) -> Option<Vec<u8>> {
+        let cache = self.cache.as_mut().filter(|cache| !cache.bypass)?;
+        cache.get(block_index)
+    }
+    /// Put data of block read from file or written to it in cache
+    /// - when bypassed, drop block from cache instead
+    pub(super) fn cache_block(&mut self, block_index: BlockIndex, data: &[u8]) {
+        match self.cache.as_mut() {
+            Some(cache) if cache.bypass => cache.remove(block_index),
+            Some(cache) => cache.insert(block_index, data.to_vec()),
+            None => {}
+        }
+    }
+    /// Drop deleted block from cache
+    pub(super) fn uncache_block(&mut self, block_index: BlockIndex) {
+        if let Some(cache) = self.cache.as_mut() {
+            cache.remove(block_index);
+        }
+    }
+}
+
+#[cfg(test)]
+mod unit_tests_cache {
+    use super::*;
+
+    fn new_storage(tmp_dir: &tempfile::TempDir) -> (Storage, String) {
+        let file_path = tmp_dir.path().join("cache.hex");
+        let file_path = file_path.to_str().unwrap().to_string();
+        (Storage::new(file_path.clone(), 4).unwrap(), file_path)
+    }
+
+    #[test]
+    fn test_lru_eviction() {
+        let mut cache = BlockCache::new(8, CachePolicy::Lru);
+        cache.insert(0, vec![0; 4]);
+        cache.insert(1, vec![1; 4]);
+        // block 0 becomes most recently used
+        assert_eq!(cache.get(0), Some(vec![0; 4]));
+        cache.insert(2, vec![2; 4]);
+        assert_eq!(cache.get(1), None);
+        assert_eq!(cache.get(0), Some(vec![0; 4]));
+        assert_eq!(cache.get(2), Some(vec![2; 4]));
+        assert_eq!(cache.stats.evictions, 1);
+        assert_eq!(cache.stats.cached_bytes, 8);
+        // larger than capacity, not cached
+        cache.insert(3, vec![3; 9]);
+        assert_eq!(cache.get(3), None);
+        assert_eq!(cache.stats.cached_bytes, 8);
+    }
+    #[test]
+    fn test_clock_eviction() {
+        let mut cache = BlockCache::new(12, CachePolicy::Clock);
+        cache.insert(0, vec![0; 4]);
+        cache.insert(1, vec![1; 4]);
+        cache.insert(2, vec![2; 4]);
+        // block 0 gets a second chance, block 1 is evicted
+        cache.get(0);
+        cache.insert(3, vec![3; 4]);
+        assert_eq!(cache.get(1), None);
+        // removed block is skipped by the hand
+        cache.remove(2);
+        cache.insert(4, vec![4; 8]);
+        assert_eq!(cache.get(0), None);
+        assert_eq!(cache.get(3), Some(vec![3; 4]));
+        assert_eq!(cache.get(4), Some(vec![4; 8]));
+        assert_eq!(cache.stats.evictions, 2);
+    }
+    #[test]
+    fn test_storage_cache() {
+        let tmp_dir = tempfile::tempdir().unwrap();
+        let (mut storage, file_path) = new_storage(&tmp_dir);
+        assert_eq!(storage.cache_stats(), None);
+        storage.enable_cache(64, CachePolicy::Lru);
+        storage.write_block(0, &[1, 2, 3]).unwrap();
+        assert_eq!(storage.read_block(0).unwrap().1, vec![1, 2, 3]);
+        // data is served from cache, even if the file changes under it
+        std::fs::write(&file_path, []).unwrap();
+        assert_eq!(storage.read_block(0).unwrap().1, vec![1, 2, 3]);
+        let stats = storage.cache_stats().unwrap();
+        assert_eq!((stats.hits, stats.misses, stats.cached_bytes), (2, 0, 3));
+        // delete drops block from cache, free blocks are not counted
+        storage.delete_block(0, false).unwrap();
+        assert_eq!(storage.read_block(0).unwrap().1, Vec::<u8>::new());
+        assert_eq!(storage.cache_stats().unwrap().cached_bytes, 0);
+    }
+    #[test]
+    fn test_storage_cache_miss() {
+        let tmp_dir = tempfile::tempdir().unwrap();
+        let (mut storage, _) = new_storage(&tmp_dir);
+        storage.write_block(0, &[1, 2, 3]).unwrap();
+        storage.enable_cache(64, CachePolicy::Clock);
+        assert_eq!(storage.read_block(0).unwrap().1, vec![1, 2, 3]);
+        assert_eq!(storage.read_block(0).unwrap().1, vec![1, 2, 3]);
+        let stats = storage.cache_stats().unwrap();
+        assert_eq!((stats.hits, stats.misses), (1, 1));
+    }
+}
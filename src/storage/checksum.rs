@@ -0,0 +1,207 @@
+use super::*;
+
+// ... ... ... ... ... ... ... ... ... CRC-32 ... ... ... ... ... ... ... ... ... ...
+
+/// Reversed polynomial of CRC-32 (IEEE 802.3), as used by zlib, gzip and png
+const CRC32_POLYNOMIAL: u32 = 0xedb8_8320;
+
+/// Lookup table of CRC-32 remainders of every byte value
+const CRC32_TABLE: [u32; 256] = crc32_table();
+
+const fn crc32_table() -> [u32; 256] {
+    let mut table = [0u32; 256];
+    let mut byte = 0;
+    while byte < 256 {
+        let mut crc = byte as u32;
+        let mut bit = 0;
+        while bit < 8 {
+            crc = if crc & 1 == 1 {
+                (crc >> 1) ^ CRC32_POLYNOMIAL
+            } else {
+                crc >> 1
+            };
+            bit += 1;
+        }
+        table[byte] = crc;
+        byte += 1;
+    }
+    table
+}
+
+/// CRC-32 register after one more byte of data
+fn crc32_step(crc: u32, byte: u8) -> u32 {
+    (crc >> 8) ^ CRC32_TABLE[((crc ^ byte as u32) & 0xff) as usize]
+}
+
+/// CRC-32 checksum of data
+/// - checksum of empty data is 0
+pub(super) fn crc32(data: &[u8]) -> u32 {
+    !data.iter().fold(!0u32, |crc, byte| crc32_step(crc, *byte))
+}
+
+/// CRC-32 checksum of data followed by zero_len zero bytes, from checksum of data
+pub(super) fn crc32_extend_zeros(checksum: u32, zero_len: u64) -> u32 {
+    !(0..zero_len).fold(!checksum, |crc, _| crc32_step(crc, 0))
+}
+
+/// CRC-32 checksum of data after some of its bytes changed, from checksum of data
+/// - old, new: bytes before and after the change, of the same length
+/// - bytes_after: length of data after the changed bytes
+/// - CRC-32 is affine, so the checksum changes by the checksum of old XOR new run from
+///   a zero register, shifted over the bytes after, without reading unchanged data
+pub(super) fn crc32_patch(checksum: u32, old: &[u8], new: &[u8], bytes_after: u64) -> u32 {
+    let change = old
+        .iter()
+        .zip(new.iter())
+        .fold(0u32, |crc, (old, new)| crc32_step(crc, old ^ new));
+    checksum ^ (0..bytes_after).fold(change, |crc, _| crc32_step(crc, 0))
+}
+
+// ... ... ... ... ... ... ... ... Verification ... ... ... ... ... ... ... ... ... .
+
+/// Result of Storage::verify_blocks
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct VerifyStep {
+    /// Indexes of corrupted blocks, in ascending order
+    pub corrupted_blocks: Vec<BlockIndex>,
+    /// Blocks verified, corrupted or not
+    pub verified_blocks: u64,
+    /// Block to verify next, None once the last block in file was verified
+    pub next_block: Option<BlockIndex>,
+}
+
+impl Storage {
+    /// Compare checksum in block header with checksum of stored block data
+    /// - notifies observers on mismatch
+    pub(super) fn verify_block_checksum(
+        &mut self,
+        block_index: BlockIndex,
+        block_header: &BlockHeader,
+        block_data: &[u8],
+    ) -> Result<(), StorageError> {
+        if crc32(block_data) == block_header.checksum {
+            return Ok(());
+        }
+        for observer in self.observers.iter_mut() {
+            observer.on_checksum_mismatch(block_index);
+        }
+        Err(StorageError::ChecksumMismatch { block_index })
+    }
+    /// Verify every block in storage file, including free blocks
+    /// - reads block headers and data from file, not from in memory state
+    /// - returns: indexes of corrupted blocks, in ascending order
+    /// - errors only if storage file could not be read
+    pub fn verify_all(&mut self) -> Result<Vec<BlockIndex>, StorageError> {
+        Ok(self.verify_blocks(0, usize::MAX)?.corrupted_blocks)
+    }
+    /// Verify up to max_blocks blocks from from_block on, like verify_all
+    /// - for verification in steps, e.g. by a scrubber, see Engine::set_scrubber
+    /// - errors only if storage file could not be read
+    pub fn verify_blocks(
+        &mut self,
+        from_block: BlockIndex,
+        max_blocks: usize,
+    ) -> Result<VerifyStep, StorageError> {
+        let block_count = self.count_blocks_on_disk()?;
+        let max_blocks = u64::try_from(max_blocks).unwrap_or(u64::MAX);
+        let end_block = from_block.saturating_add(max_blocks).min(block_count);
+        let mut corrupted_blocks = Vec::new();
+        for block_index in from_block..end_block {
+            let verified =
+                self.read_stored_block(block_index)
+                    .and_then(|(block_header, block_data)| {
+                        self.verify_block_checksum(block_index, &block_header, &block_data)
+                    });
+            match verified {
+                Ok(()) => {}
+                Err(error) if error.is_corruption() => corrupted_blocks.push(block_index),
+                Err(error) => return Err(error),
+            }
+        }
+        Ok(VerifyStep {
+            corrupted_blocks,
+            verified_blocks: end_block.saturating_sub(from_block),
+            next_block: Some(end_block).filter(|end_block| *end_block < block_count),
+        })
+    }
+}
+
+#[cfg(test)]
+mod unit_tests_checksum {
+    use super::*;
+
+    #[test]
+    fn test_crc32() {
+        assert_eq!(crc32(&[]), 0);
+        assert_eq!(crc32(b"123456789"), 0xcbf43926);
+        assert_eq!(crc32(&[0u8; 4]), 0x2144df1c);
+    }
+    #[test]
+    fn test_crc32_patch() {
+        let data = b"123456789";
+        assert_eq!(
+            crc32_extend_zeros(crc32(data), 3),
+            crc32(b"123456789\0\0\0")
+        );
+        assert_eq!(crc32_extend_zeros(crc32(&[]), 4), crc32(&[0u8; 4]));
+        assert_eq!(
+            crc32_patch(crc32(data), b"45", b"ab", 4),
+            crc32(b"123ab6789")
+        );
+    }
+
+    /// flip a bit of stored data of block, keeping its header untouched
+    fn flip_data_bit(storage: &Storage, file_path: &str, block_index: BlockIndex) {
+        let block_offset = storage.block_offset(block_index).unwrap() as usize;
+        let mut file_bytes = std::fs::read(file_path).unwrap();
+        file_bytes[block_offset + BLOCK_HEADER_SIZE] ^= 1;
+        std::fs::write(file_path, file_bytes).unwrap();
+    }
+
+    #[test]
+    fn test_read_detects_corruption() {
+        let tmp_dir = tempfile::tempdir().unwrap();
+        let file_path = tmp_dir.path().join("checksum.hex");
+        let file_path = file_path.to_str().unwrap().to_string();
+        let mut storage = Storage::new(file_path.clone(), 8).unwrap();
+        storage.write_block(0, &[1, 2, 3]).unwrap();
+        storage.write_block(1, &[4, 5, 6]).unwrap();
+        flip_data_bit(&storage, &file_path, 1);
+        assert_eq!(storage.read_block(0).unwrap().1, vec![1, 2, 3]);
+        let error = storage.read_block(1).unwrap_err();
+        assert!(matches!(
+            error,
+            StorageError::ChecksumMismatch { block_index: 1 }
+        ));
+        // rewriting block repairs it
+        storage.write_block(1, &[4, 5, 6]).unwrap();
+        assert_eq!(storage.read_block(1).unwrap().1, vec![4, 5, 6]);
+    }
+    #[test]
+    fn test_verify_all() {
+        let tmp_dir = tempfile::tempdir().unwrap();
+        let file_path = tmp_dir.path().join("checksum.hex");
+        let file_path = file_path.to_str().unwrap().to_string();
+        let mut storage = Storage::new(file_path.clone(), 8).unwrap();
+        for block_index in 0..4 {
+            storage
+                .write_block(block_index, &[block_index as u8; 8])
+                .unwrap();
+        }
+        storage.delete_block(2, false).unwrap();
+        assert_eq!(storage.verify_all().unwrap(), Vec::<BlockIndex>::new());
+        flip_data_bit(&storage, &file_path, 0);
+        flip_data_bit(&storage, &file_path, 3);
+        // soft deleted block holds stale data, but no data is covered by its checksum
+        flip_data_bit(&storage, &file_path, 2);
+        assert_eq!(storage.verify_all().unwrap(), vec![0, 3]);
+        // in steps, the last step ends the pass
+        let step = storage.verify_blocks(0, 2).unwrap();
+        assert_eq!(step.corrupted_blocks, vec![0]);
+        assert_eq!(step.next_block, Some(2));
+        let step = storage.verify_blocks(2, 3).unwrap();
+        assert_eq!((step.corrupted_blocks, step.verified_blocks), (vec![3], 2));
+        assert_eq!(step.next_block, None);
+        assert_eq!(storage.verify_blocks(9, 2).unwrap().verified_blocks, 0);
+    }
+}
@@ -0,0 +1,90 @@
+use super::*;
+use std::io;
+
+/// Copy file at source_path to target_path, creating or truncating target_path
+/// - with feature reflink on Linux, tries the FICLONE ioctl first: on file systems with
+///   copy-on-write extents (btrfs, XFS) the copy shares data blocks with the source
+///   until either side is written
+/// - otherwise, or if the file system can not clone, falls back to std::fs::copy
+fn clone_file(source_path: &str, target_path: &str) -> io::Result<()> {
+    #[cfg(all(feature = "reflink", target_os = "linux"))]
+    {
+        use std::os::unix::io::AsRawFd;
+        let source = File::open(source_path)?;
+        let target = OpenOptions::new()
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(target_path)?;
+        // - fails with EOPNOTSUPP, EXDEV or EINVAL where the file system can not clone
+        if unsafe { libc::ioctl(target.as_raw_fd(), libc::FICLONE, source.as_raw_fd()) } == 0 {
+            return Ok(());
+        }
+    }
+    std::fs::copy(source_path, target_path).map(|_| ())
+}
+
+impl Storage {
+    // ... ... ... ... ... ... ... ... ... ... Clone ... ... ... ... ... ... ... ... ... ...
+
+    /// Copy storage file to file_path and open the copy, e.g. to start a test environment
+    /// from a production snapshot
+    /// - with feature reflink on Linux, the copy is a copy-on-write clone where the file
+    ///   system supports it, so it takes no time or disk space until blocks are written,
+    ///   elsewhere the file is copied in full
+    /// - writes are synced first, and the copy is marked clean, so it opens without a
+    ///   scan of block headers, unless a write failed since open, see close
+    /// - the copy is a storage of its own, settings not stored in the file (durability,
+    ///   cache, transforms, key) are at their defaults, see StorageOptions; it keeps the
+    ///   recorded pipeline, its blocks are read once the transforms are pushed again
+    /// - creates/overwrites file_path, which must not be the storage file itself
+    /// - returns: copy opened for writing
+    pub fn clone_to(&mut self, file_path: String) -> Result<Storage, StorageError> {
+        self.flush()?;
+        let restore_dirty_flag = self.dirty_flag_set;
+        if restore_dirty_flag {
+            self.mark_clean()?;
+        }
+        let cloned = clone_file(&self.file_path, &file_path)
+            .map_err(StorageError::io("clone storage file", None));
+        if restore_dirty_flag {
+            self.mark_dirty()?;
+        }
+        cloned?;
+        Storage::open(file_path)
+    }
+}
+
+#[cfg(test)]
+mod unit_tests_clone {
+    use super::*;
+
+    fn file_path(tmp_dir: &tempfile::TempDir, name: &str) -> String {
+        tmp_dir.path().join(name).to_str().unwrap().to_string()
+    }
+
+    #[test]
+    fn test_clone_to() {
+        let tmp_dir = tempfile::tempdir().unwrap();
+        let mut storage = Storage::new(file_path(&tmp_dir, "storage.hex"), 4).unwrap();
+        let data: Vec<u8> = (0..10).collect();
+        let record_id = storage.write_record(&data).unwrap();
+        storage.write_block(5, &[5]).unwrap();
+        let mut clone = storage.clone_to(file_path(&tmp_dir, "clone.hex")).unwrap();
+        assert_eq!(clone.read_record(record_id).unwrap(), data);
+        assert_eq!(clone.free_blocks, [3, 4].iter().cloned().collect());
+        assert_eq!(clone.generation(), storage.generation());
+        // - clone and storage are independent
+        clone.write_block(3, &[3]).unwrap();
+        storage.delete_block(5, true).unwrap();
+        assert!(storage.read_block(3).unwrap().1.is_empty());
+        assert_eq!(clone.read_block(5).unwrap().1, vec![5]);
+        // - storage stays marked dirty while open
+        assert!(storage.dirty_flag_set);
+        let mut reopened = Storage::open(file_path(&tmp_dir, "clone.hex"));
+        assert!(matches!(reopened, Err(StorageError::AlreadyLocked)));
+        drop(clone);
+        reopened = Storage::open(file_path(&tmp_dir, "clone.hex"));
+        assert_eq!(reopened.unwrap().read_block(3).unwrap().1, vec![3]);
+    }
+}
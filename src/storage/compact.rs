@@ -0,0 +1,469 @@
+use super::*;
+use std::collections::HashMap;
+
+/// What compaction does with a record, see CompactionFilter
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum FilterDecision {
+    /// Keep the record as it is
+    Keep,
+    /// Delete the record, like delete_record with a soft delete
+    Drop,
+    /// Write this data as the record instead, keeping its expiry and tag
+    /// - the record gets a new record id, returned in the map of compact
+    /// - for an entry of LsmStore, the value of its key instead
+    /// - empty data fails with StorageError::EmptyRecord, use Drop
+    Replace(Vec<u8>),
+}
+
+/// Callback deciding, for every record, if compaction keeps, drops or rewrites it, e.g. to
+/// purge records past a retention policy inside maintenance, see set_compaction_filter
+/// - records with a pinned block are not passed, compaction keeps them
+/// - the same filter decides on the entries of key value stores, e.g. the table merge
+///   of LsmStore, see filter_entry
+/// - both methods keep everything unless implemented
+pub trait CompactionFilter: Send {
+    /// Decide what to do with record holding data
+    /// - expires_at and tag are those of the first block of the record
+    fn filter(
+        &mut self,
+        record_id: RecordId,
+        expires_at: Option<u64>,
+        tag: BlockTag,
+        data: &[u8],
+    ) -> FilterDecision {
+        let _ = (record_id, expires_at, tag, data);
+        FilterDecision::Keep
+    }
+    /// Decide what to do with the value of key, live in a key value store
+    /// - deleted keys are not passed, compaction leaves them out anyway
+    fn filter_entry(&mut self, key: &[u8], value: &[u8]) -> FilterDecision {
+        let _ = (key, value);
+        FilterDecision::Keep
+    }
+}
+
+impl Storage {
+    // ... ... ... ... ... ... ... ... ... Compaction ... ... ... ... ... ... ... ... ... .
+
+    /// Let compact and compact_step pass every record to filter before blocks are moved
+    /// - compact filters every time, compact_step at the first step of each pass, a pass
+    ///   ends with the step moving fewer than max_moves blocks
+    /// - None compacts without a filter, the default
+    /// - setting is not stored in storage file, it must be set every time the file is opened
+    pub fn set_compaction_filter(&mut self, filter: Option<Box<dyn CompactionFilter>>) {
+        self.compaction_filter = filter;
+    }
+
+    /// Move all live blocks toward the front of the file, and truncate the free tail
+    /// - highest live block is moved to lowest free block, until no free block is left
+    ///   below a live block
+    /// - pinned blocks are not moved, free blocks below them are filled with blocks above
+    /// - links of record blocks are updated, so record ids stay valid unless the
+    ///   first block of the record moved
+    /// - records are passed to the compaction filter first, if set, see
+    ///   set_compaction_filter
+    /// - returns: map of old to new index of every moved block, and of old to new record
+    ///   id of every record the filter replaced
+    pub fn compact(&mut self) -> Result<HashMap<BlockIndex, BlockIndex>, StorageError> {
+        let replaced = self.filter_records()?;
+        let mut remap = self.compact_blocks(usize::MAX)?;
+        self.compaction_filtered = false;
+        self.trim_tail()?;
+        add_replaced(&mut remap, replaced);
+        Ok(remap)
+    }
+    /// Online compaction, moves at most max_moves blocks per call
+    /// - lets callers interleave compaction with other operations
+    /// - free tail is truncated once no block is left to move
+    /// - the first step of a pass passes every record to the compaction filter, if set,
+    ///   records replaced do not count as moves
+    /// - returns: map of old to new index of every block moved by this call, and of
+    ///   records the filter replaced, like compact, empty once file is compact
+    pub fn compact_step(
+        &mut self,
+        max_moves: usize,
+    ) -> Result<HashMap<BlockIndex, BlockIndex>, StorageError> {
+        let replaced = if self.compaction_filtered {
+            HashMap::new()
+        } else {
+            self.filter_records()?
+        };
+        self.compaction_filtered = true;
+        let mut remap = self.compact_blocks(max_moves)?;
+        if remap.len() < max_moves {
+            self.compaction_filtered = false;
+            self.trim_tail()?;
+        }
+        add_replaced(&mut remap, replaced);
+        Ok(remap)
+    }
+    /// Pass every record without a pinned block to the compaction filter, and drop or
+    /// replace records as it decides
+    /// - returns: old to new record id of every record replaced
+    fn filter_records(&mut self) -> Result<HashMap<RecordId, RecordId>, StorageError> {
+        let mut filter = match self.compaction_filter.take() {
+            Some(filter) => filter,
+            None => return Ok(HashMap::new()),
+        };
+        let replaced = self.apply_filter(filter.as_mut());
+        self.compaction_filter = Some(filter);
+        replaced
+    }
+    fn apply_filter(
+        &mut self,
+        filter: &mut dyn CompactionFilter,
+    ) -> Result<HashMap<RecordId, RecordId>, StorageError> {
+        let previous_blocks = self.previous_blocks()?;
+        let record_ids: Vec<RecordId> = (0..self.end_block_count)
+            .filter(|block_index| {
+                !self.is_empty_block(*block_index) && !previous_blocks.contains_key(block_index)
+            })
+            .collect();
+        let mut replaced = HashMap::new();
+        for record_id in record_ids {
+            let block_indexes = self.record_blocks(record_id)?;
+            if block_indexes
+                .iter()
+                .any(|block_index| self.pinned.contains(block_index))
+            {
+                continue;
+            }
+            let block_header = self.read_block_header(record_id)?;
+            let (expires_at, tag) = (block_header.expires_at, block_header.tag);
+            let data = self.read_record(record_id)?;
+            match filter.filter(record_id, expires_at, tag, &data) {
+                FilterDecision::Keep => {}
+                FilterDecision::Drop => {
+                    self.delete_record(record_id, false)?;
+                }
+                FilterDecision::Replace(data) => {
+                    // - write the new record first, a failed write keeps the old one
+                    let block_indexes = self.write_chunks(&data, expires_at, tag)?;
+                    self.delete_record(record_id, false)?;
+                    replaced.insert(record_id, block_indexes[0]);
+                }
+            }
+        }
+        Ok(replaced)
+    }
+    /// Move up to max_moves highest live blocks to lowest free blocks
+    #[cfg_attr(
+        feature = "tracing",
+        tracing::instrument(
+            name = "compact",
+            level = "debug",
+            skip(self),
+            fields(moved = tracing::field::Empty),
+            err
+        )
+    )]
+    fn compact_blocks(
+        &mut self,
+        max_moves: usize,
+    ) -> Result<HashMap<BlockIndex, BlockIndex>, StorageError> {
+        let mut remap = HashMap::new();
+        if max_moves == 0 {
+            return Ok(remap);
+        }
+        // - record blocks linking to each block, updated as blocks move
+        let mut previous_blocks = self.previous_blocks()?;
+        while remap.len() < max_moves {
+            let free_block = match self.free_blocks.iter().next() {
+                Some(free_block) => *free_block,
+                None => break,
+            };
+            let live_block = match self.last_movable_block() {
+                Some(live_block) if live_block > free_block => live_block,
+                _ => break,
+            };
+            // - a move deletes the original, fail before the copy is written
+            if self.is_append_only() {
+                return Err(StorageError::AppendOnly {
+                    block_index: live_block,
+                });
+            }
+            self.move_block(live_block, free_block, &mut previous_blocks)?;
+            remap.insert(live_block, free_block);
+        }
+        #[cfg(feature = "tracing")]
+        tracing::Span::current().record("moved", remap.len());
+        Ok(remap)
+    }
+    /// Map each linked block to the record block linking to it
+    pub(super) fn previous_blocks(
+        &mut self,
+    ) -> Result<HashMap<BlockIndex, BlockIndex>, StorageError> {
+        let mut previous_blocks = HashMap::new();
+        for block_index in 0..self.end_block_count {
+            if self.free_blocks.contains(&block_index) {
+                continue;
+            }
+            if let Some(next_block) = self.read_block_header(block_index)?.next_block {
+                previous_blocks.insert(next_block, block_index);
+            }
+        }
+        Ok(previous_blocks)
+    }
+    /// Highest block holding data
+    fn last_live_block(&self) -> Option<BlockIndex> {
+        (0..self.end_block_count)
+            .rev()
+            .find(|block_index| !self.free_blocks.contains(block_index))
+    }
+    /// Highest block holding data that is not pinned
+    fn last_movable_block(&self) -> Option<BlockIndex> {
+        (0..self.end_block_count).rev().find(|block_index| {
+            !self.free_blocks.contains(block_index) && !self.pinned.contains(block_index)
+        })
+    }
+    /// Copy live block to free block, relink it and soft delete the original
+    /// - the copy keeps the expiry and tag of the original
+    /// - block data is read and verified, a corrupted block is not moved
+    fn move_block(
+        &mut self,
+        from_block: BlockIndex,
+        to_block: BlockIndex,
+        previous_blocks: &mut HashMap<BlockIndex, BlockIndex>,
+    ) -> Result<(), StorageError> {
+        let (block_header, block_data) = self.read_stored_block(from_block)?;
+        self.verify_block_checksum(from_block, &block_header, &block_data)?;
+        let block_data = self.decode_block_data(from_block, block_data, block_header.flags)?;
+        let next_block = block_header.next_block;
+        let (expires_at, tag) = (block_header.expires_at, block_header.tag);
+        self.write_linked_block(to_block, &block_data, next_block, expires_at, tag)?;
+        // - point block linking to moved block at its new index
+        if let Some(previous_block) = previous_blocks.remove(&from_block) {
+            let previous_header = self
+                .read_block_header(previous_block)?
+                .with_next_block(Some(to_block));
+            self.write_block_header(previous_block, previous_header)?;
+            previous_blocks.insert(to_block, previous_block);
+        }
+        if let Some(next_block) = next_block {
+            previous_blocks.insert(next_block, to_block);
+        }
+        self.delete_block(from_block, false)?;
+        Ok(())
+    }
+    /// Trim free tail of file every time the last block is deleted, see trim_tail
+    /// - default is false, deleted blocks at end of file are kept as free blocks
+    /// - setting is not stored in storage file, it must be set every time the file is opened
+    pub fn set_auto_trim(&mut self, auto_trim: bool) {
+        self.auto_trim = auto_trim;
+    }
+    /// Overwrite block header in storage file, keeping block data
+    /// - the block is given a new generation
+    fn write_block_header(
+        &mut self,
+        block_index: BlockIndex,
+        block_header: BlockHeader,
+    ) -> Result<(), StorageError> {
+        let block_offset = self.block_offset(block_index)?;
+        self.preserve_for_snapshots(block_index)?;
+        let block_header = block_header.with_generation(self.next_generation());
+        self.write_file_at(
+            block_offset,
+            &block_header.to_bytes(),
+            "write block header",
+            Some(block_index),
+        )?;
+        self.written(1)
+    }
+    /// Truncate free blocks at end of file
+    /// - end_block_count and free_blocks shrink with the file
+    /// - returns: number of removed blocks
+    pub fn trim_tail(&mut self) -> Result<u64, StorageError> {
+        let block_count = self
+            .last_live_block()
+            .map_or(0, |block_index| block_index + 1);
+        let removed_count = self.end_block_count - block_count;
+        if removed_count == 0 {
+            return Ok(0);
+        }
+        let file_len = self.block_offset(block_count)?;
+        // - generations of removed blocks are not found by a scan on a dirty open
+        self.write_generation()?;
+        if let Err(error) = self.file_writer.set_len(file_len) {
+            self.write_failed.store(true, Ordering::Relaxed);
+            return Err(StorageError::io("truncate storage file", None)(error));
+        }
+        // - keep in memory state and bitmap in line with the shorter file
+        self.free_blocks = self.free_blocks.range(..block_count).cloned().collect();
+        self.end_block_count = block_count;
+        self.write_bitmap()?;
+        self.written(1)?;
+        Ok(removed_count)
+    }
+}
+
+/// Add record ids replaced by the compaction filter to map of moved blocks, at the index
+/// their first block was moved to
+fn add_replaced(
+    remap: &mut HashMap<BlockIndex, BlockIndex>,
+    replaced: HashMap<RecordId, RecordId>,
+) {
+    for (record_id, new_record_id) in replaced {
+        let new_record_id = remap.get(&new_record_id).copied().unwrap_or(new_record_id);
+        remap.insert(record_id, new_record_id);
+    }
+}
+
+#[cfg(test)]
+mod unit_tests_compact {
+    use super::*;
+
+    fn new_storage(tmp_dir: &tempfile::TempDir) -> (Storage, String) {
+        let file_path = tmp_dir.path().join("compact.hex");
+        let file_path = file_path.to_str().unwrap().to_string();
+        (Storage::new(file_path.clone(), 4).unwrap(), file_path)
+    }
+
+    #[test]
+    fn test_compact() {
+        let tmp_dir = tempfile::tempdir().unwrap();
+        let (mut storage, file_path) = new_storage(&tmp_dir);
+        for block_index in 0..6 {
+            storage
+                .write_block(block_index, &[block_index as u8])
+                .unwrap();
+        }
+        for block_index in [0, 2, 3].iter() {
+            storage.delete_block(*block_index, false).unwrap();
+        }
+        let remap = storage.compact().unwrap();
+        let expected: HashMap<BlockIndex, BlockIndex> = [(5, 0), (4, 2)].iter().cloned().collect();
+        assert_eq!(remap, expected);
+        assert_eq!(storage.end_block_count, 3);
+        assert!(storage.free_blocks.is_empty());
+        assert_eq!(storage.read_block(0).unwrap().1, vec![5]);
+        assert_eq!(storage.read_block(1).unwrap().1, vec![1]);
+        assert_eq!(storage.read_block(2).unwrap().1, vec![4]);
+        // file is truncated after last live block
+        let file_len = std::fs::metadata(&file_path).unwrap().len();
+        assert_eq!(file_len, storage.block_offset(3).unwrap());
+        // compact file does not move
+        assert!(storage.compact().unwrap().is_empty());
+        storage.close().unwrap();
+        let mut storage = Storage::open(file_path).unwrap();
+        assert_eq!(storage.end_block_count, 3);
+        assert_eq!(storage.read_block(2).unwrap().1, vec![4]);
+    }
+    #[test]
+    fn test_compact_relinks_records() {
+        let tmp_dir = tempfile::tempdir().unwrap();
+        let (mut storage, _) = new_storage(&tmp_dir);
+        storage.write_block(0, &[9]).unwrap();
+        storage.write_block(1, &[9]).unwrap();
+        let data: Vec<u8> = (0..12).collect();
+        let record_id = storage.write_record(&data).unwrap();
+        assert_eq!(record_id, 2);
+        storage.delete_block(0, false).unwrap();
+        storage.delete_block(1, false).unwrap();
+        // blocks 4 and 3 move to 0 and 1, record still starts at block 2
+        let remap = storage.compact().unwrap();
+        assert_eq!(remap.len(), 2);
+        assert_eq!(storage.read_record(record_id).unwrap(), data);
+        assert_eq!(storage.end_block_count, 3);
+    }
+    #[test]
+    fn test_trim_tail() {
+        let tmp_dir = tempfile::tempdir().unwrap();
+        let (mut storage, file_path) = new_storage(&tmp_dir);
+        for block_index in 0..5 {
+            storage.write_block(block_index, &[1]).unwrap();
+        }
+        storage.delete_block(1, false).unwrap();
+        storage.delete_block(3, true).unwrap();
+        storage.delete_block(4, false).unwrap();
+        assert_eq!(storage.trim_tail().unwrap(), 2);
+        assert_eq!(storage.end_block_count, 3);
+        assert_eq!(storage.free_blocks, [1].iter().cloned().collect());
+        let file_len = std::fs::metadata(&file_path).unwrap().len();
+        assert_eq!(file_len, storage.block_offset(3).unwrap());
+        assert_eq!(storage.trim_tail().unwrap(), 0);
+        // tail grows again on write past end
+        storage.write_block(4, &[2]).unwrap();
+        assert_eq!(storage.end_block_count, 5);
+        assert_eq!(storage.free_blocks, [1, 3].iter().cloned().collect());
+    }
+    #[test]
+    fn test_auto_trim() {
+        let tmp_dir = tempfile::tempdir().unwrap();
+        let (mut storage, file_path) = new_storage(&tmp_dir);
+        storage.set_auto_trim(true);
+        for block_index in 0..4 {
+            storage.write_block(block_index, &[1]).unwrap();
+        }
+        // block in the middle is kept as free block
+        storage.delete_block(2, false).unwrap();
+        assert_eq!(storage.end_block_count, 4);
+        // deleting last block trims free blocks before it too
+        storage.delete_block(3, false).unwrap();
+        assert_eq!(storage.end_block_count, 2);
+        assert!(storage.free_blocks.is_empty());
+        storage.close().unwrap();
+        let storage = Storage::open(file_path).unwrap();
+        assert_eq!(storage.end_block_count, 2);
+    }
+    struct RetentionFilter;
+    impl CompactionFilter for RetentionFilter {
+        fn filter(
+            &mut self,
+            _: RecordId,
+            _: Option<u64>,
+            tag: BlockTag,
+            data: &[u8],
+        ) -> FilterDecision {
+            match tag {
+                1 => FilterDecision::Drop,
+                2 => FilterDecision::Replace(data[..1].to_vec()),
+                _ => FilterDecision::Keep,
+            }
+        }
+    }
+
+    #[test]
+    fn test_compaction_filter() {
+        let tmp_dir = tempfile::tempdir().unwrap();
+        let (mut storage, _) = new_storage(&tmp_dir);
+        storage.set_compaction_filter(Some(Box::new(RetentionFilter)));
+        let kept = storage.write_record(&[7; 6]).unwrap();
+        storage.write_block_tagged(2, &[1], 1).unwrap();
+        let shrunk = storage.write_chunks(&[2; 6], Some(99), 2).unwrap()[0];
+        storage.write_block_tagged(5, &[3], 1).unwrap();
+        storage.pin_block(5);
+        assert_eq!(shrunk, 3);
+        let remap = storage.compact().unwrap();
+        // - tagged 1 is dropped unless pinned, tagged 2 is rewritten to one block
+        assert_eq!(storage.read_record(kept).unwrap(), vec![7; 6]);
+        let new_record_id = remap[&shrunk];
+        assert_eq!(storage.read_record(new_record_id).unwrap(), vec![2]);
+        let block_header = storage.read_block_header(new_record_id).unwrap();
+        assert_eq!((block_header.expires_at, block_header.tag), (Some(99), 2));
+        assert_eq!(storage.read_block(5).unwrap().1, vec![3]);
+        assert_eq!(storage.used_block_count(), 4);
+        // - compact_step filters at the first step of a pass
+        storage.unpin_block(5);
+        storage.compact_step(8).unwrap();
+        assert_eq!(storage.used_block_count(), 3);
+        assert_eq!(storage.end_block_count, 3);
+    }
+    #[test]
+    fn test_compact_step() {
+        let tmp_dir = tempfile::tempdir().unwrap();
+        let (mut storage, _) = new_storage(&tmp_dir);
+        for block_index in 0..5 {
+            storage.write_block(block_index, &[1]).unwrap();
+        }
+        storage.delete_block(0, false).unwrap();
+        storage.delete_block(1, false).unwrap();
+        let remap = storage.compact_step(1).unwrap();
+        assert_eq!(remap.get(&4), Some(&0));
+        // tail is kept until nothing is left to move
+        assert_eq!(storage.end_block_count, 5);
+        let remap = storage.compact_step(1).unwrap();
+        assert_eq!(remap.get(&3), Some(&1));
+        assert_eq!(storage.compact_step(1).unwrap().len(), 0);
+        assert_eq!(storage.end_block_count, 3);
+    }
+}
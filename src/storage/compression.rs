@@ -0,0 +1,384 @@
+use super::*;
+
+/// Codec block data is compressed with, stored in storage header
+/// - Lz4 needs feature lz4, Zstd needs feature zstd
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Compression {
+    #[default]
+    None,
+    /// LZ4 block format, fast with a moderate ratio
+    Lz4,
+    /// Zstandard at level ZSTD_LEVEL, slower with a better ratio
+    Zstd,
+}
+
+/// Zstandard compression level, zstd's own default
+#[cfg(feature = "zstd")]
+const ZSTD_LEVEL: i32 = 3;
+
+impl Compression {
+    /// Value of compression byte in storage header
+    pub(crate) fn to_byte(self) -> u8 {
+        match self {
+            Compression::None => 0,
+            Compression::Lz4 => 1,
+            Compression::Zstd => 2,
+        }
+    }
+    /// Compression of compression byte in storage header, None if unknown
+    pub(crate) fn from_byte(byte: u8) -> Option<Compression> {
+        match byte {
+            0 => Some(Compression::None),
+            1 => Some(Compression::Lz4),
+            2 => Some(Compression::Zstd),
+            _ => None,
+        }
+    }
+    /// Check codec is compiled in
+    pub fn is_available(self) -> bool {
+        match self {
+            Compression::None => true,
+            Compression::Lz4 => cfg!(feature = "lz4"),
+            Compression::Zstd => cfg!(feature = "zstd"),
+        }
+    }
+    /// Name of codec, see BlockTransform::name
+    fn name(self) -> &'static str {
+        match self {
+            Compression::None => "none",
+            Compression::Lz4 => "lz4",
+            Compression::Zstd => "zstd",
+        }
+    }
+    /// Block flag marking block data compressed with this codec
+    fn block_flag(self) -> u32 {
+        match self {
+            Compression::None => 0,
+            Compression::Lz4 => BLOCK_FLAG_LZ4,
+            Compression::Zstd => BLOCK_FLAG_ZSTD,
+        }
+    }
+    /// Compression of block data with given block flags
+    /// - unknown flags are corruption
+    fn from_block_flags(
+        block_index: BlockIndex,
+        block_flags: u32,
+    ) -> Result<Compression, StorageError> {
+        match block_flags & !BLOCK_FLAG_ENCRYPTED {
+            0 => Ok(Compression::None),
+            BLOCK_FLAG_LZ4 => Ok(Compression::Lz4),
+            BLOCK_FLAG_ZSTD => Ok(Compression::Zstd),
+            _ => Err(StorageError::Corruption {
+                block_index: Some(block_index),
+                reason: "unknown flags in block header",
+            }),
+        }
+    }
+    /// Output of codec for data, None if codec failed
+    #[cfg_attr(not(all(feature = "lz4", feature = "zstd")), allow(unused_variables))]
+    fn compress(self, data: &[u8]) -> Result<Option<Vec<u8>>, StorageError> {
+        match self {
+            Compression::None => Ok(None),
+            #[cfg(feature = "lz4")]
+            Compression::Lz4 => Ok(Some(lz4_flex::block::compress(data))),
+            #[cfg(feature = "zstd")]
+            Compression::Zstd => Ok(zstd::bulk::compress(data, ZSTD_LEVEL).ok()),
+            #[allow(unreachable_patterns)]
+            compression => Err(StorageError::CompressionUnavailable { compression }),
+        }
+    }
+    /// Decompress codec output into data_len bytes, None if it is not valid codec output
+    #[cfg_attr(not(all(feature = "lz4", feature = "zstd")), allow(unused_variables))]
+    fn decompress(
+        self,
+        compressed: &[u8],
+        data_len: usize,
+    ) -> Result<Option<Vec<u8>>, StorageError> {
+        match self {
+            Compression::None => Ok(Some(compressed.to_vec())),
+            #[cfg(feature = "lz4")]
+            Compression::Lz4 => Ok(lz4_flex::block::decompress(compressed, data_len).ok()),
+            #[cfg(feature = "zstd")]
+            Compression::Zstd => Ok(zstd::bulk::decompress(compressed, data_len).ok()),
+            #[allow(unreachable_patterns)]
+            compression => Err(StorageError::CompressionUnavailable { compression }),
+        }
+    }
+}
+
+impl Storage {
+    // ... ... ... ... ... ... ... ... ... Compression ... ... ... ... ... ... ... ... ... .
+
+    /// Compress data of blocks written from now on with compression
+    /// - setting is stored in storage header, it stays set when the file is opened again
+    /// - blocks already written keep their compression, every block can be read
+    ///   whatever the setting
+    /// - CompressionUnavailable if the codec is not compiled in, see Compression
+    pub fn set_compression(&mut self, compression: Compression) -> Result<(), StorageError> {
+        if !compression.is_available() {
+            return Err(StorageError::CompressionUnavailable { compression });
+        }
+        self.header.compression = compression.to_byte();
+        self.pipeline.configure(&self.header);
+        self.set_storage_header()?;
+        self.written(1)
+    }
+    pub fn compression(&self) -> Compression {
+        // check_format rejects unknown compression bytes on open
+        Compression::from_byte(self.header.compression).unwrap_or_default()
+    }
+    /// Write data as a record, like write_record_expiring, compressed with compression
+    /// instead of the setting of the storage, e.g. for data that compresses better or
+    /// worse than the rest of the file
+    /// - the setting is left unchanged, the record reads like any other
+    /// - CompressionUnavailable if the codec is not compiled in, see Compression
+    /// - returns: record id
+    pub fn write_record_compressed(
+        &mut self,
+        data: &[u8],
+        compression: Compression,
+        expires_at: Option<u64>,
+    ) -> Result<RecordId, StorageError> {
+        if !compression.is_available() {
+            return Err(StorageError::CompressionUnavailable { compression });
+        }
+        let codec = std::mem::replace(&mut self.pipeline.compression.codec, compression);
+        let block_indexes = self.write_chunks(data, expires_at, 0);
+        self.pipeline.compression.codec = codec;
+        Ok(block_indexes?[0])
+    }
+}
+
+/// First transform of the pipeline, compressing block data with the codec of the storage
+/// header, see Storage::set_compression
+#[derive(Default)]
+pub(super) struct CompressionTransform {
+    pub(super) codec: Compression,
+    /// Block length of storage file, bounds decompressed data
+    pub(super) block_len: u64,
+}
+
+impl CompressionTransform {
+    /// Uncompressed length followed by codec output for data, None if codec failed
+    fn compress(&self, data: &[u8]) -> Result<Option<Vec<u8>>, StorageError> {
+        let compressed = match self.codec.compress(data)? {
+            Some(compressed) => compressed,
+            None => return Ok(None),
+        };
+        let mut block_data = Vec::with_capacity(COMPRESSED_LEN_SIZE + compressed.len());
+        block_data.extend_from_slice(&(data.len() as u64).to_le_bytes());
+        block_data.extend_from_slice(&compressed);
+        Ok(Some(block_data))
+    }
+}
+
+impl BlockTransform for CompressionTransform {
+    fn name(&self) -> &str {
+        self.codec.name()
+    }
+    fn encode(&self, data: &[u8]) -> Result<Vec<u8>, StorageError> {
+        if self.codec == Compression::None {
+            return Ok(data.to_vec());
+        }
+        self.compress(data)?.ok_or_else(|| StorageError::Transform {
+            message: format!("{:?} could not compress block data", self.codec),
+        })
+    }
+    fn decode(&self, data: &[u8]) -> Result<Vec<u8>, StorageError> {
+        decompress_block(0, data.to_vec(), self.codec.block_flag(), self.block_len)
+    }
+    /// Compress data, if that makes it shorter
+    /// - data longer than block_len is never compressed, so decompressed data of a
+    ///   block is at most block_len bytes
+    /// - block flags tell the codec of compressed data
+    fn encode_block(
+        &self,
+        data: Vec<u8>,
+        block_flags: &mut BlockFlags,
+    ) -> Result<Vec<u8>, StorageError> {
+        if self.codec == Compression::None || data.len() as u64 > self.block_len {
+            return Ok(data);
+        }
+        match self.compress(&data)? {
+            Some(block_data) if block_data.len() < data.len() => {
+                block_flags.0 |= self.codec.block_flag();
+                Ok(block_data)
+            }
+            _ => Ok(data),
+        }
+    }
+    /// Decompress block data compressed by encode_block, as told by block flags
+    fn decode_block(
+        &self,
+        block_index: BlockIndex,
+        data: Vec<u8>,
+        block_flags: BlockFlags,
+    ) -> Result<Vec<u8>, StorageError> {
+        decompress_block(block_index, data, block_flags.0, self.block_len)
+    }
+}
+
+/// Decompress block data of a storage file of block_len, see
+/// CompressionTransform::decode_block
+pub(super) fn decompress_block(
+    block_index: BlockIndex,
+    block_data: Vec<u8>,
+    block_flags: u32,
+    block_len: u64,
+) -> Result<Vec<u8>, StorageError> {
+    let compression = Compression::from_block_flags(block_index, block_flags)?;
+    if compression == Compression::None {
+        return Ok(block_data);
+    }
+    let corruption = |reason| StorageError::Corruption {
+        block_index: Some(block_index),
+        reason,
+    };
+    if block_data.len() < COMPRESSED_LEN_SIZE {
+        return Err(corruption("compressed block data is truncated"));
+    }
+    let (len_bytes, compressed) = block_data.split_at(COMPRESSED_LEN_SIZE);
+    let data_len = get_u64(len_bytes, 0);
+    // - bound allocation by block_len, a corrupted length must not exhaust memory
+    if data_len > block_len {
+        return Err(corruption("uncompressed length exceeds block length"));
+    }
+    let data_len = usize::try_from(data_len)
+        .map_err(|_| corruption("uncompressed length does not fit in memory"))?;
+    match compression.decompress(compressed, data_len)? {
+        Some(data) if data.len() == data_len => Ok(data),
+        _ => Err(corruption("compressed block data can not be decompressed")),
+    }
+}
+
+#[cfg(test)]
+mod unit_tests_compression {
+    use super::*;
+
+    fn new_storage(tmp_dir: &tempfile::TempDir) -> (Storage, String) {
+        let file_path = tmp_dir.path().join("compression.hex");
+        let file_path = file_path.to_str().unwrap().to_string();
+        (Storage::new(file_path.clone(), 256).unwrap(), file_path)
+    }
+    fn json_payload() -> Vec<u8> {
+        let item = br#"{"id":1,"name":"block","tags":["a","b"]},"#;
+        item.iter().cycle().take(200).cloned().collect()
+    }
+    /// data size stored in block header of block_index
+    fn stored_size(storage: &mut Storage, block_index: BlockIndex) -> u64 {
+        storage
+            .read_block_header(block_index)
+            .unwrap()
+            .block_data_size
+    }
+
+    #[test]
+    fn test_compression_byte() {
+        for compression in [Compression::None, Compression::Lz4, Compression::Zstd].iter() {
+            let byte = compression.to_byte();
+            assert_eq!(Compression::from_byte(byte), Some(*compression));
+        }
+        assert_eq!(Compression::from_byte(3), None);
+        assert!(Compression::None.is_available());
+    }
+    #[test]
+    fn test_uncompressed_block_flags() {
+        let tmp_dir = tempfile::tempdir().unwrap();
+        let (mut storage, _) = new_storage(&tmp_dir);
+        assert_eq!(storage.compression(), Compression::None);
+        storage.write_block(0, &json_payload()).unwrap();
+        assert_eq!(storage.read_block_header(0).unwrap().flags, 0);
+        assert_eq!(stored_size(&mut storage, 0), 200);
+        // unknown block flags are corruption
+        let block_flags = BlockFlags(BLOCK_FLAG_LZ4 | BLOCK_FLAG_ZSTD);
+        let error = storage
+            .pipeline
+            .compression
+            .decode_block(0, vec![0; 16], block_flags)
+            .unwrap_err();
+        assert!(error.is_corruption());
+    }
+    #[cfg(all(feature = "lz4", feature = "zstd"))]
+    #[test]
+    fn test_compressed_blocks() {
+        let tmp_dir = tempfile::tempdir().unwrap();
+        let (mut storage, file_path) = new_storage(&tmp_dir);
+        let payload = json_payload();
+        storage.set_compression(Compression::Lz4).unwrap();
+        storage.write_block(0, &payload).unwrap();
+        storage.set_compression(Compression::Zstd).unwrap();
+        storage.write_block(1, &payload).unwrap();
+        let record_id = storage.write_record(&payload.repeat(3)).unwrap();
+        assert_eq!(storage.read_block_header(0).unwrap().flags, BLOCK_FLAG_LZ4);
+        assert_eq!(storage.read_block_header(1).unwrap().flags, BLOCK_FLAG_ZSTD);
+        assert!(stored_size(&mut storage, 0) < 100);
+        assert!(stored_size(&mut storage, 1) < 100);
+        // data that does not shrink is stored as is
+        let incompressible: Vec<u8> = (0..64u32).map(|n| (n * 151 % 256) as u8).collect();
+        storage.write_block(5, &incompressible).unwrap();
+        assert_eq!(storage.read_block_header(5).unwrap().flags, 0);
+        // blocks decompress whatever the setting, and setting survives reopening
+        storage.set_compression(Compression::None).unwrap();
+        assert_eq!(storage.read_block(0).unwrap().1, payload);
+        storage.set_compression(Compression::Zstd).unwrap();
+        storage.close().unwrap();
+        let mut storage = Storage::open(file_path).unwrap();
+        assert_eq!(storage.compression(), Compression::Zstd);
+        assert_eq!(storage.read_block(0).unwrap().1, payload);
+        assert_eq!(storage.read_block(1).unwrap().1, payload);
+        assert_eq!(storage.read_block(5).unwrap().1, incompressible);
+        assert_eq!(storage.read_record(record_id).unwrap(), payload.repeat(3));
+        // a record can pick its own codec
+        let record_id = storage
+            .write_record_compressed(&payload, Compression::Lz4, None)
+            .unwrap();
+        assert_eq!(
+            storage.read_block_header(record_id).unwrap().flags,
+            BLOCK_FLAG_LZ4
+        );
+        assert_eq!(storage.compression(), Compression::Zstd);
+        assert_eq!(storage.read_record(record_id).unwrap(), payload);
+    }
+    #[cfg(feature = "lz4")]
+    #[test]
+    fn test_corrupted_compressed_length() {
+        let tmp_dir = tempfile::tempdir().unwrap();
+        let (mut storage, _) = new_storage(&tmp_dir);
+        storage.set_compression(Compression::Lz4).unwrap();
+        storage.write_block(0, &json_payload()).unwrap();
+        let compression = &storage.pipeline.compression;
+        let mut block_flags = BlockFlags::default();
+        let block_data = compression
+            .encode_block(json_payload(), &mut block_flags)
+            .unwrap();
+        assert_eq!(block_flags, BlockFlags(BLOCK_FLAG_LZ4));
+        let mut too_long = block_data.clone();
+        too_long[..COMPRESSED_LEN_SIZE].copy_from_slice(&u64::MAX.to_le_bytes());
+        let error = compression
+            .decode_block(0, too_long, block_flags)
+            .unwrap_err();
+        assert!(error.is_corruption());
+        let error = compression
+            .decode_block(0, block_data[..4].to_vec(), block_flags)
+            .unwrap_err();
+        assert!(error.is_corruption());
+        // standalone encode always compresses
+        assert_eq!(compression.name(), "lz4");
+        let encoded = compression.encode(&[1]).unwrap();
+        assert_eq!(encoded.len(), COMPRESSED_LEN_SIZE + 2);
+        assert_eq!(compression.decode(&encoded).unwrap(), vec![1]);
+    }
+    #[cfg(not(feature = "zstd"))]
+    #[test]
+    fn test_unavailable_compression() {
+        let tmp_dir = tempfile::tempdir().unwrap();
+        let (mut storage, _) = new_storage(&tmp_dir);
+        assert!(matches!(
+            storage.set_compression(Compression::Zstd),
+            Err(StorageError::CompressionUnavailable {
+                compression: Compression::Zstd
+            })
+        ));
+        assert_eq!(storage.compression(), Compression::None);
+    }
+}
@@ -0,0 +1,169 @@
+use super::*;
+
+/// Divergence between in memory block state and storage file
+#[derive(Debug, Clone, PartialEq)]
+pub enum Divergence {
+    /// free_blocks does not agree with block header on disk
+    FreeBlock {
+        block_index: BlockIndex,
+        free_in_memory: bool,
+        free_on_disk: bool,
+    },
+    /// end_block_count does not agree with number of blocks in file
+    EndBlockCount { in_memory: u64, on_disk: u64 },
+}
+
+/// Callback receiving every divergence found by consistency checks
+pub type ConsistencyHook = Box<dyn FnMut(&Divergence) + Send>;
+
+impl Storage {
+    /// Enable consistency checks after each write and delete
+    /// - touched block header is read back from file and compared to free_blocks
+    /// - file length is compared to end_block_count
+    /// - divergences are reported to hook, in memory state is not modified
+    pub fn enable_consistency_checks(&mut self, hook: ConsistencyHook) {
+        self.consistency_hook = Some(hook);
+    }
+    /// Disable consistency checks, dropping the registered hook
+    pub fn disable_consistency_checks(&mut self) {
+        self.consistency_hook = None;
+    }
+    /// Cross check in memory state of block against storage file
+    /// - no-op if consistency checks are disabled
+    /// - returns: number of divergences reported to hook
+    pub(super) fn check_block_consistency(
+        &mut self,
+        block_index: BlockIndex,
+    ) -> Result<usize, StorageError> {
+        if self.consistency_hook.is_none() {
+            return Ok(0);
+        }
+        let mut divergences = Vec::new();
+        // - compare end_block_count with file length
+        let on_disk_block_count = self.count_blocks_on_disk()?;
+        if on_disk_block_count != self.end_block_count {
+            divergences.push(Divergence::EndBlockCount {
+                in_memory: self.end_block_count,
+                on_disk: on_disk_block_count,
+            });
+        }
+        // - compare free_blocks with block header
+        if block_index < on_disk_block_count {
+            let free_on_disk = self.read_block_header(block_index)?.block_data_size == 0;
+            let free_in_memory = self.is_empty_block(block_index);
+            if free_on_disk != free_in_memory {
+                divergences.push(Divergence::FreeBlock {
+                    block_index,
+                    free_in_memory,
+                    free_on_disk,
+                });
+            }
+        }
+        // - report divergences
+        if let Some(hook) = self.consistency_hook.as_mut() {
+            for divergence in divergences.iter() {
+                hook(divergence);
+            }
+        }
+        Ok(divergences.len())
+    }
+    /// Count blocks in storage file from file length, trailing partial block included
+    /// - blocks end where the dedup table starts, if the file has one
+    pub(super) fn count_blocks_on_disk(&mut self) -> Result<u64, StorageError> {
+        let file_len = match self.header.dedup_table {
+            0 => self
+                .file_reader
+                .metadata()
+                .map_err(StorageError::io("read storage file metadata", None))?
+                .len(),
+            dedup_table => dedup_table,
+        };
+        let blocks_len = file_len.saturating_sub(blocks_offset(self.header.bitmap_capacity));
+        let block_size = block_size(self.header.block_len).ok_or(StorageError::Corruption {
+            block_index: None,
+            reason: "block length in storage header is out of range",
+        })?;
+        Ok(blocks_len.div_ceil(block_size))
+    }
+    /// Read block header of block from storage file
+    pub(super) fn read_block_header(
+        &mut self,
+        block_index: BlockIndex,
+    ) -> Result<BlockHeader, StorageError> {
+        let block_offset = self.block_offset(block_index)?;
+        let mut block_header_bytes = [0u8; BLOCK_HEADER_SIZE];
+        let read_error = StorageError::io("read block header", Some(block_index));
+        match positional::read_at(&self.file_reader, block_offset, &mut block_header_bytes) {
+            Ok(BLOCK_HEADER_SIZE) => Ok(BlockHeader::from_bytes(&block_header_bytes)),
+            Ok(_) => Err(read_error(std::io::ErrorKind::UnexpectedEof.into())),
+            Err(error) => Err(read_error(error)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod unit_tests_consistency {
+    use super::*;
+    use std::sync::{Arc, Mutex};
+
+    fn storage_with_hook() -> (Storage, Arc<Mutex<Vec<Divergence>>>, tempfile::TempDir) {
+        let tmp_dir = tempfile::tempdir().unwrap();
+        let file_path = tmp_dir.path().join("consistency.hex");
+        let mut storage = Storage::new(file_path.to_str().unwrap().to_string(), 8).unwrap();
+        let reported = Arc::new(Mutex::new(Vec::new()));
+        let reported_clone = reported.clone();
+        storage.enable_consistency_checks(Box::new(move |divergence| {
+            reported_clone.lock().unwrap().push(divergence.clone());
+        }));
+        (storage, reported, tmp_dir)
+    }
+
+    #[test]
+    fn test_consistent_writes_and_deletes() {
+        let (mut storage, reported, _tmp_dir) = storage_with_hook();
+        storage.write_block(0, &[1, 2, 3]).unwrap();
+        storage.write_block(1, &[4, 5, 6, 7, 8, 9, 10, 11]).unwrap();
+        storage.delete_block(0, false).unwrap();
+        storage.delete_block(1, true).unwrap();
+        assert_eq!(reported.lock().unwrap().len(), 0);
+    }
+    #[test]
+    fn test_free_blocks_divergence() {
+        let (mut storage, reported, _tmp_dir) = storage_with_hook();
+        storage.write_block(0, &[1, 2, 3]).unwrap();
+        // in memory state drifts from file
+        storage.free_blocks.insert(0);
+        assert_eq!(storage.check_block_consistency(0).unwrap(), 1);
+        let reported = reported.lock().unwrap();
+        assert_eq!(
+            reported[0],
+            Divergence::FreeBlock {
+                block_index: 0,
+                free_in_memory: true,
+                free_on_disk: false,
+            }
+        );
+    }
+    #[test]
+    fn test_end_block_count_divergence() {
+        let (mut storage, reported, _tmp_dir) = storage_with_hook();
+        storage.write_block(0, &[1, 2, 3]).unwrap();
+        storage.end_block_count = 3;
+        assert_eq!(storage.check_block_consistency(0).unwrap(), 1);
+        assert_eq!(
+            reported.lock().unwrap()[0],
+            Divergence::EndBlockCount {
+                in_memory: 3,
+                on_disk: 1,
+            }
+        );
+    }
+    #[test]
+    fn test_disabled_checks() {
+        let (mut storage, reported, _tmp_dir) = storage_with_hook();
+        storage.disable_consistency_checks();
+        storage.end_block_count = 3;
+        assert_eq!(storage.check_block_consistency(0).unwrap(), 0);
+        assert_eq!(reported.lock().unwrap().len(), 0);
+    }
+}
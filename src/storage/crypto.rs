@@ -0,0 +1,364 @@
+use super::*;
+
+/// AES-256 key block data of an encrypted storage file is encrypted with
+pub type EncryptionKey = [u8; ENCRYPTION_KEY_SIZE];
+
+/// Source of the key of an encrypted storage file, e.g. a key management service
+/// - asked once per Storage::enable_encryption call, the key is kept in memory after
+pub trait KeyProvider {
+    fn key(&self) -> Result<EncryptionKey, StorageError>;
+}
+
+/// KeyProvider of a key known up front
+pub struct StaticKey(pub EncryptionKey);
+
+impl KeyProvider for StaticKey {
+    fn key(&self) -> Result<EncryptionKey, StorageError> {
+        Ok(self.0)
+    }
+}
+
+/// AES-256-GCM cipher of an encrypted storage file
+#[cfg(feature = "encryption")]
+#[derive(Clone)]
+pub(super) struct Cipher(aes_gcm::Aes256Gcm);
+
+/// Cipher can not be created without feature encryption
+#[cfg(not(feature = "encryption"))]
+#[derive(Clone)]
+pub(super) enum Cipher {}
+
+#[cfg(feature = "encryption")]
+impl Cipher {
+    fn new(key: &EncryptionKey) -> Cipher {
+        use aes_gcm::KeyInit;
+        Cipher(aes_gcm::Aes256Gcm::new(key.into()))
+    }
+    /// Encrypt data under a random nonce
+    /// - returns: nonce, tag and ciphertext
+    fn encrypt(&self, data: Vec<u8>) -> Result<Vec<u8>, StorageError> {
+        use aes_gcm::aead::{AeadCore, AeadInPlace, OsRng};
+        let nonce = aes_gcm::Aes256Gcm::generate_nonce(&mut OsRng);
+        let mut ciphertext = data;
+        let tag = self
+            .0
+            .encrypt_in_place_detached(&nonce, &[], &mut ciphertext)
+            .map_err(|_| StorageError::Transform {
+                message: "Block data is too long to encrypt".to_string(),
+            })?;
+        let mut block_data = Vec::with_capacity(ENCRYPTION_OVERHEAD + ciphertext.len());
+        block_data.extend_from_slice(&nonce);
+        block_data.extend_from_slice(&tag);
+        block_data.extend_from_slice(&ciphertext);
+        Ok(block_data)
+    }
+    /// Decrypt nonce, tag and ciphertext written by encrypt
+    /// - returns: None if tag does not match, i.e. wrong key or modified data
+    fn decrypt(&self, block_data: Vec<u8>) -> Option<Vec<u8>> {
+        use aes_gcm::aead::AeadInPlace;
+        if block_data.len() < ENCRYPTION_OVERHEAD {
+            return None;
+        }
+        let nonce = aes_gcm::Nonce::clone_from_slice(&block_data[..ENCRYPTION_NONCE_SIZE]);
+        let tag =
+            aes_gcm::Tag::clone_from_slice(&block_data[ENCRYPTION_NONCE_SIZE..ENCRYPTION_OVERHEAD]);
+        let mut data = block_data[ENCRYPTION_OVERHEAD..].to_vec();
+        self.0
+            .decrypt_in_place_detached(&nonce, &[], &mut data, &tag)
+            .ok()?;
+        Some(data)
+    }
+}
+
+#[cfg(not(feature = "encryption"))]
+impl Cipher {
+    fn encrypt(&self, _data: Vec<u8>) -> Result<Vec<u8>, StorageError> {
+        match *self {}
+    }
+    fn decrypt(&self, _block_data: Vec<u8>) -> Option<Vec<u8>> {
+        match *self {}
+    }
+}
+
+impl Storage {
+    // ... ... ... ... ... ... ... ... ... Encryption ... ... ... ... ... ... ... ... ... .
+
+    /// Encrypt every block written from now on with AES-256-GCM, under the key of
+    /// key_provider
+    /// - encryption is stored in storage header, once enabled it can not be disabled
+    /// - on every open of an encrypted file call this again with the same key, until
+    ///   then reads of encrypted blocks and all writes fail with KeyUnavailable
+    /// - a wrong key is only noticed when reading, as DecryptionFailed
+    /// - blocks already written stay unencrypted
+    /// - each block holds ENCRYPTION_OVERHEAD bytes less data, records are split
+    ///   accordingly
+    /// - EncryptionUnavailable without feature encryption
+    #[cfg_attr(not(feature = "encryption"), allow(unused_variables))]
+    pub fn enable_encryption(
+        &mut self,
+        key_provider: &dyn KeyProvider,
+    ) -> Result<(), StorageError> {
+        #[cfg(not(feature = "encryption"))]
+        return Err(StorageError::EncryptionUnavailable);
+        #[cfg(feature = "encryption")]
+        {
+            self.pipeline.encryption.cipher = Some(Cipher::new(&key_provider.key()?));
+            if !self.is_encrypted() {
+                self.header.flags |= STORAGE_FLAG_ENCRYPTED;
+                self.pipeline.configure(&self.header);
+                self.set_storage_header()?;
+                self.written(1)?;
+            }
+            Ok(())
+        }
+    }
+    /// Check if blocks are written encrypted, see enable_encryption
+    pub fn is_encrypted(&self) -> bool {
+        self.header.flags & STORAGE_FLAG_ENCRYPTED != 0
+    }
+    /// Longest data stored in a block before encryption
+    pub(super) fn unencrypted_block_len(&self) -> u64 {
+        if self.is_encrypted() {
+            self.header
+                .block_len
+                .saturating_sub(ENCRYPTION_OVERHEAD as u64)
+        } else {
+            self.header.block_len
+        }
+    }
+}
+
+/// Last transform of the pipeline, encrypting block data once encryption is enabled, see
+/// Storage::enable_encryption
+#[derive(Default)]
+pub(super) struct EncryptionTransform {
+    /// Cipher of encrypted storage file, None until key is given
+    pub(super) cipher: Option<Cipher>,
+    /// Storage file is encrypted, blocks written are encrypted
+    pub(super) encrypted: bool,
+}
+
+impl BlockTransform for EncryptionTransform {
+    fn name(&self) -> &str {
+        "aes-256-gcm"
+    }
+    fn encode(&self, data: &[u8]) -> Result<Vec<u8>, StorageError> {
+        given_cipher(self.cipher.as_ref())?.encrypt(data.to_vec())
+    }
+    fn decode(&self, data: &[u8]) -> Result<Vec<u8>, StorageError> {
+        decrypt_block(self.cipher.as_ref(), 0, data.to_vec(), BLOCK_FLAG_ENCRYPTED)
+    }
+    /// Encrypt block data if storage file is encrypted
+    /// - empty data is kept empty, it marks a free block
+    /// - block flags get BLOCK_FLAG_ENCRYPTED for encrypted data
+    fn encode_block(
+        &self,
+        data: Vec<u8>,
+        block_flags: &mut BlockFlags,
+    ) -> Result<Vec<u8>, StorageError> {
+        if !self.encrypted || data.is_empty() {
+            return Ok(data);
+        }
+        let block_data = given_cipher(self.cipher.as_ref())?.encrypt(data)?;
+        block_flags.0 |= BLOCK_FLAG_ENCRYPTED;
+        Ok(block_data)
+    }
+    /// Decrypt stored block data encrypted by encode_block, as told by block flags
+    fn decode_block(
+        &self,
+        block_index: BlockIndex,
+        data: Vec<u8>,
+        block_flags: BlockFlags,
+    ) -> Result<Vec<u8>, StorageError> {
+        decrypt_block(self.cipher.as_ref(), block_index, data, block_flags.0)
+    }
+}
+
+/// Decrypt stored block data with cipher, see EncryptionTransform::decode_block
+pub(super) fn decrypt_block(
+    cipher: Option<&Cipher>,
+    block_index: BlockIndex,
+    block_data: Vec<u8>,
+    block_flags: u32,
+) -> Result<Vec<u8>, StorageError> {
+    if block_flags & BLOCK_FLAG_ENCRYPTED == 0 {
+        return Ok(block_data);
+    }
+    given_cipher(cipher)?
+        .decrypt(block_data)
+        .ok_or(StorageError::DecryptionFailed { block_index })
+}
+
+/// Cipher, KeyUnavailable if no key was given
+fn given_cipher(cipher: Option<&Cipher>) -> Result<&Cipher, StorageError> {
+    match cipher {
+        Some(cipher) => Ok(cipher),
+        None if cfg!(feature = "encryption") => Err(StorageError::KeyUnavailable),
+        None => Err(StorageError::EncryptionUnavailable),
+    }
+}
+
+#[cfg(test)]
+mod unit_tests_crypto {
+    use super::*;
+
+    const KEY: StaticKey = StaticKey([7; ENCRYPTION_KEY_SIZE]);
+
+    fn new_storage(tmp_dir: &tempfile::TempDir) -> (Storage, String) {
+        let file_path = tmp_dir.path().join("crypto.hex");
+        let file_path = file_path.to_str().unwrap().to_string();
+        (Storage::new(file_path.clone(), 64).unwrap(), file_path)
+    }
+    #[cfg(feature = "encryption")]
+    fn contains(haystack: &[u8], needle: &[u8]) -> bool {
+        haystack
+            .windows(needle.len())
+            .any(|window| window == needle)
+    }
+
+    #[test]
+    fn test_static_key() {
+        assert_eq!(KEY.key().unwrap(), [7; ENCRYPTION_KEY_SIZE]);
+    }
+    #[cfg(feature = "encryption")]
+    #[test]
+    fn test_encrypted_blocks() {
+        let tmp_dir = tempfile::tempdir().unwrap();
+        let (mut storage, file_path) = new_storage(&tmp_dir);
+        storage.write_block(0, b"plain block").unwrap();
+        storage.enable_encryption(&KEY).unwrap();
+        assert!(storage.is_encrypted());
+        storage.write_block(1, b"secret block").unwrap();
+        let record = b"secret record ".repeat(10);
+        let record_id = storage.write_record(&record).unwrap();
+        // full block of plaintext does not fit with nonce and tag
+        assert!(storage.write_block(2, &[1; 64]).is_err());
+        assert_eq!(storage.read_block_header(0).unwrap().flags, 0);
+        let block_header = storage.read_block_header(1).unwrap();
+        assert_eq!(block_header.flags, BLOCK_FLAG_ENCRYPTED);
+        assert_eq!(
+            block_header.block_data_size,
+            12 + ENCRYPTION_OVERHEAD as u64
+        );
+        // no plaintext of encrypted blocks on disk
+        let file_bytes = std::fs::read(&file_path).unwrap();
+        assert!(contains(&file_bytes, b"plain block"));
+        assert!(!contains(&file_bytes, b"secret"));
+        storage.close().unwrap();
+        // reopened file needs its key
+        let mut storage = Storage::open(file_path.clone()).unwrap();
+        assert!(storage.is_encrypted());
+        assert_eq!(storage.read_block(0).unwrap().1, b"plain block");
+        assert!(matches!(
+            storage.read_block(1),
+            Err(StorageError::KeyUnavailable)
+        ));
+        assert!(matches!(
+            storage.write_block(3, b"not encrypted"),
+            Err(StorageError::KeyUnavailable)
+        ));
+        storage.enable_encryption(&KEY).unwrap();
+        assert_eq!(storage.read_block(1).unwrap().1, b"secret block");
+        assert_eq!(storage.read_record(record_id).unwrap(), record);
+        // backup stays encrypted
+        let backup_path = tmp_dir.path().join("crypto_backup.hex");
+        let backup_path = backup_path.to_str().unwrap().to_string();
+        storage.backup_to(backup_path.clone()).unwrap();
+        let mut backup = Storage::open(backup_path).unwrap();
+        assert!(backup.is_encrypted());
+        backup.enable_encryption(&KEY).unwrap();
+        assert_eq!(backup.read_block(1).unwrap().1, b"secret block");
+        // wrong key is noticed on read
+        storage
+            .enable_encryption(&StaticKey([8; ENCRYPTION_KEY_SIZE]))
+            .unwrap();
+        assert!(matches!(
+            storage.read_block(1),
+            Err(StorageError::DecryptionFailed { block_index: 1 })
+        ));
+    }
+    #[cfg(feature = "encryption")]
+    #[test]
+    fn test_encryption_option() {
+        let tmp_dir = tempfile::tempdir().unwrap();
+        let (_, file_path) = new_storage(&tmp_dir);
+        let options = StorageOptions::new(file_path.clone()).encryption(KEY.0);
+        let mut storage = options.clone().open().unwrap();
+        assert!(storage.is_encrypted());
+        storage.write_block(0, b"secret block").unwrap();
+        drop(storage);
+        let mut storage = Storage::open(file_path).unwrap();
+        assert!(matches!(
+            storage.read_block(0),
+            Err(StorageError::KeyUnavailable)
+        ));
+        drop(storage);
+        let mut storage = options.clone().open().unwrap();
+        assert_eq!(storage.read_block(0).unwrap().1, b"secret block");
+        // key is not shown
+        assert!(format!("{:?}", options).contains("encryption: true"));
+        assert!(!format!("{:?}", options).contains("7, 7"));
+    }
+    #[cfg(feature = "encryption")]
+    #[test]
+    fn test_modified_ciphertext() {
+        let tmp_dir = tempfile::tempdir().unwrap();
+        let (mut storage, _) = new_storage(&tmp_dir);
+        storage.enable_encryption(&KEY).unwrap();
+        let encryption = &storage.pipeline.encryption;
+        let mut block_flags = BlockFlags::default();
+        let block_data = encryption
+            .encode_block(vec![1, 2, 3], &mut block_flags)
+            .unwrap();
+        assert_eq!(block_flags, BlockFlags(BLOCK_FLAG_ENCRYPTED));
+        assert_eq!(block_data.len(), 3 + ENCRYPTION_OVERHEAD);
+        let decrypted = encryption.decode_block(0, block_data.clone(), block_flags);
+        assert_eq!(decrypted.unwrap(), vec![1, 2, 3]);
+        assert_eq!(encryption.decode(&block_data).unwrap(), vec![1, 2, 3]);
+        let mut modified = block_data.clone();
+        modified[ENCRYPTION_OVERHEAD] ^= 1;
+        let error = encryption.decode_block(0, modified, block_flags);
+        assert!(matches!(
+            error,
+            Err(StorageError::DecryptionFailed { block_index: 0 })
+        ));
+        let truncated = block_data[..ENCRYPTION_OVERHEAD - 1].to_vec();
+        assert!(encryption.decode_block(0, truncated, block_flags).is_err());
+        // empty data marks a free block, it is not encrypted
+        let mut block_flags = BlockFlags::default();
+        let block_data = encryption.encode_block(Vec::new(), &mut block_flags);
+        assert!(block_data.unwrap().is_empty());
+        assert_eq!(block_flags, BlockFlags::default());
+    }
+    #[cfg(all(feature = "encryption", feature = "lz4"))]
+    #[test]
+    fn test_encrypted_compressed_blocks() {
+        let tmp_dir = tempfile::tempdir().unwrap();
+        let (mut storage, _) = new_storage(&tmp_dir);
+        storage.set_compression(Compression::Lz4).unwrap();
+        storage.enable_encryption(&KEY).unwrap();
+        let data = [b'a'; 36];
+        storage.write_block(0, &data).unwrap();
+        let block_header = storage.read_block_header(0).unwrap();
+        assert_eq!(block_header.flags, BLOCK_FLAG_ENCRYPTED | BLOCK_FLAG_LZ4);
+        assert!(block_header.block_data_size < (data.len() + ENCRYPTION_OVERHEAD) as u64);
+        assert_eq!(storage.read_block(0).unwrap().1, data);
+    }
+    #[cfg(not(feature = "encryption"))]
+    #[test]
+    fn test_unavailable_encryption() {
+        let tmp_dir = tempfile::tempdir().unwrap();
+        let (mut storage, _) = new_storage(&tmp_dir);
+        assert!(matches!(
+            storage.enable_encryption(&KEY),
+            Err(StorageError::EncryptionUnavailable)
+        ));
+        assert!(!storage.is_encrypted());
+        let block_flags = BlockFlags(BLOCK_FLAG_ENCRYPTED);
+        let error = storage
+            .pipeline
+            .encryption
+            .decode_block(0, vec![0; 32], block_flags);
+        assert!(matches!(error, Err(StorageError::EncryptionUnavailable)));
+    }
+}
@@ -0,0 +1,342 @@
+use super::*;
+use std::collections::HashMap;
+
+/// Content hash of every used block, see Storage::write_dedup
+/// - kept in the dedup table of the storage file while it is closed, see layout
+#[derive(Default)]
+pub(super) struct DedupIndex {
+    /// Blocks holding data of each content hash, more than one on hash collisions or
+    /// when identical data was written with write_block
+    by_hash: HashMap<u64, BTreeSet<BlockIndex>>,
+    /// Content hash of each used block
+    by_block: HashMap<BlockIndex, u64>,
+}
+
+impl DedupIndex {
+    fn insert(&mut self, block_index: BlockIndex, data: &[u8]) {
+        self.remove(block_index);
+        let hash = content_hash(data);
+        self.by_hash.entry(hash).or_default().insert(block_index);
+        self.by_block.insert(block_index, hash);
+    }
+    fn remove(&mut self, block_index: BlockIndex) {
+        let hash = match self.by_block.remove(&block_index) {
+            Some(hash) => hash,
+            None => return,
+        };
+        if let Some(blocks) = self.by_hash.get_mut(&hash) {
+            blocks.remove(&block_index);
+            if blocks.is_empty() {
+                self.by_hash.remove(&hash);
+            }
+        }
+    }
+    /// Blocks whose data may equal data, in ascending order
+    fn candidates(&self, data: &[u8]) -> Vec<BlockIndex> {
+        self.by_hash
+            .get(&content_hash(data))
+            .map(|blocks| blocks.iter().cloned().collect())
+            .unwrap_or_default()
+    }
+    /// Dedup table of index, entries in block order, see layout
+    fn to_bytes(&self) -> Vec<u8> {
+        let mut entries: Vec<(&BlockIndex, &u64)> = self.by_block.iter().collect();
+        entries.sort_unstable();
+        let mut bytes = Vec::with_capacity(
+            DEDUP_TABLE_COUNT_SIZE
+                + entries.len() * DEDUP_TABLE_ENTRY_SIZE
+                + DEDUP_TABLE_CHECKSUM_SIZE,
+        );
+        bytes.extend_from_slice(&(entries.len() as u64).to_le_bytes());
+        for (block_index, hash) in entries {
+            bytes.extend_from_slice(&block_index.to_le_bytes());
+            bytes.extend_from_slice(&hash.to_le_bytes());
+        }
+        let checksum = checksum::crc32(&bytes);
+        bytes.extend_from_slice(&checksum.to_le_bytes());
+        bytes
+    }
+    /// Index of dedup table written by to_bytes
+    /// - returns: None if bytes are not a whole table or its checksum does not match
+    fn from_bytes(bytes: &[u8]) -> Option<DedupIndex> {
+        let checksum_offset = bytes.len().checked_sub(DEDUP_TABLE_CHECKSUM_SIZE)?;
+        let (table, checksum) = bytes.split_at(checksum_offset);
+        if table.len() < DEDUP_TABLE_COUNT_SIZE || get_u32(checksum, 0) != checksum::crc32(table) {
+            return None;
+        }
+        let (count, entries) = table.split_at(DEDUP_TABLE_COUNT_SIZE);
+        if get_u64(count, 0) != (entries.len() / DEDUP_TABLE_ENTRY_SIZE) as u64
+            || entries.len() % DEDUP_TABLE_ENTRY_SIZE != 0
+        {
+            return None;
+        }
+        let mut dedup = DedupIndex::default();
+        for entry in entries.chunks_exact(DEDUP_TABLE_ENTRY_SIZE) {
+            let (block_index, hash) = (get_u64(entry, 0), get_u64(entry, 8));
+            dedup.by_hash.entry(hash).or_default().insert(block_index);
+            dedup.by_block.insert(block_index, hash);
+        }
+        Some(dedup)
+    }
+}
+
+/// 64 bit FNV-1a hash of block data, the same on every platform and version, as it is
+/// stored in the dedup table
+fn content_hash(data: &[u8]) -> u64 {
+    data.iter().fold(0xcbf2_9ce4_8422_2325, |hash, byte| {
+        (hash ^ u64::from(*byte)).wrapping_mul(0x0100_0000_01b3)
+    })
+}
+
+impl Storage {
+    // ... ... ... ... ... ... ... ... ... Dedup Writes ... ... ... ... ... ... ... ... ... .
+
+    /// Write data to a new block, unless a block holding identical data exists
+    /// - data must not be empty and must fit in one block, like write_block
+    /// - blocks are looked up by a 64 bit hash of their data, then read and compared,
+    ///   so hash collisions never return a block with other data
+    /// - any used block can be returned, including blocks written with write_block or
+    ///   of a record, deleting or overwriting it changes it for every writer
+    /// - hash table is kept in the storage file while it is closed, and read on open,
+    ///   see layout; once a file has one, every write updates it
+    /// - a file without one, or not closed cleanly, reads every used block to build it
+    ///   on the first call after open, later calls work from memory
+    /// - returns: index of existing or new block
+    pub fn write_dedup(&mut self, data: &[u8]) -> Result<BlockIndex, StorageError> {
+        if data.is_empty() {
+            return Err(StorageError::EmptyRecord);
+        }
+        let dedup = match self.dedup.take() {
+            Some(dedup) => dedup,
+            None => self.read_dedup_index()?,
+        };
+        let candidates = dedup.candidates(data);
+        self.dedup = Some(dedup);
+        for block_index in candidates {
+            if self.read_block(block_index)?.1 == data {
+                return Ok(block_index);
+            }
+        }
+        let block_index = self.allocate(1)?[0];
+        self.write_block(block_index, data)?;
+        Ok(block_index)
+    }
+    /// Update in memory content hash of block after it was written or deleted
+    /// - data: block data before encoding, None or empty when block was freed
+    /// - no-op until the hash table was loaded, by open or write_dedup
+    pub(super) fn track_content(&mut self, block_index: BlockIndex, data: Option<&[u8]>) {
+        if let Some(dedup) = self.dedup.as_mut() {
+            match data {
+                Some(data) if !data.is_empty() => dedup.insert(block_index, data),
+                _ => dedup.remove(block_index),
+            }
+        }
+    }
+    /// Load hash table from the dedup table of the storage file, see layout
+    /// - a file opened dirty may hold a table cut short, it is not read
+    /// - a damaged table is left out, write_dedup builds the hash table instead
+    pub(super) fn read_dedup_table(&mut self) -> Result<(), StorageError> {
+        let offset = self.header.dedup_table;
+        if offset == 0 || self.header.is_dirty() {
+            return Ok(());
+        }
+        let file_len = self
+            .file_reader
+            .metadata()
+            .map_err(StorageError::io("read storage file metadata", None))?
+            .len();
+        let table_len = checked_usize(file_len.saturating_sub(offset))?;
+        let mut table = vec![0u8; table_len];
+        let read_size = positional::read_at(&self.file_reader, offset, &mut table)
+            .map_err(StorageError::io("read dedup table", None))?;
+        self.dedup = DedupIndex::from_bytes(&table[..read_size]);
+        Ok(())
+    }
+    /// Write hash table after the last block, when the file is marked clean
+    /// - storage header tells where the blocks end before the table is written, so a
+    ///   crash meanwhile leaves a dirty file whose blocks are still found
+    pub(super) fn write_dedup_table(&mut self) -> Result<(), StorageError> {
+        let table = match self.dedup.as_ref() {
+            Some(dedup) => dedup.to_bytes(),
+            None => return Ok(()),
+        };
+        let offset = self.block_offset(self.end_block_count)?;
+        self.header.dedup_table = offset;
+        if let Err(error) = self.set_storage_header() {
+            self.header.dedup_table = 0;
+            return Err(error);
+        }
+        self.write_file_at(offset, &table, "write dedup table", None)?;
+        self.written(1)
+    }
+    /// Truncate dedup table away once the file is opened for writing, so blocks can
+    /// grow the file
+    /// - truncation is synced before the storage header drops the table
+    pub(super) fn remove_dedup_table(&mut self) -> Result<(), StorageError> {
+        let offset = self.header.dedup_table;
+        if offset == 0 {
+            return Ok(());
+        }
+        let truncated = self
+            .file_writer
+            .set_len(offset)
+            .and_then(|_| self.file_writer.sync_data());
+        if let Err(error) = truncated {
+            self.write_failed.store(true, Ordering::Relaxed);
+            return Err(StorageError::io("truncate dedup table", None)(error));
+        }
+        self.header.dedup_table = 0;
+        Ok(())
+    }
+    /// Hash data of every used block
+    fn read_dedup_index(&mut self) -> Result<DedupIndex, StorageError> {
+        let mut dedup = DedupIndex::default();
+        for block in self.iter_blocks() {
+            let (block_index, block_data) = block?;
+            dedup.insert(block_index, &block_data);
+        }
+        Ok(dedup)
+    }
+}
+
+#[cfg(test)]
+mod unit_tests_dedup {
+    use super::*;
+
+    fn new_storage(tmp_dir: &tempfile::TempDir) -> (Storage, String) {
+        let file_path = tmp_dir.path().join("dedup.hex");
+        let file_path = file_path.to_str().unwrap().to_string();
+        (Storage::new(file_path.clone(), 4).unwrap(), file_path)
+    }
+
+    #[test]
+    fn test_write_dedup() {
+        let tmp_dir = tempfile::tempdir().unwrap();
+        let (mut storage, file_path) = new_storage(&tmp_dir);
+        storage.write_block(0, &[1, 2]).unwrap();
+        storage.close().unwrap();
+        // hash table is built from blocks in file on first call
+        let mut storage = Storage::open(file_path).unwrap();
+        assert_eq!(storage.write_dedup(&[1, 2]).unwrap(), 0);
+        assert_eq!(storage.write_dedup(&[3]).unwrap(), 1);
+        assert_eq!(storage.write_dedup(&[3]).unwrap(), 1);
+        assert_eq!(storage.end_block_count, 2);
+        assert!(matches!(
+            storage.write_dedup(&[]),
+            Err(StorageError::EmptyRecord)
+        ));
+        // later writes and deletes are tracked in memory
+        storage.write_block(1, &[4]).unwrap();
+        assert_eq!(storage.write_dedup(&[3]).unwrap(), 2);
+        storage.delete_block(0, false).unwrap();
+        assert_eq!(storage.write_dedup(&[1, 2]).unwrap(), 0);
+        let payloads: Vec<&[u8]> = vec![&[5, 6, 7, 8, 9]];
+        let batch_blocks = storage.write_batch(&payloads).unwrap();
+        assert_eq!(storage.write_dedup(&[9]).unwrap(), batch_blocks[0][1]);
+        assert_eq!(storage.read_block(2).unwrap().1, vec![3]);
+    }
+    #[test]
+    fn test_dedup_table() {
+        let tmp_dir = tempfile::tempdir().unwrap();
+        let (mut storage, file_path) = new_storage(&tmp_dir);
+        assert_eq!(storage.write_dedup(&[1, 2]).unwrap(), 0);
+        assert_eq!(storage.write_dedup(&[3]).unwrap(), 1);
+        let blocks_end = storage.block_offset(2).unwrap();
+        storage.close().unwrap();
+        // table of 2 entries after the last block
+        let file_len = |file_path: &str| std::fs::metadata(file_path).unwrap().len();
+        assert_eq!(file_len(&file_path), blocks_end + 8 + 2 * 16 + 4);
+        let storage = StorageOptions::new(file_path.clone())
+            .read_only(true)
+            .open()
+            .unwrap();
+        assert_eq!(storage.header.dedup_table, blocks_end);
+        assert_eq!(storage.dedup.as_ref().unwrap().candidates(&[3]), vec![1]);
+        assert_eq!(storage.end_block_count, 2);
+        drop(storage);
+        // opened for writing, table is truncated away and blocks are not hashed again
+        let mut storage = Storage::open(file_path.clone()).unwrap();
+        assert_eq!(file_len(&file_path), blocks_end);
+        assert_eq!(storage.header.dedup_table, 0);
+        assert!(storage.dedup.is_some());
+        assert_eq!(storage.write_dedup(&[3]).unwrap(), 1);
+        storage.write_block(2, &[4]).unwrap();
+        storage.close().unwrap();
+        // damaged table is left out, hash table is built from blocks instead
+        let table_offset = storage_table_offset(&file_path);
+        let mut bytes = std::fs::read(&file_path).unwrap();
+        bytes[table_offset as usize + 8] ^= 1;
+        std::fs::write(&file_path, bytes).unwrap();
+        let mut storage = Storage::open(file_path).unwrap();
+        assert!(storage.dedup.is_none());
+        assert_eq!(storage.end_block_count, 3);
+        assert_eq!(storage.write_dedup(&[4]).unwrap(), 2);
+    }
+    #[test]
+    fn test_dedup_table_torn_clean_header() {
+        let tmp_dir = tempfile::tempdir().unwrap();
+        let (mut storage, file_path) = new_storage(&tmp_dir);
+        storage.write_dedup(&[1, 2]).unwrap();
+        let blocks_end = storage.block_offset(1).unwrap();
+        storage.close().unwrap();
+        // - tear the header clearing the dirty flag, the older copy is dirty and already
+        //   points at the table
+        let file = std::fs::File::open(&file_path).unwrap();
+        let (storage_header, _) = StorageHeader::read_copies(&file).unwrap();
+        let mut file_bytes = std::fs::read(&file_path).unwrap();
+        let newest_offset = storage_header_offset(storage_header.sequence) as usize;
+        file_bytes[newest_offset + 20] ^= 0xff;
+        std::fs::write(&file_path, &file_bytes).unwrap();
+        let (older_header, _) = StorageHeader::read_copies(&file).unwrap();
+        assert!(older_header.is_dirty());
+        assert_eq!(older_header.dedup_table, blocks_end);
+        // - blocks are scanned up to the table, which is truncated away unread
+        let mut storage = Storage::open(file_path.clone()).unwrap();
+        assert_eq!(storage.end_block_count, 1);
+        assert!(storage.dedup.is_none());
+        assert_eq!(std::fs::metadata(&file_path).unwrap().len(), blocks_end);
+        assert_eq!(storage.read_block(0).unwrap().1, vec![1, 2]);
+        assert_eq!(storage.write_dedup(&[1, 2]).unwrap(), 0);
+    }
+    /// Dedup table offset in storage header of closed file
+    fn storage_table_offset(file_path: &str) -> u64 {
+        let storage = StorageOptions::new(file_path.to_string())
+            .read_only(true)
+            .open()
+            .unwrap();
+        storage.header.dedup_table
+    }
+    #[test]
+    fn test_dedup_table_bytes() {
+        let mut dedup = DedupIndex::default();
+        dedup.insert(7, &[1]);
+        dedup.insert(2, &[2]);
+        let bytes = dedup.to_bytes();
+        assert_eq!(bytes.len(), 8 + 2 * 16 + 4);
+        // - entries in block order
+        assert_eq!(get_u64(&bytes, 8), 2);
+        let loaded = DedupIndex::from_bytes(&bytes).unwrap();
+        assert_eq!(loaded.by_block, dedup.by_block);
+        assert_eq!(loaded.candidates(&[1]), vec![7]);
+        assert!(DedupIndex::from_bytes(&bytes[..bytes.len() - 1]).is_none());
+        assert!(DedupIndex::from_bytes(&[]).is_none());
+        // - hash is stable across platforms and versions
+        assert_eq!(content_hash(&[]), 0xcbf2_9ce4_8422_2325);
+        assert_eq!(content_hash(b"a"), 0xaf63_dc4c_8601_ec8c);
+    }
+    #[test]
+    fn test_dedup_index() {
+        let mut dedup = DedupIndex::default();
+        dedup.insert(3, &[1]);
+        dedup.insert(5, &[1]);
+        dedup.insert(4, &[2]);
+        assert_eq!(dedup.candidates(&[1]), vec![3, 5]);
+        // overwrite moves block to hash of its new data
+        dedup.insert(3, &[2]);
+        assert_eq!(dedup.candidates(&[1]), vec![5]);
+        assert_eq!(dedup.candidates(&[2]), vec![3, 4]);
+        dedup.remove(5);
+        assert!(dedup.candidates(&[1]).is_empty());
+        assert!(dedup.by_hash.len() == 1 && dedup.by_block.len() == 2);
+    }
+}
@@ -0,0 +1,163 @@
+use super::*;
+use std::time::{Duration, Instant};
+
+/// When writes are synced to disk with fsync
+/// - until synced, acknowledged writes can be lost on power loss or OS crash
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub enum DurabilityMode {
+    /// Only sync on Storage::flush
+    #[default]
+    Never,
+    /// Sync after every block write and delete, before it returns
+    /// - a batch write syncs once per run of contiguous blocks
+    EveryWrite,
+    /// Sync after every n block writes and deletes, 0 behaves as 1
+    EveryNWrites(u32),
+    /// Sync on the first block write or delete at least ms milliseconds after the last sync
+    /// - there is no timer, a write followed by a long idle period stays unsynced
+    ///   until next write or flush
+    IntervalMs(u64),
+}
+
+/// Sync state of a Storage
+pub(super) struct Durability {
+    mode: DurabilityMode,
+    /// Block writes and deletes since last sync
+    unsynced_writes: u32,
+    /// Storage header was written since last sync
+    unsynced_header: bool,
+    last_sync: Instant,
+}
+
+impl Durability {
+    pub(super) fn new(mode: DurabilityMode) -> Self {
+        Durability {
+            mode,
+            unsynced_writes: 0,
+            unsynced_header: false,
+            last_sync: Instant::now(),
+        }
+    }
+    /// Count a write of the storage header, synced by the next flush
+    /// - does not count towards the writes of the durability mode
+    pub(super) fn header_written(&mut self) {
+        self.unsynced_header = true;
+    }
+    /// Check if mode asks for a sync after unsynced_writes
+    fn sync_due(&self) -> bool {
+        match self.mode {
+            DurabilityMode::Never => false,
+            DurabilityMode::EveryWrite => true,
+            DurabilityMode::EveryNWrites(n) => self.unsynced_writes >= n.max(1),
+            DurabilityMode::IntervalMs(ms) => self.last_sync.elapsed() >= Duration::from_millis(ms),
+        }
+    }
+}
+
+impl Storage {
+    // ... ... ... ... ... ... ... ... ... Durability ... ... ... ... ... ... ... ... ... .
+
+    /// Set when writes are synced to disk, see DurabilityMode
+    /// - default is DurabilityMode::Never
+    /// - mode is not stored in storage file, it must be set every time the file is opened
+    pub fn set_durability(&mut self, mode: DurabilityMode) {
+        self.durability.mode = mode;
+    }
+    /// Current durability mode
+    pub fn durability(&self) -> DurabilityMode {
+        self.durability.mode
+    }
+    /// Sync all writes and deletes so far to disk, and writes of the storage header
+    /// - no-op if nothing was written since last sync
+    /// - on failure writes stay unsynced, so next flush tries again, and the dirty flag
+    ///   stays set on close, see close
+    pub fn flush(&mut self) -> Result<(), StorageError> {
+        use std::io::prelude::*;
+        if self.durability.unsynced_writes == 0 && !self.durability.unsynced_header {
+            return Ok(());
+        }
+        let synced = self
+            .file_writer
+            .flush()
+            .map_err(StorageError::io("flush storage file", None))
+            .and_then(|_| {
+                self.file_writer
+                    .sync_data()
+                    .map_err(StorageError::io("sync storage file", None))
+            });
+        if synced.is_err() {
+            self.write_failed.store(true, Ordering::Relaxed);
+        }
+        synced?;
+        self.durability.unsynced_writes = 0;
+        self.durability.unsynced_header = false;
+        self.durability.last_sync = Instant::now();
+        Ok(())
+    }
+    /// Count block_count block writes or deletes, and sync if durability mode asks for it
+    pub(super) fn written(&mut self, block_count: u32) -> Result<(), StorageError> {
+        self.durability.unsynced_writes =
+            self.durability.unsynced_writes.saturating_add(block_count);
+        if self.durability.sync_due() {
+            self.flush()?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(all(test, target_os = "linux"))]
+impl Storage {
+    /// Let every later sync fail, writes go to /dev/null which can not be synced
+    pub(crate) fn fail_syncs(&mut self) {
+        if let Ok(file) = OpenOptions::new().write(true).open("/dev/null") {
+            self.file_writer = file;
+        }
+    }
+}
+
+#[cfg(test)]
+mod unit_tests_durability {
+    use super::*;
+
+    fn new_storage(tmp_dir: &tempfile::TempDir) -> Storage {
+        let file_path = tmp_dir.path().join("durability.hex");
+        Storage::new(file_path.to_str().unwrap().to_string(), 4).unwrap()
+    }
+
+    #[test]
+    fn test_durability_modes() {
+        let tmp_dir = tempfile::tempdir().unwrap();
+        let mut storage = new_storage(&tmp_dir);
+        assert_eq!(storage.durability(), DurabilityMode::Never);
+        storage.write_block(0, &[1]).unwrap();
+        storage.delete_block(0, false).unwrap();
+        assert_eq!(storage.durability.unsynced_writes, 2);
+        storage.flush().unwrap();
+        assert_eq!(storage.durability.unsynced_writes, 0);
+        storage.set_durability(DurabilityMode::EveryWrite);
+        storage.write_block(0, &[1]).unwrap();
+        assert_eq!(storage.durability.unsynced_writes, 0);
+        storage.set_durability(DurabilityMode::EveryNWrites(3));
+        storage.write_block(1, &[1]).unwrap();
+        storage.write_block(2, &[1]).unwrap();
+        assert_eq!(storage.durability.unsynced_writes, 2);
+        storage.write_block(3, &[1]).unwrap();
+        assert_eq!(storage.durability.unsynced_writes, 0);
+        // - header writes are synced with the next flush
+        storage.write_dirty_flag(true).unwrap();
+        assert!(storage.durability.unsynced_header);
+        storage.flush().unwrap();
+        assert!(!storage.durability.unsynced_header);
+    }
+    #[test]
+    fn test_interval_mode() {
+        let tmp_dir = tempfile::tempdir().unwrap();
+        let mut storage = new_storage(&tmp_dir);
+        storage.set_durability(DurabilityMode::IntervalMs(60_000));
+        storage.write_block(0, &[1]).unwrap();
+        assert_eq!(storage.durability.unsynced_writes, 1);
+        storage.set_durability(DurabilityMode::IntervalMs(0));
+        storage.write_block(1, &[1]).unwrap();
+        assert_eq!(storage.durability.unsynced_writes, 0);
+    }
+}
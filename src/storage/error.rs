@@ -1,15 +1,403 @@
-pub struct Error {
-    pub code: i32,
-    pub message: String,
-}
-// add fmt::Debug trait to Error struct
+use super::{BlockIndex, Compression, Generation};
 use std::fmt;
-impl fmt::Debug for Error {
-    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        write!(
-            f,
-            "Error {{ code: {}, message: {} }}",
-            self.code, self.message
+use std::io;
+
+/// Error returned by Storage operations
+/// - match on variants to handle classes of failures
+/// - Display gives a human readable message
+#[derive(Debug)]
+pub enum StorageError {
+    /// Storage file could not be created, opened, seeked, read or written
+    Io {
+        /// What was being done, e.g. "write block header"
+        operation: &'static str,
+        /// Block being accessed, None for storage header and bitmap
+        block_index: Option<BlockIndex>,
+        source: io::Error,
+    },
+    /// Storage file content is invalid, e.g. truncated or with impossible header values
+    Corruption {
+        /// Corrupted block, None for storage header and bitmap
+        block_index: Option<BlockIndex>,
+        reason: &'static str,
+    },
+    /// Stored block data does not match checksum in its block header
+    ChecksumMismatch { block_index: BlockIndex },
+    /// Block index can not be addressed in storage file
+    BlockOutOfRange { block_index: BlockIndex },
+    /// Block data, after block transforms, does not fit in block_len bytes
+    BlockTooLarge {
+        block_index: BlockIndex,
+        data_len: usize,
+        block_len: u64,
+    },
+    /// block_len must be within 1..=MAX_BLOCK_LEN
+    InvalidBlockLength { block_len: u64 },
+    /// Record data must not be empty, an empty block can not be told apart from a free block
+    EmptyRecord,
+    /// Block transform could not encode or decode block data
+    Transform { message: String },
+    /// Engine stopped before it sent the result of a request
+    EngineStopped,
+    /// Request was cancelled through its RequestHandle before it was served
+    Cancelled,
+    /// Request was not served before the deadline set through its RequestHandle
+    TimedOut,
+    /// Engine was shut down before it served the request, see EngineHandle::shutdown
+    ShuttingDown,
+    /// Engine queue was at its admission limit, the request was not queued, see
+    /// Engine::set_admission_limit
+    Overloaded,
+    /// File does not start with the storage file magic, it is not a storage file, or
+    /// one of format version 4 or older, see Storage::upgrade
+    NotStorageFile,
+    /// Storage file was written in a format version this version can not read, see
+    /// Storage::upgrade
+    UnsupportedVersion { version: u16 },
+    /// Codec is not compiled in, enable its feature
+    CompressionUnavailable { compression: Compression },
+    /// Encryption is not compiled in, enable feature encryption
+    EncryptionUnavailable,
+    /// Storage file is encrypted and no key was given, see Storage::enable_encryption
+    KeyUnavailable,
+    /// Encrypted block data does not match its tag, the key is wrong or data was modified
+    DecryptionFailed { block_index: BlockIndex },
+    /// Block transforms of the storage are not the pipeline recorded in the storage
+    /// file, see Storage::push_transform
+    PipelineMismatch { recorded: u32, configured: u32 },
+    /// Request addressed a storage that is not attached to the engine, see Engine::attach
+    UnknownStorage { name: String },
+    /// Record needs more blocks than fit in one segment of a SegmentedStorage
+    RecordTooLarge { block_count: usize, max_blocks: u64 },
+    /// Block is pinned and can not be deleted, see Storage::pin_block
+    BlockPinned { block_index: BlockIndex },
+    /// Storage file is append-only, block holds data and can not be overwritten or deleted,
+    /// see Storage::enable_append_only
+    AppendOnly { block_index: BlockIndex },
+    /// Data needs more blocks than were given for it, see Storage::write_into
+    NotEnoughBlocks {
+        block_count: usize,
+        given_blocks: usize,
+    },
+    /// Block index was given more than once for blocks of one record
+    DuplicateBlock { block_index: BlockIndex },
+    /// Block was written or deleted since generation expected was read, see
+    /// Storage::write_block_if
+    Conflict {
+        block_index: BlockIndex,
+        expected: Generation,
+        actual: Generation,
+    },
+    /// Server failed a request sent over the network, see server::Client
+    Remote { message: String },
+    /// Value could not be encoded into or decoded from a record, see record::Record
+    Serialization { message: String },
+    /// Log position is outside of the log, or not the start of an entry, see
+    /// log::AppendLog
+    InvalidLsn { lsn: u64 },
+    /// Length does not fit in the integer it is stored as, e.g. a key longer than
+    /// u32::MAX bytes
+    TooLarge { len: u64, max: u64 },
+    /// Writing block would grow storage file past StorageOptions::max_bytes
+    QuotaExceeded {
+        block_index: BlockIndex,
+        max_bytes: u64,
+    },
+    /// Storage file was opened read-only, see StorageOptions::read_only
+    ReadOnly,
+    /// Key value store was given a merge without a merge operator, see kv::MergeOperator
+    MergeOperatorMissing,
+    /// Column family is not in the store, see kv::ColumnFamilies::create_cf
+    UnknownColumnFamily { name: String },
+    /// Writes to column family are frozen, see kv::ColumnFamilies::freeze_cf
+    Frozen { name: String },
+    /// Another storage object, in this or another process, holds a lock of the storage
+    /// file that conflicts, e.g. it has the file open for writing
+    AlreadyLocked,
+    /// Error of an engine request traced with trace_id, see IORequest::traced
+    /// - match on untraced to handle the error itself
+    Traced {
+        trace_id: String,
+        source: Box<StorageError>,
+    },
+}
+
+impl StorageError {
+    /// Map io::Error of operation into StorageError::Io
+    /// - use with map_err
+    pub(super) fn io(
+        operation: &'static str,
+        block_index: Option<BlockIndex>,
+    ) -> impl FnOnce(io::Error) -> StorageError {
+        move |source| StorageError::Io {
+            operation,
+            block_index,
+            source,
+        }
+    }
+    /// StorageError::Io for a write that did not write all bytes
+    pub(super) fn incomplete_write(
+        operation: &'static str,
+        block_index: Option<BlockIndex>,
+    ) -> StorageError {
+        StorageError::io(operation, block_index)(io::ErrorKind::WriteZero.into())
+    }
+    /// Attach trace_id of the request that failed, see StorageError::Traced
+    /// - an error that already has a trace id keeps it
+    pub(crate) fn traced(self, trace_id: &str) -> StorageError {
+        match self {
+            StorageError::Traced { .. } => self,
+            source => StorageError::Traced {
+                trace_id: trace_id.to_string(),
+                source: Box::new(source),
+            },
+        }
+    }
+    /// Trace id of the request that failed, None if it was not traced
+    pub fn trace_id(&self) -> Option<&str> {
+        match self {
+            StorageError::Traced { trace_id, .. } => Some(trace_id),
+            _ => None,
+        }
+    }
+    /// Error without the trace id of its request, see StorageError::Traced
+    pub fn untraced(&self) -> &StorageError {
+        match self {
+            StorageError::Traced { source, .. } => source.untraced(),
+            error => error,
+        }
+    }
+    /// Check if error means storage file content is invalid, rather than inaccessible
+    pub fn is_corruption(&self) -> bool {
+        matches!(
+            self.untraced(),
+            StorageError::Corruption { .. } | StorageError::ChecksumMismatch { .. }
         )
     }
+    /// Block involved in the failure, if any
+    pub fn block_index(&self) -> Option<BlockIndex> {
+        match self.untraced() {
+            StorageError::Io { block_index, .. } | StorageError::Corruption { block_index, .. } => {
+                *block_index
+            }
+            StorageError::ChecksumMismatch { block_index }
+            | StorageError::BlockOutOfRange { block_index }
+            | StorageError::BlockTooLarge { block_index, .. }
+            | StorageError::DecryptionFailed { block_index }
+            | StorageError::BlockPinned { block_index }
+            | StorageError::AppendOnly { block_index }
+            | StorageError::DuplicateBlock { block_index }
+            | StorageError::Conflict { block_index, .. }
+            | StorageError::QuotaExceeded { block_index, .. } => Some(*block_index),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for StorageError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            StorageError::Io {
+                operation,
+                block_index: Some(block_index),
+                source,
+            } => write!(
+                f,
+                "Could not {} of block {}: {}",
+                operation, block_index, source
+            ),
+            StorageError::Io {
+                operation, source, ..
+            } => write!(f, "Could not {}: {}", operation, source),
+            StorageError::Corruption {
+                block_index: Some(block_index),
+                reason,
+            } => write!(f, "Block {} is corrupted: {}", block_index, reason),
+            StorageError::Corruption { reason, .. } => {
+                write!(f, "Storage file is corrupted: {}", reason)
+            }
+            StorageError::ChecksumMismatch { block_index } => {
+                write!(f, "Checksum mismatch in block {}", block_index)
+            }
+            StorageError::BlockOutOfRange { block_index } => {
+                write!(f, "Block index {} out of range", block_index)
+            }
+            StorageError::BlockTooLarge {
+                block_index,
+                data_len,
+                block_len,
+            } => write!(
+                f,
+                "Block data of {} bytes exceeds block length {} of block {}",
+                data_len, block_len, block_index
+            ),
+            StorageError::InvalidBlockLength { block_len } => {
+                write!(
+                    f,
+                    "Block length {} must be within 1..={}",
+                    block_len,
+                    super::layout::MAX_BLOCK_LEN
+                )
+            }
+            StorageError::EmptyRecord => write!(f, "Record data is empty"),
+            StorageError::Transform { message } => {
+                write!(f, "Block transform failed: {}", message)
+            }
+            StorageError::EngineStopped => write!(f, "Engine stopped before serving request"),
+            StorageError::Cancelled => write!(f, "Request was cancelled"),
+            StorageError::TimedOut => write!(f, "Request deadline passed before it was served"),
+            StorageError::ShuttingDown => write!(f, "Engine shut down before serving request"),
+            StorageError::Overloaded => write!(f, "Engine queue is full, request was not queued"),
+            StorageError::NotStorageFile => write!(f, "File is not a storage file"),
+            StorageError::UnsupportedVersion { version } => write!(
+                f,
+                "Storage file format version {} is not supported, expected version {}",
+                version,
+                super::layout::FORMAT_VERSION
+            ),
+            StorageError::CompressionUnavailable { compression } => write!(
+                f,
+                "Compression {:?} is not available, enable its feature",
+                compression
+            ),
+            StorageError::EncryptionUnavailable => {
+                write!(f, "Encryption is not available, enable feature encryption")
+            }
+            StorageError::KeyUnavailable => {
+                write!(f, "Storage file is encrypted, no encryption key was given")
+            }
+            StorageError::DecryptionFailed { block_index } => write!(
+                f,
+                "Block {} can not be decrypted, wrong key or modified data",
+                block_index
+            ),
+            StorageError::PipelineMismatch {
+                recorded,
+                configured,
+            } => write!(
+                f,
+                "Block transforms {:08x} are not the pipeline {:08x} of the storage file",
+                configured, recorded
+            ),
+            StorageError::UnknownStorage { name } => {
+                write!(f, "No storage named {} is attached to the engine", name)
+            }
+            StorageError::RecordTooLarge {
+                block_count,
+                max_blocks,
+            } => write!(
+                f,
+                "Record of {} blocks exceeds {} blocks per segment",
+                block_count, max_blocks
+            ),
+            StorageError::BlockPinned { block_index } => {
+                write!(f, "Block {} is pinned and can not be deleted", block_index)
+            }
+            StorageError::AppendOnly { block_index } => write!(
+                f,
+                "Storage is append-only, block {} can not be overwritten or deleted",
+                block_index
+            ),
+            StorageError::NotEnoughBlocks {
+                block_count,
+                given_blocks,
+            } => write!(
+                f,
+                "Data needs {} blocks, only {} were given",
+                block_count, given_blocks
+            ),
+            StorageError::DuplicateBlock { block_index } => {
+                write!(f, "Block {} is given more than once", block_index)
+            }
+            StorageError::Conflict {
+                block_index,
+                expected,
+                actual,
+            } => write!(
+                f,
+                "Block {} is at generation {}, expected generation {}",
+                block_index, actual, expected
+            ),
+            StorageError::Remote { message } => write!(f, "Server failed request: {}", message),
+            StorageError::Serialization { message } => {
+                write!(f, "Record serialization failed: {}", message)
+            }
+            StorageError::InvalidLsn { lsn } => {
+                write!(f, "Log position {} is not the start of a log entry", lsn)
+            }
+            StorageError::TooLarge { len, max } => {
+                write!(
+                    f,
+                    "Length {} exceeds the largest storable length {}",
+                    len, max
+                )
+            }
+            StorageError::QuotaExceeded {
+                block_index,
+                max_bytes,
+            } => write!(
+                f,
+                "Writing block {} would grow storage file past its quota of {} bytes",
+                block_index, max_bytes
+            ),
+            StorageError::ReadOnly => write!(f, "Storage file is opened read-only"),
+            StorageError::MergeOperatorMissing => {
+                write!(f, "Key value store has no merge operator")
+            }
+            StorageError::UnknownColumnFamily { name } => {
+                write!(f, "No column family named {} in the key value store", name)
+            }
+            StorageError::Frozen { name } => {
+                write!(f, "Writes to column family {} are frozen", name)
+            }
+            StorageError::AlreadyLocked => {
+                write!(f, "Storage file is locked by another storage object")
+            }
+            StorageError::Traced { trace_id, source } => {
+                write!(f, "{} (trace id {})", source, trace_id)
+            }
+        }
+    }
+}
+
+impl std::error::Error for StorageError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            StorageError::Io { source, .. } => Some(source),
+            StorageError::Traced { source, .. } => Some(source.as_ref()),
+            _ => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod unit_tests_error {
+    use super::*;
+
+    #[test]
+    fn test_io_error_source() {
+        let error = StorageError::io("read block header", Some(3))(io::ErrorKind::NotFound.into());
+        assert_eq!(error.block_index(), Some(3));
+        assert!(!error.is_corruption());
+        let source = std::error::Error::source(&error).unwrap();
+        assert_eq!(
+            source.to_string(),
+            io::Error::from(io::ErrorKind::NotFound).to_string()
+        );
+        assert!(error
+            .to_string()
+            .starts_with("Could not read block header of block 3"));
+    }
+    #[test]
+    fn test_error_classes() {
+        let error = StorageError::ChecksumMismatch { block_index: 1 };
+        assert!(error.is_corruption());
+        assert_eq!(error.to_string(), "Checksum mismatch in block 1");
+        let error = StorageError::incomplete_write("write storage header", None);
+        assert_eq!(error.block_index(), None);
+        assert!(matches!(
+            error,
+            StorageError::Io { ref source, .. } if source.kind() == io::ErrorKind::WriteZero
+        ));
+    }
 }
@@ -0,0 +1,157 @@
+use super::*;
+
+impl Storage {
+    // ... ... ... ... ... ... ... ... ... Block Expiry ... ... ... ... ... ... ... ... ... .
+
+    /// Write data to block, like write_block, with an expiry in its block header
+    /// - expires_at: seconds since UNIX epoch from which sweep_expired deletes the block,
+    ///   None (or 0) never expires
+    /// - expired blocks are readable until swept
+    /// - returns: end offset of written block data
+    pub fn write_block_expiring(
+        &mut self,
+        block_index: BlockIndex,
+        data: &[u8],
+        expires_at: Option<u64>,
+    ) -> Result<u64, StorageError> {
+        self.write_linked_block(block_index, data, None, expires_at, 0)
+    }
+    /// Write data as a record, like write_record, with the same expiry in the block header
+    /// of each of its blocks, see write_block_expiring
+    /// - returns: record id
+    pub fn write_record_expiring(
+        &mut self,
+        data: &[u8],
+        expires_at: Option<u64>,
+    ) -> Result<RecordId, StorageError> {
+        let block_indexes = self.write_chunks(data, expires_at, 0)?;
+        Ok(block_indexes[0])
+    }
+    /// Write data split in chunks, like write_blocks_chunked, with the same expiry in the
+    /// block header of each block, see write_block_expiring
+    /// - returns: indexes of all blocks used, in data order, first is the record id
+    pub fn write_blocks_expiring(
+        &mut self,
+        data: &[u8],
+        expires_at: Option<u64>,
+    ) -> Result<Vec<BlockIndex>, StorageError> {
+        self.write_chunks(data, expires_at, 0)
+    }
+    /// Expiry of block, reading its block header only
+    /// - returns: seconds since UNIX epoch the block expires at, None for blocks that
+    ///   never expire and free blocks
+    pub fn block_expiry(&mut self, block_index: BlockIndex) -> Result<Option<u64>, StorageError> {
+        if self.is_empty_block(block_index) {
+            return Ok(None);
+        }
+        Ok(self.read_block_header(block_index)?.expires_at)
+    }
+    /// Soft delete every block that expired at or before now
+    /// - now: seconds since UNIX epoch
+    /// - first sweep after open reads the header of every used block, later sweeps work
+    ///   from memory
+    /// - blocks of a record share one expiry, so a record is swept as a whole
+    /// - pinned blocks are skipped, they are swept once unpinned
+    /// - returns: indexes of deleted blocks, in ascending order
+    pub fn sweep_expired(&mut self, now: u64) -> Result<Vec<BlockIndex>, StorageError> {
+        let expiries = match self.expiries.take() {
+            Some(expiries) => expiries,
+            None => self.read_expiries()?,
+        };
+        let expired: Vec<BlockIndex> = expiries
+            .iter()
+            .filter(|(block_index, expires_at)| {
+                **expires_at <= now && !self.is_pinned(**block_index)
+            })
+            .map(|(block_index, _)| *block_index)
+            .collect();
+        self.expiries = Some(expiries);
+        for block_index in expired.iter() {
+            self.delete_block(*block_index, false)?;
+        }
+        Ok(expired)
+    }
+    /// Update in memory expiry of block after it was written or deleted
+    /// - no-op until a sweep loaded expiries
+    pub(super) fn track_expiry(&mut self, block_index: BlockIndex, expires_at: Option<u64>) {
+        if let Some(expiries) = self.expiries.as_mut() {
+            match expires_at {
+                Some(expires_at) => expiries.insert(block_index, expires_at),
+                None => expiries.remove(&block_index),
+            };
+        }
+    }
+    /// Read expiry of every used block that expires from block headers
+    fn read_expiries(&mut self) -> Result<BTreeMap<BlockIndex, u64>, StorageError> {
+        let mut expiries = BTreeMap::new();
+        for block_info in self.iter_block_headers() {
+            let block_info = block_info?;
+            if let Some(expires_at) = block_info.expires_at {
+                expiries.insert(block_info.block_index, expires_at);
+            }
+        }
+        Ok(expiries)
+    }
+}
+
+#[cfg(test)]
+mod unit_tests_expiry {
+    use super::*;
+
+    fn new_storage(tmp_dir: &tempfile::TempDir) -> (Storage, String) {
+        let file_path = tmp_dir.path().join("expiry.hex");
+        let file_path = file_path.to_str().unwrap().to_string();
+        (Storage::new(file_path.clone(), 4).unwrap(), file_path)
+    }
+
+    #[test]
+    fn test_sweep_expired() {
+        let tmp_dir = tempfile::tempdir().unwrap();
+        let (mut storage, file_path) = new_storage(&tmp_dir);
+        storage.write_block_expiring(0, &[1], Some(100)).unwrap();
+        storage.write_block(1, &[2]).unwrap();
+        let record_id = storage.write_record_expiring(&[3; 10], Some(200)).unwrap();
+        assert_eq!(storage.read_block_header(0).unwrap().expires_at, Some(100));
+        assert_eq!(storage.block_expiry(0).unwrap(), Some(100));
+        assert_eq!(storage.block_expiry(1).unwrap(), None);
+        assert_eq!(storage.block_expiry(9).unwrap(), None);
+        storage.close().unwrap();
+        // expiries are read from block headers on first sweep
+        let mut storage = Storage::open(file_path).unwrap();
+        assert!(storage.sweep_expired(99).unwrap().is_empty());
+        assert_eq!(storage.sweep_expired(100).unwrap(), vec![0]);
+        assert_eq!(storage.read_block(0).unwrap().1, Vec::<u8>::new());
+        assert_eq!(storage.read_record(record_id).unwrap(), vec![3; 10]);
+        // later writes and deletes are tracked in memory
+        storage.write_block_expiring(0, &[4], Some(150)).unwrap();
+        storage.write_block_expiring(1, &[5], None).unwrap();
+        assert_eq!(storage.sweep_expired(150).unwrap(), vec![0]);
+        let record_blocks = storage.record_blocks(record_id).unwrap();
+        assert_eq!(storage.sweep_expired(1000).unwrap(), record_blocks);
+        assert_eq!(storage.read_block(1).unwrap().1, vec![5]);
+        assert!(storage.sweep_expired(u64::MAX).unwrap().is_empty());
+    }
+    #[test]
+    fn test_overwrite_clears_expiry() {
+        let tmp_dir = tempfile::tempdir().unwrap();
+        let (mut storage, _) = new_storage(&tmp_dir);
+        assert!(storage.sweep_expired(0).unwrap().is_empty());
+        storage.write_block_expiring(0, &[1], Some(10)).unwrap();
+        storage.write_block(0, &[2]).unwrap();
+        storage.write_block_expiring(1, &[3], Some(10)).unwrap();
+        storage.delete_block(1, false).unwrap();
+        assert!(storage.sweep_expired(10).unwrap().is_empty());
+        assert_eq!(storage.read_block(0).unwrap().1, vec![2]);
+    }
+    #[test]
+    fn test_compaction_keeps_expiry() {
+        let tmp_dir = tempfile::tempdir().unwrap();
+        let (mut storage, _) = new_storage(&tmp_dir);
+        storage.write_block(0, &[1]).unwrap();
+        storage.write_block_expiring(3, &[2], Some(10)).unwrap();
+        storage.delete_block(0, false).unwrap();
+        let moves = storage.compact().unwrap();
+        let new_index = moves[&3];
+        assert_eq!(storage.sweep_expired(10).unwrap(), vec![new_index]);
+    }
+}
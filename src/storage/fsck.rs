@@ -0,0 +1,431 @@
+use super::checksum::crc32;
+use super::*;
+use std::fmt;
+
+/// Damage found in a storage file by Storage::fsck
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum FsckIssue {
+    /// Copy of storage header does not match its checksum, the other copy is used
+    DamagedStorageHeader { copy: u64 },
+    /// Allocation bitmap is cut short, file ends inside it
+    TruncatedBitmap { len: u64 },
+    /// File ends inside block header of its last block
+    TruncatedHeader { block_index: BlockIndex, len: u64 },
+    /// File ends inside data of its last block
+    TruncatedData {
+        block_index: BlockIndex,
+        data_size: u64,
+        len: u64,
+    },
+    /// Data size in block header exceeds block length
+    OversizedBlock {
+        block_index: BlockIndex,
+        data_size: u64,
+    },
+    /// Stored data does not match checksum in block header
+    ChecksumMismatch { block_index: BlockIndex },
+    /// Bit of allocation bitmap disagrees with block header, checked for clean files only
+    BitmapMismatch { block_index: BlockIndex },
+}
+
+impl FsckIssue {
+    /// Block the issue was found in, None for storage header and allocation bitmap
+    pub fn block_index(&self) -> Option<BlockIndex> {
+        match self {
+            FsckIssue::DamagedStorageHeader { .. } | FsckIssue::TruncatedBitmap { .. } => None,
+            FsckIssue::TruncatedHeader { block_index, .. }
+            | FsckIssue::TruncatedData { block_index, .. }
+            | FsckIssue::OversizedBlock { block_index, .. }
+            | FsckIssue::ChecksumMismatch { block_index }
+            | FsckIssue::BitmapMismatch { block_index } => Some(*block_index),
+        }
+    }
+}
+
+impl fmt::Display for FsckIssue {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            FsckIssue::DamagedStorageHeader { copy } => {
+                write!(f, "copy {} of storage header is damaged", copy)
+            }
+            FsckIssue::TruncatedBitmap { len } => {
+                write!(f, "allocation bitmap is truncated to {} bytes", len)
+            }
+            FsckIssue::TruncatedHeader { block_index, len } => write!(
+                f,
+                "block {}: block header is truncated to {} bytes",
+                block_index, len
+            ),
+            FsckIssue::TruncatedData {
+                block_index,
+                data_size,
+                len,
+            } => write!(
+                f,
+                "block {}: block data is truncated to {} of {} bytes",
+                block_index, len, data_size
+            ),
+            FsckIssue::OversizedBlock {
+                block_index,
+                data_size,
+            } => write!(
+                f,
+                "block {}: data size {} exceeds block length",
+                block_index, data_size
+            ),
+            FsckIssue::ChecksumMismatch { block_index } => {
+                write!(f, "block {}: checksum mismatch", block_index)
+            }
+            FsckIssue::BitmapMismatch { block_index } => write!(
+                f,
+                "block {}: allocation bitmap disagrees with block header",
+                block_index
+            ),
+        }
+    }
+}
+
+/// Result of Storage::fsck
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FsckReport {
+    pub block_len: u64,
+    /// Blocks checked, including a truncated last block
+    pub block_count: u64,
+    /// Damage found, in file order
+    pub issues: Vec<FsckIssue>,
+    /// True if issues were repaired
+    pub repaired: bool,
+}
+
+impl Storage {
+    // ... ... ... ... ... ... ... ... ... File Check ... ... ... ... ... ... ... ... ... ..
+
+    /// Check storage file for damage, without opening it as Storage
+    /// - storage header must be sane, a file whose every copy of the header is damaged
+    ///   can not be checked and fails like open
+    /// - checks both copies of storage header, a damaged copy is written over with the
+    ///   other copy on repair
+    /// - checks allocation bitmap, every block header against block length, checksum of
+    ///   every block, and a block cut short at end of file, e.g. by a full disk
+    /// - without repair the file is only read, even its dirty flag is left alone
+    /// - the file is locked like open locks it, shared to check and exclusive to repair,
+    ///   AlreadyLocked while a storage holds a lock that conflicts, see FileLock
+    /// - repair: a cut short last block is truncated away, blocks with oversized data or
+    ///   a checksum mismatch are zeroed, which frees them, then the allocation bitmap is
+    ///   rebuilt from block headers by opening the file
+    /// - NOTE: data of zeroed blocks is lost, records linking to them end with a broken
+    ///   link, see read_record
+    pub fn fsck(file_path: &str, repair: bool) -> Result<FsckReport, StorageError> {
+        let file_lock = if repair {
+            FileLock::Exclusive
+        } else {
+            FileLock::Shared
+        };
+        let file = lock::open_locked(
+            OpenOptions::new().read(true).write(repair),
+            file_path,
+            file_lock,
+        )?;
+        let (mut header, damaged_copies) = StorageHeader::read_copies(&file)?;
+        let mut issues: Vec<FsckIssue> = damaged_copies
+            .into_iter()
+            .map(|copy| FsckIssue::DamagedStorageHeader { copy })
+            .collect();
+        issues.extend(Storage::fsck_blocks(&file, &header)?);
+        let block_count = {
+            let blocks_len =
+                file_len(&file, &header)?.saturating_sub(blocks_offset(header.bitmap_capacity));
+            // - block_len was checked by read_from, block size fits in u64
+            blocks_len.div_ceil(header.block_len + BLOCK_HEADER_SIZE as u64)
+        };
+        let repaired = repair && !issues.is_empty();
+        if repaired {
+            Storage::repair_blocks(&file, &header, &issues)?;
+            // - dirty file is rescanned on open, which rewrites the allocation bitmap
+            // - next copy of the header is the damaged one, if any
+            header.flags |= STORAGE_FLAG_DIRTY;
+            header.sequence = header.sequence.saturating_add(1);
+            let header_offset = storage_header_offset(header.sequence);
+            positional::write_at(&file, header_offset, &header.to_bytes())
+                .map_err(StorageError::io("write storage header", None))?;
+            drop(file);
+            Storage::open(file_path.to_string())?.close()?;
+        }
+        Ok(FsckReport {
+            block_len: header.block_len,
+            block_count,
+            issues,
+            repaired,
+        })
+    }
+    /// Find damage in allocation bitmap and blocks of file
+    fn fsck_blocks(file: &File, header: &StorageHeader) -> Result<Vec<FsckIssue>, StorageError> {
+        let mut issues = Vec::new();
+        let file_len = file_len(file, header)?;
+        // - allocation bitmap, trusted by open for clean files only
+        let mut bitmap = vec![0u8; bitmap_len(header.bitmap_capacity) as usize];
+        let bitmap_read = positional::read_at(file, BITMAP_OFFSET, &mut bitmap)
+            .map_err(StorageError::io("read allocation bitmap", None))?;
+        if bitmap_read < bitmap.len() {
+            issues.push(FsckIssue::TruncatedBitmap {
+                len: bitmap_read as u64,
+            });
+        }
+        let check_bitmap = !header.is_dirty() && bitmap_read == bitmap.len();
+        let mut block_index = 0;
+        loop {
+            let block_offset =
+                match block_offset(header.bitmap_capacity, header.block_len, block_index) {
+                    Some(block_offset) if block_offset < file_len => block_offset,
+                    _ => break,
+                };
+            let file_left = file_len - block_offset;
+            // - block header
+            let mut block_header_bytes = [0u8; BLOCK_HEADER_SIZE];
+            let read_size = positional::read_at(file, block_offset, &mut block_header_bytes)
+                .map_err(StorageError::io("read block header", Some(block_index)))?;
+            if read_size < BLOCK_HEADER_SIZE {
+                issues.push(FsckIssue::TruncatedHeader {
+                    block_index,
+                    len: read_size as u64,
+                });
+                break;
+            }
+            let block_header = BlockHeader::from_bytes(&block_header_bytes);
+            let data_size = block_header.block_data_size;
+            if check_bitmap && block_index < header.bitmap_capacity as u64 {
+                let bit = bitmap[block_index as usize / 8] & (1 << (block_index % 8)) != 0;
+                if bit != (data_size > 0) {
+                    issues.push(FsckIssue::BitmapMismatch { block_index });
+                }
+            }
+            // - block data
+            if data_size > header.block_len {
+                issues.push(FsckIssue::OversizedBlock {
+                    block_index,
+                    data_size,
+                });
+            } else if file_left - (BLOCK_HEADER_SIZE as u64) < data_size {
+                issues.push(FsckIssue::TruncatedData {
+                    block_index,
+                    data_size,
+                    len: file_left - BLOCK_HEADER_SIZE as u64,
+                });
+            } else {
+                let mut block_data = vec![0u8; block_header.data_len(block_index)?];
+                positional::read_at(
+                    file,
+                    block_offset + BLOCK_HEADER_SIZE as u64,
+                    &mut block_data,
+                )
+                .map_err(StorageError::io("read block data", Some(block_index)))?;
+                if crc32(&block_data) != block_header.checksum {
+                    issues.push(FsckIssue::ChecksumMismatch { block_index });
+                }
+            }
+            block_index += 1;
+        }
+        Ok(issues)
+    }
+    /// Truncate away a cut short last block, zero blocks with bad data
+    /// - allocation bitmap issues are left to the rescan on open
+    fn repair_blocks(
+        file: &File,
+        header: &StorageHeader,
+        issues: &[FsckIssue],
+    ) -> Result<(), StorageError> {
+        let offset_of = |block_index: BlockIndex| {
+            block_offset(header.bitmap_capacity, header.block_len, block_index)
+                .ok_or(StorageError::BlockOutOfRange { block_index })
+        };
+        for issue in issues.iter() {
+            match issue {
+                FsckIssue::TruncatedHeader { block_index, .. }
+                | FsckIssue::TruncatedData { block_index, .. } => {
+                    file.set_len(offset_of(*block_index)?)
+                        .map_err(StorageError::io(
+                            "truncate storage file",
+                            Some(*block_index),
+                        ))?;
+                }
+                FsckIssue::OversizedBlock { block_index, .. }
+                | FsckIssue::ChecksumMismatch { block_index } => {
+                    // - zero header and data, a chunk at a time, up to end of file
+                    let block_offset = offset_of(*block_index)?;
+                    let block_end_offset =
+                        (block_offset + BLOCK_HEADER_SIZE as u64 + header.block_len)
+                            .min(file_len(file, header)?);
+                    let zeros = vec![0u8; ZERO_FILL_CHUNK_LEN as usize];
+                    let mut offset = block_offset;
+                    while offset < block_end_offset {
+                        let chunk_len = (block_end_offset - offset).min(ZERO_FILL_CHUNK_LEN);
+                        positional::write_at(file, offset, &zeros[..chunk_len as usize])
+                            .map_err(StorageError::io("zero fill block", Some(*block_index)))?;
+                        offset += chunk_len;
+                    }
+                }
+                FsckIssue::DamagedStorageHeader { .. }
+                | FsckIssue::TruncatedBitmap { .. }
+                | FsckIssue::BitmapMismatch { .. } => {}
+            }
+        }
+        file.sync_all()
+            .map_err(StorageError::io("sync storage file", None))
+    }
+}
+
+/// Length of file up to the end of its blocks, without the dedup table, if any
+fn file_len(file: &File, header: &StorageHeader) -> Result<u64, StorageError> {
+    if header.dedup_table != 0 {
+        return Ok(header.dedup_table);
+    }
+    file.metadata()
+        .map(|metadata| metadata.len())
+        .map_err(StorageError::io("read storage file metadata", None))
+}
+
+#[cfg(test)]
+mod unit_tests_fsck {
+    use super::*;
+
+    /// clean storage file with blocks 0 (free), 1 and 2
+    fn new_file(tmp_dir: &tempfile::TempDir) -> (String, Storage) {
+        let file_path = tmp_dir.path().join("fsck.hex");
+        let file_path = file_path.to_str().unwrap().to_string();
+        let mut storage = Storage::new_with_bitmap_capacity(file_path.clone(), 4, 16).unwrap();
+        storage.write_block(1, &[1, 2, 3]).unwrap();
+        storage.write_block(2, &[4, 5]).unwrap();
+        (file_path, storage)
+    }
+
+    #[test]
+    fn test_clean_file() {
+        let tmp_dir = tempfile::tempdir().unwrap();
+        let (file_path, storage) = new_file(&tmp_dir);
+        storage.close().unwrap();
+        let file_bytes = std::fs::read(&file_path).unwrap();
+        let report = Storage::fsck(&file_path, true).unwrap();
+        assert_eq!(report.block_count, 3);
+        assert!(report.issues.is_empty() && !report.repaired);
+        // nothing is written to a clean file
+        assert_eq!(std::fs::read(&file_path).unwrap(), file_bytes);
+        // - checks share the lock of a read-only storage, repairs do not
+        let read_only = StorageOptions::new(file_path.clone())
+            .read_only(true)
+            .open()
+            .unwrap();
+        assert!(Storage::fsck(&file_path, false).is_ok());
+        assert!(matches!(
+            Storage::fsck(&file_path, true),
+            Err(StorageError::AlreadyLocked)
+        ));
+        drop(read_only);
+        let storage = Storage::open(file_path.clone()).unwrap();
+        assert!(matches!(
+            Storage::fsck(&file_path, false),
+            Err(StorageError::AlreadyLocked)
+        ));
+        drop(storage);
+        assert!(matches!(
+            Storage::fsck(tmp_dir.path().join("missing").to_str().unwrap(), false),
+            Err(StorageError::Io { .. })
+        ));
+    }
+    #[test]
+    fn test_repair() {
+        let tmp_dir = tempfile::tempdir().unwrap();
+        let (file_path, storage) = new_file(&tmp_dir);
+        // - checksum mismatch in block 1, bitmap bit of block 0 set, block 2 cut short
+        let data_offset = storage.block_offset(1).unwrap() + BLOCK_HEADER_SIZE as u64;
+        storage
+            .write_file_at(data_offset, &[9], "corrupt", None)
+            .unwrap();
+        storage
+            .write_file_at(BITMAP_OFFSET, &[0b111], "corrupt", None)
+            .unwrap();
+        let file_len = storage.block_offset(2).unwrap() + BLOCK_HEADER_SIZE as u64 + 1;
+        storage.close().unwrap();
+        std::fs::OpenOptions::new()
+            .write(true)
+            .open(&file_path)
+            .unwrap()
+            .set_len(file_len)
+            .unwrap();
+        let report = Storage::fsck(&file_path, false).unwrap();
+        assert_eq!(
+            report.issues,
+            vec![
+                FsckIssue::BitmapMismatch { block_index: 0 },
+                FsckIssue::ChecksumMismatch { block_index: 1 },
+                FsckIssue::TruncatedData {
+                    block_index: 2,
+                    data_size: 2,
+                    len: 1
+                },
+            ]
+        );
+        assert!(!report.repaired);
+        assert!(Storage::fsck(&file_path, true).unwrap().repaired);
+        assert!(Storage::fsck(&file_path, false).unwrap().issues.is_empty());
+        // repaired blocks are gone, file opens clean
+        let mut storage = Storage::open(file_path).unwrap();
+        assert_eq!(storage.iter_blocks().count(), 0);
+        assert_eq!(storage.end_block_count, 2);
+    }
+    #[test]
+    fn test_damaged_storage_header() {
+        let tmp_dir = tempfile::tempdir().unwrap();
+        let (file_path, storage) = new_file(&tmp_dir);
+        storage.close().unwrap();
+        let mut file_bytes = std::fs::read(&file_path).unwrap();
+        file_bytes[STORAGE_HEADER_SIZE + 8] ^= 0xff;
+        std::fs::write(&file_path, &file_bytes).unwrap();
+        let report = Storage::fsck(&file_path, false).unwrap();
+        assert_eq!(
+            report.issues,
+            vec![FsckIssue::DamagedStorageHeader { copy: 1 }]
+        );
+        assert!(Storage::fsck(&file_path, true).unwrap().repaired);
+        assert!(Storage::fsck(&file_path, false).unwrap().issues.is_empty());
+        let mut storage = Storage::open(file_path).unwrap();
+        assert_eq!(storage.iter_blocks().count(), 2);
+    }
+    #[test]
+    fn test_truncated_header() {
+        let tmp_dir = tempfile::tempdir().unwrap();
+        let (file_path, storage) = new_file(&tmp_dir);
+        storage
+            .write_file_at(
+                storage.block_offset(1).unwrap(),
+                &9u64.to_le_bytes(),
+                "corrupt",
+                None,
+            )
+            .unwrap();
+        let file_len = storage.block_offset(2).unwrap() + 5;
+        // - left dirty, like after a crash
+        storage.crash();
+        std::fs::OpenOptions::new()
+            .write(true)
+            .open(&file_path)
+            .unwrap()
+            .set_len(file_len)
+            .unwrap();
+        // dirty files do not trust the bitmap, it is not checked
+        let report = Storage::fsck(&file_path, true).unwrap();
+        assert_eq!(
+            report.issues,
+            vec![
+                FsckIssue::OversizedBlock {
+                    block_index: 1,
+                    data_size: 9
+                },
+                FsckIssue::TruncatedHeader {
+                    block_index: 2,
+                    len: 5
+                },
+            ]
+        );
+        assert!(Storage::fsck(&file_path, false).unwrap().issues.is_empty());
+    }
+}
@@ -0,0 +1,129 @@
+use super::*;
+
+/// Value of the generation counter of a storage file, stored in every block header
+/// - every block header written by a write or a delete gets the next generation, so a
+///   block changed since its generation was read is at a higher one
+/// - 0 for blocks never written
+pub type Generation = u64;
+
+impl Storage {
+    // ... ... ... ... ... ... ... ... ... Generations ... ... ... ... ... ... ... ... ... .
+
+    /// Last generation given to a block of this storage file, 0 if none was
+    pub fn generation(&self) -> Generation {
+        self.header.generation
+    }
+    /// Generation of block, reading its block header only
+    /// - a free block is at the generation of its delete, blocks past end of file at 0
+    pub fn block_generation(
+        &mut self,
+        block_index: BlockIndex,
+    ) -> Result<Generation, StorageError> {
+        if !self.block_exists(block_index) {
+            return Ok(0);
+        }
+        Ok(self.read_block_header(block_index)?.generation)
+    }
+    /// Read block data, like read_block, with generation of the block
+    /// - pass the generation to write_block_if, to write the block only if it did not
+    ///   change in between
+    pub fn read_block_versioned(
+        &mut self,
+        block_index: BlockIndex,
+    ) -> Result<(Generation, Vec<u8>), StorageError> {
+        let generation = self.block_generation(block_index)?;
+        let (_, block_data) = self.read_block(block_index)?;
+        Ok((generation, block_data))
+    }
+    /// Write data to block, like write_block, if block is still at expected_generation
+    /// - Conflict if block was written or deleted since, nothing is written then
+    /// - expected_generation 0 writes a block that was never written
+    /// - returns: generation of written block
+    pub fn write_block_if(
+        &mut self,
+        block_index: BlockIndex,
+        expected_generation: Generation,
+        data: &[u8],
+    ) -> Result<Generation, StorageError> {
+        let generation = self.block_generation(block_index)?;
+        if generation != expected_generation {
+            return Err(StorageError::Conflict {
+                block_index,
+                expected: expected_generation,
+                actual: generation,
+            });
+        }
+        self.write_block(block_index, data)?;
+        Ok(self.header.generation)
+    }
+    /// Give out next generation, to a block header about to be written
+    pub(super) fn next_generation(&mut self) -> Generation {
+        self.header.generation = self.header.generation.saturating_add(1);
+        self.header.generation
+    }
+    /// Write last generation given out to storage header
+    pub(super) fn write_generation(&mut self) -> Result<(), StorageError> {
+        self.set_storage_header()?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod unit_tests_generation {
+    use super::*;
+
+    fn file_path(tmp_dir: &tempfile::TempDir) -> String {
+        let file_path = tmp_dir.path().join("generation.hex");
+        file_path.to_str().unwrap().to_string()
+    }
+
+    #[test]
+    fn test_write_block_if() {
+        let tmp_dir = tempfile::tempdir().unwrap();
+        let mut storage = Storage::new(file_path(&tmp_dir), 4).unwrap();
+        assert_eq!(storage.generation(), 0);
+        storage.write_block(0, &[1]).unwrap();
+        storage.write_block(1, &[2]).unwrap();
+        assert_eq!(storage.read_block_versioned(0).unwrap(), (1, vec![1]));
+        assert_eq!(storage.write_block_if(0, 1, &[3]).unwrap(), 3);
+        // - stale generation fails, block is not written
+        assert!(matches!(
+            storage.write_block_if(0, 1, &[4]),
+            Err(StorageError::Conflict {
+                block_index: 0,
+                expected: 1,
+                actual: 3,
+            })
+        ));
+        assert_eq!(storage.read_block_versioned(0).unwrap(), (3, vec![3]));
+        // - delete bumps the generation of the free block
+        storage.delete_block(1, false).unwrap();
+        assert_eq!(storage.read_block_versioned(1).unwrap(), (4, vec![]));
+        assert!(storage.write_block_if(1, 2, &[5]).is_err());
+        // - block never written is at generation 0
+        assert_eq!(storage.block_generation(6).unwrap(), 0);
+        assert_eq!(storage.write_block_if(6, 0, &[6]).unwrap(), 5);
+        assert_eq!(storage.block_generation(5).unwrap(), 0);
+    }
+    #[test]
+    fn test_generation_survives_reopen() {
+        let tmp_dir = tempfile::tempdir().unwrap();
+        let mut storage = Storage::new(file_path(&tmp_dir), 4).unwrap();
+        for block_index in 0..3 {
+            storage.write_block(block_index, &[1]).unwrap();
+        }
+        storage.set_auto_trim(true);
+        storage.delete_block(2, false).unwrap();
+        // - file is dirty while open, generation is taken from block headers, and from
+        //   the storage header for the trimmed block
+        let options = StorageOptions::new(file_path(&tmp_dir)).lock(false);
+        let dirty_storage = options.open().unwrap();
+        assert_eq!(dirty_storage.generation(), 4);
+        drop(dirty_storage);
+        storage.close().unwrap();
+        let mut storage = Storage::open(file_path(&tmp_dir)).unwrap();
+        assert_eq!(storage.generation(), 4);
+        storage.write_block(2, &[2]).unwrap();
+        assert_eq!(storage.block_generation(2).unwrap(), 5);
+    }
+}
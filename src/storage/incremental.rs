@@ -0,0 +1,283 @@
+use super::*;
+use std::io::{BufReader, BufWriter, Read, Write};
+
+/// First bytes of an incremental backup
+const INCREMENTAL_MAGIC: &[u8; 4] = b"SE1I";
+/// Incremental backup header: magic, block_len, since, generation, block_count and
+/// entry count, u64 little endian after the magic
+const INCREMENTAL_HEADER_SIZE: usize = 44;
+/// Entry of a block freed after since, block index and this kind
+const ENTRY_DELETED: u8 = 0;
+/// Entry of a block written after since, followed by block flags u32, next block u64
+/// (index + 1, 0 for None), expires_at u64 (0 for None), tag u16, data length u32 and
+/// block data as stored
+const ENTRY_WRITTEN: u8 = 1;
+
+const MALFORMED_INCREMENTAL: StorageError = StorageError::Corruption {
+    block_index: None,
+    reason: "incremental backup is malformed",
+};
+
+fn read_bytes<const N: usize>(reader: &mut impl Read) -> Result<[u8; N], StorageError> {
+    let mut bytes = [0u8; N];
+    reader
+        .read_exact(&mut bytes)
+        .map_err(|error| match error.kind() {
+            std::io::ErrorKind::UnexpectedEof => MALFORMED_INCREMENTAL,
+            _ => StorageError::Io {
+                operation: "read incremental backup",
+                block_index: None,
+                source: error,
+            },
+        })?;
+    Ok(bytes)
+}
+
+fn read_u64(reader: &mut impl Read) -> Result<u64, StorageError> {
+    read_bytes::<8>(reader).map(u64::from_le_bytes)
+}
+
+/// Header of an incremental backup, see Storage::backup_incremental
+pub(super) struct IncrementalHeader {
+    /// Generation of the backup this one builds on
+    pub(super) since: Generation,
+    /// Generation of the storage when this backup was taken
+    pub(super) generation: Generation,
+    /// Blocks of the storage when this backup was taken, blocks from it on are free
+    pub(super) block_count: u64,
+    pub(super) entry_count: u64,
+}
+
+impl IncrementalHeader {
+    /// Read header, InvalidBlockLength if the backup is not of block_len
+    pub(super) fn read(reader: &mut impl Read, block_len: u64) -> Result<Self, StorageError> {
+        if &read_bytes::<4>(reader)? != INCREMENTAL_MAGIC {
+            return Err(MALFORMED_INCREMENTAL);
+        }
+        let backup_block_len = read_u64(reader)?;
+        if backup_block_len != block_len {
+            return Err(StorageError::InvalidBlockLength {
+                block_len: backup_block_len,
+            });
+        }
+        Ok(IncrementalHeader {
+            since: read_u64(reader)?,
+            generation: read_u64(reader)?,
+            block_count: read_u64(reader)?,
+            entry_count: read_u64(reader)?,
+        })
+    }
+}
+
+/// Change of one block in an incremental backup
+pub(super) enum IncrementalEntry {
+    Deleted(BlockIndex),
+    Written {
+        block_index: BlockIndex,
+        block_flags: u32,
+        next_block: Option<BlockIndex>,
+        expires_at: Option<u64>,
+        tag: BlockTag,
+        /// Block data as stored
+        block_data: Vec<u8>,
+    },
+}
+
+impl IncrementalEntry {
+    /// Read next entry, of a backup with blocks of block_len
+    pub(super) fn read(reader: &mut impl Read, block_len: u64) -> Result<Self, StorageError> {
+        let block_index = read_u64(reader)?;
+        match read_bytes::<1>(reader)?[0] {
+            ENTRY_DELETED => Ok(IncrementalEntry::Deleted(block_index)),
+            ENTRY_WRITTEN => {
+                let block_flags = u32::from_le_bytes(read_bytes(reader)?);
+                let next_block = read_u64(reader)?.checked_sub(1);
+                let expires_at = Some(read_u64(reader)?).filter(|at| *at != 0);
+                let tag = u16::from_le_bytes(read_bytes(reader)?);
+                let data_len = u32::from_le_bytes(read_bytes(reader)?);
+                if u64::from(data_len) > block_len {
+                    return Err(MALFORMED_INCREMENTAL);
+                }
+                let mut block_data = vec![0u8; data_len as usize];
+                reader
+                    .read_exact(&mut block_data)
+                    .map_err(|_| MALFORMED_INCREMENTAL)?;
+                Ok(IncrementalEntry::Written {
+                    block_index,
+                    block_flags,
+                    next_block,
+                    expires_at,
+                    tag,
+                    block_data,
+                })
+            }
+            _ => Err(MALFORMED_INCREMENTAL),
+        }
+    }
+}
+
+impl Storage {
+    // ... ... ... ... ... ... ... ... ... Incremental backup ... ... ... ... ... ... ... ...
+
+    /// Write blocks changed after generation since to writer, see apply_incremental
+    /// - changed blocks are found by block generation, see Storage::changed_blocks_since,
+    ///   written blocks are copied as stored, freed blocks are recorded as deletions
+    /// - since is the generation of the backup it builds on: generation() of the storage
+    ///   when a full backup was taken (see backup_to), or the generation returned by the
+    ///   incremental backup before
+    /// - block data is copied as stored, so it needs the same block transforms to be
+    ///   applied, and the same key if encrypted
+    /// - returns: generation of this backup, to pass as since to the next one
+    pub fn backup_incremental(
+        &mut self,
+        since: Generation,
+        writer: impl Write,
+    ) -> Result<Generation, StorageError> {
+        let changed_blocks = self.changed_blocks_since(since)?;
+        let generation = self.generation();
+        let mut writer = BufWriter::new(writer);
+        let mut header = Vec::with_capacity(INCREMENTAL_HEADER_SIZE);
+        header.extend_from_slice(INCREMENTAL_MAGIC);
+        for field in [
+            self.header.block_len,
+            since,
+            generation,
+            self.end_block_count,
+            changed_blocks.len() as u64,
+        ] {
+            header.extend_from_slice(&field.to_le_bytes());
+        }
+        writer
+            .write_all(&header)
+            .map_err(StorageError::io("write incremental backup", None))?;
+        for block_index in changed_blocks {
+            let mut entry = block_index.to_le_bytes().to_vec();
+            match self.current_block(block_index)? {
+                Some(content) => {
+                    entry.push(ENTRY_WRITTEN);
+                    entry.extend_from_slice(&content.block_flags.to_le_bytes());
+                    let next_block = content.next_block.map_or(0, |next| next + 1);
+                    entry.extend_from_slice(&next_block.to_le_bytes());
+                    entry.extend_from_slice(&content.expires_at.unwrap_or(0).to_le_bytes());
+                    entry.extend_from_slice(&content.tag.to_le_bytes());
+                    entry.extend_from_slice(&(content.block_data.len() as u32).to_le_bytes());
+                    entry.extend_from_slice(&content.block_data);
+                }
+                None => entry.push(ENTRY_DELETED),
+            }
+            writer.write_all(&entry).map_err(StorageError::io(
+                "write incremental backup",
+                Some(block_index),
+            ))?;
+        }
+        writer
+            .flush()
+            .map_err(StorageError::io("write incremental backup", None))?;
+        Ok(generation)
+    }
+    /// Apply incremental backup read from reader, see backup_incremental
+    /// - storage must hold the backup the incremental backup builds on, e.g. after
+    ///   restore_from of the full backup and apply_incremental of every incremental
+    ///   backup before it, in order
+    /// - backup must have the same block_len, else InvalidBlockLength of backup is returned
+    /// - blocks are written and deleted one by one like any other write, blocks past the
+    ///   end of the backed up storage are freed and the free tail is truncated
+    /// - a backup that failed to apply can be applied again, every entry is the whole
+    ///   block as it was
+    /// - returns: generation of the backup, the since of the next incremental backup
+    pub fn apply_incremental(&mut self, reader: impl Read) -> Result<Generation, StorageError> {
+        let mut reader = BufReader::new(reader);
+        let header = IncrementalHeader::read(&mut reader, self.header.block_len)?;
+        for _ in 0..header.entry_count {
+            match IncrementalEntry::read(&mut reader, self.header.block_len)? {
+                IncrementalEntry::Deleted(block_index) => {
+                    self.delete_block(block_index, false)?;
+                }
+                IncrementalEntry::Written {
+                    block_index,
+                    block_flags,
+                    next_block,
+                    expires_at,
+                    tag,
+                    block_data,
+                } => {
+                    let data = self.decode_block_data(block_index, block_data, block_flags)?;
+                    self.write_linked_block(block_index, &data, next_block, expires_at, tag)?;
+                }
+            }
+        }
+        let (block_count, generation) = (header.block_count, header.generation);
+        self.free_blocks_from(block_count)?;
+        if self.end_block_count > block_count {
+            self.trim_tail()?;
+        }
+        Ok(generation)
+    }
+}
+
+#[cfg(test)]
+mod unit_tests_incremental {
+    use super::*;
+
+    fn file_path(tmp_dir: &tempfile::TempDir, name: &str) -> String {
+        tmp_dir.path().join(name).to_str().unwrap().to_string()
+    }
+
+    #[test]
+    fn test_incremental_backup() {
+        let tmp_dir = tempfile::tempdir().unwrap();
+        let mut storage = Storage::new(file_path(&tmp_dir, "storage.hex"), 4).unwrap();
+        let record_id = storage.write_record(&[1; 6]).unwrap();
+        for block_index in 2..6 {
+            storage.write_block(block_index, &[2]).unwrap();
+        }
+        let since = storage.generation();
+        let full_path = file_path(&tmp_dir, "full.hex");
+        storage.backup_to(full_path.clone()).unwrap();
+        // - only changed blocks and deletions after since
+        storage.write_block_tagged(2, &[3, 3], 7).unwrap();
+        storage.delete_block(3, true).unwrap();
+        storage.delete_block(5, false).unwrap();
+        storage.trim_tail().unwrap();
+        let mut incremental = Vec::new();
+        let generation = storage.backup_incremental(since, &mut incremental).unwrap();
+        assert_eq!(generation, storage.generation());
+        // - block 2 written, block 3 deleted, block 5 beyond the trimmed end
+        assert_eq!(incremental.len(), INCREMENTAL_HEADER_SIZE + (35 + 2) + 9);
+        let mut restored = Storage::new(file_path(&tmp_dir, "restored.hex"), 4).unwrap();
+        restored.restore_from(full_path).unwrap();
+        assert_eq!(
+            restored.apply_incremental(&incremental[..]).unwrap(),
+            generation
+        );
+        assert_eq!(restored.read_record(record_id).unwrap(), vec![1; 6]);
+        assert_eq!(restored.read_block(2).unwrap().1, vec![3, 3]);
+        assert_eq!(restored.block_tag(2).unwrap(), 7);
+        assert!(restored.read_block(3).unwrap().1.is_empty());
+        assert_eq!(restored.read_block(4).unwrap().1, vec![2]);
+        assert_eq!(restored.end_block_count, 5);
+        // - nothing changed since the last incremental backup
+        let mut empty = Vec::new();
+        storage.backup_incremental(generation, &mut empty).unwrap();
+        assert_eq!(empty.len(), INCREMENTAL_HEADER_SIZE);
+    }
+    #[test]
+    fn test_apply_rejects_bad_incremental() {
+        let tmp_dir = tempfile::tempdir().unwrap();
+        let mut storage = Storage::new(file_path(&tmp_dir, "storage.hex"), 4).unwrap();
+        storage.write_block(0, &[1]).unwrap();
+        let mut incremental = Vec::new();
+        storage.backup_incremental(0, &mut incremental).unwrap();
+        let mut other = Storage::new(file_path(&tmp_dir, "other.hex"), 8).unwrap();
+        assert!(matches!(
+            other.apply_incremental(&incremental[..]),
+            Err(StorageError::InvalidBlockLength { block_len: 4 })
+        ));
+        let truncated = &incremental[..incremental.len() - 1];
+        assert!(matches!(
+            storage.apply_incremental(truncated),
+            Err(StorageError::Corruption { .. })
+        ));
+        assert!(storage.apply_incremental(&b"SE1X"[..]).is_err());
+    }
+}
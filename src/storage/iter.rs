@@ -0,0 +1,169 @@
+use super::*;
+
+/// Block header of a live block, see Storage::iter_block_headers
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BlockInfo {
+    pub block_index: BlockIndex,
+    /// Size of stored data, after block transforms
+    pub data_size: u64,
+    /// CRC-32 checksum of stored data
+    pub checksum: u32,
+    /// Next block of a multi block record, None for single blocks and last block of a record
+    pub next_block: Option<BlockIndex>,
+    /// Expiry in seconds since UNIX epoch, None if block never expires
+    pub expires_at: Option<u64>,
+    /// Tag set by the writer of the block, 0 for untagged blocks
+    pub tag: BlockTag,
+    /// Generation of last write of the block, see Storage::read_block_versioned
+    pub generation: Generation,
+}
+
+/// Iterator over data of live blocks, in block index order
+/// - see Storage::iter_blocks
+pub struct Blocks<'a> {
+    storage: &'a mut Storage,
+    next_block: BlockIndex,
+}
+
+impl Iterator for Blocks<'_> {
+    type Item = Result<(BlockIndex, Vec<u8>), StorageError>;
+    fn next(&mut self) -> Option<Self::Item> {
+        let block_index = self.storage.next_live_block(self.next_block)?;
+        self.next_block = block_index.saturating_add(1);
+        let result = self.storage.read_block(block_index);
+        Some(result.map(|(_, block_data)| (block_index, block_data)))
+    }
+}
+
+/// Iterator over block headers of live blocks, in block index order
+/// - see Storage::iter_block_headers
+pub struct BlockHeaders<'a> {
+    storage: &'a mut Storage,
+    next_block: BlockIndex,
+}
+
+impl Iterator for BlockHeaders<'_> {
+    type Item = Result<BlockInfo, StorageError>;
+    fn next(&mut self) -> Option<Self::Item> {
+        let block_index = self.storage.next_live_block(self.next_block)?;
+        self.next_block = block_index.saturating_add(1);
+        let result = self.storage.read_block_header(block_index);
+        Some(result.map(|block_header| BlockInfo {
+            block_index,
+            data_size: block_header.block_data_size,
+            checksum: block_header.checksum,
+            next_block: block_header.next_block,
+            expires_at: block_header.expires_at,
+            tag: block_header.tag,
+            generation: block_header.generation,
+        }))
+    }
+}
+
+/// Iterator over data of live blocks with a tag, in block index order
+/// - see Storage::iter_blocks_with_tag
+pub struct TaggedBlocks<'a> {
+    storage: &'a mut Storage,
+    next_block: BlockIndex,
+    tag: BlockTag,
+}
+
+impl Iterator for TaggedBlocks<'_> {
+    type Item = Result<(BlockIndex, Vec<u8>), StorageError>;
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            let block_index = self.storage.next_live_block(self.next_block)?;
+            self.next_block = block_index.saturating_add(1);
+            match self.storage.read_block_header(block_index) {
+                Ok(block_header) if block_header.tag != self.tag => continue,
+                Ok(_) => {}
+                Err(error) => return Some(Err(error)),
+            }
+            let result = self.storage.read_block(block_index);
+            return Some(result.map(|(_, block_data)| (block_index, block_data)));
+        }
+    }
+}
+
+impl Storage {
+    // ... ... ... ... ... ... ... ... ... Block Iterators ... ... ... ... ... ... ... ... ...
+
+    /// Iterate over (block_index, block_data) of every used block, in block index order
+    /// - free blocks are skipped, iteration ends at the last block of the file
+    /// - each block is read like read_block: checksum verified, transforms reversed
+    /// - a block that fails to read yields its error, iteration goes on with next block
+    pub fn iter_blocks(&mut self) -> Blocks<'_> {
+        self.iter_blocks_from(0)
+    }
+    /// Iterate over used blocks at or after from_block, like iter_blocks
+    pub fn iter_blocks_from(&mut self, from_block: BlockIndex) -> Blocks<'_> {
+        Blocks {
+            storage: self,
+            next_block: from_block,
+        }
+    }
+    /// Iterate over block headers of every used block, in block index order
+    /// - reads block headers only, block data is not read or verified
+    pub fn iter_block_headers(&mut self) -> BlockHeaders<'_> {
+        BlockHeaders {
+            storage: self,
+            next_block: 0,
+        }
+    }
+    /// Iterate over (block_index, block_data) of every used block with tag, like iter_blocks
+    /// - block header is read first, data of blocks with another tag is not read
+    pub fn iter_blocks_with_tag(&mut self, tag: BlockTag) -> TaggedBlocks<'_> {
+        TaggedBlocks {
+            storage: self,
+            next_block: 0,
+            tag,
+        }
+    }
+    /// First used block at or after from_block, None past the last block
+    fn next_live_block(&self, from_block: BlockIndex) -> Option<BlockIndex> {
+        (from_block..self.end_block_count)
+            .find(|block_index| !self.free_blocks.contains(block_index))
+    }
+}
+
+#[cfg(test)]
+mod unit_tests_iter {
+    use super::*;
+
+    fn new_storage(tmp_dir: &tempfile::TempDir) -> Storage {
+        let file_path = tmp_dir.path().join("iter.hex");
+        Storage::new(file_path.to_str().unwrap().to_string(), 4).unwrap()
+    }
+
+    #[test]
+    fn test_iter_blocks() {
+        let tmp_dir = tempfile::tempdir().unwrap();
+        let mut storage = new_storage(&tmp_dir);
+        assert!(storage.iter_blocks().next().is_none());
+        for block_index in 0..5 {
+            storage
+                .write_block(block_index, &[block_index as u8 + 1])
+                .unwrap();
+        }
+        storage.delete_block(1, false).unwrap();
+        storage.delete_block(4, true).unwrap();
+        let blocks: Vec<(BlockIndex, Vec<u8>)> =
+            storage.iter_blocks().map(Result::unwrap).collect();
+        assert_eq!(blocks, vec![(0, vec![1]), (2, vec![3]), (3, vec![4])]);
+    }
+    #[test]
+    fn test_iter_block_headers() {
+        let tmp_dir = tempfile::tempdir().unwrap();
+        let mut storage = new_storage(&tmp_dir);
+        storage.write_block(0, &[1]).unwrap();
+        let record_id = storage.write_record(&[2; 6]).unwrap();
+        let headers: Vec<BlockInfo> = storage.iter_block_headers().map(Result::unwrap).collect();
+        assert_eq!(headers.len(), 3);
+        assert_eq!(headers[0].data_size, 1);
+        assert_eq!(headers[0].next_block, None);
+        assert_eq!(headers[1].block_index, record_id);
+        assert_eq!(headers[1].data_size, 4);
+        assert_eq!(headers[1].next_block, Some(headers[2].block_index));
+        assert_eq!(headers[2].checksum, checksum::crc32(&[2; 2]));
+    }
+}
@@ -0,0 +1,377 @@
+//! Byte level layout of storage file
+//!
+//! Format version 13, all integers are little endian and there is no padding.
+//! B is the allocation bitmap length, ceil(bitmap_capacity / 8) bytes.
+//!
+//! | offset                              | size      | field                           |
+//! |-------------------------------------|-----------|---------------------------------|
+//! | 0                                   | 4         | storage header: magic "SE1F"    |
+//! | 4                                   | 2         | storage header: format version  |
+//! | 6                                   | 8         | storage header: block_len       |
+//! | 14                                  | 4         | storage header: bitmap_capacity |
+//! | 18                                  | 4         | storage header: flags           |
+//! | 22                                  | 1         | storage header: compression     |
+//! | 23                                  | 8         | storage header: generation      |
+//! | 31                                  | 8         | storage header: sequence        |
+//! | 39                                  | 4         | storage header: pipeline        |
+//! | 43                                  | 8         | storage header: dedup table     |
+//! | 51                                  | 4         | storage header: checksum        |
+//! | 55                                  | 55        | storage header copy 1           |
+//! | 110                                 | B         | allocation bitmap               |
+//! | 110 + B + i * (40 + block_len)      | 8         | block i header: data size       |
+//! | 110 + B + i * (40 + block_len) + 8  | 4         | block i header: checksum        |
+//! | 110 + B + i * (40 + block_len) + 12 | 8         | block i header: next block      |
+//! | 110 + B + i * (40 + block_len) + 20 | 2         | block i header: flags           |
+//! | 110 + B + i * (40 + block_len) + 22 | 2         | block i header: tag             |
+//! | 110 + B + i * (40 + block_len) + 24 | 8         | block i header: expires at      |
+//! | 110 + B + i * (40 + block_len) + 32 | 8         | block i header: generation      |
+//! | 110 + B + i * (40 + block_len) + 40 | block_len | block i data                    |
+//! | dedup table                         | 8         | dedup table: entry count n      |
+//! | dedup table + 8 + j * 16            | 8         | dedup table entry j: block      |
+//! | dedup table + 8 + j * 16 + 8        | 8         | dedup table entry j: hash       |
+//! | dedup table + 8 + n * 16            | 4         | dedup table: checksum           |
+//!
+//! The storage header is stored twice, copy 0 at offset 0 and copy 1 at offset 55,
+//! with the same fields. An update of the header increments the sequence and writes
+//! copy sequence % 2, so the copy read on open is never written over; open takes the
+//! copy with the highest sequence whose checksum matches, so a header write cut short
+//! by a crash falls back to the header before it. Header checksum is CRC-32 (IEEE) of
+//! the 51 header bytes before it. A new file is created with both copies.
+//! Bit i % 8 (least significant first) of bitmap byte i / 8 is set when block i
+//! holds data. Blocks at or beyond bitmap_capacity are not tracked by the bitmap.
+//! Checksum is CRC-32 (IEEE) of the stored data size bytes of block data, so a
+//! free block (data size and checksum 0) is always valid.
+//! Next block links blocks of a multi block record, it stores index of the next
+//! block + 1, and 0 for the last (or only) block of a record.
+//! Compression is the codec new blocks are written with, 0 none, 1 LZ4, 2 Zstandard.
+//! Block flags tell how block data was compressed before block transforms: data of a
+//! compressed block starts with the uncompressed length (u64), followed by the
+//! output of the codec.
+//! Storage flag ENCRYPTED is set once encryption is enabled, every block written from
+//! then on is encrypted with AES-256-GCM after block transforms. Data of an encrypted
+//! block (block flag ENCRYPTED) is the nonce (12 bytes), the tag (16 bytes) and the
+//! ciphertext.
+//! Expires at is the time in seconds since the UNIX epoch from which the block may be
+//! deleted by an expiry sweep, 0 for blocks that never expire.
+//! Pipeline is CRC-32 (IEEE) of the names of the block transforms block data goes
+//! through between compression and encryption, each name followed by a 0 byte, in
+//! pipeline order; 0 for a pipeline without them. Block data is only read and written
+//! through the pipeline recorded, see Storage::push_transform.
+//! Dedup table is the offset of the dedup table, where the blocks end, 0 for a file
+//! without one. The table is written after the last block when the file is closed,
+//! and truncated away when it is opened for writing, so a file has one only while it
+//! is clean. Its entries are the block index and content hash (64 bit FNV-1a of block
+//! data) of every used block, in block order, followed by CRC-32 (IEEE) of the count
+//! and the entries; see Storage::write_dedup.
+//! Tag is set by the writer of the block to tell kinds of blocks apart, 0 for untagged
+//! blocks, it is not interpreted by the storage.
+//! Generation of a block is the value of a counter of the storage file, incremented
+//! for every block header written by a write or a delete, 0 for blocks never written.
+//! The generation in the storage header is the last one given out, it is written when
+//! the file is closed; a file opened dirty takes the highest generation of its block
+//! headers instead.
+//! Version 1 had only block_len in the storage header and no bitmap.
+//! Version 2 had no checksum in the block header.
+//! Version 3 had no next block in the block header.
+//! Version 4 had no magic and no format version in the storage header, so files of
+//! version 4 and older are rejected by open as not being storage files.
+//! Version 5 stored block_len, data size and next block as u32, limiting files to
+//! 2^32 blocks of at most 4GiB each.
+//! Version 6 had no compression in the storage header and no flags in the block header.
+//! Version 7 had no expires at in the block header.
+//! Version 8 stored block flags as u32 and had no tag in the block header.
+//! Version 9 had no generation in the storage header and the block header.
+//! Version 10 had a single storage header of 31 bytes, without sequence and checksum.
+//! Version 11 had no pipeline in the storage header, which was 43 bytes.
+//! Version 12 had no dedup table, its storage header was 47 bytes.
+//! Files of every older version can be rewritten in this one, see Storage::upgrade.
+//!
+//! A SegmentedStorage directory holds a manifest next to its segment files: magic
+//! "SE1S" (4 bytes), manifest version (u16), block_len (u64) and blocks per segment
+//! (u64). Each segment is a storage file of the layout above.
+//!
+//! Sizes and offsets are spelled out here instead of derived from struct layout
+//! (`std::mem::size_of`), so adding fields or padding to in memory structs can
+//! never change the file format.
+use super::util::*;
+use super::StorageError;
+use std::convert::TryFrom;
+
+// ... ... ... ... ... ... ... ... Storage Header ... ... ... ... ... ... ... ... ..
+
+/// Size of storage header in bytes, of each of its copies
+pub const STORAGE_HEADER_SIZE: usize = 55;
+/// Number of copies of storage header at start of file, written in turn
+pub const STORAGE_HEADER_COPIES: u64 = 2;
+/// First bytes of every storage file
+pub const STORAGE_MAGIC: [u8; 4] = *b"SE1F";
+/// Format version written to new storage files, the only version that can be opened
+pub const FORMAT_VERSION: u16 = 13;
+/// Offset of magic (4 bytes) within storage header
+pub const STORAGE_HEADER_MAGIC_OFFSET: usize = 0;
+/// Offset of format version (u16) within storage header
+pub const STORAGE_HEADER_VERSION_OFFSET: usize = 4;
+/// Offset of block_len (u64) within storage header
+pub const STORAGE_HEADER_BLOCK_LEN_OFFSET: usize = 6;
+/// Offset of bitmap_capacity (u32), number of blocks tracked by bitmap, within storage header
+pub const STORAGE_HEADER_BITMAP_CAPACITY_OFFSET: usize = 14;
+/// Offset of flags (u32) within storage header
+pub const STORAGE_HEADER_FLAGS_OFFSET: usize = 18;
+/// Flag set while storage file is open for writing
+/// - bitmap can not be trusted if it is set when opening the file
+pub const STORAGE_FLAG_DIRTY: u32 = 1;
+/// Flag set when blocks are written encrypted, see Storage::enable_encryption
+pub const STORAGE_FLAG_ENCRYPTED: u32 = 1 << 1;
+/// Flag set when blocks can only be appended, see Storage::enable_append_only
+pub const STORAGE_FLAG_APPEND_ONLY: u32 = 1 << 2;
+/// Offset of compression (u8), codec of new blocks, within storage header
+pub const STORAGE_HEADER_COMPRESSION_OFFSET: usize = 22;
+/// Offset of generation (u64), last generation given to a block, within storage header
+pub const STORAGE_HEADER_GENERATION_OFFSET: usize = 23;
+/// Offset of sequence (u64), incremented on every header write, within storage header
+pub const STORAGE_HEADER_SEQUENCE_OFFSET: usize = 31;
+/// Offset of pipeline (u32), CRC-32 of names of block transforms, within storage header
+pub const STORAGE_HEADER_PIPELINE_OFFSET: usize = 39;
+/// Offset of dedup table (u64), offset of dedup table in file, within storage header
+pub const STORAGE_HEADER_DEDUP_TABLE_OFFSET: usize = 43;
+/// Offset of checksum (u32), CRC-32 of the header bytes before it, within storage header
+pub const STORAGE_HEADER_CHECKSUM_OFFSET: usize = 51;
+
+/// Offset of copy of storage header written with sequence, from start of file
+pub fn storage_header_offset(sequence: u64) -> u64 {
+    (sequence % STORAGE_HEADER_COPIES) * STORAGE_HEADER_SIZE as u64
+}
+
+// ... ... ... ... ... ... ... ... Allocation Bitmap ... ... ... ... ... ... ... ..
+
+/// Offset of allocation bitmap from start of file, after every copy of storage header
+pub const BITMAP_OFFSET: u64 = STORAGE_HEADER_SIZE as u64 * STORAGE_HEADER_COPIES;
+
+/// Length in bytes of allocation bitmap tracking bitmap_capacity blocks
+pub fn bitmap_len(bitmap_capacity: u32) -> u64 {
+    (bitmap_capacity as u64).div_ceil(8)
+}
+
+// ... ... ... ... ... ... ... ... Dedup Table ... ... ... ... ... ... ... ... ... ..
+
+/// Size of entry count (u64) at start of dedup table
+pub const DEDUP_TABLE_COUNT_SIZE: usize = 8;
+/// Size of dedup table entry: block index (u64) and content hash (u64)
+pub const DEDUP_TABLE_ENTRY_SIZE: usize = 16;
+/// Size of checksum (u32), CRC-32 of count and entries, at end of dedup table
+pub const DEDUP_TABLE_CHECKSUM_SIZE: usize = 4;
+
+// ... ... ... ... ... ... ... ... Block Header ... ... ... ... ... ... ... ... ... .
+
+/// Size of block header in bytes
+pub const BLOCK_HEADER_SIZE: usize = 40;
+/// Largest block_len, so block header and block data of a block fit in u64 bytes
+pub const MAX_BLOCK_LEN: u64 = u64::MAX - BLOCK_HEADER_SIZE as u64;
+/// Most zero bytes written at once by a hard delete
+pub const ZERO_FILL_CHUNK_LEN: u64 = 64 * 1024;
+/// Offset of block_data_size (u64) within block header
+pub const BLOCK_HEADER_DATA_SIZE_OFFSET: usize = 0;
+/// Offset of checksum (u32), CRC-32 of stored block data, within block header
+pub const BLOCK_HEADER_CHECKSUM_OFFSET: usize = 8;
+/// Offset of next block (u64), index + 1 of next block of record or 0, within block header
+pub const BLOCK_HEADER_NEXT_BLOCK_OFFSET: usize = 12;
+/// Offset of block flags (u16) within block header
+pub const BLOCK_HEADER_FLAGS_OFFSET: usize = 20;
+/// Offset of tag (u16), kind of block set by its writer, within block header
+pub const BLOCK_HEADER_TAG_OFFSET: usize = 22;
+/// Offset of expires at (u64), expiry in seconds since UNIX epoch or 0, within block header
+pub const BLOCK_HEADER_EXPIRES_AT_OFFSET: usize = 24;
+/// Offset of generation (u64), bumped on every write and delete, within block header
+pub const BLOCK_HEADER_GENERATION_OFFSET: usize = 32;
+/// Block flag set when block data was compressed with LZ4
+pub const BLOCK_FLAG_LZ4: u32 = 1;
+/// Block flag set when block data was compressed with Zstandard
+pub const BLOCK_FLAG_ZSTD: u32 = 1 << 1;
+/// Block flag set when block data was encrypted
+pub const BLOCK_FLAG_ENCRYPTED: u32 = 1 << 2;
+/// Size of uncompressed length (u64) at start of compressed block data
+pub const COMPRESSED_LEN_SIZE: usize = 8;
+/// Size of AES-256 key
+pub const ENCRYPTION_KEY_SIZE: usize = 32;
+/// Size of AES-GCM nonce at start of encrypted block data
+pub const ENCRYPTION_NONCE_SIZE: usize = 12;
+/// Size of AES-GCM tag following the nonce
+pub const ENCRYPTION_TAG_SIZE: usize = 16;
+/// Bytes encryption adds to block data
+pub const ENCRYPTION_OVERHEAD: usize = ENCRYPTION_NONCE_SIZE + ENCRYPTION_TAG_SIZE;
+
+// ... ... ... ... ... ... ... ... Segment Manifest ... ... ... ... ... ... ... ... .
+
+/// Size of manifest of a SegmentedStorage directory in bytes
+pub const MANIFEST_SIZE: usize = 22;
+/// First bytes of every segment manifest
+pub const MANIFEST_MAGIC: [u8; 4] = *b"SE1S";
+/// Manifest version written to new manifests, the only version that can be opened
+pub const MANIFEST_VERSION: u16 = 1;
+/// Offset of manifest version (u16) within manifest
+pub const MANIFEST_VERSION_OFFSET: usize = 4;
+/// Offset of block_len (u64) of every segment within manifest
+pub const MANIFEST_BLOCK_LEN_OFFSET: usize = 6;
+/// Offset of blocks per segment (u64) within manifest
+pub const MANIFEST_BLOCKS_PER_SEGMENT_OFFSET: usize = 14;
+
+// ... ... ... ... ... ... ... ... ... Helpers ... ... ... ... ... ... ... ... ... ..
+
+/// Offset of first block from start of file
+pub fn blocks_offset(bitmap_capacity: u32) -> u64 {
+    BITMAP_OFFSET + bitmap_len(bitmap_capacity)
+}
+
+/// Offset of block header of given block from start of file
+/// - returns: None if end of block does not fit in u64, so offsets within the block
+///   can not overflow
+pub fn block_offset(bitmap_capacity: u32, block_len: u64, block_index: u64) -> Option<u64> {
+    let block_size = block_size(block_len)?;
+    let block_offset = block_size
+        .checked_mul(block_index)?
+        .checked_add(blocks_offset(bitmap_capacity))?;
+    block_offset.checked_add(block_size)?;
+    Some(block_offset)
+}
+
+/// Size of block header and block data of each block
+/// - returns: None if size does not fit in u64
+pub fn block_size(block_len: u64) -> Option<u64> {
+    block_len.checked_add(BLOCK_HEADER_SIZE as u64)
+}
+
+/// Length as u32, for lengths stored in 4 bytes
+/// - TooLarge if len exceeds u32::MAX
+pub fn checked_u32(len: usize) -> Result<u32, StorageError> {
+    u32::try_from(len).map_err(|_| StorageError::TooLarge {
+        len: saturating_u64(len),
+        max: u64::from(u32::MAX),
+    })
+}
+
+/// Length as usize, for lengths read as u64
+/// - TooLarge if len exceeds usize::MAX, on platforms where usize is narrower than u64
+pub fn checked_usize(len: u64) -> Result<usize, StorageError> {
+    usize::try_from(len).map_err(|_| StorageError::TooLarge {
+        len,
+        max: saturating_u64(usize::MAX),
+    })
+}
+
+/// Length as u64, saturating on platforms where usize is wider than u64
+pub fn saturating_u64(len: usize) -> u64 {
+    u64::try_from(len).unwrap_or(u64::MAX)
+}
+
+/// Write u32 as little endian at offset in bytes
+pub fn put_u32(bytes: &mut [u8], offset: usize, n: u32) {
+    bytes[offset..offset + 4].copy_from_slice(&u32_to_bytes(n));
+}
+
+/// Read little endian u32 at offset in bytes
+pub fn get_u32(bytes: &[u8], offset: usize) -> u32 {
+    bytes_to_u32(&bytes[offset..offset + 4])
+}
+
+/// Write u64 as little endian at offset in bytes
+pub fn put_u64(bytes: &mut [u8], offset: usize, n: u64) {
+    bytes[offset..offset + 8].copy_from_slice(&n.to_le_bytes());
+}
+
+/// Read little endian u64 at offset in bytes
+pub fn get_u64(bytes: &[u8], offset: usize) -> u64 {
+    let mut n_bytes = [0u8; 8];
+    n_bytes.copy_from_slice(&bytes[offset..offset + 8]);
+    u64::from_le_bytes(n_bytes)
+}
+
+/// Write u16 as little endian at offset in bytes
+pub fn put_u16(bytes: &mut [u8], offset: usize, n: u16) {
+    bytes[offset..offset + 2].copy_from_slice(&n.to_le_bytes());
+}
+
+/// Read little endian u16 at offset in bytes
+pub fn get_u16(bytes: &[u8], offset: usize) -> u16 {
+    u16::from_le_bytes([bytes[offset], bytes[offset + 1]])
+}
+
+#[cfg(test)]
+mod unit_tests_layout {
+    use super::*;
+    #[test]
+    fn test_header_sizes() {
+        assert_eq!(STORAGE_HEADER_SIZE, 55);
+        assert_eq!(BITMAP_OFFSET, 110);
+        assert_eq!(BLOCK_HEADER_SIZE, 40);
+    }
+    #[test]
+    fn test_bitmap_len() {
+        assert_eq!(bitmap_len(0), 0);
+        assert_eq!(bitmap_len(1), 1);
+        assert_eq!(bitmap_len(8), 1);
+        assert_eq!(bitmap_len(9), 2);
+        assert_eq!(bitmap_len(32768), 4096);
+    }
+    #[test]
+    fn test_block_offset() {
+        assert_eq!(block_offset(0, 8, 0), Some(110));
+        assert_eq!(block_offset(16, 8, 0), Some(112)); // 110 + 2
+        assert_eq!(block_offset(16, 8, 1), Some(160)); // 110 + 2 + (40 + 8) * 1
+        assert_eq!(block_offset(16, 8, 3), Some(256)); // 110 + 2 + (40 + 8) * 3
+        let past_4gib = 110 + 2 * (40 + u32::MAX as u64);
+        assert_eq!(block_offset(0, u32::MAX as u64, 2), Some(past_4gib));
+        // block index and block length past u32
+        let beyond_u32 = u32::MAX as u64 + 1;
+        assert_eq!(block_offset(0, 8, beyond_u32), Some(110 + 48 * beyond_u32));
+        assert_eq!(block_offset(0, beyond_u32, 1), Some(110 + 40 + beyond_u32));
+        // overflow
+        assert_eq!(block_offset(0, u32::MAX as u64, u32::MAX as u64), None);
+        assert_eq!(block_offset(0, u64::MAX, 0), None);
+        assert_eq!(block_offset(0, 8, u64::MAX), None);
+        // offset of last block fitting in u64, its end does not
+        let last_index = (u64::MAX - 110) / 48;
+        assert_eq!(
+            block_offset(0, 8, last_index - 1),
+            Some(110 + 48 * (last_index - 1))
+        );
+        assert_eq!(block_offset(0, 8, last_index), None);
+    }
+    #[test]
+    fn test_checked_u32() {
+        // - every length around the u32 boundary, on both sides
+        let boundary = u32::MAX as usize;
+        for len in boundary - 2..=boundary + 2 {
+            match checked_u32(len) {
+                Ok(checked) => assert_eq!(checked as usize, len),
+                Err(StorageError::TooLarge {
+                    len: too_large,
+                    max,
+                }) => {
+                    assert!(len > boundary);
+                    assert_eq!((too_large, max), (len as u64, u32::MAX as u64));
+                }
+                Err(error) => panic!("unexpected error {}", error),
+            }
+        }
+        assert_eq!(saturating_u64(usize::MAX), usize::MAX as u64);
+    }
+    #[test]
+    fn test_put_get_u32() {
+        let mut bytes = [0u8; 6];
+        put_u32(&mut bytes, 2, 0x12345678);
+        assert_eq!(bytes, [0, 0, 0x78, 0x56, 0x34, 0x12]);
+        assert_eq!(get_u32(&bytes, 2), 0x12345678);
+        put_u16(&mut bytes, 1, 0x1234);
+        assert_eq!(bytes[1..3], [0x34, 0x12]);
+        assert_eq!(get_u16(&bytes, 1), 0x1234);
+    }
+    #[test]
+    fn test_put_get_u64() {
+        let mut bytes = [0u8; 10];
+        put_u64(&mut bytes, 1, 0x1_2345_6789);
+        assert_eq!(bytes, [0, 0x89, 0x67, 0x45, 0x23, 0x01, 0, 0, 0, 0]);
+        assert_eq!(get_u64(&bytes, 1), 0x1_2345_6789);
+        put_u64(&mut bytes, 2, u64::MAX);
+        assert_eq!(get_u64(&bytes, 2), u64::MAX);
+    }
+}
@@ -0,0 +1,150 @@
+//! Lock of a storage file, so two storage objects never write it at once
+//! - a storage opened for writing holds an exclusive lock, a read-only storage a shared
+//!   lock; a lock that conflicts fails with AlreadyLocked instead of waiting
+//! - flock on Unix, through File::try_lock; locks are advisory, only storage objects
+//!   check them, and released when the handle holding the lock is closed
+//! - locks of one file conflict within a process too, open a storage file once and
+//!   share it, e.g. through an Engine
+//! - Windows locks (LockFileEx) are mandatory, they would also fail reads and writes
+//!   through the other handles of the storage; the handle holding the lock is opened
+//!   with a share mode that denies writers instead, so a conflict fails on open
+//! - no lock elsewhere, e.g. WASI, which has no file locks
+use super::StorageError;
+use std::fs::{File, OpenOptions};
+use std::io;
+
+/// Lock a storage object holds of its storage file
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FileLock {
+    /// Storage opened for writing, conflicts with every other lock
+    Exclusive,
+    /// Read-only storage, conflicts with exclusive locks only
+    Shared,
+    /// No lock, see StorageOptions::lock
+    Unlocked,
+}
+
+/// Open file at file_path with options, and lock it with file_lock
+/// - returns: AlreadyLocked if another handle holds a lock that conflicts
+pub fn open_locked(
+    options: &mut OpenOptions,
+    file_path: &str,
+    file_lock: FileLock,
+) -> Result<File, StorageError> {
+    if file_lock == FileLock::Unlocked {
+        return options
+            .open(file_path)
+            .map_err(StorageError::io("open storage file", None));
+    }
+    deny_writers(options);
+    let file = options.open(file_path).map_err(|error| {
+        if is_sharing_violation(&error) {
+            StorageError::AlreadyLocked
+        } else {
+            StorageError::io("open storage file", None)(error)
+        }
+    })?;
+    lock(&file, file_lock == FileLock::Shared)?;
+    Ok(file)
+}
+
+#[cfg(unix)]
+fn lock(file: &File, shared: bool) -> Result<(), StorageError> {
+    let locked = if shared {
+        file.try_lock_shared()
+    } else {
+        file.try_lock()
+    };
+    match locked {
+        Ok(()) => Ok(()),
+        Err(std::fs::TryLockError::WouldBlock) => Err(StorageError::AlreadyLocked),
+        // - file system without locks, e.g. some network file systems
+        Err(std::fs::TryLockError::Error(error)) if error.kind() == io::ErrorKind::Unsupported => {
+            Ok(())
+        }
+        Err(std::fs::TryLockError::Error(error)) => {
+            Err(StorageError::io("lock storage file", None)(error))
+        }
+    }
+}
+
+#[cfg(not(unix))]
+fn lock(_file: &File, _shared: bool) -> Result<(), StorageError> {
+    Ok(())
+}
+
+#[cfg(windows)]
+fn deny_writers(options: &mut OpenOptions) {
+    use std::os::windows::fs::OpenOptionsExt;
+    // FILE_SHARE_READ
+    options.share_mode(0x1);
+}
+
+#[cfg(not(windows))]
+fn deny_writers(_options: &mut OpenOptions) {}
+
+#[cfg(windows)]
+fn is_sharing_violation(error: &io::Error) -> bool {
+    // ERROR_SHARING_VIOLATION
+    error.raw_os_error() == Some(32)
+}
+
+#[cfg(not(windows))]
+fn is_sharing_violation(_error: &io::Error) -> bool {
+    false
+}
+
+#[cfg(test)]
+impl super::Storage {
+    /// Leave storage file as after a crash: dirty flag stays set, lock is released
+    pub(super) fn crash(self) {
+        let _ = self.file_writer.unlock();
+        std::mem::forget(self);
+    }
+}
+
+#[cfg(test)]
+mod unit_tests_lock {
+    use super::super::*;
+
+    fn file_path(tmp_dir: &tempfile::TempDir) -> String {
+        let file_path = tmp_dir.path().join("lock.hex");
+        file_path.to_str().unwrap().to_string()
+    }
+
+    #[test]
+    fn test_lock() {
+        let tmp_dir = tempfile::tempdir().unwrap();
+        let mut storage = Storage::new(file_path(&tmp_dir), 8).unwrap();
+        storage.write_block(0, &[1]).unwrap();
+        let read_only = StorageOptions::new(file_path(&tmp_dir)).read_only(true);
+        assert!(matches!(
+            Storage::open(file_path(&tmp_dir)),
+            Err(StorageError::AlreadyLocked)
+        ));
+        assert!(matches!(
+            read_only.clone().open(),
+            Err(StorageError::AlreadyLocked)
+        ));
+        // - a locked file is not truncated
+        assert!(matches!(
+            Storage::new(file_path(&tmp_dir), 8),
+            Err(StorageError::AlreadyLocked)
+        ));
+        assert_eq!(storage.read_block(0).unwrap().1, vec![1]);
+        let unlocked = StorageOptions::new(file_path(&tmp_dir)).lock(false);
+        assert_eq!(unlocked.open().unwrap().read_block(0).unwrap().1, vec![1]);
+        // - lock is released on close, read-only storages share a lock
+        storage.close().unwrap();
+        let mut reader = read_only.clone().open().unwrap();
+        let mut other_reader = read_only.open().unwrap();
+        assert!(matches!(
+            Storage::open(file_path(&tmp_dir)),
+            Err(StorageError::AlreadyLocked)
+        ));
+        assert_eq!(reader.read_block(0).unwrap().1, vec![1]);
+        assert_eq!(other_reader.read_block(0).unwrap().1, vec![1]);
+        drop((reader, other_reader));
+        Storage::open(file_path(&tmp_dir)).unwrap();
+    }
+}
@@ -0,0 +1,95 @@
+use super::*;
+
+/// BlockStore keeping block data in memory, e.g. for tests
+/// - also the block store of wasm32-unknown-unknown, which has no file system
+/// - behaves like Storage for block operations, freed blocks are reused lowest first
+/// - block data is stored as given, without block headers, checksums or encoding
+/// - nothing is persisted, data is gone once dropped
+#[derive(Debug, Clone)]
+pub struct MemStorage {
+    block_len: u64,
+    /// Data of every used block
+    blocks: BTreeMap<BlockIndex, Vec<u8>>,
+    /// Number of blocks up to the last block ever written (used or free)
+    end_block_count: u64,
+}
+
+impl MemStorage {
+    /// Create new empty in memory storage
+    /// - block_len must be within 1..=MAX_BLOCK_LEN
+    pub fn new(block_len: u64) -> Result<MemStorage, StorageError> {
+        if block_len == 0 || block_len > MAX_BLOCK_LEN {
+            return Err(StorageError::InvalidBlockLength { block_len });
+        }
+        Ok(MemStorage {
+            block_len,
+            blocks: BTreeMap::new(),
+            end_block_count: 0,
+        })
+    }
+}
+
+impl BlockStore for MemStorage {
+    fn block_len(&self) -> u64 {
+        self.block_len
+    }
+    fn read_block(&mut self, block_index: BlockIndex) -> Result<Vec<u8>, StorageError> {
+        Ok(self.blocks.get(&block_index).cloned().unwrap_or_default())
+    }
+    fn write_block(&mut self, block_index: BlockIndex, data: &[u8]) -> Result<(), StorageError> {
+        if data.len() as u64 > self.block_len {
+            return Err(StorageError::BlockTooLarge {
+                block_index,
+                data_len: data.len(),
+                block_len: self.block_len,
+            });
+        }
+        // - u64::MAX is not addressable, like past the end offsets of a storage file
+        if block_index == u64::MAX {
+            return Err(StorageError::BlockOutOfRange { block_index });
+        }
+        if data.is_empty() {
+            self.blocks.remove(&block_index);
+        } else {
+            self.blocks.insert(block_index, data.to_vec());
+        }
+        self.end_block_count = self.end_block_count.max(block_index + 1);
+        Ok(())
+    }
+    fn delete_block(
+        &mut self,
+        block_index: BlockIndex,
+        _hard_delete: bool,
+    ) -> Result<(), StorageError> {
+        // - removed data is dropped, there is no copy left to zero fill
+        self.blocks.remove(&block_index);
+        Ok(())
+    }
+    fn allocate(&self, block_count: usize) -> Result<Vec<BlockIndex>, StorageError> {
+        let mut block_indexes = Vec::with_capacity(block_count);
+        let mut block_index = 0;
+        while block_indexes.len() < block_count {
+            if block_index == u64::MAX {
+                return Err(StorageError::BlockOutOfRange { block_index });
+            }
+            // - lowest free blocks first, then blocks past the end
+            if block_index >= self.end_block_count || !self.blocks.contains_key(&block_index) {
+                block_indexes.push(block_index);
+            }
+            block_index += 1;
+        }
+        Ok(block_indexes)
+    }
+    fn stats(&self) -> StoreStats {
+        let used_blocks = self.blocks.len() as u64;
+        StoreStats {
+            block_len: self.block_len,
+            block_count: self.end_block_count,
+            used_blocks,
+            free_blocks: self.end_block_count - used_blocks,
+            preallocated_blocks: 0,
+            bytes: 0,
+            max_bytes: None,
+        }
+    }
+}
@@ -1,28 +1,283 @@
 mod error;
-use error::Error;
+pub use error::StorageError;
+mod layout;
 mod util;
-use util::*;
+use layout::*;
+pub(crate) use layout::{checked_u32, saturating_u64};
+pub use layout::{FORMAT_VERSION, MAX_BLOCK_LEN};
+mod consistency;
+pub use consistency::{ConsistencyHook, Divergence};
+mod observer;
+pub use observer::BlockObserver;
+mod transform;
+pub use transform::{BlockFlags, BlockTransform};
+mod compression;
+pub use compression::Compression;
+mod crypto;
+pub use crypto::{EncryptionKey, KeyProvider, StaticKey};
+mod bitmap;
+pub use bitmap::DEFAULT_BITMAP_CAPACITY;
+mod dedup;
+mod expiry;
+mod segmented;
+pub use segmented::SegmentedStorage;
+mod fsck;
+pub use fsck::{FsckIssue, FsckReport};
+mod allocation;
+mod append_only;
+mod upgrade;
+pub use allocation::AllocationStrategy;
+mod options;
+mod pin;
+pub use options::StorageOptions;
+mod store;
+pub use store::{BlockStore, StoreStats};
+mod memory;
+pub use memory::MemStorage;
+mod checksum;
+pub use checksum::VerifyStep;
+mod record;
+use record::broken_chain_error;
+pub use record::RecordId;
+mod batch;
+mod cache;
+pub use cache::{CachePolicy, CacheStats};
+mod backend;
+#[cfg(feature = "direct-io")]
+pub use backend::DirectBackend;
+#[cfg(feature = "mmap")]
+pub use backend::MmapBackend;
+pub use backend::{Backend, FileBackend};
+mod durability;
+pub use durability::DurabilityMode;
+mod compact;
+pub use compact::{CompactionFilter, FilterDecision};
+mod snapshot;
+pub use snapshot::Snapshot;
+mod backup;
+pub use backup::Backup;
+mod lock;
+use lock::FileLock;
+mod positional;
+mod shared;
+pub(crate) use shared::{SharedReader, StoredChunk};
+mod reader;
+pub use reader::BlockReader;
+mod writer;
+pub use writer::BlockWriter;
+mod iter;
+mod patch;
+mod range;
+mod vectored;
+pub use iter::{BlockHeaders, BlockInfo, Blocks, TaggedBlocks};
+mod tag;
+pub use tag::BlockTag;
+mod generation;
+pub use generation::Generation;
+mod mvcc;
+pub use mvcc::ReadView;
+mod replica;
+pub use replica::BlockChange;
+mod archive;
+mod clone;
+mod incremental;
+
+/// Index of a block in storage file, counted from 0
+/// - u64 on every platform, so files can hold more blocks than usize can count
+pub type BlockIndex = u64;
 
 //  ... ... ... ... ... ... ... ... Storage Header ... ... ... ... ... ... ... ... ... ..
 
 /// Main Header for storage file
-/// - Stores constant capacity of each block as 4 bytes unsied integer as little endian
+/// - Stores magic and format version, checked on open
+/// - Stores constant capacity of each block as 8 bytes unsigned integer as little endian
+/// - Stores number of blocks tracked by allocation bitmap
+/// - Stores flags (dirty, encrypted)
+/// - Stores compression of new blocks, see Compression
+/// - Stores last generation given to a block, see Storage::generation
+/// - Stores checksum of block transforms data goes through, see Storage::push_transform
+/// - Stores where the dedup table starts in a clean file, see Storage::write_dedup
+/// - Stored twice, with a sequence telling the newer copy and a checksum telling a
+///   damaged one, see Storage::set_storage_header
+/// - Byte layout is defined in layout module
 struct StorageHeader {
-    block_len: u32,
+    magic: [u8; 4],
+    version: u16,
+    block_len: u64,
+    bitmap_capacity: u32,
+    flags: u32,
+    compression: u8,
+    /// Written on close, the file may hold higher generations while it is dirty
+    generation: Generation,
+    /// Header writes so far, the copy read last is copy sequence % 2
+    sequence: u64,
+    /// CRC-32 of names of block transforms, 0 without any, see transform::Pipeline
+    pipeline: u32,
+    /// Offset of dedup table, where the blocks end, 0 without one, see dedup
+    dedup_table: u64,
 }
 
-const STORAGE_HEADER_SIZE: usize = std::mem::size_of::<StorageHeader>();
-
 impl StorageHeader {
-    fn new(block_len: u32) -> Self {
-        StorageHeader { block_len }
+    fn new(block_len: u64, bitmap_capacity: u32) -> Self {
+        StorageHeader {
+            magic: STORAGE_MAGIC,
+            version: FORMAT_VERSION,
+            block_len,
+            bitmap_capacity,
+            flags: 0,
+            compression: Compression::None.to_byte(),
+            generation: 0,
+            sequence: 0,
+            pipeline: 0,
+            dedup_table: 0,
+        }
     }
     fn from_bytes(bytes: &[u8; STORAGE_HEADER_SIZE]) -> StorageHeader {
-        let block_len = bytes_to_u32(bytes);
-        StorageHeader { block_len }
+        let mut magic = [0u8; 4];
+        magic.copy_from_slice(&bytes[STORAGE_HEADER_MAGIC_OFFSET..STORAGE_HEADER_MAGIC_OFFSET + 4]);
+        let version = get_u16(bytes, STORAGE_HEADER_VERSION_OFFSET);
+        let block_len = get_u64(bytes, STORAGE_HEADER_BLOCK_LEN_OFFSET);
+        let bitmap_capacity = get_u32(bytes, STORAGE_HEADER_BITMAP_CAPACITY_OFFSET);
+        let flags = get_u32(bytes, STORAGE_HEADER_FLAGS_OFFSET);
+        let compression = bytes[STORAGE_HEADER_COMPRESSION_OFFSET];
+        let generation = get_u64(bytes, STORAGE_HEADER_GENERATION_OFFSET);
+        let sequence = get_u64(bytes, STORAGE_HEADER_SEQUENCE_OFFSET);
+        let pipeline = get_u32(bytes, STORAGE_HEADER_PIPELINE_OFFSET);
+        let dedup_table = get_u64(bytes, STORAGE_HEADER_DEDUP_TABLE_OFFSET);
+        StorageHeader {
+            magic,
+            version,
+            block_len,
+            bitmap_capacity,
+            flags,
+            compression,
+            generation,
+            sequence,
+            pipeline,
+            dedup_table,
+        }
     }
     fn to_bytes(&self) -> [u8; STORAGE_HEADER_SIZE] {
-        u32_to_bytes(self.block_len)
+        let mut bytes = [0u8; STORAGE_HEADER_SIZE];
+        bytes[STORAGE_HEADER_MAGIC_OFFSET..STORAGE_HEADER_MAGIC_OFFSET + 4]
+            .copy_from_slice(&self.magic);
+        put_u16(&mut bytes, STORAGE_HEADER_VERSION_OFFSET, self.version);
+        put_u64(&mut bytes, STORAGE_HEADER_BLOCK_LEN_OFFSET, self.block_len);
+        put_u32(
+            &mut bytes,
+            STORAGE_HEADER_BITMAP_CAPACITY_OFFSET,
+            self.bitmap_capacity,
+        );
+        put_u32(&mut bytes, STORAGE_HEADER_FLAGS_OFFSET, self.flags);
+        bytes[STORAGE_HEADER_COMPRESSION_OFFSET] = self.compression;
+        put_u64(
+            &mut bytes,
+            STORAGE_HEADER_GENERATION_OFFSET,
+            self.generation,
+        );
+        put_u64(&mut bytes, STORAGE_HEADER_SEQUENCE_OFFSET, self.sequence);
+        put_u32(&mut bytes, STORAGE_HEADER_PIPELINE_OFFSET, self.pipeline);
+        put_u64(
+            &mut bytes,
+            STORAGE_HEADER_DEDUP_TABLE_OFFSET,
+            self.dedup_table,
+        );
+        let checksum = checksum::crc32(&bytes[..STORAGE_HEADER_CHECKSUM_OFFSET]);
+        put_u32(&mut bytes, STORAGE_HEADER_CHECKSUM_OFFSET, checksum);
+        bytes
+    }
+    /// Read storage header at beginning of file
+    /// - rejects files of other format versions, see check_format
+    fn read_from(file: &File) -> Result<StorageHeader, StorageError> {
+        let (storage_header, _) = StorageHeader::read_copies(file)?;
+        Ok(storage_header)
+    }
+    /// Read every copy of storage header, see layout
+    /// - takes the copy with the highest sequence whose checksum matches, so a header
+    ///   write cut short falls back to the copy before it
+    /// - returns: storage header, and copies that are damaged
+    fn read_copies(file: &File) -> Result<(StorageHeader, Vec<u64>), StorageError> {
+        let mut copies_bytes = [0u8; BITMAP_OFFSET as usize];
+        let read_size = positional::read_at(file, 0, &mut copies_bytes)
+            .map_err(StorageError::io("read storage header", None))?;
+        let mut storage_header: Option<StorageHeader> = None;
+        let mut damaged_copies = Vec::new();
+        let mut has_magic = false;
+        let mut unsupported_version = None;
+        for (copy, header_bytes) in copies_bytes.chunks_exact(STORAGE_HEADER_SIZE).enumerate() {
+            let copy_len = read_size.saturating_sub(copy * STORAGE_HEADER_SIZE);
+            let magic = &header_bytes[STORAGE_HEADER_MAGIC_OFFSET..STORAGE_HEADER_MAGIC_OFFSET + 4];
+            has_magic |= copy_len >= STORAGE_MAGIC.len() && magic == STORAGE_MAGIC;
+            let checksum = checksum::crc32(&header_bytes[..STORAGE_HEADER_CHECKSUM_OFFSET]);
+            if copy_len < STORAGE_HEADER_SIZE
+                || get_u32(header_bytes, STORAGE_HEADER_CHECKSUM_OFFSET) != checksum
+            {
+                // - a file of another format version has no copies or checksums
+                let version = get_u16(header_bytes, STORAGE_HEADER_VERSION_OFFSET);
+                if copy == 0 && magic == STORAGE_MAGIC && version != FORMAT_VERSION {
+                    unsupported_version = Some(version);
+                }
+                damaged_copies.push(copy as u64);
+                continue;
+            }
+            let mut copy_bytes = [0u8; STORAGE_HEADER_SIZE];
+            copy_bytes.copy_from_slice(header_bytes);
+            let copy_header = StorageHeader::from_bytes(&copy_bytes);
+            if storage_header
+                .as_ref()
+                .is_none_or(|newest| copy_header.sequence > newest.sequence)
+            {
+                storage_header = Some(copy_header);
+            }
+        }
+        let storage_header = match storage_header {
+            Some(storage_header) => storage_header,
+            None if !has_magic => return Err(StorageError::NotStorageFile),
+            None => match unsupported_version {
+                Some(version) => return Err(StorageError::UnsupportedVersion { version }),
+                None if read_size < STORAGE_HEADER_SIZE => {
+                    return Err(StorageError::Corruption {
+                        block_index: None,
+                        reason: "storage header is truncated",
+                    })
+                }
+                None => {
+                    return Err(StorageError::Corruption {
+                        block_index: None,
+                        reason: "every copy of storage header is damaged",
+                    })
+                }
+            },
+        };
+        storage_header.check_format()?;
+        Ok((storage_header, damaged_copies))
+    }
+    fn is_dirty(&self) -> bool {
+        self.flags & STORAGE_FLAG_DIRTY != 0
+    }
+    /// Check header was written by this format version of a storage file
+    fn check_format(&self) -> Result<(), StorageError> {
+        if self.magic != STORAGE_MAGIC {
+            return Err(StorageError::NotStorageFile);
+        }
+        if self.version != FORMAT_VERSION {
+            return Err(StorageError::UnsupportedVersion {
+                version: self.version,
+            });
+        }
+        if self.block_len == 0 || self.block_len > MAX_BLOCK_LEN {
+            return Err(StorageError::Corruption {
+                block_index: None,
+                reason: "block length in storage header is out of range",
+            });
+        }
+        if Compression::from_byte(self.compression).is_none() {
+            return Err(StorageError::Corruption {
+                block_index: None,
+                reason: "unknown compression in storage header",
+            });
+        }
+        Ok(())
     }
 }
 
@@ -31,25 +286,144 @@ mod unit_tests_storage_header {
     use super::*;
     #[test]
     fn test_storage_header_to_bytes() {
-        let storage_header = StorageHeader::new(16777472);
+        let storage_header = StorageHeader::new(16777472, 16);
+        let bytes = storage_header.to_bytes();
+        assert_eq!(
+            bytes,
+            [
+                b'S', b'E', b'1', b'F', 13, 0, 0, 1, 0, 1, 0, 0, 0, 0, 16, 0, 0, 0, 0, 0, 0, 0, 0,
+                0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0,
+                140, 79, 244, 177
+            ]
+        );
+        // - sequence is covered by the checksum
+        let mut storage_header = storage_header;
+        storage_header.sequence = 1;
+        let bytes = storage_header.to_bytes();
+        assert_eq!(bytes[31..39], [1, 0, 0, 0, 0, 0, 0, 0]);
+        assert_ne!(bytes[51..], [140, 79, 244, 177]);
+        // - and so is pipeline
+        storage_header.pipeline = 0x01020304;
+        let bytes = storage_header.to_bytes();
+        assert_eq!(bytes[39..43], [4, 3, 2, 1]);
+        assert_eq!(StorageHeader::from_bytes(&bytes).pipeline, 0x01020304);
+        storage_header.dedup_table = 0x0102;
         let bytes = storage_header.to_bytes();
-        assert_eq!(bytes, [0, 1, 0, 1]);
+        assert_eq!(bytes[43..51], [2, 1, 0, 0, 0, 0, 0, 0]);
+        assert_eq!(StorageHeader::from_bytes(&bytes).dedup_table, 0x0102);
     }
     #[test]
     fn test_storage_header_from_bytes() {
-        let storage_header = StorageHeader::from_bytes(&[0, 2, 0, 2]);
+        let storage_header = StorageHeader::from_bytes(&[
+            b'S', b'E', b'1', b'F', 13, 0, 0, 2, 0, 2, 0, 0, 0, 0, 0, 1, 0, 0, 1, 0, 0, 0, 2, 7, 1,
+            0, 0, 0, 0, 0, 0, 3, 0, 0, 0, 0, 0, 0, 0, 5, 0, 0, 0, 9, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0,
+            0,
+        ]);
+        assert_eq!(storage_header.pipeline, 5);
+        assert_eq!(storage_header.dedup_table, 9);
+        assert_eq!(storage_header.sequence, 3);
         assert_eq!(storage_header.block_len, 33554944);
+        assert_eq!(storage_header.bitmap_capacity, 256);
+        assert_eq!(storage_header.flags, STORAGE_FLAG_DIRTY);
+        assert_eq!(storage_header.compression, Compression::Zstd.to_byte());
+        assert_eq!(storage_header.generation, 0x107);
+        assert!(storage_header.is_dirty());
+        assert!(storage_header.check_format().is_ok());
     }
     #[test]
     fn test_storage_header_full_flow() {
         let block_length = 16777472;
-        let expected_bytes = [0, 1, 0, 1];
-        let storage_header = StorageHeader::new(block_length);
+        let expected_bytes = [
+            b'S', b'E', b'1', b'F', 13, 0, 0, 1, 0, 1, 0, 0, 0, 0, 0, 128, 0, 0, 0, 0, 0, 0, 0, 0,
+            0, 0, 0, 0, 0, 0, 0,
+        ];
+        let storage_header = StorageHeader::new(block_length, 32768);
         assert_eq!(storage_header.block_len, block_length);
+        assert!(!storage_header.is_dirty());
         let bytes = storage_header.to_bytes();
-        assert_eq!(bytes, expected_bytes);
+        assert_eq!(bytes[..STORAGE_HEADER_SEQUENCE_OFFSET], expected_bytes);
         let storage_header = StorageHeader::from_bytes(&bytes);
         assert_eq!(storage_header.block_len, block_length);
+        assert_eq!(storage_header.bitmap_capacity, 32768);
+        // block length past 4GiB
+        let block_length = u32::MAX as u64 + 1;
+        let bytes = StorageHeader::new(block_length, 16).to_bytes();
+        assert_eq!(bytes[6..14], [0, 0, 0, 0, 1, 0, 0, 0]);
+        assert_eq!(StorageHeader::from_bytes(&bytes).block_len, block_length);
+    }
+    #[test]
+    fn test_storage_header_copies() {
+        let tmp_dir = tempfile::tempdir().unwrap();
+        let file_path = tmp_dir.path().join("header.hex");
+        let file_path = file_path.to_str().unwrap().to_string();
+        let mut storage = Storage::new(file_path.clone(), 4).unwrap();
+        storage.write_block(0, &[1, 2]).unwrap();
+        storage.close().unwrap();
+        let (storage_header, damaged_copies) =
+            StorageHeader::read_copies(&File::open(&file_path).unwrap()).unwrap();
+        assert!(damaged_copies.is_empty() && !storage_header.is_dirty());
+        // - torn write of newest copy, open falls back to the older copy
+        let mut file_bytes = std::fs::read(&file_path).unwrap();
+        let newest_offset = storage_header_offset(storage_header.sequence) as usize;
+        file_bytes[newest_offset + 20] ^= 0xff;
+        std::fs::write(&file_path, &file_bytes).unwrap();
+        let (older_header, damaged_copies) =
+            StorageHeader::read_copies(&File::open(&file_path).unwrap()).unwrap();
+        assert_eq!(older_header.sequence, storage_header.sequence - 1);
+        assert_eq!(damaged_copies, vec![storage_header.sequence % 2]);
+        let mut storage = Storage::open(file_path.clone()).unwrap();
+        assert_eq!(storage.read_block(0).unwrap().1, vec![1, 2]);
+        storage.close().unwrap();
+        // - next header write goes to the damaged copy
+        let (storage_header, damaged_copies) =
+            StorageHeader::read_copies(&File::open(&file_path).unwrap()).unwrap();
+        assert!(damaged_copies.is_empty());
+        assert!(storage_header.sequence > older_header.sequence);
+        // - no intact copy left
+        let mut file_bytes = std::fs::read(&file_path).unwrap();
+        file_bytes[20] ^= 0xff;
+        file_bytes[STORAGE_HEADER_SIZE + 20] ^= 0xff;
+        std::fs::write(&file_path, &file_bytes).unwrap();
+        assert!(matches!(
+            Storage::open(file_path),
+            Err(StorageError::Corruption {
+                block_index: None,
+                ..
+            })
+        ));
+    }
+    #[test]
+    fn test_storage_header_check_format() {
+        let mut bytes = StorageHeader::new(8, 16).to_bytes();
+        bytes[4] = 9;
+        assert!(matches!(
+            StorageHeader::from_bytes(&bytes).check_format(),
+            Err(StorageError::UnsupportedVersion { version: 9 })
+        ));
+        let mut unknown_compression = StorageHeader::new(8, 16);
+        unknown_compression.compression = 3;
+        assert!(matches!(
+            unknown_compression.check_format(),
+            Err(StorageError::Corruption {
+                block_index: None,
+                ..
+            })
+        ));
+        for block_len in [0, MAX_BLOCK_LEN + 1].iter() {
+            let bytes = StorageHeader::new(*block_len, 16).to_bytes();
+            assert!(matches!(
+                StorageHeader::from_bytes(&bytes).check_format(),
+                Err(StorageError::Corruption {
+                    block_index: None,
+                    ..
+                })
+            ));
+        }
+        bytes[0] = 0;
+        assert!(matches!(
+            StorageHeader::from_bytes(&bytes).check_format(),
+            Err(StorageError::NotStorageFile)
+        ));
     }
 }
 
@@ -58,27 +432,112 @@ mod unit_tests_storage_header {
 //  ... ... ... ... ... ... ... ... Block Header ... ... ... ... ... ... ... ... ... ....
 
 /// Header of each block
-/// - Stores size of data stored in the block as 4 bytes unsied integer as little endian
+/// - Stores size of data stored in the block as 8 bytes unsigned integer as little endian
+/// - Stores CRC-32 checksum of data stored in the block
+/// - Stores index of next block of a multi block record
+/// - Stores flags (compression and encryption of block data)
+/// - Stores tag of the block, see Storage::write_block_tagged
+/// - Stores expiry of the block, see Storage::sweep_expired
+/// - Stores generation of the block, see Storage::read_block_versioned
+/// - Byte layout is defined in layout module
 struct BlockHeader {
-    block_data_size: u32,
+    block_data_size: u64,
+    checksum: u32,
+    /// None for single blocks and last block of a record
+    /// - index u64::MAX can not be linked, it is stored as index + 1
+    next_block: Option<BlockIndex>,
+    /// Stored as u16, every block flag fits in it
+    flags: u32,
+    /// 0 for untagged blocks
+    tag: BlockTag,
+    /// Seconds since UNIX epoch, None for blocks that never expire
+    /// - stored as 0 for None
+    expires_at: Option<u64>,
+    /// 0 for blocks never written
+    generation: Generation,
 }
 
-const BLOCK_HEADER_SIZE: usize = std::mem::size_of::<BlockHeader>();
-
 impl BlockHeader {
-    fn new(block_data_size: u32) -> BlockHeader {
+    fn new(block_data_size: u64, checksum: u32) -> BlockHeader {
         BlockHeader {
-            block_data_size: block_data_size,
+            block_data_size,
+            checksum,
+            next_block: None,
+            flags: 0,
+            tag: 0,
+            expires_at: None,
+            generation: 0,
         }
     }
+    /// Header of block storing given data
+    fn for_data(block_data: &[u8]) -> BlockHeader {
+        BlockHeader::new(block_data.len() as u64, checksum::crc32(block_data))
+    }
+    fn with_next_block(mut self, next_block: Option<BlockIndex>) -> BlockHeader {
+        self.next_block = next_block;
+        self
+    }
+    fn with_flags(mut self, flags: u32) -> BlockHeader {
+        self.flags = flags;
+        self
+    }
+    fn with_tag(mut self, tag: BlockTag) -> BlockHeader {
+        self.tag = tag;
+        self
+    }
+    fn with_expiry(mut self, expires_at: Option<u64>) -> BlockHeader {
+        self.expires_at = expires_at.filter(|expires_at| *expires_at != 0);
+        self
+    }
+    fn with_generation(mut self, generation: Generation) -> BlockHeader {
+        self.generation = generation;
+        self
+    }
+    /// Data size as length of block data in memory
+    /// - Corruption if data size does not fit in usize, only possible on 32 bit targets
+    fn data_len(&self, block_index: BlockIndex) -> Result<usize, StorageError> {
+        usize::try_from(self.block_data_size).map_err(|_| StorageError::Corruption {
+            block_index: Some(block_index),
+            reason: "data size in block header does not fit in memory",
+        })
+    }
     fn from_bytes(bytes: &[u8; BLOCK_HEADER_SIZE]) -> BlockHeader {
-        let block_data_size = bytes_to_u32(bytes);
+        let block_data_size = get_u64(bytes, BLOCK_HEADER_DATA_SIZE_OFFSET);
+        let checksum = get_u32(bytes, BLOCK_HEADER_CHECKSUM_OFFSET);
+        let next_block = get_u64(bytes, BLOCK_HEADER_NEXT_BLOCK_OFFSET).checked_sub(1);
+        let flags = get_u16(bytes, BLOCK_HEADER_FLAGS_OFFSET) as u32;
+        let tag = get_u16(bytes, BLOCK_HEADER_TAG_OFFSET);
+        let expires_at = Some(get_u64(bytes, BLOCK_HEADER_EXPIRES_AT_OFFSET)).filter(|n| *n != 0);
+        let generation = get_u64(bytes, BLOCK_HEADER_GENERATION_OFFSET);
         BlockHeader {
-            block_data_size: block_data_size,
+            block_data_size,
+            checksum,
+            next_block,
+            flags,
+            tag,
+            expires_at,
+            generation,
         }
     }
     fn to_bytes(&self) -> [u8; BLOCK_HEADER_SIZE] {
-        u32_to_bytes(self.block_data_size)
+        let mut bytes = [0u8; BLOCK_HEADER_SIZE];
+        put_u64(
+            &mut bytes,
+            BLOCK_HEADER_DATA_SIZE_OFFSET,
+            self.block_data_size,
+        );
+        put_u32(&mut bytes, BLOCK_HEADER_CHECKSUM_OFFSET, self.checksum);
+        let next_block = self
+            .next_block
+            .and_then(|next_block| next_block.checked_add(1))
+            .unwrap_or(0);
+        put_u64(&mut bytes, BLOCK_HEADER_NEXT_BLOCK_OFFSET, next_block);
+        put_u16(&mut bytes, BLOCK_HEADER_FLAGS_OFFSET, self.flags as u16);
+        put_u16(&mut bytes, BLOCK_HEADER_TAG_OFFSET, self.tag);
+        let expires_at = self.expires_at.unwrap_or(0);
+        put_u64(&mut bytes, BLOCK_HEADER_EXPIRES_AT_OFFSET, expires_at);
+        put_u64(&mut bytes, BLOCK_HEADER_GENERATION_OFFSET, self.generation);
+        bytes
     }
 }
 
@@ -87,25 +546,87 @@ mod unit_test_block_header {
     use super::*;
     #[test]
     fn test_block_header_to_bytes() {
-        let block_header = BlockHeader::new(16777472);
+        let block_header = BlockHeader::new(16777472, 0x12345678);
         let bytes = block_header.to_bytes();
-        assert_eq!(bytes, [0, 1, 0, 1]);
+        assert_eq!(
+            bytes,
+            [
+                0, 1, 0, 1, 0, 0, 0, 0, 0x78, 0x56, 0x34, 0x12, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0,
+                0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0
+            ]
+        );
+        let bytes = block_header
+            .with_next_block(Some(0))
+            .with_flags(BLOCK_FLAG_ZSTD)
+            .with_tag(0x0102)
+            .with_expiry(Some(0x1_0000_0001))
+            .with_generation(0x0203)
+            .to_bytes();
+        assert_eq!(
+            bytes[BLOCK_HEADER_NEXT_BLOCK_OFFSET..],
+            [1, 0, 0, 0, 0, 0, 0, 0, 2, 0, 2, 1, 1, 0, 0, 0, 1, 0, 0, 0, 3, 2, 0, 0, 0, 0, 0, 0]
+        );
     }
     #[test]
     fn test_block_header_from_bytes() {
-        let block_header = BlockHeader::from_bytes(&[0, 2, 0, 2]);
+        let block_header = BlockHeader::from_bytes(&[
+            0, 2, 0, 2, 0, 0, 0, 0, 1, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0,
+            0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0,
+        ]);
         assert_eq!(block_header.block_data_size, 33554944);
+        assert_eq!(block_header.checksum, 1);
+        assert_eq!(block_header.next_block, None);
+        assert_eq!(block_header.flags, 0);
+        assert_eq!(block_header.expires_at, None);
+        assert_eq!(block_header.generation, 0);
+        let block_header = BlockHeader::from_bytes(&[
+            0, 2, 0, 2, 0, 0, 0, 0, 1, 0, 0, 0, 8, 0, 0, 0, 0, 0, 0, 0, 1, 0, 0, 0, 10, 0, 0, 0, 0,
+            0, 0, 0, 5, 0, 0, 0, 0, 0, 0, 0,
+        ]);
+        assert_eq!(block_header.next_block, Some(7));
+        assert_eq!(block_header.flags, BLOCK_FLAG_LZ4);
+        assert_eq!(block_header.tag, 0);
+        assert_eq!(block_header.expires_at, Some(10));
+        assert_eq!(block_header.generation, 5);
     }
     #[test]
     fn test_block_header_full_flow() {
         let block_data_size = 16777472;
-        let expected_bytes = [0, 1, 0, 1];
-        let block_header = BlockHeader::new(block_data_size);
+        let expected_bytes = [
+            0, 1, 0, 1, 0, 0, 0, 0, 0, 0, 0, 0, 3, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0,
+            0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0,
+        ];
+        let block_header = BlockHeader::new(block_data_size, 0).with_next_block(Some(2));
         assert_eq!(block_header.block_data_size, block_data_size);
         let bytes = block_header.to_bytes();
         assert_eq!(bytes, expected_bytes);
         let block_header = BlockHeader::from_bytes(&bytes);
         assert_eq!(block_header.block_data_size, block_data_size);
+        assert_eq!(block_header.next_block, Some(2));
+        // data size and next block past u32
+        let beyond_u32 = u32::MAX as u64 + 1;
+        let block_header = BlockHeader::new(beyond_u32, 0).with_next_block(Some(beyond_u32));
+        let block_header = BlockHeader::from_bytes(&block_header.to_bytes());
+        assert_eq!(block_header.block_data_size, beyond_u32);
+        assert_eq!(block_header.next_block, Some(beyond_u32));
+        // largest index is not linkable, it is stored as index + 1
+        let block_header = BlockHeader::new(1, 0).with_next_block(Some(u64::MAX - 1));
+        let block_header = BlockHeader::from_bytes(&block_header.to_bytes());
+        assert_eq!(block_header.next_block, Some(u64::MAX - 1));
+        // expiry 0 is stored as never
+        let block_header = BlockHeader::new(1, 0).with_expiry(Some(0));
+        assert_eq!(block_header.expires_at, None);
+    }
+    #[test]
+    fn test_block_header_for_data() {
+        let block_header = BlockHeader::for_data(b"123456789");
+        assert_eq!(block_header.block_data_size, 9);
+        assert_eq!(block_header.checksum, 0xcbf43926);
+        // free block header is all zeros
+        assert_eq!(
+            BlockHeader::for_data(&[]).to_bytes(),
+            [0u8; BLOCK_HEADER_SIZE]
+        );
     }
 }
 
@@ -113,139 +634,262 @@ mod unit_test_block_header {
 
 // ... ... ... ... ... ... ... ... ... Storage ... ... ... ... ... ... ... ... ... ....
 
-use std::collections::BTreeSet;
+use std::collections::{BTreeMap, BTreeSet};
+use std::convert::TryFrom;
 use std::fs::{File, OpenOptions};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
 
 pub struct Storage {
     header: StorageHeader,
     /// Map of empty blocks in the storage file
-    free_blocks: BTreeSet<u32>,
+    free_blocks: BTreeSet<BlockIndex>,
     /// Number of blocks in the storage file (used or free)
-    end_block_count: u32,
-    /// File object for writing
+    end_block_count: u64,
+    /// First block preallocated by Storage::preallocate and not written past yet, blocks
+    /// from it to end_block_count are preallocated; u64::MAX if none since opened
+    preallocated_from: BlockIndex,
+    /// File object for writing, written with positional IO only; opened for reading in a
+    /// read-only storage
     file_writer: File,
-    /// Index of last written byte in the file
-    write_pointer: u64,
-    /// File object for reading
+    /// File object for reading, read with positional IO only
     file_reader: File,
-    /// Index of last read byte in the file
-    read_pointer: u64,
+    /// Path of storage file, opened again by backends that need their own open flags
+    file_path: String,
+    /// Receives divergences found by consistency checks, None when disabled
+    consistency_hook: Option<ConsistencyHook>,
+    /// Notified of block writes and deletes, in registration order
+    observers: Vec<Box<dyn BlockObserver>>,
+    /// Applied in order to block data on write, reversed on read
+    pipeline: transform::Pipeline,
+    /// True once this object has set the dirty flag in storage file, cleared on close
+    dirty_flag_set: bool,
+    /// Cache of block data in front of read_block, None when disabled
+    cache: Option<cache::BlockCache>,
+    /// Source of block reads of read_block and read_record
+    backend: Box<dyn Backend>,
+    /// When writes are synced to disk
+    durability: durability::Durability,
+    /// Trim free tail of file when last block is deleted
+    auto_trim: bool,
+    /// Drops or rewrites records during compaction, see set_compaction_filter
+    compaction_filter: Option<Box<dyn CompactionFilter>>,
+    /// compaction_filter ran in the current pass of compact_step
+    compaction_filtered: bool,
+    /// How allocate picks blocks for new data
+    allocation: AllocationStrategy,
+    /// Quota of storage file in bytes, see Storage::max_bytes
+    max_bytes: Option<u64>,
+    /// Opened with StorageOptions::read_only, every write fails with ReadOnly
+    read_only: bool,
+    /// Blocks that can not be deleted or moved, see Storage::pin_block
+    pinned: BTreeSet<BlockIndex>,
+    /// Undo logs of live snapshots, filled before blocks change
+    snapshots: Vec<std::sync::Weak<std::sync::Mutex<snapshot::UndoLog>>>,
+    /// Expiry of every used block that expires, None until first sweep loads it
+    expiries: Option<BTreeMap<BlockIndex, u64>>,
+    /// Content hash of every used block, None until open reads the dedup table or
+    /// first write_dedup builds it
+    dedup: Option<dedup::DedupIndex>,
+    /// Buffers reused by read_block_bytes and read_blocks_into
+    read_buffers: vectored::ReadBuffers,
+    /// Block versions kept for read views, None until first read view is opened
+    versions: Option<Arc<std::sync::Mutex<mvcc::VersionStore>>>,
+    /// A write or sync of the storage file failed since it was opened, so the dirty flag
+    /// stays set, see close; set through &self by write_file_at
+    write_failed: AtomicBool,
 }
 
 impl Storage {
     //  ... ... ... ... ... ... Static Functions ... ... ... ... ... ... .
 
-    /// Open storage file for writing
-    /// - creates a new file if it does not exist
-    /// - truncate: if true, truncates the file to 0 bytes
-    /// - truncate: if false, no modification to the file
-    /// - returns: (file_object_for_writing, write_pointer) - write_pointer is always 0
-    fn open_file_writer(file_path: &String, truncate: bool) -> Result<(File, u64), Error> {
-        let file_path_clone = file_path.clone();
-        let file_writer_result = OpenOptions::new()
-            .write(true)
-            .truncate(truncate)
-            .create(true)
-            .open(file_path_clone);
-        if file_writer_result.is_err() {
-            return Err(Error {
-                code: 1,
-                message: "Could not create file".to_string(),
-            });
-        }
-        let file_writer = file_writer_result.unwrap();
-        let write_pointer = 0 as u64;
-        Ok((file_writer, write_pointer))
+    /// Open storage file for writing, holding file_lock of it, see lock
+    /// - creates a new file if it does not exist, no modification to an existing file
+    /// - AlreadyLocked if another storage object holds a lock of the file
+    fn open_file_writer(file_path: &str, file_lock: FileLock) -> Result<File, StorageError> {
+        lock::open_locked(
+            OpenOptions::new().write(true).create(true),
+            file_path,
+            file_lock,
+        )
     }
     /// Open storage file for reading
-    /// - returns: (file_object_for_reading, read_pointer) - read_pointer is always 0
-    fn open_file_reader(file_path: &String) -> Result<(File, u64), Error> {
-        let file_path_clone = file_path.clone();
-        let file_reader_result = OpenOptions::new().read(true).open(file_path_clone);
-        if file_reader_result.is_err() {
-            return Err(Error {
-                code: 1,
-                message: "Could not open file".to_string(),
-            });
-        }
-        let file_reader = file_reader_result.unwrap();
-        let read_pointer = 0 as u64;
-        Ok((file_reader, read_pointer))
+    fn open_file_reader(file_path: &str) -> Result<File, StorageError> {
+        OpenOptions::new()
+            .read(true)
+            .open(file_path)
+            .map_err(StorageError::io("open storage file", None))
     }
 
     // // ... ... ... ... ... Storage Constructors ... ... ... ... ... .
 
     /// Create new storage file
     /// - Create/Overwrite new storage file in given path
-    /// - Initializes storage header
-    pub fn new(file_path: String, block_len: usize) -> Result<Storage, Error> {
-        let file_writer = Storage::open_file_writer(&file_path, true);
-        if file_writer.is_err() {
-            return Err(file_writer.unwrap_err());
+    /// - Initializes storage header and allocation bitmap of DEFAULT_BITMAP_CAPACITY blocks
+    /// - block_len must be within 1..=MAX_BLOCK_LEN
+    /// - same as StorageOptions::new(file_path).block_len(block_len).truncate(true).open(),
+    ///   see StorageOptions for other settings
+    pub fn new(file_path: String, block_len: u64) -> Result<Storage, StorageError> {
+        Storage::new_with_bitmap_capacity(file_path, block_len, DEFAULT_BITMAP_CAPACITY)
+    }
+    /// Create new storage file with allocation bitmap tracking bitmap_capacity blocks
+    /// - blocks beyond bitmap_capacity are tracked by scanning block headers on open
+    /// - bitmap_capacity 0 disables the bitmap, every open scans all block headers
+    pub fn new_with_bitmap_capacity(
+        file_path: String,
+        block_len: u64,
+        bitmap_capacity: u32,
+    ) -> Result<Storage, StorageError> {
+        Storage::create_file(file_path, block_len, bitmap_capacity, FileLock::Exclusive)
+    }
+    /// Create new storage file, see Storage::new_with_bitmap_capacity
+    /// - file_lock: lock held while the file is open, see StorageOptions::lock
+    fn create_file(
+        file_path: String,
+        block_len: u64,
+        bitmap_capacity: u32,
+        file_lock: FileLock,
+    ) -> Result<Storage, StorageError> {
+        if block_len == 0 || block_len > MAX_BLOCK_LEN {
+            return Err(StorageError::InvalidBlockLength { block_len });
         }
-        let (file_writer, write_pointer) = file_writer.unwrap();
+        let file_writer = Storage::open_file_writer(&file_path, file_lock);
+        let file_writer = file_writer?;
+        // - truncate once locked, never a file another storage object is using
+        file_writer
+            .set_len(0)
+            .map_err(StorageError::io("create storage file", None))?;
 
         let file_reader = Storage::open_file_reader(&file_path);
-        if file_reader.is_err() {
-            return Err(file_reader.unwrap_err());
-        }
-        let (file_reader, read_pointer) = file_reader.unwrap();
+        let file_reader = file_reader?;
+        let backend = Storage::file_backend(&file_reader, &file_path)?;
 
+        let header = StorageHeader::new(block_len, bitmap_capacity);
         let mut storage = Storage {
-            header: StorageHeader::new(block_len as u32),
+            pipeline: transform::Pipeline::new(&header),
+            header,
             free_blocks: BTreeSet::new(),
             end_block_count: 0,
+            preallocated_from: BlockIndex::MAX,
             file_writer,
-            write_pointer,
             file_reader,
-            read_pointer,
+            file_path,
+            consistency_hook: None,
+            observers: Vec::new(),
+            dirty_flag_set: false,
+            cache: None,
+            backend,
+            durability: durability::Durability::new(DurabilityMode::default()),
+            auto_trim: false,
+            compaction_filter: None,
+            compaction_filtered: false,
+            allocation: AllocationStrategy::default(),
+            max_bytes: None,
+            read_only: false,
+            pinned: BTreeSet::new(),
+            snapshots: Vec::new(),
+            expiries: None,
+            dedup: None,
+            read_buffers: vectored::ReadBuffers::default(),
+            versions: None,
+            write_failed: AtomicBool::new(false),
         };
-        if storage.set_storage_header().is_err() {
-            return Err(Error {
-                code: 2,
-                message: "Could not init storage".to_string(),
-            });
+        // - file is dirty from creation until close
+        storage.header.flags |= STORAGE_FLAG_DIRTY;
+        for _ in 0..STORAGE_HEADER_COPIES {
+            storage.set_storage_header()?;
         }
+        storage.dirty_flag_set = true;
+        // - write empty allocation bitmap
+        storage.write_bitmap()?;
         Ok(storage)
     }
     /// Open existing storage file
     /// - Loads storage header
-    /// - Loads free blocks Set
-    pub fn open(file_path: String) -> Result<Storage, Error> {
-        let file_writer = Storage::open_file_writer(&file_path, false);
-        if file_writer.is_err() {
-            return Err(file_writer.unwrap_err());
-        }
-        let (file_writer, write_pointer) = file_writer.unwrap();
+    /// - Loads free blocks Set from allocation bitmap
+    /// - Falls back to scanning all block headers if file was not closed cleanly
+    /// - same as StorageOptions::new(file_path).open(), see StorageOptions for other
+    ///   settings
+    pub fn open(file_path: String) -> Result<Storage, StorageError> {
+        Storage::open_file(file_path, false, FileLock::Exclusive)
+    }
+    /// Open existing storage file, see Storage::open
+    /// - read_only: if true, the file is not written, not even its dirty flag, see
+    ///   StorageOptions::read_only
+    /// - file_lock: lock held while the file is open, see StorageOptions::lock
+    fn open_file(
+        file_path: String,
+        read_only: bool,
+        file_lock: FileLock,
+    ) -> Result<Storage, StorageError> {
+        // - reader first, so a missing file fails instead of being created empty
         let file_reader = Storage::open_file_reader(&file_path);
-        if file_reader.is_err() {
-            return Err(file_reader.unwrap_err());
-        }
-        let (file_reader, read_pointer) = file_reader.unwrap();
+        let file_reader = file_reader?;
+        // - read-only storage holds its lock through its file_writer
+        let file_writer = if read_only {
+            lock::open_locked(OpenOptions::new().read(true), &file_path, file_lock)
+        } else {
+            Storage::open_file_writer(&file_path, file_lock)
+        };
+        let file_writer = file_writer?;
+        let backend = Storage::file_backend(&file_reader, &file_path)?;
 
         // - init storage object
+        let header = StorageHeader::new(0, 0);
         let mut storage = Storage {
-            header: StorageHeader::new(0),
+            pipeline: transform::Pipeline::new(&header),
+            header,
             free_blocks: BTreeSet::new(),
             end_block_count: 0,
+            preallocated_from: BlockIndex::MAX,
             file_writer,
-            write_pointer,
             file_reader,
-            read_pointer,
+            file_path,
+            consistency_hook: None,
+            observers: Vec::new(),
+            dirty_flag_set: false,
+            cache: None,
+            backend,
+            durability: durability::Durability::new(DurabilityMode::default()),
+            auto_trim: false,
+            compaction_filter: None,
+            compaction_filtered: false,
+            allocation: AllocationStrategy::default(),
+            max_bytes: None,
+            read_only,
+            pinned: BTreeSet::new(),
+            snapshots: Vec::new(),
+            expiries: None,
+            dedup: None,
+            read_buffers: vectored::ReadBuffers::default(),
+            versions: None,
+            write_failed: AtomicBool::new(false),
         };
         // - read and update storage header from file
-        if storage.get_storage_header().is_err() {
-            return Err(Error {
-                code: 2,
-                message: "Could not init storage".to_string(),
+        storage.get_storage_header()?;
+        if storage.header.block_len == 0 {
+            return Err(StorageError::Corruption {
+                block_index: None,
+                reason: "block length in storage header is 0",
             });
         }
-        // - read file and count
+        // - load free blocks
         // -- total blocks - update self.end_block_count
         // -- free blocks - update self.free_blocks
-        let blocks_status_result = storage.read_storage_block_headers();
-        if blocks_status_result.is_err() {
-            return Err(blocks_status_result.unwrap_err());
+        if storage.header.is_dirty() {
+            // bitmap may be stale, rebuild it from block headers
+            storage.read_storage_block_headers()?;
+            if !read_only {
+                storage.write_bitmap()?;
+            }
+        } else {
+            storage.read_bitmap()?;
+        }
+        // - load dedup hash table before mark_dirty truncates it away
+        storage.read_dedup_table()?;
+        // - mark file dirty until close
+        if !read_only {
+            storage.mark_dirty()?;
         }
         Ok(storage)
     }
@@ -255,414 +899,458 @@ impl Storage {
 
     // ... ... ... ... ... . InMemory Logic Functions ... ... ... ... ....
 
+    /// Capacity of each block in bytes, as set when the file was created
+    pub fn block_len(&self) -> u64 {
+        self.header.block_len
+    }
+    /// Path storage file was created or opened with
+    pub fn file_path(&self) -> &str {
+        &self.file_path
+    }
+    /// Check if storage file was opened read-only, see StorageOptions::read_only
+    pub fn is_read_only(&self) -> bool {
+        self.read_only
+    }
     /// check if block is within storage file, without reading it from file (in memory)
-    fn block_exists(&mut self, block_index: u32) -> bool {
+    fn block_exists(&mut self, block_index: BlockIndex) -> bool {
         block_index < self.end_block_count
     }
     /// Check if block is empty, without reading it from file (in memory)
-    fn is_empty_block(&mut self, block_index: usize) -> bool {
-        let block_index = block_index as u32;
+    fn is_empty_block(&mut self, block_index: BlockIndex) -> bool {
         if self.block_exists(block_index) {
-            if self.free_blocks.contains(&block_index) {
-                return true;
-            } else {
-                return false;
-            }
+            self.free_blocks.contains(&block_index)
         } else {
-            return true;
+            true
         }
     }
+    /// Offset of block in storage file
+    /// - BlockOutOfRange if offset of block does not fit in u64
+    fn block_offset(&self, block_index: BlockIndex) -> Result<u64, StorageError> {
+        let out_of_range = StorageError::BlockOutOfRange { block_index };
+        block_offset(
+            self.header.bitmap_capacity,
+            self.header.block_len,
+            block_index,
+        )
+        .ok_or(out_of_range)
+    }
 
     // ... ... ... ... ... ... ... ... ... ... ... ... ... ... ... ... ...
 
     // ... ... ... ... ... ... File IO Functions ... ... ... ... ... ... .
 
     /// Set storage header in storage file
-    /// - Write storage header to file
-    /// - NOTE: This can only be used once when creating a new storage file
-    /// - returns: write pointer
-    fn set_storage_header(&mut self) -> Result<usize, Error> {
-        use std::io::prelude::*;
-        let file = &mut self.file_writer;
-        // Write storage header to file
+    /// - Write storage header to file, with the next sequence
+    /// - writes the copy the current header was not read from or written to, so that
+    ///   copy stays intact until the new one is written in full, see layout
+    /// - returns: end offset of storage header
+    fn set_storage_header(&mut self) -> Result<usize, StorageError> {
+        let sequence = self.header.sequence;
+        self.header.sequence = sequence.saturating_add(1);
         let header_bytes = self.header.to_bytes();
-        // -- seek writer pointer to beginning of file
-        let ptr_seek_result = file.seek(std::io::SeekFrom::Start(0));
-        if ptr_seek_result.is_err() {
-            return Err(Error {
-                code: 3,
-                message: "Could not seek file pointer".to_string(),
-            });
-        }
-        // -- write storage header
-        self.write_pointer = ptr_seek_result.unwrap();
-        let write_result = file.write(&header_bytes);
-        if write_result.is_err() {
-            return Err(Error {
-                code: 2,
-                message: "Could not write to file".to_string(),
-            });
+        let header_offset = storage_header_offset(self.header.sequence);
+        if let Err(error) =
+            self.write_file_at(header_offset, &header_bytes, "write storage header", None)
+        {
+            // - next write goes to the same copy again, the other one is left intact
+            self.header.sequence = sequence;
+            return Err(error);
         }
-        // -- verify write operation was successful
-        let write_size = write_result.unwrap();
-        if write_size != STORAGE_HEADER_SIZE as usize {
-            return Err(Error {
-                code: 2,
-                message: "Could not write all header bytes to file".to_string(),
-            });
-        }
-        self.write_pointer += write_size as u64;
-        Ok(self.write_pointer as usize)
+        self.durability.header_written();
+        Ok(BITMAP_OFFSET as usize)
     }
     /// Get storage header from storage file
     /// - Read storage header from file
     /// - update storage header in object
-    /// - returns: read pointer
-    fn get_storage_header(&mut self) -> Result<usize, Error> {
-        use std::io::prelude::*;
-        let file = &mut self.file_reader;
-        // - Read storage header from file
-        // -- seek reader pointer to beginning of file
-        let ptr_seek_result = file.seek(std::io::SeekFrom::Start(0));
-        if ptr_seek_result.is_err() {
-            return Err(Error {
-                code: 3,
-                message: "Could not seek file pointer".to_string(),
-            });
-        }
-        // -- read storage header
-        let mut header_bytes = [0u8; STORAGE_HEADER_SIZE];
-        self.read_pointer = ptr_seek_result.unwrap();
-        let read_result = file.read(&mut header_bytes);
-        if read_result.is_err() {
-            return Err(Error {
-                code: 2,
-                message: "Could not read from file".to_string(),
-            });
-        }
-        // -- verify read operation was successful
-        let read_size = read_result.unwrap();
-        if read_size != STORAGE_HEADER_SIZE as usize {
-            return Err(Error {
-                code: 2,
-                message: "Could not read all header bytes from file".to_string(),
-            });
-        }
-        // -- update read pointer
-        self.read_pointer += read_size as u64;
-        // - parse storage header
-        let storage_header = StorageHeader::from_bytes(&header_bytes);
-        // - copy storage header to storage object
-        self.header = storage_header;
-        // - return read pointer
-        Ok(read_size)
+    /// - returns: end offset of storage header
+    fn get_storage_header(&mut self) -> Result<usize, StorageError> {
+        self.header = StorageHeader::read_from(&self.file_reader)?;
+        self.pipeline.configure(&self.header);
+        Ok(BITMAP_OFFSET as usize)
     }
     /// Count number of blocks in storage file
     /// -- total blocks - update self.end_block_count
     /// -- free blocks - update self.free_blocks
-    /// - returns: read pointer
-    fn read_storage_block_headers(&mut self) -> Result<usize, Error> {
-        use std::io::prelude::*;
-        let file = &mut self.file_reader;
-        // - seek reader pointer to end of file
-        let ptr_seek_result = file.seek(std::io::SeekFrom::Start(0));
-        if ptr_seek_result.is_err() {
-            return Err(Error {
-                code: 3,
-                message: "Could not seek file pointer".to_string(),
-            });
-        }
-        // - update read pointer
-        self.read_pointer = ptr_seek_result.unwrap();
+    /// - returns: end offset of last block
+    fn read_storage_block_headers(&mut self) -> Result<u64, StorageError> {
+        self.free_blocks = BTreeSet::new();
+        self.end_block_count = 0;
+        self.scan_block_headers(0)
+    }
+    /// Scan block headers from first_block until end of file, or until the dedup table
+    /// -- total blocks - update self.end_block_count
+    /// -- free blocks - add to self.free_blocks
+    /// - a file opened dirty may still hold a dedup table after its blocks, when the
+    ///   header clearing the flag was torn, the table is not read as block headers
+    /// - returns: end offset of last block
+    fn scan_block_headers(&mut self, first_block: BlockIndex) -> Result<u64, StorageError> {
         // - read file and count
         // -- total blocks - update self.end_block_count
         // -- free blocks - update self.free_blocks
         let mut free_blocks = BTreeSet::new();
-        // -- seek reader pointer to end of STORAGE_HEADER_SIZE
-        let ptr_seek_result = file.seek(std::io::SeekFrom::Start(STORAGE_HEADER_SIZE as u64));
-        if ptr_seek_result.is_err() {
-            return Err(Error {
-                code: 3,
-                message: "Could not seek file pointer".to_string(),
-            });
-        }
         // -- traverse all blocks in file, untill end of file
-        let mut block_index = 0;
+        let mut block_index = first_block;
+        let mut block_offset = self.block_offset(block_index)?;
         loop {
+            // - blocks end where the dedup table starts
+            if self.header.dedup_table != 0 && block_offset >= self.header.dedup_table {
+                break;
+            }
             // - read block header
             let mut block_header_bytes = [0u8; BLOCK_HEADER_SIZE];
-            let read_result = file.read(&mut block_header_bytes);
-            if read_result.is_err() {
-                return Err(Error {
-                    code: 2,
-                    message: "Could not read from file".to_string(),
-                });
-            }
+            let read_size =
+                positional::read_at(&self.file_reader, block_offset, &mut block_header_bytes)
+                    .map_err(StorageError::io("read block header", Some(block_index)))?;
             // -- check end of file
             // -- verify read operation was successful
-            let read_size = read_result.unwrap();
             if read_size == 0 {
                 // end of file reached
                 break;
             }
-            if read_size != BLOCK_HEADER_SIZE as usize {
-                return Err(Error {
-                    code: 2,
-                    message: "Could not read all header bytes from file".to_string(),
+            if read_size != BLOCK_HEADER_SIZE {
+                return Err(StorageError::Corruption {
+                    block_index: Some(block_index),
+                    reason: "block header is truncated",
                 });
             }
-            // -- update read pointer
-            self.read_pointer += read_size as u64;
             // -- parse block header
             let block_header = BlockHeader::from_bytes(&block_header_bytes);
+            // - generations given out since the storage header was written are in block headers
+            self.header.generation = self.header.generation.max(block_header.generation);
             // - check if block is free
             if block_header.block_data_size == 0 {
                 // -- add block to free blocks
                 free_blocks.insert(block_index);
             }
-            // -- increment block index
+            // -- increment block index, and move to next block header
             block_index += 1;
-            // - seek reader pointer to end of block
-            let ptr_seek_result =
-                file.seek(std::io::SeekFrom::Current(self.header.block_len as i64));
-            if ptr_seek_result.is_err() {
-                return Err(Error {
-                    code: 3,
-                    message: "Could not seek file pointer".to_string(),
-                });
-            }
-            let ptr_seek_result = ptr_seek_result.unwrap();
-            self.read_pointer = ptr_seek_result;
-            // -- verify seek operation was successful
-            if ptr_seek_result != self.read_pointer {
-                // end of file reached
-                break;
-            }
+            block_offset = self.block_offset(block_index)?;
         }
         // - update end block count
         self.end_block_count = block_index;
         // - update free blocks
-        self.free_blocks = free_blocks;
+        self.free_blocks.append(&mut free_blocks);
         // - return
-        Ok(self.read_pointer as usize)
+        Ok(block_offset)
+    }
+    /// Rebuild free blocks Set from block headers in storage file
+    /// - Discards in memory free_blocks and end_block_count
+    /// - Rescans every block header and rewrites allocation bitmap, user data is not modified
+    /// - returns: number of free blocks found
+    pub fn rebuild_free_list(&mut self) -> Result<usize, StorageError> {
+        self.read_storage_block_headers()?;
+        self.write_bitmap()?;
+        Ok(self.free_blocks.len())
     }
     /// Read block data from storage file
-    /// - return (block_data, read_pointer)
-    /// - returns: read pointer
-    pub fn read_block(&mut self, block_index: usize) -> Result<(usize, Vec<u8>), Error> {
+    /// - return (read_end_offset, block_data)
+    /// - verifies block checksum before reversing block transforms and compression
+    /// - served from block cache when enabled and cached
+    /// - read_end_offset: end offset of block data read from file, 0 when the block is
+    ///   empty or served from cache
+    #[cfg_attr(
+        feature = "tracing",
+        tracing::instrument(
+            level = "trace",
+            skip(self),
+            fields(bytes = tracing::field::Empty),
+            err
+        )
+    )]
+    pub fn read_block(&mut self, block_index: BlockIndex) -> Result<(u64, Vec<u8>), StorageError> {
         if self.is_empty_block(block_index) {
-            // return current read_pointer and empty vector
-            return Ok((self.read_pointer as usize, Vec::new()));
+            // nothing to read, return empty vector
+            return Ok((0, Vec::new()));
         }
-        use std::io::prelude::*;
-        let block_length = self.header.block_len;
-        let block_offset: usize = STORAGE_HEADER_SIZE as usize
-            + block_index as usize * (BLOCK_HEADER_SIZE as usize + block_length as usize);
-        // - seek reader to block offset
-        let seek_result = self
-            .file_reader
-            .seek(std::io::SeekFrom::Start(block_offset as u64));
-        if seek_result.is_err() {
-            return Err(Error {
-                code: 3,
-                message: "Could not seek to block offset".to_string(),
-            });
-        }
-        // verify seek operation was successful
-        let seek_position = seek_result.unwrap();
-        if seek_position != block_offset as u64 {
-            return Err(Error {
-                code: 3,
-                message: "Could not seek to block offset".to_string(),
-            });
-        }
-        self.read_pointer = seek_position;
-        // - read block data length from inital 4 bytes
-        let block_data_size_bytes = &mut [0u8; 4];
-        let read_result = self.file_reader.read(block_data_size_bytes);
-        if read_result.is_err() {
-            return Err(Error {
-                code: 3,
-                message: "Could not read from file".to_string(),
-            });
+        if let Some(block_data) = self.cached_block(block_index) {
+            #[cfg(feature = "tracing")]
+            tracing::Span::current().record("bytes", block_data.len());
+            return Ok((0, block_data));
         }
-        let read_size = read_result.unwrap();
+        let (block_header, block_data) = self.read_stored_block(block_index)?;
+        let read_end_offset =
+            self.block_offset(block_index)? + BLOCK_HEADER_SIZE as u64 + block_data.len() as u64;
+        // - verify stored data against checksum in block header
+        self.verify_block_checksum(block_index, &block_header, &block_data)?;
+        // - reverse block transforms and compression
+        let block_data = self.decode_block_data(block_index, block_data, block_header.flags)?;
+        self.cache_block(block_index, &block_data);
+        #[cfg(feature = "tracing")]
+        tracing::Span::current().record("bytes", block_data.len());
+        // - return read_end_offset and block_data
+        Ok((read_end_offset, block_data))
+    }
+    /// Read block header and stored (encoded) block data from storage file
+    /// - reads through backend
+    fn read_stored_block(
+        &mut self,
+        block_index: BlockIndex,
+    ) -> Result<(BlockHeader, Vec<u8>), StorageError> {
+        let (block_header, data_offset) = self.read_stored_header(block_index)?;
+        // - read block data to vec
+        let mut block_data = vec![0u8; block_header.data_len(block_index)?];
+        self.read_stored_data(block_index, data_offset, &mut block_data)?;
+        Ok((block_header, block_data))
+    }
+    /// Read block header from storage file, through backend
+    /// - verifies data size fits in block, before anything is allocated for block data
+    /// - returns: (block_header, data_offset)
+    fn read_stored_header(
+        &mut self,
+        block_index: BlockIndex,
+    ) -> Result<(BlockHeader, u64), StorageError> {
+        let block_offset = self.block_offset(block_index)?;
+        // - read block header from inital BLOCK_HEADER_SIZE bytes
+        let block_header_bytes = &mut [0u8; BLOCK_HEADER_SIZE];
+        let read_size = self
+            .backend
+            .read_at(block_offset, block_header_bytes)
+            .map_err(StorageError::io("read block header", Some(block_index)))?;
         if read_size != BLOCK_HEADER_SIZE {
-            return Err(Error {
-                code: 2,
-                message: "Could not read all block data size bytes from file".to_string(),
+            return Err(StorageError::Corruption {
+                block_index: Some(block_index),
+                reason: "block header is truncated",
             });
         }
-        self.read_pointer += read_size as u64;
-        let block_header = BlockHeader::new(bytes_to_u32(block_data_size_bytes));
-        // - read block data to vec
-        let mut block_data = vec![0u8; block_header.block_data_size as usize];
-        let read_result = self.file_reader.read(&mut block_data[..]);
-        if read_result.is_err() {
-            return Err(Error {
-                code: 4,
-                message: "Could not read from file".to_string(),
+        let block_header = self.checked_block_header(block_index, block_header_bytes)?;
+        Ok((block_header, block_offset + BLOCK_HEADER_SIZE as u64))
+    }
+    /// Block header from bytes read from storage file
+    /// - verifies data size fits in block, before anything is allocated for block data
+    fn checked_block_header(
+        &self,
+        block_index: BlockIndex,
+        block_header_bytes: &[u8; BLOCK_HEADER_SIZE],
+    ) -> Result<BlockHeader, StorageError> {
+        let block_header = BlockHeader::from_bytes(block_header_bytes);
+        if block_header.block_data_size > self.header.block_len {
+            return Err(StorageError::Corruption {
+                block_index: Some(block_index),
+                reason: "data size in block header exceeds block length",
             });
         }
-        let read_size = read_result.unwrap() as u32;
-        self.read_pointer += read_size as u64;
+        Ok(block_header)
+    }
+    /// Read stored (encoded) block data at data_offset into block_data, through backend
+    /// - block_data: data_len bytes of block header
+    fn read_stored_data(
+        &mut self,
+        block_index: BlockIndex,
+        data_offset: u64,
+        block_data: &mut [u8],
+    ) -> Result<(), StorageError> {
+        let read_size = self
+            .backend
+            .read_at(data_offset, block_data)
+            .map_err(StorageError::io("read block data", Some(block_index)))?;
         // - verify read operation was successful
-        if read_size != block_header.block_data_size {
-            return Err(Error {
-                code: 4,
-                message: "Could not read all block data from file".to_string(),
+        if read_size != block_data.len() {
+            return Err(StorageError::Corruption {
+                block_index: Some(block_index),
+                reason: "block data is truncated",
             });
         }
-        // - return read_pointer and block_data
-        Ok((self.read_pointer as usize, block_data))
+        Ok(())
     }
-    pub fn write_block(&mut self, block_index: usize, data: &Vec<u8>) -> Result<usize, Error> {
-        use std::io::prelude::*;
-        let block_length = self.header.block_len;
-        let block_offset = STORAGE_HEADER_SIZE as usize
-            + block_index as usize * (BLOCK_HEADER_SIZE as usize + block_length as usize);
-        // - seek writer to block offset
-        let seek_result = self
-            .file_writer
-            .seek(std::io::SeekFrom::Start(block_offset as u64));
-        if seek_result.is_err() {
-            return Err(Error {
-                code: 5,
-                message: "Could not seek to block offset".to_string(),
-            });
+    /// Write data to block
+    /// - data after block transforms must fit in block_len bytes
+    /// - AppendOnly if storage file is append-only and block holds data
+    /// - returns: end offset of written block data
+    #[cfg_attr(
+        feature = "tracing",
+        tracing::instrument(level = "trace", skip(self, data), fields(bytes = data.len()), err)
+    )]
+    pub fn write_block(
+        &mut self,
+        block_index: BlockIndex,
+        data: &[u8],
+    ) -> Result<u64, StorageError> {
+        self.write_linked_block(block_index, data, None, None, 0)
+    }
+    /// Write data to block, linking it to next block of a record
+    /// - expires_at: expiry stored in block header, see Storage::sweep_expired
+    /// - tag: tag stored in block header, see Storage::write_block_tagged
+    /// - returns: end offset of written block data
+    fn write_linked_block(
+        &mut self,
+        block_index: BlockIndex,
+        data: &[u8],
+        next_block: Option<BlockIndex>,
+        expires_at: Option<u64>,
+        tag: BlockTag,
+    ) -> Result<u64, StorageError> {
+        let block_offset = self.block_offset(block_index)?;
+        // - empty data frees the block
+        if data.is_empty() {
+            self.check_not_pinned(block_index)?;
         }
-        // -- verify seek operation was successful
-        let seek_position = seek_result.unwrap();
-        if seek_position != block_offset as u64 {
-            return Err(Error {
-                code: 5,
-                message: "Could not seek to block offset".to_string(),
+        self.check_appendable(block_index)?;
+        self.check_quota(block_index)?;
+        // - compress and apply block transforms
+        let (block_data, block_flags) = self.encode_block_data(data)?;
+        // - verify block data fits in block, before touching the file
+        if block_data.len() as u64 > self.header.block_len {
+            return Err(StorageError::BlockTooLarge {
+                block_index,
+                data_len: block_data.len(),
+                block_len: self.header.block_len,
             });
         }
-        self.write_pointer = seek_position;
+        // - observers only see writes that go ahead
+        for observer in self.observers.iter_mut() {
+            observer.before_write(block_index, data);
+        }
+        self.preserve_for_snapshots(block_index)?;
         // - Write Block Header
         // -- write block header to inital BLOCK_HEADER_SIZE bytes
-        let block_header = BlockHeader::new(data.len() as u32);
-        let write_result = self.file_writer.write(&block_header.to_bytes());
-        if write_result.is_err() {
-            return Err(Error {
-                code: 6,
-                message: "Could not write to file".to_string(),
-            });
-        }
-        let write_size = write_result.unwrap();
-        self.write_pointer += write_size as u64;
-        // -- verify write operation was successful
-        if write_size != BLOCK_HEADER_SIZE {
-            return Err(Error {
-                code: 8,
-                message: "Could not write all data to file".to_string(),
-            });
-        }
+        let block_header = BlockHeader::for_data(&block_data)
+            .with_next_block(next_block)
+            .with_flags(block_flags)
+            .with_tag(tag)
+            .with_expiry(expires_at)
+            .with_generation(self.next_generation());
+        self.write_file_at(
+            block_offset,
+            &block_header.to_bytes(),
+            "write block header",
+            Some(block_index),
+        )?;
         // - Write Block Data
-        // -- write block data to file
-        let write_result = self.file_writer.write(&data[..]);
-        if write_result.is_err() {
-            return Err(Error {
-                code: 7,
-                message: "Could not write to file".to_string(),
-            });
-        }
-        let write_size = write_result.unwrap();
-        self.write_pointer += write_size as u64;
-        // -- verify write operation was successful
-        if write_size != data.len() {
-            return Err(Error {
-                code: 9,
-                message: "Could not write all data to file".to_string(),
-            });
-        }
+        // -- write block data after block header
+        let data_offset = block_offset + BLOCK_HEADER_SIZE as u64;
+        self.write_file_at(
+            data_offset,
+            &block_data,
+            "write block data",
+            Some(block_index),
+        )?;
+        let write_end_offset = data_offset + block_data.len() as u64;
         // - update free_blocks map
-        let block_index = block_index as u32;
-        self.free_blocks.remove(&block_index);
+        if block_data.is_empty() {
+            // block header with data size 0 marks a free block
+            self.free_blocks.insert(block_index);
+        } else {
+            self.free_blocks.remove(&block_index);
+        }
         // - update max_block_index
         if block_index >= self.end_block_count {
+            // blocks skipped over are zero filled, so they are free
+            self.free_blocks.extend(self.end_block_count..block_index);
             self.end_block_count = block_index + 1;
         }
-        // return write pointer
-        Ok(self.write_pointer as usize)
-    }
-    pub fn delete_block(&mut self, block_index: usize, hard_delete: bool) -> Result<usize, Error> {
-        let block_index = block_index as u32;
-        if !self.block_exists(block_index as u32) {
-            return Ok(self.write_pointer as usize);
-        } else if hard_delete == false && self.free_blocks.contains(&block_index) {
-            return Ok(self.write_pointer as usize);
+        self.written_past_preallocated(block_index);
+        self.write_bitmap_bit(block_index)?;
+        if block_data.is_empty() {
+            self.uncache_block(block_index);
+            self.track_expiry(block_index, None);
+            self.track_content(block_index, None);
+        } else {
+            self.cache_block(block_index, data);
+            self.track_expiry(block_index, block_header.expires_at);
+            self.track_content(block_index, Some(data));
         }
-        use std::io::prelude::*;
-        let block_length = self.header.block_len;
-        let block_offset = STORAGE_HEADER_SIZE as usize
-            + block_index as usize * (BLOCK_HEADER_SIZE as usize + block_length as usize);
-        // - seek writer to block offset
-        let seek_result = self
-            .file_writer
-            .seek(std::io::SeekFrom::Start(block_offset as u64));
-        if seek_result.is_err() {
-            return Err(Error {
-                code: 10,
-                message: "Could not seek to block offset".to_string(),
-            });
+        for observer in self.observers.iter_mut() {
+            observer.after_write(block_index, data);
         }
-        // -- verify seek operation was successful
-        let seek_position = seek_result.unwrap();
-        if seek_position != block_offset as u64 {
-            return Err(Error {
-                code: 10,
-                message: "Could not seek to block offset".to_string(),
-            });
+        self.check_block_consistency(block_index)?;
+        self.written(1)?;
+        // return end offset of written block data
+        Ok(write_end_offset)
+    }
+    /// Delete block, marking it free
+    /// - hard_delete: also overwrite block data with zeros
+    /// - BlockPinned if block is pinned, see pin_block
+    /// - AppendOnly if storage file is append-only, see enable_append_only
+    /// - with auto trim enabled, deleting last block truncates the free tail of the file
+    /// - returns: end offset of written block header or zeros, 0 if nothing was deleted
+    #[cfg_attr(
+        feature = "tracing",
+        tracing::instrument(level = "trace", skip(self), err)
+    )]
+    pub fn delete_block(
+        &mut self,
+        block_index: BlockIndex,
+        hard_delete: bool,
+    ) -> Result<u64, StorageError> {
+        if !self.block_exists(block_index)
+            || (!hard_delete && self.free_blocks.contains(&block_index))
+        {
+            return Ok(0);
+        }
+        self.check_not_pinned(block_index)?;
+        if self.is_append_only() {
+            return Err(StorageError::AppendOnly { block_index });
         }
-        self.write_pointer = block_offset as u64;
+        self.preserve_for_snapshots(block_index)?;
+        let block_length = self.header.block_len;
+        let block_offset = self.block_offset(block_index)?;
         // - Write Block Header
         // -- write block header to inital BLOCK_HEADER_SIZE bytes
-        let block_header = BlockHeader::new(0);
-        let write_result = self.file_writer.write(&block_header.to_bytes());
-        if write_result.is_err() {
-            return Err(Error {
-                code: 11,
-                message: "Could not write to file".to_string(),
-            });
-        }
-        let write_size = write_result.unwrap();
-        self.write_pointer += write_size as u64;
-        // -- verify write operation was successful
-        if write_size != BLOCK_HEADER_SIZE {
-            return Err(Error {
-                code: 12,
-                message: "Could not write all data to file".to_string(),
-            });
-        }
+        // -- a free block keeps the generation of its delete
+        let block_header = BlockHeader::for_data(&[]).with_generation(self.next_generation());
+        self.write_file_at(
+            block_offset,
+            &block_header.to_bytes(),
+            "write block header",
+            Some(block_index),
+        )?;
+        let mut write_end_offset = block_offset + BLOCK_HEADER_SIZE as u64;
         // - hard delete block
-        if hard_delete == true {
-            // post successful block header write, writer pointer must be at data offset
-            // - overwrite full block with zeros
-            let block_data_of_zeros = vec![0u8; block_length as usize];
-            let write_result = self.file_writer.write(&block_data_of_zeros[..]);
-            if write_result.is_err() {
-                return Err(Error {
-                    code: 13,
-                    message: "Could not write to file".to_string(),
-                });
+        if hard_delete {
+            // - overwrite full block data with zeros, a chunk at a time so large blocks
+            //   are not zero filled in memory first
+            let zeros = vec![0u8; block_length.min(ZERO_FILL_CHUNK_LEN) as usize];
+            let block_end_offset = write_end_offset + block_length;
+            while write_end_offset < block_end_offset {
+                let chunk_len = (block_end_offset - write_end_offset).min(zeros.len() as u64);
+                self.write_file_at(
+                    write_end_offset,
+                    &zeros[..chunk_len as usize],
+                    "zero fill block data",
+                    Some(block_index),
+                )?;
+                write_end_offset += chunk_len;
             }
-            let write_size = write_result.unwrap();
-            // -- verify write operation was successful
-            if write_size != block_length as usize {
-                return Err(Error {
-                    code: 14,
-                    message: "Could not write all data to file".to_string(),
-                });
-            }
-            // -- increment write pointer
-            self.write_pointer += write_size as u64;
         }
         // update free_blocks map
         self.free_blocks.insert(block_index);
-        // return write pointer
-        Ok(self.write_pointer as usize)
+        self.write_bitmap_bit(block_index)?;
+        self.uncache_block(block_index);
+        self.track_expiry(block_index, None);
+        self.track_content(block_index, None);
+        for observer in self.observers.iter_mut() {
+            observer.after_delete(block_index, hard_delete);
+        }
+        self.check_block_consistency(block_index)?;
+        self.written(1)?;
+        if self.auto_trim && block_index + 1 == self.end_block_count {
+            self.trim_tail()?;
+        }
+        // return end offset of written bytes
+        Ok(write_end_offset)
+    }
+    /// Write all bytes to storage file at offset, with positional IO
+    /// - operation and block_index describe the write in errors
+    pub(super) fn write_file_at(
+        &self,
+        offset: u64,
+        bytes: &[u8],
+        operation: &'static str,
+        block_index: Option<BlockIndex>,
+    ) -> Result<(), StorageError> {
+        if self.read_only {
+            return Err(StorageError::ReadOnly);
+        }
+        let written = positional::write_at(&self.file_writer, offset, bytes)
+            .map_err(StorageError::io(operation, block_index))
+            .and_then(|write_size| match write_size == bytes.len() {
+                true => Ok(()),
+                false => Err(StorageError::incomplete_write(operation, block_index)),
+            });
+        if written.is_err() {
+            self.write_failed.store(true, Ordering::Relaxed);
+        }
+        written
     }
 
     // ... ... ... ... ... ... ... ... ... ... ... ... ... ... ... ... ...
@@ -0,0 +1,408 @@
+use super::snapshot::{BlockContent, SnapshotBlock};
+use super::*;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex, MutexGuard};
+
+/// Reads of a block that changes while it is read, before its error is returned
+const VIEW_READ_ATTEMPTS: usize = 16;
+
+/// Content of a block, kept for read views after the block changed
+struct Version {
+    block: SnapshotBlock,
+    /// Generation from which the block had other content
+    superseded_at: Generation,
+}
+
+/// Versions of blocks changed while read views of older generations were open
+/// - shared by a Storage and its ReadViews
+#[derive(Default)]
+pub(super) struct VersionStore {
+    /// Generation of each open read view, with the number of views at it
+    views: BTreeMap<Generation, usize>,
+    /// Versions of each block by generation of the version
+    versions: HashMap<BlockIndex, BTreeMap<Generation, Version>>,
+}
+
+impl VersionStore {
+    /// Check if an open view sees version of block at generation, and it is not kept yet
+    fn needs(&self, block_index: BlockIndex, generation: Generation) -> bool {
+        let kept = self
+            .versions
+            .get(&block_index)
+            .is_some_and(|versions| versions.contains_key(&generation));
+        !kept && self.views.range(generation..).next().is_some()
+    }
+    /// Drop versions no open view sees
+    fn collect_garbage(&mut self) {
+        let views = &self.views;
+        self.versions.retain(|_, versions| {
+            versions.retain(|generation, version| {
+                views
+                    .range(*generation..version.superseded_at)
+                    .next()
+                    .is_some()
+            });
+            !versions.is_empty()
+        });
+    }
+}
+
+fn lock(versions: &Mutex<VersionStore>) -> MutexGuard<'_, VersionStore> {
+    // version store stays consistent even if a holder panicked, entries are inserted whole
+    versions
+        .lock()
+        .unwrap_or_else(|poisoned| poisoned.into_inner())
+}
+
+/// Block header and data of a block read by a ReadView, as found in storage file
+enum FileBlock {
+    /// Block is as the view sees it, None if free
+    Visible(Option<BlockContent>),
+    /// Block changed after the view was opened, or was trimmed from the file
+    Changed,
+    /// Block could not be read whole, it may be written at the same time
+    Torn(StorageError),
+}
+
+/// Read only view of a Storage at a generation, for reads from other threads while the
+/// storage is written (multi-version concurrency control)
+/// - reads do not lock the storage: they read the storage file with their own file
+///   handle, blocks written after the view was opened are read from versions the
+///   storage keeps, see Storage::read_view
+/// - a block read while it is written fails its checksum and is read again
+/// - Send and Sync, a view can be shared by reader threads
+/// - dropping the view drops versions only it needed
+pub struct ReadView {
+    versions: Arc<Mutex<VersionStore>>,
+    generation: Generation,
+    end_block_count: u64,
+    file: File,
+    bitmap_capacity: u32,
+    block_len: u64,
+    cipher: Option<crypto::Cipher>,
+}
+
+impl ReadView {
+    /// Generation of the storage when the view was opened, see Storage::generation
+    pub fn generation(&self) -> Generation {
+        self.generation
+    }
+    /// Number of blocks in storage file when the view was opened
+    pub fn block_count(&self) -> u64 {
+        self.end_block_count
+    }
+    /// Read block data as it was when the view was opened
+    /// - returns: empty vector for blocks that were free
+    pub fn read_block(&self, block_index: BlockIndex) -> Result<Vec<u8>, StorageError> {
+        match self.block(block_index)? {
+            Some(content) => self.decode(block_index, content),
+            None => Ok(Vec::new()),
+        }
+    }
+    /// Read all data of a record as it was when the view was opened
+    pub fn read_record(&self, record_id: RecordId) -> Result<Vec<u8>, StorageError> {
+        let mut record = Vec::new();
+        let mut block_index = record_id;
+        // - a record can not have more blocks than the file, longer chains loop
+        for _ in 0..=self.end_block_count {
+            match self.block(block_index)? {
+                None if record.is_empty() => return Ok(record),
+                None => break,
+                Some(content) => {
+                    let next_block = content.next_block;
+                    record.append(&mut self.decode(block_index, content)?);
+                    match next_block {
+                        Some(next_block) => block_index = next_block,
+                        None => return Ok(record),
+                    }
+                }
+            }
+        }
+        Err(StorageError::Corruption {
+            block_index: Some(record_id),
+            reason: "record block chain is broken",
+        })
+    }
+    /// Stored content of block at generation of the view, None if it was free
+    fn block(&self, block_index: BlockIndex) -> Result<Option<BlockContent>, StorageError> {
+        if block_index >= self.end_block_count {
+            return Ok(None);
+        }
+        let mut torn_error = None;
+        for _ in 0..VIEW_READ_ATTEMPTS {
+            match self.read_file_block(block_index)? {
+                FileBlock::Visible(content) => return Ok(content),
+                FileBlock::Changed => return self.kept_version(block_index),
+                FileBlock::Torn(error) => torn_error = Some(error),
+            }
+            std::thread::yield_now();
+        }
+        Err(torn_error.unwrap_or(StorageError::ChecksumMismatch { block_index }))
+    }
+    /// Read block header and data of block from storage file
+    fn read_file_block(&self, block_index: BlockIndex) -> Result<FileBlock, StorageError> {
+        let truncated = |reason| {
+            FileBlock::Torn(StorageError::Corruption {
+                block_index: Some(block_index),
+                reason,
+            })
+        };
+        let block_offset = block_offset(self.bitmap_capacity, self.block_len, block_index)
+            .ok_or(StorageError::BlockOutOfRange { block_index })?;
+        let mut block_header_bytes = [0u8; BLOCK_HEADER_SIZE];
+        let read_size = positional::read_at(&self.file, block_offset, &mut block_header_bytes)
+            .map_err(StorageError::io("read block header", Some(block_index)))?;
+        if read_size == 0 {
+            return Ok(FileBlock::Changed);
+        }
+        if read_size != BLOCK_HEADER_SIZE {
+            return Ok(truncated("block header is truncated"));
+        }
+        let block_header = BlockHeader::from_bytes(&block_header_bytes);
+        if block_header.generation > self.generation {
+            return Ok(FileBlock::Changed);
+        }
+        if block_header.block_data_size == 0 {
+            return Ok(FileBlock::Visible(None));
+        }
+        if block_header.block_data_size > self.block_len {
+            return Ok(truncated("data size in block header exceeds block length"));
+        }
+        let mut block_data = vec![0u8; block_header.data_len(block_index)?];
+        let data_offset = block_offset + BLOCK_HEADER_SIZE as u64;
+        let read_size = positional::read_at(&self.file, data_offset, &mut block_data)
+            .map_err(StorageError::io("read block data", Some(block_index)))?;
+        if read_size != block_data.len() {
+            return Ok(truncated("block data is truncated"));
+        }
+        if checksum::crc32(&block_data) != block_header.checksum {
+            return Ok(FileBlock::Torn(StorageError::ChecksumMismatch {
+                block_index,
+            }));
+        }
+        Ok(FileBlock::Visible(Some(BlockContent {
+            block_data,
+            block_flags: block_header.flags,
+            next_block: block_header.next_block,
+            expires_at: block_header.expires_at,
+            tag: block_header.tag,
+            generation: block_header.generation,
+        })))
+    }
+    /// Version of block the view sees, kept by the storage when the block changed
+    fn kept_version(&self, block_index: BlockIndex) -> Result<Option<BlockContent>, StorageError> {
+        let versions = lock(&self.versions);
+        let version = versions
+            .versions
+            .get(&block_index)
+            .and_then(|versions| versions.range(..=self.generation).next_back());
+        match version.map(|(_, version)| &version.block) {
+            None | Some(SnapshotBlock::Free) => Ok(None),
+            Some(SnapshotBlock::Used(content)) => Ok(Some(content.clone())),
+            Some(SnapshotBlock::Corrupted) => Err(StorageError::Corruption {
+                block_index: Some(block_index),
+                reason: "block could not be read when read view kept it",
+            }),
+        }
+    }
+    /// Decrypt and decompress stored block data
+    fn decode(
+        &self,
+        block_index: BlockIndex,
+        content: BlockContent,
+    ) -> Result<Vec<u8>, StorageError> {
+        let block_data = crypto::decrypt_block(
+            self.cipher.as_ref(),
+            block_index,
+            content.block_data,
+            content.block_flags,
+        )?;
+        compression::decompress_block(block_index, block_data, content.block_flags, self.block_len)
+    }
+}
+
+impl Drop for ReadView {
+    fn drop(&mut self) {
+        let mut versions = lock(&self.versions);
+        if let Some(count) = versions.views.get_mut(&self.generation) {
+            *count -= 1;
+            if *count == 0 {
+                versions.views.remove(&self.generation);
+            }
+        }
+        versions.collect_garbage();
+    }
+}
+
+impl Storage {
+    // ... ... ... ... ... ... ... ... ... Read Views ... ... ... ... ... ... ... ... ... ..
+
+    /// Open a read view at the current generation, see ReadView
+    /// - from then on, a block that changes while a view sees it is read and kept in
+    ///   memory before it is written, until no open view sees it any more
+    /// - Transform error if block transforms are set, a view can not reverse them
+    /// - block data is decrypted with the key given to enable_encryption, if any
+    pub fn read_view(&mut self) -> Result<ReadView, StorageError> {
+        if self.has_block_transforms() {
+            return Err(StorageError::Transform {
+                message: "Read views can not reverse block transforms".to_string(),
+            });
+        }
+        let file = Storage::open_file_reader(&self.file_path)?;
+        let versions = self.versions.get_or_insert_with(Default::default).clone();
+        *lock(&versions)
+            .views
+            .entry(self.header.generation)
+            .or_insert(0) += 1;
+        Ok(ReadView {
+            versions,
+            generation: self.header.generation,
+            end_block_count: self.end_block_count,
+            file,
+            bitmap_capacity: self.header.bitmap_capacity,
+            block_len: self.header.block_len,
+            cipher: self.pipeline.encryption.cipher.clone(),
+        })
+    }
+    /// Number of block versions kept in memory for open read views
+    pub fn kept_versions(&self) -> usize {
+        self.versions.as_ref().map_or(0, |versions| {
+            lock(versions).versions.values().map(BTreeMap::len).sum()
+        })
+    }
+    /// Keep current content of block for open read views that see it
+    /// - called before block is changed in storage file
+    pub(super) fn preserve_versions(
+        &mut self,
+        block_index: BlockIndex,
+    ) -> Result<(), StorageError> {
+        let versions = match self.versions.as_ref() {
+            Some(versions) if !lock(versions).views.is_empty() => versions.clone(),
+            _ => return Ok(()),
+        };
+        if !self.block_exists(block_index) {
+            return Ok(());
+        }
+        let generation = self.read_block_header(block_index)?.generation;
+        if !lock(&versions).needs(block_index, generation) {
+            return Ok(());
+        }
+        let block = match self.current_block(block_index) {
+            Ok(None) => SnapshotBlock::Free,
+            Ok(Some(content)) => SnapshotBlock::Used(content),
+            Err(error) if error.is_corruption() => SnapshotBlock::Corrupted,
+            Err(error) => return Err(error),
+        };
+        // - views open now are below the generation of the change
+        let version = Version {
+            block,
+            superseded_at: self.header.generation.saturating_add(1),
+        };
+        lock(&versions)
+            .versions
+            .entry(block_index)
+            .or_default()
+            .insert(generation, version);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod unit_tests_mvcc {
+    use super::*;
+
+    fn new_storage(tmp_dir: &tempfile::TempDir) -> Storage {
+        let file_path = tmp_dir.path().join("mvcc.hex");
+        Storage::new(file_path.to_str().unwrap().to_string(), 4).unwrap()
+    }
+
+    #[test]
+    fn test_read_view() {
+        let tmp_dir = tempfile::tempdir().unwrap();
+        let mut storage = new_storage(&tmp_dir);
+        storage.write_block(0, &[1]).unwrap();
+        storage.write_block(1, &[2]).unwrap();
+        let record_id = storage.write_record(&[3; 10]).unwrap();
+        let view = storage.read_view().unwrap();
+        storage.write_block(0, &[4]).unwrap();
+        storage.write_block(0, &[5]).unwrap();
+        storage.delete_block(1, true).unwrap();
+        storage.delete_record(record_id, false).unwrap();
+        storage.write_record(&[6; 10]).unwrap();
+        storage.write_block(9, &[7]).unwrap();
+        // - view sees blocks as they were when it was opened
+        assert_eq!(view.read_block(0).unwrap(), vec![1]);
+        assert_eq!(view.read_block(1).unwrap(), vec![2]);
+        assert_eq!(view.read_record(record_id).unwrap(), vec![3; 10]);
+        assert!(view.read_block(9).unwrap().is_empty());
+        assert_eq!(storage.read_block(0).unwrap().1, vec![5]);
+        // - block overwritten twice is kept once, blocks that were free are not kept
+        assert_eq!(storage.kept_versions(), 5);
+        // - a later view sees later writes, versions only the first view saw are dropped
+        let later_view = storage.read_view().unwrap();
+        storage.write_block(0, &[8]).unwrap();
+        drop(view);
+        assert_eq!(storage.kept_versions(), 1);
+        assert_eq!(later_view.read_block(0).unwrap(), vec![5]);
+        assert_eq!(later_view.read_block(1).unwrap(), vec![6; 4]);
+        assert!(later_view.read_block(4).unwrap().is_empty());
+        drop(later_view);
+        assert_eq!(storage.kept_versions(), 0);
+        // - no versions are kept without open views
+        storage.write_block(0, &[9]).unwrap();
+        assert_eq!(storage.kept_versions(), 0);
+    }
+    #[test]
+    fn test_read_view_threads() {
+        let tmp_dir = tempfile::tempdir().unwrap();
+        let mut storage = new_storage(&tmp_dir);
+        for block_index in 0..16 {
+            storage
+                .write_block(block_index, &[block_index as u8; 4])
+                .unwrap();
+        }
+        let view = storage.read_view().unwrap();
+        std::thread::scope(|scope| {
+            for _ in 0..4 {
+                scope.spawn(|| {
+                    for _ in 0..50 {
+                        for block_index in 0..16 {
+                            let expected = vec![block_index as u8; 4];
+                            assert_eq!(view.read_block(block_index).unwrap(), expected);
+                        }
+                    }
+                });
+            }
+            // - writes go on while the view is read
+            for round in 1..50u8 {
+                let payloads: Vec<Vec<u8>> = (0..16).map(|_| vec![round; 4]).collect();
+                let payloads: Vec<&[u8]> = payloads.iter().map(Vec::as_slice).collect();
+                storage.write_range(0, &payloads).unwrap();
+            }
+        });
+        assert_eq!(storage.read_block(3).unwrap().1, vec![49; 4]);
+    }
+    #[test]
+    fn test_read_view_rejects_transforms() {
+        let tmp_dir = tempfile::tempdir().unwrap();
+        let mut storage = new_storage(&tmp_dir);
+        storage.push_transform(Box::new(Identity)).unwrap();
+        assert!(matches!(
+            storage.read_view(),
+            Err(StorageError::Transform { .. })
+        ));
+    }
+
+    struct Identity;
+    impl BlockTransform for Identity {
+        fn name(&self) -> &str {
+            "identity"
+        }
+        fn encode(&self, data: &[u8]) -> Result<Vec<u8>, StorageError> {
+            Ok(data.to_vec())
+        }
+        fn decode(&self, data: &[u8]) -> Result<Vec<u8>, StorageError> {
+            Ok(data.to_vec())
+        }
+    }
+}
@@ -0,0 +1,113 @@
+use super::*;
+
+/// Callbacks notified of block changes in a Storage
+/// - all methods default to no-op, implement only the events of interest
+/// - called synchronously from the Storage operation, keep them cheap
+pub trait BlockObserver: Send {
+    /// Called before block data is written to storage file
+    /// - not called when the write is rejected first, e.g. with BlockTooLarge
+    fn before_write(&mut self, _block_index: BlockIndex, _data: &[u8]) {}
+    /// Called after block data was written to storage file
+    fn after_write(&mut self, _block_index: BlockIndex, _data: &[u8]) {}
+    /// Called after block was deleted from storage file
+    /// - not called when delete was a no-op (block missing or already soft deleted)
+    fn after_delete(&mut self, _block_index: BlockIndex, _hard_delete: bool) {}
+    /// Called when stored block data does not match checksum in its block header
+    /// - called from read_block and verify_all, before the error is returned
+    fn on_checksum_mismatch(&mut self, _block_index: BlockIndex) {}
+}
+
+impl Storage {
+    /// Register observer notified of block writes and deletes
+    /// - observers are called in registration order
+    pub fn add_observer(&mut self, observer: Box<dyn BlockObserver>) {
+        self.observers.push(observer);
+    }
+    /// Drop all registered observers
+    pub fn clear_observers(&mut self) {
+        self.observers.clear();
+    }
+}
+
+#[cfg(test)]
+mod unit_tests_observer {
+    use super::*;
+    use std::sync::{Arc, Mutex};
+
+    struct RecordingObserver {
+        events: Arc<Mutex<Vec<String>>>,
+    }
+    impl BlockObserver for RecordingObserver {
+        fn before_write(&mut self, block_index: BlockIndex, data: &[u8]) {
+            let event = format!("before_write {} {}", block_index, data.len());
+            self.events.lock().unwrap().push(event);
+        }
+        fn after_write(&mut self, block_index: BlockIndex, data: &[u8]) {
+            let event = format!("after_write {} {}", block_index, data.len());
+            self.events.lock().unwrap().push(event);
+        }
+        fn after_delete(&mut self, block_index: BlockIndex, hard_delete: bool) {
+            let event = format!("after_delete {} {}", block_index, hard_delete);
+            self.events.lock().unwrap().push(event);
+        }
+        fn on_checksum_mismatch(&mut self, block_index: BlockIndex) {
+            let event = format!("on_checksum_mismatch {}", block_index);
+            self.events.lock().unwrap().push(event);
+        }
+    }
+
+    #[test]
+    fn test_observer_events() {
+        let tmp_dir = tempfile::tempdir().unwrap();
+        let file_path = tmp_dir.path().join("observer.hex");
+        let mut storage = Storage::new(file_path.to_str().unwrap().to_string(), 8).unwrap();
+        let events = Arc::new(Mutex::new(Vec::new()));
+        storage.add_observer(Box::new(RecordingObserver {
+            events: events.clone(),
+        }));
+        storage.write_block(0, &[1, 2, 3]).unwrap();
+        storage.delete_block(0, false).unwrap();
+        // no-op deletes are not reported
+        storage.delete_block(0, false).unwrap();
+        storage.delete_block(5, true).unwrap();
+        storage.delete_block(0, true).unwrap();
+        // rejected writes are not reported
+        assert!(storage.write_block(1, &[0; 9]).is_err());
+        assert_eq!(
+            *events.lock().unwrap(),
+            vec![
+                "before_write 0 3",
+                "after_write 0 3",
+                "after_delete 0 false",
+                "after_delete 0 true",
+            ]
+        );
+        // cleared observers are not called
+        storage.clear_observers();
+        storage.write_block(1, &[1]).unwrap();
+        assert_eq!(events.lock().unwrap().len(), 4);
+    }
+    #[test]
+    fn test_observer_checksum_mismatch() {
+        let tmp_dir = tempfile::tempdir().unwrap();
+        let file_path = tmp_dir.path().join("observer.hex");
+        let file_path = file_path.to_str().unwrap().to_string();
+        let mut storage = Storage::new(file_path.clone(), 8).unwrap();
+        storage.write_block(0, &[1, 2, 3]).unwrap();
+        // flip first data byte of block 0
+        let data_offset = storage.block_offset(0).unwrap() as usize + BLOCK_HEADER_SIZE;
+        let mut file_bytes = std::fs::read(&file_path).unwrap();
+        file_bytes[data_offset] ^= 1;
+        std::fs::write(&file_path, file_bytes).unwrap();
+        let events = Arc::new(Mutex::new(Vec::new()));
+        storage.add_observer(Box::new(RecordingObserver {
+            events: events.clone(),
+        }));
+        assert!(storage.read_block(0).is_err());
+        assert_eq!(storage.verify_all().unwrap(), vec![0]);
+        assert_eq!(
+            *events.lock().unwrap(),
+            vec!["on_checksum_mismatch 0", "on_checksum_mismatch 0"]
+        );
+    }
+}
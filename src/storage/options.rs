@@ -0,0 +1,417 @@
+use super::*;
+use std::fmt;
+
+/// Settings to create or open a storage file with, see StorageOptions::open
+/// - settings not stored in the file (durability, cache, allocation, quota, transforms,
+///   key) must be given every time it is opened
+/// - e.g. StorageOptions::new(file_path).block_len(4096).create(true).open()
+#[derive(Clone)]
+pub struct StorageOptions {
+    file_path: String,
+    block_len: Option<u64>,
+    bitmap_capacity: u32,
+    create: bool,
+    truncate: bool,
+    read_only: bool,
+    lock: bool,
+    durability: DurabilityMode,
+    cache: Option<(usize, CachePolicy)>,
+    compression: Option<Compression>,
+    transforms: Vec<Arc<dyn BlockTransform + Sync>>,
+    encryption: Option<EncryptionKey>,
+    allocation: AllocationStrategy,
+    auto_trim: bool,
+    max_bytes: Option<u64>,
+}
+
+impl StorageOptions {
+    /// Options to open an existing storage file at file_path, every setting at its default
+    pub fn new(file_path: String) -> Self {
+        StorageOptions {
+            file_path,
+            block_len: None,
+            bitmap_capacity: DEFAULT_BITMAP_CAPACITY,
+            create: false,
+            truncate: false,
+            read_only: false,
+            lock: true,
+            durability: DurabilityMode::default(),
+            cache: None,
+            compression: None,
+            transforms: Vec::new(),
+            encryption: None,
+            allocation: AllocationStrategy::default(),
+            auto_trim: false,
+            max_bytes: None,
+        }
+    }
+    /// Block length of a new storage file, within 1..=MAX_BLOCK_LEN
+    /// - an existing file must have this block length, else InvalidBlockLength of the
+    ///   file is returned
+    pub fn block_len(mut self, block_len: u64) -> Self {
+        self.block_len = Some(block_len);
+        self
+    }
+    /// Block length of the file, if open creates one and no block_len was given
+    pub(super) fn default_block_len(mut self, block_len: u64) -> Self {
+        if self.block_len.is_none() && self.creates_file() {
+            self.block_len = Some(block_len);
+        }
+        self
+    }
+    /// open creates a new file, or truncates the file
+    fn creates_file(&self) -> bool {
+        self.truncate || (self.create && !std::path::Path::new(&self.file_path).exists())
+    }
+    /// Names of transforms, in pipeline order
+    fn transform_names(&self) -> Vec<&str> {
+        self.transforms
+            .iter()
+            .map(|transform| transform.name())
+            .collect()
+    }
+    /// Blocks tracked by allocation bitmap of a new storage file, see
+    /// Storage::new_with_bitmap_capacity
+    /// - default is DEFAULT_BITMAP_CAPACITY, an existing file keeps its bitmap
+    pub fn bitmap_capacity(mut self, bitmap_capacity: u32) -> Self {
+        self.bitmap_capacity = bitmap_capacity;
+        self
+    }
+    /// Create a new storage file if none exists at file_path, block_len must be given
+    pub fn create(mut self, create: bool) -> Self {
+        self.create = create;
+        self
+    }
+    /// Create a new storage file, overwriting any file at file_path, like Storage::new
+    pub fn truncate(mut self, truncate: bool) -> Self {
+        self.truncate = truncate;
+        self
+    }
+    /// Open storage file without writing to it
+    /// - every write fails with ReadOnly, also the dirty flag is not set, so the file is
+    ///   left as it was found
+    /// - a file that was not closed cleanly is scanned on every read-only open
+    /// - can not be combined with create or truncate
+    pub fn read_only(mut self, read_only: bool) -> Self {
+        self.read_only = read_only;
+        self
+    }
+    /// Lock storage file while it is open, so no other storage object writes it at once
+    /// - default is true: exclusive lock, shared lock for read_only, see lock
+    /// - false opens the file whatever locks others hold, and takes none; keeping
+    ///   storage objects of the file in line is up to the caller, e.g. with
+    ///   Storage::rebuild_free_list
+    pub fn lock(mut self, lock: bool) -> Self {
+        self.lock = lock;
+        self
+    }
+    /// When writes are synced to disk, see Storage::set_durability
+    pub fn durability(mut self, mode: DurabilityMode) -> Self {
+        self.durability = mode;
+        self
+    }
+    /// Enable block cache, see Storage::enable_cache
+    pub fn cache(mut self, capacity_bytes: usize, policy: CachePolicy) -> Self {
+        self.cache = Some((capacity_bytes, policy));
+        self
+    }
+    /// Compression of blocks written, see Storage::set_compression
+    /// - stored in storage header, an existing file keeps its compression if not given
+    pub fn compression(mut self, compression: Compression) -> Self {
+        self.compression = Some(compression);
+        self
+    }
+    /// Append block transform to the pipeline, see Storage::push_transform
+    /// - transforms given must be the pipeline recorded in the file, else open fails
+    ///   with PipelineMismatch; a file without used blocks records them
+    /// - shared, so the same transform serves every open of the options
+    pub fn transform(mut self, transform: Arc<dyn BlockTransform + Sync>) -> Self {
+        self.transforms.push(transform);
+        self
+    }
+    /// Encrypt blocks written with key, see Storage::enable_encryption
+    /// - an encrypted file needs its key to read encrypted blocks and to write
+    pub fn encryption(mut self, key: EncryptionKey) -> Self {
+        self.encryption = Some(key);
+        self
+    }
+    /// How allocate picks blocks for new data, see Storage::set_allocation_strategy
+    pub fn allocation_strategy(mut self, strategy: AllocationStrategy) -> Self {
+        self.allocation = strategy;
+        self
+    }
+    /// Trim free tail of file when last block is deleted, see Storage::set_auto_trim
+    pub fn auto_trim(mut self, auto_trim: bool) -> Self {
+        self.auto_trim = auto_trim;
+        self
+    }
+    /// Quota of storage file in bytes, see Storage::max_bytes
+    pub fn max_bytes(mut self, max_bytes: u64) -> Self {
+        self.max_bytes = Some(max_bytes);
+        self
+    }
+    /// Create or open storage file with these options
+    /// - ReadOnly if read_only is combined with create or truncate
+    /// - InvalidBlockLength of 0 if a new file is created without block_len
+    /// - PipelineMismatch if transforms are given and the file records another pipeline
+    pub fn open(self) -> Result<Storage, StorageError> {
+        if self.read_only && (self.create || self.truncate) {
+            return Err(StorageError::ReadOnly);
+        }
+        let file_lock = match (self.lock, self.read_only) {
+            (false, _) => FileLock::Unlocked,
+            (true, true) => FileLock::Shared,
+            (true, false) => FileLock::Exclusive,
+        };
+        let mut storage = if self.creates_file() {
+            let block_len = self.block_len.unwrap_or(0);
+            Storage::create_file(self.file_path, block_len, self.bitmap_capacity, file_lock)?
+        } else {
+            let storage = Storage::open_file(self.file_path, self.read_only, file_lock)?;
+            match self.block_len {
+                Some(block_len) if block_len != storage.block_len() => {
+                    return Err(StorageError::InvalidBlockLength {
+                        block_len: storage.block_len(),
+                    });
+                }
+                _ => storage,
+            }
+        };
+        storage.set_durability(self.durability);
+        if let Some((capacity_bytes, policy)) = self.cache {
+            storage.enable_cache(capacity_bytes, policy);
+        }
+        if let Some(compression) = self.compression {
+            if compression != storage.compression() {
+                storage.set_compression(compression)?;
+            }
+        }
+        if let Some(key) = self.encryption {
+            storage.enable_encryption(&StaticKey(key))?;
+        }
+        let check_pipeline = !self.transforms.is_empty();
+        for transform in self.transforms {
+            storage.push_transform(Box::new(transform))?;
+        }
+        if check_pipeline {
+            storage.check_pipeline()?;
+        }
+        storage.set_allocation_strategy(self.allocation);
+        storage.set_auto_trim(self.auto_trim);
+        storage.max_bytes = self.max_bytes;
+        Ok(storage)
+    }
+}
+
+/// Transforms are shown by name, the key is not shown
+impl fmt::Debug for StorageOptions {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("StorageOptions")
+            .field("file_path", &self.file_path)
+            .field("block_len", &self.block_len)
+            .field("bitmap_capacity", &self.bitmap_capacity)
+            .field("create", &self.create)
+            .field("truncate", &self.truncate)
+            .field("read_only", &self.read_only)
+            .field("lock", &self.lock)
+            .field("durability", &self.durability)
+            .field("cache", &self.cache)
+            .field("compression", &self.compression)
+            .field("transforms", &self.transform_names())
+            .field("encryption", &self.encryption.is_some())
+            .field("allocation", &self.allocation)
+            .field("auto_trim", &self.auto_trim)
+            .field("max_bytes", &self.max_bytes)
+            .finish()
+    }
+}
+
+/// Transforms are compared by name
+impl PartialEq for StorageOptions {
+    fn eq(&self, other: &Self) -> bool {
+        self.file_path == other.file_path
+            && self.block_len == other.block_len
+            && self.bitmap_capacity == other.bitmap_capacity
+            && self.create == other.create
+            && self.truncate == other.truncate
+            && self.read_only == other.read_only
+            && self.lock == other.lock
+            && self.durability == other.durability
+            && self.cache == other.cache
+            && self.compression == other.compression
+            && self.transform_names() == other.transform_names()
+            && self.encryption == other.encryption
+            && self.allocation == other.allocation
+            && self.auto_trim == other.auto_trim
+            && self.max_bytes == other.max_bytes
+    }
+}
+
+impl Storage {
+    // ... ... ... ... ... ... ... ... ... ... Quota ... ... ... ... ... ... ... ... ... ...
+
+    /// Quota of storage file in bytes, None if it has none, see StorageOptions::max_bytes
+    /// - quota is counted in whole blocks: a block past the end of the file is only
+    ///   written if the file, up to the end of its block_len bytes of data, stays within
+    ///   max_bytes
+    /// - storage header and bitmap count too, they are written when the file is created
+    ///   whatever the quota
+    /// - blocks in the file can be rewritten and deleted whatever the quota, also in a
+    ///   file opened past its quota
+    pub fn max_bytes(&self) -> Option<u64> {
+        self.max_bytes
+    }
+    /// Bytes of storage file up to the end of its last block, counting every block as
+    /// holding block_len bytes of data
+    /// - the file itself may be shorter, if its last block holds less data
+    pub fn file_bytes(&self) -> u64 {
+        self.block_offset(self.end_block_count).unwrap_or(u64::MAX)
+    }
+    /// Check that writing block does not grow storage file past max_bytes
+    /// - blocks in the file already pass, they only grow the file within their block
+    pub(super) fn check_quota(&self, block_index: BlockIndex) -> Result<(), StorageError> {
+        let max_bytes = match self.max_bytes {
+            Some(max_bytes) if block_index >= self.end_block_count => max_bytes,
+            _ => return Ok(()),
+        };
+        let block_end = block_index
+            .checked_add(1)
+            .and_then(|block_count| self.block_offset(block_count).ok());
+        match block_end {
+            Some(block_end) if block_end <= max_bytes => Ok(()),
+            _ => Err(StorageError::QuotaExceeded {
+                block_index,
+                max_bytes,
+            }),
+        }
+    }
+}
+
+#[cfg(test)]
+mod unit_tests_options {
+    use super::*;
+
+    fn file_path(tmp_dir: &tempfile::TempDir) -> String {
+        let file_path = tmp_dir.path().join("options.hex");
+        file_path.to_str().unwrap().to_string()
+    }
+
+    #[test]
+    fn test_options() {
+        let tmp_dir = tempfile::tempdir().unwrap();
+        let options = StorageOptions::new(file_path(&tmp_dir));
+        assert!(matches!(
+            options.clone().open(),
+            Err(StorageError::Io { .. })
+        ));
+        assert!(matches!(
+            options.clone().create(true).open(),
+            Err(StorageError::InvalidBlockLength { block_len: 0 })
+        ));
+        let mut storage = options
+            .clone()
+            .block_len(8)
+            .create(true)
+            .durability(DurabilityMode::EveryWrite)
+            .cache(64, CachePolicy::Lru)
+            .allocation_strategy(AllocationStrategy::BestFit)
+            .open()
+            .unwrap();
+        assert_eq!(storage.durability(), DurabilityMode::EveryWrite);
+        assert!(storage.cache_stats().is_some());
+        assert_eq!(storage.allocation_strategy(), AllocationStrategy::BestFit);
+        storage.write_block(0, &[1]).unwrap();
+        drop(storage);
+        // - create opens an existing file, truncate overwrites it
+        let mut storage = options.clone().block_len(8).create(true).open().unwrap();
+        assert_eq!(storage.read_block(0).unwrap().1, vec![1]);
+        drop(storage);
+        assert!(matches!(
+            options.clone().block_len(16).open(),
+            Err(StorageError::InvalidBlockLength { block_len: 8 })
+        ));
+        let mut storage = options.block_len(16).truncate(true).open().unwrap();
+        assert!(storage.read_block(0).unwrap().1.is_empty());
+        storage.write_block(0, &[2]).unwrap();
+    }
+    #[test]
+    fn test_read_only() {
+        let tmp_dir = tempfile::tempdir().unwrap();
+        let mut storage = Storage::new(file_path(&tmp_dir), 8).unwrap();
+        storage.write_block(0, &[1]).unwrap();
+        storage.crash();
+        // - read-only open of a file that was not closed cleanly leaves it dirty
+        let file_bytes = std::fs::read(file_path(&tmp_dir)).unwrap();
+        let options = StorageOptions::new(file_path(&tmp_dir)).read_only(true);
+        let mut reader = options.clone().open().unwrap();
+        assert!(reader.is_read_only());
+        assert_eq!(reader.read_block(0).unwrap().1, vec![1]);
+        assert!(matches!(
+            reader.write_block(1, &[2]),
+            Err(StorageError::ReadOnly)
+        ));
+        assert!(matches!(
+            reader.delete_block(0, true),
+            Err(StorageError::ReadOnly)
+        ));
+        assert!(matches!(reader.preallocate(4), Err(StorageError::ReadOnly)));
+        reader.close().unwrap();
+        assert_eq!(std::fs::read(file_path(&tmp_dir)).unwrap(), file_bytes);
+        assert!(matches!(
+            options.clone().create(true).open(),
+            Err(StorageError::ReadOnly)
+        ));
+        Storage::open(file_path(&tmp_dir)).unwrap().close().unwrap();
+        let mut reader = options.open().unwrap();
+        assert_eq!(reader.read_block(0).unwrap().1, vec![1]);
+    }
+    #[test]
+    fn test_quota() {
+        let tmp_dir = tempfile::tempdir().unwrap();
+        // - room for header, bitmap and 4 blocks of 8 bytes
+        let max_bytes = blocks_offset(16) + 4 * 48;
+        let options = StorageOptions::new(file_path(&tmp_dir))
+            .block_len(8)
+            .bitmap_capacity(16)
+            .max_bytes(max_bytes);
+        let mut storage = options.clone().truncate(true).open().unwrap();
+        assert_eq!(storage.max_bytes(), Some(max_bytes));
+        storage.write_record(&[1; 24]).unwrap();
+        storage.write_block(3, &[2]).unwrap();
+        assert!(matches!(
+            storage.write_block(4, &[3]),
+            Err(StorageError::QuotaExceeded { block_index: 4, .. })
+        ));
+        // - a record past the quota is refused before any of its blocks is written
+        storage.delete_block(1, false).unwrap();
+        assert!(matches!(
+            storage.write_record(&[4; 16]),
+            Err(StorageError::QuotaExceeded { .. })
+        ));
+        assert!(storage.read_block(1).unwrap().1.is_empty());
+        assert!(matches!(
+            storage.write_batch(&[&[5; 16]]),
+            Err(StorageError::QuotaExceeded { .. })
+        ));
+        assert!(matches!(
+            storage.preallocate(5),
+            Err(StorageError::QuotaExceeded { block_index: 4, .. })
+        ));
+        // - blocks in the file are rewritten whatever the quota
+        storage.write_block(3, &[6; 8]).unwrap();
+        let stats = storage.stats();
+        assert_eq!((stats.bytes, stats.max_bytes), (max_bytes, Some(max_bytes)));
+        let file_len = std::fs::metadata(file_path(&tmp_dir)).unwrap().len();
+        assert!(file_len <= max_bytes);
+        // - quota is given again on open, not kept in the file
+        drop(storage);
+        let mut storage = Storage::open(file_path(&tmp_dir)).unwrap();
+        assert_eq!(storage.max_bytes(), None);
+        storage.write_block(4, &[3]).unwrap();
+        drop(storage);
+        let mut storage = options.max_bytes(0).open().unwrap();
+        storage.write_block(4, &[7]).unwrap();
+        storage.delete_block(4, true).unwrap();
+        assert!(storage.write_block(5, &[7]).is_err());
+    }
+}
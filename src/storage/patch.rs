@@ -0,0 +1,233 @@
+use super::*;
+
+impl Storage {
+    // ... ... ... ... ... ... ... ... ... Block Patches ... ... ... ... ... ... ... ... ....
+
+    /// Overwrite bytes of block data from offset on, without rewriting the whole block
+    /// - data grows if bytes end past it, bytes between its end and offset become zeros
+    /// - only the patched bytes and the block header are read and written, the checksum
+    ///   is updated from the bytes replaced, see checksum::crc32_patch
+    /// - blocks stored compressed, encrypted or transformed, free blocks, and storages
+    ///   with observers or dedup writes are patched by rewriting the block with
+    ///   write_block, keeping its link, expiry and tag
+    /// - BlockTooLarge if bytes would end past block_len, AppendOnly if storage file is
+    ///   append-only and block holds data
+    /// - returns: end offset of written block data
+    pub fn patch_block(
+        &mut self,
+        block_index: BlockIndex,
+        offset: u64,
+        bytes: &[u8],
+    ) -> Result<u64, StorageError> {
+        let too_large = |data_len: u64| StorageError::BlockTooLarge {
+            block_index,
+            data_len: usize::try_from(data_len).unwrap_or(usize::MAX),
+            block_len: self.header.block_len,
+        };
+        let patch_end = offset
+            .checked_add(saturating_u64(bytes.len()))
+            .ok_or_else(|| too_large(u64::MAX))?;
+        if patch_end > self.header.block_len {
+            return Err(too_large(patch_end));
+        }
+        if self.is_empty_block(block_index) {
+            return self.rewrite_patched(block_index, offset, bytes);
+        }
+        self.check_appendable(block_index)?;
+        let (block_header, data_offset) = self.read_stored_header(block_index)?;
+        if block_header.flags != 0
+            || self.has_block_transforms()
+            || !self.observers.is_empty()
+            || self.dedup.is_some()
+        {
+            return self.rewrite_patched(block_index, offset, bytes);
+        }
+        let data_len = block_header.block_data_size;
+        let new_data_len = data_len.max(patch_end);
+        // - bytes written start at offset, or at end of data to zero fill a gap
+        let write_start = offset.min(data_len);
+        let gap_len = checked_usize(offset - write_start)?;
+        let mut new_bytes = vec![0u8; gap_len];
+        new_bytes.extend_from_slice(bytes);
+        // - replaced bytes, zeros past end of data
+        let mut old_bytes = vec![0u8; new_bytes.len()];
+        let stored_len = checked_usize(data_len.min(patch_end) - write_start)?;
+        self.read_stored_data(
+            block_index,
+            data_offset + write_start,
+            &mut old_bytes[..stored_len],
+        )?;
+        let checksum = checksum::crc32_extend_zeros(block_header.checksum, new_data_len - data_len);
+        let checksum =
+            checksum::crc32_patch(checksum, &old_bytes, &new_bytes, new_data_len - patch_end);
+        self.preserve_for_snapshots(block_index)?;
+        // - write data first, a crash before the header is written fails the checksum
+        self.write_file_at(
+            data_offset + write_start,
+            &new_bytes,
+            "patch block data",
+            Some(block_index),
+        )?;
+        let block_header = BlockHeader {
+            block_data_size: new_data_len,
+            checksum,
+            generation: self.next_generation(),
+            ..block_header
+        };
+        self.write_file_at(
+            data_offset - BLOCK_HEADER_SIZE as u64,
+            &block_header.to_bytes(),
+            "write block header",
+            Some(block_index),
+        )?;
+        self.uncache_block(block_index);
+        self.check_block_consistency(block_index)?;
+        self.written(1)?;
+        Ok(data_offset + patch_end)
+    }
+    /// Patch block by reading its data and writing it again, see patch_block
+    fn rewrite_patched(
+        &mut self,
+        block_index: BlockIndex,
+        offset: u64,
+        bytes: &[u8],
+    ) -> Result<u64, StorageError> {
+        let (next_block, expires_at, tag) = if self.is_empty_block(block_index) {
+            (None, None, 0)
+        } else {
+            let block_header = self.read_block_header(block_index)?;
+            (
+                block_header.next_block,
+                block_header.expires_at,
+                block_header.tag,
+            )
+        };
+        let (_, mut data) = self.read_block(block_index)?;
+        let offset = checked_usize(offset)?;
+        let patch_end = offset.saturating_add(bytes.len());
+        if data.len() < patch_end {
+            data.resize(patch_end, 0);
+        }
+        data[offset..patch_end].copy_from_slice(bytes);
+        self.write_linked_block(block_index, &data, next_block, expires_at, tag)
+    }
+}
+
+#[cfg(test)]
+mod unit_tests_patch {
+    use super::*;
+
+    fn new_storage(tmp_dir: &tempfile::TempDir) -> Storage {
+        let file_path = tmp_dir.path().join("patch.hex");
+        Storage::new(file_path.to_str().unwrap().to_string(), 8).unwrap()
+    }
+
+    #[test]
+    fn test_patch_block() {
+        let tmp_dir = tempfile::tempdir().unwrap();
+        let mut storage = new_storage(&tmp_dir);
+        let record_id = storage.write_record(&[1u8; 12]).unwrap();
+        storage.patch_block(record_id, 2, &[7, 7]).unwrap();
+        assert_eq!(
+            storage.read_block(record_id).unwrap().1,
+            vec![1, 1, 7, 7, 1, 1, 1, 1]
+        );
+        // - link to next block of record is kept
+        assert_eq!(storage.read_record(record_id).unwrap().len(), 12);
+        // - stale bytes past end of data are zeroed when data grows past them
+        storage.write_block(2, &[5; 8]).unwrap();
+        storage.write_block(2, &[5, 5]).unwrap();
+        storage.patch_block(2, 4, &[6]).unwrap();
+        assert_eq!(storage.read_block(2).unwrap().1, vec![5, 5, 0, 0, 6]);
+        assert!(matches!(
+            storage.patch_block(2, 6, &[6; 3]),
+            Err(StorageError::BlockTooLarge { block_index: 2, .. })
+        ));
+        // - free block is written like write_block
+        storage.patch_block(3, 1, &[9]).unwrap();
+        assert_eq!(storage.read_block(3).unwrap().1, vec![0, 9]);
+        assert_eq!(storage.verify_all().unwrap(), Vec::<BlockIndex>::new());
+    }
+    #[test]
+    fn test_patch_past_u32() {
+        let tmp_dir = tempfile::tempdir().unwrap();
+        let file_path = tmp_dir.path().join("patch.hex");
+        let file_path = file_path.to_str().unwrap().to_string();
+        let block_len = u32::MAX as u64 + 16;
+        let mut storage = Storage::new(file_path.clone(), block_len).unwrap();
+        storage.write_block(0, &[1]).unwrap();
+        // - data just past u32::MAX bytes, in a sparse file, without writing it
+        let data_len = u32::MAX as u64 + 1;
+        let data_offset = storage.block_offset(0).unwrap() + BLOCK_HEADER_SIZE as u64;
+        let file = std::fs::OpenOptions::new()
+            .read(true)
+            .write(true)
+            .open(&file_path)
+            .unwrap();
+        file.set_len(data_offset + block_len).unwrap();
+        let block_header = BlockHeader::new(data_len, 0);
+        storage
+            .write_file_at(
+                data_offset - BLOCK_HEADER_SIZE as u64,
+                &block_header.to_bytes(),
+                "",
+                None,
+            )
+            .unwrap();
+        // - patches at every offset around the u32 boundary land at offset, data grows
+        //   only if they end past it
+        let mut expected_len = data_len;
+        for offset in u32::MAX as u64 - 2..=u32::MAX as u64 + 2 {
+            let patch = [offset as u8, 0xff];
+            let end = storage.patch_block(0, offset, &patch).unwrap();
+            assert_eq!(end, data_offset + offset + 2);
+            expected_len = expected_len.max(offset + 2);
+            assert_eq!(
+                storage.read_block_header(0).unwrap().block_data_size,
+                expected_len
+            );
+            let mut patched = [0u8; 2];
+            positional::read_at(&file, data_offset + offset, &mut patched).unwrap();
+            assert_eq!(patched, patch);
+        }
+        assert!(matches!(
+            storage.patch_block(0, block_len - 1, &[1, 2]),
+            Err(StorageError::BlockTooLarge { block_index: 0, .. })
+        ));
+    }
+    #[test]
+    fn test_patch_transformed_block() {
+        let tmp_dir = tempfile::tempdir().unwrap();
+        let mut storage = new_storage(&tmp_dir);
+        storage
+            .write_block_expiring(0, &[1, 2, 3], Some(100))
+            .unwrap();
+        // - patched in place, expiry is kept in block header
+        storage.patch_block(0, 3, &[4]).unwrap();
+        assert_eq!(storage.read_block_header(0).unwrap().expires_at, Some(100));
+        // - a file without used blocks records the transform
+        storage.delete_block(0, true).unwrap();
+        storage.push_transform(Box::new(Reverse)).unwrap();
+        storage
+            .write_block_expiring(1, &[4, 5, 6], Some(200))
+            .unwrap();
+        // - rewritten with the transform, expiry is kept too
+        storage.patch_block(1, 1, &[0]).unwrap();
+        assert_eq!(storage.read_block(1).unwrap().1, vec![4, 0, 6]);
+        assert_eq!(storage.read_block_header(1).unwrap().expires_at, Some(200));
+    }
+
+    /// reverses block data, so stored data differs from block data
+    struct Reverse;
+    impl BlockTransform for Reverse {
+        fn name(&self) -> &str {
+            "reverse"
+        }
+        fn encode(&self, data: &[u8]) -> Result<Vec<u8>, StorageError> {
+            Ok(data.iter().rev().cloned().collect())
+        }
+        fn decode(&self, data: &[u8]) -> Result<Vec<u8>, StorageError> {
+            self.encode(data)
+        }
+    }
+}
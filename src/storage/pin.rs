@@ -0,0 +1,100 @@
+use super::*;
+
+impl Storage {
+    // ... ... ... ... ... ... ... ... ... Block Pinning ... ... ... ... ... ... ... ... ... .
+
+    /// Pin block, so its index stays valid until unpinned
+    /// - deleting a pinned block, or freeing it by writing empty data, returns BlockPinned,
+    ///   also from delete_record, sweep_expired skips it and compaction does not move it
+    /// - data of a pinned block can still be overwritten
+    /// - pins are not stored in storage file, they must be set every time the file is opened
+    /// - returns: true if block holds data and is pinned, free blocks can not be pinned
+    pub fn pin_block(&mut self, block_index: BlockIndex) -> bool {
+        if self.is_empty_block(block_index) {
+            return false;
+        }
+        self.pinned.insert(block_index);
+        true
+    }
+    /// Unpin block pinned by pin_block
+    /// - returns: true if block was pinned
+    pub fn unpin_block(&mut self, block_index: BlockIndex) -> bool {
+        self.pinned.remove(&block_index)
+    }
+    pub fn is_pinned(&self, block_index: BlockIndex) -> bool {
+        self.pinned.contains(&block_index)
+    }
+    /// BlockPinned if block is pinned, before an operation that would free it
+    pub(super) fn check_not_pinned(&self, block_index: BlockIndex) -> Result<(), StorageError> {
+        if self.is_pinned(block_index) {
+            return Err(StorageError::BlockPinned { block_index });
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod unit_tests_pin {
+    use super::*;
+
+    fn new_storage(tmp_dir: &tempfile::TempDir) -> Storage {
+        let file_path = tmp_dir.path().join("pin.hex");
+        Storage::new(file_path.to_str().unwrap().to_string(), 4).unwrap()
+    }
+
+    #[test]
+    fn test_pinned_block_is_not_deleted() {
+        let tmp_dir = tempfile::tempdir().unwrap();
+        let mut storage = new_storage(&tmp_dir);
+        assert!(!storage.pin_block(0));
+        storage.write_block(0, &[1]).unwrap();
+        assert!(storage.pin_block(0));
+        assert!(matches!(
+            storage.delete_block(0, true),
+            Err(StorageError::BlockPinned { block_index: 0 })
+        ));
+        assert!(matches!(
+            storage.write_block(0, &[]),
+            Err(StorageError::BlockPinned { block_index: 0 })
+        ));
+        // overwrites are allowed
+        storage.write_block(0, &[2]).unwrap();
+        // no block of a record is deleted if one of them is pinned
+        let record_id = storage.write_record(&[3; 10]).unwrap();
+        let record_blocks = storage.record_blocks(record_id).unwrap();
+        storage.pin_block(record_blocks[2]);
+        assert!(matches!(
+            storage.delete_record(record_id, false),
+            Err(StorageError::BlockPinned { .. })
+        ));
+        assert_eq!(storage.read_record(record_id).unwrap(), vec![3; 10]);
+        assert!(storage.unpin_block(record_blocks[2]));
+        assert!(!storage.unpin_block(record_blocks[2]));
+        assert_eq!(storage.delete_record(record_id, false).unwrap(), 3);
+        assert_eq!(storage.read_block(0).unwrap().1, vec![2]);
+    }
+    #[test]
+    fn test_pinned_block_is_not_moved() {
+        let tmp_dir = tempfile::tempdir().unwrap();
+        let mut storage = new_storage(&tmp_dir);
+        for block_index in 0..6 {
+            storage
+                .write_block(block_index, &[block_index as u8])
+                .unwrap();
+        }
+        storage.write_block_expiring(4, &[4], Some(10)).unwrap();
+        storage.pin_block(4);
+        storage.pin_block(5);
+        for block_index in 0..3 {
+            storage.delete_block(block_index, false).unwrap();
+        }
+        assert!(storage.sweep_expired(10).unwrap().is_empty());
+        // block 3 moves to 0, pinned blocks stay and keep the tail
+        let moves = storage.compact().unwrap();
+        assert_eq!(moves.len(), 1);
+        assert_eq!(moves[&3], 0);
+        assert_eq!(storage.read_block(4).unwrap().1, vec![4]);
+        assert_eq!(storage.read_block(5).unwrap().1, vec![5]);
+        assert_eq!(storage.end_block_count, 6);
+    }
+}
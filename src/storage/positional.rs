@@ -0,0 +1,172 @@
+//! Positional file IO, reads and writes at an offset without moving a shared file offset
+//! - pread/pwrite on Unix, seek_read/seek_write on Windows
+//! - seek then read/write on WASI, whose positional FileExt is unstable; WASI
+//!   preview 1 has no threads, so no other call moves the file offset in between
+//! - unsupported elsewhere, e.g. wasm32-unknown-unknown, which has no file system,
+//!   use MemStorage there
+//! - every call names its offset, so no call depends on where an earlier one left off,
+//!   and several threads can read one File at once
+use std::fs::File;
+use std::io;
+
+/// Read bytes of file at offset into buf, until buf is full or end of file
+/// - returns: number of bytes read, less than buf length only at end of file
+pub fn read_at(file: &File, offset: u64, buf: &mut [u8]) -> io::Result<usize> {
+    let mut read_size = 0;
+    while read_size < buf.len() {
+        match read_once_at(file, offset + read_size as u64, &mut buf[read_size..]) {
+            Ok(0) => break,
+            Ok(size) => read_size += size,
+            Err(error) if error.kind() == io::ErrorKind::Interrupted => {}
+            Err(error) => return Err(error),
+        }
+    }
+    Ok(read_size)
+}
+
+/// Write all bytes of buf to file at offset
+/// - returns: number of bytes written, less than buf length only if file stopped taking bytes
+pub fn write_at(file: &File, offset: u64, buf: &[u8]) -> io::Result<usize> {
+    let mut write_size = 0;
+    while write_size < buf.len() {
+        match write_once_at(file, offset + write_size as u64, &buf[write_size..]) {
+            Ok(0) => break,
+            Ok(size) => write_size += size,
+            Err(error) if error.kind() == io::ErrorKind::Interrupted => {}
+            Err(error) => return Err(error),
+        }
+    }
+    Ok(write_size)
+}
+
+#[cfg(unix)]
+pub(super) fn read_once_at(file: &File, offset: u64, buf: &mut [u8]) -> io::Result<usize> {
+    std::os::unix::fs::FileExt::read_at(file, buf, offset)
+}
+
+#[cfg(windows)]
+pub(super) fn read_once_at(file: &File, offset: u64, buf: &mut [u8]) -> io::Result<usize> {
+    std::os::windows::fs::FileExt::seek_read(file, buf, offset)
+}
+
+#[cfg(target_os = "wasi")]
+pub(super) fn read_once_at(file: &File, offset: u64, buf: &mut [u8]) -> io::Result<usize> {
+    use std::io::{Read, Seek, SeekFrom};
+    let mut file = file;
+    file.seek(SeekFrom::Start(offset))?;
+    file.read(buf)
+}
+
+#[cfg(not(any(unix, windows, target_os = "wasi")))]
+pub(super) fn read_once_at(_file: &File, _offset: u64, _buf: &mut [u8]) -> io::Result<usize> {
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        "positional reads are not supported on this platform",
+    ))
+}
+
+#[cfg(unix)]
+fn write_once_at(file: &File, offset: u64, buf: &[u8]) -> io::Result<usize> {
+    std::os::unix::fs::FileExt::write_at(file, buf, offset)
+}
+
+#[cfg(windows)]
+fn write_once_at(file: &File, offset: u64, buf: &[u8]) -> io::Result<usize> {
+    std::os::windows::fs::FileExt::seek_write(file, buf, offset)
+}
+
+#[cfg(target_os = "wasi")]
+fn write_once_at(file: &File, offset: u64, buf: &[u8]) -> io::Result<usize> {
+    use std::io::{Seek, SeekFrom, Write};
+    let mut file = file;
+    file.seek(SeekFrom::Start(offset))?;
+    file.write(buf)
+}
+
+#[cfg(not(any(unix, windows, target_os = "wasi")))]
+fn write_once_at(_file: &File, _offset: u64, _buf: &[u8]) -> io::Result<usize> {
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        "positional writes are not supported on this platform",
+    ))
+}
+
+/// Reserve disk space for len bytes of file from offset on, growing the file if needed
+/// - posix_fallocate on Linux with feature fallocate, the file system allocates the
+///   blocks without writing them
+/// - elsewhere zeros are written a chunk at a time, setting the file length alone would
+///   leave a sparse file that still fails with ENOSPC on a later write
+/// - bytes already in the file are zero filled too, only call it past end of data
+pub fn allocate_at(file: &File, offset: u64, len: u64) -> io::Result<()> {
+    #[cfg(all(feature = "fallocate", target_os = "linux"))]
+    {
+        use std::convert::TryFrom;
+        use std::os::unix::io::AsRawFd;
+        let out_of_range =
+            || io::Error::new(io::ErrorKind::InvalidInput, "allocation past file offsets");
+        let offset = libc::off_t::try_from(offset).map_err(|_| out_of_range())?;
+        let len = libc::off_t::try_from(len).map_err(|_| out_of_range())?;
+        // - posix_fallocate returns the error number instead of setting errno
+        match unsafe { libc::posix_fallocate(file.as_raw_fd(), offset, len) } {
+            0 => Ok(()),
+            error => Err(io::Error::from_raw_os_error(error)),
+        }
+    }
+    #[cfg(not(all(feature = "fallocate", target_os = "linux")))]
+    {
+        use super::layout::ZERO_FILL_CHUNK_LEN;
+        let zeros = vec![0u8; len.min(ZERO_FILL_CHUNK_LEN) as usize];
+        let end = offset.saturating_add(len);
+        let mut write_offset = offset;
+        while write_offset < end {
+            let chunk_len = (end - write_offset).min(ZERO_FILL_CHUNK_LEN) as usize;
+            let write_size = write_at(file, write_offset, &zeros[..chunk_len])?;
+            if write_size != chunk_len {
+                return Err(io::ErrorKind::WriteZero.into());
+            }
+            write_offset += chunk_len as u64;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod unit_tests_positional {
+    use super::super::layout::ZERO_FILL_CHUNK_LEN;
+    use super::*;
+
+    #[test]
+    fn test_read_at() {
+        let tmp_dir = tempfile::tempdir().unwrap();
+        let file_path = tmp_dir.path().join("positional.hex");
+        std::fs::write(&file_path, [0u8, 1, 2, 3, 4, 5]).unwrap();
+        let file = File::open(&file_path).unwrap();
+        let mut buf = [0u8; 4];
+        assert_eq!(read_at(&file, 1, &mut buf).unwrap(), 4);
+        assert_eq!(buf, [1, 2, 3, 4]);
+        // short read only at end of file
+        assert_eq!(read_at(&file, 4, &mut buf).unwrap(), 2);
+        assert_eq!(buf[..2], [4, 5]);
+        assert_eq!(read_at(&file, 9, &mut buf).unwrap(), 0);
+    }
+    #[test]
+    fn test_write_at() {
+        let tmp_dir = tempfile::tempdir().unwrap();
+        let file_path = tmp_dir.path().join("positional.hex");
+        let file = std::fs::OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(&file_path)
+            .unwrap();
+        assert_eq!(write_at(&file, 2, &[7, 8]).unwrap(), 2);
+        // writes at an offset do not depend on earlier writes
+        assert_eq!(write_at(&file, 0, &[1]).unwrap(), 1);
+        assert_eq!(std::fs::read(&file_path).unwrap(), vec![1, 0, 7, 8]);
+        allocate_at(&file, 4, ZERO_FILL_CHUNK_LEN + 3).unwrap();
+        let file_bytes = std::fs::read(&file_path).unwrap();
+        assert_eq!(file_bytes.len() as u64, ZERO_FILL_CHUNK_LEN + 7);
+        assert!(file_bytes[4..].iter().all(|byte| *byte == 0));
+    }
+}
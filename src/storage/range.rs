@@ -0,0 +1,230 @@
+use super::batch::BatchBlock;
+use super::*;
+
+/// Bytes read at a time by read_range, a window holds at least one block
+const RANGE_READ_LEN: u64 = 4 * 1024 * 1024;
+
+impl Storage {
+    // ... ... ... ... ... ... ... ... ... Block Ranges ... ... ... ... ... ... ... ... ... .
+
+    /// Read blocks start..start + count, with one read per window of contiguous blocks
+    /// instead of a read per block header and block data
+    /// - a window holds as many blocks as fit in 4 MiB, at least one block, and is only
+    ///   read from the first to the last block that is not free or cached
+    /// - free blocks and blocks past end of file read as empty
+    /// - every block is verified and decoded on its own, like read_block, a corrupt block
+    ///   fails only its own result
+    /// - returns: result of each block in order, Err if a window could not be read
+    pub fn read_range(
+        &mut self,
+        start: BlockIndex,
+        count: u64,
+    ) -> Result<Vec<Result<Vec<u8>, StorageError>>, StorageError> {
+        let out_of_range = || StorageError::BlockOutOfRange { block_index: start };
+        let end = start.checked_add(count).ok_or_else(out_of_range)?;
+        let block_size = block_size(self.header.block_len).ok_or_else(out_of_range)?;
+        let window_blocks = (RANGE_READ_LEN / block_size).max(1);
+        let mut results = Vec::new();
+        let mut window_start = start;
+        while window_start < end {
+            let window_end = end.min(window_start.saturating_add(window_blocks));
+            results.extend(self.read_range_window(window_start, window_end, block_size)?);
+            window_start = window_end;
+        }
+        Ok(results)
+    }
+    /// Read blocks window_start..window_end, see read_range
+    fn read_range_window(
+        &mut self,
+        window_start: BlockIndex,
+        window_end: BlockIndex,
+        block_size: u64,
+    ) -> Result<Vec<Result<Vec<u8>, StorageError>>, StorageError> {
+        // - answer free and cached blocks from memory
+        let mut results: Vec<Option<Result<Vec<u8>, StorageError>>> = (window_start..window_end)
+            .map(|block_index| {
+                if self.is_empty_block(block_index) {
+                    Some(Ok(Vec::new()))
+                } else {
+                    self.cached_block(block_index).map(Ok)
+                }
+            })
+            .collect();
+        let first_unread = results.iter().position(Option::is_none);
+        let last_unread = results.iter().rposition(Option::is_none);
+        let (first_unread, last_unread) = match (first_unread, last_unread) {
+            (Some(first_unread), Some(last_unread)) => (first_unread, last_unread),
+            _ => return Ok(results.into_iter().flatten().collect()),
+        };
+        // - read blocks left in one read
+        let read_start = window_start + first_unread as u64;
+        let read_len = (last_unread - first_unread + 1) as u64 * block_size;
+        let window_len = usize::try_from(read_len).map_err(|_| StorageError::BlockOutOfRange {
+            block_index: read_start,
+        })?;
+        let mut window = vec![0u8; window_len];
+        let read_offset = self.block_offset(read_start)?;
+        let read_size = self
+            .backend
+            .read_at(read_offset, &mut window)
+            .map_err(StorageError::io("read block range", Some(read_start)))?;
+        window.truncate(read_size);
+        // - verify and decode each block read
+        for (position, result) in results.iter_mut().enumerate() {
+            if result.is_none() {
+                let block_index = window_start + position as u64;
+                let block_start = (position - first_unread) * block_size as usize;
+                let block_bytes = window.get(block_start..).unwrap_or_default();
+                *result = Some(self.decode_range_block(block_index, block_bytes));
+            }
+        }
+        Ok(results.into_iter().flatten().collect())
+    }
+    /// Verify and decode a block read by read_range
+    /// - block_bytes: bytes of storage file from block offset on, as far as they were read
+    fn decode_range_block(
+        &mut self,
+        block_index: BlockIndex,
+        block_bytes: &[u8],
+    ) -> Result<Vec<u8>, StorageError> {
+        let truncated = |reason| StorageError::Corruption {
+            block_index: Some(block_index),
+            reason,
+        };
+        let block_header_bytes = block_bytes
+            .get(..BLOCK_HEADER_SIZE)
+            .and_then(|bytes| <&[u8; BLOCK_HEADER_SIZE]>::try_from(bytes).ok())
+            .ok_or_else(|| truncated("block header is truncated"))?;
+        let block_header = self.checked_block_header(block_index, block_header_bytes)?;
+        let data_len = block_header.data_len(block_index)?;
+        let block_data = block_bytes[BLOCK_HEADER_SIZE..]
+            .get(..data_len)
+            .ok_or_else(|| truncated("block data is truncated"))?;
+        self.verify_block_checksum(block_index, &block_header, block_data)?;
+        let block_data =
+            self.decode_block_data(block_index, block_data.to_vec(), block_header.flags)?;
+        self.cache_block(block_index, &block_data);
+        Ok(block_data)
+    }
+    /// Write each payload to its own block, from block start on, with one write per run
+    /// of contiguous blocks instead of a header and a data write per block
+    /// - blocks are written like write_block, they link to no other block
+    /// - every payload is checked before the file is touched, a payload that is empty,
+    ///   does not fit its block after block transforms, or is for a block that holds
+    ///   data of an append-only storage file fails only its own result and cuts the run
+    /// - blocks are freed with delete_block, not with empty payloads
+    /// - returns: result of each payload in order, Err if a run could not be written
+    pub fn write_range(
+        &mut self,
+        start: BlockIndex,
+        payloads: &[&[u8]],
+    ) -> Result<Vec<Result<(), StorageError>>, StorageError> {
+        let mut results = Vec::with_capacity(payloads.len());
+        let mut range_blocks = Vec::with_capacity(payloads.len());
+        for (position, payload) in payloads.iter().enumerate() {
+            let block_index = start
+                .checked_add(position as u64)
+                .ok_or(StorageError::BlockOutOfRange { block_index: start })?;
+            match self.range_block(block_index, payload) {
+                Ok(range_block) => {
+                    range_blocks.push(range_block);
+                    results.push(Ok(()));
+                }
+                Err(error) => results.push(Err(error)),
+            }
+        }
+        self.write_block_runs(&range_blocks)?;
+        Ok(results)
+    }
+    /// Check and encode payload of a block of write_range
+    fn range_block(
+        &mut self,
+        block_index: BlockIndex,
+        payload: &[u8],
+    ) -> Result<BatchBlock, StorageError> {
+        if payload.is_empty() {
+            return Err(StorageError::EmptyRecord);
+        }
+        self.block_offset(block_index)?;
+        self.check_appendable(block_index)?;
+        let (block_data, block_flags) = self.encode_block_data(payload)?;
+        if block_data.len() as u64 > self.header.block_len {
+            return Err(StorageError::BlockTooLarge {
+                block_index,
+                data_len: block_data.len(),
+                block_len: self.header.block_len,
+            });
+        }
+        Ok(BatchBlock {
+            block_index,
+            data: payload.to_vec(),
+            block_data,
+            block_flags,
+            next_block: None,
+        })
+    }
+}
+
+#[cfg(test)]
+mod unit_tests_range {
+    use super::*;
+
+    fn new_storage(tmp_dir: &tempfile::TempDir) -> Storage {
+        let file_path = tmp_dir.path().join("range.hex");
+        Storage::new(file_path.to_str().unwrap().to_string(), 4).unwrap()
+    }
+
+    #[test]
+    fn test_write_range() {
+        let tmp_dir = tempfile::tempdir().unwrap();
+        let mut storage = new_storage(&tmp_dir);
+        storage.write_block(0, &[9]).unwrap();
+        // - range starts past end of file, skipped blocks are free
+        let payloads: [&[u8]; 4] = [&[1], &[2; 5], &[3, 3], &[]];
+        let results = storage.write_range(2, &payloads).unwrap();
+        assert!(results[0].is_ok() && results[2].is_ok());
+        assert!(matches!(
+            results[1],
+            Err(StorageError::BlockTooLarge { block_index: 3, .. })
+        ));
+        assert!(matches!(results[3], Err(StorageError::EmptyRecord)));
+        assert_eq!(storage.end_block_count, 5);
+        assert_eq!(
+            storage.free_blocks.iter().cloned().collect::<Vec<_>>(),
+            vec![1, 3]
+        );
+        assert_eq!(storage.read_block(2).unwrap().1, vec![1]);
+        assert_eq!(storage.read_block(4).unwrap().1, vec![3, 3]);
+        // - blocks are overwritten in place
+        let results = storage.write_range(0, &[&[4], &[5]]).unwrap();
+        assert!(results.iter().all(Result::is_ok));
+        assert_eq!(storage.read_block(0).unwrap().1, vec![4]);
+        assert_eq!(storage.verify_all().unwrap(), Vec::<BlockIndex>::new());
+    }
+    #[test]
+    fn test_read_range() {
+        let tmp_dir = tempfile::tempdir().unwrap();
+        let mut storage = new_storage(&tmp_dir);
+        let payloads: [&[u8]; 4] = [&[1], &[2, 2], &[3; 4], &[4]];
+        storage.write_range(0, &payloads).unwrap();
+        storage.delete_block(1, false).unwrap();
+        // - corrupt data of block 2
+        let data_offset = storage.block_offset(2).unwrap() + BLOCK_HEADER_SIZE as u64;
+        storage
+            .write_file_at(data_offset, &[0xff], "corrupt block", None)
+            .unwrap();
+        let results = storage.read_range(0, 6).unwrap();
+        assert_eq!(results.len(), 6);
+        assert_eq!(results[0].as_ref().unwrap(), &vec![1]);
+        assert!(results[1].as_ref().unwrap().is_empty());
+        assert!(matches!(
+            results[2],
+            Err(StorageError::ChecksumMismatch { block_index: 2, .. })
+        ));
+        assert_eq!(results[3].as_ref().unwrap(), &vec![4]);
+        // - blocks past end of file read as empty
+        assert!(results[4].as_ref().unwrap().is_empty());
+        assert!(results[5].as_ref().unwrap().is_empty());
+        assert!(storage.read_range(10, 0).unwrap().is_empty());
+    }
+}
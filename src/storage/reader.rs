@@ -0,0 +1,113 @@
+use super::*;
+use std::io;
+
+/// Streaming reader of blocks, see Storage::reader
+/// - implements io::Read, each block is read like read_block when the bytes before it
+///   were consumed, so only one block is held in memory
+/// - free blocks read as empty, they add no bytes
+/// - a block that fails to read fails the read call with its StorageError as source,
+///   the next call goes on with the next block
+pub struct BlockReader<'a> {
+    storage: &'a mut Storage,
+    /// Blocks not read yet, in read order
+    block_indexes: std::vec::IntoIter<BlockIndex>,
+    /// Data of block being read
+    block_data: Vec<u8>,
+    /// Bytes of block_data already returned
+    position: usize,
+}
+
+impl io::Read for BlockReader<'_> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+        while self.position == self.block_data.len() {
+            let block_index = match self.block_indexes.next() {
+                Some(block_index) => block_index,
+                None => return Ok(0),
+            };
+            // - cleared first, so after an error the next call goes on with the next block
+            self.block_data = Vec::new();
+            self.position = 0;
+            self.block_data = self
+                .storage
+                .read_block(block_index)
+                .map_err(io::Error::other)?
+                .1;
+        }
+        let read_size = buf.len().min(self.block_data.len() - self.position);
+        buf[..read_size].copy_from_slice(&self.block_data[self.position..][..read_size]);
+        self.position += read_size;
+        Ok(read_size)
+    }
+}
+
+impl Storage {
+    /// Streaming reader of data of blocks, in the given order, see BlockReader
+    /// - pass record_blocks(record_id) to stream a record without joining its data
+    pub fn reader(&mut self, block_indexes: Vec<BlockIndex>) -> BlockReader<'_> {
+        BlockReader {
+            storage: self,
+            block_indexes: block_indexes.into_iter(),
+            block_data: Vec::new(),
+            position: 0,
+        }
+    }
+}
+
+#[cfg(test)]
+mod unit_tests_reader {
+    use super::*;
+    use std::io::Read;
+
+    fn new_storage(tmp_dir: &tempfile::TempDir) -> Storage {
+        let file_path = tmp_dir.path().join("reader.hex");
+        Storage::new(file_path.to_str().unwrap().to_string(), 4).unwrap()
+    }
+
+    #[test]
+    fn test_streamed_record() {
+        let tmp_dir = tempfile::tempdir().unwrap();
+        let mut storage = new_storage(&tmp_dir);
+        let data: Vec<u8> = (0..14).collect();
+        let record_id = storage.write_record(&data).unwrap();
+        let record_blocks = storage.record_blocks(record_id).unwrap();
+        let mut reader = storage.reader(record_blocks);
+        let mut buf = [0u8; 3];
+        // reads stop at block ends
+        assert_eq!(reader.read(&mut buf).unwrap(), 3);
+        assert_eq!(reader.read(&mut buf).unwrap(), 1);
+        assert_eq!(buf[0], 3);
+        let mut rest = Vec::new();
+        reader.read_to_end(&mut rest).unwrap();
+        assert_eq!(rest, data[4..]);
+        assert_eq!(reader.read(&mut buf).unwrap(), 0);
+    }
+    #[test]
+    fn test_read_errors() {
+        let tmp_dir = tempfile::tempdir().unwrap();
+        let mut storage = new_storage(&tmp_dir);
+        storage.write_block(0, &[1]).unwrap();
+        storage.write_block(2, &[2]).unwrap();
+        let mut joined = Vec::new();
+        // free block 1 and blocks past the end add no bytes
+        let mut reader = storage.reader(vec![2, 1, 9, 0]);
+        reader.read_to_end(&mut joined).unwrap();
+        assert_eq!(joined, vec![2, 1]);
+        // error of a block is the source of the io::Error
+        let data_offset = storage.block_offset(2).unwrap() + BLOCK_HEADER_SIZE as u64;
+        storage
+            .write_file_at(data_offset, &[7], "corrupt", None)
+            .unwrap();
+        let mut reader = storage.reader(vec![2, 0]);
+        let mut buf = [0u8; 4];
+        let error = reader.read(&mut buf).unwrap_err();
+        let source = error.into_inner().unwrap();
+        assert!(matches!(
+            source.downcast_ref::<StorageError>(),
+            Some(StorageError::ChecksumMismatch { block_index: 2 })
+        ));
+        assert_eq!(reader.read(&mut buf).unwrap(), 1);
+    }
+}
@@ -0,0 +1,375 @@
+use super::batch::BatchBlock;
+use super::*;
+
+/// Index of first block of a record
+pub type RecordId = BlockIndex;
+
+impl Storage {
+    // ... ... ... ... ... ... ... ... Multi Block Records ... ... ... ... ... ... ... ...
+
+    /// Write data of any length as a record, split over as many blocks as needed
+    /// - blocks are linked through next block in their block headers
+    /// - blocks are picked by allocate, see AllocationStrategy
+    /// - data is split in block_len chunks before block transforms, transforms that grow
+    ///   data make full chunks exceed block_len and the write fail
+    /// - empty data is rejected, an empty block can not be told apart from a free block
+    /// - returns: record id, to read or delete the record
+    pub fn write_record(&mut self, data: &[u8]) -> Result<RecordId, StorageError> {
+        self.write_record_expiring(data, None)
+    }
+    /// Split data in block_len chunks and write each chunk to a block, see write_record
+    /// - use this instead of write_block for data that may be longer than block_len,
+    ///   write_block rejects it with StorageError::BlockTooLarge
+    /// - blocks are linked like blocks of a record, first block is the record id
+    /// - returns: indexes of all blocks used, in data order
+    pub fn write_blocks_chunked(&mut self, data: &[u8]) -> Result<Vec<BlockIndex>, StorageError> {
+        self.write_chunks(data, None, 0)
+    }
+    /// Split data in block_len chunks and write them to given blocks, in order, linked
+    /// like blocks of a record, e.g. to overwrite a record in place
+    /// - indexes: blocks to write, free or holding data, first is the record id
+    /// - every chunk is checked before the file is touched: NotEnoughBlocks if data needs
+    ///   more blocks than given, DuplicateBlock if a block would be written twice,
+    ///   AppendOnly or BlockTooLarge like write_block
+    /// - blocks given past those data needs are left as they are, delete them when
+    ///   shrinking a record
+    /// - each run of contiguous blocks is written with a single write, like write_batch,
+    ///   a failed write can leave a block linking to a block that was not written yet
+    /// - returns: indexes of blocks written, in data order
+    pub fn write_into(
+        &mut self,
+        indexes: &[BlockIndex],
+        data: &[u8],
+    ) -> Result<Vec<BlockIndex>, StorageError> {
+        if data.is_empty() {
+            return Err(StorageError::EmptyRecord);
+        }
+        let chunk_len = self.chunk_len();
+        let block_count = data.len().div_ceil(chunk_len);
+        if block_count > indexes.len() {
+            return Err(StorageError::NotEnoughBlocks {
+                block_count,
+                given_blocks: indexes.len(),
+            });
+        }
+        let block_indexes = &indexes[..block_count];
+        // - plan blocks
+        let mut planned = BTreeSet::new();
+        let mut batch_blocks = Vec::with_capacity(block_count);
+        for (chunk_index, chunk) in data.chunks(chunk_len).enumerate() {
+            let block_index = block_indexes[chunk_index];
+            if !planned.insert(block_index) {
+                return Err(StorageError::DuplicateBlock { block_index });
+            }
+            self.block_offset(block_index)?;
+            self.check_appendable(block_index)?;
+            let (block_data, block_flags) = self.encode_block_data(chunk)?;
+            if block_data.len() as u64 > self.header.block_len {
+                return Err(StorageError::BlockTooLarge {
+                    block_index,
+                    data_len: block_data.len(),
+                    block_len: self.header.block_len,
+                });
+            }
+            batch_blocks.push(BatchBlock {
+                block_index,
+                data: chunk.to_vec(),
+                block_data,
+                block_flags,
+                next_block: block_indexes.get(chunk_index + 1).cloned(),
+            });
+        }
+        // - write runs of contiguous blocks
+        batch_blocks.sort_by_key(|batch_block| batch_block.block_index);
+        self.write_block_runs(&batch_blocks)?;
+        Ok(block_indexes.to_vec())
+    }
+    /// Write data split in chunks to linked blocks, see write_blocks_chunked
+    /// - expires_at: expiry of every block, see Storage::sweep_expired
+    /// - tag: tag of every block, see Storage::write_block_tagged
+    pub(super) fn write_chunks(
+        &mut self,
+        data: &[u8],
+        expires_at: Option<u64>,
+        tag: BlockTag,
+    ) -> Result<Vec<BlockIndex>, StorageError> {
+        if data.is_empty() {
+            return Err(StorageError::EmptyRecord);
+        }
+        let chunks: Vec<&[u8]> = data.chunks(self.chunk_len()).collect();
+        let block_indexes = self.allocate(chunks.len())?;
+        // - write last block first, so no block links to a block that was not written yet
+        for (chunk_index, chunk) in chunks.iter().enumerate().rev() {
+            let next_block = block_indexes.get(chunk_index + 1).cloned();
+            let block_index = block_indexes[chunk_index];
+            self.write_linked_block(block_index, chunk, next_block, expires_at, tag)?;
+        }
+        Ok(block_indexes)
+    }
+    /// Read all blocks of a record and join their data
+    /// - returns: empty vector if first block of record is empty
+    pub fn read_record(&mut self, record_id: RecordId) -> Result<Vec<u8>, StorageError> {
+        Ok(self.read_record_chunks(record_id)?.concat())
+    }
+    /// Read all blocks of a record, keeping data of each block apart
+    /// - returns: data of every block in chain order, empty if first block of record is empty
+    pub fn read_record_chunks(
+        &mut self,
+        record_id: RecordId,
+    ) -> Result<Vec<Vec<u8>>, StorageError> {
+        let mut chunks = Vec::new();
+        if self.is_empty_block(record_id) {
+            return Ok(chunks);
+        }
+        let mut block_index = record_id;
+        // - a record can not have more blocks than the file, longer chains loop
+        for _ in 0..self.end_block_count {
+            let (block_header, block_data) = self.read_stored_block(block_index)?;
+            self.verify_block_checksum(block_index, &block_header, &block_data)?;
+            chunks.push(self.decode_block_data(block_index, block_data, block_header.flags)?);
+            match self.next_record_block(record_id, &block_header)? {
+                Some(next_block) => block_index = next_block,
+                None => return Ok(chunks),
+            }
+        }
+        Err(broken_chain_error(record_id))
+    }
+    /// Delete all blocks of a record
+    /// - block data is not read, so a record with corrupted data can still be deleted
+    /// - BlockPinned if any block of the record is pinned, before any block is deleted
+    /// - returns: number of deleted blocks
+    pub fn delete_record(
+        &mut self,
+        record_id: RecordId,
+        hard_delete: bool,
+    ) -> Result<usize, StorageError> {
+        // - collect all blocks first, deleting a block drops its link
+        let block_indexes = self.record_blocks(record_id)?;
+        // - no block is deleted if any is pinned
+        for block_index in block_indexes.iter() {
+            self.check_not_pinned(*block_index)?;
+        }
+        for block_index in block_indexes.iter() {
+            self.delete_block(*block_index, hard_delete)?;
+        }
+        Ok(block_indexes.len())
+    }
+    /// Indexes of all blocks of a record, in chain order
+    /// - block data is not read, only block headers
+    /// - returns: empty vector if first block of record is empty
+    pub fn record_blocks(&mut self, record_id: RecordId) -> Result<Vec<BlockIndex>, StorageError> {
+        if self.is_empty_block(record_id) {
+            return Ok(Vec::new());
+        }
+        let mut block_indexes = vec![record_id];
+        let mut block_index = record_id;
+        loop {
+            if block_indexes.len() as u64 > self.end_block_count {
+                return Err(broken_chain_error(record_id));
+            }
+            let block_header = self.read_block_header(block_index)?;
+            match self.next_record_block(record_id, &block_header)? {
+                Some(next_block) => {
+                    block_indexes.push(next_block);
+                    block_index = next_block;
+                }
+                None => return Ok(block_indexes),
+            }
+        }
+    }
+    /// Next block of record, after verifying it holds data
+    fn next_record_block(
+        &mut self,
+        record_id: RecordId,
+        block_header: &BlockHeader,
+    ) -> Result<Option<BlockIndex>, StorageError> {
+        match block_header.next_block {
+            None => Ok(None),
+            Some(next_block) if !self.is_empty_block(next_block) => Ok(Some(next_block)),
+            Some(_) => Err(broken_chain_error(record_id)),
+        }
+    }
+    /// Length of data chunks written to each block of a record
+    /// - block_len less encryption overhead and the overhead of block transforms, see
+    ///   BlockTransform::overhead, or all data at once if that does not fit in usize
+    /// - at least 1, data does not fit in blocks with a block_len of the overhead or
+    ///   less
+    /// - e.g. to pick the blocks for write_into, data needs data length / chunk_len
+    ///   blocks, rounded up
+    pub fn chunk_len(&self) -> usize {
+        let chunk_len = self
+            .unencrypted_block_len()
+            .saturating_sub(self.pipeline.overhead())
+            .max(1);
+        usize::try_from(chunk_len).unwrap_or(usize::MAX)
+    }
+}
+
+pub(super) fn broken_chain_error(record_id: RecordId) -> StorageError {
+    StorageError::Corruption {
+        block_index: Some(record_id),
+        reason: "record block chain is broken",
+    }
+}
+
+#[cfg(test)]
+mod unit_tests_record {
+    use super::*;
+
+    fn new_storage(tmp_dir: &tempfile::TempDir) -> Storage {
+        let file_path = tmp_dir.path().join("record.hex");
+        Storage::new(file_path.to_str().unwrap().to_string(), 4).unwrap()
+    }
+
+    #[test]
+    fn test_record_round_trip() {
+        let tmp_dir = tempfile::tempdir().unwrap();
+        let mut storage = new_storage(&tmp_dir);
+        let short_record = storage.write_record(&[1, 2]).unwrap();
+        let data: Vec<u8> = (0..10).collect();
+        let long_record = storage.write_record(&data).unwrap();
+        assert_eq!(short_record, 0);
+        assert_eq!(long_record, 1);
+        assert_eq!(storage.record_blocks(long_record).unwrap(), vec![1, 2, 3]);
+        assert_eq!(storage.read_record(short_record).unwrap(), vec![1, 2]);
+        assert_eq!(storage.read_record(long_record).unwrap(), data);
+        assert_eq!(
+            storage.read_record_chunks(long_record).unwrap(),
+            vec![vec![0, 1, 2, 3], vec![4, 5, 6, 7], vec![8, 9]]
+        );
+        // plain read returns first block only
+        assert_eq!(storage.read_block(long_record).unwrap().1, vec![0, 1, 2, 3]);
+        assert!(storage.write_record(&[]).is_err());
+    }
+    #[test]
+    fn test_record_reuses_free_blocks() {
+        let tmp_dir = tempfile::tempdir().unwrap();
+        let mut storage = new_storage(&tmp_dir);
+        for block_index in 0..4 {
+            storage.write_block(block_index, &[9]).unwrap();
+        }
+        storage.delete_block(1, false).unwrap();
+        storage.delete_block(3, false).unwrap();
+        let data: Vec<u8> = (0..12).collect();
+        let record_id = storage.write_record(&data).unwrap();
+        // blocks 1 and 3 are reused, block 4 is appended
+        assert_eq!(record_id, 1);
+        assert!(storage.free_blocks.is_empty());
+        assert_eq!(storage.end_block_count, 5);
+        assert_eq!(storage.read_record(record_id).unwrap(), data);
+    }
+    #[test]
+    fn test_delete_record() {
+        let tmp_dir = tempfile::tempdir().unwrap();
+        let mut storage = new_storage(&tmp_dir);
+        let record_id = storage.write_record(&[7u8; 9]).unwrap();
+        storage.write_block(3, &[1]).unwrap();
+        assert_eq!(storage.delete_record(record_id, false).unwrap(), 3);
+        assert_eq!(storage.free_blocks, [0, 1, 2].iter().cloned().collect());
+        assert_eq!(storage.read_record(record_id).unwrap(), Vec::<u8>::new());
+        // deleting again is a no-op
+        assert_eq!(storage.delete_record(record_id, false).unwrap(), 0);
+        assert_eq!(
+            storage.record_blocks(record_id).unwrap(),
+            Vec::<BlockIndex>::new()
+        );
+    }
+    #[test]
+    fn test_write_blocks_chunked() {
+        let tmp_dir = tempfile::tempdir().unwrap();
+        let mut storage = new_storage(&tmp_dir);
+        storage.write_block(1, &[9]).unwrap();
+        // oversize data is rejected by write_block, next block untouched
+        let data: Vec<u8> = (0..9).collect();
+        assert!(matches!(
+            storage.write_block(0, &data),
+            Err(StorageError::BlockTooLarge {
+                block_index: 0,
+                data_len: 9,
+                block_len: 4
+            })
+        ));
+        assert_eq!(storage.read_block(1).unwrap().1, vec![9]);
+        // and split over free and appended blocks by write_blocks_chunked
+        let block_indexes = storage.write_blocks_chunked(&data).unwrap();
+        assert_eq!(block_indexes, vec![0, 2, 3]);
+        assert_eq!(storage.read_block(3).unwrap().1, vec![8]);
+        assert_eq!(storage.read_record(block_indexes[0]).unwrap(), data);
+    }
+    #[test]
+    fn test_write_into() {
+        let tmp_dir = tempfile::tempdir().unwrap();
+        let mut storage = new_storage(&tmp_dir);
+        let record_id = storage.write_record(&[7u8; 9]).unwrap();
+        let block_indexes = storage.record_blocks(record_id).unwrap();
+        // overwrite record in place, in given order
+        let data: Vec<u8> = (0..9).collect();
+        let reordered = [record_id, 2, 1];
+        assert_eq!(storage.write_into(&reordered, &data).unwrap(), reordered);
+        assert_eq!(storage.read_record(record_id).unwrap(), data);
+        assert_eq!(storage.record_blocks(record_id).unwrap(), reordered);
+        // shorter data leaves blocks past it as they are
+        assert_eq!(
+            storage.write_into(&block_indexes, &[1, 2]).unwrap(),
+            vec![record_id]
+        );
+        assert_eq!(storage.read_record(record_id).unwrap(), vec![1, 2]);
+        assert_eq!(storage.read_block(2).unwrap().1, vec![4, 5, 6, 7]);
+        // capacity and duplicates are checked before writing
+        assert!(matches!(
+            storage.write_into(&[0, 1], &[3u8; 9]),
+            Err(StorageError::NotEnoughBlocks {
+                block_count: 3,
+                given_blocks: 2
+            })
+        ));
+        assert!(matches!(
+            storage.write_into(&[4, 5, 4], &[3u8; 9]),
+            Err(StorageError::DuplicateBlock { block_index: 4 })
+        ));
+        assert_eq!(storage.end_block_count, 3);
+        assert_eq!(storage.read_record(record_id).unwrap(), vec![1, 2]);
+    }
+    #[test]
+    fn test_allocate() {
+        let tmp_dir = tempfile::tempdir().unwrap();
+        let mut storage = new_storage(&tmp_dir);
+        assert_eq!(storage.allocate(2).unwrap(), vec![0, 1]);
+        for block_index in [0, 1, 3].iter() {
+            storage.write_block(*block_index, &[1]).unwrap();
+        }
+        storage.delete_block(1, false).unwrap();
+        // free blocks 1 and 2 first, then past end of file
+        assert_eq!(storage.allocate(4).unwrap(), vec![1, 2, 4, 5]);
+        assert_eq!(storage.allocate(1).unwrap(), vec![1]);
+        assert_eq!(storage.allocate(0).unwrap(), Vec::<BlockIndex>::new());
+    }
+    #[test]
+    fn test_chunking_lengths() {
+        let tmp_dir = tempfile::tempdir().unwrap();
+        let mut storage = new_storage(&tmp_dir);
+        // from 1 byte to several blocks, with and without a short last chunk
+        for data_len in 1..=13usize {
+            let data: Vec<u8> = (0..data_len as u8).collect();
+            let block_indexes = storage.write_blocks_chunked(&data).unwrap();
+            assert_eq!(block_indexes.len(), data_len.div_ceil(4));
+            let chunks = storage.read_record_chunks(block_indexes[0]).unwrap();
+            assert_eq!(chunks.last().unwrap().len(), (data_len - 1) % 4 + 1);
+            assert_eq!(chunks.concat(), data);
+            storage.delete_record(block_indexes[0], false).unwrap();
+        }
+    }
+    #[test]
+    fn test_broken_chain() {
+        let tmp_dir = tempfile::tempdir().unwrap();
+        let mut storage = new_storage(&tmp_dir);
+        let record_id = storage.write_record(&[7u8; 9]).unwrap();
+        // drop middle block, first block now links to a free block
+        storage.delete_block(1, false).unwrap();
+        let error = storage.read_record(record_id).unwrap_err();
+        assert!(error.is_corruption());
+        assert_eq!(error.block_index(), Some(record_id));
+        let error = storage.delete_record(record_id, false).unwrap_err();
+        assert!(error.is_corruption());
+    }
+}
@@ -0,0 +1,136 @@
+use super::*;
+
+/// Content of a block of a primary storage, to apply to a follower storage
+/// - a change carries the whole block as it is now, not the operation that changed it,
+///   so applying the latest change of every block brings a follower up to date
+/// - see Storage::block_change and Storage::apply_change, and engine::replication
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BlockChange {
+    pub block_index: BlockIndex,
+    /// Generation of the block on the primary, see Storage::block_generation
+    pub generation: Generation,
+    /// Block data as written, empty for a free block
+    pub data: Vec<u8>,
+    /// Next block of a multi block record, see BlockInfo::next_block
+    pub next_block: Option<BlockIndex>,
+    /// Expiry in seconds since UNIX epoch, None if block never expires
+    pub expires_at: Option<u64>,
+    pub tag: BlockTag,
+}
+
+impl Storage {
+    // ... ... ... ... ... ... ... ... ... Replication ... ... ... ... ... ... ... ... ... .
+
+    /// Current content of block, to replicate it to a follower
+    /// - a block past the end of file is a free block at generation 0
+    pub fn block_change(&mut self, block_index: BlockIndex) -> Result<BlockChange, StorageError> {
+        let mut change = BlockChange {
+            block_index,
+            generation: 0,
+            data: Vec::new(),
+            next_block: None,
+            expires_at: None,
+            tag: 0,
+        };
+        if !self.block_exists(block_index) {
+            return Ok(change);
+        }
+        let block_header = self.read_block_header(block_index)?;
+        change.generation = block_header.generation;
+        if block_header.block_data_size == 0 {
+            return Ok(change);
+        }
+        let (_, data) = self.read_block(block_index)?;
+        change.data = data;
+        change.next_block = block_header.next_block;
+        change.expires_at = block_header.expires_at;
+        change.tag = block_header.tag;
+        Ok(change)
+    }
+    /// Blocks written or deleted after generation since, in block index order
+    /// - reads the block header of every block, used or free
+    /// - a block overwritten again keeps only its last generation, so every block
+    ///   changed since is found, whatever happened to it in between
+    pub fn changed_blocks_since(
+        &mut self,
+        since: Generation,
+    ) -> Result<Vec<BlockIndex>, StorageError> {
+        let mut changed_blocks = Vec::new();
+        for block_index in 0..self.end_block_count {
+            if self.read_block_header(block_index)?.generation > since {
+                changed_blocks.push(block_index);
+            }
+        }
+        Ok(changed_blocks)
+    }
+    /// Apply change of a primary storage to this storage
+    /// - writes the block with data, next block, expiry and tag of the change, or
+    ///   deletes it for a change without data
+    /// - the block gets a generation of this storage, not the generation of the change
+    pub fn apply_change(&mut self, change: &BlockChange) -> Result<(), StorageError> {
+        if change.data.is_empty() {
+            self.delete_block(change.block_index, false)?;
+        } else {
+            self.write_linked_block(
+                change.block_index,
+                &change.data,
+                change.next_block,
+                change.expires_at,
+                change.tag,
+            )?;
+        }
+        Ok(())
+    }
+    /// Free every used block at or after block_count, e.g. after the primary trimmed its
+    /// tail, see Storage::trim_tail
+    /// - returns: number of freed blocks
+    pub fn free_blocks_from(&mut self, block_count: u64) -> Result<usize, StorageError> {
+        let mut freed_blocks = 0;
+        for block_index in block_count..self.end_block_count {
+            if !self.is_empty_block(block_index) {
+                self.delete_block(block_index, false)?;
+                freed_blocks += 1;
+            }
+        }
+        Ok(freed_blocks)
+    }
+}
+
+#[cfg(test)]
+mod unit_tests_replica {
+    use super::*;
+
+    fn new_storage(tmp_dir: &tempfile::TempDir, name: &str) -> Storage {
+        let file_path = tmp_dir.path().join(name);
+        Storage::new(file_path.to_str().unwrap().to_string(), 4).unwrap()
+    }
+
+    #[test]
+    fn test_apply_changes() {
+        let tmp_dir = tempfile::tempdir().unwrap();
+        let mut primary = new_storage(&tmp_dir, "primary.hex");
+        let mut follower = new_storage(&tmp_dir, "follower.hex");
+        let record_id = primary.write_record(&[1; 6]).unwrap();
+        primary.write_block(2, &[2]).unwrap();
+        let since = primary.generation();
+        primary.write_block_tagged(3, &[3], 9).unwrap();
+        primary.delete_block(2, false).unwrap();
+        assert_eq!(primary.changed_blocks_since(since).unwrap(), vec![2, 3]);
+        // - full copy, records keep their chains
+        for block_index in primary.changed_blocks_since(0).unwrap() {
+            let change = primary.block_change(block_index).unwrap();
+            follower.apply_change(&change).unwrap();
+        }
+        assert_eq!(follower.read_record(record_id).unwrap(), vec![1; 6]);
+        assert!(follower.read_block(2).unwrap().1.is_empty());
+        assert_eq!(follower.read_block(3).unwrap().1, vec![3]);
+        assert_eq!(follower.block_tag(3).unwrap(), 9);
+        let change = primary.block_change(3).unwrap();
+        assert_eq!(change.generation, primary.generation() - 1);
+        assert_eq!(primary.block_change(9).unwrap().generation, 0);
+        // - blocks past the end of the primary
+        follower.write_block(5, &[5]).unwrap();
+        assert_eq!(follower.free_blocks_from(4).unwrap(), 1);
+        assert_eq!(follower.used_block_count(), 3);
+    }
+}
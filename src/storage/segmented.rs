@@ -0,0 +1,401 @@
+use super::*;
+use std::path::{Path, PathBuf};
+
+/// File name of manifest in directory of a SegmentedStorage
+const MANIFEST_FILE: &str = "manifest";
+/// File name prefix and extension of segment files
+const SEGMENT_PREFIX: &str = "segment-";
+const SEGMENT_EXTENSION: &str = ".se1";
+
+/// One logical store of blocks split over segment files in a directory
+/// - segment i is a storage file holding blocks i * blocks_per_segment up to
+///   (i + 1) * blocks_per_segment, addressed from 0 within the file
+/// - a segment file is created by the first write to one of its blocks, and deleted
+///   once its last used block is deleted
+/// - block_len and blocks_per_segment are kept in a manifest file in the directory
+/// - blocks of a record link by index within their segment, so a record never spans
+///   segments and can hold at most blocks_per_segment blocks
+pub struct SegmentedStorage {
+    dir: PathBuf,
+    block_len: u64,
+    blocks_per_segment: u64,
+    /// Open segment files by segment number
+    segments: BTreeMap<u64, Storage>,
+}
+
+impl SegmentedStorage {
+    // ... ... ... ... ... ... ... ... Constructors ... ... ... ... ... ... ... ... ...
+
+    /// Create new segmented storage in directory dir
+    /// - creates dir if it does not exist, and deletes segment files of an earlier
+    ///   segmented storage in it
+    /// - segment_len: size in bytes of each segment file, rounded down to whole blocks,
+    ///   at least one block per segment
+    /// - block_len must be within 1..=MAX_BLOCK_LEN
+    pub fn new(
+        dir: String,
+        block_len: u64,
+        segment_len: u64,
+    ) -> Result<SegmentedStorage, StorageError> {
+        let block_size = match block_size(block_len) {
+            Some(block_size) if block_len != 0 => block_size,
+            _ => return Err(StorageError::InvalidBlockLength { block_len }),
+        };
+        let dir = PathBuf::from(dir);
+        std::fs::create_dir_all(&dir)
+            .map_err(StorageError::io("create segment directory", None))?;
+        for (_, segment_path) in SegmentedStorage::segment_files(&dir)? {
+            std::fs::remove_file(segment_path)
+                .map_err(StorageError::io("delete segment file", None))?;
+        }
+        let storage = SegmentedStorage {
+            dir,
+            block_len,
+            blocks_per_segment: (segment_len / block_size).max(1),
+            segments: BTreeMap::new(),
+        };
+        storage.write_manifest()?;
+        Ok(storage)
+    }
+    /// Open existing segmented storage in directory dir
+    /// - reads manifest, then opens every segment file
+    pub fn open(dir: String) -> Result<SegmentedStorage, StorageError> {
+        let dir = PathBuf::from(dir);
+        let manifest = std::fs::read(dir.join(MANIFEST_FILE))
+            .map_err(StorageError::io("read segment manifest", None))?;
+        if manifest.len() != MANIFEST_SIZE || manifest[..4] != MANIFEST_MAGIC {
+            return Err(StorageError::NotStorageFile);
+        }
+        let version = get_u16(&manifest, MANIFEST_VERSION_OFFSET);
+        if version != MANIFEST_VERSION {
+            return Err(StorageError::UnsupportedVersion { version });
+        }
+        let block_len = get_u64(&manifest, MANIFEST_BLOCK_LEN_OFFSET);
+        let blocks_per_segment = get_u64(&manifest, MANIFEST_BLOCKS_PER_SEGMENT_OFFSET);
+        if block_len == 0 || block_len > MAX_BLOCK_LEN || blocks_per_segment == 0 {
+            return Err(StorageError::Corruption {
+                block_index: None,
+                reason: "segment manifest is out of range",
+            });
+        }
+        let mut segments = BTreeMap::new();
+        for (segment, segment_path) in SegmentedStorage::segment_files(&dir)? {
+            let storage = Storage::open(segment_path.to_string_lossy().into_owned())?;
+            if storage.block_len() != block_len {
+                return Err(StorageError::Corruption {
+                    block_index: None,
+                    reason: "block length of segment does not match manifest",
+                });
+            }
+            segments.insert(segment, storage);
+        }
+        Ok(SegmentedStorage {
+            dir,
+            block_len,
+            blocks_per_segment,
+            segments,
+        })
+    }
+    fn write_manifest(&self) -> Result<(), StorageError> {
+        let mut manifest = [0u8; MANIFEST_SIZE];
+        manifest[..4].copy_from_slice(&MANIFEST_MAGIC);
+        put_u16(&mut manifest, MANIFEST_VERSION_OFFSET, MANIFEST_VERSION);
+        put_u64(&mut manifest, MANIFEST_BLOCK_LEN_OFFSET, self.block_len);
+        put_u64(
+            &mut manifest,
+            MANIFEST_BLOCKS_PER_SEGMENT_OFFSET,
+            self.blocks_per_segment,
+        );
+        std::fs::write(self.dir.join(MANIFEST_FILE), manifest)
+            .map_err(StorageError::io("write segment manifest", None))
+    }
+    /// Segment number and path of every segment file in dir
+    fn segment_files(dir: &Path) -> Result<Vec<(u64, PathBuf)>, StorageError> {
+        let entries =
+            std::fs::read_dir(dir).map_err(StorageError::io("list segment directory", None))?;
+        let mut segment_files = Vec::new();
+        for entry in entries {
+            let entry = entry.map_err(StorageError::io("list segment directory", None))?;
+            let file_name = entry.file_name();
+            let segment = file_name
+                .to_str()
+                .and_then(|name| name.strip_prefix(SEGMENT_PREFIX))
+                .and_then(|name| name.strip_suffix(SEGMENT_EXTENSION))
+                .and_then(|number| number.parse::<u64>().ok());
+            if let Some(segment) = segment {
+                segment_files.push((segment, entry.path()));
+            }
+        }
+        Ok(segment_files)
+    }
+
+    // ... ... ... ... ... ... ... ... ... Segments ... ... ... ... ... ... ... ... ... .
+
+    /// Capacity of each block in bytes, same in every segment
+    pub fn block_len(&self) -> u64 {
+        self.block_len
+    }
+    /// Number of blocks each segment file holds
+    pub fn blocks_per_segment(&self) -> u64 {
+        self.blocks_per_segment
+    }
+    /// Number of segment files, empty segments are deleted
+    pub fn segment_count(&self) -> usize {
+        self.segments.len()
+    }
+    fn segment_path(&self, segment: u64) -> String {
+        let file_name = format!("{}{:08}{}", SEGMENT_PREFIX, segment, SEGMENT_EXTENSION);
+        self.dir.join(file_name).to_string_lossy().into_owned()
+    }
+    /// Segment number and index within segment of block
+    fn locate(&self, block_index: BlockIndex) -> (u64, BlockIndex) {
+        (
+            block_index / self.blocks_per_segment,
+            block_index % self.blocks_per_segment,
+        )
+    }
+    /// Open segment, creating its file if it does not exist
+    fn segment_mut(&mut self, segment: u64) -> Result<&mut Storage, StorageError> {
+        if !self.segments.contains_key(&segment) {
+            // every block of a segment is tracked by its bitmap where it fits in u32
+            let bitmap_capacity = u32::try_from(self.blocks_per_segment).unwrap_or(u32::MAX);
+            let storage = Storage::new_with_bitmap_capacity(
+                self.segment_path(segment),
+                self.block_len,
+                bitmap_capacity,
+            )?;
+            self.segments.insert(segment, storage);
+        }
+        // - inserted above if missing
+        self.segments
+            .get_mut(&segment)
+            .ok_or(StorageError::BlockOutOfRange {
+                block_index: segment.saturating_mul(self.blocks_per_segment),
+            })
+    }
+    /// Close and delete segment file if none of its blocks is used
+    fn remove_if_empty(&mut self, segment: u64) -> Result<(), StorageError> {
+        match self.segments.get(&segment) {
+            Some(storage) if storage.used_block_count() == 0 => {}
+            _ => return Ok(()),
+        }
+        if let Some(storage) = self.segments.remove(&segment) {
+            storage.close()?;
+            std::fs::remove_file(self.segment_path(segment))
+                .map_err(StorageError::io("delete segment file", None))?;
+        }
+        Ok(())
+    }
+
+    // ... ... ... ... ... ... ... ... ... Blocks ... ... ... ... ... ... ... ... ... ...
+
+    /// Read block data, see Storage::read_block
+    /// - returns: (read end offset within segment file, block data), empty data for
+    ///   blocks of segments that do not exist
+    pub fn read_block(&mut self, block_index: BlockIndex) -> Result<(u64, Vec<u8>), StorageError> {
+        let (segment, local_index) = self.locate(block_index);
+        match self.segments.get_mut(&segment) {
+            Some(storage) => storage.read_block(local_index),
+            None => Ok((0, Vec::new())),
+        }
+    }
+    /// Write data to block, see Storage::write_block
+    /// - creates segment file of block if it does not exist
+    /// - returns: end offset of written block data within segment file
+    pub fn write_block(
+        &mut self,
+        block_index: BlockIndex,
+        data: &[u8],
+    ) -> Result<u64, StorageError> {
+        let (segment, local_index) = self.locate(block_index);
+        let write_end_offset = self.segment_mut(segment)?.write_block(local_index, data)?;
+        // - empty data frees the block
+        self.remove_if_empty(segment)?;
+        Ok(write_end_offset)
+    }
+    /// Delete block, see Storage::delete_block
+    /// - deletes segment file once its last used block is deleted
+    /// - returns: end offset of written bytes within segment file, 0 if nothing was deleted
+    pub fn delete_block(
+        &mut self,
+        block_index: BlockIndex,
+        hard_delete: bool,
+    ) -> Result<u64, StorageError> {
+        let (segment, local_index) = self.locate(block_index);
+        let write_end_offset = match self.segments.get_mut(&segment) {
+            Some(storage) => storage.delete_block(local_index, hard_delete)?,
+            None => return Ok(0),
+        };
+        self.remove_if_empty(segment)?;
+        Ok(write_end_offset)
+    }
+
+    // ... ... ... ... ... ... ... ... ... Records ... ... ... ... ... ... ... ... ... ..
+
+    /// Write data as a record in the first segment with room for all its blocks
+    /// - RecordTooLarge if data needs more than blocks_per_segment blocks
+    /// - returns: record id
+    pub fn write_record(&mut self, data: &[u8]) -> Result<RecordId, StorageError> {
+        if data.is_empty() {
+            return Err(StorageError::EmptyRecord);
+        }
+        let chunk_len = usize::try_from(self.block_len).unwrap_or(usize::MAX);
+        let block_count = data.len().div_ceil(chunk_len);
+        if block_count as u64 > self.blocks_per_segment {
+            return Err(StorageError::RecordTooLarge {
+                block_count,
+                max_blocks: self.blocks_per_segment,
+            });
+        }
+        // - a missing segment has room, so the scan ends at the first one
+        let mut segment = 0;
+        loop {
+            let fits = match self.segments.get(&segment) {
+                Some(storage) => storage
+                    .allocate(block_count)?
+                    .last()
+                    .is_some_and(|last_block| *last_block < self.blocks_per_segment),
+                None => true,
+            };
+            if fits {
+                break;
+            }
+            segment += 1;
+        }
+        let local_id = self.segment_mut(segment)?.write_record(data)?;
+        Ok(segment * self.blocks_per_segment + local_id)
+    }
+    /// Read all data of a record, see Storage::read_record
+    pub fn read_record(&mut self, record_id: RecordId) -> Result<Vec<u8>, StorageError> {
+        let (segment, local_id) = self.locate(record_id);
+        match self.segments.get_mut(&segment) {
+            Some(storage) => storage.read_record(local_id),
+            None => Ok(Vec::new()),
+        }
+    }
+    /// Delete all blocks of a record, see Storage::delete_record
+    /// - returns: number of deleted blocks
+    pub fn delete_record(
+        &mut self,
+        record_id: RecordId,
+        hard_delete: bool,
+    ) -> Result<usize, StorageError> {
+        let (segment, local_id) = self.locate(record_id);
+        let deleted_blocks = match self.segments.get_mut(&segment) {
+            Some(storage) => storage.delete_record(local_id, hard_delete)?,
+            None => return Ok(0),
+        };
+        self.remove_if_empty(segment)?;
+        Ok(deleted_blocks)
+    }
+
+    // ... ... ... ... ... ... ... ... ... ... ... ... ... ... ... ... ... ... ... ... .
+
+    /// Sync writes of every segment to disk, see Storage::flush
+    pub fn flush(&mut self) -> Result<(), StorageError> {
+        for storage in self.segments.values_mut() {
+            storage.flush()?;
+        }
+        Ok(())
+    }
+    /// Close every segment file, see Storage::close
+    pub fn close(self) -> Result<(), StorageError> {
+        for (_, storage) in self.segments {
+            storage.close()?;
+        }
+        Ok(())
+    }
+}
+
+impl Storage {
+    /// Number of blocks holding data, without reading them from file (in memory)
+    pub(super) fn used_block_count(&self) -> u64 {
+        self.end_block_count - self.free_blocks.len() as u64
+    }
+}
+
+#[cfg(test)]
+mod unit_tests_segmented {
+    use super::*;
+
+    /// segments of 3 blocks of 4 bytes
+    fn new_storage(tmp_dir: &tempfile::TempDir) -> (SegmentedStorage, String) {
+        let dir = tmp_dir.path().join("segments");
+        let dir = dir.to_str().unwrap().to_string();
+        let segment_len = 3 * block_size(4).unwrap() + 1;
+        (
+            SegmentedStorage::new(dir.clone(), 4, segment_len).unwrap(),
+            dir,
+        )
+    }
+    fn segment_files(dir: &str) -> Vec<u64> {
+        let mut segments: Vec<u64> = SegmentedStorage::segment_files(Path::new(dir))
+            .unwrap()
+            .into_iter()
+            .map(|(segment, _)| segment)
+            .collect();
+        segments.sort_unstable();
+        segments
+    }
+
+    #[test]
+    fn test_blocks_across_segments() {
+        let tmp_dir = tempfile::tempdir().unwrap();
+        let (mut storage, dir) = new_storage(&tmp_dir);
+        assert_eq!(storage.blocks_per_segment(), 3);
+        storage.write_block(1, &[1]).unwrap();
+        storage.write_block(7, &[7]).unwrap();
+        assert_eq!(segment_files(&dir), vec![0, 2]);
+        assert_eq!(storage.read_block(7).unwrap().1, vec![7]);
+        assert!(storage.read_block(4).unwrap().1.is_empty());
+        // empty segment file is deleted
+        storage.delete_block(7, false).unwrap();
+        assert_eq!(segment_files(&dir), vec![0]);
+        assert_eq!(storage.delete_block(7, false).unwrap(), 0);
+        storage.close().unwrap();
+        let mut storage = SegmentedStorage::open(dir).unwrap();
+        assert_eq!(storage.segment_count(), 1);
+        assert_eq!(storage.block_len(), 4);
+        assert_eq!(storage.read_block(1).unwrap().1, vec![1]);
+    }
+    #[test]
+    fn test_records_within_segments() {
+        let tmp_dir = tempfile::tempdir().unwrap();
+        let (mut storage, dir) = new_storage(&tmp_dir);
+        let first = storage.write_record(&[1; 8]).unwrap();
+        // 2 blocks left in segment 0 are not enough, record goes to segment 1
+        let second = storage.write_record(&[2; 9]).unwrap();
+        let third = storage.write_record(&[3; 4]).unwrap();
+        assert_eq!((first, second, third), (0, 3, 2));
+        assert_eq!(storage.read_record(second).unwrap(), vec![2; 9]);
+        assert!(matches!(
+            storage.write_record(&[4; 13]),
+            Err(StorageError::RecordTooLarge {
+                block_count: 4,
+                max_blocks: 3
+            })
+        ));
+        assert_eq!(storage.delete_record(second, false).unwrap(), 3);
+        assert_eq!(segment_files(&dir), vec![0]);
+        assert!(storage.read_record(second).unwrap().is_empty());
+    }
+    #[test]
+    fn test_new_replaces_segments() {
+        let tmp_dir = tempfile::tempdir().unwrap();
+        let (mut storage, dir) = new_storage(&tmp_dir);
+        storage.write_block(5, &[5]).unwrap();
+        storage.close().unwrap();
+        let storage = SegmentedStorage::new(dir.clone(), 8, 0).unwrap();
+        assert_eq!(storage.blocks_per_segment(), 1);
+        assert!(segment_files(&dir).is_empty());
+        assert!(matches!(
+            SegmentedStorage::new(dir.clone(), 0, 0),
+            Err(StorageError::InvalidBlockLength { block_len: 0 })
+        ));
+        std::fs::write(Path::new(&dir).join(MANIFEST_FILE), b"not a manifest").unwrap();
+        assert!(matches!(
+            SegmentedStorage::open(dir),
+            Err(StorageError::NotStorageFile)
+        ));
+    }
+}
@@ -0,0 +1,157 @@
+use super::*;
+
+/// Stored data of a block read by a SharedReader, see Storage::decode_chunks
+pub(crate) struct StoredChunk {
+    block_index: BlockIndex,
+    block_data: Vec<u8>,
+    /// Flags of block header, tell how block data was compressed
+    block_flags: u32,
+}
+
+/// Read only view of a Storage, shared by threads reading at the same time
+/// - reads the storage file with positional IO, so threads do not race on a file offset
+/// - bypasses block cache and read backend; observers are not notified
+/// - returns stored block data, decode it with Storage::decode_chunks
+#[derive(Clone, Copy)]
+pub(crate) struct SharedReader<'a> {
+    file: &'a File,
+    header: &'a StorageHeader,
+    end_block_count: u64,
+    free_blocks: &'a BTreeSet<BlockIndex>,
+}
+
+impl<'a> SharedReader<'a> {
+    /// Stored data of each block of a record, in chain order, see Storage::read_record_chunks
+    pub(crate) fn read_record_chunks(
+        &self,
+        record_id: RecordId,
+    ) -> Result<Vec<StoredChunk>, StorageError> {
+        let mut chunks = Vec::new();
+        if self.is_empty_block(record_id) {
+            return Ok(chunks);
+        }
+        let mut block_index = record_id;
+        // - a record can not have more blocks than the file, longer chains loop
+        for _ in 0..self.end_block_count {
+            let (block_header, block_data) = self.read_stored_block(block_index)?;
+            if checksum::crc32(&block_data) != block_header.checksum {
+                return Err(StorageError::ChecksumMismatch { block_index });
+            }
+            chunks.push(StoredChunk {
+                block_index,
+                block_data,
+                block_flags: block_header.flags,
+            });
+            match block_header.next_block {
+                None => return Ok(chunks),
+                Some(next_block) if !self.is_empty_block(next_block) => {
+                    block_index = next_block;
+                }
+                Some(_) => break,
+            }
+        }
+        Err(broken_chain_error(record_id))
+    }
+    fn is_empty_block(&self, block_index: BlockIndex) -> bool {
+        block_index >= self.end_block_count || self.free_blocks.contains(&block_index)
+    }
+    /// Read block header and stored block data, see Storage::read_stored_block
+    fn read_stored_block(
+        &self,
+        block_index: BlockIndex,
+    ) -> Result<(BlockHeader, Vec<u8>), StorageError> {
+        let block_offset = block_offset(
+            self.header.bitmap_capacity,
+            self.header.block_len,
+            block_index,
+        )
+        .ok_or(StorageError::BlockOutOfRange { block_index })?;
+        let block_header_bytes = &mut [0u8; BLOCK_HEADER_SIZE];
+        let read_size = positional::read_at(self.file, block_offset, block_header_bytes)
+            .map_err(StorageError::io("read block header", Some(block_index)))?;
+        if read_size != BLOCK_HEADER_SIZE {
+            return Err(StorageError::Corruption {
+                block_index: Some(block_index),
+                reason: "block header is truncated",
+            });
+        }
+        let block_header = BlockHeader::from_bytes(block_header_bytes);
+        if block_header.block_data_size > self.header.block_len {
+            return Err(StorageError::Corruption {
+                block_index: Some(block_index),
+                reason: "data size in block header exceeds block length",
+            });
+        }
+        let mut block_data = vec![0u8; block_header.data_len(block_index)?];
+        let data_offset = block_offset + BLOCK_HEADER_SIZE as u64;
+        let read_size = positional::read_at(self.file, data_offset, &mut block_data)
+            .map_err(StorageError::io("read block data", Some(block_index)))?;
+        if read_size != block_data.len() {
+            return Err(StorageError::Corruption {
+                block_index: Some(block_index),
+                reason: "block data is truncated",
+            });
+        }
+        Ok((block_header, block_data))
+    }
+}
+
+impl Storage {
+    // ... ... ... ... ... ... ... ... ... Shared Reads ... ... ... ... ... ... ... ... ...
+
+    /// Read only view for reads from several threads, see SharedReader
+    pub(crate) fn shared_reader(&self) -> SharedReader<'_> {
+        SharedReader {
+            file: &self.file_reader,
+            header: &self.header,
+            end_block_count: self.end_block_count,
+            free_blocks: &self.free_blocks,
+        }
+    }
+    /// Reverse block transforms and compression on stored data of each block, read by a
+    /// SharedReader
+    pub(crate) fn decode_chunks(
+        &self,
+        chunks: Vec<StoredChunk>,
+    ) -> Result<Vec<Vec<u8>>, StorageError> {
+        chunks
+            .into_iter()
+            .map(|chunk| {
+                self.decode_block_data(chunk.block_index, chunk.block_data, chunk.block_flags)
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod unit_tests_shared {
+    use super::*;
+
+    #[test]
+    fn test_shared_reader_threads() {
+        let tmp_dir = tempfile::tempdir().unwrap();
+        let file_path = tmp_dir.path().join("reader.hex");
+        let mut storage = Storage::new(file_path.to_str().unwrap().to_string(), 4).unwrap();
+        let records: Vec<(RecordId, Vec<u8>)> = (1..8u8)
+            .map(|len| {
+                let data = vec![len; len as usize];
+                (storage.write_record(&data).unwrap(), data)
+            })
+            .collect();
+        let reader = storage.shared_reader();
+        std::thread::scope(|scope| {
+            for (record_id, data) in records.iter() {
+                scope.spawn(move || {
+                    let chunks = reader.read_record_chunks(*record_id).unwrap();
+                    // no transforms or compression, stored data is record data
+                    let stored: Vec<u8> = chunks
+                        .into_iter()
+                        .flat_map(|chunk| chunk.block_data)
+                        .collect();
+                    assert_eq!(stored, *data);
+                });
+            }
+        });
+        assert!(reader.read_record_chunks(100).unwrap().is_empty());
+    }
+}
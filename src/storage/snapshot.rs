@@ -0,0 +1,249 @@
+use super::*;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex, MutexGuard, Weak};
+
+/// Content of a used block as stored, before block transforms and compression are reversed
+#[derive(Clone, Default)]
+pub(super) struct BlockContent {
+    pub(super) block_data: Vec<u8>,
+    /// Flags of block header, tell how block data was compressed
+    pub(super) block_flags: u32,
+    pub(super) next_block: Option<BlockIndex>,
+    pub(super) expires_at: Option<u64>,
+    pub(super) tag: BlockTag,
+    pub(super) generation: Generation,
+}
+
+/// Content of a block when a snapshot was taken
+#[derive(Clone)]
+pub(super) enum SnapshotBlock {
+    Free,
+    Used(BlockContent),
+    /// Block could not be read back before it was overwritten
+    Corrupted,
+}
+
+/// Blocks changed since a snapshot was taken, with their content at that time
+#[derive(Default)]
+pub(super) struct UndoLog {
+    blocks: HashMap<BlockIndex, SnapshotBlock>,
+}
+
+/// Point in time view of block contents of a Storage
+/// - storage keeps an undo log per live snapshot: the first time a block changes after
+///   the snapshot, its previous content is copied into the log, in memory
+/// - blocks not in the log are read from storage file
+/// - must be used with the storage it was taken from
+/// - dropping the snapshot drops its undo log, block changes stop being copied for it
+pub struct Snapshot {
+    undo_log: Arc<Mutex<UndoLog>>,
+    end_block_count: u64,
+}
+
+impl Snapshot {
+    /// Number of blocks in storage file when snapshot was taken
+    pub fn block_count(&self) -> u64 {
+        self.end_block_count
+    }
+    /// Read block data as it was when snapshot was taken
+    /// - returns: empty vector for blocks that were free
+    pub fn read_block(
+        &self,
+        storage: &mut Storage,
+        block_index: BlockIndex,
+    ) -> Result<Vec<u8>, StorageError> {
+        match self.block(storage, block_index)? {
+            Some(content) => {
+                storage.decode_block_data(block_index, content.block_data, content.block_flags)
+            }
+            None => Ok(Vec::new()),
+        }
+    }
+    /// Read all data of a record as it was when snapshot was taken
+    pub fn read_record(
+        &self,
+        storage: &mut Storage,
+        record_id: RecordId,
+    ) -> Result<Vec<u8>, StorageError> {
+        let mut record = Vec::new();
+        let mut block_index = record_id;
+        // - a record can not have more blocks than the file, longer chains loop
+        for _ in 0..=self.end_block_count {
+            match self.block(storage, block_index)? {
+                None if record.is_empty() => return Ok(record),
+                None => break,
+                Some(content) => {
+                    record.append(&mut storage.decode_block_data(
+                        block_index,
+                        content.block_data,
+                        content.block_flags,
+                    )?);
+                    match content.next_block {
+                        Some(next_block) => block_index = next_block,
+                        None => return Ok(record),
+                    }
+                }
+            }
+        }
+        Err(StorageError::Corruption {
+            block_index: Some(record_id),
+            reason: "record block chain is broken",
+        })
+    }
+    /// Stored content of block at snapshot time, None if it was free
+    pub(super) fn block(
+        &self,
+        storage: &mut Storage,
+        block_index: BlockIndex,
+    ) -> Result<Option<BlockContent>, StorageError> {
+        if block_index >= self.end_block_count {
+            return Ok(None);
+        }
+        let logged = lock(&self.undo_log).blocks.get(&block_index).cloned();
+        match logged {
+            Some(SnapshotBlock::Free) => Ok(None),
+            Some(SnapshotBlock::Used(content)) => Ok(Some(content)),
+            Some(SnapshotBlock::Corrupted) => Err(StorageError::Corruption {
+                block_index: Some(block_index),
+                reason: "block could not be read when snapshot copied it",
+            }),
+            None => storage.current_block(block_index),
+        }
+    }
+}
+
+fn lock(undo_log: &Mutex<UndoLog>) -> MutexGuard<'_, UndoLog> {
+    // undo log stays consistent even if a holder panicked, entries are inserted whole
+    undo_log
+        .lock()
+        .unwrap_or_else(|poisoned| poisoned.into_inner())
+}
+
+impl Storage {
+    // ... ... ... ... ... ... ... ... ... Snapshots ... ... ... ... ... ... ... ... ... .
+
+    /// Take a point in time view of block contents, see Snapshot
+    pub fn snapshot(&mut self) -> Snapshot {
+        let undo_log = Arc::new(Mutex::new(UndoLog::default()));
+        self.snapshots.push(Arc::downgrade(&undo_log));
+        Snapshot {
+            undo_log,
+            end_block_count: self.end_block_count,
+        }
+    }
+    /// Copy current content of block into undo log of every live snapshot missing it,
+    /// and keep it for open read views, see Storage::read_view
+    /// - called before block is changed in storage file
+    pub(super) fn preserve_for_snapshots(
+        &mut self,
+        block_index: BlockIndex,
+    ) -> Result<(), StorageError> {
+        self.preserve_versions(block_index)?;
+        if self.snapshots.is_empty() {
+            return Ok(());
+        }
+        let undo_logs: Vec<Arc<Mutex<UndoLog>>> =
+            self.snapshots.iter().filter_map(Weak::upgrade).collect();
+        self.snapshots = undo_logs.iter().map(Arc::downgrade).collect();
+        let missing = undo_logs
+            .iter()
+            .any(|undo_log| !lock(undo_log).blocks.contains_key(&block_index));
+        if !missing {
+            return Ok(());
+        }
+        let snapshot_block = match self.current_block(block_index) {
+            Ok(None) => SnapshotBlock::Free,
+            Ok(Some(content)) => SnapshotBlock::Used(content),
+            Err(error) if error.is_corruption() => SnapshotBlock::Corrupted,
+            Err(error) => return Err(error),
+        };
+        for undo_log in undo_logs.iter() {
+            lock(undo_log)
+                .blocks
+                .entry(block_index)
+                .or_insert_with(|| snapshot_block.clone());
+        }
+        Ok(())
+    }
+    /// Stored content of block in storage file, None if free
+    /// - checksum is verified, block transforms and compression are not reversed
+    pub(super) fn current_block(
+        &mut self,
+        block_index: BlockIndex,
+    ) -> Result<Option<BlockContent>, StorageError> {
+        if self.is_empty_block(block_index) {
+            return Ok(None);
+        }
+        let (block_header, block_data) = self.read_stored_block(block_index)?;
+        self.verify_block_checksum(block_index, &block_header, &block_data)?;
+        Ok(Some(BlockContent {
+            block_data,
+            block_flags: block_header.flags,
+            next_block: block_header.next_block,
+            expires_at: block_header.expires_at,
+            tag: block_header.tag,
+            generation: block_header.generation,
+        }))
+    }
+}
+
+#[cfg(test)]
+mod unit_tests_snapshot {
+    use super::*;
+
+    fn new_storage(tmp_dir: &tempfile::TempDir) -> Storage {
+        let file_path = tmp_dir.path().join("snapshot.hex");
+        Storage::new(file_path.to_str().unwrap().to_string(), 4).unwrap()
+    }
+
+    #[test]
+    fn test_snapshot_blocks() {
+        let tmp_dir = tempfile::tempdir().unwrap();
+        let mut storage = new_storage(&tmp_dir);
+        storage.write_block(0, &[1]).unwrap();
+        storage.write_block(1, &[2]).unwrap();
+        let snapshot = storage.snapshot();
+        storage.write_block(0, &[3]).unwrap();
+        storage.write_block(0, &[4]).unwrap();
+        storage.delete_block(1, true).unwrap();
+        storage.write_block(2, &[5]).unwrap();
+        assert_eq!(snapshot.block_count(), 2);
+        assert_eq!(snapshot.read_block(&mut storage, 0).unwrap(), vec![1]);
+        assert_eq!(snapshot.read_block(&mut storage, 1).unwrap(), vec![2]);
+        assert_eq!(
+            snapshot.read_block(&mut storage, 2).unwrap(),
+            Vec::<u8>::new()
+        );
+        // storage sees latest writes
+        assert_eq!(storage.read_block(0).unwrap().1, vec![4]);
+        // only first change of each block is copied
+        assert_eq!(lock(&snapshot.undo_log).blocks.len(), 3);
+    }
+    #[test]
+    fn test_snapshot_records() {
+        let tmp_dir = tempfile::tempdir().unwrap();
+        let mut storage = new_storage(&tmp_dir);
+        let data: Vec<u8> = (0..10).collect();
+        let record_id = storage.write_record(&data).unwrap();
+        let snapshot = storage.snapshot();
+        storage.delete_record(record_id, false).unwrap();
+        storage.write_record(&[7; 6]).unwrap();
+        assert_eq!(snapshot.read_record(&mut storage, record_id).unwrap(), data);
+        assert_eq!(storage.read_record(record_id).unwrap(), vec![7; 6]);
+    }
+    #[test]
+    fn test_dropped_snapshot() {
+        let tmp_dir = tempfile::tempdir().unwrap();
+        let mut storage = new_storage(&tmp_dir);
+        storage.write_block(0, &[1]).unwrap();
+        let snapshot = storage.snapshot();
+        let later_snapshot = storage.snapshot();
+        drop(snapshot);
+        storage.write_block(0, &[2]).unwrap();
+        assert_eq!(storage.snapshots.len(), 1);
+        assert_eq!(later_snapshot.read_block(&mut storage, 0).unwrap(), vec![1]);
+        drop(later_snapshot);
+        storage.write_block(0, &[3]).unwrap();
+        assert!(storage.snapshots.is_empty());
+    }
+}
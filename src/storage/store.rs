@@ -0,0 +1,137 @@
+use super::*;
+
+/// Block operations shared by storage backends, e.g. Storage on file and MemStorage
+/// in memory
+/// - code written against BlockStore runs on any backend, e.g. tests on MemStorage
+///   without touching the filesystem
+/// - block data is as given to write_block, whatever the backend stores underneath
+pub trait BlockStore {
+    /// Capacity of each block in bytes
+    fn block_len(&self) -> u64;
+    /// Read block data, empty for free blocks and blocks past the end
+    fn read_block(&mut self, block_index: BlockIndex) -> Result<Vec<u8>, StorageError>;
+    /// Write data to block, empty data frees the block
+    /// - BlockTooLarge if data does not fit in block_len bytes
+    fn write_block(&mut self, block_index: BlockIndex, data: &[u8]) -> Result<(), StorageError>;
+    /// Delete block, marking it free
+    /// - hard_delete: also overwrite block data with zeros, where the backend keeps a copy
+    fn delete_block(
+        &mut self,
+        block_index: BlockIndex,
+        hard_delete: bool,
+    ) -> Result<(), StorageError>;
+    /// Pick block indexes for block_count new blocks, see Storage::allocate
+    fn allocate(&self, block_count: usize) -> Result<Vec<BlockIndex>, StorageError>;
+    /// Counts of blocks, without reading them (in memory)
+    fn stats(&self) -> StoreStats;
+}
+
+/// Block counts of a BlockStore
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct StoreStats {
+    /// Capacity of each block in bytes
+    pub block_len: u64,
+    /// Blocks up to the last block ever written, used or free
+    pub block_count: u64,
+    /// Blocks holding data
+    pub used_blocks: u64,
+    /// Free blocks below block_count
+    pub free_blocks: u64,
+    /// Free blocks whose file space is reserved by Storage::preallocate and that were not
+    /// written yet, part of free_blocks
+    pub preallocated_blocks: u64,
+    /// Bytes of storage file up to the end of its last block, see Storage::file_bytes
+    pub bytes: u64,
+    /// Quota of storage file in bytes, None if it has none, see Storage::max_bytes
+    pub max_bytes: Option<u64>,
+}
+
+impl BlockStore for Storage {
+    fn block_len(&self) -> u64 {
+        Storage::block_len(self)
+    }
+    fn read_block(&mut self, block_index: BlockIndex) -> Result<Vec<u8>, StorageError> {
+        let (_, block_data) = Storage::read_block(self, block_index)?;
+        Ok(block_data)
+    }
+    fn write_block(&mut self, block_index: BlockIndex, data: &[u8]) -> Result<(), StorageError> {
+        Storage::write_block(self, block_index, data)?;
+        Ok(())
+    }
+    fn delete_block(
+        &mut self,
+        block_index: BlockIndex,
+        hard_delete: bool,
+    ) -> Result<(), StorageError> {
+        Storage::delete_block(self, block_index, hard_delete)?;
+        Ok(())
+    }
+    fn allocate(&self, block_count: usize) -> Result<Vec<BlockIndex>, StorageError> {
+        Storage::allocate(self, block_count)
+    }
+    fn stats(&self) -> StoreStats {
+        StoreStats {
+            block_len: self.header.block_len,
+            block_count: self.end_block_count,
+            used_blocks: self.used_block_count(),
+            free_blocks: self.free_blocks.len() as u64,
+            preallocated_blocks: self.preallocated_blocks(),
+            bytes: self.file_bytes(),
+            max_bytes: self.max_bytes(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod unit_tests_store {
+    use super::*;
+
+    /// Same block operations give same results on every backend
+    fn exercise(store: &mut dyn BlockStore) {
+        assert_eq!(store.block_len(), 4);
+        store.write_block(0, &[1, 2]).unwrap();
+        store.write_block(3, &[3]).unwrap();
+        assert_eq!(store.read_block(3).unwrap(), vec![3]);
+        assert!(store.read_block(1).unwrap().is_empty());
+        assert!(store.read_block(9).unwrap().is_empty());
+        assert_eq!(store.allocate(3).unwrap(), vec![1, 2, 4]);
+        assert!(matches!(
+            store.write_block(1, &[0; 5]),
+            Err(StorageError::BlockTooLarge {
+                block_index: 1,
+                data_len: 5,
+                block_len: 4
+            })
+        ));
+        store.delete_block(0, true).unwrap();
+        store.write_block(3, &[]).unwrap();
+        assert!(store.read_block(0).unwrap().is_empty());
+        // - bytes of a backend depend on its layout
+        let stats = store.stats();
+        assert_eq!(
+            stats,
+            StoreStats {
+                block_len: 4,
+                block_count: 4,
+                used_blocks: 0,
+                free_blocks: 4,
+                preallocated_blocks: 0,
+                bytes: stats.bytes,
+                max_bytes: None,
+            }
+        );
+        assert_eq!(store.allocate(1).unwrap(), vec![0]);
+    }
+
+    #[test]
+    fn test_storage_block_store() {
+        let tmp_dir = tempfile::tempdir().unwrap();
+        let file_path = tmp_dir.path().join("store.hex");
+        let file_path = file_path.to_str().unwrap().to_string();
+        exercise(&mut Storage::new(file_path, 4).unwrap());
+    }
+    #[test]
+    fn test_mem_storage_block_store() {
+        exercise(&mut MemStorage::new(4).unwrap());
+    }
+}
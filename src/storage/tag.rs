@@ -0,0 +1,71 @@
+use super::*;
+
+/// Kind of a block, set by its writer and stored in its block header
+/// - e.g. to tell index blocks from data blocks, without reading block data
+/// - 0 for untagged blocks, the storage does not interpret tags
+pub type BlockTag = u16;
+
+impl Storage {
+    // ... ... ... ... ... ... ... ... ... Block Tags ... ... ... ... ... ... ... ... ... ...
+
+    /// Write data to block, like write_block, with a tag in its block header
+    /// - every other write sets tag 0, compaction, patches and restores keep it
+    /// - returns: end offset of written block data
+    pub fn write_block_tagged(
+        &mut self,
+        block_index: BlockIndex,
+        data: &[u8],
+        tag: BlockTag,
+    ) -> Result<u64, StorageError> {
+        self.write_linked_block(block_index, data, None, None, tag)
+    }
+    /// Tag of block, reading its block header only
+    /// - returns: 0 for untagged and free blocks
+    pub fn block_tag(&mut self, block_index: BlockIndex) -> Result<BlockTag, StorageError> {
+        if self.is_empty_block(block_index) {
+            return Ok(0);
+        }
+        Ok(self.read_block_header(block_index)?.tag)
+    }
+}
+
+#[cfg(test)]
+mod unit_tests_tag {
+    use super::*;
+
+    fn new_storage(tmp_dir: &tempfile::TempDir) -> Storage {
+        let file_path = tmp_dir.path().join("tag.hex");
+        Storage::new(file_path.to_str().unwrap().to_string(), 4).unwrap()
+    }
+
+    #[test]
+    fn test_block_tags() {
+        let tmp_dir = tempfile::tempdir().unwrap();
+        let mut storage = new_storage(&tmp_dir);
+        storage.write_block(0, &[1]).unwrap();
+        storage.write_block(1, &[9]).unwrap();
+        storage.write_block_tagged(2, &[2], 7).unwrap();
+        storage.write_block_tagged(3, &[3], 7).unwrap();
+        storage.write_block_tagged(4, &[4], 8).unwrap();
+        assert_eq!(storage.block_tag(0).unwrap(), 0);
+        assert_eq!(storage.block_tag(2).unwrap(), 7);
+        assert_eq!(storage.block_tag(9).unwrap(), 0);
+        let tagged: Vec<(BlockIndex, Vec<u8>)> = storage
+            .iter_blocks_with_tag(7)
+            .map(Result::unwrap)
+            .collect();
+        assert_eq!(tagged, vec![(2, vec![2]), (3, vec![3])]);
+        // compaction keeps tags of moved blocks, last blocks move first
+        storage.delete_block(0, false).unwrap();
+        storage.delete_block(1, false).unwrap();
+        storage.compact().unwrap();
+        let tags: Vec<BlockTag> = storage
+            .iter_block_headers()
+            .map(|info| info.unwrap().tag)
+            .collect();
+        assert_eq!(tags, vec![8, 7, 7]);
+        // untagged write clears the tag
+        storage.write_block(0, &[5]).unwrap();
+        assert_eq!(storage.block_tag(0).unwrap(), 0);
+    }
+}
@@ -0,0 +1,345 @@
+use super::*;
+
+/// Reversible transform of block data (e.g. compression, encryption, checksums)
+/// - encode is applied on write, decode on read
+/// - decode(encode(data)) must return data
+/// - name tells the transform apart in the pipeline recorded in the storage file, see
+///   Storage::push_transform
+pub trait BlockTransform: Send {
+    /// Name of transform, the same for every version of it that decodes the same data
+    fn name(&self) -> &str;
+    fn encode(&self, data: &[u8]) -> Result<Vec<u8>, StorageError>;
+    fn decode(&self, data: &[u8]) -> Result<Vec<u8>, StorageError>;
+    /// Most bytes encode adds to data of any length, so records are split in chunks
+    /// that still fit in a block once encoded, see Storage::chunk_len
+    /// - default is 0, for transforms that never grow data
+    fn overhead(&self) -> usize {
+        0
+    }
+    /// Encode data of a block, and set block flags telling how, see Pipeline
+    /// - default is encode, for transforms every block goes through
+    fn encode_block(
+        &self,
+        data: Vec<u8>,
+        _block_flags: &mut BlockFlags,
+    ) -> Result<Vec<u8>, StorageError> {
+        self.encode(&data)
+    }
+    /// Decode stored data of block_index, as told by block flags set by encode_block
+    /// - default is decode
+    fn decode_block(
+        &self,
+        _block_index: BlockIndex,
+        data: Vec<u8>,
+        _block_flags: BlockFlags,
+    ) -> Result<Vec<u8>, StorageError> {
+        self.decode(&data)
+    }
+}
+
+/// Block transform shared with other storage objects, see StorageOptions::transform
+impl<T: BlockTransform + Sync + ?Sized> BlockTransform for Arc<T> {
+    fn name(&self) -> &str {
+        (**self).name()
+    }
+    fn encode(&self, data: &[u8]) -> Result<Vec<u8>, StorageError> {
+        (**self).encode(data)
+    }
+    fn decode(&self, data: &[u8]) -> Result<Vec<u8>, StorageError> {
+        (**self).decode(data)
+    }
+    fn overhead(&self) -> usize {
+        (**self).overhead()
+    }
+    fn encode_block(
+        &self,
+        data: Vec<u8>,
+        block_flags: &mut BlockFlags,
+    ) -> Result<Vec<u8>, StorageError> {
+        (**self).encode_block(data, block_flags)
+    }
+    fn decode_block(
+        &self,
+        block_index: BlockIndex,
+        data: Vec<u8>,
+        block_flags: BlockFlags,
+    ) -> Result<Vec<u8>, StorageError> {
+        (**self).decode_block(block_index, data, block_flags)
+    }
+}
+
+/// Flags of the block header of a block, set by built-in transforms of the pipeline,
+/// e.g. to tell compressed blocks apart
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct BlockFlags(pub(super) u32);
+
+/// Block transforms block data goes through on write, in order, and back on read
+/// - compression first, see Storage::set_compression, then the transforms of
+///   Storage::push_transform, then encryption, see Storage::enable_encryption
+/// - compression and encryption are recorded in the storage header by their settings,
+///   and per block by block flags; the other transforms by the checksum of their names
+pub(super) struct Pipeline {
+    pub(super) compression: compression::CompressionTransform,
+    transforms: Vec<Box<dyn BlockTransform>>,
+    pub(super) encryption: crypto::EncryptionTransform,
+    /// Value of pipeline in storage header for transforms, see checksum
+    checksum: u32,
+}
+
+impl Pipeline {
+    /// Pipeline without transforms, compression and encryption set as in header
+    pub(super) fn new(header: &StorageHeader) -> Self {
+        let mut pipeline = Pipeline {
+            compression: Default::default(),
+            transforms: Vec::new(),
+            encryption: Default::default(),
+            checksum: 0,
+        };
+        pipeline.configure(header);
+        pipeline
+    }
+    /// Take compression and encryption settings of header, e.g. once it is read
+    pub(super) fn configure(&mut self, header: &StorageHeader) {
+        // - check_format rejects unknown compression bytes on open
+        self.compression.codec = Compression::from_byte(header.compression).unwrap_or_default();
+        self.compression.block_len = header.block_len;
+        self.encryption.encrypted = header.flags & STORAGE_FLAG_ENCRYPTED != 0;
+    }
+    fn push(&mut self, transform: Box<dyn BlockTransform>) {
+        self.transforms.push(transform);
+        let mut names = Vec::new();
+        for transform in self.transforms.iter() {
+            names.extend_from_slice(transform.name().as_bytes());
+            names.push(0);
+        }
+        self.checksum = checksum::crc32(&names);
+    }
+    /// CRC-32 of names of transforms between compression and encryption, each followed
+    /// by a 0 byte, in pipeline order; 0 without any, see layout
+    pub(super) fn checksum(&self) -> u32 {
+        self.checksum
+    }
+    /// Most bytes the transforms between compression and encryption add to block data,
+    /// see BlockTransform::overhead
+    /// - compression stores data that does not shrink as is, encryption overhead is
+    ///   taken off by Storage::unencrypted_block_len
+    pub(super) fn overhead(&self) -> u64 {
+        self.transforms
+            .iter()
+            .map(|transform| transform.overhead() as u64)
+            .fold(0, u64::saturating_add)
+    }
+    /// Every transform of the pipeline, in pipeline order
+    fn stages(&self) -> Vec<&dyn BlockTransform> {
+        let mut stages: Vec<&dyn BlockTransform> = vec![&self.compression];
+        stages.extend(self.transforms.iter().map(|transform| transform.as_ref()));
+        stages.push(&self.encryption);
+        stages
+    }
+}
+
+impl Storage {
+    // ... ... ... ... ... ... ... ... ... Block transforms ... ... ... ... ... ... ... ...
+
+    /// Append transform to the pipeline, between compression and encryption
+    /// - on write, transforms are applied in the order they were pushed
+    /// - on read, transforms are reversed in the opposite order
+    /// - the pipeline is recorded in the storage header by the names of its transforms:
+    ///   a storage file without used blocks records the new pipeline, else block data is
+    ///   only read and written once the transforms are the ones recorded, until then
+    ///   with PipelineMismatch; give them on every open, see StorageOptions::transform
+    pub fn push_transform(
+        &mut self,
+        transform: Box<dyn BlockTransform>,
+    ) -> Result<(), StorageError> {
+        self.pipeline.push(transform);
+        let checksum = self.pipeline.checksum();
+        if checksum != self.header.pipeline && self.used_block_count() == 0 && !self.read_only {
+            self.header.pipeline = checksum;
+            self.set_storage_header()?;
+            self.written(1)?;
+        }
+        Ok(())
+    }
+    /// Check transforms are the pipeline recorded in storage header
+    pub(super) fn check_pipeline(&self) -> Result<(), StorageError> {
+        let configured = self.pipeline.checksum();
+        if configured != self.header.pipeline {
+            return Err(StorageError::PipelineMismatch {
+                recorded: self.header.pipeline,
+                configured,
+            });
+        }
+        Ok(())
+    }
+    /// Check if block data goes through transforms other than compression and encryption
+    pub(super) fn has_block_transforms(&self) -> bool {
+        self.header.pipeline != 0 || self.pipeline.checksum() != 0
+    }
+    /// Compress block data, see Storage::set_compression, apply all transforms in
+    /// pipeline order, then encrypt it, see Storage::enable_encryption
+    /// - empty data is kept empty, it marks a free block, transforms that add bytes to
+    ///   any input would keep the block used
+    /// - returns: stored block data and block flags of its block header
+    pub(super) fn encode_block_data(&self, data: &[u8]) -> Result<(Vec<u8>, u32), StorageError> {
+        self.check_pipeline()?;
+        if data.is_empty() {
+            return Ok((Vec::new(), 0));
+        }
+        let mut block_flags = BlockFlags::default();
+        let mut encoded = data.to_vec();
+        for stage in self.pipeline.stages() {
+            encoded = stage.encode_block(encoded, &mut block_flags)?;
+        }
+        Ok((encoded, block_flags.0))
+    }
+    /// Decrypt stored block data, reverse all transforms in reverse pipeline order,
+    /// then decompress it as told by block flags of its block header
+    /// - empty stored data is a free block, it decodes to empty data
+    pub(super) fn decode_block_data(
+        &self,
+        block_index: BlockIndex,
+        data: Vec<u8>,
+        block_flags: u32,
+    ) -> Result<Vec<u8>, StorageError> {
+        self.check_pipeline()?;
+        if data.is_empty() {
+            return Ok(data);
+        }
+        let mut decoded = data;
+        for stage in self.pipeline.stages().into_iter().rev() {
+            decoded = stage.decode_block(block_index, decoded, BlockFlags(block_flags))?;
+        }
+        Ok(decoded)
+    }
+}
+
+#[cfg(test)]
+mod unit_tests_transform {
+    use super::*;
+
+    /// appends marker byte on encode, strips it on decode, named by name
+    struct AppendByte(u8, &'static str);
+    impl BlockTransform for AppendByte {
+        fn name(&self) -> &str {
+            self.1
+        }
+        fn encode(&self, data: &[u8]) -> Result<Vec<u8>, StorageError> {
+            Ok([data, &[self.0]].concat())
+        }
+        fn decode(&self, data: &[u8]) -> Result<Vec<u8>, StorageError> {
+            match data.split_last() {
+                Some((last, rest)) if *last == self.0 => Ok(rest.to_vec()),
+                _ => Err(StorageError::Transform {
+                    message: "Missing marker byte".to_string(),
+                }),
+            }
+        }
+        fn overhead(&self) -> usize {
+            1
+        }
+    }
+
+    fn new_storage(tmp_dir: &tempfile::TempDir) -> (Storage, String) {
+        let file_path = tmp_dir.path().join("transform.hex");
+        let file_path = file_path.to_str().unwrap().to_string();
+        (Storage::new(file_path.clone(), 8).unwrap(), file_path)
+    }
+
+    #[test]
+    fn test_pipeline_order() {
+        let tmp_dir = tempfile::tempdir().unwrap();
+        let (mut storage, file_path) = new_storage(&tmp_dir);
+        storage
+            .push_transform(Box::new(AppendByte(0xa, "a")))
+            .unwrap();
+        storage
+            .push_transform(Box::new(AppendByte(0xb, "b")))
+            .unwrap();
+        storage.write_block(0, &[1, 2, 3]).unwrap();
+        // stored data is encoded in push order
+        let file_bytes = std::fs::read(file_path).unwrap();
+        let block_0_offset = storage.block_offset(0).unwrap() as usize;
+        assert_eq!(file_bytes[block_0_offset..block_0_offset + 4], [5, 0, 0, 0]);
+        let block_0_data_offset = block_0_offset + BLOCK_HEADER_SIZE;
+        assert_eq!(file_bytes[block_0_data_offset..], [1, 2, 3, 0xa, 0xb]);
+        // read reverses the pipeline
+        let (_, data) = storage.read_block(0).unwrap();
+        assert_eq!(data, vec![1, 2, 3]);
+        // pipeline is recorded by names, in push order
+        assert_eq!(storage.header.pipeline, checksum::crc32(b"a\0b\0"));
+        // empty data skips the pipeline and frees the block
+        storage.write_block(0, &[]).unwrap();
+        assert_eq!(storage.iter_blocks().count(), 0);
+        assert_eq!(storage.read_block(0).unwrap().1, Vec::<u8>::new());
+    }
+    #[test]
+    fn test_record_through_transforms() {
+        let tmp_dir = tempfile::tempdir().unwrap();
+        let (mut storage, _) = new_storage(&tmp_dir);
+        storage
+            .push_transform(Box::new(AppendByte(0xa, "a")))
+            .unwrap();
+        storage
+            .push_transform(Box::new(AppendByte(0xb, "b")))
+            .unwrap();
+        // chunks leave room for the bytes the transforms add
+        assert_eq!(storage.chunk_len(), 6);
+        let data: Vec<u8> = (0..20).collect();
+        let record_id = storage.write_record(&data).unwrap();
+        assert_eq!(storage.record_blocks(record_id).unwrap().len(), 4);
+        assert_eq!(storage.read_record(record_id).unwrap(), data);
+    }
+    #[test]
+    fn test_decode_failure() {
+        let tmp_dir = tempfile::tempdir().unwrap();
+        let (mut storage, file_path) = new_storage(&tmp_dir);
+        storage
+            .push_transform(Box::new(AppendByte(0xa, "a")))
+            .unwrap();
+        storage.write_block(0, &[1, 2, 3]).unwrap();
+        drop(storage);
+        // a transform of the same name is trusted to decode the data
+        let options = StorageOptions::new(file_path).transform(Arc::new(AppendByte(0xb, "a")));
+        let mut storage = options.open().unwrap();
+        assert!(matches!(
+            storage.read_block(0),
+            Err(StorageError::Transform { .. })
+        ));
+    }
+    #[test]
+    fn test_pipeline_mismatch() {
+        let tmp_dir = tempfile::tempdir().unwrap();
+        let (_, file_path) = new_storage(&tmp_dir);
+        let options =
+            StorageOptions::new(file_path.clone()).transform(Arc::new(AppendByte(0xa, "a")));
+        // - a file without used blocks records the pipeline given
+        let mut storage = options.clone().open().unwrap();
+        storage.write_block(0, &[1]).unwrap();
+        drop(storage);
+        let recorded = checksum::crc32(b"a\0");
+        // - without transforms, the file opens and its blocks are not read or written
+        let mut storage = Storage::open(file_path.clone()).unwrap();
+        assert!(matches!(
+            storage.read_block(0),
+            Err(StorageError::PipelineMismatch { recorded: r, configured: 0 }) if r == recorded
+        ));
+        assert!(matches!(
+            storage.write_block(1, &[2]),
+            Err(StorageError::PipelineMismatch { .. })
+        ));
+        // - pushing the recorded transforms again reads the blocks
+        storage
+            .push_transform(Box::new(AppendByte(0xa, "a")))
+            .unwrap();
+        assert_eq!(storage.read_block(0).unwrap().1, vec![1]);
+        drop(storage);
+        // - other transforms are rejected on open
+        let other = StorageOptions::new(file_path).transform(Arc::new(AppendByte(0xb, "b")));
+        assert!(matches!(
+            other.open(),
+            Err(StorageError::PipelineMismatch { recorded: r, .. }) if r == recorded
+        ));
+        assert_eq!(options.open().unwrap().read_block(0).unwrap().1, vec![1]);
+    }
+}
@@ -0,0 +1,525 @@
+//! Upgrade of storage files written by older format versions, see Storage::upgrade
+//! - every bump from a format version to the next is a rewrite of the storage header
+//!   and of block headers, see bump_storage_header and bump_block_header, they are
+//!   applied in turn up to FORMAT_VERSION
+//! - block data is the same in every version, it is copied as it is
+use super::checksum::crc32;
+use super::*;
+
+/// First format version, the baseline with only block_len in the storage header
+const OLDEST_FORMAT_VERSION: u16 = 1;
+/// Last format version without magic, a file of it or older is told apart by which
+/// layout it is consistent with, see Storage::upgrade
+const LAST_VERSION_WITHOUT_MAGIC: u16 = 4;
+
+/// Size of storage header of format version, of its first copy
+fn storage_header_size(version: u16) -> usize {
+    match version {
+        1 => 4,
+        2..=4 => 12,
+        5 => 18,
+        6 => 22,
+        7..=9 => 23,
+        10 => 31,
+        11 => 43,
+        12 => 47,
+        _ => STORAGE_HEADER_SIZE,
+    }
+}
+
+/// Number of copies of storage header of format version, see layout
+fn storage_header_copies(version: u16) -> u64 {
+    if version >= 11 {
+        STORAGE_HEADER_COPIES
+    } else {
+        1
+    }
+}
+
+/// Size of block header of format version
+fn block_header_size(version: u16) -> usize {
+    match version {
+        1 | 2 => 4,
+        3 => 8,
+        4 | 5 => 12,
+        6 => 20,
+        7 => 24,
+        8 | 9 => 32,
+        _ => BLOCK_HEADER_SIZE,
+    }
+}
+
+/// Data size in block header of format version, u32 before version 6
+fn data_size(version: u16, block_header: &[u8]) -> u64 {
+    if version < 6 {
+        get_u32(block_header, BLOCK_HEADER_DATA_SIZE_OFFSET) as u64
+    } else {
+        get_u64(block_header, BLOCK_HEADER_DATA_SIZE_OFFSET)
+    }
+}
+
+/// Storage header of format version from, rewritten in format version from + 1
+fn bump_storage_header(from: u16, header: &[u8]) -> Vec<u8> {
+    let mut bumped = header.to_vec();
+    match from {
+        // - version 2 added bitmap_capacity and flags, a file without bitmap gets the
+        //   capacity of new files
+        1 => {
+            bumped.extend_from_slice(&DEFAULT_BITMAP_CAPACITY.to_le_bytes());
+            bumped.extend_from_slice(&0u32.to_le_bytes());
+        }
+        // - versions 3 and 4 changed block headers only
+        2 | 3 => {}
+        // - version 5 added magic and format version before every field
+        4 => {
+            bumped = STORAGE_MAGIC.to_vec();
+            bumped.extend_from_slice(&[0, 0]);
+            bumped.extend_from_slice(header);
+        }
+        // - version 6 stored block_len as u64
+        5 => {
+            bumped = header[..STORAGE_HEADER_BLOCK_LEN_OFFSET].to_vec();
+            let block_len = get_u32(header, STORAGE_HEADER_BLOCK_LEN_OFFSET) as u64;
+            bumped.extend_from_slice(&block_len.to_le_bytes());
+            bumped.extend_from_slice(&header[STORAGE_HEADER_BLOCK_LEN_OFFSET + 4..]);
+        }
+        // - version 7 added compression, none for blocks written before
+        6 => bumped.push(Compression::None.to_byte()),
+        // - versions 8 and 9 changed block headers only
+        7 | 8 => {}
+        // - version 10 added generation, taken from block headers on open
+        9 => bumped.extend_from_slice(&0u64.to_le_bytes()),
+        // - version 11 added sequence and checksum, the checksum is set when written
+        10 => {
+            bumped.extend_from_slice(&0u64.to_le_bytes());
+            bumped.extend_from_slice(&0u32.to_le_bytes());
+        }
+        // - version 12 added pipeline before the checksum, blocks of version 11 went
+        //   through no block transforms that were recorded
+        11 => {
+            bumped = header[..STORAGE_HEADER_PIPELINE_OFFSET].to_vec();
+            bumped.extend_from_slice(&0u32.to_le_bytes());
+            bumped.extend_from_slice(&header[STORAGE_HEADER_PIPELINE_OFFSET..]);
+        }
+        // - version 13 added dedup table before the checksum, none in older files
+        _ => {
+            bumped = header[..STORAGE_HEADER_DEDUP_TABLE_OFFSET].to_vec();
+            bumped.extend_from_slice(&0u64.to_le_bytes());
+            bumped.extend_from_slice(&header[STORAGE_HEADER_DEDUP_TABLE_OFFSET..]);
+        }
+    }
+    if from >= LAST_VERSION_WITHOUT_MAGIC {
+        put_u16(&mut bumped, STORAGE_HEADER_VERSION_OFFSET, from + 1);
+    }
+    bumped
+}
+
+/// Block header of format version from, rewritten in format version from + 1
+/// - block_data: data stored in the block, data size bytes
+fn bump_block_header(
+    from: u16,
+    block_header: &[u8],
+    block_data: &[u8],
+    block_index: BlockIndex,
+) -> Result<Vec<u8>, StorageError> {
+    let mut bumped = block_header.to_vec();
+    match from {
+        // - version 2 changed storage header only
+        1 => {}
+        // - version 3 added checksum of block data
+        2 => bumped.extend_from_slice(&crc32(block_data).to_le_bytes()),
+        // - version 4 added next block, records of version 3 are single blocks
+        3 => bumped.extend_from_slice(&0u32.to_le_bytes()),
+        // - version 5 changed storage header only
+        4 => {}
+        // - version 6 stored data size and next block as u64
+        5 => {
+            let next_block = get_u32(block_header, 8) as u64;
+            bumped = data_size(from, block_header).to_le_bytes().to_vec();
+            bumped.extend_from_slice(&block_header[4..8]);
+            bumped.extend_from_slice(&next_block.to_le_bytes());
+        }
+        // - version 7 added block flags, blocks written before were not compressed
+        6 => bumped.extend_from_slice(&0u32.to_le_bytes()),
+        // - version 8 added expires at, blocks written before never expire
+        7 => bumped.extend_from_slice(&0u64.to_le_bytes()),
+        // - version 9 stored block flags as u16 and added tag, untagged before
+        8 => {
+            let flags = get_u32(block_header, BLOCK_HEADER_FLAGS_OFFSET);
+            let flags = u16::try_from(flags).map_err(|_| StorageError::Corruption {
+                block_index: Some(block_index),
+                reason: "block flags do not fit in u16",
+            })?;
+            put_u16(&mut bumped, BLOCK_HEADER_FLAGS_OFFSET, flags);
+            put_u16(&mut bumped, BLOCK_HEADER_TAG_OFFSET, 0);
+        }
+        // - version 10 added generation, a used block gets block index + 1, so every
+        //   used block has a generation of its own
+        9 => {
+            let generation = if data_size(from, block_header) > 0 {
+                block_index.saturating_add(1)
+            } else {
+                0
+            };
+            bumped.extend_from_slice(&generation.to_le_bytes());
+        }
+        // - versions 11 and 12 changed storage header only
+        _ => {}
+    }
+    Ok(bumped)
+}
+
+/// Read storage header of format version at start of file, rewritten in FORMAT_VERSION
+/// - of a file with copies of the header, the newest copy whose checksum matches
+fn read_storage_header(file: &File, version: u16) -> Result<StorageHeader, StorageError> {
+    let header_size = storage_header_size(version);
+    let mut copies_bytes = vec![0u8; header_size * storage_header_copies(version) as usize];
+    let read_size = positional::read_at(file, 0, &mut copies_bytes)
+        .map_err(StorageError::io("read storage header", None))?;
+    if read_size < copies_bytes.len() {
+        return Err(StorageError::Corruption {
+            block_index: None,
+            reason: "storage header is truncated",
+        });
+    }
+    let mut header_bytes = copies_bytes[..header_size].to_vec();
+    if storage_header_copies(version) > 1 {
+        // - checksum is the last field of every version with copies
+        let checksum_offset = header_size - 4;
+        let newest_copy = copies_bytes
+            .chunks_exact(header_size)
+            .filter(|copy| get_u32(copy, checksum_offset) == crc32(&copy[..checksum_offset]))
+            .max_by_key(|copy| get_u64(copy, STORAGE_HEADER_SEQUENCE_OFFSET));
+        header_bytes = match newest_copy {
+            Some(copy) => copy.to_vec(),
+            None => {
+                return Err(StorageError::Corruption {
+                    block_index: None,
+                    reason: "every copy of storage header is damaged",
+                })
+            }
+        };
+    }
+    for from in version..FORMAT_VERSION {
+        header_bytes = bump_storage_header(from, &header_bytes);
+    }
+    let mut bytes = [0u8; STORAGE_HEADER_SIZE];
+    bytes.copy_from_slice(&header_bytes);
+    let header = StorageHeader::from_bytes(&bytes);
+    // - versions without magic had the dirty flag only
+    if version <= LAST_VERSION_WITHOUT_MAGIC && header.flags & !STORAGE_FLAG_DIRTY != 0 {
+        return Err(StorageError::Corruption {
+            block_index: None,
+            reason: "unknown flags in storage header",
+        });
+    }
+    header.check_format()?;
+    Ok(header)
+}
+
+/// Read every block of file of format version, with its block header rewritten in
+/// FORMAT_VERSION, and write it to upgraded, if any, at its offset in FORMAT_VERSION
+/// - Corruption if a block does not fit the layout of version
+/// - returns: number of blocks
+fn upgrade_blocks(
+    file: &File,
+    version: u16,
+    header: &StorageHeader,
+    upgraded: Option<&File>,
+) -> Result<BlockIndex, StorageError> {
+    let file_len = file
+        .metadata()
+        .map_err(StorageError::io("read storage file metadata", None))?
+        .len();
+    let bitmap_len = if version > OLDEST_FORMAT_VERSION {
+        bitmap_len(header.bitmap_capacity)
+    } else {
+        0
+    };
+    let header_size = block_header_size(version);
+    let block_size = header.block_len.saturating_add(header_size as u64);
+    let headers_len = storage_header_size(version) as u64 * storage_header_copies(version);
+    let mut offset = Some(headers_len + bitmap_len);
+    let mut block_index: BlockIndex = 0;
+    let mut linked_blocks: BlockIndex = 0;
+    while let Some(old_offset) = offset.filter(|old_offset| *old_offset < file_len) {
+        let corruption = |reason| StorageError::Corruption {
+            block_index: Some(block_index),
+            reason,
+        };
+        // - block header
+        let mut block_header = vec![0u8; header_size];
+        let read_size = positional::read_at(file, old_offset, &mut block_header)
+            .map_err(StorageError::io("read block header", Some(block_index)))?;
+        if read_size < header_size {
+            return Err(corruption("block header is truncated"));
+        }
+        // - block data, a block of baseline files may be cut short after its data
+        let data_size = data_size(version, &block_header);
+        if data_size > header.block_len {
+            return Err(corruption("data size in block header exceeds block length"));
+        }
+        let mut block_data = vec![0u8; checked_usize(data_size)?];
+        let data_offset = old_offset + header_size as u64;
+        let read_size = positional::read_at(file, data_offset, &mut block_data)
+            .map_err(StorageError::io("read block data", Some(block_index)))?;
+        if read_size < block_data.len() {
+            return Err(corruption("block data is truncated"));
+        }
+        for from in version..FORMAT_VERSION {
+            block_header = bump_block_header(from, &block_header, &block_data, block_index)?;
+        }
+        let mut bytes = [0u8; BLOCK_HEADER_SIZE];
+        bytes.copy_from_slice(&block_header);
+        let upgraded_header = BlockHeader::from_bytes(&bytes);
+        if upgraded_header.checksum != crc32(&block_data) {
+            return Err(corruption("stored data does not match checksum"));
+        }
+        if let Some(next_block) = upgraded_header.next_block {
+            linked_blocks = linked_blocks.max(next_block.saturating_add(1));
+        }
+        if let Some(upgraded) = upgraded {
+            let upgraded_offset =
+                block_offset(header.bitmap_capacity, header.block_len, block_index)
+                    .ok_or(StorageError::BlockOutOfRange { block_index })?;
+            positional::write_at(upgraded, upgraded_offset, &bytes)
+                .and_then(|_| {
+                    positional::write_at(
+                        upgraded,
+                        upgraded_offset + BLOCK_HEADER_SIZE as u64,
+                        &block_data,
+                    )
+                })
+                .map_err(StorageError::io("write upgraded block", Some(block_index)))?;
+        }
+        block_index += 1;
+        offset = old_offset.checked_add(block_size);
+    }
+    // - a link to a block past the end is a file of another layout
+    if linked_blocks > block_index {
+        return Err(StorageError::Corruption {
+            block_index: None,
+            reason: "block links to a block past end of file",
+        });
+    }
+    Ok(block_index)
+}
+
+/// Format version of file, None for a file without magic, version 4 or older
+fn stored_version(file: &File) -> Result<Option<u16>, StorageError> {
+    let mut bytes = [0u8; STORAGE_HEADER_BLOCK_LEN_OFFSET];
+    let read_size = positional::read_at(file, 0, &mut bytes)
+        .map_err(StorageError::io("read storage header", None))?;
+    if read_size == bytes.len() && bytes[..STORAGE_MAGIC.len()] == STORAGE_MAGIC {
+        return Ok(Some(get_u16(&bytes, STORAGE_HEADER_VERSION_OFFSET)));
+    }
+    Ok(None)
+}
+
+/// Newest format version without magic whose layout file is consistent with
+/// - NotStorageFile if file is consistent with none of them
+fn detect_version(file: &File) -> Result<u16, StorageError> {
+    for version in (OLDEST_FORMAT_VERSION..=LAST_VERSION_WITHOUT_MAGIC).rev() {
+        let consistent = read_storage_header(file, version)
+            .and_then(|header| upgrade_blocks(file, version, &header, None));
+        match consistent {
+            Ok(_) => return Ok(version),
+            Err(error @ StorageError::Io { .. }) => return Err(error),
+            Err(_) => {}
+        }
+    }
+    Err(StorageError::NotStorageFile)
+}
+
+/// Write file of format version to upgraded_path in FORMAT_VERSION
+/// - header is marked dirty, so the allocation bitmap and the generation are rebuilt
+///   from block headers by the next open
+fn write_upgraded(
+    file: &File,
+    version: u16,
+    mut header: StorageHeader,
+    upgraded_path: &str,
+) -> Result<(), StorageError> {
+    let upgraded = OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .open(upgraded_path)
+        .map_err(StorageError::io("create upgraded storage file", None))?;
+    header.flags |= STORAGE_FLAG_DIRTY;
+    for sequence in 0..STORAGE_HEADER_COPIES {
+        header.sequence = sequence;
+        positional::write_at(
+            &upgraded,
+            storage_header_offset(sequence),
+            &header.to_bytes(),
+        )
+        .map_err(StorageError::io("write storage header", None))?;
+    }
+    // - allocation bitmap is zeros until rebuilt
+    upgraded
+        .set_len(blocks_offset(header.bitmap_capacity))
+        .map_err(StorageError::io("write allocation bitmap", None))?;
+    upgrade_blocks(file, version, &header, Some(&upgraded))?;
+    upgraded
+        .sync_all()
+        .map_err(StorageError::io("sync upgraded storage file", None))
+}
+
+impl Storage {
+    // ... ... ... ... ... ... ... ... ... Format Upgrade ... ... ... ... ... ... ... ... ..
+
+    /// Rewrite storage file written by an older format version in FORMAT_VERSION, so
+    /// it can be opened, e.g. after open failed with UnsupportedVersion or NotStorageFile
+    /// - every bump between the versions is applied in turn, see layout for what each
+    ///   version changed; block data is copied as it is and blocks keep their index, so
+    ///   record ids stay valid
+    /// - versions 1 to 4 have no magic, the version is the newest of them whose layout
+    ///   the file is consistent with: sizes, checksums and links of its blocks;
+    ///   from_version names the version instead, e.g. for a file with no used blocks,
+    ///   which is consistent with more than one; it is ignored for files with magic
+    /// - used blocks of files older than version 10 get generation block index + 1
+    /// - the allocation bitmap is rebuilt from block headers
+    /// - the upgraded file is written to `<file_path>.upgrade` and opened once, then
+    ///   renamed over file_path, so a failed upgrade leaves file_path as it was
+    /// - the file is locked like open locks it, AlreadyLocked while a storage holds it
+    /// - returns: format version the file was written in, FORMAT_VERSION if it was
+    ///   already current and left alone
+    pub fn upgrade(file_path: &str, from_version: Option<u16>) -> Result<u16, StorageError> {
+        let file = lock::open_locked(
+            OpenOptions::new().read(true),
+            file_path,
+            FileLock::Exclusive,
+        )?;
+        let version = match (stored_version(&file)?, from_version) {
+            (Some(version), _) if version > LAST_VERSION_WITHOUT_MAGIC => version,
+            (Some(version), _) => return Err(StorageError::UnsupportedVersion { version }),
+            (None, Some(version)) if version <= LAST_VERSION_WITHOUT_MAGIC => version,
+            (None, Some(version)) => return Err(StorageError::UnsupportedVersion { version }),
+            (None, None) => detect_version(&file)?,
+        };
+        if version == FORMAT_VERSION {
+            return Ok(version);
+        }
+        if version > FORMAT_VERSION {
+            return Err(StorageError::UnsupportedVersion { version });
+        }
+        let header = read_storage_header(&file, version)?;
+        let upgraded_path = format!("{}.upgrade", file_path);
+        let upgraded = write_upgraded(&file, version, header, &upgraded_path)
+            .and_then(|()| Storage::open(upgraded_path.clone())?.close())
+            .and_then(|()| {
+                std::fs::rename(&upgraded_path, file_path)
+                    .map_err(StorageError::io("replace storage file", None))
+            });
+        if upgraded.is_err() {
+            let _ = std::fs::remove_file(&upgraded_path);
+        }
+        upgraded.map(|()| version)
+    }
+}
+
+#[cfg(test)]
+mod unit_tests_upgrade {
+    use super::*;
+
+    /// Copy sample of format version into tmp_dir
+    fn sample(tmp_dir: &tempfile::TempDir, version: u16) -> String {
+        let file_path = tmp_dir.path().join(format!("version_{}.hex", version));
+        let sample_path = format!("tests/samples/storage_upgrade/version_{}.hex", version);
+        std::fs::copy(sample_path, &file_path).unwrap();
+        file_path.to_str().unwrap().to_string()
+    }
+
+    #[test]
+    fn test_bump_sizes() {
+        for from in OLDEST_FORMAT_VERSION..FORMAT_VERSION {
+            let header = vec![0u8; storage_header_size(from)];
+            let bumped = bump_storage_header(from, &header);
+            assert_eq!(bumped.len(), storage_header_size(from + 1), "from {}", from);
+            let block_header = vec![0u8; block_header_size(from)];
+            let bumped = bump_block_header(from, &block_header, &[], 0).unwrap();
+            assert_eq!(bumped.len(), block_header_size(from + 1), "from {}", from);
+        }
+        // - flags past u16 did not fit version 9
+        let mut block_header = vec![0u8; block_header_size(8)];
+        put_u32(&mut block_header, BLOCK_HEADER_FLAGS_OFFSET, 1 << 16);
+        assert!(matches!(
+            bump_block_header(8, &block_header, &[], 3),
+            Err(StorageError::Corruption {
+                block_index: Some(3),
+                ..
+            })
+        ));
+    }
+    #[test]
+    fn test_upgrade_every_version() {
+        let tmp_dir = tempfile::tempdir().unwrap();
+        for version in OLDEST_FORMAT_VERSION..FORMAT_VERSION {
+            let file_path = sample(&tmp_dir, version);
+            assert!(Storage::open(file_path.clone()).is_err());
+            assert_eq!(Storage::upgrade(&file_path, None).unwrap(), version);
+            let mut storage = Storage::open(file_path.clone()).unwrap();
+            assert_eq!(storage.block_len(), 8, "version {}", version);
+            assert!(storage.read_block(0).unwrap().1.is_empty());
+            assert!(storage.read_block(1).unwrap().1.is_empty());
+            assert_eq!(storage.read_block(2).unwrap().1, vec![17, 18, 19, 20]);
+            assert!(storage.generation() > 0);
+            // - new blocks take generations past the upgraded ones
+            storage.write_block(3, &[3]).unwrap();
+            assert!(storage.read_block_versioned(3).unwrap().0 > 0);
+            storage.close().unwrap();
+            assert!(!std::path::Path::new(&format!("{}.upgrade", file_path)).exists());
+            // - a current file is left alone
+            let upgraded = std::fs::read(&file_path).unwrap();
+            assert_eq!(Storage::upgrade(&file_path, None).unwrap(), FORMAT_VERSION);
+            assert_eq!(std::fs::read(&file_path).unwrap(), upgraded);
+        }
+    }
+    #[test]
+    fn test_upgrade_rejects() {
+        let tmp_dir = tempfile::tempdir().unwrap();
+        // - a file of version 4 is not consistent with version 1, it is left alone
+        let file_path = sample(&tmp_dir, 4);
+        let original = std::fs::read(&file_path).unwrap();
+        assert!(matches!(
+            Storage::upgrade(&file_path, Some(1)),
+            Err(StorageError::Corruption { .. })
+        ));
+        assert!(matches!(
+            Storage::upgrade(&file_path, Some(5)),
+            Err(StorageError::UnsupportedVersion { version: 5 })
+        ));
+        assert_eq!(std::fs::read(&file_path).unwrap(), original);
+        // - a damaged block fails every layout
+        let mut damaged = original;
+        let last = damaged.len() - 1;
+        damaged[last] ^= 0xff;
+        std::fs::write(&file_path, &damaged).unwrap();
+        assert!(matches!(
+            Storage::upgrade(&file_path, None),
+            Err(StorageError::NotStorageFile)
+        ));
+        // - a file of a newer version
+        let file_path = sample(&tmp_dir, 10);
+        let mut bytes = std::fs::read(&file_path).unwrap();
+        put_u16(
+            &mut bytes,
+            STORAGE_HEADER_VERSION_OFFSET,
+            FORMAT_VERSION + 1,
+        );
+        std::fs::write(&file_path, &bytes).unwrap();
+        assert!(matches!(
+            Storage::upgrade(&file_path, None),
+            Err(StorageError::UnsupportedVersion { .. })
+        ));
+        // - a file held by a storage
+        let file_path = sample(&tmp_dir, 9);
+        Storage::upgrade(&file_path, None).unwrap();
+        let _storage = Storage::open(file_path.clone()).unwrap();
+        assert!(matches!(
+            Storage::upgrade(&file_path, None),
+            Err(StorageError::AlreadyLocked)
+        ));
+    }
+}
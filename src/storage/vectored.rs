@@ -0,0 +1,262 @@
+use super::*;
+use std::io::IoSliceMut;
+
+/// Bytes reserved at a time for block data of read_block_bytes
+#[cfg(feature = "bytes")]
+const READ_POOL_LEN: usize = 64 * 1024;
+
+/// Buffers reused across reads, so plain blocks are read without allocating
+#[derive(Default)]
+pub(super) struct ReadBuffers {
+    /// Block data of read_block_bytes is split off the front of this buffer
+    #[cfg(feature = "bytes")]
+    pool: bytes::BytesMut,
+    /// Block data of read_blocks_into that spans more than one slice
+    scratch: Vec<u8>,
+}
+
+/// Position in the slices of read_blocks_into
+struct SliceCursor {
+    /// Slice being filled
+    slice: usize,
+    /// Bytes of that slice already filled
+    offset: usize,
+    /// Bytes left over all slices
+    space: usize,
+}
+
+impl SliceCursor {
+    fn new(bufs: &[IoSliceMut<'_>]) -> Self {
+        SliceCursor {
+            slice: 0,
+            offset: 0,
+            space: bufs.iter().map(|buf| buf.len()).sum(),
+        }
+    }
+    /// Next len bytes, if they are in one slice, the cursor moves past them
+    fn take_contiguous<'b>(
+        &mut self,
+        bufs: &'b mut [IoSliceMut<'_>],
+        len: usize,
+    ) -> Option<&'b mut [u8]> {
+        // - skip filled slices
+        while self.slice < bufs.len() && self.offset == bufs[self.slice].len() {
+            self.slice += 1;
+            self.offset = 0;
+        }
+        let buf = bufs.get_mut(self.slice)?;
+        if buf.len() - self.offset < len {
+            return None;
+        }
+        let start = self.offset;
+        self.offset += len;
+        self.space -= len;
+        Some(&mut buf[start..start + len])
+    }
+    /// Copy data over slices from cursor, data must fit in space left
+    fn scatter(&mut self, bufs: &mut [IoSliceMut<'_>], mut data: &[u8]) {
+        while !data.is_empty() {
+            let buf = &mut bufs[self.slice];
+            let copy_len = (buf.len() - self.offset).min(data.len());
+            buf[self.offset..self.offset + copy_len].copy_from_slice(&data[..copy_len]);
+            data = &data[copy_len..];
+            self.offset += copy_len;
+            self.space -= copy_len;
+            if self.offset == buf.len() {
+                self.slice += 1;
+                self.offset = 0;
+            }
+        }
+    }
+}
+
+impl Storage {
+    // ... ... ... ... ... ... ... ... ... Vectored Reads ... ... ... ... ... ... ... ... ... .
+
+    /// Read block data, like read_block, as bytes::Bytes
+    /// - data of blocks stored without compression, encryption or transforms is read into
+    ///   a pooled buffer and handed out without copying, so reads do not allocate
+    /// - a pooled allocation is kept until every Bytes split off it is dropped
+    /// - other blocks, and blocks served from cache, are read like read_block
+    #[cfg(feature = "bytes")]
+    pub fn read_block_bytes(
+        &mut self,
+        block_index: BlockIndex,
+    ) -> Result<bytes::Bytes, StorageError> {
+        if self.is_empty_block(block_index) {
+            return Ok(bytes::Bytes::new());
+        }
+        if let Some(block_data) = self.cached_block(block_index) {
+            return Ok(bytes::Bytes::from(block_data));
+        }
+        let (block_header, data_offset) = self.read_stored_header(block_index)?;
+        if !self.is_plain_block(&block_header) {
+            let block_data = self.read_decoded_block(block_index, &block_header, data_offset)?;
+            return Ok(bytes::Bytes::from(block_data));
+        }
+        let data_len = block_header.data_len(block_index)?;
+        // - reserve reuses the pooled allocation once all Bytes split off it are dropped
+        let mut pool = std::mem::take(&mut self.read_buffers.pool);
+        if pool.capacity() < data_len {
+            pool.reserve(data_len.max(READ_POOL_LEN));
+        }
+        pool.resize(data_len, 0);
+        let read = self
+            .read_stored_data(block_index, data_offset, &mut pool)
+            .and_then(|()| self.verify_block_checksum(block_index, &block_header, &pool));
+        if let Err(error) = read {
+            pool.clear();
+            self.read_buffers.pool = pool;
+            return Err(error);
+        }
+        let block_data = pool.split_to(data_len).freeze();
+        self.read_buffers.pool = pool;
+        self.cache_block(block_index, &block_data);
+        Ok(block_data)
+    }
+    /// Read data of blocks into bufs, in the given order, one block after the other
+    /// - data of plain blocks that fits in the current slice is read straight into it,
+    ///   other blocks are read like read_block and copied over the slices
+    /// - free blocks add no bytes
+    /// - reading stops before the first block whose data does not fit in the space left
+    /// - returns: data length of each block read, in order
+    pub fn read_blocks_into(
+        &mut self,
+        block_indexes: &[BlockIndex],
+        bufs: &mut [IoSliceMut<'_>],
+    ) -> Result<Vec<usize>, StorageError> {
+        let mut cursor = SliceCursor::new(bufs);
+        let mut data_lens = Vec::with_capacity(block_indexes.len());
+        for block_index in block_indexes.iter().cloned() {
+            if self.is_empty_block(block_index) {
+                data_lens.push(0);
+                continue;
+            }
+            let block_data = match self.cached_block(block_index) {
+                Some(block_data) => block_data,
+                None => {
+                    let (block_header, data_offset) = self.read_stored_header(block_index)?;
+                    let data_len = block_header.data_len(block_index)?;
+                    if !self.is_plain_block(&block_header) {
+                        self.read_decoded_block(block_index, &block_header, data_offset)?
+                    } else if data_len > cursor.space {
+                        break;
+                    } else if let Some(target) = cursor.take_contiguous(bufs, data_len) {
+                        // - read straight into slice
+                        self.read_stored_data(block_index, data_offset, target)?;
+                        self.verify_block_checksum(block_index, &block_header, target)?;
+                        self.cache_block(block_index, target);
+                        data_lens.push(data_len);
+                        continue;
+                    } else {
+                        // - read to scratch buffer, then spread over slices
+                        let mut scratch = std::mem::take(&mut self.read_buffers.scratch);
+                        scratch.resize(data_len, 0);
+                        let read = self
+                            .read_stored_data(block_index, data_offset, &mut scratch)
+                            .and_then(|()| {
+                                self.verify_block_checksum(block_index, &block_header, &scratch)
+                            });
+                        if read.is_ok() {
+                            self.cache_block(block_index, &scratch);
+                            cursor.scatter(bufs, &scratch);
+                        }
+                        self.read_buffers.scratch = scratch;
+                        read?;
+                        data_lens.push(data_len);
+                        continue;
+                    }
+                }
+            };
+            if block_data.len() > cursor.space {
+                break;
+            }
+            cursor.scatter(bufs, &block_data);
+            data_lens.push(block_data.len());
+        }
+        Ok(data_lens)
+    }
+    /// Check if stored data of block is its data, nothing to decompress, decrypt or
+    /// reverse
+    fn is_plain_block(&self, block_header: &BlockHeader) -> bool {
+        block_header.flags == 0 && !self.has_block_transforms()
+    }
+    /// Read, verify and decode stored data of block, see read_block
+    fn read_decoded_block(
+        &mut self,
+        block_index: BlockIndex,
+        block_header: &BlockHeader,
+        data_offset: u64,
+    ) -> Result<Vec<u8>, StorageError> {
+        let mut block_data = vec![0u8; block_header.data_len(block_index)?];
+        self.read_stored_data(block_index, data_offset, &mut block_data)?;
+        self.verify_block_checksum(block_index, block_header, &block_data)?;
+        let block_data = self.decode_block_data(block_index, block_data, block_header.flags)?;
+        self.cache_block(block_index, &block_data);
+        Ok(block_data)
+    }
+}
+
+#[cfg(test)]
+mod unit_tests_vectored {
+    use super::*;
+
+    fn new_storage(tmp_dir: &tempfile::TempDir) -> Storage {
+        let file_path = tmp_dir.path().join("vectored.hex");
+        Storage::new(file_path.to_str().unwrap().to_string(), 4).unwrap()
+    }
+
+    #[test]
+    fn test_read_blocks_into() {
+        let tmp_dir = tempfile::tempdir().unwrap();
+        let mut storage = new_storage(&tmp_dir);
+        storage.write_block(0, &[1, 2]).unwrap();
+        storage.write_block(2, &[3, 4, 5, 6]).unwrap();
+        storage.write_block(3, &[7]).unwrap();
+        storage.write_block(4, &[8, 9, 10, 11]).unwrap();
+        storage.write_block(5, &[12]).unwrap();
+        let mut first = [0u8; 3];
+        let mut second = [0u8; 8];
+        let mut bufs = [IoSliceMut::new(&mut first), IoSliceMut::new(&mut second)];
+        // block 2 spans both slices, block 5 does not fit
+        let data_lens = storage
+            .read_blocks_into(&[0, 1, 2, 3, 4, 5], &mut bufs)
+            .unwrap();
+        assert_eq!(data_lens, vec![2, 0, 4, 1, 4]);
+        assert_eq!(first, [1, 2, 3]);
+        assert_eq!(second, [4, 5, 6, 7, 8, 9, 10, 11]);
+        // cached blocks are copied, errors stop the read
+        storage.enable_cache(64, CachePolicy::Lru);
+        storage.read_block(5).unwrap();
+        let mut bufs = [IoSliceMut::new(&mut first)];
+        assert_eq!(
+            storage.read_blocks_into(&[5, 3], &mut bufs).unwrap(),
+            vec![1, 1]
+        );
+        assert_eq!(first[..2], [12, 7]);
+        let data_offset = storage.block_offset(2).unwrap() + BLOCK_HEADER_SIZE as u64;
+        storage
+            .write_file_at(data_offset, &[0], "corrupt", None)
+            .unwrap();
+        let mut bufs = [IoSliceMut::new(&mut second)];
+        assert!(matches!(
+            storage.read_blocks_into(&[2], &mut bufs),
+            Err(StorageError::ChecksumMismatch { block_index: 2 })
+        ));
+    }
+    #[cfg(feature = "bytes")]
+    #[test]
+    fn test_read_block_bytes() {
+        let tmp_dir = tempfile::tempdir().unwrap();
+        let mut storage = new_storage(&tmp_dir);
+        storage.write_block(0, &[1, 2]).unwrap();
+        storage.write_block(1, &[3, 4, 5]).unwrap();
+        let first = storage.read_block_bytes(0).unwrap();
+        let second = storage.read_block_bytes(1).unwrap();
+        assert_eq!(first[..], [1, 2]);
+        assert_eq!(second[..], [3, 4, 5]);
+        // consecutive reads share the pooled allocation
+        assert_eq!(first.as_ptr().wrapping_add(2), second.as_ptr());
+        assert!(storage.read_block_bytes(9).unwrap().is_empty());
+    }
+}
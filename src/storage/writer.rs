@@ -0,0 +1,135 @@
+use super::*;
+use std::io;
+
+/// Streaming writer of a record, see Storage::writer
+/// - implements io::Write, bytes are buffered and written a full block at a time, so
+///   payloads of any length are written without holding them in memory
+/// - last full block is held back until more bytes arrive or finish, so the last
+///   block of the record never links to a block that is not written
+/// - NOTE: unlike write_record, blocks are written in data order, each linking to the
+///   next one before it is written; after a failed write or a drop without finish the
+///   blocks written so far end in a broken link, delete them with block_indexes
+pub struct BlockWriter<'a> {
+    storage: &'a mut Storage,
+    /// Bytes not written to a block yet
+    buffer: Vec<u8>,
+    /// Block the next chunk is written to, picked when the block before it was linked
+    next_block: Option<BlockIndex>,
+    /// Blocks written so far, in data order
+    block_indexes: Vec<BlockIndex>,
+}
+
+impl<'a> BlockWriter<'a> {
+    /// Blocks written so far, in data order, first block is the record id
+    pub fn block_indexes(&self) -> &[BlockIndex] {
+        &self.block_indexes
+    }
+    /// Write buffered bytes to the last block of the record
+    /// - returns: indexes of all blocks written, in data order, first block is the
+    ///   record id; empty if no bytes were written
+    pub fn finish(mut self) -> Result<Vec<BlockIndex>, StorageError> {
+        if !self.buffer.is_empty() {
+            let chunk = std::mem::take(&mut self.buffer);
+            self.write_chunk(&chunk, false)?;
+        }
+        Ok(std::mem::take(&mut self.block_indexes))
+    }
+    /// Write chunk to next block, linked to a newly picked block if more follows
+    fn write_chunk(&mut self, chunk: &[u8], more_follows: bool) -> Result<(), StorageError> {
+        let block_index = match self.next_block {
+            Some(block_index) => block_index,
+            None => self.storage.allocate(1)?[0],
+        };
+        let next_block = if more_follows {
+            // - allocate does not reserve, so skip the block about to be written
+            let candidates = self.storage.allocate(2)?;
+            candidates
+                .into_iter()
+                .find(|candidate| *candidate != block_index)
+        } else {
+            None
+        };
+        self.storage
+            .write_linked_block(block_index, chunk, next_block, None, 0)?;
+        self.block_indexes.push(block_index);
+        self.next_block = next_block;
+        Ok(())
+    }
+}
+
+impl io::Write for BlockWriter<'_> {
+    /// Buffer buf, writing every full block but the last
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.buffer.extend_from_slice(buf);
+        let chunk_len = self.storage.chunk_len();
+        while self.buffer.len() > chunk_len {
+            let chunk: Vec<u8> = self.buffer.drain(..chunk_len).collect();
+            self.write_chunk(&chunk, true).map_err(io::Error::other)?;
+        }
+        Ok(buf.len())
+    }
+    /// Sync blocks written so far to disk, see Storage::flush
+    /// - buffered bytes stay buffered until finish
+    fn flush(&mut self) -> io::Result<()> {
+        self.storage.flush().map_err(io::Error::other)
+    }
+}
+
+impl Storage {
+    /// Streaming writer of a new record, see BlockWriter
+    /// - blocks are picked by allocate as they fill, see AllocationStrategy
+    pub fn writer(&mut self) -> BlockWriter<'_> {
+        BlockWriter {
+            storage: self,
+            buffer: Vec::new(),
+            next_block: None,
+            block_indexes: Vec::new(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod unit_tests_writer {
+    use super::*;
+    use std::io::Write;
+
+    fn new_storage(tmp_dir: &tempfile::TempDir) -> Storage {
+        let file_path = tmp_dir.path().join("writer.hex");
+        Storage::new(file_path.to_str().unwrap().to_string(), 4).unwrap()
+    }
+
+    #[test]
+    fn test_streamed_record() {
+        let tmp_dir = tempfile::tempdir().unwrap();
+        let mut storage = new_storage(&tmp_dir);
+        storage.write_block(1, &[9]).unwrap();
+        storage.write_block(3, &[9]).unwrap();
+        storage.delete_block(1, false).unwrap();
+        let data: Vec<u8> = (0..14).collect();
+        let mut writer = storage.writer();
+        for piece in data.chunks(3) {
+            writer.write_all(piece).unwrap();
+        }
+        // full blocks are written as they fill, free blocks first
+        assert_eq!(writer.block_indexes(), &[0, 1, 2]);
+        writer.flush().unwrap();
+        let block_indexes = writer.finish().unwrap();
+        assert_eq!(block_indexes, vec![0, 1, 2, 4]);
+        assert_eq!(storage.read_record(0).unwrap(), data);
+        assert_eq!(storage.read_block(3).unwrap().1, vec![9]);
+    }
+    #[test]
+    fn test_full_last_block() {
+        let tmp_dir = tempfile::tempdir().unwrap();
+        let mut storage = new_storage(&tmp_dir);
+        let mut writer = storage.writer();
+        writer.write_all(&[1; 8]).unwrap();
+        // last full block is held back until finish
+        assert_eq!(writer.block_indexes(), &[0]);
+        let block_indexes = writer.finish().unwrap();
+        assert_eq!(block_indexes, vec![0, 1]);
+        assert_eq!(storage.read_block_header(1).unwrap().next_block, None);
+        assert_eq!(storage.read_record(0).unwrap(), vec![1; 8]);
+        assert!(storage.writer().finish().unwrap().is_empty());
+    }
+}
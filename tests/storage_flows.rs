@@ -1,4 +1,4 @@
-use se1::storage::Storage;
+use se1::storage::{Storage, StorageError, StorageOptions};
 
 fn read_full_file(file_name: &str) -> Vec<u8> {
     use std::fs::read;
@@ -49,101 +49,97 @@ fn storage_open_new_file() {
     let tmp_file_path = tmp_file_path.to_str().unwrap();
     // create new storage
     let storage_result = Storage::new(String::from(tmp_file_path), 8);
-    assert_eq!(storage_result.is_ok(), true);
+    assert!(storage_result.is_ok());
     let mut storage = storage_result.unwrap();
     let expected = fetch_state("on_create.hex");
     let actual = read_full_file(tmp_file_path);
     assert_eq!(expected, actual);
     // write to block 0
-    let block_0_data = vec![
-        1 as u8, 2 as u8, 3 as u8, 4 as u8, 5 as u8, 6 as u8, 7 as u8, 8 as u8,
-    ];
+    let block_0_data = vec![1u8, 2u8, 3u8, 4u8, 5u8, 6u8, 7u8, 8u8];
     let result = storage.write_block(0, &block_0_data);
-    assert_eq!(result.is_ok(), true);
+    assert!(result.is_ok());
     let write_ptr = result.unwrap();
-    assert_eq!(write_ptr, 16); // 4 + (4 + 8) * 0 + 4 + 8
+    assert_eq!(write_ptr, 4254); // 110 + 4096 + (40 + 8) * 0 + 40 + 8
     let expected = fetch_state("on_write_block_0.hex");
     let actual = read_full_file(tmp_file_path);
     assert_eq!(expected, actual);
     // write to block 1
-    let block_1_data = vec![
-        9 as u8, 10 as u8, 11 as u8, 12 as u8, 13 as u8, 14 as u8, 15 as u8, 16 as u8,
-    ];
+    let block_1_data = vec![9u8, 10u8, 11u8, 12u8, 13u8, 14u8, 15u8, 16u8];
     let result = storage.write_block(1, &block_1_data);
-    assert_eq!(result.is_ok(), true);
+    assert!(result.is_ok());
     let write_ptr = result.unwrap();
-    assert_eq!(write_ptr, 28); // 4 + (4 + 8) * 1 + 4 + 8
+    assert_eq!(write_ptr, 4302); // 110 + 4096 + (40 + 8) * 1 + 40 + 8
     let expected = fetch_state("on_write_block_1.hex");
     let actual = read_full_file(tmp_file_path);
     assert_eq!(expected, actual);
     // write to block 2
-    let block_2_data = vec![17 as u8, 18 as u8, 19 as u8, 20 as u8];
+    let block_2_data = vec![17u8, 18u8, 19u8, 20u8];
     let result = storage.write_block(2, &block_2_data);
-    assert_eq!(result.is_ok(), true);
+    assert!(result.is_ok());
     let write_ptr = result.unwrap();
-    assert_eq!(write_ptr, 36); // 4 + (4 + 8) * 2 + 4 + 4
+    assert_eq!(write_ptr, 4346); // 110 + 4096 + (40 + 8) * 2 + 40 + 4
     let expected = fetch_state("on_write_block_2.hex");
     let actual = read_full_file(tmp_file_path);
     assert_eq!(expected, actual);
     // read from block 2
     let result = storage.read_block(2);
-    assert_eq!(result.is_ok(), true);
+    assert!(result.is_ok());
     let (read_ptr, actual_data) = result.unwrap();
-    assert_eq!(read_ptr, 36); // 4 + (4 + 8) * 2 + 4 + 4
+    assert_eq!(read_ptr, 4346); // 110 + 4096 + (40 + 8) * 2 + 40 + 4
     assert_eq!(actual_data, block_2_data);
     // read from block 1
     let result = storage.read_block(1);
-    assert_eq!(result.is_ok(), true);
+    assert!(result.is_ok());
     let (read_ptr, actual_data) = result.unwrap();
-    assert_eq!(read_ptr, 28); // 4 + (4 + 8) * 1 + 4 + 8
+    assert_eq!(read_ptr, 4302); // 110 + 4096 + (40 + 8) * 1 + 40 + 8
     assert_eq!(actual_data, block_1_data);
     // read from block 0
     let result = storage.read_block(0);
-    assert_eq!(result.is_ok(), true);
+    assert!(result.is_ok());
     let (read_ptr, actual_data) = result.unwrap();
-    assert_eq!(read_ptr, 16); // 4 + (4 + 8) * 0 + 4 + 8
+    assert_eq!(read_ptr, 4254); // 110 + 4096 + (40 + 8) * 0 + 40 + 8
     assert_eq!(actual_data, block_0_data);
     // read from block 3
     let result = storage.read_block(3);
-    assert_eq!(result.is_ok(), true);
+    assert!(result.is_ok());
     let (read_ptr, actual_data) = result.unwrap();
-    assert_eq!(read_ptr, 16); // no change
+    assert_eq!(read_ptr, 0); // empty block, nothing read
     assert_eq!(actual_data.len(), 0); // no data
                                       // soft delete_block 0
     let result = storage.delete_block(0, false);
-    assert_eq!(result.is_ok(), true);
+    assert!(result.is_ok());
     let write_ptr = result.unwrap();
-    assert_eq!(write_ptr, 8); // 4 + (4 + 8) * 0 + 4 + 0
+    assert_eq!(write_ptr, 4246); // 110 + 4096 + (40 + 8) * 0 + 40 + 0
     let expected = fetch_state("on_soft_delete_block_0.hex");
     let actual = read_full_file(tmp_file_path);
     assert_eq!(expected, actual);
     // hard delete_block 0
     let result = storage.delete_block(0, true);
-    assert_eq!(result.is_ok(), true);
+    assert!(result.is_ok());
     let write_ptr = result.unwrap();
-    assert_eq!(write_ptr, 16); // 4 + (4 + 8) * 0 + 4 + 8
+    assert_eq!(write_ptr, 4254); // 110 + 4096 + (40 + 8) * 0 + 40 + 8
     let expected = fetch_state("on_hard_delete_block_0.hex");
     let actual = read_full_file(tmp_file_path);
     assert_eq!(expected, actual);
     // soft delete_block 1
     let result = storage.delete_block(1, false);
-    assert_eq!(result.is_ok(), true);
+    assert!(result.is_ok());
     let write_ptr = result.unwrap();
-    assert_eq!(write_ptr, 20); // 4 + (4 + 8) * 1 + 4 + 0
+    assert_eq!(write_ptr, 4294); // 110 + 4096 + (40 + 8) * 1 + 40 + 0
     let expected = fetch_state("on_soft_delete_block_1.hex");
     let actual = read_full_file(tmp_file_path);
     assert_eq!(expected, actual);
     // hard delete_block 2
     let result = storage.delete_block(2, true);
-    assert_eq!(result.is_ok(), true);
+    assert!(result.is_ok());
     let write_ptr = result.unwrap();
-    assert_eq!(write_ptr, 40); // 4 + (4 + 8) * 2 + 4 + 8
+    assert_eq!(write_ptr, 4350); // 110 + 4096 + (40 + 8) * 2 + 40 + 8
     let expected = fetch_state("on_hard_delete_block_2.hex");
     let actual = read_full_file(tmp_file_path);
     assert_eq!(expected, actual);
 
     // clear clutter
-    remove_dir_contents(std::path::PathBuf::from(tmp_dir_path));
+    remove_dir_contents(tmp_dir_path);
 }
 
 #[test]
@@ -172,74 +168,254 @@ fn storage_open_existing_file1() {
     let mut storage = Storage::open(String::from(tmp_file_path)).unwrap();
     // read from block 0
     let result = storage.read_block(0);
-    assert_eq!(result.is_ok(), true);
+    assert!(result.is_ok());
     let (_, actual_data) = result.unwrap();
     assert_eq!(actual_data.len(), 0); // no data
                                       // read from block 1
     let result = storage.read_block(1);
-    assert_eq!(result.is_ok(), true);
+    assert!(result.is_ok());
     let (_, actual_data) = result.unwrap();
     assert_eq!(actual_data.len(), 0); // no data
                                       // read from block 2
     let result = storage.read_block(2);
-    assert_eq!(result.is_ok(), true);
+    assert!(result.is_ok());
     let (read_ptr, actual_data) = result.unwrap();
-    assert_eq!(read_ptr, 36); // 4 + (4 + 8) * 2 + 4 + 4
-    let block_2_data = vec![17 as u8, 18 as u8, 19 as u8, 20 as u8];
+    assert_eq!(read_ptr, 4346); // 110 + 4096 + (40 + 8) * 2 + 40 + 4
+    let block_2_data = vec![17u8, 18u8, 19u8, 20u8];
     assert_eq!(actual_data, block_2_data); // no data
                                            // read from block 3
     let result = storage.read_block(3);
-    assert_eq!(result.is_ok(), true);
+    assert!(result.is_ok());
     let (read_ptr, actual_data) = result.unwrap();
-    assert_eq!(read_ptr, 36); // no change
+    assert_eq!(read_ptr, 0); // empty block, nothing read
     assert_eq!(actual_data.len(), 0); // no data
 
     // write to block 3
-    let block_3_data = vec![3 as u8, 9 as u8, 27 as u8];
+    let block_3_data = vec![3u8, 9u8, 27u8];
     let result = storage.write_block(3, &block_3_data);
-    assert_eq!(result.is_ok(), true);
+    assert!(result.is_ok());
     let write_ptr = result.unwrap();
-    assert_eq!(write_ptr, 47); // 4 + (4 + 8) * 3 + 4 + 3
+    assert_eq!(write_ptr, 4393); // 110 + 4096 + (40 + 8) * 3 + 40 + 3
     let expected = fetch_state("w-0_w-1_w-2_sd-0_hd-0_sd-1_hd-2_w-3.hex");
     let actual = read_full_file(tmp_file_path);
     assert_eq!(expected, actual);
     // write to block 4
-    let block_4_data = vec![4 as u8, 8 as u8, 16 as u8, 32 as u8];
+    let block_4_data = vec![4u8, 8u8, 16u8, 32u8];
     let result = storage.write_block(4, &block_4_data);
-    assert_eq!(result.is_ok(), true);
+    assert!(result.is_ok());
     let write_ptr = result.unwrap();
-    assert_eq!(write_ptr, 60); // 4 + (4 + 8) * 4 + 4 + 4
+    assert_eq!(write_ptr, 4442); // 110 + 4096 + (40 + 8) * 4 + 40 + 4
     let expected = fetch_state("w-0_w-1_w-2_sd-0_hd-0_sd-1_hd-2_w-3_w-4.hex");
     let actual = read_full_file(tmp_file_path);
     assert_eq!(expected, actual);
     // write to block 5
-    let block_5_data = vec![5 as u8, 10 as u8, 20 as u8, 40 as u8, 80 as u8];
+    let block_5_data = vec![5u8, 10u8, 20u8, 40u8, 80u8];
     let result = storage.write_block(5, &block_5_data);
-    assert_eq!(result.is_ok(), true);
+    assert!(result.is_ok());
     let write_ptr = result.unwrap();
-    assert_eq!(write_ptr, 73); // 4 + (4 + 8) * 5 + 4 + 5
+    assert_eq!(write_ptr, 4491); // 110 + 4096 + (40 + 8) * 5 + 40 + 5
     let expected = fetch_state("w-0_w-1_w-2_sd-0_hd-0_sd-1_hd-2_w-3_w-4_w-5.hex");
     let actual = read_full_file(tmp_file_path);
     assert_eq!(expected, actual);
     // TODO:
     // soft delete block 1
     let result = storage.delete_block(1, false);
-    assert_eq!(result.is_ok(), true);
+    assert!(result.is_ok());
     let write_ptr = result.unwrap();
-    assert_eq!(write_ptr, 73); // no change
+    assert_eq!(write_ptr, 0); // already free, nothing written
     let actual = read_full_file(tmp_file_path);
     assert_eq!(expected, actual);
     // soft delete block 3
     let result = storage.delete_block(3, false);
-    assert_eq!(result.is_ok(), true);
+    assert!(result.is_ok());
     let write_ptr = result.unwrap();
-    assert_eq!(write_ptr, 44); // 4 + (4 + 8) * 3 + 4
+    assert_eq!(write_ptr, 4390); // 110 + 4096 + (40 + 8) * 3 + 40
     let expected = fetch_state("w-0_w-1_w-2_sd-0_hd-0_sd-1_hd-2_w-3_w-4_w-5_sd-3.hex");
     let actual = read_full_file(tmp_file_path);
     assert_eq!(expected, actual);
     // clear clutter
-    remove_dir_contents(std::path::PathBuf::from(tmp_dir_path));
+    remove_dir_contents(tmp_dir_path);
 }
 
 #[test]
 fn storage_open_existing_file2() {}
+
+#[test]
+fn storage_rebuild_free_list() {
+    let tmp_dir_path = tempfile::tempdir().unwrap().into_path();
+    let tmp_file_path: std::path::PathBuf = [
+        tmp_dir_path.to_str().unwrap().to_string(),
+        String::from("storage_rebuild_free_list.hex"),
+    ]
+    .iter()
+    .collect();
+    let tmp_file_path = tmp_file_path.to_str().unwrap();
+    // create storage and write blocks 0, 1, 2
+    let mut storage = Storage::new(String::from(tmp_file_path), 8).unwrap();
+    for block_index in 0..3 {
+        let result = storage.write_block(block_index, &[block_index as u8 + 1; 8]);
+        assert!(result.is_ok());
+    }
+    // open second handle, free list is loaded from file; first handle holds the lock
+    let mut stale_storage = StorageOptions::new(String::from(tmp_file_path))
+        .lock(false)
+        .open()
+        .unwrap();
+    // soft delete block 1 and write block 3 through first handle
+    assert!(storage.delete_block(1, false).is_ok());
+    assert!(storage.write_block(3, &[4u8; 4]).is_ok());
+    // second handle is stale until free list is rebuilt
+    let (_, actual_data) = stale_storage.read_block(3).unwrap();
+    assert_eq!(actual_data.len(), 0);
+    let result = stale_storage.rebuild_free_list();
+    assert!(result.is_ok());
+    assert_eq!(result.unwrap(), 1); // only block 1 is free
+    let (_, actual_data) = stale_storage.read_block(1).unwrap();
+    assert_eq!(actual_data.len(), 0);
+    let (_, actual_data) = stale_storage.read_block(3).unwrap();
+    assert_eq!(actual_data, vec![4u8; 4]);
+    // rebuilding again is a no-op
+    assert_eq!(stale_storage.rebuild_free_list().unwrap(), 1);
+    // clear clutter
+    remove_dir_contents(tmp_dir_path);
+}
+
+#[test]
+fn storage_invalid_arguments_return_errors() {
+    let tmp_dir_path = tempfile::tempdir().unwrap().into_path();
+    let tmp_file_path: std::path::PathBuf = [
+        tmp_dir_path.to_str().unwrap().to_string(),
+        String::from("storage_invalid_arguments.hex"),
+    ]
+    .iter()
+    .collect();
+    let tmp_file_path = tmp_file_path.to_str().unwrap();
+    // block_len must be at least 1
+    assert!(matches!(
+        Storage::new(String::from(tmp_file_path), 0),
+        Err(StorageError::InvalidBlockLength { block_len: 0 })
+    ));
+    let mut storage = Storage::new(String::from(tmp_file_path), 8).unwrap();
+    storage.write_block(0, &[1, 2, 3]).unwrap();
+    // data larger than block_len must not overwrite next block
+    assert!(matches!(
+        storage.write_block(1, &[9u8; 9]),
+        Err(StorageError::BlockTooLarge { block_index: 1, .. })
+    ));
+    // block index with an offset beyond u64 must not wrap around to block 0
+    let beyond_file = u64::MAX / 8;
+    assert!(matches!(
+        storage.write_block(beyond_file, &[9]),
+        Err(StorageError::BlockOutOfRange { .. })
+    ));
+    let (_, actual_data) = storage.read_block(beyond_file).unwrap();
+    assert_eq!(actual_data.len(), 0);
+    assert!(storage.delete_block(beyond_file, true).is_ok());
+    let (_, actual_data) = storage.read_block(0).unwrap();
+    assert_eq!(actual_data, vec![1, 2, 3]);
+    // clear clutter
+    remove_dir_contents(tmp_dir_path);
+}
+
+#[test]
+fn storage_corrupt_block_header_returns_error() {
+    let tmp_dir_path = tempfile::tempdir().unwrap().into_path();
+    let tmp_file_path: std::path::PathBuf = [
+        tmp_dir_path.to_str().unwrap().to_string(),
+        String::from("storage_corrupt_block_header.hex"),
+    ]
+    .iter()
+    .collect();
+    let tmp_file_path = tmp_file_path.to_str().unwrap();
+    let mut storage = Storage::new(String::from(tmp_file_path), 8).unwrap();
+    storage.write_block(0, &[1, 2, 3]).unwrap();
+    drop(storage);
+    // overwrite block 0 data size with a value larger than block_len
+    // - block 0 header follows two 55 byte storage header copies and 4096 byte bitmap
+    let mut file_bytes = read_full_file(tmp_file_path);
+    file_bytes[4206..4214].copy_from_slice(&[0xff; 8]);
+    std::fs::write(tmp_file_path, file_bytes).unwrap();
+    let mut storage = Storage::open(String::from(tmp_file_path)).unwrap();
+    let error = storage.read_block(0).unwrap_err();
+    assert!(error.is_corruption());
+    assert_eq!(error.block_index(), Some(0));
+    // clear clutter
+    remove_dir_contents(tmp_dir_path);
+}
+
+#[test]
+fn storage_open_rejects_foreign_files() {
+    let tmp_dir_path = tempfile::tempdir().unwrap().into_path();
+    let tmp_file_path: std::path::PathBuf = [
+        tmp_dir_path.to_str().unwrap().to_string(),
+        String::from("storage_foreign_file.hex"),
+    ]
+    .iter()
+    .collect();
+    let tmp_file_path = tmp_file_path.to_str().unwrap();
+    // any file that does not start with the magic
+    std::fs::write(tmp_file_path, b"hello world, not a storage file").unwrap();
+    assert!(matches!(
+        Storage::open(String::from(tmp_file_path)),
+        Err(StorageError::NotStorageFile)
+    ));
+    std::fs::write(tmp_file_path, b"").unwrap();
+    assert!(matches!(
+        Storage::open(String::from(tmp_file_path)),
+        Err(StorageError::NotStorageFile)
+    ));
+    // storage file of another format version, version follows 4 byte magic
+    // - in both 55 byte copies of storage header, neither is left intact
+    drop(Storage::new(String::from(tmp_file_path), 8).unwrap());
+    let mut file_bytes = read_full_file(tmp_file_path);
+    file_bytes[4..6].copy_from_slice(&[5, 0]);
+    file_bytes[59..61].copy_from_slice(&[5, 0]);
+    std::fs::write(tmp_file_path, file_bytes).unwrap();
+    assert!(matches!(
+        Storage::open(String::from(tmp_file_path)),
+        Err(StorageError::UnsupportedVersion { version: 5 })
+    ));
+    // clear clutter
+    remove_dir_contents(tmp_dir_path);
+}
+
+#[test]
+fn storage_upgrade_baseline_file() {
+    let tmp_dir_path = tempfile::tempdir().unwrap().into_path();
+    let tmp_file_path: std::path::PathBuf = [
+        tmp_dir_path.to_str().unwrap().to_string(),
+        String::from("storage_upgrade_baseline_file.hex"),
+    ]
+    .iter()
+    .collect();
+    // copy sample written by the baseline, format version 1, of the same writes as
+    // "tests/samples/storage_open_existing_file1/w-0_w-1_w-2_sd-0_hd-0_sd-1_hd-2.hex"
+    std::fs::copy(
+        "tests/samples/storage_upgrade/version_1.hex",
+        tmp_file_path.clone(),
+    )
+    .unwrap();
+    let tmp_file_path = tmp_file_path.to_str().unwrap();
+    // baseline file has no magic
+    assert!(matches!(
+        Storage::open(String::from(tmp_file_path)),
+        Err(StorageError::NotStorageFile)
+    ));
+    assert_eq!(Storage::upgrade(tmp_file_path, None).unwrap(), 1);
+    // open upgraded storage, blocks are where the current format puts them
+    let mut storage = Storage::open(String::from(tmp_file_path)).unwrap();
+    assert_eq!(storage.block_len(), 8);
+    assert_eq!(storage.read_block(0).unwrap().1.len(), 0);
+    assert_eq!(storage.read_block(1).unwrap().1.len(), 0);
+    let (read_ptr, actual_data) = storage.read_block(2).unwrap();
+    assert_eq!(read_ptr, 4346); // 110 + 4096 + (40 + 8) * 2 + 40 + 4
+    assert_eq!(actual_data, vec![17u8, 18u8, 19u8, 20u8]);
+    let write_ptr = storage.write_block(3, &[3u8, 9u8, 27u8]).unwrap();
+    assert_eq!(write_ptr, 4393); // 110 + 4096 + (40 + 8) * 3 + 40 + 3
+    storage.close().unwrap();
+    let mut storage = Storage::open(String::from(tmp_file_path)).unwrap();
+    assert_eq!(storage.read_block(3).unwrap().1, vec![3u8, 9u8, 27u8]);
+    drop(storage);
+    // clear clutter
+    remove_dir_contents(tmp_dir_path);
+}